@@ -0,0 +1,103 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Benchmark of accepting patches and iterating topics on a large drop
+//!
+//! Generates a drop using `it fixtures`, which prepares and accepts every
+//! record through the regular accept path, then iterates the records of the
+//! drop and the notes of all of its topics. The number of records defaults to
+//! 10000, and can be set via the `IT_BENCH_RECORDS` environment variable:
+//!
+//!     IT_BENCH_RECORDS=1000 cargo bench --features fixtures
+
+use std::{
+    env,
+    path::PathBuf,
+    process::Command,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::{
+    ensure,
+    Context as _,
+};
+use it::patches::{
+    iter,
+    Topic,
+    REF_IT_PATCHES,
+};
+
+const DEFAULT_RECORDS: usize = 10_000;
+/// Each patch of a topic builds on the previous one, so this is bounded by the
+/// default commit limit
+const PATCHES_PER_TOPIC: usize = 10;
+
+#[derive(serde::Deserialize)]
+struct Versioned {
+    data: Fixtures,
+}
+
+#[derive(serde::Deserialize)]
+struct Fixtures {
+    drop: PathBuf,
+    topics: Vec<Topic>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let records = match env::var("IT_BENCH_RECORDS") {
+        Ok(n) => n.parse().context("invalid IT_BENCH_RECORDS")?,
+        Err(_) => DEFAULT_RECORDS,
+    };
+    let topics = (records / PATCHES_PER_TOPIC).max(1);
+    let patches = records / topics;
+    let tmp = tempfile::tempdir()?;
+
+    let start = Instant::now();
+    let out = Command::new(env!("CARGO_BIN_EXE_it"))
+        .arg("fixtures")
+        .arg(tmp.path().join("fixtures"))
+        .args(["--topics", &topics.to_string()])
+        .args(["--patches", &patches.to_string()])
+        .args(["--comments", "0", "--checkpoints", "0"])
+        .output()?;
+    ensure!(
+        out.status.success(),
+        "it fixtures failed: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    report("prepare and accept", topics * patches, start.elapsed());
+
+    let Versioned { data: fixtures } = serde_json::from_slice(&out.stdout)?;
+    let repo = git2::Repository::open(&fixtures.drop)?;
+
+    let start = Instant::now();
+    let mut n = 0;
+    for record in iter::dropped::records(&repo, REF_IT_PATCHES) {
+        record?;
+        n += 1;
+    }
+    report("iterate records", n, start.elapsed());
+
+    let start = Instant::now();
+    let mut n = 0;
+    for topic in &fixtures.topics {
+        for note in iter::topic(&repo, topic) {
+            note?;
+            n += 1;
+        }
+    }
+    report("iterate topics", n, start.elapsed());
+
+    Ok(())
+}
+
+fn report(what: &str, n: usize, elapsed: Duration) {
+    println!(
+        "{what}: {n} in {:.2?} ({:.2?} each)",
+        elapsed,
+        elapsed / n.max(1) as u32
+    );
+}
@@ -2,19 +2,22 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
-    io,
+    io::{
+        self,
+        Write as _,
+    },
     path::PathBuf,
 };
 
 use clap::ValueHint;
 use clap_complete::Shell;
 
-static OUTPUT: it::Output = it::Output;
-
 fn main() -> it::Result<()> {
     use clap::Parser as _;
 
-    log::set_logger(&OUTPUT)?;
+    it::cmd::ui::term::init_colors();
+    let cli = It::parse();
+    log::set_boxed_logger(Box::new(it::Output::new(cli.log_format)))?;
     log::set_max_level(
         std::env::var("RUST_LOG")
             .ok()
@@ -22,12 +25,26 @@ fn main() -> it::Result<()> {
             .unwrap_or(log::LevelFilter::Info),
     );
 
-    let cli = It::parse();
+    it::cmd::ui::term::set_raw(cli.raw);
+    if let Some(via) = cli.edit_via {
+        it::cmd::ui::set_edit_via(via);
+    }
+
     match cli.cmd {
-        Cmd::Cmd(cmd) => cmd
-            .run()
-            .and_then(|o| render(o, cli.compact))
-            .or_else(|e| e.downcast::<it::cmd::Aborted>().map(|_aborted| ())),
+        Cmd::Cmd(cmd) => {
+            let oplog = cmd.oplog_name();
+            cmd.run()
+                .map(|o| {
+                    if let Some(name) = oplog {
+                        if let Err(e) = it::cmd::oplog::record(&cli.git_dir, name) {
+                            log::warn!("Failed to record {name} in the oplog: {e:#}");
+                        }
+                    }
+                    o
+                })
+                .and_then(|o| render(o, cli.compact))
+                .or_else(|e| e.downcast::<it::cmd::Aborted>().map(|_aborted| ()))
+        },
         Cmd::Hidden(cmd) => match cmd {
             Hidden::Man { out } => hidden::mangen(&out),
             Hidden::Completions { shell, out } => hidden::completions(shell, out.as_deref()),
@@ -53,31 +70,70 @@ struct It {
     /// Do not pretty-print the output
     #[clap(long, value_parser, default_value_t = false, global = true)]
     compact: bool,
+    /// Print control characters in the output as-is
+    ///
+    /// By default, control characters other than newlines and tabs are
+    /// escaped, so text written by others can't manipulate the terminal.
+    /// Escapes in JSON output don't change the values represented.
+    #[clap(long, value_parser, global = true)]
+    raw: bool,
+    /// Format of log messages written to stderr
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        env = "IT_LOG_FORMAT",
+        default_value_t = it::cmd::ui::LogFormat::Text,
+        global = true,
+    )]
+    log_format: it::cmd::ui::LogFormat,
+    /// Delegate editing to a frontend instead of invoking $EDITOR
+    ///
+    /// Either the path of a unix domain socket, or 'fd:N' for an inherited
+    /// file descriptor. Templates are written as a line of JSON, and the
+    /// edited text is read back the same way.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SOCKET|fd:N",
+        env = "IT_EDIT_VIA",
+        global = true
+    )]
+    edit_via: Option<it::cmd::ui::EditVia>,
     #[clap(subcommand)]
     cmd: Cmd,
 }
 
 fn render(output: it::cmd::Output, compact: bool) -> it::Result<()> {
-    use it::cmd::Output::*;
+    use it::cmd::{
+        ui::term::{
+            sanitize,
+            sanitize_json,
+        },
+        Output::*,
+    };
 
-    let go = |v| {
-        let out = io::stdout();
-        if compact {
-            serde_json::to_writer(out, &v)
+    let go = |v: &dyn erased_serde::Serialize| -> it::Result<()> {
+        let v = it::cmd::versioned(v);
+        let json = if compact {
+            serde_json::to_string(&v)?
         } else {
-            serde_json::to_writer_pretty(out, &v)
-        }
+            serde_json::to_string_pretty(&v)?
+        };
+        io::stdout().write_all(sanitize_json(&json).as_bytes())?;
+        Ok(())
     };
 
     match output {
-        Val(v) => go(v)?,
+        Val(v) => go(&*v)?,
         Iter(i) => {
             for v in i {
                 let v = v?;
-                go(v)?;
+                go(&*v)?;
                 println!();
             }
         },
+        Text(s) => print!("{}", sanitize(&s)),
     }
 
     Ok(())
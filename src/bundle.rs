@@ -18,6 +18,8 @@ use crate::io::{
     LenWriter,
 };
 
+pub mod chunks;
+
 pub mod error;
 
 mod fetch;
@@ -26,12 +28,18 @@ pub use fetch::{
     Fetcher,
 };
 
+mod hash;
+pub use hash::{
+    Hash,
+    HashAlgorithm,
+};
+
 mod header;
 pub use header::{
-    Hash,
     Header,
     ObjectFormat,
     ObjectId,
+    ParseWriter,
     Version,
 };
 
@@ -42,6 +50,8 @@ pub use list::{
     Uri,
 };
 
+pub mod pack;
+
 pub const FILE_EXTENSION: &str = "bundle";
 pub const DOT_FILE_EXTENSION: &str = ".bundle";
 
@@ -102,7 +112,12 @@ impl<'a> From<&'a Info> for Expect<'a> {
     }
 }
 
-pub fn create<W>(mut out: W, repo: &git2::Repository, header: &Header) -> crate::Result<Info>
+pub fn create<W>(
+    mut out: W,
+    repo: &git2::Repository,
+    header: &Header,
+    alg: HashAlgorithm,
+) -> crate::Result<Info>
 where
     W: io::Write,
 {
@@ -126,7 +141,7 @@ where
     pack.foreach(|chunk| io::Write::write_all(&mut writer, chunk).is_ok())?;
 
     let len = writer.bytes_written();
-    let hash = header.hash();
+    let hash = header.hash(alg);
     let checksum = Checksum::from(hasher.hasher());
 
     info!("Created patch bundle {hash}");
@@ -0,0 +1,428 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Deduplicated storage of bundles
+//!
+//! Snapshots repack everything the drop has seen, so consecutive snapshot
+//! bundles are mostly identical. Instead of storing each of them in full, a
+//! bundle can be split into content-defined chunks, which are stored only
+//! once below the [`DIR`] directory next to the bundles, named by their BLAKE3
+//! hash. The bundle file is then replaced by a manifest listing its chunks in
+//! order, and reassembled when it is read.
+//!
+//! Chunk boundaries are found using a rolling "gear" hash over the content, so
+//! identical runs of packdata yield identical chunks regardless of their
+//! offset in the bundle.
+//!
+//! A chunk is only referred to once the manifest of the bundle being stored
+//! is written, so [`gc`] must not run while bundles are being stored. Each
+//! [`store`] holds a marker file in the chunk directory for its duration, of
+//! which any number may exist at once, while [`gc`] holds an exclusive lock
+//! file. Either creates its own file before checking for the other's, so at
+//! least one of them backs off.
+
+use std::{
+    collections::BTreeSet,
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Read,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+    vec,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use tempfile::NamedTempFile;
+
+use super::Checksum;
+use crate::fs::LockedFile;
+
+/// Name of the directory holding the chunks, relative to the bundle directory
+pub const DIR: &str = "chunks";
+pub const FILE_EXTENSION: &str = "chunks";
+
+/// Chunks are at least this long, except for the last one
+const MIN_CHUNK: usize = 64 * 1024;
+/// Chunks are at most this long
+const MAX_CHUNK: usize = 1024 * 1024;
+/// A chunk boundary is placed where the gear hash has these bits unset, which
+/// yields chunks of 256KiB on average
+///
+/// The most significant bits are used, as they depend on the last 64 bytes
+/// seen, whereas the least significant ones depend only on the last few.
+const MASK: u64 = !(u64::MAX >> 18);
+
+const GEAR: [u64; 256] = gear();
+
+/// Base name of the lock file held by [`gc`], within the chunk directory
+const GC_LOCK: &str = "gc";
+/// Prefix of the marker files held by [`store`], within the chunk directory
+const STORE_MARKER: &str = "store-";
+/// Markers older than this are assumed to be left behind by a process which
+/// didn't exit cleanly
+const STALE_MARKER: Duration = Duration::from_secs(60 * 60);
+/// How long [`store`] waits for a concurrent [`gc`] to finish
+const GC_WAIT: Duration = Duration::from_secs(60);
+
+/// Table of pseudo-random values, one per byte value, generated by splitmix64
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// The list of chunks making up a bundle
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    /// Length of the bundle in bytes
+    pub len: u64,
+    /// Checksum of the bundle
+    pub checksum: Checksum,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Chunk {
+    pub id: Checksum,
+    pub len: u64,
+}
+
+/// Result of [`store`]
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+pub struct Stats {
+    /// Number of chunks the bundle was split into
+    pub chunks: usize,
+    /// Number of chunks not already stored
+    pub added: usize,
+    /// Length of the bundle in bytes
+    pub len: u64,
+    /// Number of bytes added to the chunk store
+    pub added_len: u64,
+}
+
+/// Path of the manifest replacing the bundle file at `bundle`
+pub fn manifest_path(bundle: &Path) -> PathBuf {
+    bundle.with_extension(FILE_EXTENSION)
+}
+
+/// Whether the bundle file at `bundle` has been replaced by a manifest
+pub fn is_chunked(bundle: &Path) -> bool {
+    !bundle.exists() && manifest_path(bundle).exists()
+}
+
+/// Whether the bundle at `bundle` is stored, either as a file or as chunks
+pub fn exists(bundle: &Path) -> bool {
+    bundle.exists() || manifest_path(bundle).exists()
+}
+
+/// Store the bundle read from `data` in chunks, and write the manifest for the
+/// bundle file at `bundle`
+///
+/// Chunks already present in the chunk store are not written again. Once the
+/// manifest is written, the bundle file can be removed.
+///
+/// Waits for a concurrent [`gc`] to finish, if any.
+pub fn store<R: Read>(bundle: &Path, data: R) -> crate::Result<Stats> {
+    let root = chunk_root(bundle)?;
+    let _storing = Storing::begin(&root)?;
+    let mut chunker = Chunker::new(BufReader::new(data));
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = Vec::with_capacity(MAX_CHUNK);
+    let mut chunks = Vec::new();
+    let mut stats = Stats::default();
+    while chunker.next_chunk(&mut buf)? {
+        hasher.update(&buf);
+        let id = Checksum(blake3::hash(&buf));
+        let path = chunk_path(&root, &id);
+        if !path.exists() {
+            let dir = path.parent().expect("chunk path has a parent");
+            fs::create_dir_all(dir)?;
+            let mut tmp = NamedTempFile::new_in(dir)?;
+            tmp.write_all(&buf)?;
+            tmp.persist(&path)?;
+            stats.added += 1;
+            stats.added_len += buf.len() as u64;
+        }
+        stats.chunks += 1;
+        stats.len += buf.len() as u64;
+        chunks.push(Chunk {
+            id,
+            len: buf.len() as u64,
+        });
+    }
+
+    let manifest = Manifest {
+        len: stats.len,
+        checksum: Checksum::from(&hasher),
+        chunks,
+    };
+    let path = manifest_path(bundle);
+    let mut tmp = NamedTempFile::new_in(path.parent().expect("bundle path has a parent"))?;
+    serde_json::to_writer(&mut tmp, &manifest)?;
+    tmp.persist(&path)?;
+
+    Ok(stats)
+}
+
+/// A reader over the chunks of the bundle at `bundle`, along with its length
+///
+/// Returns `None` if the bundle is not chunked.
+pub fn reader(bundle: &Path) -> crate::Result<Option<(u64, Reader)>> {
+    let manifest: Manifest = match fs::read(manifest_path(bundle)) {
+        Ok(json) => serde_json::from_slice(&json)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let reader = Reader {
+        root: chunk_root(bundle)?,
+        chunks: manifest.chunks.into_iter(),
+        current: None,
+    };
+
+    Ok(Some((manifest.len, reader)))
+}
+
+/// Reassemble the chunked bundle at `bundle`, writing it to `out`
+///
+/// Fails if the bundle is not chunked, or the reassembled bundle does not
+/// match the manifest.
+pub fn restore<W: Write>(bundle: &Path, mut out: W) -> crate::Result<()> {
+    let manifest: Manifest = serde_json::from_slice(&fs::read(manifest_path(bundle))?)?;
+    let root = chunk_root(bundle)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut len = 0;
+    let mut buf = Vec::with_capacity(MAX_CHUNK);
+    for chunk in &manifest.chunks {
+        buf.clear();
+        File::open(chunk_path(&root, &chunk.id))
+            .map_err(|e| anyhow!("chunk {}: {e}", chunk.id))?
+            .read_to_end(&mut buf)?;
+        ensure!(
+            Checksum(blake3::hash(&buf)) == chunk.id,
+            "chunk {} is corrupt",
+            chunk.id
+        );
+        hasher.update(&buf);
+        out.write_all(&buf)?;
+        len += buf.len() as u64;
+    }
+    ensure!(
+        len == manifest.len && Checksum::from(&hasher) == manifest.checksum,
+        "reassembled bundle does not match manifest {}",
+        manifest_path(bundle).display()
+    );
+
+    Ok(())
+}
+
+/// Remove chunks not referred to by any manifest in `bundle_dir`
+///
+/// Returns the number of chunks and bytes removed. If `dry_run` is true,
+/// nothing is actually removed.
+///
+/// Fails if bundles are being stored concurrently, cf. [`store`].
+pub fn gc(bundle_dir: &Path, dry_run: bool) -> crate::Result<(usize, u64)> {
+    let root = bundle_dir.join(DIR);
+    if !root.exists() {
+        return Ok((0, 0));
+    }
+    let _lock = LockedFile::atomic(root.join(GC_LOCK), true, LockedFile::DEFAULT_PERMISSIONS)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                anyhow!(
+                    "garbage collection of {} already in progress",
+                    root.display()
+                )
+            },
+            _ => e.into(),
+        })?;
+    for entry in fs::read_dir(&root)? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(STORE_MARKER)
+        {
+            continue;
+        }
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        ensure!(
+            age > STALE_MARKER,
+            "bundles are being stored in {}, try again later",
+            root.display()
+        );
+        fs::remove_file(entry.path())?;
+    }
+
+    let mut live = BTreeSet::new();
+    for entry in fs::read_dir(bundle_dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == FILE_EXTENSION) {
+            let manifest: Manifest = serde_json::from_slice(&fs::read(&path)?)
+                .map_err(|e| anyhow!("invalid manifest {}: {e}", path.display()))?;
+            live.extend(manifest.chunks.into_iter().map(|c| c.id.to_string()));
+        }
+    }
+
+    let mut removed = 0;
+    let mut removed_len = 0;
+    for shard in fs::read_dir(&root)? {
+        let shard = shard?.path();
+        let pre = match shard.file_name().and_then(|s| s.to_str()) {
+            Some(pre) if shard.is_dir() => pre.to_owned(),
+            _ => continue,
+        };
+        for entry in fs::read_dir(&shard)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let id = format!("{pre}{}", name.to_string_lossy());
+            if !live.contains(&id) {
+                removed += 1;
+                removed_len += entry.metadata()?.len();
+                if !dry_run {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+    }
+
+    Ok((removed, removed_len))
+}
+
+/// Marker of a [`store`] in progress, removed when dropped
+struct Storing {
+    _marker: NamedTempFile,
+}
+
+impl Storing {
+    fn begin(root: &Path) -> crate::Result<Self> {
+        fs::create_dir_all(root)?;
+        let marker = tempfile::Builder::new()
+            .prefix(STORE_MARKER)
+            .tempfile_in(root)?;
+        let lock = root.join(GC_LOCK).with_extension("lock");
+        let start = Instant::now();
+        while lock.exists() {
+            ensure!(
+                start.elapsed() < GC_WAIT,
+                "timed out waiting for garbage collection of {} to finish",
+                root.display()
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(Self { _marker: marker })
+    }
+}
+
+fn chunk_root(bundle: &Path) -> crate::Result<PathBuf> {
+    bundle
+        .parent()
+        .map(|dir| dir.join(DIR))
+        .ok_or_else(|| anyhow!("bundle path has no parent directory"))
+}
+
+fn chunk_path(root: &Path, id: &Checksum) -> PathBuf {
+    let hex = id.to_string();
+    let (pre, rest) = hex.split_at(2);
+    root.join(pre).join(rest)
+}
+
+/// A reader over the chunks of a bundle, opening one chunk file at a time
+pub struct Reader {
+    root: PathBuf,
+    chunks: vec::IntoIter<Chunk>,
+    current: Option<io::Take<File>>,
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.current {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                if file.limit() > 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "chunk file truncated",
+                    ));
+                }
+            }
+            match self.chunks.next() {
+                None => return Ok(0),
+                Some(chunk) => {
+                    let file = File::open(chunk_path(&self.root, &chunk.id))?;
+                    self.current = Some(file.take(chunk.len));
+                },
+            }
+        }
+    }
+}
+
+/// Splits a stream into content-defined chunks
+struct Chunker<R> {
+    inner: R,
+}
+
+impl<R: BufRead> Chunker<R> {
+    fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Read the next chunk into `out`, returning `false` at the end of the
+    /// stream
+    fn next_chunk(&mut self, out: &mut Vec<u8>) -> io::Result<bool> {
+        out.clear();
+        let mut hash: u64 = 0;
+        loop {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(!out.is_empty());
+            }
+            let mut cut = None;
+            for (i, b) in buf.iter().enumerate() {
+                hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+                let len = out.len() + i + 1;
+                if len >= MAX_CHUNK || (len >= MIN_CHUNK && hash & MASK == 0) {
+                    cut = Some(i + 1);
+                    break;
+                }
+            }
+            let take = cut.unwrap_or(buf.len());
+            out.extend_from_slice(&buf[..take]);
+            self.inner.consume(take);
+            if cut.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+}
@@ -29,3 +29,18 @@ pub enum Header {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 }
+
+#[derive(Debug, Error)]
+pub enum Hash {
+    #[error("unknown or unsupported hash algorithm: {0}")]
+    Algorithm(String),
+
+    #[error("hash algorithm {0} is not supported by this build of it, which must be compiled with feature '{1}'")]
+    Disabled(&'static str, &'static str),
+
+    #[error("invalid hash length")]
+    Length,
+
+    #[error("invalid hex hash")]
+    Hex(#[from] hex::FromHexError),
+}
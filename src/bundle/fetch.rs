@@ -6,8 +6,6 @@ use std::{
     io::{
         self,
         Read,
-        Seek,
-        SeekFrom,
         Write,
     },
     path::{
@@ -28,13 +26,13 @@ use url::Url;
 use super::{
     header,
     Expect,
-    Header,
 };
 use crate::{
     bundle,
     fs::LockedFile,
     git,
     io::HashWriter,
+    net,
 };
 
 const MAX_BUNDLE_URIS_BYTES: u64 = 50_000;
@@ -50,16 +48,9 @@ impl Fetched {
     }
 }
 
+#[derive(Default)]
 pub struct Fetcher {
-    agent: ureq::Agent,
-}
-
-impl Default for Fetcher {
-    fn default() -> Self {
-        Self {
-            agent: ureq::agent(),
-        }
-    }
+    _priv: (),
 }
 
 impl Fetcher {
@@ -69,7 +60,7 @@ impl Fetcher {
         out_dir: &Path,
         expect: Expect,
     ) -> crate::Result<Either<bundle::List, Fetched>> {
-        let resp = self.agent.request_url("GET", url).call()?;
+        let resp = net::request("GET", url)?.call()?;
         let mut body = resp.into_reader();
 
         let mut buf = [0; 16];
@@ -92,17 +83,23 @@ impl Fetcher {
                 LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?
             };
 
-            let mut out = HashWriter::new(blake3::Hasher::new(), &mut lck);
+            let mut out =
+                bundle::ParseWriter::new(HashWriter::new(blake3::Hasher::new(), &mut lck));
             out.write_all(&buf)?;
 
-            let len = buf.len() as u64 + io::copy(&mut body.take(expect.len), &mut out)?;
-            let checksum = bundle::Checksum::from(out.hasher());
+            let rest = expect.len.saturating_sub(buf.len() as u64);
+            let len = buf.len() as u64 + io::copy(&mut body.take(rest), &mut out)?;
+            ensure!(
+                len == expect.len,
+                "bundle truncated: expected {} bytes, got {len}",
+                expect.len
+            );
+            let checksum = bundle::Checksum::from(out.get_ref().hasher());
             if let Some(chk) = expect.checksum {
                 ensure!(chk == &checksum, "checksum mismatch");
             }
-            lck.seek(SeekFrom::Start(0))?;
-            let header = Header::from_reader(&mut lck)?;
-            let hash = header.hash();
+            let (header, _) = out.finish()?;
+            let hash = header.hash(expect.hash.algorithm());
 
             lck.persist()?;
 
@@ -0,0 +1,377 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Algorithm-tagged hashes
+//!
+//! The textual and binary forms carry a multihash-style prefix (multicodec
+//! code and digest length), except for SHA-256, which is represented as the
+//! bare digest. This keeps hashes created before other algorithms were
+//! supported valid, and their string forms (and thus file names, URLs and
+//! refnames derived from them) unchanged.
+
+use core::fmt;
+use std::{
+    borrow::Cow,
+    str::FromStr,
+};
+
+use digest::Digest;
+use hex::FromHex;
+use sha2::Sha256;
+
+use super::error;
+
+const DIGEST_LEN: usize = 32;
+
+#[cfg(not(feature = "blake3-hash"))]
+const BLAKE3_DISABLED: error::Hash = error::Hash::Disabled("blake3", "blake3-hash");
+
+/// The algorithms a [`Hash`] can be computed with
+///
+/// BLAKE3 requires the `blake3-hash` feature. Without it, hashes and names of
+/// that algorithm are still recognised, but rejected with
+/// [`error::Hash::Disabled`].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    #[cfg(feature = "blake3-hash")]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// The multicodec code of the algorithm
+    pub const fn code(&self) -> u8 {
+        match self {
+            Self::Sha256 => 0x12,
+            #[cfg(feature = "blake3-hash")]
+            Self::Blake3 => 0x1e,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, error::Hash> {
+        match code {
+            0x12 => Ok(Self::Sha256),
+            #[cfg(feature = "blake3-hash")]
+            0x1e => Ok(Self::Blake3),
+            #[cfg(not(feature = "blake3-hash"))]
+            0x1e => Err(BLAKE3_DISABLED),
+            x => Err(error::Hash::Algorithm(format!("{x:#04x}"))),
+        }
+    }
+
+    /// Whether hashes of this algorithm are represented without prefix
+    const fn is_legacy(&self) -> bool {
+        matches!(self, Self::Sha256)
+    }
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Sha256
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "sha256",
+            #[cfg(feature = "blake3-hash")]
+            Self::Blake3 => "blake3",
+        })
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = error::Hash;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            #[cfg(feature = "blake3-hash")]
+            "blake3" => Ok(Self::Blake3),
+            #[cfg(not(feature = "blake3-hash"))]
+            "blake3" => Err(BLAKE3_DISABLED),
+            x => Err(error::Hash::Algorithm(x.to_owned())),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for HashAlgorithm {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Hash {
+    alg: HashAlgorithm,
+    digest: [u8; DIGEST_LEN],
+}
+
+impl Hash {
+    /// Hash the concatenation of `parts` using `alg`
+    pub fn digest<I, T>(alg: HashAlgorithm, parts: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let digest = match alg {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                for part in parts {
+                    hasher.update(part);
+                }
+                hasher.finalize().into()
+            },
+            #[cfg(feature = "blake3-hash")]
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                for part in parts {
+                    hasher.update(part.as_ref());
+                }
+                hasher.finalize().into()
+            },
+        };
+
+        Self { alg, digest }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.alg
+    }
+
+    /// The raw digest, without algorithm tag
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.digest
+    }
+
+    /// The binary, algorithm-tagged form
+    ///
+    /// For SHA-256, this is just the digest.
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        if self.alg.is_legacy() {
+            Cow::Borrowed(&self.digest)
+        } else {
+            let mut buf = Vec::with_capacity(2 + DIGEST_LEN);
+            buf.extend_from_slice(&[self.alg.code(), DIGEST_LEN as u8]);
+            buf.extend_from_slice(&self.digest);
+            Cow::Owned(buf)
+        }
+    }
+
+    /// Parse the binary, algorithm-tagged form, cf. [`Self::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, error::Hash> {
+        let (alg, digest) = match bytes {
+            digest if digest.len() == DIGEST_LEN => (HashAlgorithm::Sha256, digest),
+            [code, len, digest @ ..] => {
+                if *len as usize != DIGEST_LEN || digest.len() != DIGEST_LEN {
+                    return Err(error::Hash::Length);
+                }
+                (HashAlgorithm::from_code(*code)?, digest)
+            },
+            _ => return Err(error::Hash::Length),
+        };
+
+        Ok(Self {
+            alg,
+            digest: digest.try_into().unwrap(),
+        })
+    }
+
+    pub fn is_valid(hex: &str) -> bool {
+        Self::from_str(hex).is_ok()
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.to_bytes()))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl serde::Serialize for Hash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        crate::serde::display::serialize(self, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Hash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        crate::serde::display::deserialize(deserializer)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = error::Hash;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
+impl FromHex for Hash {
+    type Error = error::Hash;
+
+    fn from_hex<T: AsRef<[u8]>>(hex: T) -> Result<Self, Self::Error> {
+        Self::from_bytes(&hex::decode(hex)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const BLAKE3_EMPTY: &str = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+
+    #[test]
+    fn sha256_is_untagged() {
+        let hash = Hash::digest(HashAlgorithm::Sha256, [b""]);
+        assert_eq!(hash.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(hash.to_string(), SHA256_EMPTY);
+        assert_eq!(hash.to_bytes().len(), DIGEST_LEN);
+        assert_eq!(hash.as_bytes(), &hash.to_bytes()[..]);
+
+        assert_eq!(Hash::from_hex(SHA256_EMPTY).unwrap(), hash);
+        assert_eq!(SHA256_EMPTY.parse::<Hash>().unwrap(), hash);
+        assert_eq!(Hash::from_bytes(&hash.to_bytes()).unwrap(), hash);
+    }
+
+    #[test]
+    fn sha256_tagged() {
+        // A tagged SHA-256 hash is accepted, but not produced
+        let hash = Hash::from_hex(format!("1220{SHA256_EMPTY}")).unwrap();
+        assert_eq!(hash.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(hash.to_string(), SHA256_EMPTY);
+    }
+
+    #[cfg(feature = "blake3-hash")]
+    #[test]
+    fn blake3_is_tagged() {
+        let hash = Hash::digest(HashAlgorithm::Blake3, [b""]);
+        let hex = format!("1e20{BLAKE3_EMPTY}");
+        assert_eq!(hash.algorithm(), HashAlgorithm::Blake3);
+        assert_eq!(hash.to_string(), hex);
+        assert_eq!(hash.to_bytes().len(), 2 + DIGEST_LEN);
+        assert_eq!(hex::encode(hash.as_bytes()), BLAKE3_EMPTY);
+
+        assert_eq!(Hash::from_hex(&hex).unwrap(), hash);
+        assert_eq!(Hash::from_bytes(&hash.to_bytes()).unwrap(), hash);
+        // Without the tag, the digest is taken to be SHA-256
+        assert_eq!(
+            Hash::from_hex(BLAKE3_EMPTY).unwrap().algorithm(),
+            HashAlgorithm::Sha256
+        );
+    }
+
+    #[cfg(not(feature = "blake3-hash"))]
+    #[test]
+    fn blake3_disabled() {
+        assert!(matches!(
+            Hash::from_hex(format!("1e20{BLAKE3_EMPTY}")),
+            Err(error::Hash::Disabled("blake3", "blake3-hash"))
+        ));
+        assert!(matches!(
+            "blake3".parse::<HashAlgorithm>(),
+            Err(error::Hash::Disabled("blake3", "blake3-hash"))
+        ));
+        let e = serde_json::from_str::<HashAlgorithm>(r#""blake3""#).unwrap_err();
+        assert!(e.to_string().contains("blake3-hash"), "{e}");
+    }
+
+    #[test]
+    fn unknown_algorithm() {
+        assert!(matches!(
+            Hash::from_hex(format!("1320{SHA256_EMPTY}")),
+            Err(error::Hash::Algorithm(code)) if code == "0x13"
+        ));
+        assert!(matches!(
+            "sha1".parse::<HashAlgorithm>(),
+            Err(error::Hash::Algorithm(name)) if name == "sha1"
+        ));
+    }
+
+    /// The value of the `X-it-Hash-Algorithm` header
+    #[test]
+    fn algorithm_names() {
+        assert_eq!(HashAlgorithm::default(), HashAlgorithm::Sha256);
+        assert_eq!(HashAlgorithm::Sha256.to_string(), "sha256");
+        assert_eq!(
+            "sha256".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Sha256
+        );
+        assert_eq!(
+            serde_json::to_string(&HashAlgorithm::Sha256).unwrap(),
+            r#""sha256""#
+        );
+        #[cfg(feature = "blake3-hash")]
+        {
+            assert_eq!(HashAlgorithm::Blake3.to_string(), "blake3");
+            assert_eq!(
+                "blake3".parse::<HashAlgorithm>().unwrap(),
+                HashAlgorithm::Blake3
+            );
+            assert_eq!(
+                serde_json::from_str::<HashAlgorithm>(r#""blake3""#).unwrap(),
+                HashAlgorithm::Blake3
+            );
+        }
+        // Names are case-sensitive
+        assert!("SHA256".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn invalid_length() {
+        let digest = hex::decode(SHA256_EMPTY).unwrap();
+        let invalid: [&[u8]; 7] = [
+            &[],
+            &digest[..DIGEST_LEN - 1],
+            &[&digest[..], &[0]].concat(),
+            &[&[0x12, 0x20], &digest[..DIGEST_LEN - 1]].concat(),
+            &[&[0x12, 0x20], &digest[..], &[0]].concat(),
+            &[&[0x1e, 0x20], &digest[..DIGEST_LEN - 1]].concat(),
+            &[&[0x12, 0x21], &digest[..], &[0]].concat(),
+        ];
+        for bytes in invalid {
+            assert!(
+                matches!(Hash::from_bytes(bytes), Err(error::Hash::Length)),
+                "{}",
+                hex::encode(bytes)
+            );
+            assert!(matches!(
+                Hash::from_hex(hex::encode(bytes)),
+                Err(error::Hash::Length)
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_hex() {
+        assert!(matches!(
+            Hash::from_hex(&SHA256_EMPTY[1..]),
+            Err(error::Hash::Hex(_))
+        ));
+        assert!(matches!(
+            Hash::from_hex(SHA256_EMPTY.replace('e', "g")),
+            Err(error::Hash::Hex(_))
+        ));
+        assert!(!Hash::is_valid(""));
+    }
+}
@@ -8,23 +8,18 @@ use std::{
         BTreeSet,
     },
     io,
-    ops::Deref,
-    str::FromStr,
+    mem,
 };
 
-use digest::Digest;
-use hex::{
-    FromHex,
-    FromHexError,
-};
+use hex::FromHex;
 use refs::Refname;
-use sha2::Sha256;
 
-use super::error;
-use crate::{
-    git::refs,
-    io::Lines,
+use super::{
+    error,
+    Hash,
+    HashAlgorithm,
 };
+use crate::git::refs;
 
 pub const SIGNATURE_V2: &str = "# v2 git bundle";
 pub const SIGNATURE_V3: &str = "# v3 git bundle";
@@ -125,6 +120,10 @@ impl From<&git2::Oid> for ObjectId {
     }
 }
 
+/// Convert to a libgit2 object id
+///
+/// The libgit2 version we link against only supports SHA-1 repositories, so
+/// SHA-256 object ids are rejected.
 impl TryFrom<&ObjectId> for git2::Oid {
     type Error = git2::Error;
 
@@ -134,7 +133,7 @@ impl TryFrom<&ObjectId> for git2::Oid {
             ObjectId::Sha2(_) => Err(git2::Error::new(
                 git2::ErrorCode::Invalid,
                 git2::ErrorClass::Sha1,
-                "sha2 oids not yet supported",
+                "sha256 object ids are not supported by the linked libgit2",
             )),
         }
     }
@@ -152,122 +151,26 @@ pub struct Header {
 impl Header {
     /// Parse a [`Header`] from an IO stream.
     ///
-    /// The stream will be buffered internally, and its position set to the
-    /// start of the packfile section.
+    /// The stream is read in chunks, and its position set to the start of the
+    /// packfile section afterwards.
     pub fn from_reader<R>(mut io: R) -> Result<Self, error::Header>
     where
         R: io::Read + io::Seek,
     {
-        use hex::FromHex as _;
-
-        let mut lines = Lines::new(io::BufReader::new(&mut io)).until_blank();
-
-        let mut version: Option<Version> = None;
-        let mut object_format: Option<ObjectFormat> = None;
-        let mut prerequisites = BTreeSet::new();
-        let mut references = BTreeMap::new();
-
-        match lines
-            .next()
-            .ok_or(error::Header::Format("empty input"))??
-            .as_str()
-        {
-            SIGNATURE_V2 => {
-                version = Some(Version::V2);
-                object_format = Some(ObjectFormat::Sha1);
-                Ok(())
-            },
-
-            SIGNATURE_V3 => {
-                version = Some(Version::V2);
-                Ok(())
-            },
-
-            _ => Err(error::Header::Format("invalid signature")),
-        }?;
-
-        if let Some(Version::V3) = version {
-            for capability in lines.by_ref() {
-                let capability = capability?;
-
-                if !capability.starts_with('@') {
-                    return Err(error::Header::Format("expected capabilities"));
-                }
-
-                if capability.starts_with("@filter") {
-                    return Err(error::Header::Format("object filters are not supported"));
-                }
-
-                match capability.strip_prefix("@object-format=") {
-                    Some("sha1") => {
-                        object_format = Some(ObjectFormat::Sha1);
-                    },
-
-                    Some("sha256") => {
-                        object_format = Some(ObjectFormat::Sha256);
-                    },
-
-                    _ => return Err(error::Header::Format("unrecognised capability")),
-                }
-
-                if object_format.is_some() {
-                    break;
-                }
+        let start = io.stream_position()?;
+        let mut parser = Parser::new();
+        let mut buf = [0; 4096];
+        while !parser.is_done() {
+            let n = io.read(&mut buf)?;
+            if n == 0 {
+                break;
             }
+            parser.feed(&buf[..n])?;
         }
+        let (header, len) = parser.finish()?;
+        io.seek(io::SeekFrom::Start(start + len))?;
 
-        let version = version.unwrap();
-        let object_format = object_format.ok_or(error::Header::Format("missing object-format"))?;
-
-        for tip in lines.by_ref() {
-            let mut tip = tip?;
-            let oid_off = usize::from(tip.starts_with('-'));
-            let oid_hexsz = match object_format {
-                ObjectFormat::Sha1 => 40,
-                ObjectFormat::Sha256 => 64,
-            };
-
-            let oid = ObjectId::from_hex(&tip[oid_off..oid_hexsz + oid_off])?;
-            if matches!(
-                (&object_format, &oid),
-                (ObjectFormat::Sha1, ObjectId::Sha2(_)) | (ObjectFormat::Sha256, ObjectId::Sha1(_))
-            ) {
-                return Err(error::Header::ObjectFormat {
-                    fmt: object_format,
-                    oid,
-                });
-            }
-            if !matches!(tip.chars().nth(oid_off + oid_hexsz), None | Some(' ')) {
-                return Err(error::Header::UnrecognisedHeader(tip));
-            }
-
-            if oid_off > 0 {
-                prerequisites.insert(oid);
-            } else {
-                let refname = tip.split_off(oid_off + oid_hexsz + 1);
-                if !refname.starts_with("refs/") {
-                    return Err(error::Header::Format("shorthand refname"));
-                }
-                if references.insert(refname.parse()?, oid).is_some() {
-                    return Err(error::Header::Format("duplicate refname"));
-                }
-            }
-        }
-
-        if references.is_empty() {
-            return Err(error::Header::Format("empty references"));
-        }
-
-        let pos = io::Seek::stream_position(&mut lines)?;
-        drop(lines);
-        io.seek(io::SeekFrom::Start(pos))?;
-
-        Ok(Header {
-            version,
-            object_format,
-            prerequisites,
-            references,
-        })
+        Ok(header)
     }
 
     pub fn to_writer<W>(&self, mut io: W) -> io::Result<()>
@@ -308,56 +211,270 @@ impl Header {
         self.references.insert(name, oid.into())
     }
 
-    pub fn hash(&self) -> Hash {
+    pub fn hash(&self, alg: HashAlgorithm) -> Hash {
         let mut ids: BTreeSet<&ObjectId> = BTreeSet::new();
         ids.extend(self.prerequisites.iter());
         ids.extend(self.references.values());
 
-        let mut sha = Sha256::new();
-        for id in ids {
-            sha.update(id);
-        }
-        Hash(sha.finalize().into())
+        Hash::digest(alg, ids)
     }
 }
 
-#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
-pub struct Hash(#[serde(with = "hex::serde")] [u8; 32]);
+/// Maximum length of a header line
+///
+/// Generous for a refname, but bounds the memory used when fed garbage.
+const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Section {
+    Signature,
+    Capabilities,
+    Tips,
+    Done,
+}
 
-impl Hash {
-    pub fn as_bytes(&self) -> &[u8] {
-        self.deref()
+/// Incremental parser of a bundle [`Header`]
+///
+/// Input is supplied in arbitrarily sized chunks via [`Parser::feed`], so that
+/// a bundle can be validated while it is being received. Lines contained in a
+/// single chunk are parsed in place, only lines spanning chunks are buffered.
+pub struct Parser {
+    section: Section,
+    partial: Vec<u8>,
+    consumed: u64,
+    version: Version,
+    object_format: Option<ObjectFormat>,
+    prerequisites: BTreeSet<ObjectId>,
+    references: BTreeMap<Refname, ObjectId>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            section: Section::Signature,
+            partial: Vec::new(),
+            consumed: 0,
+            version: Version::V2,
+            object_format: None,
+            prerequisites: BTreeSet::new(),
+            references: BTreeMap::new(),
+        }
     }
 
-    pub fn is_valid(hex: &str) -> bool {
-        Self::from_str(hex).is_ok()
+    /// Whether the header is complete
+    pub fn is_done(&self) -> bool {
+        self.section == Section::Done
     }
-}
 
-impl Deref for Hash {
-    type Target = [u8; 32];
+    /// Feed the next chunk of input
+    ///
+    /// Returns the number of bytes of `buf` which belong to the header. Once
+    /// the header is complete, the remainder of `buf` is the start of the
+    /// packfile section, and no further input is consumed.
+    pub fn feed(&mut self, mut buf: &[u8]) -> Result<usize, error::Header> {
+        let mut used = 0;
+        while !self.is_done() && !buf.is_empty() {
+            match buf.iter().position(|b| *b == b'\n') {
+                Some(nl) => {
+                    let line = &buf[..nl];
+                    if self.partial.is_empty() {
+                        self.line(line)?;
+                    } else {
+                        let mut partial = mem::take(&mut self.partial);
+                        partial.extend_from_slice(line);
+                        if partial.len() > MAX_LINE_LEN {
+                            return Err(error::Header::Format("line too long"));
+                        }
+                        self.line(&partial)?;
+                    }
+                    used += nl + 1;
+                    buf = &buf[nl + 1..];
+                },
+                None => {
+                    if self.partial.len() + buf.len() > MAX_LINE_LEN {
+                        return Err(error::Header::Format("line too long"));
+                    }
+                    self.partial.extend_from_slice(buf);
+                    used += buf.len();
+                    buf = &[];
+                },
+            }
+        }
+        self.consumed += used as u64;
+
+        Ok(used)
+    }
+
+    /// Finish parsing
+    ///
+    /// Returns the header along with the offset of the packfile section
+    /// relative to the start of the input. Fails if the header is incomplete.
+    pub fn finish(self) -> Result<(Header, u64), error::Header> {
+        match self.section {
+            Section::Done => {},
+            Section::Signature if self.consumed == 0 => {
+                return Err(error::Header::Format("empty input"))
+            },
+            _ => return Err(error::Header::Format("truncated header")),
+        }
+        let header = Header {
+            version: self.version,
+            // Set at the latest when leaving the capabilities section
+            object_format: self.object_format.unwrap(),
+            prerequisites: self.prerequisites,
+            references: self.references,
+        };
+
+        Ok((header, self.consumed))
+    }
+
+    fn line(&mut self, line: &[u8]) -> Result<(), error::Header> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = std::str::from_utf8(line).map_err(|_| error::Header::Format("invalid utf8"))?;
+        match self.section {
+            Section::Signature => match line {
+                SIGNATURE_V2 => {
+                    self.version = Version::V2;
+                    self.object_format = Some(ObjectFormat::Sha1);
+                    self.section = Section::Tips;
+                    Ok(())
+                },
+                SIGNATURE_V3 => {
+                    self.version = Version::V3;
+                    self.section = Section::Capabilities;
+                    Ok(())
+                },
+                _ => Err(error::Header::Format("invalid signature")),
+            },
+
+            Section::Capabilities => match line.strip_prefix('@') {
+                Some(capability) => {
+                    if capability.starts_with("filter") {
+                        return Err(error::Header::Format("object filters are not supported"));
+                    }
+                    self.object_format = match capability.strip_prefix("object-format=") {
+                        Some("sha1") => Some(ObjectFormat::Sha1),
+                        Some("sha256") => Some(ObjectFormat::Sha256),
+                        _ => return Err(error::Header::Format("unrecognised capability")),
+                    };
+                    Ok(())
+                },
+                None => {
+                    if self.object_format.is_none() {
+                        return Err(error::Header::Format("missing object-format"));
+                    }
+                    self.section = Section::Tips;
+                    self.line(line.as_bytes())
+                },
+            },
+
+            Section::Tips if line.is_empty() => {
+                if self.references.is_empty() {
+                    return Err(error::Header::Format("empty references"));
+                }
+                self.section = Section::Done;
+                Ok(())
+            },
+            Section::Tips => self.tip(line),
+
+            Section::Done => unreachable!("no input consumed after header is done"),
+        }
+    }
+
+    fn tip(&mut self, tip: &str) -> Result<(), error::Header> {
+        let object_format = self
+            .object_format
+            .as_ref()
+            .expect("object-format known before tips");
+        let unrecognised = || error::Header::UnrecognisedHeader(tip.to_owned());
+
+        let (prerequisite, rest) = match tip.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, tip),
+        };
+        let oid_hexsz = match object_format {
+            ObjectFormat::Sha1 => 40,
+            ObjectFormat::Sha256 => 64,
+        };
+        let hex = rest.get(..oid_hexsz).ok_or_else(unrecognised)?;
+        let oid = ObjectId::from_hex(hex)?;
+        if matches!(
+            (object_format, &oid),
+            (ObjectFormat::Sha1, ObjectId::Sha2(_)) | (ObjectFormat::Sha256, ObjectId::Sha1(_))
+        ) {
+            return Err(error::Header::ObjectFormat {
+                fmt: self.object_format.take().unwrap(),
+                oid,
+            });
+        }
+        let rest = &rest[oid_hexsz..];
+
+        if prerequisite {
+            // Prerequisites may carry a comment
+            if !(rest.is_empty() || rest.starts_with(' ')) {
+                return Err(unrecognised());
+            }
+            self.prerequisites.insert(oid);
+        } else {
+            let refname = rest.strip_prefix(' ').ok_or_else(unrecognised)?;
+            if !refname.starts_with("refs/") {
+                return Err(error::Header::Format("shorthand refname"));
+            }
+            if self.references.insert(refname.parse()?, oid).is_some() {
+                return Err(error::Header::Format("duplicate refname"));
+            }
+        }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        Ok(())
     }
 }
 
-impl fmt::Display for Hash {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_str(&hex::encode(self.0))
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl fmt::Debug for Hash {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&hex::encode(self.0))
+/// A [`io::Write`] which parses the [`Header`] of the bundle written through
+/// it
+///
+/// Writing fails as soon as the header is found to be invalid, so a bundle
+/// received over the network can be rejected without reading it in full.
+pub struct ParseWriter<W> {
+    parser: Parser,
+    inner: W,
+}
+
+impl<W> ParseWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            parser: Parser::new(),
+            inner,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Finish parsing, see [`Parser::finish`]
+    pub fn finish(self) -> Result<(Header, u64), error::Header> {
+        self.parser.finish()
     }
 }
 
-impl FromStr for Hash {
-    type Err = FromHexError;
+impl<W: io::Write> io::Write for ParseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if !self.parser.is_done() {
+            self.parser
+                .feed(&buf[..n])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(n)
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        <[u8; 32]>::from_hex(s).map(Self)
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
@@ -0,0 +1,178 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Streaming inspection of packfiles
+//!
+//! Indexing a pack requires writing it (and any delta bases needed to complete
+//! a thin pack) to disk. [`scan`] instead walks the pack object by object,
+//! inflating each only to determine where the next one starts, so that limits
+//! can be enforced before committing to indexing it.
+
+use std::{
+    collections::HashMap,
+    io::{
+        self,
+        BufRead,
+        Read,
+    },
+};
+
+use anyhow::{
+    bail,
+    ensure,
+};
+use flate2::bufread::ZlibDecoder;
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// The kind of a packed object
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+    /// A delta against an object not (yet) known to be in the pack
+    Unknown,
+}
+
+/// An object encountered by [`scan`]
+#[derive(Clone, Copy, Debug)]
+pub struct Object {
+    pub kind: Kind,
+    /// Size of the object (or delta) when inflated
+    pub size: u64,
+}
+
+/// Walk the pack read from `r`, calling `f` for every object in order
+///
+/// Deltas against an object earlier in the pack (`OFS_DELTA`) are reported with
+/// the kind of their base, deltas against an object referred to by id
+/// (`REF_DELTA`) as [`Kind::Unknown`]. Counting the objects of a particular
+/// kind thus yields a lower bound.
+///
+/// Scanning stops at the first error returned by `f`. The trailing checksum is
+/// not verified, which is left to indexing.
+///
+/// Returns the number of objects announced in the pack header.
+pub fn scan<R, F>(r: R, mut f: F) -> crate::Result<u32>
+where
+    R: BufRead,
+    F: FnMut(&Object) -> crate::Result<()>,
+{
+    let mut r = Counting { inner: r, pos: 0 };
+
+    let mut hdr = [0; 12];
+    r.read_exact(&mut hdr)?;
+    ensure!(&hdr[..4] == b"PACK", "not a packfile");
+    let version = u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]);
+    ensure!(
+        version == 2 || version == 3,
+        "unsupported pack version {version}"
+    );
+    let count = u32::from_be_bytes([hdr[8], hdr[9], hdr[10], hdr[11]]);
+
+    let mut kinds = HashMap::new();
+    for _ in 0..count {
+        let offset = r.pos;
+        let (typ, size) = read_type_and_size(&mut r)?;
+        let kind = match typ {
+            OBJ_COMMIT => Kind::Commit,
+            OBJ_TREE => Kind::Tree,
+            OBJ_BLOB => Kind::Blob,
+            OBJ_TAG => Kind::Tag,
+            OBJ_OFS_DELTA => {
+                let rel = read_ofs(&mut r)?;
+                let base = offset
+                    .checked_sub(rel)
+                    .ok_or_else(|| anyhow::anyhow!("delta base offset out of range"))?;
+                kinds.get(&base).copied().unwrap_or(Kind::Unknown)
+            },
+            OBJ_REF_DELTA => {
+                let mut base = [0; 20];
+                r.read_exact(&mut base)?;
+                Kind::Unknown
+            },
+            x => bail!("invalid object type {x} at offset {offset}"),
+        };
+
+        let inflated = io::copy(&mut ZlibDecoder::new(&mut r), &mut io::sink())?;
+        ensure!(
+            inflated == size,
+            "object at offset {offset} inflates to {inflated} bytes, expected {size}"
+        );
+        if kind != Kind::Unknown {
+            kinds.insert(offset, kind);
+        }
+        f(&Object { kind, size })?;
+    }
+
+    Ok(count)
+}
+
+fn read_type_and_size<R: Read>(mut r: R) -> crate::Result<(u8, u64)> {
+    let mut byte = read_u8(&mut r)?;
+    let typ = (byte >> 4) & 0x7;
+    let mut size = u64::from(byte & 0xf);
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        ensure!(shift < 64, "object size overflow");
+        byte = read_u8(&mut r)?;
+        size |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+    }
+
+    Ok((typ, size))
+}
+
+fn read_ofs<R: Read>(mut r: R) -> crate::Result<u64> {
+    let mut byte = read_u8(&mut r)?;
+    let mut ofs = u64::from(byte & 0x7f);
+    while byte & 0x80 != 0 {
+        byte = read_u8(&mut r)?;
+        ofs = ofs
+            .checked_add(1)
+            .and_then(|ofs| ofs.checked_mul(128))
+            .ok_or_else(|| anyhow::anyhow!("delta base offset overflow"))?
+            | u64::from(byte & 0x7f);
+    }
+
+    Ok(ofs)
+}
+
+fn read_u8<R: Read>(mut r: R) -> io::Result<u8> {
+    let mut buf = [0];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Tracks the position in the stream, which is needed to resolve `OFS_DELTA`
+/// bases
+struct Counting<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: BufRead> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Counting<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.pos += amt as u64;
+    }
+}
@@ -19,32 +19,70 @@ pub mod paths {
         Path::new("it/bundles")
     }
 
+    /// Path where to store unfinished chunked uploads, see
+    /// [`crate::patches::upload`]
+    ///
+    /// This is a relative path, to be treated as relative to GIT_DIR.
+    pub fn uploads() -> &'static Path {
+        Path::new("it/uploads")
+    }
+
+    /// Path of the persisted [`crate::metadata::ancestry::Index`]
+    ///
+    /// `None` if no home directory could be determined.
+    pub fn ancestry_index() -> Option<PathBuf> {
+        ProjectDirs::from("io", "eagain", "it").map(|dirs| dirs.cache_dir().join("ancestry.json"))
+    }
+
     fn project_dirs() -> ProjectDirs {
         ProjectDirs::from("io", "eagain", "it").expect("no valid $HOME")
     }
 }
 
 pub mod git {
-    use std::path::Path;
+    use std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+        },
+        env,
+        fmt::Write as _,
+        fs,
+        io::{
+            self,
+            Write as _,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+    };
 
     use anyhow::{
         anyhow,
         bail,
         ensure,
+        Context as _,
     };
+    use regex::Regex;
+    use url::Url;
     use zeroize::Zeroizing;
 
     use crate::{
+        bundle,
         git::{
             self,
             if_not_found_none,
             Refname,
         },
+        gpg,
         keys::{
             Agent,
             Signer,
+            VerificationKey,
         },
         metadata::IdentityId,
+        patches,
         ssh::{
             self,
             agent,
@@ -53,6 +91,9 @@ pub mod git {
 
     /// Last resort to override the signing key, if neither [`USER_SIGNING_KEY`]
     /// nor [`SSH_KEY_COMMAND`] will cut it.
+    ///
+    /// A value of the form `gpg::<KEYID>` selects a GnuPG key, see
+    /// [`crate::gpg`].
     pub const IT_SIGNING_KEY: &str = "it.signingKey";
     /// The default `it` identity to use.
     pub const IT_ID: &str = "it.id";
@@ -65,17 +106,148 @@ pub mod git {
     ///
     /// [`user.signingKey`]: https://git-scm.com/docs/git-config#Documentation/git-config.txt-usersigningKey
     pub const USER_SIGNING_KEY: &str = "user.signingKey";
+    /// The signature format, see [`gpg.format`]
+    ///
+    /// If set to "openpgp", [`USER_SIGNING_KEY`] is taken to be a GnuPG key
+    /// id. Otherwise, including if not set, it is taken to be an SSH key.
+    ///
+    /// [`gpg.format`]: https://git-scm.com/docs/git-config#Documentation/git-config.txt-gpgformat
+    pub const GPG_FORMAT: &str = "gpg.format";
+    /// The GnuPG program to use, see [`gpg.program`]
+    ///
+    /// `gpg.openpgp.program` takes precedence. If neither is set, "gpg" is
+    /// used.
+    ///
+    /// [`gpg.program`]: https://git-scm.com/docs/git-config#Documentation/git-config.txt-gpgprogram
+    pub const GPG_PROGRAM: &str = "gpg.program";
+    pub const GPG_OPENPGP_PROGRAM: &str = "gpg.openpgp.program";
     /// The default branch name, see [`init.defaultBranch`]
     ///
     /// If not set, the default branch is "master".
     ///
     /// [`init.defaultBranch`]: https://git-scm.com/docs/git-config#Documentation/git-config.txt-initdefaultBranch
     pub const DEFAULT_BRANCH: &str = "init.defaultBranch";
+    /// Number of accepted patches after which to run repository maintenance
+    ///
+    /// Maintenance consists of repacking, and writing a commit-graph and
+    /// multi-pack-index. Zero disables maintenance. If not set, the default
+    /// is [`DEFAULT_MAINTENANCE_INTERVAL`].
+    pub const IT_MAINTENANCE_INTERVAL: &str = "it.maintenanceInterval";
+    pub const DEFAULT_MAINTENANCE_INTERVAL: u64 = 100;
+    /// Executable to run before accepting a patch, see [`patches::Hooks`]
+    pub const IT_PRE_ACCEPT_HOOK: &str = "it.preAcceptHook";
+    /// Executable to run after accepting a patch, see [`patches::Hooks`]
+    pub const IT_POST_ACCEPT_HOOK: &str = "it.postAcceptHook";
+    /// Executable to run after quarantining a patch, see [`patches::Hooks`]
+    pub const IT_QUARANTINE_HOOK: &str = "it.quarantineHook";
+    /// Minimum number of seconds between automated replies to first-time
+    /// submitters on the same topic, see [`patches::AutoReply`]
+    ///
+    /// If not set, the default is [`DEFAULT_AUTO_REPLY_INTERVAL`].
+    pub const IT_AUTO_REPLY_INTERVAL: &str = "it.autoReplyInterval";
+    pub const DEFAULT_AUTO_REPLY_INTERVAL: u64 = 60;
+    /// Whether to hold patches by first-time submitters for review, see
+    /// [`patches::AcceptOptions`]
+    ///
+    /// If not set, patches are recorded immediately.
+    pub const IT_QUARANTINE_NEW: &str = "it.quarantineNewSubmitters";
+    /// Whether to accept patches with encrypted packdata, see
+    /// [`patches::AcceptOptions`]
+    ///
+    /// Encrypted patches are recorded, but can not be unbundled by the drop.
+    /// If not set, they are rejected.
+    pub const IT_ALLOW_ENCRYPTED: &str = "it.allowEncrypted";
+    pub const IT_DEDUP_SNAPSHOTS: &str = "it.dedupSnapshots";
+    pub const IT_SPAM_POLICY: &str = "it.spamPolicy";
+    pub const IT_AUDIT_LOG: &str = "it.auditLog";
+    /// Regular expression the subject line of each commit of a submitted
+    /// patch must match, see [`patches::lint::Rules`]
+    pub const IT_LINT_SUBJECT_PATTERN: &str = "it.lint.subjectPattern";
+    /// Maximum number of characters of the subject line of each commit of a
+    /// submitted patch, see [`patches::lint::Rules`]
+    pub const IT_LINT_MAX_SUBJECT_LENGTH: &str = "it.lint.maxSubjectLength";
+    /// Trailer each commit of a submitted patch must carry, eg.
+    /// `Signed-off-by`, see [`patches::lint::Rules`]
+    ///
+    /// This is a multi-valued key.
+    pub const IT_LINT_REQUIRE_TRAILER: &str = "it.lint.requireTrailer";
+    /// Whether to materialise the refs of accepted patches, see
+    /// [`patches::AcceptOptions`]
+    ///
+    /// Drops which only archive bundles may set this to false, and run `drop
+    /// unbundle --record` when the refs of a record are needed. If not set,
+    /// patches are unbundled immediately.
+    pub const IT_UNBUNDLE: &str = "it.unbundle";
+    /// The [`bundle::HashAlgorithm`] to use for new patch bundles
+    ///
+    /// If not set, "sha256" is used.
+    pub const IT_HASH_ALGORITHM: &str = "it.hashAlgorithm";
+    /// Proxy to use for all outbound HTTP requests
+    ///
+    /// Accepts 'http://', 'socks4://', 'socks4a://' and 'socks5://' URLs. A
+    /// SOCKS proxy is required to reach onion services. The `IT_PROXY`
+    /// environment variable takes precedence. Only the global and system
+    /// configuration is consulted, as it applies before a repository is
+    /// opened.
+    pub const IT_PROXY: &str = "it.proxy";
+    /// DNS-over-HTTPS resolver used to discover drops by domain name
+    ///
+    /// Must support the JSON API (`application/dns-json`).
+    pub const IT_DNS_RESOLVER: &str = "it.dnsResolver";
+    pub const DEFAULT_DNS_RESOLVER: &str = "https://cloudflare-dns.com/dns-query";
+    /// Base URL to fetch bundles from, see `drop bundles sync`
+    pub const IT_BUNDLE_URL: &str = "it.bundleUrl";
+    /// Topics to be notified about, see [`patches::subscriptions`]
+    ///
+    /// This is a multi-valued key, managed by `topic subscribe` and `topic
+    /// unsubscribe`.
+    pub const IT_SUBSCRIBE: &str = "it.subscribe";
+    /// Executable to run when new records land on subscribed topics
+    ///
+    /// Invoked with a JSON [`patches::subscriptions::News`] on stdin. If not
+    /// set, a digest is printed instead.
+    pub const IT_NOTIFY_HOOK: &str = "it.notifyHook";
+    /// Whether to cache rendered notes for `topic show`
+    ///
+    /// The cache is kept below `$GIT_DIR/it/cache`, and invalidated when the
+    /// topic ref moves. If not set, no cache is used.
+    pub const IT_TOPIC_CACHE: &str = "it.topicCache";
+    /// Drops an identity may submit patches to, see [`AllowedDrop`]
+    ///
+    /// This is a multi-valued key in the subsection named after the identity,
+    /// ie. `it.<ID>.allowedDrop`. If not set for an identity, patches may be
+    /// submitted to any drop.
+    pub const IT_ALLOWED_DROP: &str = "allowedDrop";
+    /// Directory identity repositories advertised by drops are resolved
+    /// against
+    ///
+    /// The drop metadata may come from a remote, so advertised repositories
+    /// located outside of this directory are ignored. Relative paths are
+    /// resolved against $GIT_DIR. If not set, only repositories within the
+    /// drop repository itself are consulted.
+    pub const IT_ID_PATH_ROOT: &str = "it.idPathRoot";
+    /// Path to a drop accepting composite patches together with this one, see
+    /// [`patches::composite`]
+    ///
+    /// Set in the subsection named after the part the drop accepts, ie.
+    /// `it.<NAME>.siblingDrop`. Relative paths are resolved against $GIT_DIR.
+    /// The drop itself must be listed, too.
+    pub const IT_SIBLING_DROP: &str = "siblingDrop";
+    /// URL to notify when a patch is accepted, see
+    /// [`patches::hooks::webhook`]
+    ///
+    /// Set in the subsection named after the webhook, ie. `it.<NAME>.webhook`,
+    /// and managed by `drop hooks`.
+    pub const IT_WEBHOOK: &str = "webhook";
+    /// Secret to sign the payload sent to the webhook of the same subsection
+    /// with, ie. `it.<NAME>.webhookSecret`
+    pub const IT_WEBHOOK_SECRET: &str = "webhookSecret";
 
     #[allow(clippy::large_enum_variant)]
     pub enum Key {
         Secret(ssh::PrivateKey),
         Public(ssh::PublicKey),
+        Gpg(gpg::Key),
     }
 
     impl Key {
@@ -83,13 +255,212 @@ pub mod git {
             match self {
                 Self::Secret(sk) => sk.public_key(),
                 Self::Public(pk) => pk,
+                Self::Gpg(key) => &key.public,
+            }
+        }
+    }
+
+    /// Open the configuration of `repo`
+    ///
+    /// Unlike [`git2::Repository::config`], this honours the same sources as
+    /// git itself, in order of increasing precedence:
+    ///
+    /// * the system configuration, or `$GIT_CONFIG_SYSTEM` (skipped if
+    ///   `$GIT_CONFIG_NOSYSTEM` is true)
+    /// * the global and XDG configuration, or `$GIT_CONFIG_GLOBAL`
+    /// * the repository configuration
+    /// * the per-worktree configuration, if `extensions.worktreeConfig` is set
+    /// * `$GIT_CONFIG_COUNT` / `$GIT_CONFIG_KEY_<n>` / `$GIT_CONFIG_VALUE_<n>`
+    ///   and `$GIT_CONFIG_PARAMETERS` (as set by `git -c`)
+    ///
+    /// The returned configuration is a read-only snapshot. Modifications must
+    /// go through the [`git2::Config`] of the desired level.
+    pub fn open(repo: &git2::Repository) -> crate::Result<git2::Config> {
+        build(Some(repo))
+    }
+
+    /// Like [`open`], but without any repository configuration
+    pub fn open_default() -> crate::Result<git2::Config> {
+        build(None)
+    }
+
+    fn build(repo: Option<&git2::Repository>) -> crate::Result<git2::Config> {
+        let mut cfg = git2::Config::new()?;
+
+        if !env_bool("GIT_CONFIG_NOSYSTEM")? {
+            match env::var_os("GIT_CONFIG_SYSTEM") {
+                Some(path) => add_file(&mut cfg, Path::new(&path), git2::ConfigLevel::System)?,
+                None => {
+                    if let Some(path) = if_not_found_none(git2::Config::find_system())? {
+                        add_file(&mut cfg, &path, git2::ConfigLevel::System)?;
+                    }
+                },
+            }
+        }
+        match env::var_os("GIT_CONFIG_GLOBAL") {
+            Some(path) => add_file(&mut cfg, Path::new(&path), git2::ConfigLevel::Global)?,
+            None => {
+                if let Some(path) = if_not_found_none(git2::Config::find_xdg())? {
+                    add_file(&mut cfg, &path, git2::ConfigLevel::XDG)?;
+                }
+                if let Some(path) = if_not_found_none(git2::Config::find_global())? {
+                    add_file(&mut cfg, &path, git2::ConfigLevel::Global)?;
+                }
+            },
+        }
+
+        let mut worktree = None;
+        if let Some(repo) = repo {
+            let local = common_dir(repo)?.join("config");
+            add_file(&mut cfg, &local, git2::ConfigLevel::Local)?;
+            let enabled = if_not_found_none(
+                git2::Config::open(&local)?.get_bool("extensions.worktreeConfig"),
+            )?
+            .unwrap_or(false);
+            if enabled {
+                worktree = Some(repo.path().join("config.worktree"));
+            }
+        }
+
+        // libgit2 has no in-memory backend, nor a level between the repository
+        // and the application configuration. Hence, the per-worktree
+        // configuration and the overrides from the environment are combined in
+        // a temporary file, which is no longer needed after taking a snapshot.
+        let overrides = env_overrides()?;
+        let tmp = if worktree.is_some() || !overrides.is_empty() {
+            let mut buf = String::new();
+            if let Some(path) = &worktree {
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("worktree config path not utf8"))?;
+                writeln!(buf, "[include]\n\tpath = {}", quote(path))?;
+            }
+            for (key, val) in &overrides {
+                let (section, name) = key
+                    .rsplit_once('.')
+                    .filter(|(section, name)| !section.is_empty() && !name.is_empty())
+                    .ok_or_else(|| anyhow!("invalid config key in environment: {key}"))?;
+                match section.split_once('.') {
+                    Some((section, sub)) => writeln!(buf, "[{section} {}]", quote(sub))?,
+                    None => writeln!(buf, "[{section}]")?,
+                }
+                match val {
+                    Some(val) => writeln!(buf, "\t{name} = {}", quote(val))?,
+                    None => writeln!(buf, "\t{name}")?,
+                }
+            }
+            let mut tmp = tempfile::NamedTempFile::new()?;
+            tmp.write_all(buf.as_bytes())?;
+            tmp.flush()?;
+            cfg.add_file(tmp.path(), git2::ConfigLevel::App, false)?;
+            Some(tmp)
+        } else {
+            None
+        };
+        let snapshot = cfg.snapshot()?;
+        drop(tmp);
+
+        Ok(snapshot)
+    }
+
+    /// The directory shared by all worktrees of `repo`
+    fn common_dir(repo: &git2::Repository) -> io::Result<PathBuf> {
+        let git_dir = repo.path();
+        match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common) => Ok(git_dir.join(common.trim_end())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(git_dir.to_owned()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add_file(
+        cfg: &mut git2::Config,
+        path: &Path,
+        level: git2::ConfigLevel,
+    ) -> crate::Result<()> {
+        Ok(cfg.add_file(path, level, false)?)
+    }
+
+    /// Configuration given via the environment, in order of precedence
+    ///
+    /// A value of `None` denotes a key given without a value, which is
+    /// interpreted as boolean true.
+    fn env_overrides() -> crate::Result<Vec<(String, Option<String>)>> {
+        let mut overrides = Vec::new();
+
+        if let Some(count) = env_var("GIT_CONFIG_COUNT")? {
+            let count: usize = count
+                .parse()
+                .map_err(|_| anyhow!("GIT_CONFIG_COUNT is not a number: {count}"))?;
+            for i in 0..count {
+                let key = env_var(&format!("GIT_CONFIG_KEY_{i}"))?
+                    .ok_or_else(|| anyhow!("missing config key GIT_CONFIG_KEY_{i}"))?;
+                let val = env_var(&format!("GIT_CONFIG_VALUE_{i}"))?
+                    .ok_or_else(|| anyhow!("missing config value GIT_CONFIG_VALUE_{i}"))?;
+                overrides.push((key, Some(val)));
+            }
+        }
+
+        if let Some(params) = env_var("GIT_CONFIG_PARAMETERS")? {
+            let params = shlex::split(&params)
+                .ok_or_else(|| anyhow!("bogus format in GIT_CONFIG_PARAMETERS"))?;
+            for param in params {
+                match param.split_once('=') {
+                    Some((key, val)) => overrides.push((key.to_owned(), Some(val.to_owned()))),
+                    None => overrides.push((param, None)),
+                }
+            }
+        }
+
+        Ok(overrides)
+    }
+
+    fn env_var(name: &str) -> crate::Result<Option<String>> {
+        match env::var(name) {
+            Ok(val) => Ok(Some(val)),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(anyhow!("{name}: {e}")),
+        }
+    }
+
+    fn env_bool(name: &str) -> crate::Result<bool> {
+        match env_var(name)?
+            .as_deref()
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            None | Some("") | Some("0") | Some("false") | Some("no") | Some("off") => Ok(false),
+            Some("1") | Some("true") | Some("yes") | Some("on") => Ok(true),
+            Some(other) => bail!("{name}: invalid boolean value {other}"),
+        }
+    }
+
+    /// Quote `s` as a gitconfig value or subsection name
+    fn quote(s: &str) -> String {
+        let mut quoted = String::with_capacity(s.len() + 2);
+        quoted.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                c => quoted.push(c),
             }
         }
+        quoted.push('"');
+        quoted
     }
 
     pub fn signing_key(c: &git2::Config) -> crate::Result<Option<Key>> {
         match if_not_found_none(c.get_string(IT_SIGNING_KEY))? {
-            Some(v) => ssh_signing_key_from_config_value(v).map(Some),
+            Some(v) => match v.strip_prefix("gpg::") {
+                Some(spec) => gpg::Key::lookup(&gpg_program(c)?, spec)
+                    .map(Key::Gpg)
+                    .map(Some),
+                None => ssh_signing_key_from_config_value(v).map(Some),
+            },
+            None if is_openpgp(c)? => gpg_signing_key(c),
             None => ssh_signing_key(c)
                 .transpose()
                 .or_else(|| ssh_key_command(c).transpose())
@@ -107,6 +478,20 @@ pub mod git {
                 let client = agent::Client::from_env()?;
                 Ok(Box::new(Agent::new(client, pk.into())))
             },
+            Key::Gpg(key) => {
+                let client = gpg::agent()
+                    .with_context(|| format!("unable to sign with gpg key {}", key.spec))?;
+                Ok(Box::new(Agent::new(client, key.public.into())))
+            },
+            // The private part of security keys stays on the token, so we
+            // can only sign via the agent
+            Key::Secret(sk) if VerificationKey::from(sk.public_key()).is_security_key() => {
+                let client = agent::Client::from_env()
+                    .context("signing with a security key requires ssh-agent")?;
+                let mut agent = Agent::new(client, sk.public_key().into());
+                agent.ensure_key()?;
+                Ok(Box::new(agent))
+            },
             Key::Secret(sk) => {
                 if sk.is_encrypted() {
                     let prompt = format!(
@@ -159,6 +544,25 @@ pub mod git {
         }
     }
 
+    /// Whether git is configured to sign with GnuPG, see [`GPG_FORMAT`]
+    pub fn is_openpgp(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_string(GPG_FORMAT))?.as_deref() == Some("openpgp"))
+    }
+
+    pub fn gpg_program(cfg: &git2::Config) -> crate::Result<String> {
+        let prog = match if_not_found_none(cfg.get_string(GPG_OPENPGP_PROGRAM))? {
+            Some(prog) => Some(prog),
+            None => if_not_found_none(cfg.get_string(GPG_PROGRAM))?,
+        };
+        Ok(prog.unwrap_or_else(|| gpg::DEFAULT_PROGRAM.to_owned()))
+    }
+
+    pub fn gpg_signing_key(cfg: &git2::Config) -> crate::Result<Option<Key>> {
+        if_not_found_none(cfg.get_string(USER_SIGNING_KEY))?
+            .map(|spec| gpg::Key::lookup(&gpg_program(cfg)?, &spec).map(Key::Gpg))
+            .transpose()
+    }
+
     pub fn ssh_key_command(cfg: &git2::Config) -> crate::Result<Option<Key>> {
         let out = git::config_command(cfg, SSH_KEY_COMMAND)?;
         let key = out
@@ -170,6 +574,211 @@ pub mod git {
         Ok(key)
     }
 
+    pub fn maintenance_interval(cfg: &git2::Config) -> crate::Result<u64> {
+        let interval = if_not_found_none(cfg.get_i64(IT_MAINTENANCE_INTERVAL))?
+            .map(u64::try_from)
+            .transpose()
+            .map_err(|_| anyhow!("{IT_MAINTENANCE_INTERVAL} must not be negative"))?;
+
+        Ok(interval.unwrap_or(DEFAULT_MAINTENANCE_INTERVAL))
+    }
+
+    pub fn auto_reply_interval(cfg: &git2::Config) -> crate::Result<u64> {
+        let interval = if_not_found_none(cfg.get_i64(IT_AUTO_REPLY_INTERVAL))?
+            .map(u64::try_from)
+            .transpose()
+            .map_err(|_| anyhow!("{IT_AUTO_REPLY_INTERVAL} must not be negative"))?;
+
+        Ok(interval.unwrap_or(DEFAULT_AUTO_REPLY_INTERVAL))
+    }
+
+    pub fn dns_resolver(cfg: &git2::Config) -> crate::Result<Url> {
+        let url = if_not_found_none(cfg.get_string(IT_DNS_RESOLVER))?;
+        Ok(Url::parse(url.as_deref().unwrap_or(DEFAULT_DNS_RESOLVER))?)
+    }
+
+    pub fn accept_hooks(cfg: &git2::Config) -> crate::Result<patches::Hooks> {
+        Ok(patches::Hooks {
+            pre_accept: if_not_found_none(cfg.get_path(IT_PRE_ACCEPT_HOOK))?,
+            post_accept: if_not_found_none(cfg.get_path(IT_POST_ACCEPT_HOOK))?,
+            quarantine: if_not_found_none(cfg.get_path(IT_QUARANTINE_HOOK))?,
+            webhooks: webhooks(cfg)?,
+        })
+    }
+
+    pub fn webhooks(cfg: &git2::Config) -> crate::Result<Vec<patches::hooks::Webhook>> {
+        let suffix = format!(".{}", IT_WEBHOOK.to_lowercase());
+        let mut hooks = Vec::new();
+        let mut iter = cfg.entries(Some(&format!("it\\..*\\{suffix}$")))?;
+        while let Some(entry) = iter.next() {
+            let entry = entry?;
+            let key = entry.name().ok_or_else(|| anyhow!("config key not utf8"))?;
+            let name = key
+                .strip_prefix("it.")
+                .and_then(|key| key.strip_suffix(&suffix))
+                .ok_or_else(|| anyhow!("unexpected config key {key}"))?;
+            patches::hooks::webhook::validate_name(name)?;
+            let url = entry
+                .value()
+                .ok_or_else(|| anyhow!("value for {key} not utf8"))?
+                .parse()
+                .map_err(|e| anyhow!("{key}: {e}"))?;
+            let secret =
+                if_not_found_none(cfg.get_string(&format!("it.{name}.{IT_WEBHOOK_SECRET}")))?;
+            hooks.push(patches::hooks::Webhook {
+                name: name.to_owned(),
+                url,
+                secret,
+            });
+        }
+
+        Ok(hooks)
+    }
+
+    pub fn lint_rules(cfg: &git2::Config) -> crate::Result<patches::lint::Rules> {
+        let subject_pattern = if_not_found_none(cfg.get_string(IT_LINT_SUBJECT_PATTERN))?
+            .map(|pat| Regex::new(&pat).map_err(|e| anyhow!("{IT_LINT_SUBJECT_PATTERN}: {e}")))
+            .transpose()?;
+        let max_subject_len = if_not_found_none(cfg.get_i64(IT_LINT_MAX_SUBJECT_LENGTH))?
+            .map(usize::try_from)
+            .transpose()
+            .map_err(|_| anyhow!("{IT_LINT_MAX_SUBJECT_LENGTH} must not be negative"))?;
+        let mut required_trailers = Vec::new();
+        let mut iter = cfg.multivar(IT_LINT_REQUIRE_TRAILER, None)?;
+        while let Some(entry) = iter.next() {
+            let entry = entry?;
+            let value = entry
+                .value()
+                .ok_or_else(|| anyhow!("value for {IT_LINT_REQUIRE_TRAILER} not utf8"))?;
+            required_trailers.push(value.trim().to_owned());
+        }
+
+        Ok(patches::lint::Rules {
+            subject_pattern,
+            max_subject_len,
+            required_trailers,
+        })
+    }
+
+    pub fn quarantine_new(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_bool(IT_QUARANTINE_NEW))?.unwrap_or(false))
+    }
+
+    pub fn allow_encrypted(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_bool(IT_ALLOW_ENCRYPTED))?.unwrap_or(false))
+    }
+
+    pub fn dedup_snapshots(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_bool(IT_DEDUP_SNAPSHOTS))?.unwrap_or(false))
+    }
+
+    /// The anti-spam rules read from the JSON file at `it.spamPolicy`, if set
+    pub fn spam_policy(cfg: &git2::Config) -> crate::Result<Option<patches::spam::Rules>> {
+        if_not_found_none(cfg.get_path(IT_SPAM_POLICY))?
+            .map(|path| patches::spam::Rules::from_file(&path))
+            .transpose()
+    }
+
+    /// Whether to record drop operations in the [`patches::audit`] log
+    pub fn audit_log(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_bool(IT_AUDIT_LOG))?.unwrap_or(false))
+    }
+
+    pub fn unbundle(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_bool(IT_UNBUNDLE))?.unwrap_or(true))
+    }
+
+    pub fn subscriptions(cfg: &git2::Config) -> crate::Result<BTreeSet<patches::Topic>> {
+        let mut topics = BTreeSet::new();
+        let mut iter = cfg.multivar(IT_SUBSCRIBE, None)?;
+        while let Some(entry) = iter.next() {
+            let entry = entry?;
+            let value = entry
+                .value()
+                .ok_or_else(|| anyhow!("value for {IT_SUBSCRIBE} not utf8"))?;
+            let topic = value
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("invalid topic in {IT_SUBSCRIBE}: {e}"))?;
+            topics.insert(topic);
+        }
+
+        Ok(topics)
+    }
+
+    pub fn notify_hook(cfg: &git2::Config) -> crate::Result<Option<PathBuf>> {
+        Ok(if_not_found_none(cfg.get_path(IT_NOTIFY_HOOK))?)
+    }
+
+    pub fn topic_cache(cfg: &git2::Config) -> crate::Result<bool> {
+        Ok(if_not_found_none(cfg.get_bool(IT_TOPIC_CACHE))?.unwrap_or(false))
+    }
+
+    pub fn id_path_root(cfg: &git2::Config) -> crate::Result<Option<PathBuf>> {
+        Ok(if_not_found_none(cfg.get_path(IT_ID_PATH_ROOT))?)
+    }
+
+    pub fn hash_algorithm(cfg: &git2::Config) -> crate::Result<bundle::HashAlgorithm> {
+        if_not_found_none(cfg.get_string(IT_HASH_ALGORITHM))?
+            .map(|alg| alg.parse())
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(Into::into)
+    }
+
+    /// A drop an identity is bound to
+    ///
+    /// Either the id of an identity in the drop's root role, or the URL patches
+    /// are submitted to.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub enum AllowedDrop {
+        Root(IdentityId),
+        Url(Url),
+    }
+
+    pub fn allowed_drops(cfg: &git2::Config, id: &IdentityId) -> crate::Result<Vec<AllowedDrop>> {
+        let key = format!("it.{id}.{IT_ALLOWED_DROP}");
+        let mut allowed = Vec::new();
+        let mut iter = cfg.multivar(&key, None)?;
+        while let Some(entry) = iter.next() {
+            let entry = entry?;
+            let value = entry
+                .value()
+                .ok_or_else(|| anyhow!("value for {key} not utf8"))?;
+            let drop = match value.parse::<IdentityId>() {
+                Ok(root) => AllowedDrop::Root(root),
+                Err(_) => AllowedDrop::Url(
+                    Url::parse(value)
+                        .map_err(|e| anyhow!("{key}: neither an identity id nor a URL: {e}"))?,
+                ),
+            };
+            allowed.push(drop);
+        }
+
+        Ok(allowed)
+    }
+
+    pub fn sibling_drops(cfg: &git2::Config) -> crate::Result<BTreeMap<String, PathBuf>> {
+        let suffix = format!(".{}", IT_SIBLING_DROP.to_lowercase());
+        let mut drops = BTreeMap::new();
+        let mut iter = cfg.entries(Some(&format!("it\\..*\\{suffix}$")))?;
+        while let Some(entry) = iter.next() {
+            let entry = entry?;
+            let key = entry.name().ok_or_else(|| anyhow!("config key not utf8"))?;
+            let name = key
+                .strip_prefix("it.")
+                .and_then(|key| key.strip_suffix(&suffix))
+                .ok_or_else(|| anyhow!("unexpected config key {key}"))?;
+            patches::composite::validate_name(name)?;
+            let path = entry
+                .value()
+                .ok_or_else(|| anyhow!("value for {key} not utf8"))?;
+            drops.insert(name.to_owned(), PathBuf::from(path));
+        }
+
+        Ok(drops)
+    }
+
     pub fn default_branch(cfg: &git2::Config) -> crate::Result<Refname> {
         if_not_found_none(cfg.get_string(DEFAULT_BRANCH))?
             .unwrap_or_else(|| String::from("master"))
@@ -13,9 +13,15 @@ use crate::metadata::git::{
 mod util;
 use util::args;
 
+pub mod drafts;
 pub mod drop;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
 pub mod id;
+pub mod maintenance;
 pub mod mergepoint;
+pub mod oplog;
+pub mod outbox;
 pub mod patch;
 pub mod topic;
 pub mod ui;
@@ -45,6 +51,8 @@ pub(crate) use abort;
 pub enum Output {
     Val(Box<dyn erased_serde::Serialize>),
     Iter(Box<dyn Iterator<Item = Result<Box<dyn erased_serde::Serialize>>>>),
+    /// Pre-rendered, human-readable output
+    Text(String),
 }
 
 impl Output {
@@ -68,6 +76,33 @@ impl Output {
     }
 }
 
+/// Version of the JSON output of commands
+///
+/// Must be incremented whenever an existing field is removed, renamed, or
+/// changes its type.
+pub const OUTPUT_VERSION: u64 = 3;
+
+/// A value of an [`Output`] tagged with the [`OUTPUT_VERSION`]
+///
+/// Serialises as `{"output_version":N,"data":...}`, regardless of the type of
+/// the value.
+#[derive(serde::Serialize)]
+pub struct Versioned<'a, T: ?Sized> {
+    pub output_version: u64,
+    pub data: &'a T,
+}
+
+/// Prepare a value of an [`Output`] for printing, see [`Versioned`]
+pub fn versioned<T>(data: &T) -> Versioned<'_, T>
+where
+    T: serde::Serialize + ?Sized,
+{
+    Versioned {
+        output_version: OUTPUT_VERSION,
+        data,
+    }
+}
+
 trait IntoOutput {
     fn into_output(self) -> Output;
 }
@@ -102,6 +137,26 @@ pub enum Cmd {
     /// Topics
     #[clap(subcommand)]
     Topic(topic::Cmd),
+
+    /// Unsubmitted comments
+    #[clap(subcommand)]
+    Drafts(drafts::Cmd),
+
+    /// Queued submissions
+    #[clap(subcommand)]
+    Outbox(outbox::Cmd),
+
+    /// Repository maintenance
+    #[clap(subcommand)]
+    Maintenance(maintenance::Cmd),
+
+    /// Log of commands which modified the drop
+    #[clap(subcommand)]
+    Oplog(oplog::Cmd),
+
+    /// Generate a deterministic drop for tests, benchmarks and examples
+    #[cfg(feature = "fixtures")]
+    Fixtures(fixtures::Fixtures),
 }
 
 impl Cmd {
@@ -112,6 +167,86 @@ impl Cmd {
             Self::Patch(cmd) => cmd.run(),
             Self::MergePoint(cmd) => cmd.run(),
             Self::Topic(cmd) => cmd.run(),
+            Self::Drafts(cmd) => cmd.run(),
+            Self::Outbox(cmd) => cmd.run(),
+            Self::Maintenance(cmd) => cmd.run(),
+            Self::Oplog(cmd) => cmd.run(),
+            #[cfg(feature = "fixtures")]
+            Self::Fixtures(args) => fixtures::fixtures(args).map(IntoOutput::into_output),
         }
     }
+
+    /// The name under which the command is recorded in the [`oplog`], if it
+    /// modifies the drop
+    pub fn oplog_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Drop(cmd) => cmd.oplog_name(),
+            Self::Patch(patch::Cmd::Record(_)) => Some("patch record"),
+            Self::Patch(patch::Cmd::Review(args)) if args.is_record() => Some("patch review"),
+            Self::MergePoint(mergepoint::Cmd::Record(_)) => Some("merge-point record"),
+            Self::Topic(topic::Cmd::Comment(topic::comment::Cmd::Record(_))) => {
+                Some("topic comment record")
+            },
+            Self::Topic(topic::Cmd::Close(args)) if args.is_record() => Some("topic close"),
+            Self::Topic(topic::Cmd::Reopen(args)) if args.is_record() => Some("topic reopen"),
+            Self::Topic(topic::Cmd::Unbundle(_)) => Some("topic unbundle"),
+            Self::Maintenance(maintenance::Cmd::Run(_)) => Some("maintenance run"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn render<T: serde::Serialize + ?Sized>(v: &T) -> String {
+        serde_json::to_string(&versioned(v)).unwrap()
+    }
+
+    #[derive(serde::Serialize)]
+    struct Edit {
+        repo: &'static str,
+        #[serde(rename = "ref")]
+        refname: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<&'static str>,
+    }
+
+    #[test]
+    fn object() {
+        let v = Edit {
+            repo: "/src/repo",
+            refname: "refs/it/patches",
+            state: None,
+        };
+        assert_eq!(
+            render(&v),
+            r#"{"output_version":3,"data":{"repo":"/src/repo","ref":"refs/it/patches"}}"#
+        )
+    }
+
+    #[test]
+    fn map() {
+        let v = BTreeMap::from([("b", vec![1, 2]), ("a", vec![])]);
+        assert_eq!(
+            render(&v),
+            r#"{"output_version":3,"data":{"a":[],"b":[1,2]}}"#
+        )
+    }
+
+    #[test]
+    fn scalar() {
+        assert_eq!(render(&()), r#"{"output_version":3,"data":null}"#);
+        assert_eq!(render("x"), r#"{"output_version":3,"data":"x"}"#);
+        assert_eq!(render(&[42]), r#"{"output_version":3,"data":[42]}"#);
+    }
+
+    #[test]
+    fn erased() {
+        let v: Box<dyn erased_serde::Serialize> = Box::new(Some(1));
+        assert_eq!(render(&*v), r#"{"output_version":3,"data":1}"#);
+    }
 }
@@ -0,0 +1,215 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Unsubmitted comments
+//!
+//! Text entered in `$EDITOR` when commenting on a topic is kept under
+//! `$GIT_DIR/it/drafts/<topic>/<identity>.txt` until the comment is recorded,
+//! so it survives an aborted editor session or a failed submission.
+
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::anyhow;
+use time::OffsetDateTime;
+
+use crate::{
+    cfg,
+    cmd::{
+        self,
+        ui::info,
+    },
+    fs::LockedFile,
+    git,
+    metadata::IdentityId,
+    patches::Topic,
+};
+
+const DRAFTS_DIR: &str = "it/drafts";
+const FILE_EXTENSION: &str = "txt";
+
+pub struct Draft {
+    path: PathBuf,
+}
+
+impl Draft {
+    pub fn new(git_dir: &Path, topic: &Topic, id: &IdentityId) -> Self {
+        let mut path = git_dir
+            .join(DRAFTS_DIR)
+            .join(topic.to_string())
+            .join(id.to_string());
+        path.set_extension(FILE_EXTENSION);
+        Self { path }
+    }
+
+    pub fn load(&self) -> cmd::Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => Ok(Some(text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, text: &str) -> cmd::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lock = LockedFile::atomic(&self.path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+        io::Write::write_all(&mut lock, text.as_bytes())?;
+        lock.persist()?;
+
+        Ok(())
+    }
+
+    /// Remove the draft, and the topic directory if it became empty
+    pub fn remove(&self) -> cmd::Result<bool> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {
+                if let Some(dir) = self.path.parent() {
+                    // Fails if not empty, which is fine
+                    let _ = fs::remove_dir(dir);
+                }
+                Ok(true)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Cmd {
+    /// List unsubmitted comments
+    List(List),
+    /// Discard unsubmitted comments
+    Rm(Rm),
+}
+
+impl Cmd {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::List(args) => list(args).map(cmd::Output::iter),
+            Self::Rm(args) => rm(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct List {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Only list drafts for this topic
+    #[clap(long, value_parser)]
+    topic: Option<Topic>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Entry {
+    topic: Topic,
+    id: IdentityId,
+    #[serde(with = "time::serde::rfc3339")]
+    modified: OffsetDateTime,
+    /// First line of the draft
+    summary: String,
+}
+
+pub fn list(args: List) -> cmd::Result<Vec<cmd::Result<Entry>>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let root = repo.path().join(DRAFTS_DIR);
+    let topics = match &args.topic {
+        Some(topic) => vec![root.join(topic.to_string())],
+        None => match fs::read_dir(&root) {
+            Ok(dir) => dir
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<_, _>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    let mut entries = Vec::new();
+    for dir in topics {
+        let files = match fs::read_dir(&dir) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for file in files {
+            entries.push(
+                file.map_err(Into::into)
+                    .and_then(|f| entry(&dir, &f.path())),
+            );
+        }
+    }
+
+    Ok(entries)
+}
+
+fn entry(dir: &Path, path: &Path) -> cmd::Result<Entry> {
+    let name = |p: &Path| -> cmd::Result<String> {
+        p.file_stem()
+            .and_then(|s| s.to_str())
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| anyhow!("invalid draft path {}", p.display()))
+    };
+    let topic = name(dir)?.parse()?;
+    let id = name(path)?.parse()?;
+    let modified = fs::metadata(path)?.modified()?.into();
+    let summary = fs::read_to_string(path)?
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+
+    Ok(Entry {
+        topic,
+        id,
+        modified,
+        summary,
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Rm {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// The identity whose draft to remove
+    ///
+    /// If not given, the identity configured via the 'it.id' config is used.
+    #[clap(long, value_parser)]
+    id: Option<IdentityId>,
+    /// The topic of the draft
+    #[clap(value_parser)]
+    topic: Topic,
+}
+
+#[derive(serde::Serialize)]
+pub struct Removed {
+    removed: bool,
+}
+
+pub fn rm(args: Rm) -> cmd::Result<Removed> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let id = match args.id {
+        Some(id) => id,
+        None => cfg::git::identity(&cfg::git::open(&repo)?)?
+            .ok_or_else(|| anyhow!("no identity configured for signer"))?,
+    };
+    let removed = Draft::new(repo.path(), &args.topic, &id).remove()?;
+    if removed {
+        info!("Removed draft for {}", args.topic);
+    } else {
+        info!("No draft for {}", args.topic);
+    }
+
+    Ok(Removed { removed })
+}
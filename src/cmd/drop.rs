@@ -2,23 +2,41 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    num::NonZeroUsize,
     ops::Deref,
-    path::PathBuf,
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
+    sync::{
+        mpsc,
+        Arc,
+        Mutex,
+    },
+    thread,
 };
 
 use anyhow::{
+    anyhow,
     ensure,
     Context,
 };
 use clap::ValueHint;
-use either::Either::Left;
 
 use crate::{
     cmd,
+    git::{
+        self,
+        Refname,
+    },
     metadata::{
         self,
         git::{
-            FromGit,
             META_FILE_ALTERNATES,
             META_FILE_MIRRORS,
         },
@@ -28,6 +46,30 @@ use crate::{
     patches::REF_HEADS_PATCHES,
 };
 
+mod archive;
+pub use archive::Archive;
+
+mod audit;
+pub use audit::{
+    audit,
+    Audit,
+};
+
+mod at;
+pub use at::{
+    at,
+    At,
+};
+
+mod branch;
+pub use branch::Branch;
+
+mod branch_log;
+pub use branch_log::{
+    branch_log,
+    BranchLog,
+};
+
 mod bundles;
 pub use bundles::{
     sync,
@@ -35,24 +77,63 @@ pub use bundles::{
     Sync,
 };
 
+mod clone;
+pub use clone::{
+    clone,
+    Clone,
+};
+
 mod edit;
 pub use edit::{
     edit,
     Edit,
 };
 
+mod fsck;
+pub use fsck::{
+    fsck,
+    Fsck,
+};
+
+mod hooks;
+pub use hooks::Hooks;
+
+mod issue;
+pub use issue::Issue;
+
 mod init;
 pub use init::{
     init,
     Init,
 };
 
+mod mirror;
+pub use mirror::Mirror;
+
+mod moderate;
+pub use moderate::Moderate;
+
+mod repair_refs;
+pub use repair_refs::{
+    repair_refs,
+    RepairRefs,
+};
+
+mod role;
+pub use role::Role;
+
 mod serve;
 pub use serve::{
     serve,
     Serve,
 };
 
+mod serve_ssh;
+pub use serve_ssh::{
+    serve_ssh,
+    ServeSsh,
+};
+
 mod snapshot;
 pub use snapshot::{
     snapshot,
@@ -65,44 +146,158 @@ pub use show::{
     Show,
 };
 
+mod status;
+pub use status::{
+    status,
+    Status,
+};
+
+mod verify;
+pub use verify::{
+    verify,
+    Verify,
+};
+
 mod unbundle;
 pub use unbundle::{
     unbundle,
     Unbundle,
 };
 
+mod watch;
+pub use watch::{
+    watch,
+    Watch,
+};
+
 #[derive(Debug, clap::Subcommand)]
 #[allow(clippy::large_enum_variant)]
 pub enum Cmd {
     /// Initialise a drop
     Init(Init),
+    /// Clone a remote drop, verifying its metadata history
+    Clone(Clone),
     /// Display the drop metadata
     Show(Show),
+    /// Show the drop state as of a past point in its history
+    At(At),
     /// Serve bundles and patch submission over HTTP
+    ///
+    /// On SIGTERM or SIGINT, stops accepting connections and exits once
+    /// requests in flight have been served. A second signal exits
+    /// immediately.
     Serve(Serve),
+    /// Accept a single patch submission over SSH
+    ///
+    /// Reads the submission from stdin and writes the outcome to stdout, as
+    /// used by `it patch submit --url ssh://...`. Typically configured
+    /// as the forced command of the keys allowed to submit patches.
+    ServeSsh(ServeSsh),
     /// Edit the drop metadata
     Edit(Edit),
+    /// Modify the roles of the drop metadata non-interactively
+    #[clap(subcommand)]
+    Role(Role),
+    /// Add or remove branches of the drop metadata non-interactively
+    #[clap(subcommand)]
+    Branch(Branch),
+    /// Link topics to the issues of an external issue tracker
+    #[clap(subcommand)]
+    Issue(Issue),
+    /// Add or remove mirrors non-interactively, or keep this drop in sync
+    /// with its upstream as a live mirror (--follow)
+    Mirror(Mirror),
     /// Manage patch bundles
     #[clap(subcommand)]
     Bundles(Bundles),
+    /// Manage the hooks and webhooks run when a patch is accepted
+    #[clap(subcommand)]
+    Hooks(Hooks),
     /// Take a snapshot of the patches received so far
     Snapshot(Snapshot),
-    /// Unbundle the entire drop history
+    /// Unbundle the entire drop history, or records accepted without
+    /// unbundling
     Unbundle(Unbundle),
+    /// Review submissions held in quarantine
+    #[clap(subcommand)]
+    Moderate(Moderate),
+    /// Show the checkpoints which advanced a branch
+    BranchLog(BranchLog),
+    /// Summarise tracking branches, unseen topics and pending submissions
+    Status(Status),
+    /// Verify and repair the tracking branches, and their symrefs in a bare
+    /// drop
+    RepairRefs(RepairRefs),
+    /// Cross-check the seen objects tree against the drop history, and
+    /// optionally rebuild it
+    Fsck(Fsck),
+    /// Print new records and topic notes as they land in the drop
+    Watch(Watch),
+    /// Export the drop to a portable archive, or import one
+    #[clap(subcommand)]
+    Archive(Archive),
+    /// Show the audit log of drop operations, and verify its integrity
+    Audit(Audit),
+    /// Verify the entire drop history, including stored bundles and unbundled
+    /// refs
+    Verify(Verify),
 }
 
 impl Cmd {
     pub fn run(self) -> cmd::Result<cmd::Output> {
         match self {
             Self::Init(args) => init(args).map(cmd::IntoOutput::into_output),
+            Self::Clone(args) => clone(args).map(cmd::IntoOutput::into_output),
             Self::Show(args) => show(args).map(cmd::IntoOutput::into_output),
+            Self::At(args) => at(args),
             Self::Serve(args) => serve(args).map(cmd::IntoOutput::into_output),
+            Self::ServeSsh(args) => serve_ssh(args),
             Self::Edit(args) => edit(args).map(cmd::IntoOutput::into_output),
+            Self::Role(cmd) => cmd.run(),
+            Self::Branch(cmd) => cmd.run(),
+            Self::Issue(cmd) => cmd.run(),
+            Self::Mirror(cmd) => cmd.run(),
             Self::Bundles(cmd) => cmd.run(),
+            Self::Hooks(cmd) => cmd.run(),
             Self::Snapshot(args) => snapshot(args).map(cmd::IntoOutput::into_output),
             Self::Unbundle(args) => unbundle(args).map(cmd::IntoOutput::into_output),
+            Self::Moderate(cmd) => cmd.run(),
+            Self::BranchLog(args) => branch_log(args),
+            Self::Status(args) => status(args),
+            Self::RepairRefs(args) => repair_refs(args).map(cmd::IntoOutput::into_output),
+            Self::Fsck(args) => fsck(args).map(cmd::IntoOutput::into_output),
+            Self::Watch(args) => watch(args).map(cmd::Output::iter),
+            Self::Archive(cmd) => cmd.run(),
+            Self::Audit(args) => audit(args).map(cmd::IntoOutput::into_output),
+            Self::Verify(args) => verify(args).map(cmd::IntoOutput::into_output),
         }
     }
+
+    pub(super) fn oplog_name(&self) -> Option<&'static str> {
+        let name = match self {
+            Self::Init(_) => "drop init",
+            Self::Edit(_) => "drop edit",
+            Self::Role(Role::Ls(_)) => return None,
+            Self::Role(_) => "drop role",
+            Self::Branch(_) => "drop branch",
+            Self::Issue(Issue::Ls(_)) => return None,
+            Self::Issue(_) => "drop issue",
+            Self::Mirror(args) if args.is_edit() => "drop mirror",
+            Self::Bundles(Bundles::Sync(_)) => "drop bundles sync",
+            Self::Bundles(Bundles::Prune(_)) => "drop bundles prune",
+            Self::Hooks(Hooks::Add(_)) => "drop hooks add",
+            Self::Hooks(Hooks::Remove(_)) => "drop hooks remove",
+            Self::Snapshot(_) => "drop snapshot",
+            Self::Unbundle(_) => "drop unbundle",
+            Self::Moderate(Moderate::Approve(_)) => "drop moderate approve",
+            Self::Moderate(Moderate::Reject(_)) => "drop moderate reject",
+            Self::RepairRefs(args) if !args.is_dry_run() => "drop repair-refs",
+            Self::Fsck(args) if args.is_repair() => "drop fsck",
+            Self::Archive(Archive::Import(_)) => "drop archive import",
+            _ => return None,
+        };
+        Some(name)
+    }
 }
 
 #[derive(Debug, clap::Args)]
@@ -122,17 +317,19 @@ struct Common {
     id_path: cmd::util::args::IdSearchPath,
 }
 
+/// Find and verify identity `id` in `id_path`
+///
+/// The history of the identity is read from the repository it was found in,
+/// which is not necessarily an alternate of the drop repository.
 fn find_id(
-    repo: &git2::Repository,
     id_path: &[git2::Repository],
     id: &IdentityId,
 ) -> cmd::Result<Signed<metadata::Identity>> {
-    let signed = metadata::Identity::from_search_path(id_path, cmd::id::identity_ref(Left(id))?)?
-        .meta
-        .signed;
+    let found = cmd::id::from_search_path(id_path, id)?;
+    let signed = found.meta.signed;
 
     let verified_id = signed
-        .verify(cmd::find_parent(repo))
+        .verify(cmd::find_parent(found.repo))
         .with_context(|| format!("invalid identity {id}"))?;
     ensure!(
         &verified_id == id,
@@ -142,10 +339,106 @@ fn find_id(
     Ok(signed)
 }
 
+/// Maximum number of threads [`find_ids`] verifies identities on
+const MAX_VERIFY_THREADS: usize = 8;
+
+/// Like [`find_id`], but for many identities at once
+///
+/// The identities are verified concurrently, on up to [`MAX_VERIFY_THREADS`]
+/// threads, each with its own handles on `id_path`. All failures are reported
+/// together, instead of only the first one.
+fn find_ids(
+    id_path: &[git2::Repository],
+    ids: BTreeSet<IdentityId>,
+) -> cmd::Result<BTreeMap<IdentityId, Signed<metadata::Identity>>> {
+    let threads = thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+        .min(MAX_VERIFY_THREADS)
+        .min(ids.len());
+    if threads <= 1 {
+        return ids
+            .into_iter()
+            .map(|id| find_id(id_path, &id).map(|signed| (id, signed)))
+            .collect();
+    }
+
+    let total = ids.len();
+    let queue = Arc::new(Mutex::new(ids.into_iter()));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..threads {
+        let queue = Arc::clone(&queue);
+        let tx = tx.clone();
+        let id_paths = id_path
+            .iter()
+            .map(|r| r.path().to_owned())
+            .collect::<Vec<_>>();
+        thread::spawn(move || {
+            let handles = id_paths
+                .iter()
+                .map(git::repo::open_bare)
+                .collect::<Result<Vec<_>, _>>();
+            loop {
+                let next = queue.lock().unwrap().next();
+                let id = match next {
+                    Some(id) => id,
+                    None => break,
+                };
+                let res = match &handles {
+                    Ok(id_path) => find_id(id_path, &id),
+                    Err(e) => Err(anyhow!("failed to open repository: {}", e.message())),
+                };
+                if tx.send((id, res)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let mut found = BTreeMap::new();
+    let mut failed = Vec::new();
+    for (id, res) in rx {
+        match res {
+            Ok(signed) => {
+                found.insert(id, signed);
+            },
+            Err(e) => failed.push(format!("{id}: {e:#}")),
+        }
+    }
+    if !failed.is_empty() {
+        failed.sort();
+        return Err(anyhow!(
+            "{} of {} identities could not be verified:\n  {}",
+            failed.len(),
+            total,
+            failed.join("\n  ")
+        ));
+    }
+
+    Ok(found)
+}
+
+/// Parse a branch name, qualifying it with `refs/heads/` if it is not already
+fn branch_name(s: &str) -> cmd::Result<Refname> {
+    if s.starts_with("refs/heads/") {
+        s.parse()
+    } else {
+        format!("refs/heads/{s}").parse()
+    }
+    .map_err(Into::into)
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Editable {
     description: metadata::drop::Description,
     roles: metadata::drop::Roles,
+    #[serde(default)]
+    id_path: Vec<String>,
+    #[serde(default)]
+    ref_policy: metadata::drop::RefPolicy,
+    #[serde(default)]
+    commit_limits: metadata::drop::CommitLimits,
     custom: metadata::Custom,
 }
 
@@ -154,6 +447,9 @@ impl From<metadata::Drop> for Editable {
         metadata::Drop {
             description,
             roles,
+            id_path,
+            ref_policy,
+            commit_limits,
             custom,
             ..
         }: metadata::Drop,
@@ -161,6 +457,9 @@ impl From<metadata::Drop> for Editable {
         Self {
             description,
             roles,
+            id_path,
+            ref_policy,
+            commit_limits,
             custom,
         }
     }
@@ -173,6 +472,9 @@ impl TryFrom<Editable> for metadata::Drop {
         Editable {
             description,
             roles,
+            id_path,
+            ref_policy,
+            commit_limits,
             custom,
         }: Editable,
     ) -> Result<Self, Self::Error> {
@@ -193,12 +495,30 @@ impl TryFrom<Editable> for metadata::Drop {
             ensure!(name.starts_with("refs/heads/"), "not a branch {name}");
             ensure!(name.deref() != REF_HEADS_PATCHES, "reserved branch {name}");
         }
+        for path in &id_path {
+            ensure!(
+                Path::new(path)
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_))),
+                "identity repository {path} must be a relative path without '..' components"
+            );
+        }
+        for glob in ref_policy.iter() {
+            globset::Glob::new(glob).with_context(|| format!("invalid ref policy glob {glob}"))?;
+        }
+        for glob in commit_limits.refs.keys() {
+            globset::Glob::new(glob)
+                .with_context(|| format!("invalid commit limit glob {glob}"))?;
+        }
 
         Ok(Self {
             fmt_version: Default::default(),
             description,
             prev: None,
             roles,
+            id_path,
+            ref_policy,
+            commit_limits,
             custom,
         })
     }
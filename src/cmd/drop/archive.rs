@@ -0,0 +1,548 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        BufWriter,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+    Context,
+};
+use clap::ValueHint;
+use globset::{
+    GlobSet,
+    GlobSetBuilder,
+};
+use once_cell::sync::Lazy;
+
+use crate::{
+    bundle,
+    cfg,
+    cmd::{
+        self,
+        ui::{
+            self,
+            debug,
+            info,
+            warn,
+        },
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    metadata::{
+        self,
+        git::{
+            FromGit,
+            META_FILE_ID,
+        },
+        identity::ExpiryPolicy,
+        IdentityId,
+    },
+    patches::{
+        self,
+        iter::{
+            dropped,
+            unbundled,
+        },
+        notes::TopicState,
+        reachable,
+        record::Heads,
+        AcceptArgs,
+        AcceptOptions,
+        Bundle,
+        DropHead,
+        DropHeadCache,
+        Record,
+        Seen,
+        Submission,
+        Topic,
+        GLOB_HEADS,
+        GLOB_IT_BUNDLES,
+        GLOB_IT_IDS,
+        GLOB_IT_TOPICS,
+        GLOB_NOTES,
+        GLOB_TAGS,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+};
+
+/// Version of the archive layout written by `export`
+const ARCHIVE_VERSION: usize = 1;
+
+const FILE_MANIFEST: &str = "archive.json";
+const FILE_RECORDS: &str = "records.jsonl";
+const FILE_TOPICS: &str = "topics.jsonl";
+const FILE_NOTES: &str = "notes.jsonl";
+const FILE_IDENTITIES: &str = "identities.jsonl";
+const DIR_BUNDLES: &str = "bundles";
+
+const FOLDED_HISTORY: &str = ".history";
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Archive {
+    /// Export the drop history to a directory of JSON Lines files and bundles
+    Export(Export),
+    /// Replay an exported archive onto this drop
+    Import(Import),
+}
+
+impl Archive {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Export(args) => export(args).map(cmd::IntoOutput::into_output),
+            Self::Import(args) => import(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct Common {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// The directory where patch bundles are stored
+    ///
+    /// Unless this is an absolute path, it is treated as relative to $GIT_DIR.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        default_value_os_t = cfg::paths::bundles().to_owned(),
+        value_hint = ValueHint::DirPath,
+    )]
+    bundle_dir: PathBuf,
+}
+
+impl Common {
+    fn open(&self) -> cmd::Result<(git2::Repository, PathBuf)> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let bundle_dir = if self.bundle_dir.is_relative() {
+            repo.path().join(&self.bundle_dir)
+        } else {
+            self.bundle_dir.clone()
+        };
+
+        Ok((repo, bundle_dir))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    version: usize,
+    records: usize,
+    topics: usize,
+    identities: usize,
+}
+
+#[derive(serde::Serialize)]
+struct TopicEntry<'a> {
+    topic: &'a Topic,
+    subject: &'a str,
+    state: TopicState,
+}
+
+#[derive(serde::Deserialize)]
+struct TopicLine {
+    topic: Topic,
+}
+
+#[derive(serde::Serialize)]
+struct NoteEntry<'a> {
+    topic: &'a Topic,
+    note: patches::iter::Note,
+}
+
+/// An identity as recorded in the drop, along with all its previous revisions
+///
+/// `revisions` contains the verbatim JSON documents, oldest first, so that
+/// their content hashes are preserved on import.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IdentityEntry {
+    id: IdentityId,
+    revisions: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Export {
+    #[clap(flatten)]
+    common: Common,
+    /// Overwrite the contents of a non-empty directory
+    #[clap(long, value_parser)]
+    force: bool,
+    /// The directory to write the archive to
+    #[clap(value_parser, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    dir: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+pub struct Exported {
+    dir: PathBuf,
+    #[serde(flatten)]
+    manifest: Manifest,
+}
+
+pub fn export(args: Export) -> cmd::Result<Exported> {
+    let (repo, bundle_dir) = args.common.open()?;
+    let dir = args.dir;
+    if dir.exists() {
+        ensure!(
+            args.force || fs::read_dir(&dir)?.next().is_none(),
+            "{} is not empty, use --force to overwrite",
+            dir.display()
+        );
+    }
+    let archive_bundles = dir.join(DIR_BUNDLES);
+    fs::create_dir_all(&archive_bundles)?;
+
+    let mut records = 0;
+    {
+        let mut out = JsonLines::create(&dir.join(FILE_RECORDS))?;
+        for rec in dropped::records_rev(&repo, REF_IT_PATCHES) {
+            let rec = rec?;
+            let src = rec.bundle_path(&bundle_dir);
+            let dst = rec.bundle_path(&archive_bundles);
+            if !dst.exists() {
+                copy_bundle(&src, &dst)
+                    .with_context(|| format!("failed to copy bundle {}", rec.bundle_hash()))?;
+            }
+            out.write(&rec)?;
+            records += 1;
+        }
+        out.finish()?;
+    }
+    debug!("Exported {records} records");
+
+    let mut topics = 0;
+    {
+        let mut out_topics = JsonLines::create(&dir.join(FILE_TOPICS))?;
+        let mut out_notes = JsonLines::create(&dir.join(FILE_NOTES))?;
+        for item in unbundled::topics_with_subject(&repo) {
+            let (topic, subject, state) = item?;
+            out_topics.write(&TopicEntry {
+                topic: &topic,
+                subject: &subject,
+                state,
+            })?;
+            for note in patches::iter::topic(&repo, &topic).rev() {
+                out_notes.write(&NoteEntry {
+                    topic: &topic,
+                    note: note?,
+                })?;
+            }
+            topics += 1;
+        }
+        out_topics.finish()?;
+        out_notes.finish()?;
+    }
+    debug!("Exported {topics} topics");
+
+    let mut identities = 0;
+    {
+        let drop = DropHead::from_refname(&repo, REF_IT_PATCHES)?;
+        let mut out = JsonLines::create(&dir.join(FILE_IDENTITIES))?;
+        for entry in drop.ids.iter() {
+            let name = entry
+                .name()
+                .ok_or_else(|| anyhow!("invalid identity entry in drop"))?;
+            let id: IdentityId = name
+                .parse()
+                .with_context(|| format!("invalid identity entry {name} in drop"))?;
+            let tree = entry
+                .to_object(&repo)?
+                .into_tree()
+                .map_err(|_| anyhow!("{name} is not a directory"))?;
+            out.write(&IdentityEntry {
+                id,
+                revisions: identity_revisions(&repo, &tree)?,
+            })?;
+            identities += 1;
+        }
+        out.finish()?;
+    }
+    debug!("Exported {identities} identities");
+
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        records,
+        topics,
+        identities,
+    };
+    {
+        let mut out = File::create(dir.join(FILE_MANIFEST))?;
+        serde_json::to_writer_pretty(&mut out, &manifest)?;
+        out.sync_all()?;
+    }
+    info!("Exported drop to {}", dir.display());
+
+    Ok(Exported { dir, manifest })
+}
+
+/// Copy the bundle at `src` to `dst`, reassembling it if it is stored in
+/// chunks
+fn copy_bundle(src: &Path, dst: &Path) -> cmd::Result<()> {
+    if bundle::chunks::is_chunked(src) {
+        let mut out = BufWriter::new(File::create(dst)?);
+        bundle::chunks::restore(src, &mut out)?;
+        out.flush()?;
+    } else {
+        fs::copy(src, dst)?;
+    }
+
+    Ok(())
+}
+
+fn identity_revisions(repo: &git2::Repository, tree: &git2::Tree) -> cmd::Result<Vec<String>> {
+    let mut history = Vec::new();
+    if let Some(hist) = tree.get_name(FOLDED_HISTORY) {
+        let hist = hist
+            .to_object(repo)?
+            .into_tree()
+            .map_err(|_| anyhow!("{FOLDED_HISTORY} is not a directory"))?;
+        for entry in hist.iter() {
+            let n = entry
+                .name()
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| anyhow!("unexpected entry in {FOLDED_HISTORY}"))?;
+            history.push((n, entry.id()));
+        }
+    }
+    history.sort_by_key(|(n, _)| *n);
+
+    let current = tree
+        .get_name(META_FILE_ID)
+        .ok_or_else(|| anyhow!("{META_FILE_ID} not found"))?
+        .id();
+    history
+        .into_iter()
+        .map(|(_, oid)| oid)
+        .chain(Some(current))
+        .map(|oid| {
+            let blob = repo.find_blob(oid)?;
+            let json = std::str::from_utf8(blob.content())?;
+            Ok(json.to_owned())
+        })
+        .collect()
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Import {
+    #[clap(flatten)]
+    common: Common,
+    /// The directory containing an archive written by 'export'
+    #[clap(value_parser, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    dir: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+pub struct Imported {
+    identities: usize,
+    records: usize,
+    skipped: usize,
+}
+
+/// Refs a record in the archive may carry
+///
+/// The archive was accepted by its origin drop already, including snapshots,
+/// so we only restrict what can end up in the ref namespace.
+static IMPORT_REFS: Lazy<GlobSet> = Lazy::new(|| {
+    GlobSetBuilder::new()
+        .add(GLOB_HEADS.clone())
+        .add(GLOB_TAGS.clone())
+        .add(GLOB_NOTES.clone())
+        .add(GLOB_IT_TOPICS.clone())
+        .add(GLOB_IT_IDS.clone())
+        .add(GLOB_IT_BUNDLES.clone())
+        .build()
+        .unwrap()
+});
+
+pub fn import(args: Import) -> cmd::Result<Imported> {
+    let (repo, bundle_dir) = args.common.open()?;
+    let dir = args.dir;
+
+    let manifest: Manifest = {
+        let path = dir.join(FILE_MANIFEST);
+        let file =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        serde_json::from_reader(file)?
+    };
+    ensure!(
+        manifest.version == ARCHIVE_VERSION,
+        "unsupported archive version {}",
+        manifest.version
+    );
+
+    // Identities are looked up by content hash while accepting records, so
+    // make all known revisions available first.
+    let find_parent = metadata::git::find_parent(&repo);
+    let mut identities = 0;
+    for entry in read_lines::<IdentityEntry>(&dir.join(FILE_IDENTITIES))? {
+        let IdentityEntry { id, revisions } = entry?;
+        let mut tip = None;
+        for rev in &revisions {
+            tip = Some(repo.blob(rev.as_bytes())?);
+        }
+        let tip = tip.ok_or_else(|| anyhow!("no revisions for identity {id}"))?;
+        let verified = metadata::Identity::from_blob(&repo.find_blob(tip)?)?
+            .signed
+            .verified(&find_parent)
+            .with_context(|| format!("invalid identity {id}"))?;
+        ensure!(
+            verified.id() == &id,
+            "ids do not match after verification: expected {id}, found {}",
+            verified.id()
+        );
+        identities += 1;
+    }
+    debug!("Imported {identities} identities");
+
+    let cfg = cfg::git::open(&repo)?;
+    let mut signer = cfg::signer(&cfg, ui::askpass)?;
+    let hooks = cfg::git::accept_hooks(&cfg)?;
+    let dedup_snapshots = cfg::git::dedup_snapshots(&cfg)?;
+    let archive_bundles = dir.join(DIR_BUNDLES);
+
+    let drop_cache = DropHeadCache::default();
+    let reachable_cache = reachable::Cache::default();
+    let mut records = 0;
+    let mut skipped = 0;
+    for rec in read_lines::<Record>(&dir.join(FILE_RECORDS))? {
+        let rec = rec?;
+        if is_seen(&repo, &rec.heads)? {
+            debug!("Skipping already recorded {}", rec.heads);
+            skipped += 1;
+            continue;
+        }
+
+        Bundle::from_stored(&archive_bundles, rec.bundle_info().as_expect())
+            .with_context(|| format!("invalid bundle {} in archive", rec.bundle_hash()))?;
+        let dst = rec.bundle_path(&bundle_dir);
+        if !bundle::chunks::exists(&dst) {
+            fs::create_dir_all(&bundle_dir)?;
+            fs::copy(rec.bundle_path(&archive_bundles), &dst)?;
+        }
+
+        let mut sub = Submission {
+            signature: rec.meta.signature.clone(),
+            bundle: Bundle::from_stored(&bundle_dir, rec.bundle_info().as_expect())?,
+        };
+        sub.try_accept(AcceptArgs {
+            unbundle_prefix: REF_IT_BUNDLES,
+            drop_ref: REF_IT_PATCHES,
+            seen_ref: REF_IT_SEEN,
+            repo: &repo,
+            signer: &mut signer,
+            ipfs_api: None,
+            drop_cache: Some(&drop_cache),
+            reachable_cache: Some(&reachable_cache),
+            options: AcceptOptions {
+                allow_fat_pack: true,
+                allow_encrypted: true,
+                allowed_refs: IMPORT_REFS.clone(),
+                // Records were subject to the policy of the exporting drop
+                enforce_ref_policy: false,
+                max_branches: usize::MAX,
+                max_tags: usize::MAX,
+                max_notes: usize::MAX,
+                max_refs: usize::MAX,
+                max_commits: usize::MAX,
+                max_commits_checkpoint: usize::MAX,
+                enforce_commit_limits: false,
+                hooks: hooks.clone(),
+                quarantine_new: false,
+                unbundle: true,
+                lint: Default::default(),
+                dedup_snapshots,
+                spam_policy: None,
+                audit: false,
+                // Role members may have expired since the records were made
+                expiry: ExpiryPolicy::Warn,
+            },
+        })
+        .with_context(|| format!("failed to import {} ({})", rec.heads, rec.topic))?;
+        records += 1;
+    }
+
+    for line in read_lines::<TopicLine>(&dir.join(FILE_TOPICS))? {
+        let TopicLine { topic } = line?;
+        if if_not_found_none(repo.refname_to_id(&topic.as_refname()))?.is_none() {
+            warn!("Topic {topic} was not reconstructed from the archive");
+        }
+    }
+    info!("Imported {records} records, skipped {skipped}");
+
+    Ok(Imported {
+        identities,
+        records,
+        skipped,
+    })
+}
+
+fn is_seen(repo: &git2::Repository, heads: &Heads) -> cmd::Result<bool> {
+    match if_not_found_none(repo.find_reference(REF_IT_SEEN))? {
+        Some(seen) => Ok(heads.in_tree(&seen.peel_to_tree()?)?),
+        None => Ok(false),
+    }
+}
+
+struct JsonLines {
+    out: BufWriter<File>,
+}
+
+impl JsonLines {
+    fn create(path: &Path) -> cmd::Result<Self> {
+        let out =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self {
+            out: BufWriter::new(out),
+        })
+    }
+
+    fn write<T: serde::Serialize>(&mut self, item: &T) -> cmd::Result<()> {
+        serde_json::to_writer(&mut self.out, item)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(self) -> cmd::Result<()> {
+        let file = self
+            .out
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+fn read_lines<T>(path: &Path) -> cmd::Result<impl Iterator<Item = cmd::Result<T>>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?)))
+}
@@ -0,0 +1,197 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use time::OffsetDateTime;
+
+use super::Common;
+use crate::{
+    bundle::ObjectId,
+    cmd::{
+        self,
+        util::args::Refname,
+    },
+    git,
+    metadata::{
+        ContentHash,
+        IdentityId,
+    },
+    patches::{
+        view::{
+            self,
+            View,
+        },
+        Topic,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct At {
+    #[clap(flatten)]
+    common: Common,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Show what changed between this earlier point and the given one
+    #[clap(long, value_parser, value_name = "DATE|REV")]
+    since: Option<view::At>,
+    /// The point in the drop history
+    ///
+    /// Either a date in RFC 3339 format, in which case the most recent drop
+    /// commit not newer than it is used, or a commit in 'git rev-parse'
+    /// syntax.
+    #[clap(value_parser, value_name = "DATE|REV")]
+    at: view::At,
+}
+
+#[derive(serde::Serialize)]
+pub struct State {
+    #[serde(with = "git::serde::oid")]
+    commit: git2::Oid,
+    #[serde(with = "time::serde::rfc3339")]
+    time: OffsetDateTime,
+    /// Content hash of the drop metadata
+    meta: ContentHash,
+    /// Identities and the tree ids of their latest revision
+    ids: BTreeMap<IdentityId, ObjectId>,
+    /// Tracking branch positions
+    branches: BTreeMap<Refname, ObjectId>,
+    /// Topics and their number of records
+    topics: BTreeMap<Topic, usize>,
+}
+
+impl State {
+    fn from_view(view: &View) -> cmd::Result<Self> {
+        let commit = view.commit();
+        let time = OffsetDateTime::from_unix_timestamp(commit.time().seconds())?;
+        let meta = view.meta()?.hash;
+        let ids = view
+            .ids()?
+            .iter()
+            .filter_map(|entry| {
+                let id = entry.name()?.parse().ok()?;
+                Some((id, ObjectId::from(&entry.id())))
+            })
+            .collect();
+        let branches = view
+            .branches()?
+            .into_iter()
+            .map(|(name, oid)| (name, ObjectId::from(&oid)))
+            .collect();
+        let topics = view
+            .topics()?
+            .into_iter()
+            .map(|(topic, records)| (topic, records.len()))
+            .collect();
+
+        Ok(Self {
+            commit: commit.id(),
+            time,
+            meta,
+            ids,
+            branches,
+            topics,
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Diff {
+    from: State,
+    to: State,
+    meta_changed: bool,
+    ids: Changes<IdentityId>,
+    branches: BTreeMap<Refname, Change>,
+    topics: Changes<Topic>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Changes<T: Ord> {
+    added: BTreeSet<T>,
+    removed: BTreeSet<T>,
+    updated: BTreeSet<T>,
+}
+
+impl<T: Clone + Ord> Changes<T> {
+    fn between<V: PartialEq>(from: &BTreeMap<T, V>, to: &BTreeMap<T, V>) -> Self {
+        let mut changes = Self {
+            added: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            updated: BTreeSet::new(),
+        };
+        for (k, v) in to {
+            match from.get(k) {
+                None => {
+                    changes.added.insert(k.clone());
+                },
+                Some(prev) if prev != v => {
+                    changes.updated.insert(k.clone());
+                },
+                Some(_) => {},
+            }
+        }
+        changes.removed = from
+            .keys()
+            .filter(|k| !to.contains_key(k))
+            .cloned()
+            .collect();
+
+        changes
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Change {
+    from: Option<ObjectId>,
+    to: Option<ObjectId>,
+}
+
+impl Diff {
+    fn between(from: State, to: State) -> Self {
+        let mut branches = BTreeMap::new();
+        for name in from.branches.keys().chain(to.branches.keys()) {
+            let (a, b) = (from.branches.get(name), to.branches.get(name));
+            if a != b {
+                branches.insert(
+                    name.clone(),
+                    Change {
+                        from: a.copied(),
+                        to: b.copied(),
+                    },
+                );
+            }
+        }
+
+        Self {
+            meta_changed: from.meta != to.meta,
+            ids: Changes::between(&from.ids, &to.ids),
+            branches,
+            topics: Changes::between(&from.topics, &to.topics),
+            from,
+            to,
+        }
+    }
+}
+
+/// The drop state as of a past drop commit, or the changes between two
+pub fn at(args: At) -> cmd::Result<cmd::Output> {
+    let repo = git::repo::open(&args.common.git_dir)?;
+    let to = State::from_view(&View::resolve(&repo, &args.drop_ref, &args.at)?)?;
+    match &args.since {
+        None => Ok(cmd::Output::val(to)),
+        Some(since) => {
+            let from = State::from_view(&View::resolve(&repo, &args.drop_ref, since)?)?;
+            Ok(cmd::Output::val(Diff::between(from, to)))
+        },
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::path::PathBuf;
+
+use crate::{
+    cmd::{
+        self,
+        util::args::Refname,
+    },
+    git,
+    patches::{
+        audit,
+        REF_HEADS_PATCHES,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Audit {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    ///
+    /// The roles of the drop determine whose signatures are accepted on
+    /// entries. Defaults to 'refs/heads/patches' in bare repositories, and
+    /// 'refs/it/patches' otherwise.
+    #[clap(long = "drop", value_parser, value_name = "REF")]
+    drop_ref: Option<Refname>,
+    /// Only show the most recent entries
+    #[clap(long, value_parser, value_name = "N")]
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    /// Whether all entries are signed by a drop role member, and none are
+    /// missing
+    intact: bool,
+    /// Problems found with the log
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+    /// The entries, oldest first
+    entries: Vec<audit::Logged>,
+}
+
+/// Print the audit log, and verify its integrity
+pub fn audit(args: Audit) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let drop_ref = match args.drop_ref {
+        Some(r) => r,
+        None if repo.is_bare() => REF_HEADS_PATCHES.parse()?,
+        None => REF_IT_PATCHES.parse()?,
+    };
+
+    let audit::Verification {
+        problems,
+        mut entries,
+    } = audit::verify(&repo, &drop_ref)?;
+    if let Some(limit) = args.limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+
+    Ok(Output {
+        intact: problems.is_empty(),
+        problems,
+        entries,
+    })
+}
@@ -0,0 +1,127 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::BTreeSet,
+    num::NonZeroUsize,
+};
+
+use anyhow::ensure;
+
+use super::{
+    branch_name,
+    edit::{
+        EditState,
+        Output,
+    },
+    Common,
+};
+use crate::{
+    cmd,
+    git::Refname,
+    metadata::{
+        drop::{
+            Annotated,
+            Description,
+            Role,
+        },
+        IdentityId,
+    },
+};
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Branch {
+    /// Add a branch along with its role
+    Add(Add),
+    /// Remove a branch and its role
+    Remove(Remove),
+}
+
+impl Branch {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Add(args) => add(args),
+            Self::Remove(args) => remove(args),
+        }
+        .map(cmd::IntoOutput::into_output)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Add {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// Description of the branch
+    #[clap(long, value_parser, default_value = "")]
+    description: Description,
+    /// Identities allowed to advance the branch
+    #[clap(long = "id", value_parser, value_name = "ID", required = true)]
+    ids: Vec<IdentityId>,
+    /// The number of signatures required
+    #[clap(long, value_parser, value_name = "N", default_value = "1")]
+    threshold: NonZeroUsize,
+    /// The name of the branch
+    #[clap(value_parser = branch_name, value_name = "BRANCH")]
+    name: Refname,
+}
+
+pub fn add(args: Add) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Add branch {}", args.name));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        let ids = args.ids.into_iter().collect::<BTreeSet<_>>();
+        ensure!(
+            args.threshold.get() <= ids.len(),
+            "threshold {} exceeds the number of identities {}",
+            args.threshold,
+            ids.len()
+        );
+        ensure!(
+            !meta.roles.branches.contains_key(&args.name),
+            "branch {} already exists",
+            args.name
+        );
+        meta.roles.branches.insert(
+            args.name,
+            Annotated {
+                role: Role {
+                    ids,
+                    threshold: args.threshold,
+                },
+                description: args.description,
+            },
+        );
+        Ok(())
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Remove {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// The name of the branch
+    #[clap(value_parser = branch_name, value_name = "BRANCH")]
+    name: Refname,
+}
+
+pub fn remove(args: Remove) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Remove branch {}", args.name));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        ensure!(
+            meta.roles.branches.remove(&args.name).is_some(),
+            "no such branch {}",
+            args.name
+        );
+        Ok(())
+    })
+}
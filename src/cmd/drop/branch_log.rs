@@ -0,0 +1,213 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
+use time::{
+    format_description::well_known::Rfc3339,
+    OffsetDateTime,
+    UtcOffset,
+};
+
+use crate::{
+    bundle,
+    cmd::{
+        self,
+        ui::{
+            term::{
+                self,
+                Table,
+            },
+            Msg,
+        },
+        util::args::Refname,
+        IntoOutput as _,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    metadata::IdentityId,
+    patches::{
+        self,
+        iter::dropped,
+        record::Heads,
+        Topic,
+        TrackingBranch,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct BranchLog {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// The branch to show the checkpoint history of, eg. 'refs/heads/main'
+    #[clap(value_parser, value_name = "REFNAME")]
+    branch: Refname,
+    /// Print a table instead of JSON
+    #[clap(long, value_parser)]
+    table: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct Checkpoint {
+    /// The tip the branch was advanced to
+    #[serde(with = "git::serde::oid")]
+    tip: git2::Oid,
+    /// The tip before the update, if any
+    #[serde(
+        with = "git::serde::oid::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    previous: Option<git2::Oid>,
+    /// When the update was recorded, if known
+    #[serde(
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    time: Option<OffsetDateTime>,
+    bundle: bundle::Hash,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submitter: Option<IdentityId>,
+    /// The record of the checkpoint, if found in the drop history
+    #[serde(flatten)]
+    record: Option<RecordInfo>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct RecordInfo {
+    topic: Topic,
+    heads: Heads,
+}
+
+/// List the checkpoints which advanced `branch`, most recent first
+///
+/// The updates are taken from the reflog of the branch's tracking ref, and
+/// annotated with the matching records from the drop history. If there is no
+/// reflog (eg. because the drop was cloned), the history is approximated from
+/// the records referencing the branch alone.
+pub fn branch_log(args: BranchLog) -> cmd::Result<cmd::Output> {
+    let table = args.table;
+    let log = checkpoints(args)?;
+    if !table {
+        return Ok(log.into_output());
+    }
+
+    let mut out = Table::new();
+    out.row(
+        [
+            Msg::ColTime,
+            Msg::ColTip,
+            Msg::ColPrevious,
+            Msg::ColSubmitter,
+            Msg::ColTopic,
+        ]
+        .map(Msg::text),
+    );
+    let short = |oid: git2::Oid| oid.to_string()[..12].to_owned();
+    for c in log {
+        let time = c
+            .time
+            .and_then(|t| t.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        let submitter = c
+            .submitter
+            .map(|id| id.to_string()[..12].to_owned())
+            .unwrap_or_default();
+        let topic = c.record.map(|r| r.topic.to_string()).unwrap_or_default();
+        out.row([
+            time,
+            short(c.tip),
+            c.previous.map(short).unwrap_or_default(),
+            submitter,
+            topic,
+        ]);
+    }
+
+    Ok(cmd::Output::Text(out.render(term::width())))
+}
+
+fn checkpoints(args: BranchLog) -> cmd::Result<Vec<Checkpoint>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let tracking = TrackingBranch::for_branch(&args.branch)?.into_refname();
+    if_not_found_none(repo.find_reference(&tracking))?
+        .ok_or_else(|| anyhow!("no checkpoints recorded for {}", args.branch))?;
+
+    let mut records = BTreeMap::new();
+    let mut chain = Vec::new();
+    for rec in dropped::records_rev(&repo, &args.drop_ref) {
+        let rec = rec?;
+        if let Some(tip) = rec.meta.bundle.references.get(&args.branch) {
+            let tip = git2::Oid::try_from(tip)?;
+            chain.push((*rec.bundle_hash(), tip));
+            records.insert(
+                *rec.bundle_hash(),
+                RecordInfo {
+                    topic: rec.topic,
+                    heads: rec.heads,
+                },
+            );
+        }
+    }
+
+    let mut log = Vec::new();
+    let reflog = repo.reflog(&tracking)?;
+    if reflog.is_empty() {
+        let mut previous: Option<git2::Oid> = None;
+        for (hash, tip) in chain {
+            if let Some(prev) = previous {
+                if tip == prev || !repo.graph_descendant_of(tip, prev)? {
+                    continue;
+                }
+            }
+            log.push(Checkpoint {
+                tip,
+                previous,
+                time: None,
+                bundle: hash,
+                submitter: None,
+                record: records.remove(&hash),
+            });
+            previous = Some(tip);
+        }
+        log.reverse();
+    } else {
+        for entry in reflog.iter() {
+            let parsed = entry.message().and_then(patches::parse_update_tip_reflog);
+            let (hash, submitter) = match parsed {
+                Some(x) => x,
+                None => continue,
+            };
+            let time = {
+                let t = entry.committer().when();
+                let ofs = UtcOffset::from_whole_seconds(t.offset_minutes() * 60)?;
+                OffsetDateTime::from_unix_timestamp(t.seconds())?.replace_offset(ofs)
+            };
+            let previous = Some(entry.id_old()).filter(|oid| !oid.is_zero());
+            log.push(Checkpoint {
+                tip: entry.id_new(),
+                previous,
+                time: Some(time),
+                bundle: hash,
+                submitter: Some(submitter),
+                record: records.get(&hash).cloned(),
+            });
+        }
+    }
+
+    Ok(log)
+}
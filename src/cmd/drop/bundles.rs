@@ -10,6 +10,13 @@ pub use prune::{
 };
 
 mod sync;
+pub(super) use sync::{
+    def_jobs,
+    fetch_bundles,
+    verify_history,
+    Filter as SyncFilter,
+    Options as SyncOptions,
+};
 pub use sync::{
     sync,
     Sync,
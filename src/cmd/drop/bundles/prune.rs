@@ -3,8 +3,14 @@
 
 use std::{
     collections::BTreeSet,
-    fs,
-    path::PathBuf,
+    fs::{
+        self,
+        File,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
     str::FromStr,
 };
 
@@ -24,10 +30,6 @@ use crate::{
     patches::iter::dropped,
 };
 
-// TODO:
-//
-// - option to prune bundles made obsolete by snapshots
-
 #[derive(Debug, clap::Args)]
 pub struct Prune {
     /// Path to the drop repository
@@ -56,9 +58,45 @@ pub struct Prune {
     /// Also remove location files (.uris)
     #[clap(long, value_parser)]
     remove_locations: bool,
+    /// Also prune bundles superseded by the latest full snapshot
+    ///
+    /// These are the bundles 'drop bundles sync' would not fetch: all records
+    /// preceding the latest full snapshot, and the records between it and the
+    /// latest snapshot, except for merge points and incremental snapshots.
+    /// Encrypted bundles are never included in snapshots, and thus never
+    /// superseded. If no full snapshot exists, nothing is superseded.
+    #[clap(long, value_parser)]
+    superseded: bool,
+    /// Move pruned bundles to this directory instead of deleting them
+    ///
+    /// Chunked bundles are reassembled into the archive.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+    )]
+    archive: Option<PathBuf>,
 }
 
-pub fn prune(args: Prune) -> cmd::Result<Vec<bundle::Hash>> {
+#[derive(serde::Serialize)]
+pub struct Output {
+    /// Whether this was a dry run
+    dry_run: bool,
+    /// Bundles not referred to by any of the drops
+    pruned: Vec<bundle::Hash>,
+    /// Bundles superseded by a snapshot
+    superseded: Vec<bundle::Hash>,
+    /// Where the pruned bundles were moved to, if anywhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive: Option<PathBuf>,
+    /// Number of unreferenced chunks removed
+    chunks: usize,
+    /// Number of bytes freed by removing chunks
+    chunks_len: u64,
+}
+
+pub fn prune(args: Prune) -> cmd::Result<Output> {
     let repo = git::repo::open_bare(&args.git_dir)?;
     let bundle_dir = if args.bundle_dir.is_relative() {
         repo.path().join(args.bundle_dir)
@@ -66,36 +104,75 @@ pub fn prune(args: Prune) -> cmd::Result<Vec<bundle::Hash>> {
         args.bundle_dir
     };
 
+    if let Some(dir) = &args.archive {
+        if !args.dry_run {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
     let mut seen = BTreeSet::new();
+    let mut needed = BTreeSet::new();
     for short in &args.drop_refs {
         let drop_ref = repo.resolve_reference_from_short_name(short)?;
         let ref_name = drop_ref.name().expect("drop references to be valid utf8");
         info!("Collecting bundle hashes from {ref_name} ...");
+        let mut since_snapshot = BTreeSet::new();
+        let mut chasing_snapshots = false;
+        let mut full_snapshot = false;
         for record in dropped::records(&repo, ref_name) {
             let record = record?;
-            seen.insert(*record.bundle_hash());
+            let hash = *record.bundle_hash();
+            seen.insert(hash);
+            if !args.superseded {
+                continue;
+            }
+            // Mirrors what sync fetches, except that encrypted bundles are
+            // not included in snapshots
+            if record.is_encrypted() {
+                since_snapshot.insert(hash);
+            } else if full_snapshot {
+                continue;
+            } else if record.is_snapshot() {
+                chasing_snapshots = true;
+                full_snapshot = record.bundle_info().prerequisites.is_empty();
+                since_snapshot.insert(hash);
+            } else if !chasing_snapshots || record.is_mergepoint() {
+                since_snapshot.insert(hash);
+            }
+        }
+        if full_snapshot {
+            info!("Found full snapshot in {ref_name}");
+            needed.extend(since_snapshot);
+        } else {
+            needed.extend(seen.iter().copied());
         }
     }
 
     info!("Traversing bundle dir {} ...", bundle_dir.display());
     let mut pruned = Vec::new();
+    let mut superseded = Vec::new();
     for entry in fs::read_dir(&bundle_dir)? {
         let entry = entry?;
         let path = entry.path();
         match path.extension() {
-            Some(ext) if ext == bundle::FILE_EXTENSION => {
+            Some(ext) if ext == bundle::FILE_EXTENSION || ext == bundle::chunks::FILE_EXTENSION => {
                 let name = path.file_stem();
                 match name
                     .and_then(|n| n.to_str())
                     .and_then(|s| bundle::Hash::from_str(s).ok())
                 {
                     Some(hash) => {
-                        if !seen.contains(&hash) {
-                            if !args.dry_run {
-                                fs::remove_file(&path)?;
-                            }
-                            pruned.push(hash);
+                        let list = if !seen.contains(&hash) {
+                            &mut pruned
+                        } else if args.superseded && !needed.contains(&hash) {
+                            &mut superseded
+                        } else {
+                            continue;
+                        };
+                        if !args.dry_run {
+                            remove(&path, args.archive.as_deref())?;
                         }
+                        list.push(hash);
                     },
                     None => warn!("Ignoring {}: file name not a bundle hash", path.display()),
                 }
@@ -105,9 +182,48 @@ pub fn prune(args: Prune) -> cmd::Result<Vec<bundle::Hash>> {
                     fs::remove_file(&path)?;
                 }
             },
+            None if path
+                .file_name()
+                .map_or(false, |name| name == bundle::chunks::DIR) => {},
             _ => warn!("Ignoring {}: missing .bundle", path.display()),
         }
     }
 
-    Ok(pruned)
+    let (chunks, chunks_len) = bundle::chunks::gc(&bundle_dir, args.dry_run)?;
+    if chunks > 0 {
+        info!("Removed {chunks} unreferenced chunks ({chunks_len} bytes)");
+    }
+
+    Ok(Output {
+        dry_run: args.dry_run,
+        pruned,
+        superseded,
+        archive: args.archive,
+        chunks,
+        chunks_len,
+    })
+}
+
+/// Remove the bundle (or chunk manifest) at `path`, moving it to `archive` if
+/// given
+fn remove(path: &Path, archive: Option<&Path>) -> cmd::Result<()> {
+    if let Some(dir) = archive {
+        let name = path.with_extension(bundle::FILE_EXTENSION);
+        let dest = dir.join(name.file_name().expect("bundle path has a file name"));
+        if path
+            .extension()
+            .map_or(false, |ext| ext == bundle::FILE_EXTENSION)
+        {
+            if fs::rename(path, &dest).is_ok() {
+                return Ok(());
+            }
+            // Possibly on a different file system
+            fs::copy(path, &dest)?;
+        } else {
+            bundle::chunks::restore(&name, File::create(&dest)?)?;
+        }
+    }
+    fs::remove_file(path)?;
+
+    Ok(())
 }
@@ -3,6 +3,10 @@
 
 use std::{
     borrow::Cow,
+    collections::{
+        BTreeSet,
+        HashSet,
+    },
     mem,
     num::NonZeroUsize,
     path::PathBuf,
@@ -16,7 +20,10 @@ use std::{
     },
 };
 
-use anyhow::anyhow;
+use anyhow::{
+    anyhow,
+    bail,
+};
 use clap::ValueHint;
 use either::Either::{
     Left,
@@ -30,7 +37,11 @@ use crate::{
     cfg,
     cmd::{
         self,
-        drop::Common,
+        args::Refname,
+        drop::{
+            clone::fetch,
+            Common,
+        },
         ui::{
             debug,
             info,
@@ -45,7 +56,14 @@ use crate::{
         self,
         iter::dropped,
         record,
+        view::{
+            self,
+            View,
+        },
+        Record,
+        Topic,
         REF_IT_PATCHES,
+        REF_IT_VERIFIED,
     },
 };
 
@@ -71,9 +89,21 @@ pub struct Sync {
     /// Name of the git ref holding the drop metadata history
     #[clap(long = "drop", value_parser, value_name = "REF")]
     drop_ref: Option<String>,
+    /// Name of the git remote to fetch the drop history from
+    ///
+    /// If the remote is configured, the drop history is fetched before the
+    /// bundles, and must extend the history last verified from the remote.
+    #[clap(long, value_parser, value_name = "NAME", default_value = "origin")]
+    remote: String,
+    /// Accept a drop history which does not extend the one last verified from
+    /// the remote, or whose records are out of order
+    #[clap(long, value_parser)]
+    accept_rewrite: bool,
     /// Base URL to fetch from
+    ///
+    /// If not given, the value of the 'it.bundleUrl' git config is used.
     #[clap(long, value_parser, value_name = "URL", value_hint = ValueHint::Url)]
-    url: Url,
+    url: Option<Url>,
     /// Fetch via IPFS
     #[clap(
         long,
@@ -90,13 +120,39 @@ pub struct Sync {
     /// Ignore snapshots if encountered
     #[clap(long, value_parser)]
     no_snapshots: bool,
+    /// Only fetch the bundles of records since this point in the drop history
+    ///
+    /// Either a date in RFC 3339 format, or a drop commit in 'git rev-parse'
+    /// syntax, which is included. Snapshots are ignored if --since or --topic
+    /// is given.
+    #[clap(long, value_parser, value_name = "DATE|REV")]
+    since: Option<view::At>,
+    /// Only fetch the bundles of records on this topic; may be given
+    /// multiple times
+    #[clap(
+        long = "topic",
+        visible_alias = "topics",
+        value_parser,
+        value_name = "TOPIC",
+        num_args = 1..
+    )]
+    topics: Vec<Topic>,
+    /// With --since or --topic, do not fetch the bundles providing the
+    /// prerequisites of the selected ones
+    ///
+    /// By default, bundles conveying prerequisite commits which are not
+    /// present locally are fetched as well, so the selected bundles can be
+    /// unbundled. If the prerequisites are available by other means, eg. from
+    /// the upstream repository, this saves downloading them.
+    #[clap(long, value_parser)]
+    no_deps: bool,
     /// Maximum number of concurrent downloads. Default is the number of
     /// available cores.
     #[clap(short, long, value_parser, default_value_t = def_jobs())]
     jobs: NonZeroUsize,
 }
 
-fn def_jobs() -> NonZeroUsize {
+pub(in crate::cmd::drop) fn def_jobs() -> NonZeroUsize {
     NonZeroUsize::new(num_cpus::get()).unwrap_or_else(|| NonZeroUsize::new(1).unwrap())
 }
 
@@ -115,10 +171,238 @@ pub fn sync(args: Sync) -> cmd::Result<Vec<bundle::Info>> {
             .to_owned(),
         None => REF_IT_PATCHES.to_owned(),
     };
+    if if_not_found_none(repo.find_remote(&args.remote))?.is_some() {
+        info!("Fetching {drop_ref} from {}", args.remote);
+        fetch(repo.path(), &args.remote)?;
+        verify_history(&repo, &args.remote, &drop_ref, args.accept_rewrite)?;
+    } else {
+        debug!(
+            "Remote {} not configured, not fetching {drop_ref}",
+            args.remote
+        );
+    }
+    let url = match args.url {
+        Some(url) => url,
+        None => if_not_found_none(cfg::git::open(&repo)?.get_string(cfg::git::IT_BUNDLE_URL))?
+            .ok_or_else(|| anyhow!("no --url given and '{}' not set", cfg::git::IT_BUNDLE_URL))?
+            .parse()?,
+    };
+
+    let fetched = fetch_bundles(
+        &repo,
+        &drop_ref,
+        Options {
+            bundle_dir,
+            url,
+            ipfs_gateway: args.ipfs_gateway,
+            overwrite: args.overwrite,
+            no_snapshots: args.no_snapshots,
+            filter: Filter {
+                since: args.since,
+                topics: args.topics.into_iter().collect(),
+                no_deps: args.no_deps,
+            },
+            jobs: args.jobs,
+        },
+    )?;
+    if let Err(e) = cmd::topic::notify(&repo, &drop_ref) {
+        warn!("Failed to notify about subscribed topics: {e:#}");
+    }
+
+    Ok(fetched)
+}
+
+/// Check that `drop_ref` extends the history last verified from `remote`
+///
+/// That is, the last verified tip must be an ancestor of the current one, and
+/// no drop commit since then may be older than any of its parents. If either
+/// is not the case, `drop_ref` is reset to the last verified tip, unless
+/// `accept_rewrite` is true.
+///
+/// The verified tip is kept in a ref below [`REF_IT_VERIFIED`], whose reflog
+/// records any accepted rewrites.
+pub(in crate::cmd::drop) fn verify_history(
+    repo: &git2::Repository,
+    remote: &str,
+    drop_ref: &str,
+    accept_rewrite: bool,
+) -> cmd::Result<()> {
+    let verified_ref = Refname::try_from(format!(
+        "{REF_IT_VERIFIED}/{remote}/{}",
+        drop_ref.strip_prefix("refs/").unwrap_or(drop_ref)
+    ))?;
+    let tip = repo.refname_to_id(drop_ref)?;
+    let verified = if_not_found_none(repo.refname_to_id(&verified_ref))?;
+    if verified == Some(tip) {
+        return Ok(());
+    }
+
+    let mut problem = None;
+    if let Some(verified) = verified {
+        if !repo.graph_descendant_of(tip, verified)? {
+            let divergence = match if_not_found_none(repo.merge_base(tip, verified))? {
+                Some(base) => format!("diverging after {base}"),
+                None => "with no history in common".to_owned(),
+            };
+            problem = Some(format!(
+                "{drop_ref} was rewritten by {remote}: {tip} does not extend {verified}, {divergence}"
+            ));
+        }
+    }
+    if problem.is_none() {
+        let mut walk = repo.revwalk()?;
+        walk.push(tip)?;
+        if let Some(verified) = verified {
+            walk.hide(verified)?;
+        }
+        for oid in walk {
+            let commit = repo.find_commit(oid?)?;
+            let time = commit.time().seconds();
+            if let Some(parent) = commit.parents().find(|p| p.time().seconds() > time) {
+                problem = Some(format!(
+                    "{drop_ref} from {remote} is out of order: {} is older than its parent {}",
+                    commit.id(),
+                    parent.id()
+                ));
+                break;
+            }
+        }
+    }
+
+    let reflog = match problem {
+        None => format!("it: verified {drop_ref} from {remote}"),
+        Some(problem) => {
+            warn!("{problem}");
+            if !accept_rewrite {
+                if let Some(verified) = verified {
+                    repo.reference(
+                        drop_ref,
+                        verified,
+                        true,
+                        &format!("it: reset {drop_ref} to last verified tip"),
+                    )?;
+                    info!("Reset {drop_ref} to {verified}");
+                }
+                bail!("refusing to accept the history of {drop_ref} from {remote}, use --accept-rewrite to override");
+            }
+            format!("it: accepted {problem}")
+        },
+    };
+    repo.reference(&verified_ref, tip, true, &reflog)?;
+
+    Ok(())
+}
+
+pub(in crate::cmd::drop) struct Options {
+    /// Absolute path of the bundle directory
+    pub bundle_dir: PathBuf,
+    pub url: Url,
+    pub ipfs_gateway: Url,
+    pub overwrite: bool,
+    pub no_snapshots: bool,
+    pub filter: Filter,
+    pub jobs: NonZeroUsize,
+}
+
+/// Restricts which bundles [`fetch_bundles`] fetches
+#[derive(Default)]
+pub(in crate::cmd::drop) struct Filter {
+    /// Only records since this point in the drop history
+    pub since: Option<view::At>,
+    /// Only records on these topics, unless empty
+    pub topics: BTreeSet<Topic>,
+    /// Do not fetch the bundles providing the prerequisites of the selected
+    /// records
+    pub no_deps: bool,
+}
+
+impl Filter {
+    fn is_empty(&self) -> bool {
+        self.since.is_none() && self.topics.is_empty()
+    }
+
+    /// Select the records matching the filter, most recent first
+    ///
+    /// Unless `no_deps` is set, this includes the records whose references
+    /// provide prerequisites of selected records which are not present in
+    /// `repo`, transitively. Snapshots are never selected.
+    fn select(&self, repo: &git2::Repository, drop_ref: &str) -> cmd::Result<Vec<Record>> {
+        let (since_time, since_commit) = match &self.since {
+            None => (None, None),
+            Some(view::At::Date(date)) => (Some(date.unix_timestamp()), None),
+            Some(at) => (None, Some(View::resolve(repo, drop_ref, at)?.commit().id())),
+        };
+
+        let odb = repo.odb()?;
+        let mut wanted = HashSet::new();
+        let mut selected = Vec::new();
+        let mut in_range = true;
+        let mut walk = repo.revwalk()?;
+        walk.push_ref(drop_ref)?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        for oid in walk {
+            let commit = repo.find_commit(oid?)?;
+            if since_time.map_or(false, |since| commit.time().seconds() < since) {
+                in_range = false;
+            }
+            if !in_range && wanted.is_empty() {
+                break;
+            }
+            if Topic::from_commit(&commit)?.is_some() {
+                let record = Record::from_commit(repo, &commit)?;
+                if !record.is_snapshot() {
+                    let info = record.bundle_info();
+                    let mut provides = false;
+                    for oid in info.references.values() {
+                        provides |= wanted.remove(&git2::Oid::try_from(oid)?);
+                    }
+                    let matches =
+                        in_range && (self.topics.is_empty() || self.topics.contains(&record.topic));
+                    if matches || provides {
+                        if !self.no_deps {
+                            for oid in &info.prerequisites {
+                                let oid = git2::Oid::try_from(oid)?;
+                                if !odb.exists(oid) {
+                                    wanted.insert(oid);
+                                }
+                            }
+                        }
+                        selected.push(record);
+                    }
+                }
+            }
+            if since_commit == Some(commit.id()) {
+                in_range = false;
+            }
+        }
+        if !wanted.is_empty() {
+            warn!(
+                "No record provides the prerequisite commits {}, the selected bundles may not apply",
+                wanted
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(selected)
+    }
+}
+
+/// Fetch the bundles of the records in `drop_ref`, most recent first
+///
+/// Stops at the first full snapshot, unless `no_snapshots` is set. If
+/// `filter` is not empty, only the records selected by it are considered.
+pub(in crate::cmd::drop) fn fetch_bundles(
+    repo: &git2::Repository,
+    drop_ref: &str,
+    args: Options,
+) -> cmd::Result<Vec<bundle::Info>> {
     let base_url = args.url.join("bundles/")?;
     let fetcher = Arc::new(Fetcher {
         fetcher: bundle::Fetcher::default(),
-        bundle_dir,
+        bundle_dir: args.bundle_dir,
         base_url: base_url.clone(),
         ipfs_gateway: args.ipfs_gateway,
     });
@@ -126,8 +410,15 @@ pub fn sync(args: Sync) -> cmd::Result<Vec<bundle::Info>> {
     let pool = ThreadPool::new(args.jobs.get());
 
     let fetched = Arc::new(Mutex::new(Vec::new()));
+    let records: Box<dyn Iterator<Item = cmd::Result<Record>>> = if args.filter.is_empty() {
+        Box::new(dropped::records(repo, drop_ref))
+    } else {
+        let selected = args.filter.select(repo, drop_ref)?;
+        info!("Selected {} records", selected.len());
+        Box::new(selected.into_iter().map(Ok))
+    };
     let mut chasing_snaphots = false;
-    for record in dropped::records(&repo, &drop_ref) {
+    for record in records {
         let record = record?;
         let hexdig = record.bundle_hash().to_string();
 
@@ -143,7 +434,7 @@ pub fn sync(args: Sync) -> cmd::Result<Vec<bundle::Info>> {
             continue;
         }
 
-        if !args.overwrite && record.bundle_path(&fetcher.bundle_dir).exists() {
+        if !args.overwrite && bundle::chunks::exists(&record.bundle_path(&fetcher.bundle_dir)) {
             info!("Skipping existing bundle {hexdig}");
             continue;
         }
@@ -0,0 +1,298 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    fs,
+    num::NonZeroUsize,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use clap::ValueHint;
+use url::Url;
+
+use super::{
+    bundles::{
+        def_jobs,
+        fetch_bundles,
+        verify_history,
+        SyncFilter,
+        SyncOptions,
+    },
+    unbundle::unbundle_records,
+};
+use crate::{
+    bundle,
+    cfg,
+    cmd::{
+        self,
+        args::Refname,
+        ui::{
+            debug,
+            info,
+            warn,
+        },
+    },
+    discovery,
+    git,
+    metadata::{
+        self,
+        IdentityId,
+    },
+    net,
+    patches::{
+        DropHead,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Clone {
+    /// URL of the git repository holding the drop
+    ///
+    /// May also be a domain name advertising the drop via DNS, ie. by SRV
+    /// records at '_it._tcp.<domain>'. Root identities pinned in TXT records
+    /// at the same name are verified.
+    #[clap(value_parser, value_name = "URL", value_hint = ValueHint::Url)]
+    url: String,
+    /// Directory to create the local drop in
+    ///
+    /// If not given, the last path component of URL, or the domain name, is
+    /// used.
+    #[clap(value_parser, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    dir: Option<PathBuf>,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Name of the git remote to track the drop from
+    #[clap(long, value_parser, value_name = "NAME", default_value = "origin")]
+    origin: String,
+    /// Trusted identity, may be given multiple times
+    ///
+    /// The initial drop metadata in the history must name only trusted
+    /// identities in its root role. If none are given, the identities pinned
+    /// via DNS are trusted, or else the identities found are trusted on first
+    /// use.
+    #[clap(long = "trust", value_parser, value_name = "ID")]
+    trust: Vec<IdentityId>,
+    /// Base URL to fetch bundles from
+    ///
+    /// If not given, URL is used if it is a HTTP(S) URL.
+    #[clap(long, value_parser, value_name = "URL", value_hint = ValueHint::Url)]
+    bundle_url: Option<Url>,
+    /// Fetch via IPFS
+    #[clap(
+        long,
+        value_parser,
+        value_name = "URL",
+        value_hint = ValueHint::Url,
+        env = "IPFS_GATEWAY",
+        default_value_t = Url::parse("https://ipfs.io").unwrap(),
+    )]
+    ipfs_gateway: Url,
+    /// Only clone the drop history, don't fetch and unbundle bundles
+    #[clap(long, value_parser)]
+    no_bundles: bool,
+    /// Maximum number of concurrent downloads. Default is the number of
+    /// available cores.
+    #[clap(short, long, value_parser, default_value_t = def_jobs())]
+    jobs: NonZeroUsize,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    repo: PathBuf,
+    #[serde(rename = "ref")]
+    refname: Refname,
+    root: BTreeSet<IdentityId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bundle_url: Option<Url>,
+    fetched: Vec<bundle::Hash>,
+    updated: BTreeMap<Refname, git::serde::oid::Oid>,
+}
+
+pub fn clone(mut args: Clone) -> cmd::Result<Output> {
+    let discovered = if discovery::is_domain(&args.url) {
+        let resolver = cfg::git::dns_resolver(&cfg::git::open_default()?)?;
+        let found = discovery::discover(&resolver, &args.url)?;
+        info!("Discovered drop at {} via {}", found.url(), found.domain);
+        if args.dir.is_none() {
+            args.dir = Some(PathBuf::from(&found.domain));
+        }
+        if args.trust.is_empty() {
+            args.trust = found.roots.iter().copied().collect();
+        }
+        args.url = found.url().to_string();
+        Some(found)
+    } else {
+        None
+    };
+    let git_dir = match &args.dir {
+        Some(dir) => dir.clone(),
+        None => args
+            .url
+            .trim_end_matches('/')
+            .rsplit(['/', ':'])
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("unable to determine directory name from {}", args.url))?,
+    };
+    let repo = git::repo::init(
+        &git_dir,
+        git::repo::InitOpts {
+            bare: true,
+            description: "`it` drop",
+            initial_head: &args.drop_ref,
+        },
+    )?;
+
+    match setup(&repo, &args, discovered.as_ref()) {
+        Ok(out) => Ok(out),
+        Err(e) => {
+            debug!("Removing {}", git_dir.display());
+            if let Err(rm) = fs::remove_dir_all(&git_dir) {
+                warn!("Failed to remove {}: {rm}", git_dir.display());
+            }
+            Err(e)
+        },
+    }
+}
+
+fn setup(
+    repo: &git2::Repository,
+    args: &Clone,
+    discovered: Option<&discovery::Discovered>,
+) -> cmd::Result<Output> {
+    let drop_ref = &args.drop_ref;
+    repo.remote_with_fetch(&args.origin, &args.url, &format!("+{drop_ref}:{drop_ref}"))?;
+    info!("Fetching {drop_ref} from {}", args.url);
+    fetch(repo.path(), &args.origin)?;
+    verify_history(repo, &args.origin, drop_ref, false)?;
+
+    let drop = DropHead::from_refname(repo, drop_ref)?;
+    let root = genesis_root(repo, &drop.meta)?;
+    if let Some(found) = discovered {
+        found.verify_roots(&root)?;
+    }
+    if args.trust.is_empty() {
+        warn!("No --trust given, trusting root identities on first use:");
+        for id in &root {
+            warn!("  {id}");
+        }
+    } else {
+        let untrusted = root
+            .iter()
+            .filter(|id| !args.trust.contains(id))
+            .collect::<Vec<_>>();
+        ensure!(
+            untrusted.is_empty(),
+            "initial drop metadata names untrusted root identities: {untrusted:?}"
+        );
+    }
+
+    let mut cfg = repo.config()?;
+    // Keep a reflog of the tracking branches, cf. 'drop branch-log'
+    cfg.set_str("core.logAllRefUpdates", "always")?;
+    let bundle_url = args.bundle_url.clone().or_else(|| {
+        Url::parse(&args.url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+    });
+    if let Some(url) = &bundle_url {
+        cfg.set_str(cfg::git::IT_BUNDLE_URL, url.as_str())?;
+    }
+
+    let mut fetched = Vec::new();
+    let mut updated = BTreeMap::new();
+    if args.no_bundles {
+        info!("--no-bundles given, not fetching bundles");
+    } else if let Some(url) = &bundle_url {
+        let bundle_dir = repo.path().join(cfg::paths::bundles());
+        fetched = fetch_bundles(
+            repo,
+            drop_ref,
+            SyncOptions {
+                bundle_dir: bundle_dir.clone(),
+                url: url.clone(),
+                ipfs_gateway: args.ipfs_gateway.clone(),
+                overwrite: false,
+                no_snapshots: false,
+                filter: SyncFilter::default(),
+                jobs: args.jobs,
+            },
+        )?
+        .into_iter()
+        .map(|info| info.hash)
+        .collect();
+        updated = unbundle_records(repo, &bundle_dir, drop_ref, true, None, args.jobs)?;
+    } else {
+        warn!("No bundle URL known, not fetching bundles");
+    }
+
+    Ok(Output {
+        repo: repo.path().to_owned(),
+        refname: drop_ref.clone(),
+        root,
+        bundle_url,
+        fetched,
+        updated,
+    })
+}
+
+/// Run `git fetch` for `remote` in the repository at `git_dir`
+///
+/// Shelling out to git makes all transports available which git knows about.
+pub(super) fn fetch(git_dir: &Path, remote: &str) -> cmd::Result<()> {
+    let mut git = Command::new("git");
+    git.arg("--git-dir").arg(git_dir);
+    if let Some(proxy) = net::git_proxy()? {
+        git.arg("-c").arg(format!("http.proxy={proxy}"));
+    }
+    let status = git
+        .args(["fetch", "--quiet", remote])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .status()?;
+    ensure!(status.success(), "git fetch failed: {status}");
+
+    Ok(())
+}
+
+/// The root role identities of the first drop metadata in the history of
+/// `meta`
+fn genesis_root(
+    repo: &git2::Repository,
+    meta: &metadata::drop::Verified,
+) -> cmd::Result<BTreeSet<IdentityId>> {
+    let find_prev = metadata::git::find_parent::<metadata::Drop>(repo);
+    let mut root = meta.roles.root.ids.clone();
+    let mut prev = meta.prev.clone();
+    while let Some(hash) = prev {
+        let signed = find_prev(&hash)?;
+        root = signed.signed.roles.root.ids;
+        prev = signed.signed.prev;
+    }
+
+    Ok(root)
+}
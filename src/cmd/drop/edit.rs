@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
+    fs,
     iter,
     path::PathBuf,
 };
@@ -10,9 +11,11 @@ use anyhow::{
     anyhow,
     ensure,
 };
+use clap::ValueHint;
 
 use super::{
     find_id,
+    find_ids,
     Common,
     Editable,
 };
@@ -25,6 +28,11 @@ use crate::{
             edit_commit_message,
             edit_metadata,
             info,
+            warn,
+        },
+        util::{
+            args::IdSearchPath,
+            auth,
         },
         Aborted,
     },
@@ -37,18 +45,22 @@ use crate::{
     keys::Signer,
     metadata::{
         self,
+        drop::RoleName,
         git::{
             FromGit,
             GitDrop,
             META_FILE_ALTERNATES,
             META_FILE_DROP,
             META_FILE_MIRRORS,
+            META_FILE_POLICY,
         },
+        DateTime,
         IdentityId,
         Metadata,
     },
     patches::{
         self,
+        FILE_AUTO_REPLY,
         REF_HEADS_PATCHES,
         REF_IT_PATCHES,
     },
@@ -74,6 +86,48 @@ enum Cmd {
     Mirrors,
     /// Edit the alternates file
     Alternates,
+    /// Set or remove the automated reply to first-time submitters
+    AutoReply(AutoReply),
+    /// Set or remove the usage policy submitters must acknowledge
+    Policy(Policy),
+}
+
+#[derive(Debug, clap::Args)]
+struct AutoReply {
+    /// Remove the template, disabling automated replies
+    #[clap(long, value_parser, conflicts_with = "file")]
+    remove: bool,
+    /// File to read the template from
+    ///
+    /// The placeholders `{topic}`, `{submitter}` and `{patch}` are replaced
+    /// with the respective values of the accepted patch.
+    #[clap(
+        value_parser,
+        value_name = "FILE",
+        required_unless_present = "remove",
+        value_hint = ValueHint::FilePath,
+    )]
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct Policy {
+    /// Remove the policy, no longer requiring acknowledgments
+    #[clap(long, value_parser, conflicts_with_all = &["file", "expires"])]
+    remove: bool,
+    /// File to read the policy text from
+    ///
+    /// Changing the text requires submitters to acknowledge the new policy.
+    #[clap(
+        value_parser,
+        value_name = "FILE",
+        required_unless_present = "remove",
+        value_hint = ValueHint::FilePath,
+    )]
+    file: Option<PathBuf>,
+    /// Date/time after which the policy should no longer be considered valid
+    #[clap(long, value_parser, value_name = "DATETIME")]
+    expires: Option<DateTime>,
 }
 
 #[derive(serde::Serialize)]
@@ -86,67 +140,111 @@ pub struct Output {
 }
 
 pub fn edit(args: Edit) -> cmd::Result<Output> {
-    let Common { git_dir, id_path } = args.common;
-
-    let repo = git::repo::open(git_dir)?;
-    let drop_ref = if repo.is_bare() {
-        REF_HEADS_PATCHES
-    } else {
-        REF_IT_PATCHES
-    }
-    .parse()
-    .unwrap();
-
-    let id_path = id_path.open_git();
-    git::add_alternates(&repo, &id_path)?;
-    let cfg = repo.config()?.snapshot()?;
-    let signer = cfg::signer(&cfg, ui::askpass)?;
-    let signer_id = SignerIdentity::new(&signer, &repo, &cfg, &id_path)?;
-    let meta = metadata::Drop::from_tip(&repo, &drop_ref)?;
-
-    let s = EditState {
-        repo,
-        id_path,
-        signer,
-        signer_id,
-        drop_ref,
-        meta,
-    };
-
+    let s = EditState::open(args.common)?;
     match args.cmd {
         None => s.edit_drop(args.message),
         Some(Cmd::Mirrors) => s.edit_mirrors(args.message),
         Some(Cmd::Alternates) => s.edit_alternates(args.message),
+        Some(Cmd::AutoReply(auto_reply)) => s.edit_auto_reply(auto_reply, args.message),
+        Some(Cmd::Policy(policy)) => s.edit_policy(policy, args.message),
     }
 }
 
-struct EditState<S> {
+pub(super) struct EditState<S> {
     repo: git2::Repository,
+    search_path: IdSearchPath,
     id_path: Vec<git2::Repository>,
     signer: S,
     signer_id: SignerIdentity,
     drop_ref: Refname,
     meta: GitDrop,
+    audit: bool,
+}
+
+impl EditState<Box<dyn Signer>> {
+    pub(super) fn open(Common { git_dir, id_path }: Common) -> cmd::Result<Self> {
+        let repo = git::repo::open(git_dir)?;
+        let drop_ref = if repo.is_bare() {
+            REF_HEADS_PATCHES
+        } else {
+            REF_IT_PATCHES
+        }
+        .parse()
+        .unwrap();
+
+        let meta = metadata::Drop::from_tip(&repo, &drop_ref)?;
+        let search_path = id_path;
+        let id_path = search_path.open_git_with(&repo, &meta.signed.signed.id_path)?;
+        let cfg = cfg::git::open(&repo)?;
+        let signer = cfg::signer(&cfg, ui::askpass)?;
+        let signer_id = SignerIdentity::new(&signer, &cfg, &id_path)?;
+        let audit = cfg::git::audit_log(&cfg)?;
+
+        Ok(Self {
+            repo,
+            search_path,
+            id_path,
+            signer,
+            signer_id,
+            drop_ref,
+            meta,
+            audit,
+        })
+    }
 }
 
 impl<S: Signer + 'static> EditState<S> {
-    fn edit_drop(mut self, message: Option<String>) -> cmd::Result<Output> {
+    fn edit_drop(self, message: Option<String>) -> cmd::Result<Output> {
+        let parent = &self.meta.signed.signed;
+        auth::ensure_role(
+            parent,
+            &self.signer_id.id,
+            RoleName::Root,
+            "edit the drop metadata",
+        )?;
+        let meta = edit_metadata(Editable::from(parent.clone()))?.try_into()?;
+
+        self.commit_drop(meta, message)
+    }
+
+    /// Modify the drop metadata by `f` without user interaction
+    ///
+    /// The result is subject to the same validation as an interactive edit.
+    pub(super) fn update_drop<F>(self, message: String, f: F) -> cmd::Result<Output>
+    where
+        F: FnOnce(&mut metadata::Drop) -> cmd::Result<()>,
+    {
+        let mut meta = self.meta.signed.signed.clone();
+        auth::ensure_role(
+            &meta,
+            &self.signer_id.id,
+            RoleName::Root,
+            "edit the drop metadata",
+        )?;
+        f(&mut meta)?;
+        let meta = Editable::from(meta).try_into()?;
+
+        self.commit_drop(meta, Some(message))
+    }
+
+    fn commit_drop(
+        mut self,
+        mut meta: metadata::Drop,
+        message: Option<String>,
+    ) -> cmd::Result<Output> {
         let GitDrop {
             hash: parent_hash,
             signed: metadata::Signed { signed: parent, .. },
         } = self.meta;
 
-        ensure!(
-            self.signer_id.can_edit_drop(&parent),
-            "signer identity not allowed to edit the drop metadata"
-        );
-
-        let mut meta: metadata::Drop = edit_metadata(Editable::from(parent.clone()))?.try_into()?;
         if meta.canonicalise()? == parent.canonicalise()? {
             info!("Document unchanged");
             cmd::abort!();
         }
         meta.prev = Some(parent_hash);
+        if meta.id_path != parent.id_path {
+            self.id_path = self.search_path.open_git_with(&self.repo, &meta.id_path)?;
+        }
 
         let signed = Metadata::drop(&meta).sign(iter::once(&mut self.signer as &mut dyn Signer))?;
 
@@ -164,12 +262,7 @@ impl<S: Signer + 'static> EditState<S> {
         let mut ids = self
             .repo
             .treebuilder(get_tree(&self.repo, &root, "ids")?.as_ref())?;
-        let identities = meta
-            .roles
-            .ids()
-            .into_iter()
-            .map(|id| find_id(&self.repo, &self.id_path, &id).map(|signed| (id, signed)))
-            .collect::<Result<Vec<_>, _>>()?;
+        let identities = find_ids(&self.id_path, meta.roles.ids())?;
         for (iid, id) in identities {
             let iid = iid.to_string();
             let mut tb = self
@@ -194,6 +287,15 @@ impl<S: Signer + 'static> EditState<S> {
         drop_ref.set_target(commit, "it: metadata edit");
 
         tx.commit()?;
+        if self.audit {
+            audit_edit(
+                &self.repo,
+                &mut self.signer,
+                drop_ref.name(),
+                commit,
+                "drop metadata",
+            );
+        }
 
         Ok(Output {
             repo: self.repo.path().to_owned(),
@@ -202,13 +304,35 @@ impl<S: Signer + 'static> EditState<S> {
         })
     }
 
-    pub fn edit_mirrors(mut self, message: Option<String>) -> cmd::Result<Output> {
-        ensure!(
-            self.signer_id.can_edit_mirrors(&self.meta.signed.signed),
-            "signer identity not allowed to edit mirrors"
-        );
+    pub fn edit_mirrors(self, message: Option<String>) -> cmd::Result<Output> {
+        let prev = self.load_mirrors()?;
+        let prev_canonical = prev.canonicalise()?;
+        let meta = edit_metadata(prev)?;
+
+        self.commit_mirrors(meta, prev_canonical, message)
+    }
 
-        let prev = metadata::Mirrors::from_tip(&self.repo, &self.drop_ref)
+    /// Modify the mirrors file by `f` without user interaction
+    pub(super) fn update_mirrors<F>(self, message: String, f: F) -> cmd::Result<Output>
+    where
+        F: FnOnce(&mut metadata::Mirrors) -> cmd::Result<()>,
+    {
+        let mut meta = self.load_mirrors()?;
+        let prev_canonical = meta.canonicalise()?;
+        f(&mut meta)?;
+
+        self.commit_mirrors(meta, prev_canonical, Some(message))
+    }
+
+    fn load_mirrors(&self) -> cmd::Result<metadata::Mirrors> {
+        auth::ensure_role(
+            &self.meta.signed.signed,
+            &self.signer_id.id,
+            RoleName::Mirrors,
+            "edit mirrors",
+        )?;
+
+        metadata::Mirrors::from_tip(&self.repo, &self.drop_ref)
             .map(|m| m.signed.signed)
             .or_else(|e| {
                 if e.is::<metadata::git::error::FileNotFound>() {
@@ -216,9 +340,15 @@ impl<S: Signer + 'static> EditState<S> {
                 } else {
                     Err(e)
                 }
-            })?;
-        let prev_canonical = prev.canonicalise()?;
-        let meta = edit_metadata(prev)?;
+            })
+    }
+
+    fn commit_mirrors(
+        mut self,
+        meta: metadata::Mirrors,
+        prev_canonical: Vec<u8>,
+        message: Option<String>,
+    ) -> cmd::Result<Output> {
         if meta.canonicalise()? == prev_canonical {
             info!("Document unchanged");
             cmd::abort!();
@@ -251,6 +381,15 @@ impl<S: Signer + 'static> EditState<S> {
         drop_ref.set_target(commit, "it: mirrors edit");
 
         tx.commit()?;
+        if self.audit {
+            audit_edit(
+                &self.repo,
+                &mut self.signer,
+                drop_ref.name(),
+                commit,
+                "mirrors",
+            );
+        }
 
         Ok(Output {
             repo: self.repo.path().to_owned(),
@@ -260,10 +399,12 @@ impl<S: Signer + 'static> EditState<S> {
     }
 
     pub fn edit_alternates(mut self, message: Option<String>) -> cmd::Result<Output> {
-        ensure!(
-            self.signer_id.can_edit_mirrors(&self.meta.signed.signed),
-            "signer identity not allowed to edit alternates"
-        );
+        auth::ensure_role(
+            &self.meta.signed.signed,
+            &self.signer_id.id,
+            RoleName::Mirrors,
+            "edit alternates",
+        )?;
 
         let prev = metadata::Alternates::from_tip(&self.repo, &self.drop_ref)
             .map(|m| m.signed.signed)
@@ -308,6 +449,169 @@ impl<S: Signer + 'static> EditState<S> {
         drop_ref.set_target(commit, "it: alternates edit");
 
         tx.commit()?;
+        if self.audit {
+            audit_edit(
+                &self.repo,
+                &mut self.signer,
+                drop_ref.name(),
+                commit,
+                "alternates",
+            );
+        }
+
+        Ok(Output {
+            repo: self.repo.path().to_owned(),
+            refname: drop_ref.into(),
+            commit,
+        })
+    }
+
+    fn edit_auto_reply(mut self, args: AutoReply, message: Option<String>) -> cmd::Result<Output> {
+        auth::ensure_role(
+            &self.meta.signed.signed,
+            &self.signer_id.id,
+            RoleName::Root,
+            "edit the automated reply",
+        )?;
+
+        let mut tx = refs::Transaction::new(&self.repo)?;
+        let drop_ref = tx.lock_ref(self.drop_ref)?;
+
+        let parent = self
+            .repo
+            .find_reference(drop_ref.name())?
+            .peel_to_commit()?;
+        let parent_tree = parent.tree()?;
+        let mut root = self.repo.treebuilder(Some(&parent_tree))?;
+        patches::Record::remove_from(&mut root)?;
+        match args.file {
+            Some(path) if !args.remove => {
+                let template = fs::read_to_string(&path)?;
+                ensure!(!template.trim().is_empty(), "template is empty");
+                root.insert(
+                    FILE_AUTO_REPLY,
+                    self.repo.blob(template.as_bytes())?,
+                    git2::FileMode::Blob.into(),
+                )?;
+            },
+            _ => {
+                if root.get(FILE_AUTO_REPLY)?.is_none() {
+                    info!("No automated reply configured");
+                    cmd::abort!();
+                }
+                root.remove(FILE_AUTO_REPLY)?;
+            },
+        }
+        let tree = self.repo.find_tree(root.write()?)?;
+        if tree.id() == parent_tree.id() {
+            info!("Document unchanged");
+            cmd::abort!();
+        }
+
+        let msg = message.map(Ok).unwrap_or_else(|| {
+            edit_commit_message(&self.repo, drop_ref.name(), &parent_tree, &tree)
+        })?;
+        let commit = git::commit_signed(&mut self.signer, &self.repo, msg, &tree, &[&parent])?;
+        drop_ref.set_target(commit, "it: auto-reply edit");
+
+        tx.commit()?;
+        if self.audit {
+            audit_edit(
+                &self.repo,
+                &mut self.signer,
+                drop_ref.name(),
+                commit,
+                "auto-reply",
+            );
+        }
+
+        Ok(Output {
+            repo: self.repo.path().to_owned(),
+            refname: drop_ref.into(),
+            commit,
+        })
+    }
+
+    fn edit_policy(mut self, args: Policy, message: Option<String>) -> cmd::Result<Output> {
+        auth::ensure_role(
+            &self.meta.signed.signed,
+            &self.signer_id.id,
+            RoleName::Root,
+            "edit the usage policy",
+        )?;
+
+        let signed = match args.file {
+            Some(path) if !args.remove => {
+                let text = fs::read_to_string(&path)?;
+                ensure!(!text.trim().is_empty(), "policy is empty");
+                if let Some(expires) = &args.expires {
+                    ensure!(expires > &DateTime::now(), "expiry must be in the future");
+                }
+                let policy = metadata::Policy {
+                    expires: args.expires,
+                    ..metadata::Policy::new(text)
+                };
+                // A policy which does not verify would render the drop unusable
+                let threshold = self.meta.signed.signed.roles.root.threshold;
+                ensure!(
+                    threshold.get() == 1,
+                    "the root role requires {threshold} signatures, which is not supported for the usage policy"
+                );
+                let signed = Metadata::policy(policy)
+                    .sign(iter::once(&mut self.signer as &mut dyn Signer))?;
+                Some(signed)
+            },
+            _ => None,
+        };
+
+        let mut tx = refs::Transaction::new(&self.repo)?;
+        let drop_ref = tx.lock_ref(self.drop_ref)?;
+
+        let parent = self
+            .repo
+            .find_reference(drop_ref.name())?
+            .peel_to_commit()?;
+        let parent_tree = parent.tree()?;
+        let mut root = self.repo.treebuilder(Some(&parent_tree))?;
+        patches::Record::remove_from(&mut root)?;
+        match signed {
+            Some(signed) => {
+                root.insert(
+                    META_FILE_POLICY,
+                    json::to_blob(&self.repo, &signed)?,
+                    git2::FileMode::Blob.into(),
+                )?;
+            },
+            None => {
+                if root.get(META_FILE_POLICY)?.is_none() {
+                    info!("No usage policy configured");
+                    cmd::abort!();
+                }
+                root.remove(META_FILE_POLICY)?;
+            },
+        }
+        let tree = self.repo.find_tree(root.write()?)?;
+        if tree.id() == parent_tree.id() {
+            info!("Document unchanged");
+            cmd::abort!();
+        }
+
+        let msg = message.map(Ok).unwrap_or_else(|| {
+            edit_commit_message(&self.repo, drop_ref.name(), &parent_tree, &tree)
+        })?;
+        let commit = git::commit_signed(&mut self.signer, &self.repo, msg, &tree, &[&parent])?;
+        drop_ref.set_target(commit, "it: policy edit");
+
+        tx.commit()?;
+        if self.audit {
+            audit_edit(
+                &self.repo,
+                &mut self.signer,
+                drop_ref.name(),
+                commit,
+                "policy",
+            );
+        }
 
         Ok(Output {
             repo: self.repo.path().to_owned(),
@@ -317,6 +621,25 @@ impl<S: Signer + 'static> EditState<S> {
     }
 }
 
+/// Record the edit committed as `commit` in the audit log
+///
+/// The edit is already done at this point, so failure is only reported.
+fn audit_edit<S: Signer>(
+    repo: &git2::Repository,
+    signer: &mut S,
+    drop_ref: &str,
+    commit: git2::Oid,
+    what: &str,
+) {
+    let event = patches::audit::Event::Edit {
+        what: what.to_owned(),
+        commit,
+    };
+    if let Err(e) = patches::audit::append(repo, signer, drop_ref, event) {
+        warn!("Failed to append to the audit log: {e:#}");
+    }
+}
+
 fn get_tree<'a>(
     repo: &'a git2::Repository,
     builder: &git2::TreeBuilder,
@@ -341,13 +664,12 @@ struct SignerIdentity {
 impl SignerIdentity {
     pub fn new<S: Signer>(
         signer: &S,
-        repo: &git2::Repository,
         cfg: &git2::Config,
         id_path: &[git2::Repository],
     ) -> cmd::Result<Self> {
         let id =
             cfg::git::identity(cfg)?.ok_or_else(|| anyhow!("signer identity not in gitconfig"))?;
-        let meta = find_id(repo, id_path, &id)?;
+        let meta = find_id(id_path, &id)?;
         let keyid = metadata::KeyId::from(signer.ident());
 
         ensure!(
@@ -357,12 +679,4 @@ impl SignerIdentity {
 
         Ok(Self { id })
     }
-
-    pub fn can_edit_drop(&self, parent: &metadata::Drop) -> bool {
-        parent.roles.root.ids.contains(&self.id)
-    }
-
-    pub fn can_edit_mirrors(&self, parent: &metadata::Drop) -> bool {
-        parent.roles.mirrors.ids.contains(&self.id)
-    }
 }
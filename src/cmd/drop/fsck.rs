@@ -0,0 +1,244 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{
+    cmd::{
+        self,
+        util::args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+        refs,
+    },
+    patches::{
+        self,
+        iter::dropped,
+        record::Heads,
+        write_sharded,
+        REF_HEADS_PATCHES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+};
+
+const REFLOG: &str = "it: fsck";
+
+#[derive(Debug, clap::Args)]
+pub struct Fsck {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    ///
+    /// Defaults to 'refs/heads/patches' in bare repositories, and
+    /// 'refs/it/patches' otherwise.
+    #[clap(long = "drop", value_parser, value_name = "REF")]
+    drop_ref: Option<Refname>,
+    /// The refname anchoring the seen objects tree
+    #[clap(
+        long = "seen",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_SEEN.parse().unwrap(),
+    )]
+    seen_ref: Refname,
+    /// Rebuild the seen objects tree from the drop history if it is
+    /// inconsistent
+    #[clap(long, value_parser)]
+    repair: bool,
+}
+
+impl Fsck {
+    pub(super) fn is_repair(&self) -> bool {
+        self.repair
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    status: Status,
+    /// Number of records in the drop history
+    records: usize,
+    /// Number of entries in the seen objects tree
+    seen: usize,
+    /// Records in the drop history without an entry in the seen objects tree
+    ///
+    /// These could be submitted (and recorded) again.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing: Vec<Heads>,
+    /// Entries in the seen objects tree without a record in the drop history
+    ///
+    /// Submissions of these would be rejected as duplicates.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extraneous: Vec<String>,
+    /// Problems with the structure of the seen objects tree
+    ///
+    /// Eg. shards or entries referring to objects missing from the repository,
+    /// or entries not at the path they are looked up at.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    malformed: Vec<String>,
+    shards: Shards,
+}
+
+/// Statistics about the shards of the seen objects tree
+#[derive(Default, serde::Serialize)]
+pub struct Shards {
+    /// Number of shards
+    count: usize,
+    /// Least number of entries in a shard
+    min: usize,
+    /// Most number of entries in a shard
+    max: usize,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Ok,
+    Repaired,
+    NeedsRepair,
+}
+
+/// Cross-check the seen objects tree against the drop history
+///
+/// Every record in the drop history should have an entry in the seen objects
+/// tree, and vice versa. Both are updated in the same ref transaction when a
+/// patch is accepted, but may still diverge if the process is interrupted, or
+/// either ref is manipulated by other means. The shards of the tree are also
+/// checked to be well-formed, and to refer only to objects present in the
+/// repository.
+///
+/// With `--repair`, the seen objects tree is rebuilt from the drop history.
+/// Both refs are locked while checking, so the check and repair are atomic
+/// with respect to concurrent submissions.
+pub fn fsck(args: Fsck) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let drop_ref = match args.drop_ref {
+        Some(r) => r,
+        None if repo.is_bare() => REF_HEADS_PATCHES.parse()?,
+        None => REF_IT_PATCHES.parse()?,
+    };
+
+    // Same order as when accepting a patch
+    let mut tx = refs::Transaction::new(&repo)?;
+    let seen_ref = tx.lock_ref(args.seen_ref)?;
+    let _drop_ref = tx.lock_ref(drop_ref.clone())?;
+
+    let mut recorded = BTreeSet::new();
+    for rec in dropped::records(&repo, &drop_ref) {
+        recorded.insert(rec?.heads);
+    }
+    let Scan {
+        names: seen,
+        shards,
+        malformed,
+    } = match if_not_found_none(repo.find_reference(seen_ref.name()))? {
+        Some(r) => scan(&repo, &r.peel_to_tree()?)?,
+        None => Scan::default(),
+    };
+
+    let missing = recorded
+        .iter()
+        .filter(|heads| !seen.contains(&heads.to_string()))
+        .copied()
+        .collect::<Vec<_>>();
+    let recorded_names = recorded
+        .iter()
+        .map(ToString::to_string)
+        .collect::<BTreeSet<_>>();
+    let extraneous = seen
+        .iter()
+        .filter(|name| !recorded_names.contains(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let status = if missing.is_empty() && extraneous.is_empty() && malformed.is_empty() {
+        Status::Ok
+    } else if args.repair {
+        let mut root = repo.treebuilder(None)?;
+        for heads in &recorded {
+            let blob = patches::to_blob(&repo, heads)?;
+            write_sharded(&repo, &mut root, heads, blob)?;
+        }
+        seen_ref.set_target(root.write()?, REFLOG);
+        tx.commit()?;
+        Status::Repaired
+    } else {
+        Status::NeedsRepair
+    };
+
+    Ok(Output {
+        status,
+        records: recorded.len(),
+        seen: seen.len(),
+        missing,
+        extraneous,
+        malformed,
+        shards,
+    })
+}
+
+#[derive(Default)]
+pub(super) struct Scan {
+    /// The names of all entries
+    pub names: BTreeSet<String>,
+    pub shards: Shards,
+    pub malformed: Vec<String>,
+}
+
+/// Collect the entries of the sharded seen objects tree, and check its
+/// structure
+pub(super) fn scan(repo: &git2::Repository, tree: &git2::Tree) -> cmd::Result<Scan> {
+    let odb = repo.odb()?;
+    let mut scan = Scan::default();
+    let mut sizes = Vec::new();
+    for shard in tree {
+        let pre = shard.name().unwrap_or_default().to_owned();
+        if !odb.exists(shard.id()) {
+            scan.malformed
+                .push(format!("{pre}: shard {} is missing", shard.id()));
+            continue;
+        }
+        match shard.to_object(repo)?.into_tree() {
+            Ok(sub) => {
+                sizes.push(sub.len());
+                if sub.is_empty() {
+                    scan.malformed.push(format!("{pre}: empty shard"));
+                }
+                for entry in &sub {
+                    let name = format!("{pre}{}", entry.name().unwrap_or_default());
+                    if !odb.exists(entry.id()) {
+                        scan.malformed
+                            .push(format!("{name}: blob {} is missing", entry.id()));
+                    }
+                    // Normalise to the form written by current versions
+                    let canonical = Heads::from_str(&name).map_or(name.clone(), |h| h.to_string());
+                    if pre.len() != 2 || canonical != name {
+                        scan.malformed
+                            .push(format!("{name}: not at its canonical path"));
+                    }
+                    scan.names.insert(canonical);
+                }
+            },
+            // Not written by us, but report it nevertheless
+            Err(_) => {
+                scan.malformed.push(format!("{pre}: not a tree"));
+                scan.names.insert(pre);
+            },
+        }
+    }
+    scan.shards = Shards {
+        count: sizes.len(),
+        min: sizes.iter().copied().min().unwrap_or_default(),
+        max: sizes.iter().copied().max().unwrap_or_default(),
+    };
+
+    Ok(scan)
+}
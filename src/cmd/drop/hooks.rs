@@ -0,0 +1,162 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use clap::ValueHint;
+use url::Url;
+
+use super::Common;
+use crate::{
+    cfg,
+    cmd,
+    git::{
+        self,
+        if_not_found_none,
+    },
+    patches::hooks::webhook,
+};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Hooks {
+    /// List the configured hooks and webhooks
+    Ls(Ls),
+    /// Add a webhook to notify of accepted patches, or change its URL
+    Add(Add),
+    /// Remove a webhook
+    Remove(Remove),
+    /// List webhook deliveries waiting to be retried
+    Pending(Pending),
+}
+
+impl Hooks {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Ls(args) => ls(args).map(cmd::IntoOutput::into_output),
+            Self::Add(args) => add(args).map(cmd::IntoOutput::into_output),
+            Self::Remove(args) => remove(args).map(cmd::IntoOutput::into_output),
+            Self::Pending(args) => pending(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Ls {
+    #[clap(flatten)]
+    common: Common,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Add {
+    #[clap(flatten)]
+    common: Common,
+    /// Secret to sign the payload with
+    ///
+    /// The signature is sent as the hex-encoded HMAC-SHA256 of the request
+    /// body in the X-it-Hmac-Sha256 header. If not given, an existing secret
+    /// of the webhook is kept.
+    #[clap(long, value_parser, value_name = "SECRET")]
+    secret: Option<String>,
+    /// Name of the webhook
+    ///
+    /// Only ASCII alphanumerics, '-' and '_' are allowed.
+    #[clap(value_parser, value_name = "NAME")]
+    name: String,
+    /// URL to POST the notification to
+    #[clap(value_parser, value_name = "URL", value_hint = ValueHint::Url)]
+    url: Url,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Remove {
+    #[clap(flatten)]
+    common: Common,
+    /// Name of the webhook
+    #[clap(value_parser, value_name = "NAME")]
+    name: String,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Pending {
+    #[clap(flatten)]
+    common: Common,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pre_accept: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_accept: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quarantine: Option<PathBuf>,
+    webhooks: Vec<WebhookInfo>,
+}
+
+#[derive(serde::Serialize)]
+pub struct WebhookInfo {
+    name: String,
+    url: Url,
+    /// Whether payloads are signed. The secret itself is not shown.
+    signed: bool,
+}
+
+pub fn ls(args: Ls) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.common.git_dir)?;
+    output(&repo)
+}
+
+pub fn add(args: Add) -> cmd::Result<Output> {
+    webhook::validate_name(&args.name)?;
+    let repo = git::repo::open(&args.common.git_dir)?;
+    let mut cfg = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+    cfg.set_str(&url_key(&args.name), args.url.as_str())?;
+    if let Some(secret) = &args.secret {
+        cfg.set_str(&secret_key(&args.name), secret)?;
+    }
+
+    output(&repo)
+}
+
+pub fn remove(args: Remove) -> cmd::Result<Output> {
+    webhook::validate_name(&args.name)?;
+    let repo = git::repo::open(&args.common.git_dir)?;
+    let mut cfg = repo.config()?.open_level(git2::ConfigLevel::Local)?;
+    if_not_found_none(cfg.remove(&url_key(&args.name)))?
+        .ok_or_else(|| anyhow!("no webhook {} in this repository", args.name))?;
+    if_not_found_none(cfg.remove(&secret_key(&args.name)))?;
+
+    output(&repo)
+}
+
+pub fn pending(args: Pending) -> cmd::Result<Vec<webhook::Pending>> {
+    let repo = git::repo::open(&args.common.git_dir)?;
+    webhook::pending(repo.path())
+}
+
+fn output(repo: &git2::Repository) -> cmd::Result<Output> {
+    let hooks = cfg::git::accept_hooks(&cfg::git::open(repo)?)?;
+    Ok(Output {
+        pre_accept: hooks.pre_accept,
+        post_accept: hooks.post_accept,
+        quarantine: hooks.quarantine,
+        webhooks: hooks
+            .webhooks
+            .into_iter()
+            .map(|hook| WebhookInfo {
+                name: hook.name,
+                url: hook.url,
+                signed: hook.secret.is_some(),
+            })
+            .collect(),
+    })
+}
+
+fn url_key(name: &str) -> String {
+    format!("it.{name}.{}", cfg::git::IT_WEBHOOK)
+}
+
+fn secret_key(name: &str) -> String {
+    format!("it.{name}.{}", cfg::git::IT_WEBHOOK_SECRET)
+}
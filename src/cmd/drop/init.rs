@@ -14,6 +14,7 @@ use anyhow::{
 
 use super::{
     find_id,
+    find_ids,
     Common,
     Editable,
 };
@@ -75,7 +76,10 @@ pub struct Output {
 }
 
 pub fn init(args: Init) -> cmd::Result<Output> {
-    let Common { git_dir, id_path } = args.common;
+    let Common {
+        git_dir,
+        id_path: local_id_path,
+    } = args.common;
     let drop_ref: Refname = REF_IT_PATCHES.parse().unwrap();
 
     let repo = git::repo::open_or_init(
@@ -95,15 +99,17 @@ pub fn init(args: Init) -> cmd::Result<Output> {
         drop_ref
     );
 
-    let id_path = id_path.open_git();
+    let id_path = local_id_path.open_git();
     git::add_alternates(&repo, &id_path)?;
+    // Keep a reflog of the tracking branches, cf. 'drop branch-log'
+    repo.config()?.set_str("core.logAllRefUpdates", "always")?;
 
-    let cfg = repo.config()?.snapshot()?;
+    let cfg = cfg::git::open(&repo)?;
     let mut signer = cfg::signer(&cfg, ui::askpass)?;
     let signer_id = {
         let iid =
             cfg::git::identity(&cfg)?.ok_or_else(|| anyhow!("signer identity not in gitconfig"))?;
-        let id = find_id(&repo, &id_path, &iid)?;
+        let id = find_id(&id_path, &iid)?;
         let keyid = metadata::KeyId::from(signer.ident());
         ensure!(
             id.signed.keys.contains_key(&keyid),
@@ -124,6 +130,9 @@ pub fn init(args: Init) -> cmd::Result<Output> {
             fmt_version: Default::default(),
             description: args.description,
             prev: None,
+            id_path: Default::default(),
+            ref_policy: Default::default(),
+            commit_limits: Default::default(),
             custom: Default::default(),
             roles: metadata::drop::Roles {
                 root: default_role.clone(),
@@ -150,14 +159,15 @@ pub fn init(args: Init) -> cmd::Result<Output> {
     );
     let signed = Metadata::drop(&meta).sign(iter::once(&mut signer))?;
 
+    let id_path = if meta.id_path.is_empty() {
+        id_path
+    } else {
+        local_id_path.open_git_with(&repo, &meta.id_path)?
+    };
+
     let mut root = repo.treebuilder(None)?;
     let mut ids = repo.treebuilder(None)?;
-    let identities = meta
-        .roles
-        .ids()
-        .into_iter()
-        .map(|id| find_id(&repo, &id_path, &id).map(|signed| (id, signed)))
-        .collect::<Result<Vec<_>, _>>()?;
+    let identities = find_ids(&id_path, meta.roles.ids())?;
     for (iid, id) in identities {
         let iid = iid.to_string();
         let mut tb = repo.treebuilder(None)?;
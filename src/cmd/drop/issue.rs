@@ -0,0 +1,148 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::path::PathBuf;
+
+use anyhow::ensure;
+
+use super::{
+    edit::{
+        EditState,
+        Output,
+    },
+    Common,
+};
+use crate::{
+    cmd::{
+        self,
+        util::args::Refname,
+    },
+    git,
+    patches::{
+        issues::{
+            self,
+            Links,
+        },
+        Topic,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Issue {
+    /// Link an issue of an external issue tracker to a topic
+    Link(Link),
+    /// Remove the link between an issue and a topic
+    Unlink(Unlink),
+    /// List the issues linked to topics
+    ///
+    /// Includes the issues referenced by 'Closes:' trailers of the commits of
+    /// accepted patches.
+    Ls(Ls),
+}
+
+impl Issue {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Link(args) => link(args).map(cmd::IntoOutput::into_output),
+            Self::Unlink(args) => unlink(args).map(cmd::IntoOutput::into_output),
+            Self::Ls(args) => ls(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Link {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// The topic to link the issue to
+    #[clap(value_parser, value_name = "TOPIC")]
+    topic: Topic,
+    /// Key or URL of the issue, eg. 'PROJ-123'
+    #[clap(value_parser, value_name = "ISSUE")]
+    issue: issues::Issue,
+}
+
+pub fn link(args: Link) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Link issue {} to topic {}", args.issue, args.topic));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        let mut links = issues::from_custom(&meta.custom)?;
+        ensure!(
+            links
+                .entry(args.topic.clone())
+                .or_default()
+                .insert(args.issue.clone()),
+            "issue {} is already linked to topic {}",
+            args.issue,
+            args.topic
+        );
+        issues::to_custom(&mut meta.custom, links)
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Unlink {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// The topic to unlink the issue from
+    #[clap(value_parser, value_name = "TOPIC")]
+    topic: Topic,
+    /// Key or URL of the issue
+    #[clap(value_parser, value_name = "ISSUE")]
+    issue: issues::Issue,
+}
+
+pub fn unlink(args: Unlink) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Unlink issue {} from topic {}", args.issue, args.topic));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        let mut links = issues::from_custom(&meta.custom)?;
+        ensure!(
+            links
+                .get_mut(&args.topic)
+                .map_or(false, |linked| linked.remove(&args.issue)),
+            "issue {} is not linked to topic {}",
+            args.issue,
+            args.topic
+        );
+        issues::to_custom(&mut meta.custom, links)
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Ls {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Only list the issues linked to this topic
+    #[clap(long, value_parser, value_name = "TOPIC")]
+    topic: Option<Topic>,
+}
+
+pub fn ls(args: Ls) -> cmd::Result<Links> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let mut links = issues::linked(&repo, &args.drop_ref)?;
+    if let Some(topic) = &args.topic {
+        links.retain(|linked, _| linked == topic);
+    }
+
+    Ok(links)
+}
@@ -0,0 +1,144 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use anyhow::ensure;
+use url::Url;
+
+use super::{
+    edit::{
+        EditState,
+        Output,
+    },
+    Common,
+};
+use crate::{
+    cmd,
+    metadata,
+};
+
+mod follow;
+pub use follow::{
+    follow,
+    Follow,
+};
+
+#[derive(Debug, clap::Args)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Mirror {
+    #[clap(subcommand)]
+    cmd: Option<Edit>,
+    #[clap(flatten)]
+    follow: Follow,
+}
+
+impl Mirror {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self.cmd {
+            Some(cmd) => cmd.run(),
+            None => follow(self.follow).map(cmd::Output::iter),
+        }
+    }
+
+    /// Whether the drop metadata is edited, as opposed to following an
+    /// upstream drop
+    pub(super) fn is_edit(&self) -> bool {
+        self.cmd.is_some()
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Edit {
+    /// Add a mirror
+    Add(Add),
+    /// Remove a mirror
+    Remove(Remove),
+}
+
+impl Edit {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Add(args) => add(args),
+            Self::Remove(args) => remove(args),
+        }
+        .map(cmd::IntoOutput::into_output)
+    }
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Kind {
+    /// Can fetch bundles
+    Bundled,
+    /// Can fetch packs via git-protocol
+    Packed,
+    /// Not serving bundles at all
+    Sparse,
+}
+
+impl From<Kind> for metadata::MirrorKind {
+    fn from(k: Kind) -> Self {
+        match k {
+            Kind::Bundled => Self::Bundled,
+            Kind::Packed => Self::Packed,
+            Kind::Sparse => Self::Sparse,
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Add {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// What the mirror serves
+    #[clap(long, value_enum, default_value_t = Kind::Packed)]
+    kind: Kind,
+    /// URL of the mirror
+    #[clap(value_parser, value_name = "URL")]
+    url: Url,
+}
+
+pub fn add(args: Add) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Add mirror {}", args.url));
+    EditState::open(args.common)?.update_mirrors(message, |meta| {
+        ensure!(
+            !meta.mirrors.iter().any(|m| m.url == args.url),
+            "mirror {} already exists",
+            args.url
+        );
+        meta.mirrors.push(metadata::Mirror {
+            url: args.url,
+            kind: args.kind.into(),
+            custom: Default::default(),
+        });
+        Ok(())
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Remove {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// URL of the mirror
+    #[clap(value_parser, value_name = "URL")]
+    url: Url,
+}
+
+pub fn remove(args: Remove) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Remove mirror {}", args.url));
+    EditState::open(args.common)?.update_mirrors(message, |meta| {
+        let before = meta.mirrors.len();
+        meta.mirrors.retain(|m| m.url != args.url);
+        ensure!(meta.mirrors.len() < before, "no such mirror {}", args.url);
+        Ok(())
+    })
+}
@@ -0,0 +1,296 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use clap::ValueHint;
+use url::Url;
+
+use crate::{
+    bundle,
+    cfg,
+    cmd::{
+        self,
+        drop::{
+            bundles::{
+                def_jobs,
+                fetch_bundles,
+                verify_history,
+                SyncFilter,
+                SyncOptions,
+            },
+            clone::fetch,
+            unbundle::unbundle_records,
+            Common,
+        },
+        ui::{
+            debug,
+            info,
+            warn,
+        },
+        util::args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    http,
+    net,
+    patches::{
+        DropHead,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Follow {
+    #[clap(flatten)]
+    common: Common,
+    /// Keep the drop in sync with its upstream until interrupted
+    ///
+    /// The drop history is fetched from --remote, verified, and the bundles
+    /// of new records are fetched from --url and unbundled. If the upstream
+    /// drop is served by `it drop serve`, its status is polled so nothing is
+    /// fetched until its history changes.
+    #[clap(long, value_parser, required = true)]
+    follow: bool,
+    /// The directory where to write the bundles to
+    ///
+    /// Unless this is an absolute path, it is treated as relative to $GIT_DIR.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        default_value_os_t = cfg::paths::bundles().to_owned(),
+        value_hint = ValueHint::DirPath,
+    )]
+    bundle_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Name of the git remote to fetch the drop history from
+    #[clap(long, value_parser, value_name = "NAME", default_value = "origin")]
+    remote: String,
+    /// Base URL of the upstream drop
+    ///
+    /// If not given, the value of the 'it.bundleUrl' git config is used.
+    #[clap(long, value_parser, value_name = "URL", value_hint = ValueHint::Url)]
+    url: Option<Url>,
+    /// Fetch via IPFS
+    #[clap(
+        long,
+        value_parser,
+        value_name = "URL",
+        value_hint = ValueHint::Url,
+        env = "IPFS_GATEWAY",
+        default_value_t = Url::parse("https://ipfs.io").unwrap(),
+    )]
+    ipfs_gateway: Url,
+    /// Seconds to wait between checks for new records
+    #[clap(long, value_parser, value_name = "SECS", default_value_t = 60)]
+    interval: u64,
+    /// Maximum number of concurrent downloads. Default is the number of
+    /// available cores.
+    #[clap(short, long, value_parser, default_value_t = def_jobs())]
+    jobs: NonZeroUsize,
+}
+
+/// Outcome of catching up with the upstream drop
+#[derive(serde::Serialize)]
+pub struct Synced {
+    /// The drop history is now at this commit
+    #[serde(with = "git::serde::oid")]
+    tip: git2::Oid,
+    /// Bundles fetched
+    fetched: Vec<bundle::Hash>,
+    /// Refs updated by unbundling
+    updated: BTreeMap<Refname, git::serde::oid::Oid>,
+}
+
+/// Follow the upstream drop, and emit a [`Synced`] whenever the local drop
+/// caught up with new records
+///
+/// Runs until SIGTERM or SIGINT is received. Failures to sync, including a
+/// rewritten upstream history, are logged and retried after the interval.
+pub fn follow(args: Follow) -> cmd::Result<impl Iterator<Item = cmd::Result<Synced>>> {
+    // Before spawning any threads, so they don't receive the signals
+    let shutdown = http::shutdown_signals()?;
+
+    let repo = git::repo::open_bare(&args.common.git_dir)?;
+    let bundle_dir = if args.bundle_dir.is_relative() {
+        repo.path().join(args.bundle_dir)
+    } else {
+        args.bundle_dir
+    };
+    let url = match args.url {
+        Some(url) => url,
+        None => if_not_found_none(cfg::git::open(&repo)?.get_string(cfg::git::IT_BUNDLE_URL))?
+            .ok_or_else(|| anyhow!("no --url given and '{}' not set", cfg::git::IT_BUNDLE_URL))?
+            .parse()?,
+    };
+    let has_remote = if_not_found_none(repo.find_remote(&args.remote))?.is_some();
+    if !has_remote {
+        warn!(
+            "Remote {} not configured, only fetching bundles of {}",
+            args.remote, args.drop_ref
+        );
+    }
+    info!("Following {url}");
+
+    Ok(Follower {
+        repo,
+        bundle_dir,
+        drop_ref: args.drop_ref,
+        remote: if has_remote { Some(args.remote) } else { None },
+        url,
+        ipfs_gateway: args.ipfs_gateway,
+        interval: Duration::from_secs(args.interval),
+        jobs: args.jobs,
+        shutdown,
+        last: None,
+        done: false,
+    })
+}
+
+struct Follower {
+    repo: git2::Repository,
+    bundle_dir: PathBuf,
+    drop_ref: Refname,
+    remote: Option<String>,
+    url: Url,
+    ipfs_gateway: Url,
+    interval: Duration,
+    jobs: NonZeroUsize,
+    shutdown: mpsc::Receiver<()>,
+    /// Drop tip as of the last successful round
+    last: Option<git2::Oid>,
+    done: bool,
+}
+
+impl Follower {
+    fn round(&mut self) -> cmd::Result<Option<Synced>> {
+        let local = if_not_found_none(self.repo.refname_to_id(&self.drop_ref))?;
+        if let Some(upstream) = self.upstream_tip() {
+            if self.last.is_some() && Some(upstream) == local {
+                return Ok(None);
+            }
+        }
+        if let Some(remote) = &self.remote {
+            debug!("Fetching {} from {remote}", self.drop_ref);
+            fetch(self.repo.path(), remote)?;
+            verify_history(&self.repo, remote, &self.drop_ref, false)?;
+        }
+        let tip = self.repo.refname_to_id(&self.drop_ref)?;
+        if self.last == Some(tip) {
+            return Ok(None);
+        }
+        // Verifies the drop metadata
+        DropHead::from_refname(&self.repo, &self.drop_ref)?;
+
+        let fetched = fetch_bundles(
+            &self.repo,
+            &self.drop_ref,
+            SyncOptions {
+                bundle_dir: self.bundle_dir.clone(),
+                url: self.url.clone(),
+                ipfs_gateway: self.ipfs_gateway.clone(),
+                overwrite: false,
+                no_snapshots: false,
+                filter: SyncFilter::default(),
+                jobs: self.jobs,
+            },
+        )?
+        .into_iter()
+        .map(|info| info.hash)
+        .collect();
+        let updated = unbundle_records(
+            &self.repo,
+            &self.bundle_dir,
+            &self.drop_ref,
+            true,
+            None,
+            self.jobs,
+        )?;
+        if let Err(e) = cmd::topic::notify(&self.repo, &self.drop_ref) {
+            warn!("Failed to notify about subscribed topics: {e:#}");
+        }
+        info!("{} is now at {tip}", self.drop_ref);
+        self.last = Some(tip);
+
+        Ok(Some(Synced {
+            tip,
+            fetched,
+            updated,
+        }))
+    }
+
+    /// The drop tip reported by the upstream `/-/status` endpoint, if
+    /// available
+    fn upstream_tip(&self) -> Option<git2::Oid> {
+        #[derive(serde::Deserialize)]
+        struct Status {
+            #[serde(with = "git::serde::oid")]
+            drop_tip: git2::Oid,
+        }
+
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .push("-")
+            .push("status");
+        let status = net::request("GET", &url)
+            .and_then(|req| Ok(req.call()?.into_json::<Status>()?))
+            .map_err(|e| debug!("Unable to query {url}: {e:#}"))
+            .ok()?;
+
+        Some(status.drop_tip)
+    }
+
+    /// Wait for the interval to elapse, returning `false` if we were asked to
+    /// shut down in the meantime
+    fn wait(&self) -> bool {
+        match self.shutdown.recv_timeout(self.interval) {
+            Ok(()) => false,
+            Err(mpsc::RecvTimeoutError::Timeout) => true,
+            // Signals are not supported on this platform
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                thread::sleep(self.interval);
+                true
+            },
+        }
+    }
+}
+
+impl Iterator for Follower {
+    type Item = cmd::Result<Synced>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.round() {
+                Ok(Some(synced)) => return Some(Ok(synced)),
+                Ok(None) => {},
+                Err(e) => warn!("Failed to sync with {}: {e:#}", self.url),
+            }
+            if !self.wait() {
+                info!("Shutting down");
+                self.done = true;
+            }
+        }
+
+        None
+    }
+}
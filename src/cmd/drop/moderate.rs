@@ -0,0 +1,255 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    fs,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use clap::ValueHint;
+
+use crate::{
+    cfg,
+    cmd::{
+        self,
+        args::Refname,
+        ui::{
+            self,
+            info,
+            warn,
+        },
+    },
+    git::{
+        self,
+        refs,
+    },
+    patches::{
+        self,
+        audit,
+        record::Heads,
+        AcceptArgs,
+        AcceptOptions,
+        Bundle,
+        Record,
+        Submission,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_QUARANTINE,
+        REF_IT_SEEN,
+    },
+};
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Moderate {
+    /// List the submissions pending review
+    Ls(Ls),
+    /// Record a quarantined submission as if it was just submitted
+    Approve(Approve),
+    /// Discard a quarantined submission
+    Reject(Reject),
+}
+
+impl Moderate {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Ls(args) => ls(args).map(cmd::Output::iter),
+            Self::Approve(args) => approve(args).map(cmd::IntoOutput::into_output),
+            Self::Reject(args) => reject(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct Common {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// The directory where patch bundles are stored
+    ///
+    /// Unless this is an absolute path, it is treated as relative to $GIT_DIR.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        default_value_os_t = cfg::paths::bundles().to_owned(),
+        value_hint = ValueHint::DirPath,
+    )]
+    bundle_dir: PathBuf,
+}
+
+impl Common {
+    fn open(&self) -> cmd::Result<(git2::Repository, PathBuf)> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let bundle_dir = if self.bundle_dir.is_relative() {
+            repo.path().join(&self.bundle_dir)
+        } else {
+            self.bundle_dir.clone()
+        };
+
+        Ok((repo, bundle_dir))
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Ls {
+    #[clap(flatten)]
+    common: Common,
+}
+
+#[derive(serde::Serialize)]
+pub struct Pending {
+    #[serde(rename = "ref")]
+    refname: String,
+    record: Record,
+}
+
+pub fn ls(args: Ls) -> cmd::Result<Vec<cmd::Result<Pending>>> {
+    let (repo, _) = args.common.open()?;
+    let glob = format!("{REF_IT_QUARANTINE}/*");
+    let pending = repo
+        .references_glob(&glob)?
+        .map(|r| -> cmd::Result<Pending> {
+            let r = r?;
+            let refname = r
+                .name()
+                .ok_or_else(|| anyhow!("invalid ref name"))?
+                .to_owned();
+            let record = Record::from_commit(&repo, &r.peel_to_commit()?)?;
+
+            Ok(Pending { refname, record })
+        })
+        .collect();
+
+    Ok(pending)
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Approve {
+    #[clap(flatten)]
+    common: Common,
+    /// Ref prefix under which to store the refs contained in patch bundles
+    #[clap(
+        long,
+        value_parser,
+        value_name = "REF",
+        default_value_t = Refname::from_str(REF_IT_BUNDLES).unwrap()
+    )]
+    unbundle_prefix: Refname,
+    /// The refname anchoring the seen objects tree
+    #[clap(
+        long,
+        value_parser,
+        value_name = "REF",
+        default_value_t = Refname::from_str(REF_IT_SEEN).unwrap()
+    )]
+    seen_ref: Refname,
+    /// The heads of the quarantined submission, as shown by 'ls'
+    #[clap(value_parser, value_name = "HEADS")]
+    heads: Heads,
+}
+
+pub fn approve(args: Approve) -> cmd::Result<Record> {
+    let (repo, bundle_dir) = args.common.open()?;
+    let quarantine_ref = patches::quarantined_ref(&args.heads)?;
+    let quarantined = Record::from_commit(
+        &repo,
+        &repo.find_reference(&quarantine_ref)?.peel_to_commit()?,
+    )?;
+
+    let mut sub = Submission {
+        signature: quarantined.meta.signature.clone(),
+        bundle: Bundle::from_stored(&bundle_dir, quarantined.bundle_info().as_expect())?,
+    };
+    let cfg = cfg::git::open(&repo)?;
+    let mut signer = cfg::signer(&cfg, ui::askpass)?;
+    let record = sub.try_accept(AcceptArgs {
+        unbundle_prefix: &args.unbundle_prefix,
+        drop_ref: REF_IT_PATCHES,
+        seen_ref: &args.seen_ref,
+        repo: &repo,
+        signer: &mut signer,
+        ipfs_api: None,
+        drop_cache: None,
+        reachable_cache: None,
+        options: AcceptOptions {
+            hooks: cfg::git::accept_hooks(&cfg)?,
+            unbundle: cfg::git::unbundle(&cfg)?,
+            lint: cfg::git::lint_rules(&cfg)?,
+            audit: cfg::git::audit_log(&cfg)?,
+            ..Default::default()
+        },
+    })?;
+
+    let mut tx = refs::Transaction::new(&repo)?;
+    tx.lock_ref(quarantine_ref)?.remove();
+    tx.commit()?;
+
+    Ok(record)
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Reject {
+    #[clap(flatten)]
+    common: Common,
+    /// Keep the bundle file
+    #[clap(long, value_parser)]
+    keep_bundle: bool,
+    /// The heads of the quarantined submission, as shown by 'ls'
+    #[clap(value_parser, value_name = "HEADS")]
+    heads: Heads,
+}
+
+#[derive(serde::Serialize)]
+pub struct Rejected {
+    #[serde(rename = "ref")]
+    refname: Refname,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed: Option<PathBuf>,
+}
+
+pub fn reject(args: Reject) -> cmd::Result<Rejected> {
+    let (repo, bundle_dir) = args.common.open()?;
+    let quarantine_ref = patches::quarantined_ref(&args.heads)?;
+    let record = Record::from_commit(
+        &repo,
+        &repo.find_reference(&quarantine_ref)?.peel_to_commit()?,
+    )?;
+
+    let mut tx = refs::Transaction::new(&repo)?;
+    tx.lock_ref(quarantine_ref.clone())?.remove();
+    tx.commit()?;
+
+    let cfg = cfg::git::open(&repo)?;
+    if cfg::git::audit_log(&cfg)? {
+        let mut signer = cfg::signer(&cfg, ui::askpass)?;
+        let event = audit::Event::Reject {
+            heads: record.heads,
+            signer: record.meta.signature.signer.clone(),
+            reason: "rejected by moderator".to_owned(),
+        };
+        if let Err(e) = audit::append(&repo, &mut signer, REF_IT_PATCHES, event) {
+            warn!("Failed to append to the audit log: {e:#}");
+        }
+    }
+
+    let mut removed = None;
+    if !args.keep_bundle {
+        let path = record.bundle_path(&bundle_dir);
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!("Removed {}", path.display());
+                removed = Some(path);
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(Rejected {
+        refname: quarantine_ref,
+        removed,
+    })
+}
@@ -0,0 +1,253 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    path::PathBuf,
+};
+
+use crate::{
+    cmd::{
+        self,
+        util::args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+        refs,
+    },
+    metadata::{
+        self,
+        git::FromGit as _,
+        ContentHash,
+        IdentityId,
+    },
+    patches::{
+        iter::dropped,
+        DropHead,
+        TrackingBranch,
+        REF_HEADS_PATCHES,
+        REF_IT_PATCHES,
+        TOPIC_MERGES,
+    },
+};
+
+const REFLOG: &str = "it: repair-refs";
+
+#[derive(Debug, clap::Args)]
+pub struct RepairRefs {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    ///
+    /// Defaults to 'refs/heads/patches' in bare repositories, and
+    /// 'refs/it/patches' otherwise.
+    #[clap(long = "drop", value_parser, value_name = "REF")]
+    drop_ref: Option<Refname>,
+    /// Only report what would be repaired
+    #[clap(long, value_parser)]
+    dry_run: bool,
+}
+
+impl RepairRefs {
+    pub(super) fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    branch: Refname,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tracking: Option<Refname>,
+    status: Status,
+    /// The repairs made, or which would be made if --dry-run was given
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    repairs: Vec<String>,
+    /// Problems which can not be repaired automatically
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Ok,
+    Repaired,
+    NeedsRepair,
+    Irreparable,
+}
+
+/// Audit the tracking branches of the drop, and the symrefs pointing to them
+/// in a bare drop
+///
+/// The expected tip of each tracking branch is the most recent checkpoint
+/// recorded in the drop history by an identity in the branch's role (as of the
+/// current drop metadata). Missing or stale tracking branches, and missing or
+/// misdirected symrefs are repaired, as long as doing so doesn't discard any
+/// commits.
+pub fn repair_refs(args: RepairRefs) -> cmd::Result<Vec<Output>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let drop_ref = match args.drop_ref {
+        Some(r) => r,
+        None if repo.is_bare() => REF_HEADS_PATCHES.parse()?,
+        None => REF_IT_PATCHES.parse()?,
+    };
+    let meta = DropHead::from_refname(&repo, &drop_ref)?.meta;
+    let tips = recorded_tips(&repo, &drop_ref, &meta)?;
+
+    let mut tx = refs::Transaction::new(&repo)?;
+    let mut out = Vec::with_capacity(meta.roles.branches.len());
+    for branch in meta.roles.branches.keys() {
+        let mut repairs = Vec::new();
+        let mut problems = Vec::new();
+
+        let tracking = match TrackingBranch::for_branch(branch) {
+            Ok(tracking) => tracking.into_refname(),
+            Err(e) => {
+                out.push(Output {
+                    branch: branch.clone(),
+                    tracking: None,
+                    status: Status::Irreparable,
+                    repairs,
+                    problems: vec![format!("{e:#}")],
+                });
+                continue;
+            },
+        };
+        let ours = if_not_found_none(repo.refname_to_id(&tracking))?;
+        let expected = tips.get(branch).copied();
+        let tip = match (ours, expected) {
+            (None, None) => None,
+            (Some(ours), None) => {
+                problems.push(format!(
+                    "{tracking} is at {ours}, but no checkpoint of {branch} is recorded in {drop_ref}"
+                ));
+                Some(ours)
+            },
+            (_, Some(expected)) if if_not_found_none(repo.find_commit(expected))?.is_none() => {
+                problems.push(format!(
+                    "recorded tip {expected} of {branch} not found, try 'it drop unbundle'"
+                ));
+                ours
+            },
+            (None, Some(expected)) => {
+                repairs.push(format!("create {tracking} at {expected}"));
+                Some(expected)
+            },
+            (Some(ours), Some(expected)) if ours == expected => Some(ours),
+            (Some(ours), Some(expected)) if repo.graph_descendant_of(expected, ours)? => {
+                repairs.push(format!("fast-forward {tracking} from {ours} to {expected}"));
+                Some(expected)
+            },
+            (Some(ours), Some(expected)) => {
+                problems.push(format!(
+                    "{tracking} at {ours} diverges from recorded tip {expected}"
+                ));
+                Some(ours)
+            },
+        };
+        if let Some(tip) = tip.filter(|tip| Some(*tip) != ours) {
+            if !args.dry_run {
+                tx.lock_ref(tracking.clone())?.set_target(tip, REFLOG);
+            }
+        }
+
+        if repo.is_bare() && tip.is_some() {
+            let needs_symref = match if_not_found_none(repo.find_reference(branch))? {
+                None => {
+                    repairs.push(format!("create symref {branch} -> {tracking}"));
+                    true
+                },
+                Some(r) => match r.symbolic_target() {
+                    Some(target) if target == &*tracking => false,
+                    Some(target) => {
+                        repairs.push(format!(
+                            "point symref {branch} to {tracking} instead of {target}"
+                        ));
+                        true
+                    },
+                    None if r.target() == tip => {
+                        repairs.push(format!("replace {branch} by a symref to {tracking}"));
+                        true
+                    },
+                    None => {
+                        problems.push(format!(
+                            "{branch} is not a symref to {tracking}, and points to a different commit"
+                        ));
+                        false
+                    },
+                },
+            };
+            if needs_symref && !args.dry_run {
+                tx.lock_ref(branch.clone())?
+                    .set_symbolic_target(tracking.clone(), REFLOG.to_owned());
+            }
+        }
+
+        let status = if !problems.is_empty() {
+            Status::Irreparable
+        } else if repairs.is_empty() {
+            Status::Ok
+        } else if args.dry_run {
+            Status::NeedsRepair
+        } else {
+            Status::Repaired
+        };
+        out.push(Output {
+            branch: branch.clone(),
+            tracking: Some(tracking),
+            status,
+            repairs,
+            problems,
+        });
+    }
+    tx.commit()?;
+
+    Ok(out)
+}
+
+/// The most recent checkpoint of each branch recorded in `drop_ref` by an
+/// identity eligible to update it
+fn recorded_tips(
+    repo: &git2::Repository,
+    drop_ref: &str,
+    meta: &metadata::drop::Verified,
+) -> cmd::Result<BTreeMap<Refname, git2::Oid>> {
+    let mut submitters: HashMap<ContentHash, IdentityId> = HashMap::new();
+    let mut tips = BTreeMap::new();
+    for rec in dropped::records(repo, drop_ref) {
+        let rec = rec?;
+        if rec.topic != *TOPIC_MERGES {
+            continue;
+        }
+        let signer = &rec.meta.signature.signer;
+        let submitter = match submitters.get(signer) {
+            Some(id) => *id,
+            None => {
+                let id = *metadata::Identity::from_content_hash(repo, signer)?
+                    .verified(metadata::git::find_parent(repo))?
+                    .id();
+                submitters.insert(signer.clone(), id);
+                id
+            },
+        };
+        for (branch, role) in &meta.roles.branches {
+            if tips.contains_key(branch) || !role.role.ids.contains(&submitter) {
+                continue;
+            }
+            if let Some(tip) = rec.meta.bundle.references.get(branch) {
+                tips.insert(branch.clone(), git2::Oid::try_from(tip)?);
+            }
+        }
+        if tips.len() == meta.roles.branches.len() {
+            break;
+        }
+    }
+
+    Ok(tips)
+}
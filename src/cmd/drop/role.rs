@@ -0,0 +1,241 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+
+use super::{
+    branch_name,
+    edit::{
+        EditState,
+        Output,
+    },
+    Common,
+};
+use crate::{
+    cmd,
+    git::{
+        self,
+        Refname,
+    },
+    metadata::{
+        self,
+        IdentityId,
+    },
+    patches::{
+        roles,
+        DropHead,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Role {
+    /// Add identities to a role
+    AddId(AddId),
+    /// Remove identities from a role
+    RemoveId(RemoveId),
+    /// Set the signature threshold of a role
+    SetThreshold(SetThreshold),
+    /// List the roles with their verified members and thresholds
+    Ls(Ls),
+}
+
+impl Role {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::AddId(args) => add_id(args).map(cmd::IntoOutput::into_output),
+            Self::RemoveId(args) => remove_id(args).map(cmd::IntoOutput::into_output),
+            Self::SetThreshold(args) => set_threshold(args).map(cmd::IntoOutput::into_output),
+            Self::Ls(args) => ls(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+/// The name of a role on the command line
+///
+/// Either of `root`, `snapshot` or `mirrors`, or else the name of a branch.
+#[derive(Clone, Debug)]
+pub enum Name {
+    Root,
+    Snapshot,
+    Mirrors,
+    Branch(Refname),
+}
+
+impl Name {
+    fn get_mut<'a>(
+        &self,
+        meta: &'a mut metadata::Drop,
+    ) -> cmd::Result<&'a mut metadata::drop::Role> {
+        let roles = &mut meta.roles;
+        match self {
+            Self::Root => Ok(&mut roles.root),
+            Self::Snapshot => Ok(&mut roles.snapshot),
+            Self::Mirrors => Ok(&mut roles.mirrors),
+            Self::Branch(name) => roles
+                .branches
+                .get_mut(name)
+                .map(|ann| &mut ann.role)
+                .ok_or_else(|| anyhow!("no role for branch {name}")),
+        }
+    }
+}
+
+impl FromStr for Name {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "root" => Ok(Self::Root),
+            "snapshot" => Ok(Self::Snapshot),
+            "mirrors" => Ok(Self::Mirrors),
+            branch => branch_name(branch).map(Self::Branch),
+        }
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Root => f.write_str("root"),
+            Self::Snapshot => f.write_str("snapshot"),
+            Self::Mirrors => f.write_str("mirrors"),
+            Self::Branch(name) => write!(f, "branches[{name}]"),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct AddId {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// The role: root, snapshot, mirrors, or the name of a branch
+    #[clap(value_parser, value_name = "ROLE")]
+    role: Name,
+    /// The identities to add
+    #[clap(value_parser, value_name = "ID", required = true)]
+    ids: Vec<IdentityId>,
+}
+
+pub fn add_id(args: AddId) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Add {} to role {}", join(&args.ids), args.role));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        let role = args.role.get_mut(meta)?;
+        for id in args.ids {
+            ensure!(role.ids.insert(id), "{id} is already in role {}", args.role);
+        }
+        Ok(())
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RemoveId {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// The role: root, snapshot, mirrors, or the name of a branch
+    #[clap(value_parser, value_name = "ROLE")]
+    role: Name,
+    /// The identities to remove
+    #[clap(value_parser, value_name = "ID", required = true)]
+    ids: Vec<IdentityId>,
+}
+
+pub fn remove_id(args: RemoveId) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Remove {} from role {}", join(&args.ids), args.role));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        let role = args.role.get_mut(meta)?;
+        for id in &args.ids {
+            ensure!(role.ids.remove(id), "{id} is not in role {}", args.role);
+        }
+        ensure_threshold(role, &args.role)
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SetThreshold {
+    #[clap(flatten)]
+    common: Common,
+    /// Commit message for this edit
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// The role: root, snapshot, mirrors, or the name of a branch
+    #[clap(value_parser, value_name = "ROLE")]
+    role: Name,
+    /// The number of signatures required
+    #[clap(value_parser, value_name = "N")]
+    threshold: NonZeroUsize,
+}
+
+pub fn set_threshold(args: SetThreshold) -> cmd::Result<Output> {
+    let message = args
+        .message
+        .unwrap_or_else(|| format!("Set threshold of role {} to {}", args.role, args.threshold));
+    EditState::open(args.common)?.update_drop(message, |meta| {
+        let role = args.role.get_mut(meta)?;
+        role.threshold = args.threshold;
+        ensure_threshold(role, &args.role)
+    })
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Ls {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop metadata history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+}
+
+/// Resolve the members of all roles of the drop
+///
+/// Members whose identity can not be verified are listed with the reason, and
+/// do not count towards the role's threshold.
+pub fn ls(args: Ls) -> cmd::Result<roles::Roles> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let drop = DropHead::from_refname(&repo, &args.drop_ref)?;
+    roles::resolve(&repo, &drop)
+}
+
+fn ensure_threshold(role: &metadata::drop::Role, name: &Name) -> cmd::Result<()> {
+    ensure!(
+        role.threshold.get() <= role.ids.len(),
+        "threshold {} of role {name} exceeds its number of identities {}",
+        role.threshold,
+        role.ids.len()
+    );
+    Ok(())
+}
+
+fn join(ids: &[IdentityId]) -> String {
+    ids.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -4,26 +4,41 @@
 use std::{
     fs::File,
     io::Read,
+    net::ToSocketAddrs,
+    num::{
+        NonZeroU32,
+        NonZeroU64,
+        NonZeroUsize,
+    },
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
 };
 
 use clap::ValueHint;
 use url::Url;
 
 use super::Common;
+#[cfg(feature = "rpc")]
+use crate::rpc;
 use crate::{
     cfg,
     cmd::{
         self,
         args::Refname,
+        ui::info,
     },
     http,
+    metadata::identity::ExpiryPolicy,
     patches::{
         REF_IT_BUNDLES,
         REF_IT_PATCHES,
         REF_IT_SEEN,
     },
+    service::{
+        self,
+        Service,
+    },
 };
 
 #[derive(Debug, clap::Args)]
@@ -57,19 +72,54 @@ pub struct Serve {
         default_value_t = Refname::from_str(REF_IT_SEEN).unwrap()
     )]
     seen_ref: Refname,
-    /// 'host:port' to listen on
-    #[clap(
-        long,
-        value_parser,
-        value_name = "HOST:PORT",
-        default_value = "127.0.0.1:8084"
-    )]
-    listen: String,
+    /// 'host:port' or 'unix:/path/to/socket' to listen on
+    ///
+    /// May be given multiple times. A host name resolving to multiple
+    /// addresses (eg. 'localhost') is bound on each of them, which allows to
+    /// serve both IPv4 and IPv6.
+    ///
+    /// If the process was started via socket activation (the LISTEN_FDS
+    /// protocol used by systemd), the inherited sockets are used in addition.
+    /// If neither are given, '127.0.0.1:8084' is used.
+    #[clap(long, value_parser, value_name = "ADDR")]
+    listen: Vec<String>,
+    /// 'host:port' to listen on using TLS
+    ///
+    /// Like 'listen', but accepts TLS connections using 'tls-cert' and
+    /// 'tls-key'. If not given, but 'tls-cert' is, all TCP listeners use TLS.
+    #[clap(long, value_parser, value_name = "HOST:PORT", requires = "tls_cert")]
+    tls_listen: Vec<String>,
     /// Number of threads to use for the server
     ///
     /// If not set, the number of available cores is used.
     #[clap(long, value_parser, value_name = "INT")]
     threads: Option<usize>,
+    /// Maximum number of patch submissions per client IP address per hour
+    ///
+    /// Note that when running behind a proxy, all submissions appear to come
+    /// from the proxy's address. Connections via a unix domain socket are not
+    /// limited.
+    ///
+    /// Chunked uploads are limited accordingly: each client may send enough
+    /// chunks per hour to upload bundles of 'max-chunked-upload-size' twice
+    /// per submission.
+    #[clap(long, value_parser, value_name = "INT")]
+    max_submissions_per_hour: Option<NonZeroU32>,
+    /// Maximum number of patch bundles being uploaded at the same time
+    #[clap(long, value_parser, value_name = "INT")]
+    max_concurrent_uploads: Option<NonZeroUsize>,
+    /// Maximum number of bytes per second to receive from all uploads combined
+    #[clap(long, value_parser, value_name = "BYTES")]
+    max_upload_rate: Option<NonZeroU64>,
+    /// Accept patch bundles of up to this many bytes uploaded in chunks
+    ///
+    /// Bundles larger than a single request permits (eg. snapshots) are
+    /// uploaded in resumable chunks. Unfinished uploads are kept below
+    /// $GIT_DIR/it/uploads, and removed after 24 hours of inactivity. At most
+    /// 32 uploads may be unfinished at any time, and at most 4 per client IP
+    /// address. If not set, chunked uploads are rejected.
+    #[clap(long, value_parser, value_name = "BYTES")]
+    max_chunked_upload_size: Option<NonZeroU64>,
     /// PEM-encoded TLS certificate
     ///
     /// Requires 'tls-key'. If not set (the default), the server will not use
@@ -95,6 +145,9 @@ pub struct Serve {
     )]
     tls_key: Option<PathBuf>,
     /// IPFS API to publish received patch bundle to
+    ///
+    /// If publishing fails, it is retried after the next accepted patch, or
+    /// every five minutes.
     #[clap(
         long,
         value_parser,
@@ -102,12 +155,68 @@ pub struct Serve {
         value_hint = ValueHint::Url,
     )]
     ipfs_api: Option<Url>,
+    /// Externally visible base URL of the drop
+    ///
+    /// Used to generate absolute URLs in responses, eg. bundle lists. This is
+    /// needed when the server is not reachable at the address it listens on,
+    /// such as when running as a Tor onion service: listen on localhost, point
+    /// the 'HiddenServicePort' at the listen address, and set this to the
+    /// '.onion' address.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "URL",
+        value_hint = ValueHint::Url,
+    )]
+    public_url: Option<Url>,
+    /// Trust the 'X-Forwarded-*' headers set by this reverse proxy
+    ///
+    /// Either an IP address, a network in CIDR notation, or 'unix' to trust
+    /// any connection on a unix domain socket. May be given multiple times.
+    ///
+    /// Requests from a trusted proxy are attributed to the client address in
+    /// 'X-Forwarded-For' for rate limiting and logging. Unless '--public-url'
+    /// is given, absolute URLs are generated from 'X-Forwarded-Proto' and
+    /// 'X-Forwarded-Host'.
+    #[clap(long = "trusted-proxy", value_parser, value_name = "ADDR")]
+    trusted_proxies: Vec<http::TrustedProxy>,
+    /// 'host:port' or 'unix:/path/to/socket' to accept JSON-RPC connections on
+    ///
+    /// May be given multiple times. The RPC service is disabled unless this is
+    /// given.
+    #[cfg(feature = "rpc")]
+    #[clap(long, value_parser, value_name = "ADDR")]
+    rpc_listen: Vec<String>,
+    /// Start even if the refnames conflict with existing state
+    ///
+    /// By default, the server refuses to start if eg. the seen ref does not
+    /// exist, but the default one does, which usually indicates a typo.
+    #[clap(long, value_parser)]
+    force: bool,
+    /// Serve plain HTML pages to browse the drop
+    ///
+    /// The description, branches, and topics of the drop are rendered at '/',
+    /// and the notes of each topic at '/topics/<topic>'. No JavaScript is
+    /// used.
+    #[clap(long, value_parser)]
+    serve_html: bool,
+    /// Accept the drop metadata even if identities of role members have
+    /// expired
+    ///
+    /// Signatures made by expired identities are assumed to have been made
+    /// before expiry, and a warning is logged. The identity of the submitter
+    /// of a patch must not be expired regardless.
+    #[clap(long, value_parser)]
+    allow_expired_ids: bool,
 }
 
 #[derive(serde::Serialize)]
 pub struct Output;
 
 pub fn serve(args: Serve) -> cmd::Result<Output> {
+    // Before spawning any threads, so they don't receive the signals
+    let shutdown = http::shutdown_signals()?;
+
     let tls = args
         .tls_cert
         .map(|cert_path| -> cmd::Result<http::SslConfig> {
@@ -124,17 +233,123 @@ pub fn serve(args: Serve) -> cmd::Result<Output> {
         })
         .transpose()?;
 
+    // Without explicit TLS listeners, TLS applies to all TCP listeners, as it
+    // did before they could be configured separately
+    let plain_tls = if args.tls_listen.is_empty() {
+        tls.clone()
+    } else {
+        None
+    };
+
+    let mut listeners = Vec::new();
+    for addr in &args.listen {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            listeners.push(unix_listener(path)?);
+        } else {
+            for addr in addr.to_socket_addrs()? {
+                listeners.push(http::Listener {
+                    listen: http::Listen::Tcp(addr),
+                    tls: plain_tls.clone(),
+                });
+            }
+        }
+    }
+    for addr in &args.tls_listen {
+        for addr in addr.to_socket_addrs()? {
+            listeners.push(http::Listener {
+                listen: http::Listen::Tcp(addr),
+                tls: tls.clone(),
+            });
+        }
+    }
+    for listener in http::listen_fds()? {
+        let tls = match listener {
+            tiny_http::Listener::Tcp(_) => plain_tls.clone(),
+            #[cfg(unix)]
+            tiny_http::Listener::Unix(_) => None,
+        };
+        listeners.push(http::Listener {
+            listen: http::Listen::Inherited(listener),
+            tls,
+        });
+    }
+    if listeners.is_empty() {
+        for addr in DEFAULT_LISTEN.to_socket_addrs()? {
+            listeners.push(http::Listener {
+                listen: http::Listen::Tcp(addr),
+                tls: plain_tls.clone(),
+            });
+        }
+    }
+    for listener in &listeners {
+        info!(
+            "Listening on {}{}",
+            listener.listen,
+            if listener.tls.is_some() { " (TLS)" } else { "" }
+        );
+    }
+
+    let service = Arc::new(Service::open(service::Options {
+        git_dir: args.common.git_dir,
+        bundle_dir: args.bundle_dir,
+        unbundle_prefix: args.unbundle_prefix.into(),
+        drop_ref: REF_IT_PATCHES.into(),
+        seen_ref: args.seen_ref.into(),
+        ipfs_api: args.ipfs_api,
+        public_url: args.public_url,
+        force: args.force,
+        serve_html: args.serve_html,
+        expiry: if args.allow_expired_ids {
+            ExpiryPolicy::Warn
+        } else {
+            ExpiryPolicy::Strict
+        },
+    })?);
+    service.spawn_retries()?;
+    let limits = http::Limits {
+        submissions_per_hour: args.max_submissions_per_hour,
+        concurrent_uploads: args.max_concurrent_uploads,
+        bandwidth: args.max_upload_rate,
+    };
+
+    #[cfg(feature = "rpc")]
+    for addr in &args.rpc_listen {
+        let listen = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => rpc::Listen::Unix(path.into()),
+            _ => rpc::Listen::Tcp(addr.to_socket_addrs()?.collect()),
+        };
+        info!("Listening on {listen} (JSON-RPC)");
+        rpc::spawn(listen, Arc::clone(&service), limits)?;
+    }
+
     http::serve(
-        args.listen,
+        listeners,
         http::Options {
-            git_dir: args.common.git_dir,
-            bundle_dir: args.bundle_dir,
-            unbundle_prefix: args.unbundle_prefix.into(),
-            drop_ref: REF_IT_PATCHES.into(),
-            seen_ref: args.seen_ref.into(),
             threads: args.threads,
-            tls,
-            ipfs_api: args.ipfs_api,
+            limits,
+            max_upload_len: args.max_chunked_upload_size,
+            trusted_proxies: args.trusted_proxies,
+            shutdown: Some(shutdown),
         },
-    )
+        service,
+    )?;
+
+    Ok(Output)
+}
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8084";
+
+#[cfg(unix)]
+fn unix_listener(path: &str) -> cmd::Result<http::Listener> {
+    anyhow::ensure!(!path.is_empty(), "empty unix socket path");
+    Ok(http::Listener {
+        listen: http::Listen::Unix(path.into()),
+        tls: None,
+    })
+}
+
+#[cfg(not(unix))]
+fn unix_listener(_: &str) -> cmd::Result<http::Listener> {
+    anyhow::bail!("unix domain sockets are not supported on this platform")
 }
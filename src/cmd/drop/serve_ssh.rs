@@ -0,0 +1,139 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    io,
+    num::NonZeroU64,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use clap::ValueHint;
+
+use super::Common;
+use crate::{
+    cfg,
+    cmd::{
+        self,
+        args::Refname,
+        ui::{
+            info,
+            warn,
+        },
+    },
+    metadata::identity::ExpiryPolicy,
+    patches::{
+        self,
+        ssh::Response,
+        MAX_LEN_BUNDLE,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+    service::{
+        self,
+        Service,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct ServeSsh {
+    #[clap(flatten)]
+    common: Common,
+    /// The directory where to write the bundle to
+    ///
+    /// Unless this is an absolute path, it is treated as relative to $GIT_DIR.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        default_value_os_t = cfg::paths::bundles().to_owned(),
+        value_hint = ValueHint::DirPath,
+    )]
+    bundle_dir: PathBuf,
+    /// Ref prefix under which to store the refs contained in patch bundles
+    #[clap(
+        long,
+        value_parser,
+        value_name = "REF",
+        default_value_t = Refname::from_str(REF_IT_BUNDLES).unwrap()
+    )]
+    unbundle_prefix: Refname,
+    /// The refname anchoring the seen objects tree
+    #[clap(
+        long,
+        value_parser,
+        value_name = "REF",
+        default_value_t = Refname::from_str(REF_IT_SEEN).unwrap()
+    )]
+    seen_ref: Refname,
+    /// Accept patch bundles of up to this many bytes
+    ///
+    /// Defaults to the size a bundle submitted over HTTP in a single request
+    /// may have.
+    #[clap(long, value_parser, value_name = "BYTES")]
+    max_bundle_size: Option<NonZeroU64>,
+    /// Start even if the refnames conflict with existing state
+    #[clap(long, value_parser)]
+    force: bool,
+    /// Accept the drop metadata even if identities of role members have
+    /// expired
+    ///
+    /// Signatures made by expired identities are assumed to have been made
+    /// before expiry, and a warning is logged. The identity of the submitter
+    /// of a patch must not be expired regardless.
+    #[clap(long, value_parser)]
+    allow_expired_ids: bool,
+}
+
+/// Accept a single patch submission on stdin, see [`patches::ssh`]
+pub fn serve_ssh(args: ServeSsh) -> cmd::Result<cmd::Output> {
+    let service = Service::open(service::Options {
+        git_dir: args.common.git_dir,
+        bundle_dir: args.bundle_dir,
+        unbundle_prefix: args.unbundle_prefix.into(),
+        drop_ref: REF_IT_PATCHES.into(),
+        seen_ref: args.seen_ref.into(),
+        ipfs_api: None,
+        public_url: None,
+        force: args.force,
+        serve_html: false,
+        expiry: if args.allow_expired_ids {
+            ExpiryPolicy::Warn
+        } else {
+            ExpiryPolicy::Strict
+        },
+    })?;
+    let max_len = args
+        .max_bundle_size
+        .map_or(MAX_LEN_BUNDLE as u64, NonZeroU64::get);
+
+    let stdin = io::stdin();
+    let sub = patches::Submission::from_ssh(service.bundle_dir(), &mut stdin.lock(), max_len);
+    let resp = match sub.and_then(|sub| service.accept(sub)) {
+        Ok(record) => {
+            info!("Accepted patch for topic {}", record.topic);
+            // Nobody subscribed to this short-lived service, so we hold the
+            // only reference
+            Response::Accepted {
+                record: Arc::try_unwrap(record).map_err(|_| anyhow!("record is shared"))?,
+            }
+        },
+        Err(e) => match e.downcast::<patches::Quarantined>() {
+            Ok(patches::Quarantined { record, .. }) => Response::Quarantined { record },
+            Err(e) => {
+                warn!("Rejected submission: {e:#}");
+                Response::Rejected {
+                    reason: e.to_string(),
+                }
+            },
+        },
+    };
+    service.maintain_if_due();
+
+    let mut line = serde_json::to_string(&resp)?;
+    line.push('\n');
+    Ok(cmd::Output::Text(line))
+}
@@ -26,11 +26,22 @@ use crate::{
     git,
     metadata::{
         self,
+        git::{
+            GitPolicy,
+            META_FILE_POLICY,
+        },
+        identity::ExpiryPolicy,
         ContentHash,
         IdentityId,
         KeySet,
     },
-    patches::REF_IT_PATCHES,
+    patches::{
+        view::{
+            At,
+            View,
+        },
+        REF_IT_PATCHES,
+    },
 };
 
 #[derive(Debug, clap::Args)]
@@ -45,17 +56,34 @@ pub struct Show {
         default_value_t = REF_IT_PATCHES.parse().unwrap(),
     )]
     drop_ref: Refname,
+    /// Show the metadata as of a past point in the drop history
+    ///
+    /// Either a date in RFC 3339 format, in which case the most recent drop
+    /// commit not newer than it is used, or a commit in 'git rev-parse'
+    /// syntax.
+    #[clap(long, value_parser, value_name = "DATE|REV")]
+    at: Option<At>,
+    /// Consider signatures made by expired identities valid
+    ///
+    /// Such signatures are assumed to have been made before the identity
+    /// expired, and a warning is printed.
+    #[clap(long, value_parser)]
+    allow_expired_ids: bool,
 }
 
 #[derive(serde::Serialize)]
 pub struct Output {
     repo: PathBuf,
     refname: Refname,
+    #[serde(with = "git::serde::oid")]
+    commit: git2::Oid,
     drop: Data<metadata::Drop>,
     #[serde(skip_serializing_if = "Option::is_none")]
     mirrors: Option<Data<metadata::Mirrors>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     alternates: Option<Data<metadata::Alternates>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<Data<metadata::Policy>>,
 }
 
 #[derive(serde::Serialize)]
@@ -84,6 +112,11 @@ pub fn show(args: Show) -> cmd::Result<Output> {
     let drop_ref = args.drop_ref;
 
     let repo = git::repo::open(git_dir)?;
+    let commit = match &args.at {
+        Some(at) => View::resolve(&repo, &drop_ref, at)?.commit().clone(),
+        None => repo.find_reference(&drop_ref)?.peel_to_commit()?,
+    };
+    let tree = commit.tree()?;
 
     let GitDrop {
         hash,
@@ -91,9 +124,14 @@ pub fn show(args: Show) -> cmd::Result<Output> {
             signed: drop,
             signatures,
         },
-    } = metadata::Drop::from_tip(&repo, &drop_ref)?;
+    } = metadata::Drop::from_commit(&repo, &commit)?;
 
-    let mut signer_cache = SignerCache::new(&repo, &drop_ref)?;
+    let expiry = if args.allow_expired_ids {
+        ExpiryPolicy::Warn
+    } else {
+        ExpiryPolicy::Strict
+    };
+    let mut signer_cache = SignerCache::new(&repo, &tree, expiry)?;
     let status = drop
         .verify(
             &signatures,
@@ -104,8 +142,8 @@ pub fn show(args: Show) -> cmd::Result<Output> {
 
     let mut mirrors = None;
     let mut alternates = None;
+    let mut policy = None;
 
-    let tree = repo.find_reference(&drop_ref)?.peel_to_commit()?.tree()?;
     if let Some(entry) = tree.get_name(META_FILE_MIRRORS) {
         let blob = entry.to_object(&repo)?.peel_to_blob()?;
         let GitMirrors { hash, signed } = metadata::Mirrors::from_blob(&blob)?;
@@ -134,9 +172,24 @@ pub fn show(args: Show) -> cmd::Result<Output> {
         });
     }
 
+    if let Some(entry) = tree.get_name(META_FILE_POLICY) {
+        let blob = entry.to_object(&repo)?.peel_to_blob()?;
+        let GitPolicy { hash, signed } = metadata::Policy::from_blob(&blob)?;
+        let status = drop
+            .verify_policy(&signed, find_signer(&mut signer_cache))
+            .into();
+
+        policy = Some(Data {
+            hash,
+            status,
+            json: signed.signed,
+        });
+    }
+
     Ok(Output {
         repo: repo.path().to_owned(),
         refname: drop_ref,
+        commit: commit.id(),
         drop: Data {
             hash,
             status,
@@ -144,21 +197,25 @@ pub fn show(args: Show) -> cmd::Result<Output> {
         },
         mirrors,
         alternates,
+        policy,
     })
 }
 
 struct SignerCache<'a> {
     repo: &'a git2::Repository,
     root: git2::Tree<'a>,
+    expiry: ExpiryPolicy,
     keys: BTreeMap<IdentityId, KeySet<'static>>,
 }
 
 impl<'a> SignerCache<'a> {
-    pub(self) fn new(repo: &'a git2::Repository, refname: &Refname) -> git::Result<Self> {
+    pub(self) fn new(
+        repo: &'a git2::Repository,
+        tree: &git2::Tree,
+        expiry: ExpiryPolicy,
+    ) -> git::Result<Self> {
         let root = {
-            let id = repo
-                .find_reference(refname)?
-                .peel_to_tree()?
+            let id = tree
                 .get_name("ids")
                 .ok_or_else(|| {
                     git2::Error::new(
@@ -172,7 +229,12 @@ impl<'a> SignerCache<'a> {
         };
         let keys = BTreeMap::new();
 
-        Ok(Self { repo, root, keys })
+        Ok(Self {
+            repo,
+            root,
+            expiry,
+            keys,
+        })
     }
 }
 
@@ -182,13 +244,14 @@ fn find_signer<'a>(
     fn go(
         repo: &git2::Repository,
         root: &git2::Tree,
+        expiry: ExpiryPolicy,
         keys: &mut BTreeMap<IdentityId, KeySet<'static>>,
         id: &IdentityId,
     ) -> cmd::Result<KeySet<'static>> {
         match keys.get(id) {
             Some(keys) => Ok(keys.clone()),
             None => {
-                let (id, verified) = metadata::identity::find_in_tree(repo, root, id)
+                let (id, verified) = metadata::identity::find_in_tree_with(repo, root, id, expiry)
                     .with_context(|| format!("identity {id} failed to verify"))?
                     .into_parts();
                 keys.insert(id, verified.keys.clone());
@@ -197,7 +260,7 @@ fn find_signer<'a>(
         }
     }
 
-    |id| go(cache.repo, &cache.root, &mut cache.keys, id).map_err(as_io)
+    |id| go(cache.repo, &cache.root, cache.expiry, &mut cache.keys, id).map_err(as_io)
 }
 
 fn as_io<E>(e: E) -> io::Error
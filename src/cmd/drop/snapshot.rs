@@ -13,8 +13,16 @@ use crate::{
 pub struct Snapshot {
     #[clap(flatten)]
     common: patch::Common,
+    /// Include the refs of all records, instead of only those recorded since
+    /// the previous snapshot
+    ///
+    /// Full snapshots are self-contained, but mostly repeat the previous
+    /// snapshot. Setting `it.dedupSnapshots` in the drop repository stores
+    /// the repeated parts only once.
+    #[clap(long, value_parser)]
+    full: bool,
 }
 
-pub fn snapshot(Snapshot { common }: Snapshot) -> cmd::Result<patches::Record> {
-    patch::create(patch::Kind::Snapshot { common })
+pub fn snapshot(Snapshot { common, full }: Snapshot) -> cmd::Result<patches::Record> {
+    patch::create(patch::Kind::Snapshot { common, full })
 }
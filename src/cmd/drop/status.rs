@@ -0,0 +1,236 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    cmd::{
+        self,
+        ui::{
+            term::{
+                self,
+                Align,
+                Table,
+            },
+            Msg,
+        },
+        util::args::Refname,
+        IntoOutput as _,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    patches::{
+        self,
+        iter::dropped,
+        record::Heads,
+        Record,
+        Seen as _,
+        Topic,
+        REF_IT_BRANCHES,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_QUARANTINE,
+        REF_IT_SEEN,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Status {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Print a table instead of JSON
+    #[clap(long, value_parser)]
+    table: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    branches: Vec<Branch>,
+    topics: Vec<UnseenTopic>,
+    pending: Vec<Pending>,
+}
+
+/// A tracking branch and how it relates to the local branch of the same name
+#[derive(serde::Serialize)]
+pub struct Branch {
+    branch: Refname,
+    #[serde(with = "git::serde::oid")]
+    tracking: git2::Oid,
+    #[serde(
+        with = "git::serde::oid::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    local: Option<git2::Oid>,
+    /// Commits in the tracking branch not in the local branch
+    ahead: usize,
+    /// Commits in the local branch not in the tracking branch
+    behind: usize,
+}
+
+/// A topic with records which were not unbundled yet
+#[derive(serde::Serialize)]
+pub struct UnseenTopic {
+    topic: Topic,
+    unseen: usize,
+}
+
+/// A quarantined submission which was not yet approved
+#[derive(serde::Serialize)]
+pub struct Pending {
+    heads: Heads,
+    topic: Topic,
+}
+
+pub fn status(args: Status) -> cmd::Result<cmd::Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let out = Output {
+        branches: branches(&repo)?,
+        topics: unseen_topics(&repo, &args.drop_ref)?,
+        pending: pending(&repo)?,
+    };
+
+    Ok(if args.table {
+        cmd::Output::Text(out.to_table())
+    } else {
+        out.into_output()
+    })
+}
+
+fn branches(repo: &git2::Repository) -> cmd::Result<Vec<Branch>> {
+    let mut branches = Vec::new();
+    for r in repo.references_glob(&format!("{REF_IT_BRANCHES}/*"))? {
+        let r = r?;
+        let name = r.name().ok_or_else(|| anyhow!("invalid ref name"))?;
+        let branch: Refname =
+            format!("refs/heads/{}", &name[REF_IT_BRANCHES.len() + 1..]).try_into()?;
+        let tracking = r.peel_to_commit()?.id();
+        let local = if repo.is_bare() {
+            None
+        } else {
+            if_not_found_none(repo.refname_to_id(&branch))?
+        };
+        let (ahead, behind) = match local {
+            Some(local) => repo.graph_ahead_behind(tracking, local)?,
+            None => (0, 0),
+        };
+        branches.push(Branch {
+            branch,
+            tracking,
+            local,
+            ahead,
+            behind,
+        });
+    }
+
+    Ok(branches)
+}
+
+fn unseen_topics(repo: &git2::Repository, drop_ref: &str) -> cmd::Result<Vec<UnseenTopic>> {
+    let mut unseen = BTreeMap::new();
+    for rec in dropped::records(repo, drop_ref) {
+        let rec = rec?;
+        if !is_unbundled(repo, &rec)? {
+            *unseen.entry(rec.topic).or_insert(0) += 1;
+        }
+    }
+
+    Ok(unseen
+        .into_iter()
+        .map(|(topic, unseen)| UnseenTopic { topic, unseen })
+        .collect())
+}
+
+fn is_unbundled(repo: &git2::Repository, rec: &Record) -> cmd::Result<bool> {
+    match rec.meta.bundle.references.keys().next() {
+        None => Ok(true),
+        Some(name) => {
+            let unbundled = patches::unbundled_ref(REF_IT_BUNDLES, rec, name)?;
+            Ok(if_not_found_none(repo.refname_to_id(&unbundled))?.is_some())
+        },
+    }
+}
+
+fn pending(repo: &git2::Repository) -> cmd::Result<Vec<Pending>> {
+    let seen = match if_not_found_none(repo.find_reference(REF_IT_SEEN))? {
+        Some(seen) => Some(seen.peel_to_tree()?),
+        None => None,
+    };
+    let mut pending = Vec::new();
+    for r in repo.references_glob(&format!("{REF_IT_QUARANTINE}/*"))? {
+        let Record { topic, heads, .. } = Record::from_commit(repo, &r?.peel_to_commit()?)?;
+        // Approved, but the quarantine ref was not cleaned up
+        if let Some(seen) = &seen {
+            if heads.in_tree(seen)? {
+                continue;
+            }
+        }
+        pending.push(Pending { heads, topic });
+    }
+
+    Ok(pending)
+}
+
+impl Output {
+    fn to_table(&self) -> String {
+        let width = term::width();
+        let section = |out: &mut String, heading: Msg, table: Table| {
+            out.push_str(heading.text());
+            out.push('\n');
+            if table.is_empty() {
+                let _ = writeln!(out, "  {}", Msg::None.text());
+            } else {
+                out.push_str(&table.render(width));
+            }
+        };
+        let mut out = String::new();
+
+        let mut branches = Table::new().indent(2);
+        for b in &self.branches {
+            let state = match (b.local, b.ahead, b.behind) {
+                (None, _, _) => Msg::NoLocalBranch.text().to_owned(),
+                (Some(_), 0, 0) => Msg::UpToDate.text().to_owned(),
+                (Some(_), ahead, 0) => Msg::Ahead.fmt(&[("ahead", &ahead)]),
+                (Some(_), 0, behind) => Msg::Behind.fmt(&[("behind", &behind)]),
+                (Some(_), ahead, behind) => {
+                    Msg::Diverged.fmt(&[("ahead", &ahead), ("behind", &behind)])
+                },
+            };
+            let tracking = b.tracking.to_string();
+            branches.row([b.branch.to_string(), tracking[..12].to_owned(), state]);
+        }
+        section(&mut out, Msg::HeadingBranches, branches);
+
+        let mut topics = Table::new().indent(2).align(1, Align::Right);
+        for t in &self.topics {
+            topics.row([t.topic.to_string(), t.unseen.to_string()]);
+        }
+        out.push('\n');
+        section(&mut out, Msg::HeadingUnseenTopics, topics);
+
+        let mut pending = Table::new().indent(2);
+        for p in &self.pending {
+            pending.row([p.heads.to_string(), p.topic.to_string()]);
+        }
+        out.push('\n');
+        section(&mut out, Msg::HeadingPending, pending);
+
+        out
+    }
+}
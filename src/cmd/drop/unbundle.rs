@@ -3,24 +3,60 @@
 
 use std::{
     collections::BTreeMap,
-    path::PathBuf,
+    num::NonZeroUsize,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        mpsc,
+        Arc,
+    },
 };
 
-use anyhow::anyhow;
+use anyhow::{
+    anyhow,
+    ensure,
+};
 use clap::ValueHint;
+use threadpool::ThreadPool;
 
+use super::bundles::def_jobs;
 use crate::{
-    cmd,
+    bundle,
+    cfg,
+    cmd::{
+        self,
+        ui::{
+            self,
+            debug,
+            info,
+            warn,
+        },
+    },
     git::{
         self,
         if_not_found_none,
         refs,
         Refname,
     },
+    metadata::{
+        self,
+        git::{
+            find_parent,
+            FromGit as _,
+        },
+    },
     patches::{
         self,
         iter::dropped,
+        record::Heads,
+        view::{
+            self,
+            View,
+        },
         Bundle,
+        Record,
         REF_IT_BUNDLES,
         REF_IT_PATCHES,
     },
@@ -49,6 +85,43 @@ pub struct Unbundle {
         value_hint = ValueHint::DirPath,
     )]
     bundle_dir: PathBuf,
+    /// Only unbundle the record at this drop commit, which was accepted
+    /// without unbundling; may be given multiple times
+    ///
+    /// Unlike unbundling the entire history, this also merges the record's
+    /// notes into its topic and updates the tracking branches, as if the
+    /// record had been unbundled when it was accepted.
+    #[clap(long = "record", value_parser, value_name = "REV")]
+    records: Vec<String>,
+    /// Ref prefix under which to store the refs contained in patch bundles
+    #[clap(
+        long,
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_BUNDLES.parse().unwrap(),
+        requires = "records",
+    )]
+    unbundle_prefix: Refname,
+    /// Decrypt encrypted bundles instead of skipping them
+    ///
+    /// OpenPGP encrypted bundles are decrypted using 'gpg', which must have
+    /// access to the secret key. Age-encrypted bundles require '--identity'.
+    #[clap(long, value_parser)]
+    decrypt: bool,
+    /// An age identity file to decrypt bundles with; may be given multiple
+    /// times
+    #[clap(
+        long = "identity",
+        value_parser,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        requires = "decrypt"
+    )]
+    identities: Vec<PathBuf>,
+    /// Maximum number of bundles to verify and index concurrently. Default is
+    /// the number of available cores.
+    #[clap(short, long, value_parser, default_value_t = def_jobs())]
+    jobs: NonZeroUsize,
     /// The drop history to find the topic in
     #[clap(value_parser)]
     drop: Option<String>,
@@ -74,20 +147,253 @@ pub fn unbundle(args: Unbundle) -> cmd::Result<Output> {
             .to_owned(),
         None => REF_IT_PATCHES.to_owned(),
     };
+    let decrypt = if args.decrypt {
+        Some(Decrypt {
+            gpg_program: cfg::git::gpg_program(&repo.config()?)?,
+            identities: args.identities,
+        })
+    } else {
+        None
+    };
+
+    let (updated, records) = if args.records.is_empty() {
+        let updated = unbundle_records(
+            &repo,
+            &bundle_dir,
+            &drop,
+            false,
+            decrypt.as_ref(),
+            args.jobs,
+        )?;
+        (updated, None)
+    } else {
+        let mut updated = BTreeMap::new();
+        let mut records = Vec::with_capacity(args.records.len());
+        for rev in &args.records {
+            let (heads, up) = unbundle_deferred(
+                &repo,
+                &bundle_dir,
+                &drop,
+                &args.unbundle_prefix,
+                rev,
+                decrypt.as_ref(),
+            )?;
+            if !up.is_empty() {
+                records.push(heads);
+            }
+            updated.extend(up);
+        }
+        (updated, Some(records))
+    };
+
+    let cfg = cfg::git::open(&repo)?;
+    if !updated.is_empty() && cfg::git::audit_log(&cfg)? {
+        let mut signer = cfg::signer(&cfg, ui::askpass)?;
+        let event = patches::audit::Event::Unbundle {
+            records,
+            refs: updated.len(),
+        };
+        if let Err(e) = patches::audit::append(&repo, &mut signer, &drop, event) {
+            warn!("Failed to append to the audit log: {e:#}");
+        }
+    }
+    if let Err(e) = cmd::topic::notify(&repo, &drop) {
+        warn!("Failed to notify about subscribed topics: {e:#}");
+    }
+
+    Ok(Output { updated })
+}
+
+/// How to decrypt encrypted bundles, cf. [`Bundle::decrypt`]
+pub(super) struct Decrypt {
+    gpg_program: String,
+    identities: Vec<PathBuf>,
+}
+
+/// Index the objects of `bundle` into `repo`
+///
+/// If the bundle is encrypted, it is decrypted using `decrypt`, and the
+/// plaintext is verified to actually contain the heads claimed by `record`.
+/// Returns `false` if the bundle is encrypted, but `decrypt` is `None`.
+fn index_bundle(
+    repo: &git2::Repository,
+    bundle: &Bundle,
+    record: &Record,
+    decrypt: Option<&Decrypt>,
+) -> cmd::Result<bool> {
+    if !bundle.is_encrypted() {
+        bundle.packdata()?.index(&repo.odb()?)?;
+        return Ok(true);
+    }
+    let decrypt = match decrypt {
+        Some(decrypt) => decrypt,
+        None => return Ok(false),
+    };
+
+    let header = bundle.header();
+    ensure!(
+        Heads::from_header(header, bundle.info().hash.algorithm()) == record.heads,
+        "bundle {} does not match the heads of record {}",
+        record.bundle_hash(),
+        record.heads
+    );
+    let mut pack = bundle.decrypt(&decrypt.gpg_program, &decrypt.identities)?;
+    let objects = pack.index_objects(repo)?;
+    for (name, oid) in &header.references {
+        let is_prerequisite = header.prerequisites.contains(oid);
+        let oid = git2::Oid::try_from(oid)?;
+        ensure!(
+            objects.contains(&oid) || is_prerequisite,
+            "{name} points to {oid}, which is not contained in bundle {}",
+            record.bundle_hash()
+        );
+    }
+
+    Ok(true)
+}
+
+/// Materialise the refs of the record at `rev`, which was accepted without
+/// unbundling
+///
+/// Does nothing if the record's refs are already present below `prefix`.
+/// Returns the heads of the record along with the refs updated.
+fn unbundle_deferred(
+    repo: &git2::Repository,
+    bundle_dir: &Path,
+    drop_ref: &str,
+    prefix: &str,
+    rev: &str,
+    decrypt: Option<&Decrypt>,
+) -> cmd::Result<(Heads, BTreeMap<Refname, git::serde::oid::Oid>)> {
+    let view = View::resolve(repo, drop_ref, &view::At::Rev(rev.to_owned()))?;
+    let record = Record::from_commit(repo, view.commit())?;
+    if patches::is_unbundled(repo, prefix, &record)? {
+        info!("Record {} is already unbundled", record.heads);
+        return Ok((record.heads, BTreeMap::new()));
+    }
+
+    let bundle = Bundle::from_stored(bundle_dir, record.bundle_info().as_expect())?;
+    ensure!(
+        index_bundle(repo, &bundle, &record, decrypt)?,
+        "bundle {} is encrypted, use --decrypt",
+        record.bundle_hash()
+    );
+
+    let submitter = metadata::Identity::from_content_hash(repo, &record.meta.signature.signer)?
+        .signed
+        .verified(find_parent(repo))?;
+    let (_, meta) = patches::verified_tree(repo, &view.tree()?)?;
+
+    let mut tx = refs::Transaction::new(repo)?;
+    let updated = patches::materialise(repo, &mut tx, prefix, &submitter, &meta, &record)?;
+    tx.commit()?;
+
+    Ok((
+        record.heads,
+        updated
+            .into_iter()
+            .map(|(name, oid)| (name, oid.into()))
+            .collect(),
+    ))
+}
+
+/// Unbundle the records in `drop_ref`, oldest first
+///
+/// If `skip_missing` is true, records whose bundle is not found in
+/// `bundle_dir` are skipped instead of failing. Records whose bundle is
+/// encrypted are skipped unless `decrypt` is given.
+///
+/// Verifying and indexing the bundles happens on up to `jobs` threads. Since a
+/// thin pack can only be indexed once the bundles providing its delta bases
+/// are, indexing may fail until then: such bundles, along with encrypted ones,
+/// are indexed again in order once all others are done. Only updating the refs
+/// is serialised.
+pub(super) fn unbundle_records(
+    repo: &git2::Repository,
+    bundle_dir: &Path,
+    drop_ref: &str,
+    skip_missing: bool,
+    decrypt: Option<&Decrypt>,
+    jobs: NonZeroUsize,
+) -> cmd::Result<BTreeMap<Refname, git::serde::oid::Oid>> {
+    enum Indexed {
+        Done,
+        Deferred,
+    }
+
+    let records = dropped::records_rev(repo, drop_ref)
+        .map(|rec| rec.map(Arc::new))
+        .collect::<cmd::Result<Vec<_>>>()?;
+
+    let pool = ThreadPool::new(jobs.get());
+    let (tx, rx) = mpsc::channel();
+    let mut pending = 0;
+    for (i, rec) in records.iter().enumerate() {
+        if skip_missing && !bundle::chunks::exists(&rec.bundle_path(bundle_dir)) {
+            continue;
+        }
+        pool.execute({
+            let git_dir = repo.path().to_owned();
+            let bundle_dir = bundle_dir.to_owned();
+            let rec = Arc::clone(rec);
+            let tx = tx.clone();
+            move || {
+                let res = Bundle::from_stored(&bundle_dir, rec.bundle_info().as_expect()).and_then(
+                    |bundle| {
+                        if bundle.is_encrypted() {
+                            return Ok(Indexed::Deferred);
+                        }
+                        let repo = git2::Repository::open(&git_dir)?;
+                        let odb = repo.odb()?;
+                        match bundle.packdata()?.index(&odb) {
+                            Ok(()) => Ok(Indexed::Done),
+                            Err(e) => {
+                                debug!("Deferring bundle {}: {e:#}", rec.bundle_hash());
+                                Ok(Indexed::Deferred)
+                            },
+                        }
+                    },
+                );
+                // Receiver hangs up only on error
+                let _ = tx.send((i, res));
+            }
+        });
+        pending += 1;
+    }
+    drop(tx);
+
+    let mut indexed = BTreeMap::new();
+    for (i, res) in rx.iter().take(pending) {
+        indexed.insert(i, res?);
+    }
+    pool.join();
 
     let odb = repo.odb()?;
-    let mut tx = refs::Transaction::new(&repo)?;
+    odb.refresh()?;
+    let mut tx = refs::Transaction::new(repo)?;
     let mut up = BTreeMap::new();
-    for rec in dropped::records_rev(&repo, &drop) {
-        let rec = rec?;
-        let bundle = Bundle::from_stored(&bundle_dir, rec.bundle_info().as_expect())?;
-        bundle.packdata()?.index(&odb)?;
-        let updated = patches::unbundle(&odb, &mut tx, REF_IT_BUNDLES, &rec)?;
+    for (i, rec) in records.iter().enumerate() {
+        match indexed.remove(&i) {
+            None => {
+                debug!("Skipping missing bundle {}", rec.bundle_hash());
+                continue;
+            },
+            Some(Indexed::Done) => {},
+            Some(Indexed::Deferred) => {
+                let bundle = Bundle::from_stored(bundle_dir, rec.bundle_info().as_expect())?;
+                if !index_bundle(repo, &bundle, rec, decrypt)? {
+                    info!("Skipping encrypted bundle {}", rec.bundle_hash());
+                    continue;
+                }
+                odb.refresh()?;
+            },
+        }
+        let updated = patches::unbundle(&odb, &mut tx, REF_IT_BUNDLES, rec)?;
         for (name, oid) in updated {
             up.insert(name, oid.into());
         }
     }
     tx.commit()?;
 
-    Ok(Output { updated: up })
+    Ok(up)
 }
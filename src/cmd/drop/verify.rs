@@ -0,0 +1,408 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::{
+        hash_map,
+        BTreeSet,
+        HashMap,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+    ensure,
+};
+use clap::ValueHint;
+use time::{
+    format_description::well_known::Rfc3339,
+    OffsetDateTime,
+};
+
+use super::fsck;
+use crate::{
+    bundle,
+    cmd::{
+        self,
+        ui::debug,
+        util::args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    keys::VerificationKey,
+    metadata::{
+        self,
+        git::{
+            find_parent,
+            FromGit,
+            GitMeta,
+            META_FILE_ID,
+        },
+        identity,
+        KeyId,
+    },
+    patches::{
+        record::Heads,
+        unbundled_ref,
+        Bundle,
+        DropHead,
+        Record,
+        Seen,
+        Topic,
+        REF_HEADS_PATCHES,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+    paths,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Verify {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    ///
+    /// Defaults to 'refs/heads/patches' in bare repositories, and
+    /// 'refs/it/patches' otherwise.
+    #[clap(long = "drop", value_parser, value_name = "REF")]
+    drop_ref: Option<Refname>,
+    /// The refname anchoring the seen objects tree
+    #[clap(
+        long = "seen",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_SEEN.parse().unwrap(),
+    )]
+    seen_ref: Refname,
+    /// The directory where bundles are stored
+    ///
+    /// Unless this is an absolute path, it is treated as relative to $GIT_DIR.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        default_value_os_t = paths::bundles().to_owned(),
+        value_hint = ValueHint::DirPath,
+    )]
+    bundle_dir: PathBuf,
+    /// Ref prefix under which the refs contained in patch bundles are stored
+    #[clap(
+        long,
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_BUNDLES.parse().unwrap(),
+    )]
+    unbundle_prefix: Refname,
+    /// Only list records with problems
+    #[clap(long, value_parser)]
+    problems_only: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    /// Whether no problems were found
+    ok: bool,
+    /// Number of records in the drop history
+    records: usize,
+    /// Number of records with problems
+    failed: usize,
+    /// Number of records whose bundle is not stored locally
+    bundles_missing: usize,
+    /// Problems with the seen objects tree not pertaining to any record
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    seen: Vec<String>,
+    /// The records, oldest first
+    entries: Vec<Entry>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Entry {
+    /// The commit on the drop history
+    #[serde(with = "git::serde::oid")]
+    commit: git2::Oid,
+    /// Absent if the record could not be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heads: Option<Heads>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<Topic>,
+    bundle: BundleStatus,
+    /// Whether all refs of the record are present below the unbundle prefix
+    unbundled: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BundleStatus {
+    /// The stored bundle matches the record
+    Verified,
+    /// The bundle is not stored locally, eg. because it was pruned
+    Missing,
+    /// The stored bundle does not match the record
+    Invalid,
+    /// The record could not be read
+    Unknown,
+}
+
+/// Verify the entire drop history
+///
+/// For every record, this checks that:
+///
+/// * the drop commit is signed by a member of the drop roles, as of that commit
+/// * the record is signed by its submitter, whose identity is known to the drop
+///   as of that commit
+/// * the stored bundle, if any, hashes to the recorded bundle info
+/// * it has an entry in the seen objects tree
+/// * its unbundled refs, if any, point to the recorded objects
+///
+/// The current drop metadata must verify, otherwise no records are checked.
+pub fn verify(args: Verify) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let drop_ref = match args.drop_ref {
+        Some(r) => r,
+        None if repo.is_bare() => REF_HEADS_PATCHES.parse()?,
+        None => REF_IT_PATCHES.parse()?,
+    };
+    let bundle_dir = if args.bundle_dir.is_relative() {
+        repo.path().join(args.bundle_dir)
+    } else {
+        args.bundle_dir
+    };
+
+    DropHead::from_refname(&repo, &drop_ref)?;
+    let seen_tree = match if_not_found_none(repo.find_reference(&args.seen_ref))? {
+        Some(r) => r.peel_to_tree()?,
+        None => git::empty_tree(&repo)?,
+    };
+
+    let mut walk = repo.revwalk()?;
+    walk.push_ref(&drop_ref)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+    let mut signers = HashMap::new();
+    let mut recorded = BTreeSet::new();
+    let mut entries = Vec::new();
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        // Edits of the drop metadata are not records
+        let topic = match Topic::from_commit(&commit)? {
+            Some(topic) => topic,
+            None => continue,
+        };
+        debug!("verifying record {}", commit.id());
+
+        let mut problems = Vec::new();
+        if let Err(e) = verify_committer(&repo, &commit, &mut signers) {
+            problems.push(format!("drop commit: {e:#}"));
+        }
+        let record = match Record::from_commit(&repo, &commit) {
+            Ok(record) => record,
+            Err(e) => {
+                problems.push(format!("unreadable record: {e:#}"));
+                entries.push(Entry {
+                    commit: commit.id(),
+                    heads: None,
+                    topic: Some(topic),
+                    bundle: BundleStatus::Unknown,
+                    unbundled: false,
+                    problems,
+                });
+                continue;
+            },
+        };
+        recorded.insert(record.heads.to_string());
+
+        if let Err(e) = verify_signature(&repo, &commit, &record) {
+            problems.push(format!("record signature: {e:#}"));
+        }
+
+        let bundle = if !bundle::chunks::exists(&record.bundle_path(&bundle_dir)) {
+            BundleStatus::Missing
+        } else {
+            match verify_bundle(&bundle_dir, &record) {
+                Ok(()) => BundleStatus::Verified,
+                Err(e) => {
+                    problems.push(format!("bundle {}: {e:#}", record.bundle_hash()));
+                    BundleStatus::Invalid
+                },
+            }
+        };
+
+        if !record.heads.in_tree(&seen_tree)? {
+            problems.push("missing from the seen objects tree".to_owned());
+        }
+
+        let mut unbundled = true;
+        for (name, oid) in &record.bundle_info().references {
+            let refname = unbundled_ref(&args.unbundle_prefix, &record, name)?;
+            match if_not_found_none(repo.refname_to_id(&refname))? {
+                Some(have) if have != git2::Oid::try_from(oid)? => {
+                    problems.push(format!("{refname} points to {have}, expected {oid}"))
+                },
+                Some(_) => {},
+                None => unbundled = false,
+            }
+        }
+
+        entries.push(Entry {
+            commit: commit.id(),
+            heads: Some(record.heads),
+            topic: Some(record.topic),
+            bundle,
+            unbundled,
+            problems,
+        });
+    }
+
+    let scan = fsck::scan(&repo, &seen_tree)?;
+    let mut seen = scan.malformed;
+    seen.extend(
+        scan.names
+            .difference(&recorded)
+            .map(|name| format!("{name}: not in the drop history")),
+    );
+
+    let records = entries.len();
+    let failed = entries.iter().filter(|e| !e.problems.is_empty()).count();
+    let bundles_missing = entries
+        .iter()
+        .filter(|e| matches!(e.bundle, BundleStatus::Missing))
+        .count();
+    if args.problems_only {
+        entries.retain(|e| !e.problems.is_empty());
+    }
+
+    Ok(Output {
+        ok: failed == 0 && seen.is_empty(),
+        records,
+        failed,
+        bundles_missing,
+        seen,
+        entries,
+    })
+}
+
+/// Verify that `commit` is signed by a member of the drop roles, as of that
+/// commit
+///
+/// The keys of the role members are memoised in `signers`, keyed by the drop
+/// metadata and identities they were determined from.
+fn verify_committer(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    signers: &mut HashMap<(git2::Oid, git2::Oid), BTreeSet<KeyId>>,
+) -> cmd::Result<()> {
+    let root = commit.tree()?;
+    let meta = root
+        .get_name(metadata::git::META_FILE_DROP)
+        .ok_or_else(|| anyhow!("no drop metadata"))?;
+    let ids = root
+        .get_name("ids")
+        .ok_or_else(|| anyhow!("no identities"))?;
+    let keys = match signers.entry((meta.id(), ids.id())) {
+        hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        hash_map::Entry::Vacant(entry) => {
+            let ids = ids.to_object(repo)?.peel_to_tree()?;
+            let drop = metadata::Drop::from_tree(repo, &root)?;
+            let mut keys = BTreeSet::new();
+            for id in drop.signed.signed.roles.ids() {
+                let id = identity::find_in_tree(repo, &ids, &id)?;
+                keys.extend(id.identity().keys.keys().cloned());
+                keys.extend(id.identity().bots.values().map(|bot| bot.key.id()));
+            }
+            entry.insert(keys)
+        },
+    };
+
+    let pk = git::verify_commit_signature(repo, &commit.id())?;
+    let keyid = VerificationKey::from(pk).keyid();
+    ensure!(
+        keys.contains(&keyid),
+        "signed by {keyid}, which is not a key of any member of the drop roles"
+    );
+
+    Ok(())
+}
+
+/// Verify the signature over `record`
+///
+/// The signing revision of the submitter's identity must be the revision known
+/// to the drop as of `commit`, or one of its ancestors. The signing key must
+/// not have been revoked by the known revision as of the time of `commit`.
+fn verify_signature(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    record: &Record,
+) -> cmd::Result<()> {
+    let ids = commit
+        .tree()?
+        .get_name("ids")
+        .ok_or_else(|| anyhow!("no identities"))?
+        .to_object(repo)?
+        .peel_to_tree()?;
+    let find_parent = find_parent(repo);
+    let mut latest = None;
+    let key = record.verify_signature(|hash| {
+        let GitMeta { hash, signed } = metadata::Identity::from_content_hash(repo, hash)?;
+        let signer = signed.verified(&find_parent)?;
+        let path = PathBuf::from(signer.id().to_string()).join(META_FILE_ID);
+        let known = match if_not_found_none(ids.get_path(&path))? {
+            Some(entry) => entry,
+            None => bail!("{} is not known to the drop", signer.id()),
+        };
+        if hash != known.id() {
+            let known = metadata::Identity::from_blob(&repo.find_blob(known.id())?)?
+                .signed
+                .verified(&find_parent)?;
+            ensure!(
+                known.identity().has_ancestor(&hash, &find_parent)?,
+                "{hash} is not a revision of {} known to the drop",
+                signer.id()
+            );
+            latest = Some(known);
+        }
+
+        Ok(signer)
+    })?;
+    if let Some(known) = latest {
+        let at = OffsetDateTime::from_unix_timestamp(commit.time().seconds())?.into();
+        if let Some(revocation) = known.revoked_at(&key, &at) {
+            bail!(
+                "signed by key {key}, revoked ({}) as of {}",
+                revocation.reason,
+                revocation.effective.format(&Rfc3339)?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Verify that the stored bundle of `record` matches the recorded bundle info
+fn verify_bundle(bundle_dir: &Path, record: &Record) -> cmd::Result<()> {
+    let info = record.bundle_info();
+    let bundle = Bundle::from_stored(bundle_dir, info.as_expect())?;
+    ensure!(
+        bundle.info().len == info.info.len,
+        "size is {}, expected {}",
+        bundle.info().len,
+        info.info.len
+    );
+    let header = bundle.header();
+    ensure!(
+        header.prerequisites == info.prerequisites && header.references == info.references,
+        "header does not match the recorded prerequisites and references"
+    );
+
+    Ok(())
+}
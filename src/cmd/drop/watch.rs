@@ -0,0 +1,250 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::VecDeque,
+    io::{
+        self,
+        Write as _,
+    },
+    path::PathBuf,
+    process::{
+        Command,
+        Stdio,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    cmd::{
+        self,
+        ui::{
+            debug,
+            warn,
+        },
+        util::args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+        EMPTY_TREE,
+    },
+    patches::{
+        iter::Subject,
+        notes,
+        record::Heads,
+        Record,
+        Topic,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Watch {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Seconds to wait between checks for new records
+    #[clap(long, value_parser, value_name = "SECS", default_value_t = 2)]
+    interval: u64,
+    /// Shell command to run for each event
+    ///
+    /// The event is passed as JSON on stdin, and its kind in the environment
+    /// variable IT_EVENT. The exit status is ignored.
+    #[clap(long, value_parser, value_name = "COMMAND")]
+    exec: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A record was added to the drop history
+    Record {
+        #[serde(with = "git::serde::oid")]
+        commit: git2::Oid,
+        record: Record,
+    },
+    /// A topic note was conveyed by a new record
+    Note {
+        topic: Topic,
+        patch: Heads,
+        #[serde(with = "git::serde::oid")]
+        id: git2::Oid,
+        author: Subject,
+        message: notes::Note,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Record { .. } => "record",
+            Self::Note { .. } => "note",
+        }
+    }
+}
+
+/// Watch the drop history, and emit an [`Event`] for each record and topic
+/// note as it lands
+///
+/// Only records added after the command was started are reported. The drop
+/// ref is polled, so this works the same for bare and non-bare drops. Notes
+/// are only reported if the record's bundle was unbundled by the time the
+/// record is seen.
+pub fn watch(args: Watch) -> cmd::Result<impl Iterator<Item = cmd::Result<Event>>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let last = if_not_found_none(repo.refname_to_id(&args.drop_ref))?;
+
+    Ok(Watcher {
+        repo,
+        drop_ref: args.drop_ref,
+        interval: Duration::from_secs(args.interval),
+        exec: args.exec,
+        last,
+        pending: VecDeque::new(),
+    })
+}
+
+struct Watcher {
+    repo: git2::Repository,
+    drop_ref: Refname,
+    interval: Duration,
+    exec: Option<String>,
+    last: Option<git2::Oid>,
+    pending: VecDeque<Event>,
+}
+
+impl Watcher {
+    fn poll(&mut self) -> cmd::Result<()> {
+        let tip = match if_not_found_none(self.repo.refname_to_id(&self.drop_ref))? {
+            Some(tip) if Some(tip) != self.last => tip,
+            _ => return Ok(()),
+        };
+        debug!("{} is now at {tip}", self.drop_ref);
+
+        let mut walk = self.repo.revwalk()?;
+        walk.push(tip)?;
+        if let Some(last) = self.last {
+            walk.hide(last)?;
+        }
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        for oid in walk {
+            let commit = self.repo.find_commit(oid?)?;
+            // Not every commit in the drop history is a record
+            if Topic::from_commit(&commit)?.is_none() {
+                continue;
+            }
+            let record = Record::from_commit(&self.repo, &commit)?;
+            let notes = self.notes(&record)?;
+            self.pending.push_back(Event::Record {
+                commit: commit.id(),
+                record,
+            });
+            self.pending.extend(notes);
+        }
+        self.last = Some(tip);
+
+        Ok(())
+    }
+
+    fn notes(&self, record: &Record) -> cmd::Result<Vec<Event>> {
+        let refs = &record.meta.bundle.references;
+        let tip = match refs.get(&record.topic.as_refname()) {
+            Some(tip) => git2::Oid::try_from(tip)?,
+            None => return Ok(Vec::new()),
+        };
+        if if_not_found_none(self.repo.find_commit(tip))?.is_none() {
+            debug!("{} not unbundled, skipping notes", record.heads);
+            return Ok(Vec::new());
+        }
+
+        let mut walk = self.repo.revwalk()?;
+        walk.push(tip)?;
+        for pre in &record.meta.bundle.prerequisites {
+            // Prerequisites may refer to objects we don't have, eg. if the
+            // drop was cloned shallowly
+            if let Err(e) = walk.hide(git2::Oid::try_from(pre)?) {
+                debug!("unable to hide prerequisite {pre}: {e}");
+            }
+        }
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+        let mut notes = Vec::new();
+        for oid in walk {
+            let commit = self.repo.find_commit(oid?)?;
+            // Merges of patches into the topic have an empty tree
+            if commit.tree_id() == *EMPTY_TREE {
+                continue;
+            }
+            notes.push(Event::Note {
+                topic: record.topic.clone(),
+                patch: record.heads,
+                id: commit.id(),
+                author: Subject::try_from(commit.author())?,
+                message: notes::Note::from_tree(&self.repo, &commit.tree()?)?,
+            });
+        }
+
+        Ok(notes)
+    }
+
+    fn exec(&self, event: &Event) -> cmd::Result<()> {
+        let cmd = match &self.exec {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .env("GIT_DIR", self.repo.path())
+            .env("IT_EVENT", event.kind())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| anyhow!("failed to run '{cmd}': {e}"))?;
+        {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            let mut json = serde_json::to_vec(event)?;
+            json.push(b'\n');
+            match stdin.write_all(&json) {
+                // The command may not care about its input
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+                x => x?,
+            }
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            warn!("'{cmd}' exited with {status}");
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = cmd::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(self.exec(&event).map(|()| event));
+            }
+            thread::sleep(self.interval);
+            if let Err(e) = self.poll() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
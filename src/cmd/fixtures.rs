@@ -0,0 +1,464 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Generator of deterministic drops, for tests, benchmarks and examples
+//!
+//! All randomness, including the signing keys of the identities, is derived
+//! from a seed, and commits are timestamped by a fake clock. Generating
+//! fixtures from the same seed and parameters thus yields the same objects,
+//! down to the drop tip.
+
+use core::{
+    iter,
+    num::NonZeroUsize,
+};
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use clap::ValueHint;
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+use super::{
+    id::Namespace,
+    patch::prepare,
+    ui::info,
+};
+use crate::{
+    bundle,
+    cfg,
+    cmd,
+    git::{
+        self,
+        Refname,
+    },
+    json,
+    keys::Signer as _,
+    metadata::{
+        self,
+        git::{
+            META_FILE_DROP,
+            META_FILE_ID,
+        },
+        IdentityId,
+        KeySet,
+        Metadata,
+    },
+    patches::{
+        self,
+        large_blobs,
+        Topic,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+    ssh,
+};
+
+/// Timestamp of the first commit of every fixture, 2020-09-13T12:26:40Z
+const EPOCH: i64 = 1_600_000_000;
+
+#[derive(Debug, clap::Args)]
+pub struct Fixtures {
+    /// Directory to create the fixtures in, which must not exist
+    #[clap(value_parser, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    dir: PathBuf,
+    /// Seed from which all content and keys are derived
+    #[clap(long, value_parser, value_name = "INT", default_value_t = 0)]
+    seed: u64,
+    /// Number of identities submitting patches
+    ///
+    /// The first identity also signs the drop metadata and history.
+    #[clap(long, value_parser, value_name = "INT", default_value_t = NonZeroUsize::new(3).unwrap())]
+    identities: NonZeroUsize,
+    /// Number of topics
+    #[clap(long, value_parser, value_name = "INT", default_value_t = 4)]
+    topics: usize,
+    /// Number of patches per topic
+    #[clap(long, value_parser, value_name = "INT", default_value_t = NonZeroUsize::new(2).unwrap())]
+    patches: NonZeroUsize,
+    /// Number of comments per topic
+    #[clap(long, value_parser, value_name = "INT", default_value_t = 2)]
+    comments: usize,
+    /// Number of topics to merge, each followed by a merge checkpoint
+    #[clap(long, value_parser, value_name = "INT", default_value_t = 1)]
+    checkpoints: usize,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    /// The drop repository
+    drop: PathBuf,
+    /// The repository holding the identities
+    ids: PathBuf,
+    /// Private keys of the identities, in the order they were created
+    keys: Vec<PathBuf>,
+    identities: Vec<IdentityId>,
+    topics: Vec<Topic>,
+    #[serde(with = "crate::git::serde::oid")]
+    tip: git2::Oid,
+}
+
+struct Ident {
+    id: IdentityId,
+    key: ssh::PrivateKey,
+    signed: metadata::Signed<metadata::Identity>,
+}
+
+pub fn fixtures(args: Fixtures) -> cmd::Result<Output> {
+    ensure!(!args.dir.exists(), "{} already exists", args.dir.display());
+    ensure!(
+        args.checkpoints <= args.topics,
+        "can not merge more topics than there are"
+    );
+    let mut rng = Rng::new(args.seed);
+    git::set_fixed_time(Some(EPOCH));
+    let res = generate(&args, &mut rng);
+    git::set_fixed_time(None);
+
+    res
+}
+
+fn generate(args: &Fixtures, rng: &mut Rng) -> cmd::Result<Output> {
+    let ids_dir = args.dir.join("ids");
+    let keys_dir = args.dir.join("keys");
+    let drop_dir = args.dir.join("drop");
+    std::fs::create_dir_all(&keys_dir)?;
+
+    let ids_repo = git::repo::open_or_init(
+        &ids_dir,
+        git::repo::InitOpts {
+            bare: true,
+            description: "`it` keyring",
+            initial_head: "refs/heads/main",
+        },
+    )?;
+    set_user(&ids_repo)?;
+    let mut idents = Vec::with_capacity(args.identities.get());
+    let mut keys = Vec::with_capacity(args.identities.get());
+    for i in 0..args.identities.get() {
+        let ident = identity(&ids_repo, rng)?;
+        let path = keys_dir.join(format!("id{i}"));
+        ident.key.write_openssh_file(&path, ssh::LineEnding::LF)?;
+        info!("Created identity {}", ident.id);
+        keys.push(path);
+        idents.push(ident);
+    }
+
+    let repo = git::repo::open_or_init(
+        &drop_dir,
+        git::repo::InitOpts {
+            bare: false,
+            description: "`it` drop",
+            initial_head: "refs/heads/main",
+        },
+    )?;
+    set_user(&repo)?;
+    git::add_alternates(&repo, iter::once(&ids_repo))?;
+    let main: Refname = "refs/heads/main".parse()?;
+    let base = {
+        let tree = write_tree(&repo, rng, None)?;
+        let oid = commit(&repo, &idents[0], "Initial commit", &tree, &[])?;
+        repo.reference(&main, oid, false, "it: fixtures")?;
+        oid
+    };
+    let tip = init_drop(&repo, &idents, &main)?;
+    info!("Created drop at {tip}");
+
+    let repo = prepare::Repo::new(repo, vec![ids_repo], None);
+    let mut drop_signer = idents[0].key.clone();
+    let mut record = |kind: prepare::Kind, submitter: &Ident, message: String| {
+        let drop = patches::DropHead::from_refname(repo.target(), REF_IT_PATCHES)?;
+        let merges = matches!(kind, prepare::Kind::Mergepoint { .. });
+        let mut signer = submitter.key.clone();
+        let mut options = patches::AcceptOptions::default();
+        if merges {
+            options.allow_fat_pack = true;
+            options.max_branches = drop.meta.roles.branches.len();
+            options.max_refs = options.max_branches + 1;
+            options.lint = Default::default();
+        }
+        prepare::Preparator::new(
+            &repo,
+            &drop,
+            prepare::Submitter {
+                signer: &mut signer,
+                id: submitter.id,
+                acknowledge_policy: false,
+            },
+            bundle::HashAlgorithm::default(),
+        )
+        .prepare_patch(
+            &repo.target().path().join(cfg::paths::bundles()),
+            kind,
+            Some(message),
+            &[],
+        )?
+        .try_accept(patches::AcceptArgs {
+            unbundle_prefix: REF_IT_BUNDLES,
+            drop_ref: REF_IT_PATCHES,
+            seen_ref: REF_IT_SEEN,
+            repo: repo.target(),
+            signer: &mut drop_signer,
+            ipfs_api: None,
+            drop_cache: None,
+            reachable_cache: None,
+            options,
+        })
+    };
+
+    record(
+        prepare::Kind::Mergepoint { force: true },
+        &idents[0],
+        "Initial merge point".to_owned(),
+    )?;
+
+    let mut base = base;
+    let mut topics = Vec::with_capacity(args.topics);
+    for t in 0..args.topics {
+        let submitter = &idents[t % idents.len()];
+        let mut topic: Option<Topic> = None;
+        let mut head = base;
+        for p in 0..args.patches.get() {
+            let parent = repo.target().find_commit(head)?;
+            let tree = write_tree(repo.target(), rng, Some(&parent.tree()?))?;
+            head = commit(
+                repo.target(),
+                submitter,
+                &format!("Change {} of topic {t}", p + 1),
+                &tree,
+                &[&parent],
+            )?;
+            let rec = record(
+                prepare::Kind::Patch {
+                    head,
+                    base,
+                    name: main.clone(),
+                    re: topic.clone().map(|topic| (topic, None)),
+                    large_blobs: large_blobs::Options::default(),
+                },
+                submitter,
+                format!("Topic {t}, revision {}\n\n{}", p + 1, rng.text(3)),
+            )?;
+            topic.get_or_insert(rec.topic);
+        }
+        let topic = topic.expect("at least one patch per topic");
+        for c in 0..args.comments {
+            let commenter = &idents[(t + c + 1) % idents.len()];
+            record(
+                prepare::Kind::Comment {
+                    topic: topic.clone(),
+                    reply: None,
+                    draft: None,
+                    note: None,
+                },
+                commenter,
+                rng.text(2),
+            )?;
+        }
+        info!("Created topic {topic}");
+
+        if t < args.checkpoints {
+            repo.target()
+                .reference(&main, head, true, "it: fixtures merge")?;
+            record(
+                prepare::Kind::Mergepoint { force: true },
+                &idents[0],
+                format!("Merge topic {t}"),
+            )?;
+            base = head;
+        }
+        topics.push(topic);
+    }
+
+    let tip = repo.target().refname_to_id(REF_IT_PATCHES)?;
+    Ok(Output {
+        drop: repo.target().path().to_owned(),
+        ids: ids_dir,
+        keys,
+        identities: idents.iter().map(|ident| ident.id).collect(),
+        topics,
+        tip,
+    })
+}
+
+/// Create an identity with a single key derived from `rng`
+fn identity(repo: &git2::Repository, rng: &mut Rng) -> cmd::Result<Ident> {
+    let mut key = ssh::PrivateKey::from(ssh::private::Ed25519Keypair::from_seed(&rng.next()));
+    let keys = iter::once(metadata::Key::from(key.ident().to_owned())).collect::<KeySet>();
+    let roles = metadata::identity::Roles::root(
+        keys.keys().cloned().collect(),
+        NonZeroUsize::new(1).unwrap(),
+    );
+    let meta = metadata::Identity {
+        fmt_version: Default::default(),
+        prev: None,
+        keys,
+        roles,
+        mirrors: Default::default(),
+        expires: None,
+        custom: Default::default(),
+        bots: Default::default(),
+        revoked: Default::default(),
+    };
+    let id = metadata::IdentityId::try_from(&meta).unwrap();
+    let signed = Metadata::identity(meta).sign(iter::once(&mut key))?;
+
+    let tree = {
+        let mut tb = repo.treebuilder(None)?;
+        tb.insert(
+            META_FILE_ID,
+            json::to_blob(repo, &signed)?,
+            git2::FileMode::Blob.into(),
+        )?;
+        repo.find_tree(tb.write()?)?
+    };
+    let oid = git::commit_signed(&mut key, repo, format!("Create identity {id}"), &tree, &[])?;
+    repo.reference(&Namespace::Own.refname(&id), oid, false, "it: fixtures")?;
+
+    let signed = signed.fmap(|meta| match meta {
+        Metadata::Identity(inner) => inner.into_owned(),
+        _ => unreachable!("signed identity metadata"),
+    });
+
+    Ok(Ident { id, key, signed })
+}
+
+/// Create the drop, with the first of `idents` holding all roles
+fn init_drop(
+    repo: &git2::Repository,
+    idents: &[Ident],
+    branch: &Refname,
+) -> cmd::Result<git2::Oid> {
+    let role = metadata::drop::Role {
+        ids: [idents[0].id].into(),
+        threshold: NonZeroUsize::new(1).unwrap(),
+    };
+    let meta = metadata::Drop {
+        fmt_version: Default::default(),
+        description: metadata::drop::Description::try_from("fixtures".to_owned())?,
+        prev: None,
+        id_path: Default::default(),
+        ref_policy: Default::default(),
+        commit_limits: Default::default(),
+        custom: Default::default(),
+        roles: metadata::drop::Roles {
+            root: role.clone(),
+            snapshot: role.clone(),
+            mirrors: role.clone(),
+            branches: [(
+                branch.clone(),
+                metadata::drop::Annotated {
+                    role,
+                    description: metadata::drop::Description::try_from("main".to_owned())?,
+                },
+            )]
+            .into(),
+        },
+    };
+    let mut signer = idents[0].key.clone();
+    let signed = Metadata::drop(&meta).sign(iter::once(&mut signer))?;
+
+    let mut root = repo.treebuilder(None)?;
+    let mut ids = repo.treebuilder(None)?;
+    let mut tb = repo.treebuilder(None)?;
+    metadata::identity::fold_to_tree(repo, &mut tb, idents[0].signed.clone())?;
+    ids.insert(
+        idents[0].id.to_string(),
+        tb.write()?,
+        git2::FileMode::Tree.into(),
+    )?;
+    root.insert("ids", ids.write()?, git2::FileMode::Tree.into())?;
+    root.insert(
+        META_FILE_DROP,
+        json::to_blob(repo, &signed)?,
+        git2::FileMode::Blob.into(),
+    )?;
+    let tree = repo.find_tree(root.write()?)?;
+    let commit = git::commit_signed(&mut signer, repo, "Create drop 'fixtures'", &tree, &[])?;
+    repo.reference(REF_IT_PATCHES, commit, false, "it: create")?;
+
+    Ok(commit)
+}
+
+/// Write a tree with a file of random content added to `base`
+fn write_tree<'a>(
+    repo: &'a git2::Repository,
+    rng: &mut Rng,
+    base: Option<&git2::Tree>,
+) -> cmd::Result<git2::Tree<'a>> {
+    let mut tb = repo.treebuilder(base)?;
+    let name = format!("{}.txt", hex::encode(&rng.next()[..4]));
+    tb.insert(
+        name,
+        repo.blob(rng.text(4).as_bytes())?,
+        git2::FileMode::Blob.into(),
+    )?;
+
+    Ok(repo.find_tree(tb.write()?)?)
+}
+
+/// Commit `tree` as authored by `author`, using the fake clock
+fn commit(
+    repo: &git2::Repository,
+    author: &Ident,
+    msg: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+) -> cmd::Result<git2::Oid> {
+    let sig = git2::Signature::new(
+        &format!("Identity {}", &author.id.to_string()[..8]),
+        &format!("{}@fixtures.invalid", author.id),
+        &git2::Time::new(git::next_fixed_time().unwrap_or(EPOCH), 0),
+    )?;
+
+    Ok(repo.commit(None, &sig, &sig, msg, tree, parents)?)
+}
+
+/// Use a fixed committer, so the global git config does not leak into the
+/// fixtures
+fn set_user(repo: &git2::Repository) -> cmd::Result<()> {
+    let mut cfg = repo.config()?;
+    cfg.set_str("user.name", "it fixtures")?;
+    cfg.set_str("user.email", "fixtures@it.invalid")?;
+
+    Ok(())
+}
+
+/// Deterministic source of bytes, by hashing the seed with a counter
+struct Rng {
+    seed: u64,
+    counter: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self { seed, counter: 0 }
+    }
+
+    fn next(&mut self) -> [u8; 32] {
+        self.counter += 1;
+        Sha256::new()
+            .chain_update(b"it fixtures")
+            .chain_update(self.seed.to_be_bytes())
+            .chain_update(self.counter.to_be_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// Some lines of text
+    fn text(&mut self, lines: usize) -> String {
+        (0..lines)
+            .map(|_| {
+                self.next()
+                    .chunks(4)
+                    .map(hex::encode)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
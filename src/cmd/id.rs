@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
-    collections::BTreeSet,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
     path::PathBuf,
 };
 
@@ -11,11 +14,6 @@ use anyhow::{
     ensure,
 };
 use clap::ValueHint;
-use either::{
-    Either,
-    Left,
-    Right,
-};
 use url::Url;
 
 use crate::{
@@ -27,12 +25,18 @@ use crate::{
     git,
     metadata::{
         self,
-        git::META_FILE_ID,
+        git::{
+            FromGit as _,
+            FromSearchPath,
+            META_FILE_ID,
+        },
         IdentityId,
     },
     paths,
 };
 
+pub mod bot;
+
 mod edit;
 pub use edit::{
     edit,
@@ -45,6 +49,34 @@ pub use init::{
     Init,
 };
 
+mod ls;
+pub use ls::{
+    ls,
+    Ls,
+};
+
+mod mv;
+pub use mv::{
+    mv,
+    Mv,
+};
+
+pub mod namespace;
+pub use namespace::Namespace;
+
+mod profile;
+pub use profile::{
+    profile,
+    resolve as resolve_profile,
+    Profile,
+};
+
+mod revoke_key;
+pub use revoke_key::{
+    revoke_key,
+    RevokeKey,
+};
+
 mod show;
 pub use show::{
     show,
@@ -68,6 +100,21 @@ pub enum Cmd {
     Edit(Edit),
     /// Sign a proposed identity document
     Sign(Sign),
+    /// Set the profile data of the identity, such as display name and avatar
+    Profile(Profile),
+    /// Manage keys for automated use
+    #[clap(subcommand)]
+    Bot(bot::Cmd),
+    /// Revoke a key of the identity
+    ///
+    /// The key is removed from the identity, and a tombstone recorded in its
+    /// place. Signatures made by the key from the effective date/time on are
+    /// rejected, and the key can not be added back.
+    RevokeKey(RevokeKey),
+    /// List the identities in the keyring, by namespace
+    Ls(Ls),
+    /// Move an identity to a different namespace of the keyring
+    Mv(Mv),
 }
 
 impl Cmd {
@@ -77,6 +124,11 @@ impl Cmd {
             Self::Show(args) => show(args).map(cmd::IntoOutput::into_output),
             Self::Edit(args) => edit(args).map(cmd::IntoOutput::into_output),
             Self::Sign(args) => sign(args).map(cmd::IntoOutput::into_output),
+            Self::Profile(args) => profile(args).map(cmd::IntoOutput::into_output),
+            Self::Bot(cmd) => cmd.run(),
+            Self::RevokeKey(args) => revoke_key(args).map(cmd::IntoOutput::into_output),
+            Self::Ls(args) => ls(args).map(cmd::IntoOutput::into_output),
+            Self::Mv(args) => mv(args).map(cmd::IntoOutput::into_output),
         }
     }
 }
@@ -104,30 +156,46 @@ pub struct Common {
 }
 
 impl Common {
+    /// Open the keyring and find the branch of the identity
+    ///
+    /// If the identity is not present in the keyring, the branch it would be
+    /// created at in the `own` namespace is returned.
     pub fn resolve(&self) -> cmd::Result<(git2::Repository, Refname)> {
         let repo = git::repo::open(&self.git_dir)?;
-        let refname = identity_ref(
-            match self.id {
-                Some(id) => Left(id),
-                None => Right(repo.config()?),
-            }
-            .as_ref(),
-        )?;
+        let id = self.id(&repo)?;
+        let refname = match namespace::find(&repo, &id)? {
+            Some(r) => Refname::try_from(
+                r.name()
+                    .ok_or_else(|| anyhow!("non-utf8 ref name for {id}"))?
+                    .to_owned(),
+            )?,
+            None => Namespace::Own.refname(&id),
+        };
 
         Ok((repo, refname))
     }
+
+    fn id(&self, repo: &git2::Repository) -> cmd::Result<IdentityId> {
+        match self.id {
+            Some(id) => Ok(id),
+            None => cfg::git::identity(&cfg::git::open(repo)?)?
+                .ok_or_else(|| anyhow!("'{}' not set", cfg::git::IT_ID)),
+        }
+    }
 }
 
-pub fn identity_ref(id: Either<&IdentityId, &git2::Config>) -> cmd::Result<Refname> {
-    let id = id.either(
-        |iid| Ok(iid.to_string()),
-        |cfg| {
-            cfg::git::identity(cfg)?
-                .ok_or_else(|| anyhow!("'{}' not set", cfg::git::IT_ID))
-                .map(|iid| iid.to_string())
-        },
-    )?;
-    Ok(Refname::try_from(format!("refs/heads/it/ids/{id}"))?)
+/// Load identity `id` from the search path, preferring the `own` namespace
+///
+/// cf. [`namespace::find_in_path`]
+pub fn from_search_path<'a>(
+    search_path: &'a [git2::Repository],
+    id: &IdentityId,
+) -> cmd::Result<FromSearchPath<'a, metadata::Identity>> {
+    let (repo, reference) = namespace::find_in_path(search_path, id)?
+        .ok_or_else(|| anyhow!("identity {id} not found in search path"))?;
+    let meta = metadata::Identity::from_reference(repo, &reference)?;
+
+    Ok(FromSearchPath { repo, meta })
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -138,6 +206,10 @@ struct Editable {
     mirrors: BTreeSet<Url>,
     expires: Option<metadata::DateTime>,
     custom: metadata::Custom,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    bots: BTreeMap<metadata::KeyId, metadata::identity::BotKey>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    revoked: BTreeMap<metadata::KeyId, metadata::identity::Revocation>,
 }
 
 impl From<metadata::Identity> for Editable {
@@ -148,6 +220,8 @@ impl From<metadata::Identity> for Editable {
             mirrors,
             expires,
             custom,
+            bots,
+            revoked,
             ..
         }: metadata::Identity,
     ) -> Self {
@@ -157,6 +231,8 @@ impl From<metadata::Identity> for Editable {
             mirrors,
             expires,
             custom,
+            bots,
+            revoked,
         }
     }
 }
@@ -171,9 +247,31 @@ impl TryFrom<Editable> for metadata::Identity {
             mirrors,
             expires,
             custom,
+            bots,
+            revoked,
         }: Editable,
     ) -> Result<Self, Self::Error> {
         ensure!(!keys.is_empty(), "keys cannot be empty");
+        ensure!(
+            bots.iter().all(|(id, bot)| id == &bot.key.id()),
+            "bot key ids must match their keys"
+        );
+        ensure!(
+            bots.keys().all(|id| !keys.contains_key(id)),
+            "bot keys must not be regular keys"
+        );
+        ensure!(
+            revoked
+                .iter()
+                .all(|(id, revocation)| id == &revocation.key.id()),
+            "revoked key ids must match their keys"
+        );
+        ensure!(
+            revoked
+                .keys()
+                .all(|id| !keys.contains_key(id) && !bots.contains_key(id)),
+            "revoked keys must not be regular or bot keys"
+        );
         ensure!(
             !roles.is_threshold(),
             "flat threshold is deprecated, please specify the root keys explicity"
@@ -187,6 +285,8 @@ impl TryFrom<Editable> for metadata::Identity {
             mirrors,
             expires,
             custom,
+            bots,
+            revoked,
         })
     }
 }
@@ -0,0 +1,129 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use anyhow::ensure;
+
+use super::{
+    edit::{
+        self,
+        Update,
+    },
+    Common,
+};
+use crate::{
+    cmd::{
+        self,
+        args::Refname,
+        FromGit as _,
+        GitIdentity,
+    },
+    metadata::{
+        self,
+        identity::{
+            BotKey,
+            BotScope,
+        },
+        DateTime,
+        IdentityId,
+        Key,
+    },
+};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Cmd {
+    /// Add a key for automated use to the identity
+    Add(Add),
+}
+
+impl Cmd {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Add(args) => add(args),
+        }
+        .map(cmd::IntoOutput::into_output)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Add {
+    #[clap(flatten)]
+    common: Common,
+    /// The public key of the bot, in OpenSSH format
+    ///
+    /// The bot should generate its own key pair, eg. using `ssh-keygen -t
+    /// ed25519`, so the secret key never needs to leave the bot's host.
+    #[clap(long, value_parser, value_name = "KEY")]
+    key: Key<'static>,
+    /// Human-readable name of the bot
+    #[clap(long, value_parser)]
+    name: Option<String>,
+    /// Only allow the bot to comment on topics
+    #[clap(long, value_parser)]
+    comment_only: bool,
+    /// Only allow the bot to submit to drops with this identity in their root
+    /// role; may be given multiple times
+    #[clap(long = "drop", value_parser, value_name = "ID")]
+    drops: Vec<IdentityId>,
+    /// Date/time after which the key should no longer be considered valid
+    #[clap(long, value_parser, value_name = "DATETIME")]
+    expires: Option<DateTime>,
+    /// Commit to this branch to propose the update
+    ///
+    /// If not given, the update is performed in-place if the signature
+    /// threshold is met using the supplied keys.
+    #[clap(long, value_parser)]
+    propose_as: Option<Refname>,
+    /// Commit message for this edit
+    ///
+    /// Like git, $EDITOR will be invoked if not specified.
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+}
+
+pub fn add(args: Add) -> cmd::Result<edit::Output> {
+    let (repo, refname) = args.common.resolve()?;
+    let GitIdentity {
+        hash: parent_hash,
+        signed: metadata::Signed { signed: parent, .. },
+    } = metadata::Identity::from_tip(&repo, &refname)?;
+
+    let keyid = args.key.id();
+    ensure!(
+        !parent.keys.contains_key(&keyid),
+        "{keyid} is a regular key of the identity"
+    );
+    ensure!(
+        !parent.bots.contains_key(&keyid),
+        "{keyid} is already a bot key of the identity"
+    );
+    if let Some(expires) = &args.expires {
+        ensure!(expires > &DateTime::now(), "expiry must be in the future");
+    }
+
+    let mut id = parent.clone();
+    id.bots.insert(
+        keyid,
+        BotKey {
+            key: args.key,
+            name: args.name,
+            scope: BotScope {
+                comment_only: args.comment_only,
+                drops: args.drops.into_iter().collect(),
+            },
+            expires: args.expires,
+        },
+    );
+
+    edit::update(
+        &repo,
+        &refname,
+        parent_hash,
+        &parent,
+        id,
+        Update {
+            propose_as: args.propose_as.as_ref(),
+            checkout: false,
+            message: args.message,
+        },
+    )
+}
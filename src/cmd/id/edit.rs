@@ -88,14 +88,52 @@ pub fn edit(args: Edit) -> cmd::Result<Output> {
         signed: metadata::Signed { signed: parent, .. },
     } = metadata::Identity::from_tip(&repo, &refname)?;
 
-    let mut id: metadata::Identity = edit_metadata(Editable::from(parent.clone()))?.try_into()?;
+    let id: metadata::Identity = edit_metadata(Editable::from(parent.clone()))?.try_into()?;
     if id.canonicalise()? == parent.canonicalise()? {
         info!("Document unchanged");
         cmd::abort!();
     }
+
+    update(
+        &repo,
+        &refname,
+        parent_hash,
+        &parent,
+        id,
+        Update {
+            propose_as: args.propose_as.as_ref(),
+            checkout: args.checkout,
+            message: args.message,
+        },
+    )
+}
+
+pub(super) struct Update<'a> {
+    /// Commit to this branch instead of the identity branch
+    pub propose_as: Option<&'a Refname>,
+    /// Check out the committed changes
+    pub checkout: bool,
+    /// Commit message, $EDITOR is invoked if `None`
+    pub message: Option<String>,
+}
+
+/// Sign and commit `id` as the successor of `parent`
+pub(super) fn update(
+    repo: &git2::Repository,
+    refname: &Refname,
+    parent_hash: metadata::ContentHash,
+    parent: &metadata::Identity,
+    mut id: metadata::Identity,
+    args: Update,
+) -> cmd::Result<Output> {
     id.prev = Some(parent_hash.clone());
+    for key in id.keys.values().chain(id.bots.values().map(|bot| &bot.key)) {
+        if !parent.keys.contains_key(&key.id()) && !parent.bots.contains_key(&key.id()) {
+            key.ensure_supported()?;
+        }
+    }
 
-    let cfg = repo.config()?;
+    let cfg = cfg::git::open(&repo)?;
     let mut signer = cfg::signer(&cfg, ui::askpass)?;
     let keyid = metadata::KeyId::from(signer.ident());
     ensure!(
@@ -104,9 +142,9 @@ pub fn edit(args: Edit) -> cmd::Result<Output> {
     );
     let signed = Metadata::identity(&id).sign(iter::once(&mut signer))?;
 
-    let commit_to = match id.verify(&signed.signatures, cmd::find_parent(&repo)) {
-        Ok(_) => args.propose_as.as_ref().unwrap_or(&refname),
-        Err(metadata::error::Verification::SignatureThreshold) => match &args.propose_as {
+    let commit_to = match id.verify(&signed.signatures, cmd::find_parent(repo)) {
+        Ok(_) => args.propose_as.unwrap_or(refname),
+        Err(metadata::error::Verification::SignatureThreshold) => match args.propose_as {
             None => bail!("cannot update {refname} in place as signature threshold is not met"),
             Some(tgt) => {
                 warn!("Signature threshold is not met");
@@ -116,7 +154,7 @@ pub fn edit(args: Edit) -> cmd::Result<Output> {
         Err(e) => bail!(e),
     };
 
-    let mut tx = refs::Transaction::new(&repo)?;
+    let mut tx = refs::Transaction::new(repo)?;
 
     let _tip = tx.lock_ref(refname.clone())?;
     let tip = repo.find_reference(_tip.name())?;
@@ -128,7 +166,7 @@ pub fn edit(args: Edit) -> cmd::Result<Output> {
             anyhow!("{refname} was modified concurrently, {META_FILE_ID} not found in tree")
         })?;
         ensure!(
-            parent_hash == entry.to_object(&repo)?.peel_to_blob()?.id(),
+            parent_hash == entry.to_object(repo)?.peel_to_blob()?.id(),
             "{refname} was modified concurrently",
         );
     }
@@ -137,15 +175,15 @@ pub fn edit(args: Edit) -> cmd::Result<Output> {
         !repo.is_bare() && git2::Branch::wrap(repo.find_reference(commit_to.name())?).is_head();
 
     let tree = if on_head {
-        write_tree(&repo, &signed)
+        write_tree(repo, &signed)
     } else {
-        write_tree_bare(&repo, &signed, Some(&parent_tree))
+        write_tree_bare(repo, &signed, Some(&parent_tree))
     }?;
     let msg = args
         .message
         .map(Ok)
-        .unwrap_or_else(|| edit_commit_message(&repo, commit_to.name(), &parent_tree, &tree))?;
-    let commit = git::commit_signed(&mut signer, &repo, msg, &tree, &[&parent_commit])?;
+        .unwrap_or_else(|| edit_commit_message(repo, commit_to.name(), &parent_tree, &tree))?;
+    let commit = git::commit_signed(&mut signer, repo, msg, &tree, &[&parent_commit])?;
     commit_to.set_target(commit, "it: edit identity");
 
     tx.commit()?;
@@ -13,6 +13,7 @@ use url::Url;
 
 use super::{
     Editable,
+    Namespace,
     META_FILE_ID,
 };
 use crate::{
@@ -125,7 +126,7 @@ pub fn init(args: Init) -> cmd::Result<Output> {
     info!("Initialising fresh identity at {}", git_dir.display());
 
     let custom = args.custom.map(json::load).transpose()?.unwrap_or_default();
-    let cfg = git2::Config::open_default()?;
+    let cfg = cfg::git::open_default()?;
     let mut signer = cfg::signer(&cfg, ui::askpass)?;
     let threshold = match args.threshold {
         None => NonZeroUsize::new(1)
@@ -141,6 +142,7 @@ pub fn init(args: Init) -> cmd::Result<Output> {
     };
 
     let signer_id = signer.ident().to_owned();
+    signer_id.ensure_supported()?;
     let keys = iter::once(signer_id.clone())
         .map(metadata::Key::from)
         .chain(args.public)
@@ -156,6 +158,8 @@ pub fn init(args: Init) -> cmd::Result<Output> {
             mirrors: args.mirrors.into_iter().collect(),
             expires: args.expires,
             custom,
+            bots: Default::default(),
+            revoked: Default::default(),
         };
 
         if args.edit {
@@ -168,7 +172,7 @@ pub fn init(args: Init) -> cmd::Result<Output> {
     let signed = metadata::Metadata::identity(meta).sign(iter::once(&mut signer))?;
 
     let out = if !args.dry_run {
-        let id_ref = Refname::try_from(format!("refs/heads/it/ids/{}", sigid)).unwrap();
+        let id_ref = Namespace::Own.refname(&sigid);
         let repo = git::repo::open_or_init(
             git_dir,
             git::repo::InitOpts {
@@ -0,0 +1,73 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::path::PathBuf;
+
+use clap::ValueHint;
+
+use super::{
+    namespace::REF_PREFIX,
+    Namespace,
+};
+use crate::{
+    cmd::{
+        self,
+        args::Refname,
+    },
+    git,
+    metadata::IdentityId,
+    paths,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Ls {
+    /// Path to the 'keyring' repository
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        env = "GIT_DIR",
+        default_value_os_t = paths::ids(),
+        value_hint = ValueHint::DirPath,
+    )]
+    git_dir: PathBuf,
+    /// Only list identities in this namespace
+    ///
+    /// One of 'own', 'contacts', or 'drops/<name>'.
+    #[clap(long, value_parser, value_name = "NAMESPACE")]
+    namespace: Option<Namespace>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    id: IdentityId,
+    #[serde(with = "crate::serde::display")]
+    namespace: Namespace,
+    #[serde(rename = "ref")]
+    refname: Refname,
+}
+
+pub fn ls(args: Ls) -> cmd::Result<Vec<Output>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let mut out = Vec::new();
+    for r in repo.references_glob(&format!("{REF_PREFIX}/*"))? {
+        let name = match r?.name() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let (namespace, id) = match Namespace::from_refname(&name) {
+            Some(x) => x,
+            None => continue,
+        };
+        if args.namespace.as_ref().map_or(true, |ns| ns == &namespace) {
+            out.push(Output {
+                id,
+                namespace,
+                refname: name.try_into()?,
+            });
+        }
+    }
+    out.sort_by(|a, b| (&a.namespace, &a.id).cmp(&(&b.namespace, &b.id)));
+
+    Ok(out)
+}
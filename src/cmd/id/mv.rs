@@ -0,0 +1,74 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+
+use super::{
+    Common,
+    Namespace,
+};
+use crate::{
+    cmd::{
+        self,
+        args::Refname,
+        ui::info,
+        Aborted,
+    },
+    git::{
+        if_not_found_none,
+        refs,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Mv {
+    #[clap(flatten)]
+    common: Common,
+    /// The namespace to move the identity to
+    ///
+    /// One of 'own', 'contacts', or 'drops/<name>'.
+    #[clap(value_parser, value_name = "NAMESPACE")]
+    to: Namespace,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    from: Refname,
+    to: Refname,
+    #[serde(with = "crate::git::serde::oid")]
+    commit: git2::Oid,
+}
+
+pub fn mv(args: Mv) -> cmd::Result<Output> {
+    let (repo, from) = args.common.resolve()?;
+    let commit = if_not_found_none(repo.refname_to_id(&from))?
+        .ok_or_else(|| anyhow!("identity not found in keyring"))?;
+    let id = Namespace::from_refname(&from)
+        .map(|(_, id)| id)
+        .ok_or_else(|| anyhow!("{from} is not an identity branch"))?;
+    let to = args.to.refname(&id);
+    if to == from {
+        info!("{id} is already in namespace {}", args.to);
+        cmd::abort!();
+    }
+
+    let mut tx = refs::Transaction::new(&repo)?;
+    let from_ref = tx.lock_ref(from.clone())?;
+    let to_ref = tx.lock_ref(to.clone())?;
+    ensure!(
+        if_not_found_none(repo.refname_to_id(to_ref.name()))?.is_none(),
+        "{to} already exists"
+    );
+    to_ref.set_target(commit, format!("it: move from {from}"));
+    from_ref.remove();
+    tx.commit()?;
+    // The keyring's HEAD is initially the first identity created in it
+    if repo.find_reference("HEAD")?.symbolic_target() == Some(&from) {
+        repo.set_head(&to)?;
+    }
+
+    Ok(Output { from, to, commit })
+}
@@ -0,0 +1,181 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Namespaces of identities in the keyring repository
+//!
+//! Identities are stored as branches below [`REF_PREFIX`], in one of the
+//! namespaces:
+//!
+//! - `own/`: the user's own identities
+//! - `contacts/`: identities of others, imported manually
+//! - `drops/<name>/`: identities imported from the drop `<name>`
+//!
+//! Identities stored directly below [`REF_PREFIX`], as created by earlier
+//! versions, are considered to be in the `own` namespace.
+
+use core::{
+    fmt,
+    str::FromStr,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+
+use crate::{
+    cmd,
+    git::{
+        if_not_found_none,
+        Refname,
+    },
+    metadata::IdentityId,
+};
+
+pub const REF_PREFIX: &str = "refs/heads/it/ids";
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Namespace {
+    Own,
+    Contacts,
+    Drop(String),
+}
+
+impl Namespace {
+    pub fn refname(&self, id: &IdentityId) -> Refname {
+        Refname::try_from(format!("{REF_PREFIX}/{self}/{id}")).unwrap()
+    }
+
+    /// Determine the namespace and identity id from a reference name
+    ///
+    /// Returns `None` if `name` does not denote an identity.
+    pub fn from_refname(name: &str) -> Option<(Self, IdentityId)> {
+        let path = name.strip_prefix(REF_PREFIX)?.strip_prefix('/')?;
+        let (ns, id) = match path.rsplit_once('/') {
+            None => (Self::Own, path),
+            Some((ns, id)) => (ns.parse().ok()?, id),
+        };
+
+        Some((ns, id.parse().ok()?))
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Own => f.write_str("own"),
+            Self::Contacts => f.write_str("contacts"),
+            Self::Drop(name) => write!(f, "drops/{name}"),
+        }
+    }
+}
+
+impl FromStr for Namespace {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "own" => Ok(Self::Own),
+            "contacts" => Ok(Self::Contacts),
+            _ => {
+                let name = s.strip_prefix("drops/").ok_or_else(|| {
+                    anyhow!("invalid namespace '{s}', expected one of own, contacts, drops/<name>")
+                })?;
+                ensure!(
+                    !name.is_empty()
+                        && !name.contains('/')
+                        && git2::Reference::is_valid_name(&format!("{REF_PREFIX}/drops/{name}")),
+                    "invalid drop name '{name}'"
+                );
+                Ok(Self::Drop(name.to_owned()))
+            },
+        }
+    }
+}
+
+/// Find the branch holding `id` in `repo`
+///
+/// If the identity is present in multiple namespaces, the `own` namespace is
+/// preferred over `contacts`, which is preferred over any of `drops`.
+pub fn find<'a>(
+    repo: &'a git2::Repository,
+    id: &IdentityId,
+) -> cmd::Result<Option<git2::Reference<'a>>> {
+    for tier in Tier::ALL {
+        if let Some(r) = tier.find(repo, id)? {
+            return Ok(Some(r));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Find the branch holding `id` in the first repository of `search_path` which
+/// has it
+///
+/// Namespaces take precedence over the order of the search path, ie. an
+/// identity in the `own` namespace of a repository late in the search path is
+/// preferred over the same identity in the `contacts` namespace of one earlier
+/// in the search path.
+pub fn find_in_path<'a>(
+    search_path: &'a [git2::Repository],
+    id: &IdentityId,
+) -> cmd::Result<Option<(&'a git2::Repository, git2::Reference<'a>)>> {
+    for tier in Tier::ALL {
+        for repo in search_path {
+            if let Some(r) = tier.find(repo, id)? {
+                return Ok(Some((repo, r)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Clone, Copy)]
+enum Tier {
+    Own,
+    Contacts,
+    Drops,
+}
+
+impl Tier {
+    const ALL: [Self; 3] = [Self::Own, Self::Contacts, Self::Drops];
+
+    fn find<'a>(
+        self,
+        repo: &'a git2::Repository,
+        id: &IdentityId,
+    ) -> cmd::Result<Option<git2::Reference<'a>>> {
+        let find_ref = |name: &str| if_not_found_none(repo.find_reference(name));
+        match self {
+            Self::Own => match find_ref(&Namespace::Own.refname(id))? {
+                Some(r) => Ok(Some(r)),
+                None => Ok(find_ref(&bundle_refname(id))?),
+            },
+            Self::Contacts => Ok(find_ref(&Namespace::Contacts.refname(id))?),
+            Self::Drops => {
+                let mut names = Vec::new();
+                for r in repo.references_glob(&format!("{REF_PREFIX}/drops/*/{id}"))? {
+                    if let Some(name) = r?.name() {
+                        if let Some((Namespace::Drop(_), _)) = Namespace::from_refname(name) {
+                            names.push(name.to_owned());
+                        }
+                    }
+                }
+                names.sort();
+                match names.first() {
+                    None => Ok(None),
+                    Some(name) => Ok(Some(repo.find_reference(name)?)),
+                }
+            },
+        }
+    }
+}
+
+/// Name of the branch conveying updates to `id` in patch bundles
+///
+/// This is independent of the namespace the identity is stored in locally.
+pub fn bundle_refname(id: &IdentityId) -> Refname {
+    Refname::try_from(format!("{REF_PREFIX}/{id}")).unwrap()
+}
@@ -0,0 +1,214 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    fs,
+    io::Read,
+    iter,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::ensure;
+use clap::ValueHint;
+use url::Url;
+
+use super::Common;
+use crate::{
+    cfg,
+    cmd::{
+        self,
+        ui::{
+            self,
+            debug,
+            warn,
+        },
+        FromGit as _,
+    },
+    metadata::{
+        self,
+        identity,
+        profile::{
+            self,
+            Avatar,
+        },
+        DateTime,
+        IdentityId,
+        Metadata,
+        Signed,
+    },
+    net,
+    str::Varchar,
+};
+
+/// Age after which a cached profile is fetched again
+const CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, clap::Args)]
+pub struct Profile {
+    #[clap(flatten)]
+    common: Common,
+    /// Display name
+    #[clap(long, value_parser)]
+    name: Option<Varchar<String, 128>>,
+    /// Short description of yourself
+    #[clap(long, value_parser)]
+    bio: Option<Varchar<String, 2048>>,
+    /// Avatar image, in PNG, JPEG or WebP format
+    #[clap(long, value_parser, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    avatar: Option<PathBuf>,
+    /// Remove the avatar from the profile
+    #[clap(long, value_parser, conflicts_with = "avatar")]
+    no_avatar: bool,
+    /// Date/time after which the profile should no longer be considered valid
+    #[clap(long, value_parser, value_name = "DATETIME")]
+    expires: Option<DateTime>,
+    /// Also publish the profile in this drop repository, so it is served at
+    /// the well-known path by 'it serve'; may be given multiple times
+    #[clap(
+        long = "publish",
+        value_parser,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+    )]
+    publish: Vec<PathBuf>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    id: IdentityId,
+    /// The URL referenced by the identity, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<Url>,
+    stored: Vec<PathBuf>,
+}
+
+/// Sign and store the profile of the identity
+///
+/// Fields not given on the command line are retained from the previously
+/// stored profile, if any.
+pub fn profile(args: Profile) -> cmd::Result<Output> {
+    let (repo, refname) = args.common.resolve()?;
+    let signer_id =
+        metadata::Identity::from_tip(&repo, &refname)?.verified(cmd::find_parent(&repo))?;
+    let id = *signer_id.id();
+
+    let mut prof = profile::load(repo.path(), &id)?
+        .map(|signed| signed.signed)
+        .unwrap_or_default();
+    prof.fmt_version = Default::default();
+    if let Some(name) = args.name {
+        prof.name = Some(name);
+    }
+    if let Some(bio) = args.bio {
+        prof.bio = Some(bio);
+    }
+    if let Some(path) = &args.avatar {
+        prof.avatar = Some(Avatar::from_bytes(fs::read(path)?)?);
+    }
+    if args.no_avatar {
+        prof.avatar = None;
+    }
+    if let Some(expires) = args.expires {
+        ensure!(expires > DateTime::now(), "expiry must be in the future");
+        prof.expires = Some(expires);
+    }
+
+    let cfg = cfg::git::open(&repo)?;
+    let mut signer = cfg::signer(&cfg, ui::askpass)?;
+    let keyid = metadata::KeyId::from(signer.ident());
+    ensure!(
+        signer_id.identity().keys.contains_key(&keyid),
+        "signing key {keyid} is not a key of {id}"
+    );
+    let signatures = Metadata::profile(&prof)
+        .sign(iter::once(&mut signer))?
+        .signatures;
+    let signed = Signed {
+        signed: prof,
+        signatures,
+    };
+    signed.verify(&signer_id)?;
+
+    let mut stored = vec![profile::store(repo.path(), &id, &signed)?];
+    for dir in &args.publish {
+        stored.push(profile::store(dir, &id, &signed)?);
+    }
+
+    let url = signer_id.identity().profile_url();
+    if url.is_none() {
+        warn!(
+            "The identity does not reference a profile URL, set '{}' in its custom metadata, eg. to https://<drop>/{}/{id}",
+            profile::CUSTOM_KEY,
+            profile::WELL_KNOWN_PATH,
+        );
+    }
+
+    Ok(Output { id, url, stored })
+}
+
+/// The profile of `id`, if it has one
+///
+/// Profiles are cached in `repo`, and refreshed from the URL the identity
+/// references if the cached copy is older than a day. If fetching fails, any
+/// cached copy is used instead.
+pub fn resolve(
+    repo: &git2::Repository,
+    id: &identity::Verified,
+) -> cmd::Result<Option<metadata::Profile>> {
+    let path = profile::path(repo.path(), id.id());
+    let is_fresh = fs::metadata(&path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|mtime| mtime.elapsed().ok())
+        .map_or(false, |age| age < CACHE_TTL);
+    let cached = || match profile::load(repo.path(), id.id()) {
+        Ok(Some(signed)) => match signed.verify(id) {
+            Ok(()) => Some(signed.signed),
+            Err(e) => {
+                warn!("Ignoring cached profile of {}: {e}", id.id());
+                None
+            },
+        },
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Ignoring cached profile of {}: {e:#}", id.id());
+            None
+        },
+    };
+
+    let url = match id.identity().profile_url() {
+        Some(url) if !is_fresh => url,
+        _ => return Ok(cached()),
+    };
+    match fetch(&url, id) {
+        Ok(signed) => {
+            profile::store(repo.path(), id.id(), &signed)?;
+            Ok(Some(signed.signed))
+        },
+        Err(e) => {
+            warn!("Failed to fetch profile of {} from {url}: {e:#}", id.id());
+            Ok(cached())
+        },
+    }
+}
+
+fn fetch(url: &Url, id: &identity::Verified) -> cmd::Result<Signed<metadata::Profile>> {
+    debug!("Fetching profile of {} from {url}", id.id());
+    let resp = net::request("GET", url)?
+        .set("Accept", "application/json")
+        .call()?;
+    ensure!(
+        resp.content_type() == "application/json",
+        "unexpected content type {}",
+        resp.content_type()
+    );
+    let mut data = Vec::new();
+    resp.into_reader()
+        .take(profile::MAX_SIZE as u64 + 1)
+        .read_to_end(&mut data)?;
+    let signed = profile::from_slice(&data)?;
+    signed.verify(id)?;
+
+    Ok(signed)
+}
@@ -0,0 +1,130 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+
+use super::{
+    edit::{
+        self,
+        Update,
+    },
+    Common,
+};
+use crate::{
+    cmd::{
+        self,
+        args::Refname,
+        FromGit as _,
+        GitIdentity,
+    },
+    metadata::{
+        self,
+        identity::{
+            Revocation,
+            RevocationReason,
+            Roles,
+        },
+        DateTime,
+        KeyId,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct RevokeKey {
+    #[clap(flatten)]
+    common: Common,
+    /// Why the key is revoked: 'compromised', 'superseded', or 'retired'
+    #[clap(long, value_parser, default_value = "compromised")]
+    reason: RevocationReason,
+    /// Date/time from which signatures made by the key are invalid
+    ///
+    /// If not given, the revocation is effective immediately. Set to an
+    /// earlier date/time if the key is suspected to have been compromised
+    /// before. Must not be in the future.
+    #[clap(long, value_parser, value_name = "DATETIME")]
+    effective: Option<DateTime>,
+    /// Commit to this branch to propose the update
+    ///
+    /// If not given, the update is performed in-place if the signature
+    /// threshold is met using the supplied keys.
+    #[clap(long, value_parser)]
+    propose_as: Option<Refname>,
+    /// Commit message for this edit
+    ///
+    /// Like git, $EDITOR will be invoked if not specified.
+    #[clap(short, long, value_parser)]
+    message: Option<String>,
+    /// Id of the key to revoke, may be a regular or a bot key
+    #[clap(value_parser, value_name = "KEYID")]
+    key: KeyId,
+}
+
+pub fn revoke_key(args: RevokeKey) -> cmd::Result<edit::Output> {
+    let (repo, refname) = args.common.resolve()?;
+    let GitIdentity {
+        hash: parent_hash,
+        signed: metadata::Signed { signed: parent, .. },
+    } = metadata::Identity::from_tip(&repo, &refname)?;
+
+    let keyid = args.key;
+    ensure!(
+        !parent.revoked.contains_key(&keyid),
+        "{keyid} is already revoked"
+    );
+    let now = DateTime::now();
+    let effective = args.effective.unwrap_or(now);
+    ensure!(
+        effective <= now,
+        "effective date/time must not be in the future"
+    );
+
+    let mut id = parent.clone();
+    let key = match id.keys.remove(&keyid) {
+        Some(key) => key,
+        None => {
+            id.bots
+                .remove(&keyid)
+                .ok_or_else(|| anyhow!("{keyid} is not a key of the identity"))?
+                .key
+        },
+    };
+    ensure!(!id.keys.is_empty(), "cannot revoke the last key");
+    match &mut id.roles {
+        Roles::Threshold(threshold) => ensure!(
+            id.keys.len() >= threshold.get(),
+            "revoking {keyid} would leave fewer keys than the threshold of {threshold}"
+        ),
+        Roles::Roles { root } => {
+            root.keys.remove(&keyid);
+            ensure!(
+                root.keys.len() >= root.threshold.get(),
+                "revoking {keyid} would leave fewer root keys than the threshold of {}",
+                root.threshold
+            );
+        },
+    }
+    id.revoked.insert(
+        keyid,
+        Revocation {
+            key,
+            reason: args.reason,
+            effective,
+        },
+    );
+
+    edit::update(
+        &repo,
+        &refname,
+        parent_hash,
+        &parent,
+        id,
+        Update {
+            propose_as: args.propose_as.as_ref(),
+            checkout: false,
+            message: args.message,
+        },
+    )
+}
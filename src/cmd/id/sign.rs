@@ -165,7 +165,7 @@ pub fn sign(args: Sign) -> cmd::Result<Output> {
     let commit_to = tx.lock_ref(args.commit_to)?;
 
     let canonical = proposed.canonicalise()?;
-    let mut signer = cfg::signer(&repo.config()?, ui::askpass)?;
+    let mut signer = cfg::signer(&cfg::git::open(&repo)?, ui::askpass)?;
     let mut signatures = BTreeMap::new();
     let keyid = metadata::KeyId::from(signer.ident());
     if !parent.keys.contains_key(&keyid) && !proposed.keys.contains_key(&keyid) {
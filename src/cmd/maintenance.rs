@@ -0,0 +1,74 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Repository maintenance
+//!
+//! Maintenance normally runs after a number of accepts, cf.
+//! `it.maintenanceInterval`. `it maintenance run` runs it on demand, which is
+//! useful eg. from a cron job while the drop is not accepting patches.
+
+use std::path::PathBuf;
+
+use crate::{
+    cfg,
+    cmd,
+    git,
+    patches::{
+        hooks::webhook,
+        pins,
+    },
+};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Cmd {
+    /// Repack the repository and retry pending IPFS pins and webhook
+    /// deliveries
+    Run(Run),
+}
+
+impl Cmd {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Run(args) => run(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Run {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Only retry pending IPFS pins and webhook deliveries, don't repack
+    #[clap(long, value_parser)]
+    pins_only: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    pinned: Vec<pins::Pinned>,
+    /// Number of pins still pending
+    pending: usize,
+    delivered: Vec<webhook::Delivered>,
+    /// Number of webhook deliveries still pending
+    pending_webhooks: usize,
+}
+
+pub fn run(args: Run) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    if !args.pins_only {
+        git::maintenance::run(repo.path())?;
+    }
+    let pinned = pins::retry(repo.path())?;
+    let pending = pins::pending(repo.path())?.len();
+    let webhooks = cfg::git::webhooks(&cfg::git::open(&repo)?)?;
+    let delivered = webhook::retry(repo.path(), &webhooks)?;
+    let pending_webhooks = webhook::pending(repo.path())?.len();
+
+    Ok(Output {
+        pinned,
+        pending,
+        delivered,
+        pending_webhooks,
+    })
+}
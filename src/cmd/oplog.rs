@@ -0,0 +1,219 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Local log of the commands which modified the drop
+//!
+//! Every successful invocation of a command which modifies the drop appends
+//! an [`Entry`] to `$GIT_DIR/it/oplog`, one JSON object per line. Each entry
+//! carries the SHA-256 hash of the line preceding it, so removing or altering
+//! an entry (other than the most recent one) breaks the chain, which `oplog
+//! show` reports.
+//!
+//! The log is informational: it is local to the repository, not signed, and
+//! failing to write it does not fail the command.
+
+use std::{
+    env,
+    fs,
+    io::{
+        self,
+        BufRead as _,
+        Read as _,
+        Seek as _,
+        Write as _,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::Context as _;
+use sha2::{
+    Digest as _,
+    Sha256,
+};
+use time::OffsetDateTime;
+
+use crate::{
+    cfg,
+    cmd,
+    fs::LockedFile,
+    git::{
+        self,
+        if_not_found_none,
+    },
+    metadata::IdentityId,
+    patches::{
+        REF_HEADS_PATCHES,
+        REF_IT_PATCHES,
+    },
+};
+
+const OPLOG_FILE: &str = "it/oplog";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// Position in the log, starting at 1
+    pub seq: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// The operating system user who ran the command, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The command, eg. "drop edit"
+    pub command: String,
+    /// The command line arguments, excluding the program name
+    pub args: Vec<String>,
+    /// The identity configured as the signer at the time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<IdentityId>,
+    /// The tip of the drop history after the command completed
+    #[serde(
+        default,
+        with = "git::serde::oid::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tip: Option<git2::Oid>,
+    /// Hex-encoded SHA-256 hash of the previous line of the log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+}
+
+/// Append an entry for `command` to the oplog of the repository at `git_dir`
+pub fn record(git_dir: &Path, command: &str) -> cmd::Result<()> {
+    let repo = git::repo::open(git_dir)?;
+    let cfg = repo.config()?;
+    let drop_ref = if repo.is_bare() {
+        REF_HEADS_PATCHES
+    } else {
+        REF_IT_PATCHES
+    };
+
+    let path = repo.path().join(OPLOG_FILE);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut lock = LockedFile::in_place(&path, false, LockedFile::DEFAULT_PERMISSIONS)?;
+    let mut log = Vec::new();
+    lock.read_to_end(&mut log)?;
+    let (seq, prev) = match log.split(|b| *b == b'\n').filter(|l| !l.is_empty()).last() {
+        Some(line) => {
+            let last: Entry = serde_json::from_slice(line).context("corrupt oplog")?;
+            (last.seq + 1, Some(hex::encode(Sha256::digest(line))))
+        },
+        None => (1, None),
+    };
+
+    let entry = Entry {
+        seq,
+        time: OffsetDateTime::now_utc(),
+        user: env::var("USER").or_else(|_| env::var("LOGNAME")).ok(),
+        command: command.to_owned(),
+        args: env::args().skip(1).collect(),
+        signer: cfg::git::identity(&cfg)?,
+        tip: if_not_found_none(repo.refname_to_id(drop_ref))?,
+        prev,
+    };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    lock.seek(io::SeekFrom::End(0))?;
+    lock.write_all(&line)?;
+    lock.persist()?;
+
+    Ok(())
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Cmd {
+    /// Show the log of commands which modified the drop, and verify its
+    /// integrity
+    Show(Show),
+}
+
+impl Cmd {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Show(args) => show(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Show {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Only show the most recent entries
+    #[clap(long, value_parser, value_name = "N")]
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    /// Whether the hash chain is intact
+    intact: bool,
+    /// Problems found with the hash chain
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+    /// The entries, oldest first
+    entries: Vec<Entry>,
+}
+
+pub fn show(args: Show) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let file = match fs::File::open(repo.path().join(OPLOG_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Output {
+                intact: true,
+                problems: vec![],
+                entries: vec![],
+            })
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut problems = Vec::new();
+    let mut entries = Vec::new();
+    let mut prev: Option<String> = None;
+    let mut expect_seq = 1;
+    for (i, line) in io::BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: Entry = match serde_json::from_slice(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                problems.push(format!("line {}: unreadable entry: {e}", i + 1));
+                prev = Some(hex::encode(Sha256::digest(&line)));
+                continue;
+            },
+        };
+        if entry.seq != expect_seq {
+            problems.push(format!(
+                "entry {}: expected sequence number {expect_seq}",
+                entry.seq
+            ));
+        }
+        if entry.prev != prev {
+            problems.push(format!(
+                "entry {}: hash of previous entry does not match",
+                entry.seq
+            ));
+        }
+        expect_seq = entry.seq + 1;
+        prev = Some(hex::encode(Sha256::digest(&line)));
+        entries.push(entry);
+    }
+    if let Some(limit) = args.limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+
+    Ok(Output {
+        intact: problems.is_empty(),
+        problems,
+        entries,
+    })
+}
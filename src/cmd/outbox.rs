@@ -0,0 +1,298 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Queued submissions
+//!
+//! A patch or comment created with `--queue` is signed and bundled as usual,
+//! but instead of being submitted right away, its signature, bundle info and
+//! target are kept under `$GIT_DIR/it/outbox/<bundle hash>.json`. The bundle
+//! itself stays in the bundle directory it was written to.
+//!
+//! With `--queue-if-unreachable`, the submission is attempted right away, and
+//! only queued if the drop (or the DNS resolver, if discovery is required)
+//! could not be reached.
+//!
+//! `it outbox push` (or `it patch push-queued`) submits the queued entries in
+//! the order they were queued, retrying transient failures with exponential
+//! backoff.
+
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use time::OffsetDateTime;
+
+use crate::{
+    bundle,
+    cfg,
+    cmd::{
+        self,
+        patch::resolve_url,
+        ui::{
+            info,
+            warn,
+        },
+    },
+    fs::LockedFile,
+    git,
+    patches::{
+        self,
+        DropHead,
+        Submission,
+    },
+};
+
+const OUTBOX_DIR: &str = "it/outbox";
+const FILE_EXTENSION: &str = "json";
+
+/// A submission waiting to be pushed
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Queued {
+    /// URL, or domain name advertising the drop, to submit to
+    pub url: String,
+    /// Refname of the drop the patch was created against
+    pub drop_ref: String,
+    pub bundle_dir: PathBuf,
+    pub info: bundle::Info,
+    pub signature: patches::Signature,
+    #[serde(with = "time::serde::rfc3339")]
+    pub queued: OffsetDateTime,
+    /// Number of failed attempts to push the submission
+    #[serde(default)]
+    pub attempts: u32,
+    /// The error the last attempt failed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Queued {
+    pub fn new(
+        url: String,
+        drop_ref: String,
+        bundle_dir: PathBuf,
+        submission: &Submission,
+    ) -> Self {
+        Self {
+            url,
+            drop_ref,
+            bundle_dir,
+            info: submission.bundle.info().clone(),
+            signature: submission.signature.clone(),
+            queued: OffsetDateTime::now_utc(),
+            attempts: 0,
+            error: None,
+        }
+    }
+
+    fn path(git_dir: &Path, hash: &bundle::Hash) -> PathBuf {
+        let mut path = git_dir.join(OUTBOX_DIR).join(hash.to_string());
+        path.set_extension(FILE_EXTENSION);
+        path
+    }
+
+    fn load(path: &Path) -> cmd::Result<Self> {
+        let file = fs::File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+
+    fn save(&self, git_dir: &Path) -> cmd::Result<PathBuf> {
+        let path = Self::path(git_dir, &self.info.hash);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lock = LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+        serde_json::to_writer_pretty(&mut lock, self)?;
+        lock.persist()?;
+
+        Ok(path)
+    }
+}
+
+/// Add `queued` to the outbox of `repo`
+pub fn enqueue(repo: &git2::Repository, queued: &Queued) -> cmd::Result<PathBuf> {
+    queued.save(repo.path())
+}
+
+/// All queued submissions, oldest first
+fn queued(repo: &git2::Repository) -> cmd::Result<Vec<Queued>> {
+    let dir = match fs::read_dir(repo.path().join(OUTBOX_DIR)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut queued = Vec::new();
+    for entry in dir {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == FILE_EXTENSION) {
+            queued.push(Queued::load(&path)?);
+        }
+    }
+    queued.sort_by_key(|q| q.queued);
+
+    Ok(queued)
+}
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Cmd {
+    /// List queued submissions
+    #[clap(visible_alias = "list")]
+    Ls(Ls),
+    /// Submit queued submissions
+    Push(Push),
+}
+
+impl Cmd {
+    pub fn run(self) -> cmd::Result<cmd::Output> {
+        match self {
+            Self::Ls(args) => ls(args).map(cmd::IntoOutput::into_output),
+            Self::Push(args) => push(args).map(cmd::IntoOutput::into_output),
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Ls {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+pub struct Entry {
+    hash: bundle::Hash,
+    url: String,
+    drop_ref: String,
+    #[serde(with = "time::serde::rfc3339")]
+    queued: OffsetDateTime,
+    attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Queued> for Entry {
+    fn from(q: Queued) -> Self {
+        Self {
+            hash: q.info.hash,
+            url: q.url,
+            drop_ref: q.drop_ref,
+            queued: q.queued,
+            attempts: q.attempts,
+            error: q.error,
+        }
+    }
+}
+
+pub fn ls(args: Ls) -> cmd::Result<Vec<Entry>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    Ok(queued(&repo)?.into_iter().map(Entry::from).collect())
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Push {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Number of times to retry a submission failing due to a network or
+    /// server error
+    #[clap(long, value_parser, value_name = "N", default_value_t = 3)]
+    retries: u32,
+    /// Seconds to wait before the first retry, doubled after each one
+    #[clap(long, value_parser, value_name = "SECS", default_value_t = 5)]
+    backoff: u64,
+}
+
+#[derive(serde::Serialize)]
+pub struct Pushed {
+    hash: bundle::Hash,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<patches::Record>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub fn push(args: Push) -> cmd::Result<Vec<Pushed>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let cfg = cfg::git::open(&repo)?;
+
+    let mut pushed = Vec::new();
+    for mut q in queued(&repo)? {
+        let hash = q.info.hash;
+        info!("Submitting {hash} to {}", q.url);
+        match push_one(&repo, &cfg, &q, &args) {
+            Ok(record) => {
+                fs::remove_file(Queued::path(repo.path(), &hash))?;
+                pushed.push(Pushed {
+                    hash,
+                    record: Some(record),
+                    error: None,
+                });
+            },
+            Err(e) => {
+                let error = format!("{e:#}");
+                warn!("Failed to submit {hash}: {error}");
+                q.attempts += 1;
+                q.error = Some(error.clone());
+                q.save(repo.path())?;
+                pushed.push(Pushed {
+                    hash,
+                    record: None,
+                    error: Some(error),
+                });
+            },
+        }
+    }
+
+    Ok(pushed)
+}
+
+fn push_one(
+    repo: &git2::Repository,
+    cfg: &git2::Config,
+    q: &Queued,
+    args: &Push,
+) -> cmd::Result<patches::Record> {
+    let drop = DropHead::from_refname(repo, &q.drop_ref)?;
+    let url = resolve_url(cfg, &q.url, &drop)?;
+
+    let mut backoff = Duration::from_secs(args.backoff);
+    let mut retries = args.retries;
+    loop {
+        let submission = Submission::from_stored(&q.bundle_dir, q.signature.clone(), &q.info)
+            .map_err(|e| anyhow!("unable to load queued bundle: {e:#}"))?;
+        match submission.submit(url.clone()) {
+            Err(e) if retries > 0 && is_transient(&e) => {
+                warn!("{e:#}, retrying in {}s", backoff.as_secs());
+                thread::sleep(backoff);
+                backoff *= 2;
+                retries -= 1;
+            },
+            x => return x,
+        }
+    }
+}
+
+/// Whether the submission may succeed when retried
+fn is_transient(e: &crate::Error) -> bool {
+    match e.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Transport(_)) => true,
+        Some(ureq::Error::Status(code, _)) => *code == 429 || *code >= 500,
+        None => false,
+    }
+}
+
+/// Whether `e` was caused by the remote end not being reachable at all
+pub fn is_unreachable(e: &crate::Error) -> bool {
+    matches!(
+        e.downcast_ref::<ureq::Error>(),
+        Some(ureq::Error::Transport(_))
+    )
+}
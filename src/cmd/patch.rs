@@ -2,18 +2,59 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use crate::{
-    cmd,
+    cmd::{
+        self,
+        outbox,
+    },
     patches,
 };
 
 mod create;
-mod prepare;
+pub(crate) mod prepare;
+
+mod composite;
+pub use composite::{
+    composite,
+    Composite,
+};
+
+mod list;
+pub use list::{
+    list,
+    List,
+};
+
+mod resubmit;
+pub use resubmit::{
+    resubmit,
+    Resubmit,
+};
+
+mod review;
+pub use review::{
+    review,
+    Review,
+};
+
+mod show;
+pub use show::{
+    show,
+    Show,
+};
+
+mod status;
+pub use status::{
+    status,
+    Status,
+};
 
 pub use create::{
     create,
+    resolve_url,
     Comment,
     Common,
     Kind,
+    Mbox,
     Patch,
     Remote,
 };
@@ -24,15 +65,42 @@ pub enum Cmd {
     Record(Record),
     /// Submit a patch to a remote drop
     Submit(Submit),
+    /// Create a patch from a mailbox of 'git format-patch' emails, and record
+    /// or submit it
+    ImportMbox(ImportMbox),
+    /// Retry the last failed submission, optionally applying remediations
+    Resubmit(Resubmit),
+    /// Submit patches queued with --queue or --queue-if-unreachable
+    ///
+    /// Same as 'it outbox push'.
+    PushQueued(outbox::Push),
+    /// Show which topics were merged into the drop's branches
+    Status(Status),
+    /// List the patches recorded in the drop, or export them as emails
+    List(List),
+    /// Create a patch spanning several drops, and record or submit it
+    Composite(Composite),
+    /// Review a patch, optionally commenting on lines of its files
+    Review(Review),
+    /// Show the thread of a topic, or the diffs of its patches with review
+    /// comments inline
+    Show(Show),
 }
 
 impl Cmd {
     pub fn run(self) -> cmd::Result<cmd::Output> {
         match self {
-            Self::Record(args) => record(args),
-            Self::Submit(args) => submit(args),
+            Self::Record(args) => record(args).map(cmd::IntoOutput::into_output),
+            Self::Submit(args) => submit(args).map(cmd::IntoOutput::into_output),
+            Self::ImportMbox(args) => import_mbox(args).map(cmd::IntoOutput::into_output),
+            Self::Resubmit(args) => resubmit(args).map(cmd::IntoOutput::into_output),
+            Self::PushQueued(args) => outbox::push(args).map(cmd::IntoOutput::into_output),
+            Self::Status(args) => status(args).map(cmd::IntoOutput::into_output),
+            Self::List(args) => list(args),
+            Self::Composite(args) => composite(args).map(cmd::IntoOutput::into_output),
+            Self::Review(args) => review(args).map(cmd::IntoOutput::into_output),
+            Self::Show(args) => show(args),
         }
-        .map(cmd::IntoOutput::into_output)
     }
 }
 
@@ -75,3 +143,43 @@ pub fn submit(
         patch,
     })
 }
+
+#[derive(Debug, clap::Args)]
+pub struct ImportMbox {
+    #[clap(flatten)]
+    common: Common,
+    #[clap(flatten)]
+    mbox: Mbox,
+    /// Url to submit the patch to
+    ///
+    /// If not set, the patch is recorded in the local drop, like 'it patch
+    /// record' does.
+    #[clap(
+        long,
+        visible_alias = "submit-to",
+        value_parser,
+        value_name = "URL",
+        requires = "drop_ref"
+    )]
+    url: Option<String>,
+    /// Refname of the drop to record the patch with, when submitting to --url
+    #[clap(long = "drop", value_parser, value_name = "STRING", requires = "url")]
+    drop_ref: Option<String>,
+}
+
+pub fn import_mbox(
+    ImportMbox {
+        common,
+        mbox,
+        url,
+        drop_ref,
+    }: ImportMbox,
+) -> cmd::Result<patches::Record> {
+    create(Kind::Mbox {
+        common,
+        remote: url
+            .zip(drop_ref)
+            .map(|(url, drop_ref)| Remote::new(url, drop_ref)),
+        mbox,
+    })
+}
@@ -0,0 +1,317 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use clap::ValueHint;
+use url::Url;
+
+use super::{
+    create::dwim_base,
+    prepare,
+};
+use crate::{
+    bundle,
+    cfg,
+    cmd::{
+        self,
+        ui::{
+            self,
+            edit_cover_letter,
+            info,
+        },
+        util::{
+            args::IdSearchPath,
+            auth,
+        },
+        Aborted,
+    },
+    git,
+    metadata::{
+        self,
+        drop::RoleName,
+        git::FromGit as _,
+        IdentityId,
+    },
+    patches::{
+        self,
+        composite,
+        large_blobs,
+        notes,
+        DropHead,
+        REF_HEADS_PATCHES,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+    paths,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Composite {
+    /// A part of the composite patch, as NAME=DIR
+    ///
+    /// DIR is the repository of the drop the part is destined for, which must
+    /// also contain the patch. The patch proposes the HEAD of DIR against
+    /// the "main" or "master" branch of the drop. NAME identifies the part to
+    /// the drops, which must be configured with their siblings under the same
+    /// names (`it.<NAME>.siblingDrop`).
+    ///
+    /// Must be given at least once.
+    #[clap(
+        long = "part",
+        value_parser,
+        value_name = "NAME=DIR",
+        required = true,
+        value_hint = ValueHint::DirPath,
+    )]
+    parts: Vec<PartArg>,
+    /// Identity to assume
+    ///
+    /// If not set as an option nor in the environment, the value of `it.id` in
+    /// the git config of the first part is tried.
+    #[clap(short = 'I', long = "identity", value_name = "ID", env = "IT_ID")]
+    id: Option<IdentityId>,
+    /// A list of paths to search for identity repositories
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PATH",
+        env = "IT_ID_PATH",
+        default_value_t,
+        value_hint = ValueHint::DirPath,
+    )]
+    id_path: IdSearchPath,
+    /// Cover letter of the composite patch, posted with every part
+    ///
+    /// If not set, $EDITOR will be invoked to author one.
+    #[clap(short, long, value_parser, value_name = "STRING")]
+    message: Option<String>,
+    /// Write the bundle list referring to the bundles of all parts to this
+    /// file
+    #[clap(
+        short,
+        long,
+        value_parser,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+    )]
+    output: Option<PathBuf>,
+    /// Url to submit the composite patch to
+    ///
+    /// If not set, the parts are recorded in the local drops, all or none.
+    #[clap(long, visible_alias = "submit-to", value_parser, value_name = "URL")]
+    url: Option<Url>,
+    /// Create the patch, but stop short of submitting / recording it
+    #[clap(long, value_parser)]
+    dry_run: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PartArg {
+    name: String,
+    dir: PathBuf,
+}
+
+impl FromStr for PartArg {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, dir) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected NAME=DIR"))?;
+        composite::validate_name(name)?;
+        Ok(Self {
+            name: name.to_owned(),
+            dir: dir.into(),
+        })
+    }
+}
+
+struct Part {
+    name: String,
+    repo: prepare::Repo,
+    drop_ref: String,
+    base: (git::Refname, git2::Oid),
+    head: git2::Oid,
+}
+
+pub fn composite(args: Composite) -> cmd::Result<Vec<patches::Record>> {
+    ensure!(
+        args.parts.len() <= composite::MAX_PARTS,
+        "a composite patch can have at most {} parts",
+        composite::MAX_PARTS
+    );
+
+    let mut parts = Vec::with_capacity(args.parts.len());
+    let mut manifest = composite::Manifest::default();
+    for PartArg { name, dir } in args.parts {
+        let drp = git::repo::open(&dir)?;
+        let drop_ref = if drp.is_bare() {
+            REF_HEADS_PATCHES
+        } else {
+            REF_IT_PATCHES
+        };
+        let advertised = metadata::Drop::from_tip(&drp, drop_ref)
+            .map(|meta| meta.signed.signed.id_path)
+            .unwrap_or_default();
+        let ids = args.id_path.open_git_with(&drp, &advertised)?;
+        let head = drp.head()?.peel_to_commit()?.id();
+        let repo = prepare::Repo::new(drp, ids, None);
+        let base = {
+            let drop = DropHead::from_refname(repo.target(), drop_ref)?;
+            let (branch, base_ref) = dwim_base(repo.target(), &drop, None, None, None)?
+                .ok_or_else(|| anyhow!("part {name}: unable to determine base branch"))?;
+            let base = repo
+                .target()
+                .find_reference(&base_ref)?
+                .peel_to_commit()?
+                .id();
+            (branch, base)
+        };
+
+        manifest.insert(name.clone(), head)?;
+        parts.push(Part {
+            name,
+            repo,
+            drop_ref: drop_ref.to_owned(),
+            base,
+            head,
+        });
+    }
+
+    let first = parts[0].repo.source();
+    let cfg = cfg::git::open(first)?;
+    let mut signer = cfg::git::signer(&cfg, ui::askpass)?;
+    let hash_algorithm = cfg::git::hash_algorithm(&cfg)?;
+    let signer_id = match args.id {
+        Some(id) => id,
+        None => {
+            cfg::git::identity(&cfg)?.ok_or_else(|| anyhow!("no identity configured for signer"))?
+        },
+    };
+    let message = match args.message {
+        Some(message) => message,
+        None => match edit_cover_letter(first)? {
+            notes::Simple::Known(notes::Predef::Basic { message }) => message,
+            _ => unreachable!("cover letters are basic notes"),
+        },
+    };
+    let topic = manifest.derive_topic(&signer.ident().keyid());
+    info!("Posting composite patch to topic {topic}");
+
+    let mut container = composite::Container { parts: vec![] };
+    let mut submissions = Vec::with_capacity(parts.len());
+    for part in &parts {
+        let drop = DropHead::from_refname(part.repo.target(), &part.drop_ref)?;
+        if args.url.is_none() {
+            auth::ensure_role(
+                &drop.meta,
+                &signer_id,
+                RoleName::Snapshot,
+                "record patches in the local drop",
+            )?;
+        }
+        let bundle_dir = part.repo.target().path().join(paths::bundles());
+        let (name, base) = part.base.clone();
+        let sub = prepare::Preparator::new(
+            &part.repo,
+            &drop,
+            prepare::Submitter {
+                signer: &mut signer,
+                id: signer_id,
+                acknowledge_policy: false,
+            },
+            hash_algorithm,
+        )
+        .composite(topic.clone(), manifest.clone())
+        .prepare_patch(
+            &bundle_dir,
+            prepare::Kind::Patch {
+                head: part.head,
+                base,
+                name,
+                re: None,
+                large_blobs: large_blobs::Options {
+                    strip: false,
+                    ..Default::default()
+                },
+            },
+            Some(message.clone()),
+            &[],
+        )?;
+        info!(
+            "Created bundle {} for part {}",
+            sub.bundle.info().hash,
+            part.name
+        );
+        container.parts.push(composite::Part {
+            name: part.name.clone(),
+            hash: sub.bundle.info().hash,
+            len: sub.bundle.info().len,
+            signature: sub.signature.clone(),
+            path: bundle_dir
+                .join(sub.bundle.info().hash.to_string())
+                .with_extension(bundle::FILE_EXTENSION),
+        });
+        submissions.push(sub);
+    }
+
+    if let Some(output) = &args.output {
+        container.write(output)?;
+        info!("Wrote bundle list to {}", output.display());
+    }
+    if args.dry_run {
+        info!("--dry-run given, stopping here");
+        cmd::abort!();
+    }
+
+    match args.url {
+        Some(url) => composite::submit(&container, url),
+        None => {
+            // Each drop signs its history with the key configured for it
+            let mut signers = Vec::with_capacity(parts.len());
+            let mut options = Vec::with_capacity(parts.len());
+            for part in &parts {
+                let cfg = cfg::git::open(part.repo.target())?;
+                signers.push(cfg::git::signer(&cfg, ui::askpass)?);
+                options.push(patches::AcceptOptions {
+                    hooks: cfg::git::accept_hooks(&cfg)?,
+                    lint: cfg::git::lint_rules(&cfg)?,
+                    ..Default::default()
+                });
+            }
+            let mut accept = Vec::with_capacity(parts.len());
+            for (((part, submission), signer), options) in parts
+                .iter()
+                .zip(&mut submissions)
+                .zip(&mut signers)
+                .zip(options)
+            {
+                accept.push(composite::Accept {
+                    name: &part.name,
+                    args: patches::AcceptArgs {
+                        unbundle_prefix: REF_IT_BUNDLES,
+                        drop_ref: &part.drop_ref,
+                        seen_ref: REF_IT_SEEN,
+                        repo: part.repo.target(),
+                        signer,
+                        ipfs_api: None,
+                        drop_cache: None,
+                        reachable_cache: None,
+                        options,
+                    },
+                    submission,
+                });
+            }
+            composite::accept(accept)
+        },
+    }
+}
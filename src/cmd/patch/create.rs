@@ -2,10 +2,13 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
-    borrow::Cow,
     collections::BTreeMap,
     env,
-    path::PathBuf,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
 };
 
 use anyhow::anyhow;
@@ -17,27 +20,44 @@ use globset::{
 use once_cell::sync::Lazy;
 use url::Url;
 
-use super::prepare;
+use super::{
+    prepare,
+    resubmit,
+};
 use crate::{
     cfg,
     cmd::{
         self,
+        drafts::Draft,
+        outbox,
         ui::{
             self,
             debug,
             info,
+            warn,
+        },
+        util::{
+            args::IdSearchPath,
+            auth,
         },
-        util::args::IdSearchPath,
         Aborted,
     },
+    discovery,
     git::{
         self,
         Refname,
     },
-    metadata::IdentityId,
+    metadata::{
+        self,
+        drop::RoleName,
+        git::FromGit as _,
+        IdentityId,
+    },
     patches::{
         self,
         iter,
+        large_blobs,
+        notes,
         DropHead,
         Topic,
         TrackingBranch,
@@ -48,10 +68,13 @@ use crate::{
         REF_IT_BUNDLES,
         REF_IT_PATCHES,
         REF_IT_SEEN,
+        REF_IT_TOPICS,
     },
     paths,
 };
 
+mod wizard;
+
 #[derive(Debug, clap::Args)]
 pub struct Common {
     /// Path to the drop repository
@@ -120,17 +143,43 @@ pub struct Common {
     /// Create the patch, but stop short of submitting / recording it
     #[clap(long, value_parser)]
     dry_run: bool,
+    /// Proceed even if the identity is not bound to the target drop
+    ///
+    /// Identities can be bound to drops by setting `it.<ID>.allowedDrop` in
+    /// the git config to either a root identity of the drop, or the URL
+    /// patches are submitted to.
+    #[clap(long, value_parser)]
+    force_drop: bool,
+    /// Agree to the usage policy of the drop, eg. a contributor license
+    /// agreement
+    ///
+    /// Only needed once per identity, unless the policy changes. The policy
+    /// is shown by 'it drop show'.
+    #[clap(long, value_parser)]
+    acknowledge_policy: bool,
+    /// Encrypt the patch to the given age recipient
+    ///
+    /// May be given multiple times. Recipients are 'age1...' or SSH public
+    /// keys. Only the packdata is encrypted, the refs and prerequisites of the
+    /// bundle remain readable. Requires the 'age' program to be installed,
+    /// and the drop to accept encrypted patches (`it.allowEncrypted`).
+    #[clap(long, value_parser, value_name = "RECIPIENT")]
+    encrypt_to: Vec<String>,
 }
 
 #[derive(Debug, clap::Args)]
 pub struct Remote {
     /// Url to submit the patch to
     ///
-    /// Usually one of the alternates from the drop metadata. If not set,
-    /// GIT_DIR is assumed to contain a drop with which the patch can be
+    /// Usually one of the alternates from the drop metadata. May also be a
+    /// domain name advertising the drop via DNS, in which case the root
+    /// identities of the drop must be pinned by the domain, if any are. An
+    /// 'ssh://' URL submits the patch by running `it drop serve-ssh` on the
+    /// remote host, using the 'ssh' command or $IT_SSH_COMMAND. If not
+    /// set, GIT_DIR is assumed to contain a drop with which the patch can be
     /// recorded without any network access.
     #[clap(long, visible_alias = "submit-to", value_parser, value_name = "URL")]
-    url: Url,
+    url: String,
     /// Refname of the drop to record the patch with
     ///
     /// We need to pick a local (remote-tracking) drop history in order to
@@ -139,6 +188,56 @@ pub struct Remote {
     /// 'origin/patches' are attempted to be resolved.
     #[clap(long = "drop", value_parser, value_name = "STRING")]
     drop_ref: String,
+    /// Queue the submission instead of submitting it right away
+    ///
+    /// Queued submissions are submitted by `it outbox push`. If --url is a
+    /// domain name, discovery is deferred until then.
+    #[clap(long, value_parser)]
+    queue: bool,
+    /// Queue the submission only if the drop can not be reached
+    ///
+    /// The submission is attempted right away, but if --url (or the DNS
+    /// resolver, if --url is a domain name) is unreachable, it is queued as if
+    /// --queue was given.
+    #[clap(long, value_parser, conflicts_with = "queue")]
+    queue_if_unreachable: bool,
+}
+
+impl Remote {
+    fn resolve_url(&self, cfg: &git2::Config, drop: &DropHead) -> cmd::Result<Url> {
+        resolve_url(cfg, &self.url, drop)
+    }
+
+    pub(crate) fn new(url: String, drop_ref: String) -> Self {
+        Self {
+            url,
+            drop_ref,
+            queue: false,
+            queue_if_unreachable: false,
+        }
+    }
+
+    pub(super) fn from_saved(saved: &resubmit::Saved) -> Self {
+        Self {
+            url: saved.url.to_string(),
+            drop_ref: saved.drop_ref.clone(),
+            queue: false,
+            queue_if_unreachable: false,
+        }
+    }
+}
+
+/// Resolve the URL to submit to, discovering it via DNS if `url` is a domain
+/// name
+pub fn resolve_url(cfg: &git2::Config, url: &str, drop: &DropHead) -> cmd::Result<Url> {
+    if !discovery::is_domain(url) {
+        return Ok(Url::parse(url)?);
+    }
+    let found = discovery::discover(&cfg::git::dns_resolver(cfg)?, url)?;
+    found.verify_roots(&drop.meta.roles.root.ids)?;
+    info!("Discovered drop at {} via {}", found.url(), found.domain);
+
+    Ok(found.url().clone())
 }
 
 #[derive(Debug, clap::Args)]
@@ -169,6 +268,53 @@ pub struct Patch {
     /// Only considered if --topic is given.
     #[clap(long, value_parser, value_name = "ID")]
     reply_to: Option<git2::Oid>,
+    /// Size in bytes above which blobs introduced by the patch are considered
+    /// large
+    #[clap(
+        long,
+        value_parser,
+        value_name = "BYTES",
+        default_value_t = large_blobs::DEFAULT_THRESHOLD,
+    )]
+    large_blob_threshold: u64,
+    /// Rewrite the patch to exclude large blobs
+    ///
+    /// The stripped blobs are listed in the cover letter. Note that rewritten
+    /// commits lose their signatures, if any.
+    #[clap(long, value_parser)]
+    strip_large_blobs: bool,
+    /// Choose the topic, base branch and head revision interactively
+    ///
+    /// The values of --topic, --reply-to, --base and --head are offered as
+    /// defaults. Before the patch bundle is signed, its contents are shown
+    /// for confirmation.
+    #[clap(long, value_parser)]
+    interactive: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Mbox {
+    /// Mailbox containing the patch series, eg. as produced by 'git
+    /// format-patch --stdout'
+    ///
+    /// The patches are applied in order, as authored by their senders. The
+    /// cover letter, if any, is used as the message of the patch unless
+    /// --message is given.
+    #[clap(value_parser, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    mbox: PathBuf,
+    /// Base branch the patches apply to
+    ///
+    /// Resolved like the --base option of 'it patch record'.
+    #[clap(long = "base", value_parser, value_name = "REF")]
+    base: Option<String>,
+    /// Post the patch to a previously recorded topic
+    #[clap(long, value_parser, value_name = "TOPIC")]
+    topic: Option<Topic>,
+    /// Reply to a particular entry within a topic
+    ///
+    /// Only considered if --topic is given.
+    #[clap(long, value_parser, value_name = "ID")]
+    reply_to: Option<git2::Oid>,
 }
 
 #[derive(Debug, clap::Args)]
@@ -179,6 +325,23 @@ pub struct Comment {
     /// Reply to a particular entry within the topic
     #[clap(long, value_parser, value_name = "ID")]
     reply_to: Option<git2::Oid>,
+    /// Post this note instead of a free-form comment
+    #[clap(skip)]
+    note: Option<notes::Simple>,
+}
+
+impl Comment {
+    /// Post the structured `note` to `topic`, eg. a topic state change or a
+    /// review
+    ///
+    /// The --message, if any, is attached to the note.
+    pub(crate) fn note(topic: Topic, reply_to: Option<git2::Oid>, note: notes::Simple) -> Self {
+        Self {
+            topic,
+            reply_to,
+            note: Some(note),
+        }
+    }
 }
 
 pub enum Kind {
@@ -189,6 +352,7 @@ pub enum Kind {
     },
     Snapshot {
         common: Common,
+        full: bool,
     },
     Comment {
         common: Common,
@@ -200,15 +364,27 @@ pub enum Kind {
         remote: Option<Remote>,
         patch: Patch,
     },
+    Mbox {
+        common: Common,
+        remote: Option<Remote>,
+        mbox: Mbox,
+    },
+    Resubmit {
+        common: Common,
+        remote: Remote,
+        spec: resubmit::Spec,
+    },
 }
 
 impl Kind {
     fn common(&self) -> &Common {
         match self {
             Self::Merges { common, .. }
-            | Self::Snapshot { common }
+            | Self::Snapshot { common, .. }
             | Self::Comment { common, .. }
-            | Self::Patch { common, .. } => common,
+            | Self::Patch { common, .. }
+            | Self::Mbox { common, .. }
+            | Self::Resubmit { common, .. } => common,
         }
     }
 
@@ -216,19 +392,32 @@ impl Kind {
         match self {
             Self::Merges { remote, .. }
             | Self::Comment { remote, .. }
-            | Self::Patch { remote, .. } => remote.as_ref(),
+            | Self::Patch { remote, .. }
+            | Self::Mbox { remote, .. } => remote.as_ref(),
+            Self::Resubmit { remote, .. } => Some(remote),
             Self::Snapshot { .. } => None,
         }
     }
 
-    fn accept_options(&self, drop: &DropHead) -> patches::AcceptOptions {
-        let mut options = patches::AcceptOptions::default();
+    fn accept_options(
+        &self,
+        drop: &DropHead,
+        cfg: &git2::Config,
+    ) -> cmd::Result<patches::AcceptOptions> {
+        let mut options = patches::AcceptOptions {
+            hooks: cfg::git::accept_hooks(cfg)?,
+            lint: cfg::git::lint_rules(cfg)?,
+            allow_encrypted: !self.common().encrypt_to.is_empty(),
+            audit: cfg::git::audit_log(cfg)?,
+            ..Default::default()
+        };
         match self {
             Self::Merges { common, .. } => {
                 options.allow_fat_pack = true;
                 options.max_branches = drop.meta.roles.branches.len();
                 options.max_refs = options.max_branches + common.ids.len() + 1;
-                options.max_commits = 100_000;
+                // Merges convey history which has been accepted before
+                options.lint = Default::default();
             },
             Self::Snapshot { .. } => {
                 options.allow_fat_pack = true;
@@ -236,14 +425,17 @@ impl Kind {
                 options.max_branches = usize::MAX;
                 options.max_refs = usize::MAX;
                 options.max_commits = usize::MAX;
+                options.max_commits_checkpoint = usize::MAX;
                 options.max_notes = usize::MAX;
                 options.max_tags = usize::MAX;
+                options.lint = Default::default();
+                options.dedup_snapshots = cfg::git::dedup_snapshots(cfg)?;
             },
 
             _ => {},
         }
 
-        options
+        Ok(options)
     }
 }
 
@@ -251,12 +443,45 @@ struct Resolved {
     repo: prepare::Repo,
     signer_id: IdentityId,
     bundle_dir: PathBuf,
+    drop_ref: String,
 }
 
 impl Common {
-    fn resolve(&self) -> cmd::Result<Resolved> {
+    pub(super) fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub(super) fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// Reconstruct the options of a failed submission
+    pub(super) fn from_saved(
+        git_dir: PathBuf,
+        saved: &resubmit::Saved,
+        message: Option<String>,
+        dry_run: bool,
+        force_drop: bool,
+        acknowledge_policy: bool,
+    ) -> Self {
+        Self {
+            git_dir,
+            src_dir: Some(saved.src_dir.clone()),
+            id: Some(saved.id),
+            id_path: saved.id_path.clone(),
+            bundle_dir: saved.bundle_dir.clone(),
+            ipfs_api: None,
+            ids: saved.ids.clone(),
+            message,
+            dry_run,
+            force_drop,
+            acknowledge_policy,
+            encrypt_to: saved.encrypt_to.clone(),
+        }
+    }
+
+    fn resolve(&self, remote: Option<&Remote>) -> cmd::Result<Resolved> {
         let drp = git::repo::open(&self.git_dir)?;
-        let ids = self.id_path.open_git();
         let src = match self.src_dir.as_ref() {
             None => {
                 let cwd = env::current_dir()?;
@@ -268,6 +493,29 @@ impl Common {
         .map(git::repo::open_bare)
         .transpose()?;
 
+        let drop_ref = match remote {
+            Some(remote) => {
+                let full = src
+                    .as_ref()
+                    .unwrap_or(&drp)
+                    .resolve_reference_from_short_name(&remote.drop_ref)?;
+                full.name()
+                    .ok_or_else(|| anyhow!("invalid drop ref"))?
+                    .to_owned()
+            },
+            None if drp.is_bare() => REF_HEADS_PATCHES.to_owned(),
+            None => REF_IT_PATCHES.to_owned(),
+        };
+        // Include the identity repositories advertised by the drop, if any
+        let advertised = match metadata::Drop::from_tip(&drp, &drop_ref) {
+            Ok(meta) => meta.signed.signed.id_path,
+            Err(e) => {
+                debug!("unable to read drop metadata from {drop_ref}: {e:#}");
+                Vec::new()
+            },
+        };
+        let ids = self.id_path.open_git_with(&drp, &advertised)?;
+
         debug!(
             "drop: {}, src: {:?}, ids: {:?}",
             drp.path().display(),
@@ -275,13 +523,10 @@ impl Common {
             env::join_paths(ids.iter().map(|r| r.path()))
         );
 
-        // IT_ID_PATH could differ from what was used at initialisation
-        git::add_alternates(&drp, &ids)?;
-
         let repo = prepare::Repo::new(drp, ids, src);
         let signer_id = match self.id {
             Some(id) => id,
-            None => cfg::git::identity(&repo.source().config()?)?
+            None => cfg::git::identity(&cfg::git::open(repo.source())?)?
                 .ok_or_else(|| anyhow!("no identity configured for signer"))?,
         };
         let bundle_dir = if self.bundle_dir.is_absolute() {
@@ -294,6 +539,7 @@ impl Common {
             repo,
             signer_id,
             bundle_dir,
+            drop_ref,
         })
     }
 }
@@ -307,35 +553,97 @@ static SNAPSHOT_REFS: Lazy<GlobSet> = Lazy::new(|| {
         .unwrap()
 });
 
-pub fn create(args: Kind) -> cmd::Result<patches::Record> {
+pub fn create(mut args: Kind) -> cmd::Result<patches::Record> {
     let Resolved {
         repo,
         signer_id,
         bundle_dir,
-    } = args.common().resolve()?;
-    let drop_ref: Cow<str> = match args.remote() {
-        Some(remote) => {
-            let full = repo
-                .source()
-                .resolve_reference_from_short_name(&remote.drop_ref)?;
-            full.name()
-                .ok_or_else(|| anyhow!("invalid drop ref"))?
-                .to_owned()
-                .into()
+        drop_ref,
+    } = args.common().resolve(args.remote())?;
+
+    let cfg = cfg::git::open(repo.source())?;
+    let mut signer = cfg::git::signer(&cfg, ui::askpass)?;
+    let hash_algorithm = cfg::git::hash_algorithm(&cfg)?;
+    let drop = patches::DropHead::from_refname(repo.target(), &drop_ref)?;
+    let mut queue = args.remote().map_or(false, |remote| remote.queue);
+    // Discovery requires network access, so is deferred for queued submissions
+    let remote_url = match args.remote() {
+        Some(remote) if queue && discovery::is_domain(&remote.url) => None,
+        Some(remote) => match remote.resolve_url(&cfg, &drop) {
+            Ok(url) => Some(url),
+            Err(e) if remote.queue_if_unreachable && outbox::is_unreachable(&e) => {
+                warn!("Discovery failed: {e:#}");
+                queue = true;
+                None
+            },
+            Err(e) => return Err(e),
         },
-        None if repo.target().is_bare() => REF_HEADS_PATCHES.into(),
-        None => REF_IT_PATCHES.into(),
+        None => None,
     };
 
-    let mut signer = cfg::git::signer(&repo.source().config()?, ui::askpass)?;
-    let drop = patches::DropHead::from_refname(repo.target(), &drop_ref)?;
+    if args.common().force_drop {
+        debug!("--force-drop given, not checking drop binding");
+    } else {
+        auth::ensure_allowed_drop(
+            &cfg::git::allowed_drops(&cfg, &signer_id)?,
+            &drop.meta,
+            &signer_id,
+            remote_url.as_ref(),
+        )?;
+    }
+    // Fail before invoking $EDITOR if we know the patch can't be accepted
+    if let Kind::Merges { .. } = &args {
+        auth::ensure_role(
+            &drop.meta,
+            &signer_id,
+            RoleName::AnyBranch,
+            "create merge checkpoints",
+        )?;
+    }
+    if let Kind::Comment {
+        comment: Comment {
+            note: Some(note), ..
+        },
+        ..
+    } = &args
+    {
+        if note.topic_state().is_some() {
+            auth::ensure_role(
+                &drop.meta,
+                &signer_id,
+                RoleName::AnyBranch,
+                "change the state of topics",
+            )?;
+        }
+    }
+    if args.remote().is_none() {
+        auth::ensure_role(
+            &drop.meta,
+            &signer_id,
+            RoleName::Snapshot,
+            "record patches in the local drop",
+        )?;
+    }
+
+    let mut preview = false;
+    if let Kind::Patch { patch, .. } = &mut args {
+        if patch.interactive {
+            wizard::run(&repo, &drop, patch)?;
+            preview = true;
+        }
+    }
 
+    let mut patch_spec = None;
+    let mut message = args.common().message.clone();
     let spec = match &args {
         Kind::Merges { force, .. } => prepare::Kind::Mergepoint { force: *force },
-        Kind::Snapshot { .. } => prepare::Kind::Snapshot { incremental: true },
+        Kind::Snapshot { full, .. } => prepare::Kind::Snapshot { incremental: !full },
         Kind::Comment { comment, .. } => prepare::Kind::Comment {
             topic: comment.topic.clone(),
             reply: comment.reply_to,
+            draft: (args.common().message.is_none() && comment.note.is_none())
+                .then(|| Draft::new(repo.target().path(), &comment.topic, &signer_id)),
+            note: comment.note.clone(),
         },
         Kind::Patch { patch, .. } => {
             let (name, base_ref) = dwim_base(
@@ -357,12 +665,59 @@ pub fn create(args: Kind) -> cmd::Result<patches::Record> {
                 .peel_to_commit()?
                 .id();
 
-            prepare::Kind::Patch {
+            let spec = resubmit::Spec {
                 head,
                 base,
                 name,
-                re: patch.topic.as_ref().map(|t| (t.clone(), patch.reply_to)),
+                topic: patch.topic.clone(),
+                reply_to: patch.reply_to,
+                large_blobs: large_blobs::Options {
+                    threshold: patch.large_blob_threshold,
+                    strip: patch.strip_large_blobs,
+                },
+            };
+            patch_spec = Some(spec.clone());
+            spec.into()
+        },
+        Kind::Mbox { mbox, .. } => {
+            let (name, base_ref) = dwim_base(
+                repo.target(),
+                &drop,
+                mbox.topic.as_ref(),
+                mbox.reply_to,
+                mbox.base.as_deref(),
+            )?
+            .ok_or_else(|| anyhow!("unable to determine base branch"))?;
+            let base = repo
+                .target()
+                .find_reference(&base_ref)?
+                .peel_to_commit()?
+                .id();
+            let series = patches::mbox::parse(&fs::read(&mbox.mbox)?)?;
+            info!(
+                "Applying {} patches from {} onto {name}",
+                series.patches.len(),
+                mbox.mbox.display()
+            );
+            let head = patches::mbox::apply(repo.source(), base, &series.patches)?;
+            if message.is_none() {
+                message = series.cover_letter.as_ref().map(|cover| cover.message());
             }
+
+            let spec = resubmit::Spec {
+                head,
+                base,
+                name,
+                topic: mbox.topic.clone(),
+                reply_to: mbox.reply_to,
+                large_blobs: Default::default(),
+            };
+            patch_spec = Some(spec.clone());
+            spec.into()
+        },
+        Kind::Resubmit { spec, .. } => {
+            patch_spec = Some(spec.clone());
+            spec.clone().into()
         },
     };
 
@@ -372,41 +727,192 @@ pub fn create(args: Kind) -> cmd::Result<patches::Record> {
         prepare::Submitter {
             signer: &mut signer,
             id: signer_id,
+            acknowledge_policy: args.common().acknowledge_policy,
         },
+        hash_algorithm,
     )
-    .prepare_patch(
-        &bundle_dir,
-        spec,
-        args.common().message.clone(),
-        &args.common().ids,
-    )?;
+    .encrypt_to(args.common().encrypt_to.clone())
+    .preview(preview)
+    .prepare_patch(&bundle_dir, spec, message.clone(), &args.common().ids)?;
 
     if args.common().dry_run {
         info!("--dry-run given, stopping here");
         cmd::abort!();
     }
 
-    match args.remote() {
-        Some(remote) => patch.submit(remote.url.clone()),
-        None => patch.try_accept(patches::AcceptArgs {
-            unbundle_prefix: REF_IT_BUNDLES,
-            drop_ref: &drop_ref,
-            seen_ref: REF_IT_SEEN,
-            repo: repo.target(),
-            signer: &mut signer,
-            ipfs_api: args.common().ipfs_api.as_ref(),
-            options: args.accept_options(&drop),
-        }),
+    let queued = args.remote().map(|remote| {
+        let url = remote_url
+            .as_ref()
+            .map_or_else(|| remote.url.clone(), |url| url.to_string());
+        outbox::Queued::new(url, drop_ref.clone(), bundle_dir.clone(), &patch)
+    });
+    let enqueue = |queued: &outbox::Queued| -> cmd::Result<()> {
+        let path = outbox::enqueue(repo.target(), queued)?;
+        info!("Queued submission at {}", path.display());
+        info!("Use `it outbox push` to submit");
+        if let Kind::Comment { comment, .. } = &args {
+            if let Err(e) = Draft::new(repo.target().path(), &comment.topic, &signer_id).remove() {
+                warn!("Failed to remove draft: {e:#}");
+            }
+        }
+        Ok(())
+    };
+    if queue {
+        if let Some(queued) = &queued {
+            enqueue(queued)?;
+            cmd::abort!();
+        }
+    }
+
+    let record = match remote_url {
+        Some(url) => {
+            let saved = patch_spec.map(|spec| resubmit::Saved {
+                url: url.clone(),
+                drop_ref: drop_ref.clone(),
+                src_dir: repo.source().path().to_owned(),
+                id: signer_id,
+                id_path: args.common().id_path.clone(),
+                bundle_dir: args.common().bundle_dir.clone(),
+                ids: args.common().ids.clone(),
+                encrypt_to: args.common().encrypt_to.clone(),
+                message: message.or_else(|| cover_letter(repo.source(), &patch)),
+                spec,
+                error: String::new(),
+            });
+            match patch.submit(url) {
+                Ok(record) => {
+                    resubmit::clear(repo.target())?;
+                    Ok(record)
+                },
+                Err(e) => {
+                    let unreachable = args.remote().map_or(false, |remote| {
+                        remote.queue_if_unreachable && outbox::is_unreachable(&e)
+                    });
+                    if let Some(queued) = queued.as_ref().filter(|_| unreachable) {
+                        warn!("Submission failed: {e:#}");
+                        enqueue(queued)?;
+                        cmd::abort!();
+                    }
+                    if let Some(mut saved) = saved {
+                        saved.error = format!("{e:#}");
+                        match resubmit::save(repo.target(), &saved) {
+                            Ok(()) => info!("Use `it patch resubmit` to retry"),
+                            Err(e) => warn!("Failed to save submission parameters: {e:#}"),
+                        }
+                    }
+                    Err(e)
+                },
+            }
+        },
+        None => {
+            let record = patch.try_accept(patches::AcceptArgs {
+                unbundle_prefix: REF_IT_BUNDLES,
+                drop_ref: &drop_ref,
+                seen_ref: REF_IT_SEEN,
+                repo: repo.target(),
+                signer: &mut signer,
+                ipfs_api: args.common().ipfs_api.as_ref(),
+                drop_cache: None,
+                reachable_cache: None,
+                options: args.accept_options(&drop, &cfg::git::open(repo.target())?)?,
+            })?;
+            if let Err(e) = maintain(repo.target()) {
+                warn!("Maintenance failed: {e:#}");
+            }
+            if let Err(e) = patches::pins::retry(repo.target().path()) {
+                warn!("Retrying pending IPFS pins failed: {e:#}");
+            }
+
+            Ok(record)
+        },
+    }?;
+    if let Kind::Comment { comment, .. } = &args {
+        if let Err(e) = Draft::new(repo.target().path(), &comment.topic, &signer_id).remove() {
+            warn!("Failed to remove draft: {e:#}");
+        }
+    }
+
+    Ok(record)
+}
+
+/// The cover letter of the patch, if it is a basic note
+fn cover_letter(repo: &git2::Repository, patch: &patches::Submission) -> Option<String> {
+    let header = patch.bundle.header();
+    let (_, oid) = header
+        .references
+        .iter()
+        .find(|(name, _)| name.starts_with(REF_IT_TOPICS))?;
+    let commit = repo.find_commit(git2::Oid::try_from(oid).ok()?).ok()?;
+    match notes::Simple::from_commit(repo, &commit).ok()? {
+        notes::Simple::Known(notes::Predef::Basic { message }) => Some(message),
+        _ => None,
     }
 }
 
-fn dwim_base(
+fn maintain(repo: &git2::Repository) -> cmd::Result<()> {
+    let interval = cfg::git::maintenance_interval(&cfg::git::open(&repo)?)?;
+    if git::maintenance::record_accept(repo.path(), interval)? {
+        git::maintenance::run(repo.path())?;
+    }
+
+    Ok(())
+}
+
+pub(super) fn dwim_base(
     repo: &git2::Repository,
     drop: &DropHead,
     topic: Option<&Topic>,
     reply_to: Option<git2::Oid>,
     base: Option<&str>,
 ) -> cmd::Result<Option<(Refname, Refname)>> {
+    let candidates = base_candidates(repo, drop, topic, reply_to)?;
+
+    const FMTS: &[fn(&str) -> String] = &[
+        |s| s.to_owned(),
+        |s| format!("refs/{}", s),
+        |s| format!("refs/heads/{}", s),
+        |s| format!("refs/tags/{}", s),
+    ];
+
+    debug!("dwim candidates: {candidates:#?}");
+
+    match base {
+        Some(base) => {
+            for (virt, act) in candidates {
+                for f in FMTS {
+                    let name = f(base);
+                    if name == virt {
+                        let refname = name.parse()?;
+                        return Ok(Some((refname, act)));
+                    }
+                }
+            }
+            Ok(None)
+        },
+
+        // nb. biased towards "main" because we use a BTreeMap
+        None => Ok(candidates.into_iter().find_map(|(k, _)| match k.as_str() {
+            "refs/heads/main" => Some((Refname::main(), TrackingBranch::main().into_refname())),
+            "refs/heads/master" => {
+                Some((Refname::master(), TrackingBranch::master().into_refname()))
+            },
+            _ => None,
+        })),
+    }
+}
+
+/// The branches a patch may be based on, by name, along with the ref holding
+/// their tip
+///
+/// If `topic` is given, these are the branches of the patch which `reply_to`
+/// (or the default entry to reply to) belongs to. Otherwise, they are the
+/// branches of the drop.
+fn base_candidates(
+    repo: &git2::Repository,
+    drop: &DropHead,
+    topic: Option<&Topic>,
+    reply_to: Option<git2::Oid>,
+) -> cmd::Result<BTreeMap<String, Refname>> {
     let mut candidates = BTreeMap::new();
     match topic {
         Some(topic) => {
@@ -448,36 +954,5 @@ fn dwim_base(
         ),
     };
 
-    const FMTS: &[fn(&str) -> String] = &[
-        |s| s.to_owned(),
-        |s| format!("refs/{}", s),
-        |s| format!("refs/heads/{}", s),
-        |s| format!("refs/tags/{}", s),
-    ];
-
-    debug!("dwim candidates: {candidates:#?}");
-
-    match base {
-        Some(base) => {
-            for (virt, act) in candidates {
-                for f in FMTS {
-                    let name = f(base);
-                    if name == virt {
-                        let refname = name.parse()?;
-                        return Ok(Some((refname, act)));
-                    }
-                }
-            }
-            Ok(None)
-        },
-
-        // nb. biased towards "main" because we use a BTreeMap
-        None => Ok(candidates.into_iter().find_map(|(k, _)| match k.as_str() {
-            "refs/heads/main" => Some((Refname::main(), TrackingBranch::main().into_refname())),
-            "refs/heads/master" => {
-                Some((Refname::master(), TrackingBranch::master().into_refname()))
-            },
-            _ => None,
-        })),
-    }
+    Ok(candidates)
 }
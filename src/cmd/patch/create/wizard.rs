@@ -0,0 +1,177 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Interactive preparation of a patch, cf. `--interactive`
+
+use anyhow::anyhow;
+
+use super::{
+    base_candidates,
+    dwim_base,
+    prepare,
+    Patch,
+};
+use crate::{
+    cmd::{
+        self,
+        ui::{
+            self,
+            info,
+            warn,
+            Msg,
+        },
+    },
+    patches::{
+        iter,
+        notes::{
+            self,
+            TopicState,
+        },
+        DropHead,
+        TOPIC_MERGES,
+        TOPIC_SNAPSHOTS,
+    },
+};
+
+/// Max number of commits of the patch to show
+const MAX_RANGE: usize = 20;
+
+/// Fill in `patch` by asking the user, offering its current values as defaults
+pub(super) fn run(repo: &prepare::Repo, drop: &DropHead, patch: &mut Patch) -> cmd::Result<()> {
+    choose_topic(repo.target(), patch)?;
+    choose_base(repo.target(), drop, patch)?;
+    choose_head(repo, drop, patch)
+}
+
+fn choose_topic(repo: &git2::Repository, patch: &mut Patch) -> cmd::Result<()> {
+    let topic = match patch.topic.clone() {
+        Some(topic) => topic,
+        None => {
+            let mut topics = iter::unbundled::topics_with_subject(repo)
+                .filter(|t| {
+                    !matches!(t, Ok((t, _, _)) if *t == *TOPIC_MERGES || *t == *TOPIC_SNAPSHOTS)
+                })
+                .collect::<cmd::Result<Vec<_>>>()?;
+            if topics.is_empty() {
+                return Ok(());
+            }
+            let items = topics
+                .iter()
+                .map(|(topic, subject, state)| match state {
+                    TopicState::Open => format!("{topic} {subject}"),
+                    _ => format!("{topic} [{state}] {subject}"),
+                })
+                .collect::<Vec<_>>();
+            match ui::select(Msg::WizardTopic.text(), &items, None)? {
+                Some(i) => topics.swap_remove(i).0,
+                None => return Ok(()),
+            }
+        },
+    };
+
+    let notes = iter::topic(repo, &topic).collect::<cmd::Result<Vec<_>>>()?;
+    let default = match patch.reply_to {
+        Some(id) => Some(id),
+        None => iter::topic::default_reply_to(repo, &topic)?,
+    }
+    .and_then(|id| notes.iter().position(|note| note.header.id == id))
+    .unwrap_or(0);
+    let items = notes
+        .iter()
+        .map(|iter::Note { header, message }| {
+            let subject = match message {
+                notes::Note::Simple(simple) => simple.subject(),
+                notes::Note::Automerge(_) => None,
+            };
+            format!(
+                "{:.7} {} {}: {}",
+                header.id,
+                header.time.date(),
+                header.author.name,
+                subject.unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>();
+    let i = ui::select(Msg::WizardReplyTo.text(), &items, Some(default))?.unwrap_or(default);
+
+    patch.reply_to = Some(notes[i].header.id);
+    patch.topic = Some(topic);
+
+    Ok(())
+}
+
+fn choose_base(repo: &git2::Repository, drop: &DropHead, patch: &mut Patch) -> cmd::Result<()> {
+    let candidates = base_candidates(repo, drop, patch.topic.as_ref(), patch.reply_to)?;
+    let names = candidates.into_keys().collect::<Vec<_>>();
+    if names.is_empty() {
+        return Err(anyhow!("no branches to base the patch on"));
+    }
+    let default = dwim_base(
+        repo,
+        drop,
+        patch.topic.as_ref(),
+        patch.reply_to,
+        patch.base.as_deref(),
+    )?
+    .and_then(|(name, _)| names.iter().position(|n| *n == *name))
+    .unwrap_or(0);
+    let i = ui::select(Msg::WizardBase.text(), &names, Some(default))?.unwrap_or(default);
+    patch.base = Some(names[i].clone());
+
+    Ok(())
+}
+
+fn choose_head(repo: &prepare::Repo, drop: &DropHead, patch: &mut Patch) -> cmd::Result<()> {
+    let (name, base_ref) = dwim_base(
+        repo.target(),
+        drop,
+        patch.topic.as_ref(),
+        patch.reply_to,
+        patch.base.as_deref(),
+    )?
+    .ok_or_else(|| anyhow!("unable to determine base branch"))?;
+    let base = repo
+        .target()
+        .find_reference(&base_ref)?
+        .peel_to_commit()?
+        .id();
+
+    loop {
+        let head = ui::prompt(Msg::WizardHead.text(), &patch.head)?;
+        let commit = match repo
+            .source()
+            .revparse_single(&head)
+            .and_then(|obj| obj.peel_to_commit())
+        {
+            Ok(commit) => commit,
+            Err(e) => {
+                warn!("{}", e.message());
+                continue;
+            },
+        };
+
+        let mut walk = repo.source().revwalk()?;
+        walk.push(commit.id())?;
+        walk.hide(base)?;
+        let range = walk.collect::<Result<Vec<_>, _>>()?;
+        if range.is_empty() {
+            warn!("{}", Msg::WizardEmptyRange.fmt(&[("base", &name)]));
+            continue;
+        }
+
+        info!(
+            "{}",
+            Msg::WizardRange.fmt(&[("count", &range.len()), ("base", &name)])
+        );
+        for oid in range.iter().take(MAX_RANGE) {
+            let commit = repo.source().find_commit(*oid)?;
+            info!("  {:.7} {}", oid, commit.summary().unwrap_or_default());
+        }
+        if range.len() > MAX_RANGE {
+            info!("  ...");
+        }
+
+        patch.head = head;
+        return Ok(());
+    }
+}
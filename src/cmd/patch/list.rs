@@ -0,0 +1,353 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    fmt::Write as _,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::anyhow;
+use clap::ValueHint;
+use time::format_description::well_known::Rfc2822;
+
+use crate::{
+    bundle::ObjectId,
+    cmd::{
+        self,
+        ui::warn,
+        util::args::Refname,
+    },
+    git,
+    patches::{
+        self,
+        iter::{
+            dropped,
+            Note,
+        },
+        notes,
+        record::{
+            Diffstat,
+            Heads,
+        },
+        Record,
+        Topic,
+        GLOB_IT_TOPICS,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct List {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Only list patches to this topic; may be given multiple times
+    #[clap(long = "topic", value_parser, value_name = "TOPIC")]
+    topics: Vec<Topic>,
+    /// Output format
+    ///
+    /// 'mbox' and 'maildir' render each note of the selected topics as an
+    /// email, followed by the commits of the patch it was submitted with (if
+    /// any) in 'git format-patch' style. Patches must be unbundled for their
+    /// commits to be included.
+    #[clap(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+    /// Write the mbox to this file, or the messages to this maildir, instead
+    /// of stdout
+    #[clap(
+        short,
+        long,
+        value_parser,
+        value_name = "PATH",
+        value_hint = ValueHint::AnyPath,
+        required_if_eq("format", "maildir"),
+    )]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    /// One JSON object per patch
+    Json,
+    /// A single mbox
+    Mbox,
+    /// One file per message in a maildir
+    Maildir,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    topic: Topic,
+    id: Heads,
+    /// The refs conveyed by the patch, excluding the topic
+    refs: BTreeMap<Refname, ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diffstat: Option<Diffstat>,
+}
+
+pub fn list(args: List) -> cmd::Result<cmd::Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let records = dropped::records_rev(&repo, &args.drop_ref)
+        .filter(|record| match record {
+            Ok(record) => {
+                !record.is_mergepoint()
+                    && !record.is_snapshot()
+                    && (args.topics.is_empty() || args.topics.contains(&record.topic))
+            },
+            Err(_) => true,
+        })
+        .collect::<cmd::Result<Vec<_>>>()?;
+
+    let messages = match args.format {
+        Format::Json => {
+            let is_topic = GLOB_IT_TOPICS.compile_matcher();
+            return Ok(cmd::Output::iter(records.into_iter().map(move |record| {
+                let refs = record
+                    .meta
+                    .bundle
+                    .references
+                    .into_iter()
+                    .filter(|(name, _)| !is_topic.is_match(name))
+                    .collect();
+                Ok(Output {
+                    topic: record.topic,
+                    id: record.heads,
+                    refs,
+                    diffstat: record.meta.diffstat,
+                })
+            })));
+        },
+        Format::Mbox | Format::Maildir => render(&repo, &records)?,
+    };
+
+    match (args.format, args.output) {
+        (Format::Maildir, Some(dir)) => {
+            write_maildir(&dir, &messages)?;
+            Ok(cmd::Output::val(dir))
+        },
+        (_, Some(path)) => {
+            fs::write(&path, messages.concat())?;
+            Ok(cmd::Output::val(path))
+        },
+        (_, None) => Ok(cmd::Output::Text(messages.concat())),
+    }
+}
+
+/// Render the notes of the topics of `records` as emails, oldest first
+///
+/// The first note on a patch is followed by the patch's commits, threaded as
+/// replies to it.
+fn render(repo: &git2::Repository, records: &[Record]) -> cmd::Result<Vec<String>> {
+    let by_heads = records
+        .iter()
+        .map(|record| (record.heads, record))
+        .collect::<HashMap<_, _>>();
+    let mut topics = Vec::new();
+    for record in records {
+        if !topics.contains(&&record.topic) {
+            topics.push(&record.topic);
+        }
+    }
+
+    let mut messages = Vec::new();
+    for topic in topics {
+        let subject = patches::iter::unbundled::find_subject(repo, &topic.as_refname())?;
+        let mut rendered = Vec::new();
+        for note in patches::iter::topic(repo, topic).rev() {
+            let note = note?;
+            let patch = note.header.patch.id;
+            messages.push(note_message(topic, &subject, &note)?);
+            if rendered.contains(&patch) {
+                continue;
+            }
+            rendered.push(patch);
+            match by_heads.get(&patch) {
+                Some(record) => {
+                    messages.extend(patch_messages(repo, topic, record, note.header.id)?)
+                },
+                None => warn!("No record found for patch {patch}"),
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+fn note_message(topic: &Topic, subject: &str, note: &Note) -> cmd::Result<String> {
+    let hdr = &note.header;
+    let body = match &note.message {
+        notes::Note::Simple(notes::Simple::Known(notes::Predef::Basic { message })) => {
+            message.clone()
+        },
+        notes::Note::Simple(notes::Simple::Known(notes::Predef::CodeComment { loc, message })) => {
+            let mut body = format!("On {}", loc.file);
+            if let Some(line) = &loc.line {
+                write!(body, " lines {}-{}", line.start, line.end)?;
+            }
+            format!("{body}:\n\n{message}")
+        },
+        other => serde_json::to_string_pretty(other)?,
+    };
+
+    let mut msg = String::new();
+    writeln!(msg, "From {} Mon Sep 17 00:00:00 2001", hdr.id)?;
+    writeln!(msg, "From: {} <{}>", hdr.author.name, hdr.author.email)?;
+    writeln!(msg, "Date: {}", hdr.time.format(&Rfc2822)?)?;
+    match hdr.in_reply_to {
+        None => writeln!(msg, "Subject: {subject}")?,
+        Some(parent) => {
+            writeln!(msg, "Subject: Re: {subject}")?;
+            writeln!(msg, "In-Reply-To: {}", message_id(topic, &parent))?;
+        },
+    }
+    writeln!(msg, "Message-ID: {}", message_id(topic, &hdr.id))?;
+    writeln!(msg, "X-It-Topic: {topic}")?;
+    writeln!(msg, "X-It-Patch: {}", hdr.patch.id)?;
+    writeln!(msg)?;
+    push_body(&mut msg, &body);
+
+    Ok(msg)
+}
+
+/// Render the commits of the patch `record` as 'git format-patch' emails in
+/// reply to the note `cover`
+fn patch_messages(
+    repo: &git2::Repository,
+    topic: &Topic,
+    record: &Record,
+    cover: git2::Oid,
+) -> cmd::Result<Vec<String>> {
+    let is_topic = GLOB_IT_TOPICS.compile_matcher();
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    let mut any = false;
+    for (name, oid) in &record.meta.bundle.references {
+        if is_topic.is_match(name) {
+            continue;
+        }
+        let oid = git2::Oid::try_from(oid)?;
+        if !repo.odb()?.exists(oid) {
+            warn!(
+                "{name} of patch {} is not present locally, try unbundling it",
+                record.heads
+            );
+            continue;
+        }
+        walk.push(oid)?;
+        any = true;
+    }
+    if !any {
+        return Ok(vec![]);
+    }
+    for oid in &record.meta.bundle.prerequisites {
+        walk.hide(oid.try_into()?)?;
+    }
+    let commits = walk
+        .map(|oid| Ok(repo.find_commit(oid?)?))
+        .collect::<cmd::Result<Vec<_>>>()?;
+
+    let count = commits.len();
+    let mut messages = Vec::with_capacity(count);
+    for (i, commit) in commits.iter().enumerate() {
+        if commit.parent_count() > 1 {
+            warn!("Skipping merge commit {}", commit.id());
+            continue;
+        }
+        let parent = commit.parents().next().map(|p| p.tree()).transpose()?;
+        let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+        let email = git2::Email::from_diff(
+            &diff,
+            i + 1,
+            count,
+            &commit.id(),
+            commit.summary().unwrap_or_default(),
+            commit.body().unwrap_or_default(),
+            &commit.author(),
+            &mut git2::EmailCreateOptions::new(),
+        )?;
+        let email = std::str::from_utf8(email.as_slice())
+            .map_err(|e| anyhow!("commit {} is not valid UTF-8: {e}", commit.id()))?;
+        // Splice in the threading headers after the mbox 'From ' line
+        let (from, rest) = email.split_once('\n').unwrap_or((email, ""));
+        let mut msg = format!("{from}\n");
+        writeln!(msg, "Message-ID: {}", message_id(topic, &commit.id()))?;
+        writeln!(msg, "In-Reply-To: {}", message_id(topic, &cover))?;
+        writeln!(msg, "X-It-Topic: {topic}")?;
+        writeln!(msg, "X-It-Patch: {}", record.heads)?;
+        match rest.split_once("\n\n") {
+            Some((headers, body)) => {
+                msg.push_str(headers);
+                msg.push_str("\n\n");
+                push_body(&mut msg, body);
+            },
+            None => push_body(&mut msg, rest),
+        }
+        messages.push(msg);
+    }
+
+    Ok(messages)
+}
+
+fn message_id(topic: &Topic, oid: &git2::Oid) -> String {
+    format!("<{oid}@{topic}.it>")
+}
+
+/// Append `body` to `msg`, quoting lines which would otherwise be mistaken for
+/// the start of a new message (mboxrd)
+fn push_body(msg: &mut String, body: &str) {
+    for line in body.lines() {
+        if line.trim_start_matches('>').starts_with("From ") {
+            msg.push('>');
+        }
+        msg.push_str(line);
+        msg.push('\n');
+    }
+    msg.push('\n');
+}
+
+fn write_maildir(dir: &Path, messages: &[String]) -> cmd::Result<()> {
+    for sub in ["tmp", "new", "cur"] {
+        fs::create_dir_all(dir.join(sub))?;
+    }
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let pid = std::process::id();
+    for (i, msg) in messages.iter().enumerate() {
+        let name = format!("{now}.P{pid}Q{i}.it");
+        let tmp = dir.join("tmp").join(&name);
+        // Strip the mbox 'From ' line, and the mboxrd quoting
+        let body = msg
+            .split_once('\n')
+            .map(|(_, rest)| rest)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| match line.strip_prefix('>') {
+                Some(unquoted) if unquoted.trim_start_matches('>').starts_with("From ") => unquoted,
+                _ => line,
+            })
+            .fold(String::with_capacity(msg.len()), |mut acc, line| {
+                acc.push_str(line);
+                acc.push('\n');
+                acc
+            });
+        fs::write(&tmp, body)?;
+        fs::rename(&tmp, dir.join("new").join(&name))?;
+    }
+
+    Ok(())
+}
@@ -11,21 +11,22 @@ use anyhow::{
     bail,
     ensure,
 };
-use digest::Digest;
-use either::Either::Left;
-use sha2::Sha256;
 
 use crate::{
     bundle,
     cmd::{
         self,
+        drafts::Draft,
         ui::{
+            confirm,
             debug,
             edit_comment,
             edit_cover_letter,
             info,
             warn,
+            Msg,
         },
+        Aborted,
     },
     git::{
         self,
@@ -49,12 +50,20 @@ use crate::{
     },
     patches::{
         self,
+        composite,
         iter::{
             dropped,
             topic,
         },
+        large_blobs::{
+            self,
+            LargeBlob,
+        },
         notes,
+        policy,
         record,
+        IdentityRevision,
+        PolicyAck,
         Topic,
         REF_IT_BUNDLES,
         REF_IT_PATCHES,
@@ -75,16 +84,25 @@ pub enum Kind {
         base: git2::Oid,
         name: Refname,
         re: Option<(Topic, Option<git2::Oid>)>,
+        large_blobs: large_blobs::Options,
     },
     Comment {
         topic: Topic,
         reply: Option<git2::Oid>,
+        /// Where to keep the comment text entered in the editor
+        draft: Option<Draft>,
+        /// Post this note instead of a free-form comment, with the message
+        /// attached
+        note: Option<notes::Simple>,
     },
 }
 
 pub struct Submitter<'a, S: ?Sized> {
     pub signer: &'a mut S,
     pub id: IdentityId,
+    /// Whether the submitter agrees to the usage policy of the drop, if it
+    /// has one
+    pub acknowledge_policy: bool,
 }
 
 pub struct Repo {
@@ -122,6 +140,11 @@ pub struct Preparator<'a, S: ?Sized> {
     repo: &'a Repo,
     drop: &'a patches::DropHead<'a>,
     submitter: Submitter<'a, S>,
+    hash_algorithm: bundle::HashAlgorithm,
+    encrypt_to: Vec<String>,
+    preview: bool,
+    policy_ack: Option<PolicyAck>,
+    composite: Option<(Topic, composite::Manifest)>,
 }
 
 impl<'a, S: Signer> Preparator<'a, S> {
@@ -129,14 +152,42 @@ impl<'a, S: Signer> Preparator<'a, S> {
         repo: &'a Repo,
         drop: &'a patches::DropHead<'a>,
         submitter: Submitter<'a, S>,
+        hash_algorithm: bundle::HashAlgorithm,
     ) -> Self {
         Self {
             repo,
             drop,
             submitter,
+            hash_algorithm,
+            encrypt_to: vec![],
+            preview: false,
+            policy_ack: None,
+            composite: None,
         }
     }
 
+    /// Encrypt the packdata of the patch bundle to the given age recipients
+    pub fn encrypt_to(mut self, recipients: Vec<String>) -> Self {
+        self.encrypt_to = recipients;
+        self
+    }
+
+    /// Show the contents of the patch bundle, and ask for confirmation before
+    /// signing it
+    pub fn preview(mut self, preview: bool) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// Prepare a part of a composite patch
+    ///
+    /// The patch is posted to `topic` unless it is a reply, and its cover
+    /// letter carries `manifest`.
+    pub fn composite(mut self, topic: Topic, manifest: composite::Manifest) -> Self {
+        self.composite = Some((topic, manifest));
+        self
+    }
+
     pub fn prepare_patch(
         &mut self,
         bundle_dir: &Path,
@@ -144,16 +195,30 @@ impl<'a, S: Signer> Preparator<'a, S> {
         message: Option<String>,
         additional_ids: &[IdentityId],
     ) -> cmd::Result<patches::Submission> {
+        // Check before the user is asked to write a cover letter
+        self.policy_ack = self.policy_ack()?;
         let mut header = bundle::Header::default();
 
         match kind {
             Kind::Mergepoint { force } => {
-                mergepoint(self.repo, &self.drop.meta, &mut header, force)?;
+                let commits = mergepoint(self.repo, &self.drop.meta, &mut header, force)?;
                 ensure!(
                     !header.references.is_empty(),
                     "refusing to create empty checkpoint"
                 );
-                self.annotate_checkpoint(&mut header, &TOPIC_MERGES, message)?;
+                let squashed = patches::squash::detect(
+                    self.repo.target(),
+                    self.drop.tip.name().unwrap_or(REF_IT_PATCHES),
+                    self.repo.source(),
+                    commits,
+                )?;
+                for sq in &squashed {
+                    info!(
+                        "Patch {} of {} was squash-merged as {}",
+                        sq.patch, sq.topic, sq.commit
+                    );
+                }
+                self.annotate_checkpoint(&mut header, &TOPIC_MERGES, message, squashed)?;
             },
             Kind::Snapshot { incremental } => {
                 snapshot(self.repo, &mut header, incremental)?;
@@ -161,48 +226,54 @@ impl<'a, S: Signer> Preparator<'a, S> {
                     !header.references.is_empty(),
                     "refusing to create empty snapshot"
                 );
-                self.annotate_checkpoint(&mut header, &TOPIC_SNAPSHOTS, message)?;
+                self.annotate_checkpoint(&mut header, &TOPIC_SNAPSHOTS, message, vec![])?;
             },
             Kind::Patch {
                 head,
                 base,
                 name,
                 re,
+                large_blobs,
             } => {
                 ensure!(base != head, "refusing to create empty patch");
                 ensure!(
                     if_not_found_none(self.repo.source().merge_base(base, head))?.is_some(),
                     "{base} is not reachable from {head}"
                 );
+                let (head, stripped) = strip_large_blobs(self.repo, base, head, large_blobs)?;
                 info!("Adding patch for {name}: {base}..{head}");
                 header.add_prerequisite(&base);
                 header.add_reference(name, &head);
-                self.annotate_patch(&mut header, message, re)?;
+                self.annotate_patch(&mut header, message, re, &stripped)?;
+            },
+            Kind::Comment {
+                topic,
+                reply,
+                draft,
+                note: None,
+            } => {
+                self.annotate_comment(&mut header, topic, message, reply, draft.as_ref())?;
             },
-            Kind::Comment { topic, reply } => {
-                self.annotate_comment(&mut header, topic, message, reply)?;
+            Kind::Comment {
+                topic,
+                reply,
+                note: Some(mut note),
+                ..
+            } => {
+                let parent = find_reply_to(self.repo, &topic, reply)?;
+                note.set_message(message);
+                self.annotate(&mut header, &topic, Some(parent), &note)?;
             },
         }
 
         for id in additional_ids {
-            Identity::find(
-                self.repo.target(),
-                &self.drop.ids,
-                self.repo.id_path(),
-                cmd::id::identity_ref(Left(id))?,
-            )?
-            .update(&mut header);
+            Identity::find(self.repo.target(), &self.drop.ids, self.repo.id_path(), id)?
+                .update(&mut header);
         }
 
         let signer_hash = {
             let keyid = self.submitter.signer.ident().keyid();
-            let id_ref = cmd::id::identity_ref(Left(&self.submitter.id))?;
-            let id = Identity::find(
-                self.repo.target(),
-                &self.drop.ids,
-                self.repo.id_path(),
-                id_ref,
-            )?;
+            let id = self.submitter_identity()?;
             ensure!(
                 id.contains(&keyid),
                 "signing key {keyid} not in identity {}",
@@ -213,7 +284,28 @@ impl<'a, S: Signer> Preparator<'a, S> {
             id.hash().clone()
         };
 
-        let bundle = patches::Bundle::create(bundle_dir, self.repo.source(), header)?;
+        if self.preview {
+            info!("{}", Msg::WizardHeader.text());
+            for (name, oid) in &header.references {
+                info!("  {oid} {name}");
+            }
+            for oid in &header.prerequisites {
+                info!("  -{oid}");
+            }
+            if !confirm(Msg::WizardProceed.text())? {
+                cmd::abort!();
+            }
+        }
+
+        let mut bundle =
+            patches::Bundle::create(bundle_dir, self.repo.source(), header, self.hash_algorithm)?;
+        if !self.encrypt_to.is_empty() {
+            bundle.encrypt_age(&self.encrypt_to)?;
+            info!(
+                "Encrypted patch bundle to {} recipient(s)",
+                self.encrypt_to.len()
+            );
+        }
         let signature = bundle
             .sign(self.submitter.signer)
             .map(|signature| patches::Signature {
@@ -224,11 +316,43 @@ impl<'a, S: Signer> Preparator<'a, S> {
         Ok(patches::Submission { signature, bundle })
     }
 
+    /// The trailer acknowledging the usage policy of the drop, if the
+    /// submitter has yet to acknowledge it
+    fn policy_ack(&self) -> cmd::Result<Option<PolicyAck>> {
+        let policy = match self.drop.policy {
+            None => return Ok(None),
+            Some(policy) => policy,
+        };
+        let id = &self.submitter.id;
+        if policy::is_exempt(&self.drop.meta, id)
+            || policy::is_acknowledged(self.repo.target(), self.drop.acks.as_ref(), id, policy)?
+        {
+            return Ok(None);
+        }
+        ensure!(
+            self.submitter.acknowledge_policy,
+            "the drop requires acknowledging its usage policy {policy}: review it using 'it drop show', and pass --acknowledge-policy to agree to it"
+        );
+        info!("Acknowledging usage policy {policy}");
+
+        Ok(Some(PolicyAck::new(policy)))
+    }
+
+    fn submitter_identity(&self) -> cmd::Result<Identity> {
+        Identity::find(
+            self.repo.target(),
+            &self.drop.ids,
+            self.repo.id_path(),
+            &self.submitter.id,
+        )
+    }
+
     fn annotate_checkpoint(
         &mut self,
         bundle: &mut bundle::Header,
         topic: &Topic,
         message: Option<String>,
+        squashed: Vec<notes::Squashed>,
     ) -> cmd::Result<()> {
         let kind = if topic == &*TOPIC_MERGES {
             notes::CheckpointKind::Merge
@@ -237,7 +361,7 @@ impl<'a, S: Signer> Preparator<'a, S> {
         } else {
             bail!("not a checkpoint topic: {topic}")
         };
-        let note = notes::Simple::checkpoint(kind, bundle.references.clone(), message);
+        let note = notes::Simple::checkpoint(kind, bundle.references.clone(), message, squashed);
         let parent = topic::default_reply_to(self.repo.target(), topic)?
             .map(|id| self.repo.source().find_commit(id))
             .transpose()?;
@@ -250,24 +374,33 @@ impl<'a, S: Signer> Preparator<'a, S> {
         bundle: &mut bundle::Header,
         cover: Option<String>,
         re: Option<(Topic, Option<git2::Oid>)>,
+        stripped: &[LargeBlob],
     ) -> cmd::Result<()> {
-        let cover = cover
+        let mut cover = cover
             .map(notes::Simple::new)
             .map(Ok)
             .unwrap_or_else(|| edit_cover_letter(self.repo.source()))?;
+        if !stripped.is_empty() {
+            let mut pointers = String::from("Large blobs stripped from this patch:\n");
+            for blob in stripped {
+                pointers.push_str(&format!("\n    {blob}"));
+            }
+            cover.append_paragraph(&pointers);
+        }
         let (topic, parent) = match re {
             Some((topic, reply_to)) => {
                 let parent = find_reply_to(self.repo, &topic, reply_to)?;
                 (topic, Some(parent))
             },
             None => {
-                // This is pretty arbitrary -- just use a random string instead?
-                let topic = {
-                    let mut hasher = Sha256::new();
-                    hasher.update(record::Heads::from(bundle as &bundle::Header));
-                    serde_json::to_writer(&mut hasher, &cover)?;
-                    hasher.update(self.submitter.signer.ident().keyid());
-                    Topic::from(hasher.finalize())
+                let topic = match &self.composite {
+                    Some((topic, _)) => topic.clone(),
+                    // This is pretty arbitrary -- just use a random string instead?
+                    None => Topic::derive(
+                        &cover,
+                        &record::Heads::from_header(bundle, self.hash_algorithm),
+                        &self.submitter.signer.ident().keyid(),
+                    )?,
                 };
                 let parent = topic::default_reply_to(self.repo.target(), &topic)?
                     .map(|id| self.repo.source().find_commit(id))
@@ -286,11 +419,12 @@ impl<'a, S: Signer> Preparator<'a, S> {
         topic: Topic,
         message: Option<String>,
         reply_to: Option<git2::Oid>,
+        draft: Option<&Draft>,
     ) -> cmd::Result<()> {
         let parent = find_reply_to(self.repo, &topic, reply_to)?;
         let edit = || -> cmd::Result<notes::Simple> {
             let re = notes::Simple::from_commit(self.repo.target(), &parent)?;
-            edit_comment(self.repo.source(), Some(&re))
+            edit_comment(self.repo.source(), Some(&re), draft)
         };
         let comment = message
             .map(notes::Simple::new)
@@ -314,9 +448,22 @@ impl<'a, S: Signer> Preparator<'a, S> {
             patches::to_tree(repo, &mut tb, note)?;
             repo.find_tree(tb.write()?)?
         };
+        let mut trailers = format!(
+            "{}\n{}",
+            topic.as_trailer(),
+            IdentityRevision::new(self.submitter_identity()?.hash()).as_trailer()
+        );
+        if let Some(ack) = &self.policy_ack {
+            trailers.push('\n');
+            trailers.push_str(&ack.as_trailer());
+        }
+        if let Some((_, manifest)) = &self.composite {
+            trailers.push('\n');
+            trailers.push_str(&manifest.as_trailers());
+        }
         let msg = match note.subject() {
-            Some(s) => format!("{}\n\n{}", s, topic.as_trailer()),
-            None => topic.as_trailer(),
+            Some(s) => format!("{}\n\n{}", s, trailers),
+            None => trailers,
         };
         let commit = git::commit_signed(
             self.submitter.signer,
@@ -335,14 +482,56 @@ impl<'a, S: Signer> Preparator<'a, S> {
     }
 }
 
+/// Warn about large blobs in `base..head`, and strip them if requested
+///
+/// Returns the (possibly rewritten) head, and the blobs which were stripped.
+fn strip_large_blobs(
+    repo: &Repo,
+    base: git2::Oid,
+    head: git2::Oid,
+    opts: large_blobs::Options,
+) -> cmd::Result<(git2::Oid, Vec<LargeBlob>)> {
+    let large = large_blobs::find(repo.source(), base, head, opts.threshold)?;
+    if large.is_empty() {
+        return Ok((head, large));
+    }
+    for blob in &large {
+        warn!("Blob {blob} exceeds {} bytes", opts.threshold);
+    }
+    if !opts.strip {
+        warn!("Pass --strip-large-blobs to exclude them from the patch");
+        return Ok((head, vec![]));
+    }
+    let stripped = large_blobs::strip(repo.source(), base, head, &large)?;
+    info!("Stripped large blobs, patch head is now {stripped}");
+
+    Ok((stripped, large))
+}
+
+/// Add the branches of the drop to `bundle`
+///
+/// Returns the commits new to the drop, up to [`MAX_SQUASH_CANDIDATES`] per
+/// branch, to detect squash merges among.
 fn mergepoint(
     repos: &Repo,
     meta: &metadata::drop::Verified,
     bundle: &mut bundle::Header,
     force: bool,
-) -> git::Result<()> {
+) -> git::Result<Vec<git2::Oid>> {
+    let mut commits = Vec::new();
+    let mut new_commits = |base: Option<git2::Oid>, head: git2::Oid| -> git::Result<()> {
+        let mut walk = repos.source().revwalk()?;
+        walk.push(head)?;
+        if let Some(base) = base {
+            walk.hide(base)?;
+        }
+        for oid in walk.take(MAX_SQUASH_CANDIDATES) {
+            commits.push(oid?);
+        }
+        Ok(())
+    };
     for branch in meta.roles.branches.keys() {
-        let sandboxed = match patches::TrackingBranch::try_from(branch) {
+        let sandboxed = match patches::TrackingBranch::for_branch(branch) {
             Ok(tracking) => tracking,
             Err(e) => {
                 warn!("Skipping invalid branch {branch}: {e}");
@@ -377,6 +566,7 @@ fn mergepoint(
                     info!("Adding thin checkpoint for branch {branch}: {base}..{head}");
                     bundle.add_prerequisite(&base);
                     bundle.add_reference(branch.clone(), &head);
+                    new_commits(Some(base), head)?;
                 } else {
                     warn!(
                         "{branch} diverges from drop state: no merge base between {base}..{head}"
@@ -387,13 +577,17 @@ fn mergepoint(
             None => {
                 info!("Adding full checkpoint for branch {branch}: {head}");
                 bundle.add_reference(branch.clone(), &head);
+                new_commits(None, head)?;
             },
         }
     }
 
-    Ok(())
+    Ok(commits)
 }
 
+/// Maximum number of commits per branch to consider for squash merge detection
+const MAX_SQUASH_CANDIDATES: usize = 1000;
+
 fn snapshot(repo: &Repo, bundle: &mut bundle::Header, incremental: bool) -> cmd::Result<()> {
     for record in dropped::records(repo.target(), REF_IT_PATCHES) {
         let record = record?;
@@ -460,7 +654,7 @@ impl Identity {
         repo: &git2::Repository,
         ids: &git2::Tree,
         id_path: &[git2::Repository],
-        refname: Refname,
+        id: &IdentityId,
     ) -> cmd::Result<Self> {
         let find_parent = metadata::git::find_parent(repo);
 
@@ -475,22 +669,23 @@ impl Identity {
             }
         }
 
-        let (ours_in, ours) =
-            metadata::Identity::from_search_path(id_path, &refname).and_then(|data| {
-                let signer = data.meta.signed.verified(&find_parent)?;
-                Ok((
-                    data.repo,
-                    Meta {
-                        hash: data.meta.hash,
-                        id: signer,
-                    },
-                ))
-            })?;
+        // Updates are conveyed under the same name regardless of the
+        // namespace the identity is stored in locally
+        let refname = cmd::id::namespace::bundle_refname(id);
+        let (ours_in, tip) = cmd::id::namespace::find_in_path(id_path, id)?
+            .ok_or_else(|| anyhow!("identity {id} not found in search path"))?;
+        let ours = metadata::Identity::from_reference(ours_in, &tip).and_then(
+            |GitMeta { hash, signed }| {
+                let signer = signed.verified(&find_parent)?;
+                Ok(Meta { hash, id: signer })
+            },
+        )?;
+        let tip = tip.peel_to_commit()?.id();
 
         let tree_path = PathBuf::from(ours.id.id().to_string()).join(META_FILE_ID);
         let newer = match if_not_found_none(ids.get_path(&tree_path))? {
             None => {
-                let start = ours_in.refname_to_id(&refname)?;
+                let start = tip;
                 let range = Range {
                     refname,
                     start,
@@ -515,7 +710,7 @@ impl Identity {
                     })?;
 
                 if ours.identity().has_ancestor(&theirs.hash, &find_parent)? {
-                    let range = Range::compute(ours_in, refname, theirs.hash.as_oid())?;
+                    let range = Range::compute(ours_in, refname, tip, theirs.hash.as_oid())?;
                     Self {
                         hash: ours.hash,
                         verified: ours.id,
@@ -548,8 +743,9 @@ impl Identity {
         &self.hash
     }
 
+    /// Whether `key` is a regular or an unexpired bot key of the identity
     pub fn contains(&self, key: &KeyId) -> bool {
-        self.verified.identity().keys.contains_key(key)
+        self.verified.identity().keys.contains_key(key) || self.verified.bot_key(key).is_some()
     }
 
     pub fn update(&self, bundle: &mut bundle::Header) {
@@ -569,10 +765,9 @@ impl Range {
     fn compute(
         repo: &git2::Repository,
         refname: Refname,
+        start: git2::Oid,
         known: git2::Oid,
     ) -> cmd::Result<Option<Self>> {
-        let start = repo.refname_to_id(&refname)?;
-
         let mut walk = repo.revwalk()?;
         walk.push(start)?;
         for oid in walk {
@@ -0,0 +1,308 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    fs,
+    io::{
+        self,
+        Write as _,
+    },
+    num::NonZeroUsize,
+    path::PathBuf,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use url::Url;
+
+use super::{
+    create,
+    prepare,
+    Common,
+    Kind,
+    Remote,
+};
+use crate::{
+    cmd::{
+        self,
+        ui::{
+            info,
+            warn,
+        },
+        util::args::IdSearchPath,
+    },
+    fs::LockedFile,
+    git::{
+        self,
+        if_not_found_none,
+        Refname,
+    },
+    metadata::IdentityId,
+    patches::{
+        self,
+        large_blobs,
+        Topic,
+        GLOB_IT_BUNDLES,
+        REF_IT_BRANCHES,
+    },
+};
+
+/// Where the parameters of the last failed submission are kept, relative to
+/// the drop's $GIT_DIR
+const SAVED_SUBMISSION: &str = "it/resubmit.json";
+
+#[derive(Debug, clap::Args)]
+pub struct Resubmit {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Rebase the patch onto the most recent commit known to the drop
+    ///
+    /// Candidates are the checkpointed branches and previously accepted
+    /// patches of the local drop, so it should be synced before.
+    #[clap(long, value_parser)]
+    fix_prereqs: bool,
+    /// Split the patch into a series of patches of at most N commits each
+    ///
+    /// If N is not given, the limit reported by the remote is used. Only
+    /// the first-parent history of the patch is considered.
+    #[clap(long, value_parser, value_name = "N", num_args = 0..=1)]
+    split: Option<Option<NonZeroUsize>>,
+    /// Replace the message of the failed submission
+    #[clap(short, long, value_parser, value_name = "STRING")]
+    message: Option<String>,
+    /// Create the patch, but stop short of submitting it
+    #[clap(long, value_parser)]
+    dry_run: bool,
+    /// Proceed even if the identity is not bound to the target drop
+    #[clap(long, value_parser)]
+    force_drop: bool,
+    /// Agree to the usage policy of the drop, see 'patch create'
+    #[clap(long, value_parser)]
+    acknowledge_policy: bool,
+}
+
+/// A resolved patch specification
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Spec {
+    #[serde(with = "git::serde::oid")]
+    pub head: git2::Oid,
+    #[serde(with = "git::serde::oid")]
+    pub base: git2::Oid,
+    pub name: Refname,
+    pub topic: Option<Topic>,
+    #[serde(
+        with = "git::serde::oid::option",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub reply_to: Option<git2::Oid>,
+    #[serde(default)]
+    pub large_blobs: large_blobs::Options,
+}
+
+impl From<Spec> for prepare::Kind {
+    fn from(
+        Spec {
+            head,
+            base,
+            name,
+            topic,
+            reply_to,
+            large_blobs,
+        }: Spec,
+    ) -> Self {
+        Self::Patch {
+            head,
+            base,
+            name,
+            re: topic.map(|t| (t, reply_to)),
+            large_blobs,
+        }
+    }
+}
+
+/// The parameters of a failed submission
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Saved {
+    pub url: Url,
+    pub drop_ref: String,
+    pub src_dir: PathBuf,
+    pub id: IdentityId,
+    #[serde(with = "crate::serde::display")]
+    pub id_path: IdSearchPath,
+    pub bundle_dir: PathBuf,
+    pub ids: Vec<IdentityId>,
+    /// Age recipients the patch was encrypted to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub encrypt_to: Vec<String>,
+    pub message: Option<String>,
+    pub spec: Spec,
+    /// The error the submission failed with
+    pub error: String,
+}
+
+pub fn resubmit(args: Resubmit) -> cmd::Result<Vec<patches::Record>> {
+    let drp = git::repo::open(&args.git_dir)?;
+    let saved = load(&drp)?.ok_or_else(|| anyhow!("no failed submission to retry"))?;
+    info!("Retrying submission which failed with: {}", saved.error);
+
+    let src = git::repo::open_bare(&saved.src_dir)?;
+    let mut spec = saved.spec.clone();
+    if args.fix_prereqs {
+        fix_prereqs(&src, &drp, &mut spec)?;
+    }
+    let parts = match args.split {
+        None => vec![spec],
+        Some(max) => {
+            let max = max
+                .or_else(|| max_commits_from_error(&saved.error))
+                .ok_or_else(|| {
+                    anyhow!("unable to determine the max number of commits, use --split=N")
+                })?;
+            split(&src, &spec, max)?
+        },
+    };
+
+    let total = parts.len();
+    if total > 1 {
+        info!("Submitting patch as a series of {total}");
+    }
+    let mut records = Vec::with_capacity(total);
+    for (i, spec) in parts.into_iter().enumerate() {
+        let message = args
+            .message
+            .clone()
+            .or_else(|| saved.message.clone())
+            .map(|msg| {
+                if total > 1 {
+                    format!("[{}/{total}] {msg}", i + 1)
+                } else {
+                    msg
+                }
+            });
+        let record = create(Kind::Resubmit {
+            common: Common::from_saved(
+                args.git_dir.clone(),
+                &saved,
+                message,
+                args.dry_run,
+                args.force_drop,
+                args.acknowledge_policy,
+            ),
+            remote: Remote::from_saved(&saved),
+            spec,
+        })?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Set the base of `spec` to the most recent ancestor of its head which is
+/// known to the drop
+fn fix_prereqs(src: &git2::Repository, drp: &git2::Repository, spec: &mut Spec) -> cmd::Result<()> {
+    // The candidates may not be present in the source repo
+    src.odb()?
+        .add_disk_alternate(&drp.path().join("objects").to_string_lossy())?;
+
+    let mut best: Option<git2::Oid> = None;
+    for glob in [
+        GLOB_IT_BUNDLES.glob().to_owned(),
+        format!("{REF_IT_BRANCHES}/**"),
+    ] {
+        for r in drp.references_glob(&glob)? {
+            let tip = match r?.peel_to_commit() {
+                Ok(commit) => commit.id(),
+                Err(_) => continue,
+            };
+            let base = match if_not_found_none(src.merge_base(tip, spec.head))? {
+                Some(base) => base,
+                None => continue,
+            };
+            best = match best {
+                Some(prev) if prev == base || src.graph_descendant_of(prev, base)? => Some(prev),
+                _ => Some(base),
+            };
+        }
+    }
+
+    let base = best.ok_or_else(|| anyhow!("no common ancestor with the drop found"))?;
+    ensure!(
+        base != spec.head,
+        "{} is already known to the drop, nothing to submit",
+        spec.head
+    );
+    if base == spec.base {
+        warn!("Base {base} is already the most recent known to the drop");
+    } else {
+        info!("Rebasing patch from {} onto {base}", spec.base);
+        spec.base = base;
+    }
+
+    Ok(())
+}
+
+/// Split `spec` into consecutive patches of at most `max` first-parent commits
+fn split(src: &git2::Repository, spec: &Spec, max: NonZeroUsize) -> cmd::Result<Vec<Spec>> {
+    let mut walk = src.revwalk()?;
+    walk.push(spec.head)?;
+    walk.hide(spec.base)?;
+    walk.simplify_first_parent()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    let commits = walk.collect::<Result<Vec<_>, _>>()?;
+
+    let mut parts = Vec::new();
+    let mut base = spec.base;
+    for chunk in commits.chunks(max.get()) {
+        let head = *chunk.last().expect("chunks are non-empty");
+        parts.push(Spec {
+            head,
+            base,
+            ..spec.clone()
+        });
+        base = head;
+    }
+
+    Ok(parts)
+}
+
+/// Extract the limit from a "exceeds configured max number of commits (N)"
+/// error
+fn max_commits_from_error(error: &str) -> Option<NonZeroUsize> {
+    const NEEDLE: &str = "max number of commits (";
+
+    let start = error.find(NEEDLE)? + NEEDLE.len();
+    let len = error[start..].find(')')?;
+    error[start..start + len].parse().ok()
+}
+
+pub(super) fn load(repo: &git2::Repository) -> cmd::Result<Option<Saved>> {
+    match fs::read(repo.path().join(SAVED_SUBMISSION)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(super) fn save(repo: &git2::Repository, saved: &Saved) -> cmd::Result<()> {
+    let path = repo.path().join(SAVED_SUBMISSION);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut lock = LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+    serde_json::to_writer_pretty(&mut lock, saved)?;
+    writeln!(lock)?;
+    lock.persist()?;
+
+    Ok(())
+}
+
+pub(super) fn clear(repo: &git2::Repository) -> cmd::Result<()> {
+    match fs::remove_file(repo.path().join(SAVED_SUBMISSION)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::path::Path;
+
+use anyhow::{
+    anyhow,
+    bail,
+    ensure,
+};
+
+use super::{
+    create,
+    show,
+    Comment,
+    Common,
+    Kind,
+    Remote,
+};
+use crate::{
+    cmd,
+    git::{
+        self,
+        if_not_found_none,
+    },
+    patches::{
+        self,
+        notes::{
+            self,
+            ReviewComment,
+            Verdict,
+        },
+        record::Heads,
+        Topic,
+        REF_HEADS_PATCHES,
+        REF_IT_PATCHES,
+        REF_IT_TOPICS,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Review {
+    #[clap(flatten)]
+    common: Common,
+    /// The topic of the patch to review
+    #[clap(value_parser, value_name = "TOPIC")]
+    topic: Topic,
+    /// The patch to review
+    ///
+    /// If not set, the most recent patch to the topic is reviewed.
+    #[clap(long, value_parser, value_name = "ID")]
+    patch: Option<Heads>,
+    /// Approve the patch
+    #[clap(long, value_parser, conflicts_with = "request_changes")]
+    approve: bool,
+    /// Request changes to the patch
+    #[clap(long, value_parser)]
+    request_changes: bool,
+    /// Comment on a file of the patch, optionally on a line or an inclusive
+    /// range of lines, eg. 'src/lib.rs:10-12'
+    ///
+    /// May be given multiple times. Lines refer to the file as of the head of
+    /// the patch.
+    #[clap(
+        short,
+        long,
+        value_parser,
+        num_args = 2,
+        value_names = ["FILE[:LINES]", "MESSAGE"],
+    )]
+    comment: Vec<String>,
+    /// Url to submit the review to
+    ///
+    /// If not set, the review is recorded in the local drop, like 'it topic
+    /// comment record' does.
+    #[clap(
+        long,
+        visible_alias = "submit-to",
+        value_parser,
+        value_name = "URL",
+        requires = "drop_ref"
+    )]
+    url: Option<String>,
+    /// Refname of the drop to record the review with, when submitting to
+    /// --url
+    #[clap(long = "drop", value_parser, value_name = "STRING", requires = "url")]
+    drop_ref: Option<String>,
+}
+
+impl Review {
+    /// Whether the review is recorded in the local drop
+    pub fn is_record(&self) -> bool {
+        self.url.is_none()
+    }
+}
+
+pub fn review(
+    Review {
+        common,
+        topic,
+        patch,
+        approve,
+        request_changes,
+        comment,
+        url,
+        drop_ref,
+    }: Review,
+) -> cmd::Result<patches::Record> {
+    let verdict = if approve {
+        Some(Verdict::Approve)
+    } else if request_changes {
+        Some(Verdict::RequestChanges)
+    } else {
+        None
+    };
+    ensure!(
+        verdict.is_some() || !comment.is_empty() || common.message().is_some(),
+        "empty review, give a verdict, --comment or --message"
+    );
+
+    let repo = git::repo::open(common.git_dir())?;
+    let full_ref = match &drop_ref {
+        Some(short) => repo
+            .resolve_reference_from_short_name(short)?
+            .name()
+            .ok_or_else(|| anyhow!("invalid drop ref"))?
+            .to_owned(),
+        None if repo.is_bare() => REF_HEADS_PATCHES.to_owned(),
+        None => REF_IT_PATCHES.to_owned(),
+    };
+    let records = show::patch_records(&repo, &full_ref, &topic)?;
+    let record = match patch {
+        Some(id) => records.into_iter().find(|record| record.heads == id),
+        None => records.into_iter().last(),
+    }
+    .ok_or_else(|| anyhow!("no such patch on topic {topic}"))?;
+    let tips = show::tips(&repo, &record)?;
+
+    let mut comments = Vec::with_capacity(comment.len() / 2);
+    for pair in comment.chunks(2) {
+        let (path, lines) = show::parse_loc(&pair[0])?;
+        comments.push(anchor(&repo, &tips, path, lines, pair[1].clone())?);
+    }
+
+    // Reply to the cover letter of the patch
+    let reply_to = record
+        .meta
+        .bundle
+        .references
+        .iter()
+        .find_map(|(name, oid)| {
+            name.starts_with(REF_IT_TOPICS)
+                .then(|| git2::Oid::try_from(oid))
+        })
+        .transpose()?;
+
+    create(Kind::Comment {
+        common,
+        remote: url
+            .zip(drop_ref)
+            .map(|(url, drop_ref)| Remote::new(url, drop_ref)),
+        comment: Comment::note(
+            topic,
+            reply_to,
+            notes::Simple::review(record.heads, verdict, comments),
+        ),
+    })
+}
+
+/// Resolve the blob `path` refers to at the head of the patch, and check that
+/// `lines` are within it
+fn anchor(
+    repo: &git2::Repository,
+    tips: &[show::Tip],
+    path: String,
+    lines: Option<std::ops::Range<usize>>,
+    message: String,
+) -> cmd::Result<ReviewComment> {
+    for tip in tips {
+        let commit = repo.find_commit(tip.head)?;
+        let entry = match if_not_found_none(commit.tree()?.get_path(Path::new(&path)))? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let blob = repo.find_blob(entry.id())?;
+        if let Some(lines) = &lines {
+            let content = blob.content();
+            let mut len = content.iter().filter(|&&b| b == b'\n').count();
+            if !content.is_empty() && !content.ends_with(b"\n") {
+                len += 1;
+            }
+            ensure!(
+                lines.end - 1 <= len,
+                "{path} has {len} lines, can't comment on line {}",
+                lines.end - 1
+            );
+        }
+
+        return Ok(ReviewComment {
+            path,
+            commit: (&commit.id()).into(),
+            blob: (&blob.id()).into(),
+            lines,
+            message,
+        });
+    }
+
+    bail!("{path} not found in the patch, is it unbundled?")
+}
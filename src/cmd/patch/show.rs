@@ -0,0 +1,745 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+    },
+    fmt::Write as _,
+    path::PathBuf,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    cmd::{
+        self,
+        args::Refname,
+        ui::warn,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    metadata::{
+        self,
+        git::FromGit as _,
+    },
+    patches::{
+        issues::{
+            self,
+            Issue,
+        },
+        iter::{
+            self,
+            dropped,
+        },
+        notes::{
+            self,
+            Predef,
+            ReviewComment,
+            TopicState,
+            Verdict,
+        },
+        record::{
+            Diffstat,
+            Heads,
+        },
+        Record,
+        Topic,
+        GLOB_IT_TOPICS,
+        REF_IT_PATCHES,
+        REF_IT_TOPICS,
+    },
+};
+
+/// Replies nested deeper than this are not indented any further
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, clap::Args)]
+pub struct Show {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Output format
+    #[clap(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Show the diffs of the patches, with the comments of their reviews
+    /// inline, instead of the thread
+    ///
+    /// Patches must be unbundled for their diffs to be shown. Not supported
+    /// with --format json.
+    #[clap(long, value_parser)]
+    diff: bool,
+    /// Only show the diff of this patch of the topic
+    #[clap(long, value_parser, value_name = "ID", requires = "diff")]
+    patch: Option<Heads>,
+    /// The topic to show
+    #[clap(value_parser, value_name = "TOPIC")]
+    topic: Topic,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    /// Plain text, indented by reply depth
+    Text,
+    /// Markdown, nesting replies as block quotes
+    Markdown,
+    /// A single JSON object
+    Json,
+}
+
+#[derive(serde::Serialize)]
+pub struct Thread {
+    topic: Topic,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+    state: TopicState,
+    /// Issues of an external issue tracker linked to the topic
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    issues: Vec<Issue>,
+    /// The notes of the topic, in thread order
+    entries: Vec<Entry>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    note: iter::Note,
+    /// Nesting level in the thread
+    depth: usize,
+    /// The branches of the patch, if the note is its cover letter
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tips: Vec<TipStat>,
+}
+
+#[derive(serde::Serialize)]
+pub struct TipStat {
+    name: git::Refname,
+    #[serde(with = "git::serde::oid")]
+    head: git2::Oid,
+    /// Not known if the patch is not unbundled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diffstat: Option<Diffstat>,
+}
+
+/// Render a topic thread, or the diffs of the patches to a topic
+pub fn show(args: Show) -> cmd::Result<cmd::Output> {
+    let repo = git::repo::open(&args.git_dir)?;
+    if args.diff {
+        ensure!(
+            !matches!(args.format, Format::Json),
+            "--diff is not supported with --format json"
+        );
+        return diffs(&repo, &args).map(cmd::Output::Text);
+    }
+
+    let thread = thread(&repo, &args.drop_ref, &args.topic)?;
+    Ok(match args.format {
+        Format::Json => cmd::Output::val(thread),
+        Format::Text => cmd::Output::Text(text(&thread)),
+        Format::Markdown => cmd::Output::Text(markdown(&thread)),
+    })
+}
+
+fn thread(repo: &git2::Repository, drop_ref: &str, topic: &Topic) -> cmd::Result<Thread> {
+    let tip = if_not_found_none(repo.refname_to_id(&topic.as_refname()))?
+        .ok_or_else(|| anyhow!("topic {topic} not found"))?;
+    let state = iter::state(repo, tip)?;
+
+    let mut issues = issues::from_custom(
+        &metadata::Drop::from_tip(repo, drop_ref)?
+            .signed
+            .signed
+            .custom,
+    )?
+    .remove(topic)
+    .unwrap_or_default();
+    // Cover letters are the topic refs of the patch records
+    let mut tips = HashMap::new();
+    for record in patch_records(repo, drop_ref, topic)? {
+        issues.extend(record.meta.issues.iter().cloned());
+        let cover = record
+            .meta
+            .bundle
+            .references
+            .iter()
+            .find_map(|(name, oid)| name.starts_with(REF_IT_TOPICS).then(|| oid));
+        if let Some(cover) = cover {
+            let stats = tip_stats(repo, &record)?;
+            tips.insert(git2::Oid::try_from(cover)?, stats);
+        }
+    }
+
+    // Oldest first
+    let notes = iter::topic(repo, topic)
+        .rev()
+        .collect::<Result<Vec<_>, _>>()?;
+    let subject = notes
+        .first()
+        .and_then(|note| match &note.message {
+            notes::Note::Simple(simple) => simple.subject(),
+            notes::Note::Automerge(_) => None,
+        })
+        .map(ToOwned::to_owned);
+
+    let ids = notes
+        .iter()
+        .map(|note| note.header.id)
+        .collect::<HashSet<_>>();
+    let mut roots = Vec::new();
+    let mut replies: HashMap<git2::Oid, Vec<usize>> = HashMap::new();
+    for (i, note) in notes.iter().enumerate() {
+        match note.header.in_reply_to {
+            Some(parent) if ids.contains(&parent) => replies.entry(parent).or_default().push(i),
+            _ => roots.push(i),
+        }
+    }
+
+    // Depth-first, replies oldest first
+    let mut order = Vec::with_capacity(notes.len());
+    let mut stack = roots.into_iter().rev().map(|i| (i, 0)).collect::<Vec<_>>();
+    while let Some((i, depth)) = stack.pop() {
+        order.push((i, depth));
+        if let Some(children) = replies.get(&notes[i].header.id) {
+            stack.extend(children.iter().rev().map(|&j| (j, depth + 1)));
+        }
+    }
+    let mut notes = notes.into_iter().map(Some).collect::<Vec<_>>();
+    let entries = order
+        .into_iter()
+        .filter_map(|(i, depth)| {
+            let note = notes[i].take()?;
+            Some(Entry {
+                tips: tips.remove(&note.header.id).unwrap_or_default(),
+                note,
+                depth,
+            })
+        })
+        .collect();
+
+    Ok(Thread {
+        topic: topic.clone(),
+        subject,
+        state,
+        issues: issues.into_iter().collect(),
+        entries,
+    })
+}
+
+fn tip_stats(repo: &git2::Repository, record: &Record) -> cmd::Result<Vec<TipStat>> {
+    let is_topic = GLOB_IT_TOPICS.compile_matcher();
+    let present = tips(repo, record)?;
+    let mut stats = Vec::new();
+    for (name, oid) in &record.meta.bundle.references {
+        if is_topic.is_match(name) {
+            continue;
+        }
+        let head = git2::Oid::try_from(oid)?;
+        let diffstat = match present.iter().find(|tip| tip.head == head) {
+            Some(tip) => Some(diffstat(repo, tip)?),
+            None => None,
+        };
+        stats.push(TipStat {
+            name: name.clone(),
+            head,
+            diffstat,
+        });
+    }
+
+    Ok(stats)
+}
+
+fn diffstat(repo: &git2::Repository, tip: &Tip) -> cmd::Result<Diffstat> {
+    let stats = tip_diff(repo, tip)?.stats()?;
+    Ok(Diffstat {
+        files: stats.files_changed(),
+        insertions: stats.insertions(),
+        deletions: stats.deletions(),
+    })
+}
+
+fn text(thread: &Thread) -> String {
+    let mut out = String::new();
+    let subject = thread.subject.as_deref().unwrap_or("(no subject)");
+    writeln!(out, "{subject}").ok();
+    writeln!(out, "Topic {} [{}]", thread.topic, thread.state).ok();
+    if !thread.issues.is_empty() {
+        writeln!(out, "Issues: {}", join(&thread.issues)).ok();
+    }
+    for entry in &thread.entries {
+        let indent = "  ".repeat(entry.depth.min(MAX_DEPTH));
+        let hdr = &entry.note.header;
+        writeln!(
+            out,
+            "\n{indent}* {} <{}> {} ({})",
+            hdr.author.name,
+            hdr.author.email,
+            hdr.time.format(&Rfc3339).unwrap_or_default(),
+            &hdr.id.to_string()[..8],
+        )
+        .ok();
+        for block in body(entry) {
+            match block {
+                Block::Line(line) => writeln!(out, "{indent}  {line}").ok(),
+                Block::Item(item) => writeln!(out, "{indent}    {item}").ok(),
+                Block::Message(msg) => {
+                    for line in msg.lines() {
+                        writeln!(out, "{}", format!("{indent}  {line}").trim_end()).ok();
+                    }
+                    Some(())
+                },
+            };
+        }
+    }
+
+    out
+}
+
+fn markdown(thread: &Thread) -> String {
+    let mut out = String::new();
+    let subject = thread.subject.as_deref().unwrap_or("(no subject)");
+    writeln!(out, "# {subject}\n").ok();
+    writeln!(out, "Topic `{}` is **{}**", thread.topic, thread.state).ok();
+    if !thread.issues.is_empty() {
+        writeln!(out, "\nIssues: {}", join(&thread.issues)).ok();
+    }
+    for entry in &thread.entries {
+        let quote = "> ".repeat(entry.depth.min(MAX_DEPTH));
+        let hdr = &entry.note.header;
+        writeln!(out, "{}", quote.trim_end()).ok();
+        writeln!(
+            out,
+            "{quote}**{}** <{}> _{}_ `{}`",
+            hdr.author.name,
+            hdr.author.email,
+            hdr.time.format(&Rfc3339).unwrap_or_default(),
+            &hdr.id.to_string()[..8],
+        )
+        .ok();
+        writeln!(out, "{}", quote.trim_end()).ok();
+        for block in body(entry) {
+            match block {
+                Block::Line(line) => writeln!(out, "{quote}{line}").ok(),
+                Block::Item(item) => writeln!(out, "{quote}- {item}").ok(),
+                Block::Message(msg) => {
+                    writeln!(out, "{quote}```").ok();
+                    for line in msg.lines() {
+                        writeln!(out, "{}", format!("{quote}{line}").trim_end()).ok();
+                    }
+                    writeln!(out, "{quote}```").ok()
+                },
+            };
+        }
+    }
+
+    out
+}
+
+fn join(issues: &[Issue]) -> String {
+    issues
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Part of the rendering of an entry
+enum Block {
+    Line(String),
+    /// An item of a list, eg. of the branches of a patch
+    Item(String),
+    /// A free-form message
+    Message(String),
+}
+
+/// The blocks describing `entry`, without its header
+fn body(entry: &Entry) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    if !entry.tips.is_empty() {
+        blocks.push(Block::Line(format!("Patch {}", entry.note.header.patch.id)));
+        for tip in &entry.tips {
+            let stat = tip
+                .diffstat
+                .map(|stat| stat.to_string())
+                .unwrap_or_else(|| "not unbundled".to_owned());
+            blocks.push(Block::Item(format!(
+                "{} {} ({stat})",
+                tip.name,
+                &tip.head.to_string()[..8]
+            )));
+        }
+    }
+
+    let simple = match &entry.note.message {
+        notes::Note::Simple(simple) => simple,
+        notes::Note::Automerge(_) => {
+            blocks.push(Block::Line("(Automerge document, not rendered)".to_owned()));
+            return blocks;
+        },
+    };
+    match simple {
+        notes::Simple::Known(Predef::Basic { message }) => {
+            blocks.push(Block::Message(message.clone()));
+        },
+        notes::Simple::Known(Predef::CodeComment { loc, message }) => {
+            let range = loc
+                .line
+                .as_ref()
+                .map(|lines| format!(", lines {}-{}", lines.start, lines.end))
+                .unwrap_or_default();
+            blocks.push(Block::Line(format!("On blob {}{range}:", loc.file)));
+            blocks.push(Block::Message(message.clone()));
+        },
+        notes::Simple::Known(Predef::Checkpoint {
+            kind,
+            refs,
+            message,
+            squashed,
+        }) => {
+            let kind = match kind {
+                notes::CheckpointKind::Merge => "Merge",
+                notes::CheckpointKind::Snapshot => "Snapshot",
+            };
+            blocks.push(Block::Line(format!("{kind} checkpoint")));
+            for (name, oid) in refs {
+                blocks.push(Block::Item(format!("{name} -> {oid}")));
+            }
+            if !squashed.is_empty() {
+                blocks.push(Block::Line(format!(
+                    "{} patch(es) squash-merged",
+                    squashed.len()
+                )));
+            }
+            blocks.extend(message.clone().map(Block::Message));
+        },
+        notes::Simple::Known(Predef::State { state, message }) => {
+            blocks.push(Block::Line(format!("Marked the topic as {state}")));
+            blocks.extend(message.clone().map(Block::Message));
+        },
+        notes::Simple::Known(Predef::Review {
+            patch,
+            verdict,
+            message,
+            comments,
+        }) => {
+            let verdict = verdict
+                .map(|verdict| format!(": {verdict}"))
+                .unwrap_or_default();
+            blocks.push(Block::Line(format!("Review of patch {patch}{verdict}")));
+            blocks.extend(message.clone().map(Block::Message));
+            for comment in comments {
+                blocks.push(Block::Item(format!(
+                    "{}: {}",
+                    location(comment),
+                    comment.message
+                )));
+            }
+        },
+        notes::Simple::Unknown(map) => {
+            blocks.push(Block::Message(
+                serde_json::to_string_pretty(map).unwrap_or_default(),
+            ));
+        },
+    }
+
+    blocks
+}
+
+/// Render the diffs of the patches to a topic, with the comments of their
+/// reviews inline
+fn diffs(repo: &git2::Repository, args: &Show) -> cmd::Result<String> {
+    let fence = matches!(args.format, Format::Markdown);
+    let mut reviews = reviews(repo, &args.topic)?;
+    let records = patch_records(repo, &args.drop_ref, &args.topic)?
+        .into_iter()
+        .filter(|record| args.patch.map_or(true, |id| id == record.heads))
+        .collect::<Vec<_>>();
+    ensure!(
+        !records.is_empty(),
+        "no patches found on topic {}",
+        args.topic
+    );
+
+    let mut out = String::new();
+    for record in records {
+        writeln!(out, "patch {}", record.heads)?;
+        let reviews = reviews.remove(&record.heads).unwrap_or_default();
+        for review in &reviews {
+            let verdict = review
+                .verdict
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "comment".to_owned());
+            writeln!(out, "Review by {}: {verdict}", review.author)?;
+            if let Some(message) = &review.message {
+                quote(&mut out, message);
+            }
+        }
+        let comments = reviews
+            .iter()
+            .flat_map(|review| {
+                review
+                    .comments
+                    .iter()
+                    .map(move |comment| (review.author.as_str(), comment))
+            })
+            .collect::<Vec<_>>();
+        let tips = tips(repo, &record)?;
+        if tips.is_empty() {
+            warn!(
+                "Patch {} is not present locally, try unbundling it",
+                record.heads
+            );
+        }
+        for tip in tips {
+            writeln!(out, "\n{} {}", tip.name, tip.head)?;
+            if fence {
+                writeln!(out, "\n```diff")?;
+            }
+            render_diff(repo, &tip, &comments, &mut out)?;
+            if fence {
+                writeln!(out, "```")?;
+            }
+        }
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// A branch conveyed by a patch
+pub(super) struct Tip {
+    pub name: git::Refname,
+    pub head: git2::Oid,
+    /// The prerequisite the head is based on, if any
+    pub base: Option<git2::Oid>,
+}
+
+/// The records of the patches to `topic`, oldest first
+///
+/// Records of comments are omitted.
+pub(super) fn patch_records(
+    repo: &git2::Repository,
+    drop_ref: &str,
+    topic: &Topic,
+) -> cmd::Result<Vec<Record>> {
+    let is_topic = GLOB_IT_TOPICS.compile_matcher();
+    let mut records = Vec::new();
+    for record in dropped::records_rev(repo, drop_ref) {
+        let record = record?;
+        if record.topic == *topic
+            && record
+                .meta
+                .bundle
+                .references
+                .keys()
+                .any(|name| !is_topic.is_match(name))
+        {
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+/// The branches of the patch `record` which are present locally
+pub(super) fn tips(repo: &git2::Repository, record: &Record) -> cmd::Result<Vec<Tip>> {
+    let is_topic = GLOB_IT_TOPICS.compile_matcher();
+    let odb = repo.odb()?;
+    let prereqs = record
+        .meta
+        .bundle
+        .prerequisites
+        .iter()
+        .map(git2::Oid::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut tips = Vec::new();
+    for (name, oid) in &record.meta.bundle.references {
+        if is_topic.is_match(name) {
+            continue;
+        }
+        let head = git2::Oid::try_from(oid)?;
+        if !odb.exists(head) {
+            continue;
+        }
+        let mut base = None;
+        for prereq in &prereqs {
+            if odb.exists(*prereq) && repo.graph_descendant_of(head, *prereq)? {
+                base = Some(*prereq);
+                break;
+            }
+        }
+        tips.push(Tip {
+            name: name.clone(),
+            head,
+            base,
+        });
+    }
+
+    Ok(tips)
+}
+
+struct Review {
+    author: String,
+    verdict: Option<Verdict>,
+    message: Option<String>,
+    comments: Vec<ReviewComment>,
+}
+
+/// The reviews on `topic` by patch, oldest first
+fn reviews(repo: &git2::Repository, topic: &Topic) -> cmd::Result<BTreeMap<Heads, Vec<Review>>> {
+    let mut reviews: BTreeMap<Heads, Vec<Review>> = BTreeMap::new();
+    for note in iter::topic(repo, topic).rev() {
+        let iter::Note { header, message } = note?;
+        if let notes::Note::Simple(notes::Simple::Known(notes::Predef::Review {
+            patch,
+            verdict,
+            message,
+            comments,
+        })) = message
+        {
+            reviews.entry(patch).or_default().push(Review {
+                author: format!("{} <{}>", header.author.name, header.author.email),
+                verdict,
+                message,
+                comments,
+            });
+        }
+    }
+
+    Ok(reviews)
+}
+
+/// Render the diff of `tip` against its base, with the `comments` anchored
+/// to its lines
+///
+/// Comments on blobs or lines not in the diff are listed at the end.
+fn render_diff(
+    repo: &git2::Repository,
+    tip: &Tip,
+    comments: &[(&str, &ReviewComment)],
+    out: &mut String,
+) -> cmd::Result<()> {
+    let diff = tip_diff(repo, tip)?;
+    let mut shown = vec![false; comments.len()];
+    diff.print(git2::DiffFormat::Patch, |delta, _, line| {
+        let origin = line.origin();
+        if matches!(origin, '+' | '-' | ' ') {
+            out.push(origin);
+        }
+        out.push_str(&String::from_utf8_lossy(line.content()));
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+
+        let new_file = delta.new_file();
+        let anchored = |comment: &ReviewComment| {
+            new_file
+                .path()
+                .map_or(false, |path| path.to_str() == Some(comment.path.as_str()))
+                && git2::Oid::try_from(&comment.blob).map_or(false, |blob| blob == new_file.id())
+        };
+        let lineno = match origin {
+            // File header
+            'F' => None,
+            '+' | ' ' => line.new_lineno().map(|n| n as usize),
+            _ => return true,
+        };
+        for (i, (author, comment)) in comments.iter().enumerate() {
+            let here = match (&comment.lines, lineno) {
+                (None, None) => true,
+                (Some(lines), Some(n)) => lines.end.saturating_sub(1) == n,
+                _ => false,
+            };
+            if !shown[i] && here && anchored(comment) {
+                comment_block(out, author, comment);
+                shown[i] = true;
+            }
+        }
+
+        true
+    })?;
+
+    let rest = comments
+        .iter()
+        .zip(shown)
+        .filter(|(_, shown)| !shown)
+        .map(|(comment, _)| comment)
+        .collect::<Vec<_>>();
+    if !rest.is_empty() {
+        writeln!(out, "\nComments outside the diff:")?;
+        for (author, comment) in rest {
+            comment_block(out, author, comment);
+        }
+    }
+
+    Ok(())
+}
+
+fn tip_diff<'a>(repo: &'a git2::Repository, tip: &Tip) -> cmd::Result<git2::Diff<'a>> {
+    let head = repo.find_commit(tip.head)?.tree()?;
+    let base = tip
+        .base
+        .map(|oid| repo.find_commit(oid).and_then(|commit| commit.tree()))
+        .transpose()?;
+    Ok(repo.diff_tree_to_tree(base.as_ref(), Some(&head), None)?)
+}
+
+fn location(comment: &ReviewComment) -> String {
+    match &comment.lines {
+        Some(lines) if lines.end > lines.start + 1 => {
+            format!("{}:{}-{}", comment.path, lines.start, lines.end - 1)
+        },
+        Some(lines) => format!("{}:{}", comment.path, lines.start),
+        None => comment.path.clone(),
+    }
+}
+
+fn comment_block(out: &mut String, author: &str, comment: &ReviewComment) {
+    writeln!(out, "    | {author} on {}:", location(comment)).ok();
+    for line in comment.message.lines() {
+        writeln!(out, "    | {line}").ok();
+    }
+}
+
+fn quote(out: &mut String, message: &str) {
+    for line in message.lines() {
+        writeln!(out, "    {line}").ok();
+    }
+}
+
+/// Parse `FILE[:LINES]`, where `LINES` is a line number or an inclusive range
+/// like '10-12'
+pub(super) fn parse_loc(loc: &str) -> cmd::Result<(String, Option<std::ops::Range<usize>>)> {
+    let parse_lines = |lines: &str| -> Option<std::ops::Range<usize>> {
+        let (start, end) = match lines.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse::<usize>().ok()?),
+            None => {
+                let n = lines.parse().ok()?;
+                (n, n)
+            },
+        };
+        (start > 0 && start <= end).then(|| start..end + 1)
+    };
+    match loc.rsplit_once(':') {
+        Some((path, lines)) if !path.is_empty() => {
+            let lines = parse_lines(lines).ok_or_else(|| anyhow!("invalid lines in {loc}"))?;
+            Ok((path.to_owned(), Some(lines)))
+        },
+        _ => Ok((loc.to_owned(), None)),
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+};
+
+use crate::{
+    bundle::ObjectId,
+    cmd::{
+        self,
+        args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    patches::{
+        iter,
+        notes,
+        record::Heads,
+        DropHead,
+        Topic,
+        TrackingBranch,
+        GLOB_HEADS,
+        REF_IT_PATCHES,
+        TOPIC_MERGES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Status {
+    /// Path to the drop repository
+    #[clap(from_global)]
+    git_dir: PathBuf,
+    /// Name of the git ref holding the drop history
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Only show this topic
+    #[clap(long, value_parser)]
+    topic: Option<Topic>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Open,
+    Merged,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    topic: Topic,
+    state: State,
+    patches: Vec<Patch>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Patch {
+    id: Heads,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged: Option<Merged>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "how", rename_all = "lowercase")]
+pub enum Merged {
+    /// The patch tip is reachable from the branch
+    Merge { branch: git::Refname },
+    /// The patch was squashed into a single commit, as recorded by a
+    /// mergepoint
+    Squash { commit: ObjectId },
+}
+
+/// The merge state of the topics with patches in the drop history
+///
+/// A topic is considered merged if any of its patches was merged into one of
+/// the drop's branches, either such that the patch tip is reachable from the
+/// branch, or as a squash commit detected when the mergepoint was recorded.
+pub fn status(args: Status) -> cmd::Result<Vec<Output>> {
+    let repo = git::repo::open(&args.git_dir)?;
+    let drop = DropHead::from_refname(&repo, args.drop_ref.to_string())?;
+
+    let mut branches = Vec::new();
+    for branch in drop.meta.roles.branches.keys() {
+        let tracking = TrackingBranch::for_branch(branch)?;
+        if let Some(tip) = if_not_found_none(repo.refname_to_id(&tracking))? {
+            branches.push((branch.clone(), tip));
+        }
+    }
+
+    let mut squashed = BTreeMap::new();
+    if if_not_found_none(repo.find_reference(&TOPIC_MERGES.as_refname()))?.is_some() {
+        for note in iter::topic(&repo, &TOPIC_MERGES) {
+            if let notes::Note::Simple(simple) = note?.message {
+                for sq in simple.squashed() {
+                    squashed.insert(sq.patch.to_string(), sq.commit.clone());
+                }
+            }
+        }
+    }
+
+    let heads = GLOB_HEADS.compile_matcher();
+    let mut topics: BTreeMap<Topic, Vec<Patch>> = BTreeMap::new();
+    for record in iter::dropped::records(&repo, &args.drop_ref) {
+        let record = record?;
+        if record.is_mergepoint() || record.is_snapshot() {
+            continue;
+        }
+        if args
+            .topic
+            .as_ref()
+            .map_or(false, |topic| topic != &record.topic)
+        {
+            continue;
+        }
+        let tips = record
+            .bundle_info()
+            .references
+            .iter()
+            .filter(|(name, _)| heads.is_match(&***name))
+            .map(|(_, oid)| git2::Oid::try_from(oid))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Comments don't carry any branches
+        if tips.is_empty() {
+            continue;
+        }
+
+        let mut merged = squashed
+            .get(&record.heads.to_string())
+            .map(|commit| Merged::Squash {
+                commit: commit.clone(),
+            });
+        'branches: for (branch, tip) in &branches {
+            if merged.is_some() {
+                break;
+            }
+            for patch_tip in &tips {
+                let reachable = tip == patch_tip
+                    || if_not_found_none(repo.graph_descendant_of(*tip, *patch_tip))?
+                        .unwrap_or(false);
+                if reachable {
+                    merged = Some(Merged::Merge {
+                        branch: branch.clone(),
+                    });
+                    break 'branches;
+                }
+            }
+        }
+
+        topics.entry(record.topic).or_default().push(Patch {
+            id: record.heads,
+            merged,
+        });
+    }
+
+    Ok(topics
+        .into_iter()
+        .map(|(topic, patches)| {
+            let state = if patches.iter().any(|p| p.merged.is_some()) {
+                State::Merged
+            } else {
+                State::Open
+            };
+            Output {
+                topic,
+                state,
+                patches,
+            }
+        })
+        .collect())
+}
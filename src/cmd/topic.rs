@@ -7,6 +7,8 @@ use crate::cmd;
 
 pub mod comment;
 
+mod cache;
+
 mod ls;
 pub use ls::{
     ls,
@@ -19,6 +21,23 @@ pub use show::{
     Show,
 };
 
+mod state;
+pub use state::{
+    close,
+    reopen,
+    Close,
+    Reopen,
+};
+
+mod subscribe;
+pub use subscribe::{
+    notify,
+    subscribe,
+    unsubscribe,
+    Subscribe,
+    Unsubscribe,
+};
+
 mod unbundle;
 pub use unbundle::{
     unbundle,
@@ -35,17 +54,39 @@ pub enum Cmd {
     /// Comment on a topic
     #[clap(subcommand)]
     Comment(comment::Cmd),
+    /// Mark a topic as closed, or as merged
+    ///
+    /// Requires a branch role in the drop. The state is shown by 'it topic
+    /// ls', and the most recent change takes precedence.
+    Close(Close),
+    /// Mark a closed or merged topic as open again
+    ///
+    /// Requires a branch role in the drop.
+    Reopen(Reopen),
     /// Unbundle a topic
     Unbundle(Unbundle),
+    /// Get notified about new records on a topic
+    ///
+    /// When 'drop bundles sync' or 'drop unbundle' find new records on
+    /// subscribed topics, the executable configured as 'it.notifyHook' is run
+    /// with a JSON summary on stdin. If none is configured, a digest is
+    /// printed.
+    Subscribe(Subscribe),
+    /// Stop getting notified about new records on a topic
+    Unsubscribe(Unsubscribe),
 }
 
 impl Cmd {
     pub fn run(self) -> cmd::Result<cmd::Output> {
         match self {
-            Self::Ls(args) => ls(args).map(cmd::Output::iter),
+            Self::Ls(args) => ls(args),
             Self::Show(args) => show(args).map(cmd::Output::iter),
             Self::Comment(cmd) => cmd.run(),
+            Self::Close(args) => close(args).map(cmd::Output::val),
+            Self::Reopen(args) => reopen(args).map(cmd::Output::val),
             Self::Unbundle(args) => unbundle(args).map(cmd::Output::val),
+            Self::Subscribe(args) => subscribe(args).map(cmd::Output::val),
+            Self::Unsubscribe(args) => unsubscribe(args).map(cmd::Output::val),
         }
     }
 }
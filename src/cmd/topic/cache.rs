@@ -0,0 +1,91 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Cache of rendered topic notes
+//!
+//! Entries are keyed by the oid the topic ref points to, so any update to the
+//! topic invalidates the cache. Stale entries are removed when a new one is
+//! written.
+
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    cmd::{
+        self,
+        ui::debug,
+    },
+    fs::LockedFile,
+    patches::Topic,
+};
+
+const CACHE_DIR: &str = "it/cache/topics";
+
+pub struct Cache {
+    dir: PathBuf,
+    tip: git2::Oid,
+}
+
+impl Cache {
+    pub fn new(git_dir: &Path, topic: &Topic, tip: git2::Oid) -> Self {
+        Self {
+            dir: git_dir.join(CACHE_DIR).join(topic.to_string()),
+            tip,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}.json", self.tip))
+    }
+
+    /// Load the cached notes, newest first
+    ///
+    /// A corrupt cache entry is treated as a cache miss.
+    pub fn load(&self) -> cmd::Result<Option<Vec<serde_json::Value>>> {
+        let path = self.path();
+        match fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(notes) => Ok(Some(notes)),
+                Err(e) => {
+                    debug!("ignoring corrupt cache entry {}: {e}", path.display());
+                    Ok(None)
+                },
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Store `notes`, newest first, and remove stale entries
+    ///
+    /// If the entry is locked by a concurrent process, nothing is stored.
+    pub fn store(&self, notes: &[&serde_json::Value]) -> cmd::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path();
+        let mut lock = match LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                debug!("{} is locked, not caching", path.display());
+                return Ok(());
+            },
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::to_writer(&mut lock, notes)?;
+        lock.persist()?;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?.path();
+            if entry != path && entry.extension().map_or(false, |ext| ext == "json") {
+                fs::remove_file(&entry)?;
+            }
+        }
+
+        Ok(())
+    }
+}
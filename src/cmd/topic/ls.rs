@@ -2,31 +2,228 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use crate::{
-    git,
+    cmd::util::args::Refname,
+    git::{
+        self,
+        if_not_found_none,
+    },
+    metadata::{
+        self,
+        git::FromGit as _,
+    },
     patches::{
         self,
+        issues::{
+            self,
+            Issue,
+        },
+        notes::TopicState,
+        view::{
+            At,
+            View,
+        },
         Topic,
+        REF_IT_PATCHES,
     },
 };
 
 use super::Common;
-use crate::cmd;
+use crate::cmd::{
+    self,
+    ui::{
+        term::{
+            self,
+            Table,
+        },
+        Msg,
+    },
+};
 
 #[derive(Debug, clap::Args)]
 pub struct Ls {
     #[clap(flatten)]
     common: Common,
+    /// List the topics recorded as of a past point in the drop history
+    ///
+    /// Either a date in RFC 3339 format, in which case the most recent drop
+    /// commit not newer than it is used, or a commit in 'git rev-parse'
+    /// syntax.
+    #[clap(long, value_parser, value_name = "DATE|REV")]
+    at: Option<At>,
+    /// Name of the git ref holding the drop history
+    ///
+    /// The diffstats and linked issues of the topics are taken from it, if it
+    /// exists.
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// Print a table instead of JSON
+    #[clap(long, value_parser)]
+    table: bool,
 }
 
 #[derive(serde::Serialize)]
 pub struct Output {
     topic: Topic,
     subject: String,
+    /// Unknown if the topic notes are not present locally
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<TopicState>,
+    /// Total changes of the patches to the topic, eg. "+120/-45, 6 files"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diffstat: Option<String>,
+    /// Issues of an external issue tracker linked to the topic
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    issues: Vec<Issue>,
 }
 
-pub fn ls(args: Ls) -> cmd::Result<Vec<cmd::Result<Output>>> {
+pub fn ls(args: Ls) -> cmd::Result<cmd::Output> {
+    let table = args.table;
+    let topics = list(args)?;
+    if !table {
+        return Ok(cmd::Output::iter(topics));
+    }
+
+    let mut out = Table::new();
+    out.row(
+        [
+            Msg::ColTopic,
+            Msg::ColState,
+            Msg::ColChanges,
+            Msg::ColIssues,
+            Msg::ColSubject,
+        ]
+        .map(Msg::text),
+    );
+    for topic in topics {
+        let Output {
+            topic,
+            subject,
+            state,
+            diffstat,
+            issues,
+        } = topic?;
+        out.row([
+            topic.to_string(),
+            state.map(|s| s.to_string()).unwrap_or_default(),
+            diffstat.unwrap_or_default(),
+            issues
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+            subject,
+        ]);
+    }
+
+    Ok(cmd::Output::Text(out.render(term::width())))
+}
+
+fn list(args: Ls) -> cmd::Result<Vec<cmd::Result<Output>>> {
     let repo = git::repo::open(&args.common.git_dir)?;
+    if let Some(at) = &args.at {
+        let view = View::resolve(&repo, &args.drop_ref, at)?;
+        let mut links = issues::from_custom(
+            &metadata::Drop::from_commit(&repo, view.commit())?
+                .signed
+                .signed
+                .custom,
+        )?;
+        return Ok(view
+            .topics()?
+            .into_iter()
+            .map(|(topic, records)| {
+                let subject = match records.first() {
+                    Some(first) => first_subject(&repo, &topic, first)?,
+                    None => String::default(),
+                };
+                let state = match records.last() {
+                    Some(last) => last_state(&repo, &topic, last)?,
+                    None => None,
+                };
+                let diffstat = records
+                    .iter()
+                    .filter_map(|r| r.meta.diffstat)
+                    .reduce(|mut total, stat| {
+                        total += stat;
+                        total
+                    })
+                    .map(|stat| stat.to_string());
+                let mut linked = links.remove(&topic).unwrap_or_default();
+                for record in &records {
+                    linked.extend(record.meta.issues.iter().cloned());
+                }
+                Ok(Output {
+                    topic,
+                    subject,
+                    state,
+                    diffstat,
+                    issues: linked.into_iter().collect(),
+                })
+            })
+            .collect());
+    }
+
+    let (mut diffstats, mut links) = match if_not_found_none(repo.find_reference(&args.drop_ref))? {
+        Some(_) => (
+            patches::iter::dropped::diffstats(&repo, &args.drop_ref)?,
+            issues::linked(&repo, &args.drop_ref)?,
+        ),
+        None => Default::default(),
+    };
     Ok(patches::iter::unbundled::topics_with_subject(&repo)
-        .map(|i| i.map(|(topic, subject)| Output { topic, subject }))
+        .map(|i| {
+            i.map(|(topic, subject, state)| {
+                let diffstat = diffstats.remove(&topic).map(|stat| stat.to_string());
+                let issues = links.remove(&topic).unwrap_or_default();
+                Output {
+                    topic,
+                    subject,
+                    state: Some(state),
+                    diffstat,
+                    issues: issues.into_iter().collect(),
+                }
+            })
+        })
         .collect())
 }
+
+/// The subject of `topic` from its first record, if the topic commit is
+/// present locally
+fn first_subject(
+    repo: &git2::Repository,
+    topic: &Topic,
+    first: &patches::Record,
+) -> cmd::Result<String> {
+    let topic_ref = topic.as_refname();
+    let oid = match first.bundle_info().references.get(&topic_ref) {
+        Some(oid) => git2::Oid::try_from(oid)?,
+        None => return Ok(String::default()),
+    };
+    match if_not_found_none(repo.find_commit(oid))? {
+        Some(commit) => patches::iter::subject(repo, &commit),
+        None => Ok(String::default()),
+    }
+}
+
+/// The state of `topic` as of its last record, if the topic commit is present
+/// locally
+fn last_state(
+    repo: &git2::Repository,
+    topic: &Topic,
+    last: &patches::Record,
+) -> cmd::Result<Option<TopicState>> {
+    let topic_ref = topic.as_refname();
+    let oid = match last.bundle_info().references.get(&topic_ref) {
+        Some(oid) => git2::Oid::try_from(oid)?,
+        None => return Ok(None),
+    };
+    match if_not_found_none(repo.find_commit(oid))? {
+        Some(_) => patches::iter::state(repo, oid).map(Some),
+        None => Ok(None),
+    }
+}
@@ -1,14 +1,37 @@
 // Copyright © 2022 Kim Altintop <kim@eagain.io>
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
-use super::Common;
+use std::collections::{
+    hash_map,
+    HashMap,
+};
+
+use super::{
+    cache::Cache,
+    Common,
+};
 use crate::{
-    cmd,
-    git,
+    cfg,
+    cmd::{
+        self,
+        id::resolve_profile,
+        ui::warn,
+        util::args::Refname,
+        FromGit as _,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    metadata::{
+        self,
+        ContentHash,
+        IdentityId,
+    },
     patches::{
         self,
-        iter::Note,
         Topic,
+        REF_IT_PATCHES,
     },
 };
 
@@ -19,16 +42,123 @@ pub struct Show {
     /// Traverse the topic in reverse order, ie. oldest first
     #[clap(long, value_parser)]
     reverse: bool,
+    /// Don't use the note cache, even if enabled via `it.topicCache`
+    #[clap(long, value_parser)]
+    no_cache: bool,
+    /// Include the identity and profile of the submitter of each patch
+    ///
+    /// Profiles are fetched from the URL referenced by the identity, unless a
+    /// recent copy is cached.
+    #[clap(long, value_parser)]
+    profiles: bool,
+    /// Name of the git ref holding the drop history
+    ///
+    /// Only considered if --profiles is given.
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
     #[clap(value_parser)]
     topic: Topic,
 }
 
-pub fn show(args: Show) -> cmd::Result<Vec<cmd::Result<Note>>> {
+#[derive(serde::Serialize)]
+pub struct Submitter {
+    id: IdentityId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<metadata::Profile>,
+}
+
+pub fn show(args: Show) -> cmd::Result<Vec<cmd::Result<serde_json::Value>>> {
     let repo = git::repo::open(&args.common.git_dir)?;
-    let iter = patches::iter::topic(&repo, &args.topic);
-    if args.reverse {
-        Ok(iter.rev().collect())
+    let cache = if !args.no_cache && cfg::git::topic_cache(&cfg::git::open(&repo)?)? {
+        if_not_found_none(repo.refname_to_id(&args.topic.as_refname()))?
+            .map(|tip| Cache::new(repo.path(), &args.topic, tip))
     } else {
-        Ok(iter.collect())
+        None
+    };
+
+    let cached = match &cache {
+        Some(cache) => cache.load()?,
+        None => None,
+    };
+    let mut notes = match cached {
+        Some(notes) => notes.into_iter().map(Ok).collect(),
+        None => {
+            let notes = patches::iter::topic(&repo, &args.topic)
+                .map(|note| Ok(serde_json::to_value(note?)?))
+                .collect::<Vec<_>>();
+            // Don't cache partial results
+            if let (Some(cache), true) = (&cache, notes.iter().all(Result::is_ok)) {
+                let complete = notes.iter().filter_map(|note| note.as_ref().ok());
+                if let Err(e) = cache.store(&complete.collect::<Vec<_>>()) {
+                    warn!("Failed to update note cache: {e:#}");
+                }
+            }
+            notes
+        },
+    };
+    if args.reverse {
+        notes.reverse();
+    }
+    if args.profiles {
+        let submitters = submitters(&repo, &args.drop_ref, &args.topic)?;
+        for note in notes.iter_mut().filter_map(|note| note.as_mut().ok()) {
+            let patch = note
+                .pointer("/header/patch/id")
+                .and_then(|id| id.as_str())
+                .and_then(|id| submitters.get(id));
+            if let (Some(header), Some(submitter)) = (
+                note.get_mut("header").and_then(|h| h.as_object_mut()),
+                patch,
+            ) {
+                header.insert("submitter".into(), serde_json::to_value(submitter)?);
+            }
+        }
     }
+
+    Ok(notes)
+}
+
+/// The submitters of the patches recorded for `topic`, by patch id
+fn submitters(
+    repo: &git2::Repository,
+    drop_ref: &str,
+    topic: &Topic,
+) -> cmd::Result<HashMap<String, Submitter>> {
+    let mut ids: HashMap<ContentHash, IdentityId> = HashMap::new();
+    let mut profiles: HashMap<IdentityId, Option<metadata::Profile>> = HashMap::new();
+    let mut submitters = HashMap::new();
+    for record in patches::iter::dropped::records(repo, drop_ref) {
+        let record = record?;
+        if &record.topic != topic {
+            continue;
+        }
+        let signer = &record.meta.signature.signer;
+        let id = match ids.get(signer) {
+            Some(id) => *id,
+            None => {
+                let verified = metadata::Identity::from_content_hash(repo, signer)?
+                    .verified(cmd::find_parent(repo))?;
+                let id = *verified.id();
+                if let hash_map::Entry::Vacant(entry) = profiles.entry(id) {
+                    entry.insert(resolve_profile(repo, &verified)?);
+                }
+                ids.insert(signer.clone(), id);
+                id
+            },
+        };
+        submitters.insert(
+            record.heads.to_string(),
+            Submitter {
+                id,
+                profile: profiles.get(&id).cloned().flatten(),
+            },
+        );
+    }
+
+    Ok(submitters)
 }
@@ -0,0 +1,110 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use crate::{
+    cmd::{
+        self,
+        patch,
+    },
+    patches::{
+        self,
+        notes::{
+            self,
+            TopicState,
+        },
+        Topic,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Close {
+    #[clap(flatten)]
+    common: patch::Common,
+    #[clap(flatten)]
+    target: Target,
+    /// Mark the topic as merged rather than closed
+    #[clap(long, value_parser)]
+    merged: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Reopen {
+    #[clap(flatten)]
+    common: patch::Common,
+    #[clap(flatten)]
+    target: Target,
+}
+
+impl Close {
+    /// Whether the state change is recorded in the local drop
+    pub fn is_record(&self) -> bool {
+        self.target.url.is_none()
+    }
+}
+
+impl Reopen {
+    /// Whether the state change is recorded in the local drop
+    pub fn is_record(&self) -> bool {
+        self.target.url.is_none()
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct Target {
+    /// The topic to change the state of
+    #[clap(value_parser, value_name = "TOPIC")]
+    topic: Topic,
+    /// Url to submit the state change to
+    ///
+    /// If not set, the state change is recorded in the local drop, like
+    /// 'it topic comment record' does.
+    #[clap(
+        long,
+        visible_alias = "submit-to",
+        value_parser,
+        value_name = "URL",
+        requires = "drop_ref"
+    )]
+    url: Option<String>,
+    /// Refname of the drop to record the state change with, when submitting
+    /// to --url
+    #[clap(long = "drop", value_parser, value_name = "STRING", requires = "url")]
+    drop_ref: Option<String>,
+}
+
+pub fn close(
+    Close {
+        common,
+        target,
+        merged,
+    }: Close,
+) -> cmd::Result<patches::Record> {
+    let state = if merged {
+        TopicState::Merged
+    } else {
+        TopicState::Closed
+    };
+    set_state(common, target, state)
+}
+
+pub fn reopen(Reopen { common, target }: Reopen) -> cmd::Result<patches::Record> {
+    set_state(common, target, TopicState::Open)
+}
+
+fn set_state(
+    common: patch::Common,
+    Target {
+        topic,
+        url,
+        drop_ref,
+    }: Target,
+    state: TopicState,
+) -> cmd::Result<patches::Record> {
+    patch::create(patch::Kind::Comment {
+        common,
+        remote: url
+            .zip(drop_ref)
+            .map(|(url, drop_ref)| patch::Remote::new(url, drop_ref)),
+        comment: patch::Comment::note(topic, None, notes::Simple::state(state, None)),
+    })
+}
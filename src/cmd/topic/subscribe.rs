@@ -0,0 +1,126 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use anyhow::anyhow;
+
+use super::Common;
+use crate::{
+    cfg,
+    cmd::{
+        self,
+        ui::info,
+        util::args::Refname,
+    },
+    git::{
+        self,
+        if_not_found_none,
+    },
+    patches::{
+        subscriptions,
+        Topic,
+        REF_IT_PATCHES,
+    },
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Subscribe {
+    #[clap(flatten)]
+    common: Common,
+    /// Name of the git ref holding the drop history
+    ///
+    /// Only records added to it after subscribing are notified about.
+    #[clap(
+        long = "drop",
+        value_parser,
+        value_name = "REF",
+        default_value_t = REF_IT_PATCHES.parse().unwrap(),
+    )]
+    drop_ref: Refname,
+    /// The topic to be notified about
+    #[clap(value_parser)]
+    topic: Topic,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Unsubscribe {
+    #[clap(flatten)]
+    common: Common,
+    /// The topic to no longer be notified about
+    #[clap(value_parser)]
+    topic: Topic,
+}
+
+#[derive(serde::Serialize)]
+pub struct Output {
+    subscriptions: Vec<Topic>,
+}
+
+pub fn subscribe(args: Subscribe) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.common.git_dir)?;
+    let topic = args.topic.to_string();
+    repo.config()?
+        .open_level(git2::ConfigLevel::Local)?
+        .set_multivar(cfg::git::IT_SUBSCRIBE, &format!("^{topic}$"), &topic)?;
+
+    let notified_ref = subscriptions::notified_ref(&args.drop_ref)?;
+    if if_not_found_none(repo.refname_to_id(&notified_ref))?.is_none() {
+        if let Some(tip) = if_not_found_none(repo.refname_to_id(&args.drop_ref))? {
+            subscriptions::mark_notified(&repo, &args.drop_ref, tip)?;
+        }
+    }
+
+    output(&repo)
+}
+
+pub fn unsubscribe(args: Unsubscribe) -> cmd::Result<Output> {
+    let repo = git::repo::open(&args.common.git_dir)?;
+    let topic = args.topic.to_string();
+    if_not_found_none(
+        repo.config()?
+            .open_level(git2::ConfigLevel::Local)?
+            .remove_multivar(cfg::git::IT_SUBSCRIBE, &format!("^{topic}$")),
+    )?
+    .ok_or_else(|| anyhow!("not subscribed to {topic} in this repository"))?;
+
+    output(&repo)
+}
+
+fn output(repo: &git2::Repository) -> cmd::Result<Output> {
+    let subscriptions = cfg::git::subscriptions(&cfg::git::open(repo)?)?;
+    Ok(Output {
+        subscriptions: subscriptions.into_iter().collect(),
+    })
+}
+
+/// Notify the user of new records on subscribed topics in `drop_ref`
+///
+/// The news are passed to the executable configured as `it.notifyHook`, or
+/// else printed as a digest. Once that succeeded, the records are not notified
+/// about again.
+pub fn notify(repo: &git2::Repository, drop_ref: &str) -> cmd::Result<()> {
+    let cfg = cfg::git::open(repo)?;
+    let topics = cfg::git::subscriptions(&cfg)?;
+    let news = match subscriptions::news(repo, drop_ref, &topics)? {
+        Some(news) => news,
+        None => return Ok(()),
+    };
+    if !news.is_empty() {
+        match cfg::git::notify_hook(&cfg)? {
+            Some(hook) => subscriptions::run_hook(&hook, repo.path(), &news)?,
+            None => digest(&news),
+        }
+    }
+    subscriptions::mark_notified(repo, drop_ref, news.tip)?;
+
+    Ok(())
+}
+
+fn digest(news: &subscriptions::News) {
+    for (topic, updates) in &news.topics {
+        info!("New on topic {topic}:");
+        for update in updates {
+            let kind = if update.comment { "comment" } else { "patch" };
+            info!("  {} {kind} {}", update.time.date(), update.heads);
+        }
+    }
+}
@@ -13,10 +13,14 @@ use anyhow::anyhow;
 use clap::ValueHint;
 
 use super::Common;
+
+mod inspect;
+
 use crate::{
     cmd::{
         self,
         ui::{
+            self,
             debug,
             info,
             warn,
@@ -72,6 +76,10 @@ pub struct Unbundle {
     /// The drop history to find the topic in
     #[clap(value_parser)]
     drop: Option<String>,
+    /// Print statistics about the topic's bundles, and ask for confirmation
+    /// before updating any refs
+    #[clap(long, value_parser)]
+    inspect: bool,
 }
 
 #[derive(serde::Serialize)]
@@ -146,6 +154,14 @@ pub fn unbundle(args: Unbundle) -> cmd::Result<Output> {
         cmd::abort!();
     }
 
+    if args.inspect {
+        inspect::inspect(&repo, &bundle_dir, on_topic.iter().rev())?;
+        if !ui::confirm("Proceed with unbundling?")? {
+            info!("Aborting");
+            cmd::abort!();
+        }
+    }
+
     info!("Unbundling topic records...");
     let mut tx = refs::Transaction::new(&repo)?;
     let topic_ref = tx.lock_ref(args.topic.as_refname())?;
@@ -0,0 +1,166 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    collections::{
+        BTreeSet,
+        HashSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+use crate::{
+    bundle::ObjectId,
+    cmd::{
+        self,
+        ui::{
+            debug,
+            info,
+        },
+    },
+    git::{
+        if_not_found_none,
+        Refname,
+    },
+    patches::{
+        Bundle,
+        Record,
+    },
+};
+
+/// Index the bundles of `records` into a scratch repository, and print
+/// statistics about them
+///
+/// `records` are expected in the order they should be applied in. The scratch
+/// repository borrows objects from `repo`, which is left untouched.
+pub fn inspect<'a, I>(repo: &git2::Repository, bundle_dir: &Path, records: I) -> cmd::Result<()>
+where
+    I: IntoIterator<Item = &'a Record>,
+{
+    let tmp = tempfile::tempdir()?;
+    let scratch = git2::Repository::init_bare(tmp.path())?;
+    let odb = scratch.odb()?;
+    odb.add_disk_alternate(&repo.path().join("objects").to_string_lossy())?;
+
+    for rec in records {
+        let hash = rec.bundle_hash();
+        let info = rec.bundle_info();
+        let bundle = Bundle::from_stored(bundle_dir, info.as_expect())?;
+        if bundle.is_encrypted() {
+            info!("Bundle {hash}: encrypted, cannot inspect");
+            continue;
+        }
+
+        let before = packs(&scratch)?;
+        let stats = bundle.packdata()?.index_with_stats(&odb)?;
+        let depth = packs(&scratch)?
+            .difference(&before)
+            .filter_map(|idx| max_delta_depth(idx))
+            .max();
+        info!(
+            "Bundle {hash}: {} objects, {} deltas ({} bases found locally){}",
+            stats.objects,
+            stats.deltas,
+            stats.local_objects,
+            depth
+                .map(|d| format!(", max delta depth {d}"))
+                .unwrap_or_default()
+        );
+
+        for (name, tip) in &info.references {
+            if name.starts_with("refs/it/") {
+                continue;
+            }
+            diffstat(&scratch, &info.prerequisites, name, tip)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn diffstat(
+    repo: &git2::Repository,
+    prerequisites: &BTreeSet<ObjectId>,
+    name: &Refname,
+    tip: &ObjectId,
+) -> cmd::Result<()> {
+    let tip = repo.find_object(tip.try_into()?, None)?.peel_to_commit()?;
+    let mut base = None;
+    for oid in prerequisites {
+        let oid = git2::Oid::try_from(oid)?;
+        if if_not_found_none(repo.merge_base(tip.id(), oid))? == Some(oid) {
+            base = Some(repo.find_commit(oid)?.tree()?);
+            break;
+        }
+    }
+    let diff = repo.diff_tree_to_tree(base.as_ref(), Some(&tip.tree()?), None)?;
+    let stats = diff.stats()?;
+    info!(
+        "  {name}: {} files changed, {} insertions(+), {} deletions(-)",
+        stats.files_changed(),
+        stats.insertions(),
+        stats.deletions()
+    );
+    for delta in diff.deltas() {
+        if let Some(path) = delta.new_file().path().or_else(|| delta.old_file().path()) {
+            info!("    {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Pack index files of `repo`
+fn packs(repo: &git2::Repository) -> cmd::Result<HashSet<PathBuf>> {
+    let mut idx = HashSet::new();
+    for entry in repo.path().join("objects").join("pack").read_dir()? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "idx").unwrap_or(false) {
+            idx.insert(path);
+        }
+    }
+
+    Ok(idx)
+}
+
+/// Longest delta chain in the pack `idx`
+///
+/// libgit2 doesn't tell us, so ask `git verify-pack`. Returns `None` if that
+/// fails for any reason.
+fn max_delta_depth(idx: &Path) -> Option<usize> {
+    let out = Command::new("git")
+        .args(["verify-pack", "-s"])
+        .arg(idx)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| debug!("git verify-pack: {e}"))
+        .ok()?;
+    if !out.status.success() {
+        debug!("git verify-pack: {}", out.status);
+        return None;
+    }
+
+    // "non delta: 3 objects"
+    // "chain length = 1: 2 objects"
+    let depth = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| {
+            line.strip_prefix("chain length = ")?
+                .split(':')
+                .next()?
+                .parse::<usize>()
+                .ok()
+        })
+        .max()
+        .unwrap_or(0);
+
+    Some(depth)
+}
@@ -20,20 +20,29 @@ use zeroize::Zeroizing;
 use crate::{
     cmd::{
         self,
+        drafts::Draft,
         Aborted,
     },
     patches::notes,
 };
 
 mod editor;
+pub use editor::{
+    set_edit_via,
+    EditVia,
+};
+mod messages;
+pub use messages::Msg;
 mod output;
 pub use output::{
     debug,
     error,
     info,
     warn,
+    LogFormat,
     Output,
 };
+pub mod term;
 
 pub fn edit_commit_message(
     repo: &git2::Repository,
@@ -64,11 +73,36 @@ pub fn edit_cover_letter(repo: &git2::Repository) -> cmd::Result<notes::Simple>
     )
 }
 
+/// Edit a comment, keeping the text entered as a `draft`
+///
+/// If a draft exists already, the user is offered to resume editing it. If the
+/// editor exits unsuccessfully, the draft is kept and the command aborted.
 pub fn edit_comment(
     repo: &git2::Repository,
     re: Option<&notes::Simple>,
+    draft: Option<&Draft>,
 ) -> cmd::Result<notes::Simple> {
-    abort_if_empty("comment", editor::Comment::new(repo.path())?.edit(re))
+    let resume = match draft {
+        Some(draft) => match draft.load()? {
+            Some(text) if confirm(Msg::ResumeDraft.text())? => Some(text),
+            _ => None,
+        },
+        None => None,
+    };
+    let (text, ok) = editor::Comment::new(repo.path())?.edit(re, resume.as_deref())?;
+    if let (Some(draft), Some(text)) = (draft, &text) {
+        draft.save(text)?;
+    }
+    if !ok {
+        if text.is_some() && draft.is_some() {
+            info!("{}", Msg::EditorFailedDraftKept.text());
+        } else {
+            info!("{}", Msg::EditorFailed.text());
+        }
+        cmd::abort!()
+    }
+
+    abort_if_empty("comment", Ok(text.map(notes::Simple::new)))
 }
 
 pub fn edit_metadata<T>(template: T) -> cmd::Result<T>
@@ -80,11 +114,73 @@ where
 
 fn abort_if_empty<T>(ctx: &str, edit: io::Result<Option<T>>) -> cmd::Result<T> {
     edit?.map(Ok).unwrap_or_else(|| {
-        info!("Aborting due to empty {ctx}");
+        info!("{}", Msg::AbortEmpty.fmt(&[("ctx", &ctx)]));
         cmd::abort!()
     })
 }
 
+/// Ask the user a yes/no question on the terminal
+///
+/// Returns `false` if stderr is not a terminal.
+pub fn confirm(prompt: &str) -> cmd::Result<bool> {
+    let tty = Term::stderr();
+    if !tty.is_term() {
+        return Ok(false);
+    }
+    tty.write_str(&format!("{prompt} [y/N] "))?;
+    let answer = tty.read_line()?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Ask the user for a line of input on the terminal, offering `default`
+///
+/// An empty answer selects the default. Fails if stderr is not a terminal.
+pub fn prompt(prompt: &str, default: &str) -> cmd::Result<String> {
+    let tty = interactive_term()?;
+    if default.is_empty() {
+        tty.write_str(&format!("{prompt} "))?;
+    } else {
+        tty.write_str(&format!("{prompt} [{default}] "))?;
+    }
+    let answer = tty.read_line()?;
+    let answer = answer.trim();
+
+    Ok(if answer.is_empty() { default } else { answer }.to_owned())
+}
+
+/// Let the user pick one of `items` on the terminal, returning its index
+///
+/// If `default` is `None`, the user may also pick none of the items. Fails if
+/// stderr is not a terminal.
+pub fn select<T>(prompt: &str, items: &[T], default: Option<usize>) -> cmd::Result<Option<usize>>
+where
+    T: std::fmt::Display,
+{
+    let tty = interactive_term()?;
+    tty.write_line(prompt)?;
+    for (i, item) in items.iter().enumerate() {
+        tty.write_line(&format!("{:>3}) {item}", i + 1))?;
+    }
+    loop {
+        let default = default.map(|i| (i + 1).to_string()).unwrap_or_default();
+        let answer = self::prompt(Msg::SelectNumber.text(), &default)?;
+        if answer.is_empty() {
+            return Ok(None);
+        }
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=items.len()).contains(&n) => return Ok(Some(n - 1)),
+            _ => tty.write_line(&Msg::SelectInvalid.fmt(&[("max", &items.len())]))?,
+        }
+    }
+}
+
+fn interactive_term() -> cmd::Result<Term> {
+    let tty = Term::stderr();
+    ensure!(tty.is_term(), "interactive input requires a terminal");
+    Ok(tty)
+}
+
 pub fn askpass(prompt: &str) -> cmd::Result<Zeroizing<Vec<u8>>> {
     const DEFAULT_ASKPASS: &str = "ssh-askpass";
 
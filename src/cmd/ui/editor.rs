@@ -4,6 +4,7 @@
 use std::{
     env,
     ffi::OsString,
+    fs,
     io::{
         self,
         BufRead as _,
@@ -18,18 +19,25 @@ use std::{
 
 use tempfile::TempPath;
 
+use super::term;
 use crate::{
     fs::LockedFile,
     patches::notes,
 };
 
+mod remote;
+pub use remote::{
+    set as set_edit_via,
+    EditVia,
+};
+
 const SCISSORS: &str = "# ------------------------ >8 ------------------------";
 
 pub struct Commit(Editmsg);
 
 impl Commit {
     pub fn new<P: AsRef<Path>>(git_dir: P) -> io::Result<Self> {
-        Editmsg::new(git_dir.as_ref().join("COMMIT_EDITMSG")).map(Self)
+        Editmsg::new("commit-message", git_dir.as_ref().join("COMMIT_EDITMSG")).map(Self)
     }
 
     pub fn edit(self, branch: &str, diff: git2::Diff) -> io::Result<Option<String>> {
@@ -73,7 +81,7 @@ pub struct CoverLetter(Editmsg);
 
 impl CoverLetter {
     pub fn new<P: AsRef<Path>>(git_dir: P) -> io::Result<Self> {
-        Editmsg::new(git_dir.as_ref().join("NOTES_EDITMSG")).map(Self)
+        Editmsg::new("cover-letter", git_dir.as_ref().join("NOTES_EDITMSG")).map(Self)
     }
 
     // TODO: render patch series a la git log
@@ -107,39 +115,58 @@ pub struct Comment(Editmsg);
 
 impl Comment {
     pub fn new<P: AsRef<Path>>(git_dir: P) -> io::Result<Self> {
-        Editmsg::new(git_dir.as_ref().join("NOTES_EDITMSG")).map(Self)
+        Editmsg::new("comment", git_dir.as_ref().join("NOTES_EDITMSG")).map(Self)
     }
 
-    pub fn edit(self, re: Option<&notes::Simple>) -> io::Result<Option<notes::Simple>> {
-        let txt = self.0.edit(|buf| {
-            write!(
-                buf,
-                "
+    /// Edit a comment, pre-filled with `draft` if given
+    ///
+    /// Returns the text entered, and whether the editor exited successfully.
+    pub fn edit(
+        self,
+        re: Option<&notes::Simple>,
+        draft: Option<&str>,
+    ) -> io::Result<(Option<String>, bool)> {
+        self.0
+            .edit_with_status(|buf| comment_template(buf, re, draft))
+    }
+}
+
+/// Pre-fill the editor for a comment
+///
+/// The comment being replied to is untrusted, so is [`term::sanitize_json`]d.
+fn comment_template<W: io::Write>(
+    buf: &mut W,
+    re: Option<&notes::Simple>,
+    draft: Option<&str>,
+) -> io::Result<()> {
+    if let Some(draft) = draft {
+        writeln!(buf, "{draft}")?;
+    }
+    write!(
+        buf,
+        "
 # Enter your comment above. Lines starting with '#' will be ignored,
 # and an empty message aborts the comment creation.
 "
-            )?;
+    )?;
 
-            if let Some(prev) = re {
-                write!(
-                    buf,
-                    "#
+    if let Some(prev) = re {
+        write!(
+            buf,
+            "#
 {SCISSORS}
 # Do not modify or remove the line above.
 # Everything below it will be ignored.
 #
 # Replying to:
 "
-                )?;
-
-                serde_json::to_writer_pretty(buf, prev)?;
-            }
+        )?;
 
-            Ok(())
-        })?;
-
-        Ok(txt.map(notes::Simple::new))
+        let prev = serde_json::to_string_pretty(prev)?;
+        buf.write_all(term::sanitize_json(&prev).as_bytes())?;
     }
+
+    Ok(())
 }
 
 pub struct Metadata {
@@ -153,7 +180,7 @@ impl Metadata {
             .suffix(".json")
             .tempfile()?
             .into_temp_path();
-        let msg = Editmsg::new(&_tmp)?;
+        let msg = Editmsg::new("metadata", &_tmp)?;
 
         Ok(Self { _tmp, msg })
     }
@@ -174,23 +201,41 @@ impl Metadata {
 }
 
 struct Editmsg {
+    kind: &'static str,
     file: LockedFile,
 }
 
 impl Editmsg {
-    fn new<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
-        LockedFile::in_place(path, true, 0o644).map(|file| Self { file })
+    fn new<P: Into<PathBuf>>(kind: &'static str, path: P) -> io::Result<Self> {
+        LockedFile::in_place(path, true, 0o644).map(|file| Self { kind, file })
     }
 
-    fn edit<F>(mut self, pre_fill: F) -> io::Result<Option<String>>
+    fn edit<F>(self, pre_fill: F) -> io::Result<Option<String>>
+    where
+        F: FnOnce(&mut LockedFile) -> io::Result<()>,
+    {
+        self.edit_with_status(pre_fill).map(|(msg, _)| msg)
+    }
+
+    fn edit_with_status<F>(mut self, pre_fill: F) -> io::Result<(Option<String>, bool)>
     where
         F: FnOnce(&mut LockedFile) -> io::Result<()>,
     {
         pre_fill(&mut self.file)?;
-        Command::new(editor())
-            .arg(self.file.edit_path())
-            .spawn()?
-            .wait()?;
+        let success = match remote::get() {
+            Some(via) => {
+                self.file.flush()?;
+                let template = fs::read_to_string(self.file.edit_path())?;
+                let (text, ok) = via.edit(self.kind, &template)?;
+                fs::write(self.file.edit_path(), text.unwrap_or_default())?;
+                ok
+            },
+            None => Command::new(editor())
+                .arg(self.file.edit_path())
+                .spawn()?
+                .wait()?
+                .success(),
+        };
         self.file.reopen()?;
         let mut msg = String::new();
         for line in io::BufReader::new(self.file).lines() {
@@ -208,7 +253,8 @@ impl Editmsg {
         let len = msg.trim_end().len();
         msg.truncate(len);
 
-        Ok(if msg.is_empty() { None } else { Some(msg) })
+        let msg = if msg.is_empty() { None } else { Some(msg) };
+        Ok((msg, success))
     }
 }
 
@@ -226,3 +272,23 @@ fn editor() -> OsString {
     }
     DEFAULT_EDITOR.into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_template_escapes_reply() {
+        let _lock = term::tests::lock();
+        let re = notes::Simple::basic("\x1b]0;pwned\x07\u{9b}2J\u{202e}txt.exe".to_owned());
+        let mut buf = Vec::new();
+        comment_template(&mut buf, Some(&re), Some("draft")).unwrap();
+        let buf = String::from_utf8(buf).unwrap();
+
+        assert!(buf.starts_with("draft\n"));
+        let (_, replied) = buf.split_once(SCISSORS).unwrap();
+        assert!(replied.contains(r#""\u001b]0;pwned\u0007\u009b2J\u202etxt.exe""#));
+        assert!(!buf.contains(|c: char| c.is_control() && c != '\n'));
+        assert!(!buf.contains('\u{202e}'));
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Delegating edits to a frontend
+//!
+//! GUI frontends and IDE plugins can not intercept the `$EDITOR` based flows.
+//! Instead, they may set `--edit-via` (or `IT_EDIT_VIA`) to either the path of
+//! a unix domain socket they listen on, or `fd:N` for a file descriptor
+//! inherited by the `it` process which can be both read from and written to
+//! (eg. one end of a `socketpair(2)`).
+//!
+//! For every edit, a single line of JSON is written:
+//!
+//! ```json
+//! {"kind":"comment","text":"<template>"}
+//! ```
+//!
+//! where `kind` is one of `commit-message`, `cover-letter`, `comment` or
+//! `metadata`, and `text` is exactly what would be presented in `$EDITOR`.
+//! The frontend responds with a single line of JSON:
+//!
+//! ```json
+//! {"text":"<edited>","ok":true}
+//! ```
+//!
+//! A `null` text aborts the edit, as does an empty one. `ok` is optional and
+//! defaults to `true`; `false` has the same meaning as the editor exiting
+//! unsuccessfully. The edited text is subject to the same processing and
+//! validation as when edited in `$EDITOR`.
+//!
+//! When connecting to a socket, a new connection is established for every
+//! edit.
+
+use std::{
+    fmt,
+    io::{
+        self,
+        BufRead as _,
+    },
+    path::PathBuf,
+    str::FromStr,
+};
+
+use once_cell::sync::OnceCell;
+
+static EDIT_VIA: OnceCell<EditVia> = OnceCell::new();
+
+/// Delegate all edits to `via` for the remainder of the process
+///
+/// Has no effect if called more than once.
+pub fn set(via: EditVia) {
+    let _ = EDIT_VIA.set(via);
+}
+
+pub(super) fn get() -> Option<&'static EditVia> {
+    EDIT_VIA.get()
+}
+
+/// Maximum size of a response line
+const MAX_RESPONSE: u64 = 1_000_000;
+
+#[derive(Clone, Debug)]
+pub enum EditVia {
+    Socket(PathBuf),
+    Fd(i32),
+}
+
+impl EditVia {
+    /// Present `text` for editing, returning the edited text and whether the
+    /// edit was successful
+    pub(super) fn edit(&self, kind: &str, text: &str) -> io::Result<(Option<String>, bool)> {
+        #[derive(serde::Serialize)]
+        struct Request<'a> {
+            kind: &'a str,
+            text: &'a str,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Response {
+            text: Option<String>,
+            #[serde(default = "ok")]
+            ok: bool,
+        }
+
+        fn ok() -> bool {
+            true
+        }
+
+        let mut req = serde_json::to_vec(&Request { kind, text })?;
+        req.push(b'\n');
+        let line = self.roundtrip(&req)?;
+        if line.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{self} closed without responding"),
+            ));
+        }
+        let Response { text, ok } = serde_json::from_str(&line)?;
+
+        Ok((text, ok))
+    }
+
+    #[cfg(unix)]
+    fn roundtrip(&self, req: &[u8]) -> io::Result<String> {
+        use std::os::unix::{
+            io::FromRawFd as _,
+            net::UnixStream,
+        };
+
+        fn go<S>(mut s: S, req: &[u8]) -> io::Result<String>
+        where
+            S: io::Read + io::Write,
+        {
+            s.write_all(req)?;
+            s.flush()?;
+            let mut line = String::new();
+            io::BufReader::new(s.take(MAX_RESPONSE)).read_line(&mut line)?;
+            Ok(line)
+        }
+
+        match self {
+            Self::Socket(path) => go(UnixStream::connect(path)?, req),
+            Self::Fd(fd) => {
+                // The descriptor is owned by the parent process, and may be
+                // used for multiple edits
+                let file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(*fd) });
+                go(&*file, req)
+            },
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn roundtrip(&self, _: &[u8]) -> io::Result<String> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--edit-via is only supported on unix",
+        ))
+    }
+}
+
+impl FromStr for EditVia {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("fd:") {
+            Some(fd) => fd
+                .parse()
+                .map(Self::Fd)
+                .map_err(|e| format!("invalid file descriptor '{fd}': {e}")),
+            None if s.is_empty() => Err("empty socket path".into()),
+            None => Ok(Self::Socket(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for EditVia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Socket(path) => write!(f, "{}", path.display()),
+            Self::Fd(fd) => write!(f, "fd:{fd}"),
+        }
+    }
+}
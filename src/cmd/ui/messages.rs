@@ -0,0 +1,105 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Catalog of the messages of human-readable output
+//!
+//! Messages are referred to by [`Msg`] instead of being spelled out where they
+//! are used, so they can be translated in one place. Placeholders of the form
+//! `{name}` are substituted by [`Msg::fmt`]. Only English is provided for now.
+
+use std::fmt::Display;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Msg {
+    /// Placeholder for an empty list
+    None,
+    HeadingBranches,
+    HeadingUnseenTopics,
+    HeadingPending,
+    ColTopic,
+    ColSubject,
+    ColChanges,
+    ColState,
+    ColTip,
+    ColPrevious,
+    ColTime,
+    ColSubmitter,
+    ColIssues,
+    NoLocalBranch,
+    UpToDate,
+    /// {ahead}
+    Ahead,
+    /// {behind}
+    Behind,
+    /// {ahead} {behind}
+    Diverged,
+    /// {ctx}
+    AbortEmpty,
+    EditorFailed,
+    EditorFailedDraftKept,
+    ResumeDraft,
+    SelectNumber,
+    /// {max}
+    SelectInvalid,
+    WizardTopic,
+    WizardReplyTo,
+    WizardBase,
+    WizardHead,
+    /// {count} {base}
+    WizardRange,
+    /// {base}
+    WizardEmptyRange,
+    WizardHeader,
+    WizardProceed,
+}
+
+impl Msg {
+    /// The message template, with placeholders left as is
+    pub fn text(self) -> &'static str {
+        use Msg::*;
+
+        match self {
+            None => "(none)",
+            HeadingBranches => "Branches:",
+            HeadingUnseenTopics => "Topics with unseen notes:",
+            HeadingPending => "Pending submissions:",
+            ColTopic => "TOPIC",
+            ColSubject => "SUBJECT",
+            ColChanges => "CHANGES",
+            ColState => "STATE",
+            ColTip => "TIP",
+            ColPrevious => "PREVIOUS",
+            ColTime => "TIME",
+            ColSubmitter => "SUBMITTER",
+            ColIssues => "ISSUES",
+            NoLocalBranch => "no local branch",
+            UpToDate => "up to date",
+            Ahead => "{ahead} ahead",
+            Behind => "{behind} behind",
+            Diverged => "diverged ({ahead} ahead, {behind} behind)",
+            AbortEmpty => "Aborting due to empty {ctx}",
+            EditorFailed => "Editor exited unsuccessfully",
+            EditorFailedDraftKept => "Editor exited unsuccessfully, comment kept as draft",
+            ResumeDraft => "Resume the unsubmitted draft for this topic?",
+            SelectNumber => "Number:",
+            SelectInvalid => "Please enter a number between 1 and {max}",
+            WizardTopic => "Post to an existing topic? (leave empty to start a new one)",
+            WizardReplyTo => "Reply to:",
+            WizardBase => "Base branch:",
+            WizardHead => "Head revision:",
+            WizardRange => "{count} commit(s) on top of {base}:",
+            WizardEmptyRange => "No commits on top of {base}",
+            WizardHeader => "The patch bundle will contain:",
+            WizardProceed => "Sign and proceed?",
+        }
+    }
+
+    /// The message with the placeholders substituted by `args`
+    pub fn fmt(self, args: &[(&str, &dyn Display)]) -> String {
+        let mut out = self.text().to_owned();
+        for (name, val) in args {
+            out = out.replace(&format!("{{{name}}}"), &val.to_string());
+        }
+        out
+    }
+}
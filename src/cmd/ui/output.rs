@@ -1,14 +1,65 @@
 // Copyright © 2022 Kim Altintop <kim@eagain.io>
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
+use std::fmt::Write as _;
+
 pub use log::{
     debug,
     error,
     info,
     warn,
 };
+use time::{
+    format_description::well_known::Rfc3339,
+    OffsetDateTime,
+};
+
+use crate::trace;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable messages, prefixed by the spans they were emitted in
+    Text,
+    /// One JSON object per line, including the time, level, and spans
+    Json,
+}
+
+pub struct Output {
+    format: LogFormat,
+}
+
+impl Output {
+    pub fn new(format: LogFormat) -> Self {
+        Self { format }
+    }
 
-pub struct Output;
+    fn log_json(&self, record: &log::Record) {
+        let spans = trace::visit(|spans| {
+            spans
+                .iter()
+                .map(|span| {
+                    let mut obj = serde_json::Map::new();
+                    obj.insert("name".into(), span.name.into());
+                    for (k, v) in &span.fields {
+                        obj.insert((*k).into(), v.as_str().into());
+                    }
+                    serde_json::Value::Object(obj)
+                })
+                .collect::<Vec<_>>()
+        });
+        let time = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let line = serde_json::json!({
+            "time": time,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+            "spans": spans,
+        });
+        eprintln!("{line}");
+    }
+}
 
 impl log::Log for Output {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
@@ -20,6 +71,10 @@ impl log::Log for Output {
         if !self.enabled(meta) {
             return;
         }
+        if let LogFormat::Json = self.format {
+            return self.log_json(record);
+        }
+
         let level = meta.level();
         let style = {
             let s = console::Style::new().for_stderr();
@@ -37,7 +92,28 @@ impl log::Log for Output {
             }
         };
 
-        eprintln!("{}", style.apply_to(record.args()));
+        let mut msg = trace::visit(|spans| {
+            let mut prefix = String::new();
+            for span in spans {
+                prefix.push_str(span.name);
+                if !span.fields.is_empty() {
+                    prefix.push('{');
+                    for (i, (k, v)) in span.fields.iter().enumerate() {
+                        let sep = if i > 0 { " " } else { "" };
+                        let _ = write!(prefix, "{sep}{k}={v}");
+                    }
+                    prefix.push('}');
+                }
+                prefix.push_str(": ");
+            }
+            prefix
+        });
+        let _ = write!(msg, "{}", record.args());
+        let msg = match super::term::width_stderr() {
+            Some(width) if console::user_attended_stderr() => super::term::wrap(&msg, width),
+            _ => msg,
+        };
+        eprintln!("{}", style.apply_to(msg));
     }
 
     fn flush(&self) {}
@@ -0,0 +1,385 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Rendering of human-readable output to the terminal
+//!
+//! Text is wrapped, and table columns are aligned, according to the width of
+//! the terminal. Colours follow the `NO_COLOR` and `CLICOLOR` /
+//! `CLICOLOR_FORCE` conventions. Control characters are escaped, cf.
+//! [`sanitize`].
+
+use std::{
+    borrow::Cow,
+    env,
+    fmt::Write as _,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+};
+
+use console::{
+    measure_text_width,
+    truncate_str,
+    Term,
+};
+
+/// Tables and wrapped text are never narrower than this, even if the
+/// terminal is
+const MIN_WIDTH: usize = 40;
+
+static RAW: AtomicBool = AtomicBool::new(false);
+
+/// Don't [`sanitize`] output for the remainder of the process
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed)
+}
+
+/// Escape control characters in `text`, except newlines and tabs
+///
+/// Notes, commit messages, and the like are written by untrusted submitters,
+/// and may contain escape sequences which manipulate the terminal they are
+/// printed to, or bidirectional overrides which make the text read differently
+/// from what it is. Such characters are replaced by their Rust escape, eg.
+/// `\u{1b}`.
+///
+/// Returns `text` unchanged if [`set_raw`] was called.
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    escape_controls(text, |c, out| write!(out, "{}", c.escape_unicode()))
+}
+
+/// Like [`sanitize`], but for serialized JSON
+///
+/// The characters `serde_json` does not escape (DEL, the C1 range and
+/// bidirectional overrides) are replaced by their JSON escape, eg. `\u009b`.
+/// Unlike [`sanitize`], this doesn't change the value the JSON represents.
+pub fn sanitize_json(json: &str) -> Cow<'_, str> {
+    escape_controls(json, |c, out| write!(out, "\\u{:04x}", c as u32))
+}
+
+fn escape_controls<F>(text: &str, mut escape: F) -> Cow<'_, str>
+where
+    F: FnMut(char, &mut String) -> std::fmt::Result,
+{
+    let unsafe_char = |c: char| (c.is_control() && c != '\n' && c != '\t') || is_bidi(c);
+    if RAW.load(Ordering::Relaxed) || !text.contains(unsafe_char) {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if unsafe_char(c) {
+            escape(c, &mut out).ok();
+        } else {
+            out.push(c);
+        }
+    }
+
+    Cow::Owned(out)
+}
+
+/// Whether `c` is an explicit bidirectional embedding, override or isolate
+fn is_bidi(c: char) -> bool {
+    matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Apply the user's colour preferences from the environment
+///
+/// `NO_COLOR` (if set to a non-empty value) disables colours unconditionally.
+/// Otherwise, `CLICOLOR_FORCE` (if not "0") enables colours even if the output
+/// is not a terminal, and `CLICOLOR=0` disables them. If none of these is
+/// set, colours are used when writing to a terminal.
+pub fn init_colors() {
+    let var = |name| env::var(name).ok().filter(|v| !v.is_empty());
+    let enabled = if var("NO_COLOR").is_some() {
+        Some(false)
+    } else if var("CLICOLOR_FORCE").map_or(false, |v| v != "0") {
+        Some(true)
+    } else if var("CLICOLOR").map_or(false, |v| v == "0") {
+        Some(false)
+    } else {
+        None
+    };
+    if let Some(enabled) = enabled {
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
+}
+
+/// The width of the terminal stdout is connected to, if any
+///
+/// `COLUMNS` takes precedence, if set.
+pub fn width() -> Option<usize> {
+    width_of(&Term::stdout())
+}
+
+/// The width of the terminal stderr is connected to, if any
+pub fn width_stderr() -> Option<usize> {
+    width_of(&Term::stderr())
+}
+
+fn width_of(term: &Term) -> Option<usize> {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|cols| cols.parse().ok())
+        .or_else(|| {
+            term.is_term()
+                .then(|| term.size_checked())
+                .flatten()
+                .map(|(_, cols)| cols as usize)
+        })
+        .map(|cols: usize| cols.max(MIN_WIDTH))
+}
+
+/// Wrap `text` at whitespace, such that no line exceeds `width` columns
+///
+/// Existing line breaks are preserved, and lines which fit are left alone.
+/// Words longer than `width` are not broken.
+pub fn wrap(text: &str, width: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if measure_text_width(line) <= width {
+            out.push_str(line);
+            continue;
+        }
+        // Keep the indentation of the line for continuation lines
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let mut col = 0;
+        for word in line.split_whitespace() {
+            let len = measure_text_width(word);
+            if col == 0 {
+                out.push_str(indent);
+                col = measure_text_width(indent);
+            } else if col + 1 + len > width {
+                out.push('\n');
+                out.push_str(indent);
+                col = measure_text_width(indent);
+            } else {
+                out.push(' ');
+                col += 1;
+            }
+            out.push_str(word);
+            col += len;
+        }
+    }
+
+    out
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum Align {
+    #[default]
+    Left,
+    Right,
+}
+
+/// Rows of text rendered with aligned columns
+///
+/// The last column is truncated if a row would otherwise exceed the width of
+/// the terminal.
+#[derive(Default)]
+pub struct Table {
+    indent: usize,
+    align: Vec<Align>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indent all rows by `n` spaces
+    pub fn indent(mut self, n: usize) -> Self {
+        self.indent = n;
+        self
+    }
+
+    /// Set the alignment of column `col`
+    pub fn align(mut self, col: usize, align: Align) -> Self {
+        if self.align.len() <= col {
+            self.align.resize(col + 1, Align::default());
+        }
+        self.align[col] = align;
+        self
+    }
+
+    pub fn row<I, S>(&mut self, cols: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.rows.push(
+            cols.into_iter()
+                .map(|col| {
+                    let col = col.into();
+                    match sanitize(&col) {
+                        Cow::Borrowed(_) => col,
+                        Cow::Owned(escaped) => escaped,
+                    }
+                })
+                .collect(),
+        );
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Render to a string, fitting the rows into `width` columns if given
+    pub fn render(&self, width: Option<usize>) -> String {
+        const SEP: &str = "  ";
+
+        let ncols = self.rows.iter().map(Vec::len).max().unwrap_or(0);
+        let mut widths = vec![0; ncols];
+        for row in &self.rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(measure_text_width(cell));
+            }
+        }
+
+        let mut out = String::new();
+        for row in &self.rows {
+            let mut line = " ".repeat(self.indent);
+            for (i, cell) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push_str(SEP);
+                }
+                let pad = widths[i] - measure_text_width(cell);
+                let last = i + 1 == row.len();
+                match self.align.get(i).copied().unwrap_or_default() {
+                    Align::Left if last => line.push_str(cell),
+                    Align::Left => {
+                        line.push_str(cell);
+                        line.push_str(&" ".repeat(pad));
+                    },
+                    Align::Right => {
+                        line.push_str(&" ".repeat(pad));
+                        line.push_str(cell);
+                    },
+                }
+            }
+            match width {
+                Some(width) => writeln!(out, "{}", truncate_str(&line, width, "…")).ok(),
+                None => writeln!(out, "{line}").ok(),
+            };
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::sync::{
+        Mutex,
+        MutexGuard,
+    };
+
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    /// Serialises tests against [`set_raw`], which affects the whole process
+    pub(in crate::cmd::ui) fn lock() -> MutexGuard<'static, ()> {
+        static LOCK: Lazy<Mutex<()>> = Lazy::new(Mutex::default);
+        LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn csi() {
+        let _lock = lock();
+        assert_eq!(
+            sanitize("\x1b[2J\x1b[1;31mred\x1b[0m"),
+            "\\u{1b}[2J\\u{1b}[1;31mred\\u{1b}[0m"
+        )
+    }
+
+    #[test]
+    fn osc() {
+        let _lock = lock();
+        // Set the window title, and a hyperlink terminated by BEL and ST
+        assert_eq!(
+            sanitize("\x1b]0;pwned\x07\x1b]8;;https://evil.example\x1b\\click\x1b]8;;\x1b\\"),
+            "\\u{1b}]0;pwned\\u{7}\\u{1b}]8;;https://evil.example\\u{1b}\\click\\u{1b}]8;;\\u{1b}\\"
+        )
+    }
+
+    #[test]
+    fn c1_and_del() {
+        let _lock = lock();
+        assert_eq!(
+            sanitize("\u{9b}2J\u{9d}0;x\u{9c}"),
+            "\\u{9b}2J\\u{9d}0;x\\u{9c}"
+        );
+        assert_eq!(sanitize("a\x7fb\x08c\rd"), "a\\u{7f}b\\u{8}c\\u{d}d");
+    }
+
+    #[test]
+    fn bidi() {
+        let _lock = lock();
+        assert_eq!(
+            sanitize("access = \"user\u{202e} \u{2066}// admin\u{2069}\u{2066}\""),
+            "access = \"user\\u{202e} \\u{2066}// admin\\u{2069}\\u{2066}\""
+        );
+        assert_eq!(sanitize("\u{202a}\u{202d}"), "\\u{202a}\\u{202d}");
+    }
+
+    #[test]
+    fn benign() {
+        let _lock = lock();
+        let text = "Fix the thing\n\n\tIt was broken. Größe: 🦀, עברית";
+        assert!(matches!(sanitize(text), Cow::Borrowed(t) if t == text));
+    }
+
+    #[test]
+    fn json_escapes() {
+        let _lock = lock();
+        let value = serde_json::json!({
+            "message": "\x1b[31m\u{9b}31m\x7f\u{202e}\n\"\\",
+        });
+        let json = serde_json::to_string(&value).unwrap();
+        let sanitized = sanitize_json(&json);
+        assert_eq!(
+            sanitized,
+            r#"{"message":"\u001b[31m\u009b31m\u007f\u202e\n\"\\"}"#
+        );
+        assert!(!sanitized.contains(|c: char| c.is_control() || is_bidi(c)));
+        let parsed: serde_json::Value = serde_json::from_str(&sanitized).unwrap();
+        assert_eq!(parsed, value);
+    }
+
+    #[test]
+    fn table_rows() {
+        let _lock = lock();
+        let mut table = Table::new();
+        table
+            .row(["\x1b[8mhidden", "ok"])
+            .row(["x", "\u{9b}1A\u{202e}gnp.exe"]);
+        assert_eq!(
+            table.render(None),
+            "\\u{1b}[8mhidden  ok\nx                \\u{9b}1A\\u{202e}gnp.exe\n"
+        );
+    }
+
+    #[test]
+    fn raw() {
+        let _lock = lock();
+        let payload = "\x1b[2J\u{9b}\u{202e}";
+        set_raw(true);
+        let text = sanitize(payload).into_owned();
+        let json = sanitize_json(payload).into_owned();
+        let mut table = Table::new();
+        table.row([payload]);
+        let rendered = table.render(None);
+        set_raw(false);
+
+        assert_eq!(text, payload);
+        assert_eq!(json, payload);
+        assert_eq!(rendered, format!("{payload}\n"));
+        assert_ne!(sanitize(payload), payload);
+    }
+}
@@ -2,3 +2,4 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 pub mod args;
+pub mod auth;
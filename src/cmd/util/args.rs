@@ -8,6 +8,7 @@ use core::{
 };
 use std::{
     borrow::Borrow,
+    collections::BTreeSet,
     convert::Infallible,
     env,
     path::PathBuf,
@@ -16,7 +17,17 @@ use std::{
 
 pub use crate::git::Refname;
 use crate::{
-    cfg::paths,
+    cfg::{
+        self,
+        paths,
+    },
+    cmd::{
+        id::{
+            namespace,
+            Namespace,
+        },
+        ui::warn,
+    },
     git,
 };
 
@@ -71,6 +82,11 @@ impl<'a> IntoIterator for &'a SearchPath {
 
 /// A [`SearchPath`] with a [`Default`] appropriate for `it` identity
 /// repositories.
+///
+/// When resolving an identity, the namespace it is stored in takes precedence
+/// over the order of the path elements: identities in the `own` namespace of
+/// any repository are preferred over imported ones, cf.
+/// [`namespace::find_in_path`].
 #[derive(Clone, Debug)]
 pub struct IdSearchPath(SearchPath);
 
@@ -98,6 +114,110 @@ impl IdSearchPath {
 
         rs
     }
+
+    /// Like [`Self::open_git`], but also consider the identity repositories
+    /// advertised by the drop in `drop`
+    ///
+    /// `advertised` paths are resolved relative to the directory configured as
+    /// [`cfg::git::IT_ID_PATH_ROOT`], or the drop repository if not set. The
+    /// drop metadata may come from a remote, so paths resolving to a location
+    /// outside of that directory are ignored with a warning. Local path
+    /// elements take precedence, advertised ones are appended unless already
+    /// present. A warning is emitted if a local repository shadows an
+    /// identity found in an advertised repository with a different tip.
+    ///
+    /// The objects of the local repositories are made available to `drop`
+    /// persistently, cf. [`git::add_alternates`]. The advertised repositories
+    /// are opened separately, and only consulted by the handle `drop` for the
+    /// remainder of the process, cf. [`git::repo::add_transient_alternates`].
+    pub fn open_git_with<S: AsRef<str>>(
+        &self,
+        drop: &git2::Repository,
+        advertised: &[S],
+    ) -> crate::Result<Vec<git2::Repository>> {
+        let mut rs = self.open_git();
+        // IT_ID_PATH could differ from what was used at initialisation
+        git::add_alternates(drop, &rs)?;
+        if advertised.is_empty() {
+            return Ok(rs);
+        }
+
+        let mut seen = self
+            .into_iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect::<BTreeSet<_>>();
+        let root = match cfg::git::id_path_root(&drop.config()?)? {
+            Some(root) => drop.path().join(root),
+            None => drop.path().to_owned(),
+        };
+        let root = root.canonicalize().unwrap_or(root);
+        let local = rs.len();
+        for path in advertised {
+            let path = root.join(path.as_ref());
+            let path = match path.canonicalize() {
+                Ok(canonical) if canonical.starts_with(&root) => canonical,
+                Ok(_) => {
+                    warn!(
+                        "ignoring advertised identity repository {} outside of {}",
+                        path.display(),
+                        root.display()
+                    );
+                    continue;
+                },
+                Err(e) => {
+                    warn!(
+                        "advertised identity repository {} could not be opened: {e}",
+                        path.display(),
+                    );
+                    continue;
+                },
+            };
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            match git::repo::open_bare(&path) {
+                Ok(repo) => {
+                    warn_shadowed(&rs[..local], &repo);
+                    rs.push(repo)
+                },
+                Err(e) => warn!(
+                    "advertised identity repository {} could not be opened: {}",
+                    path.display(),
+                    e.message()
+                ),
+            }
+        }
+        git::repo::add_transient_alternates(drop, &rs[local..])?;
+
+        Ok(rs)
+    }
+}
+
+fn warn_shadowed(local: &[git2::Repository], advertised: &git2::Repository) {
+    let refs = match advertised.references_glob(&format!("{}/*", namespace::REF_PREFIX)) {
+        Ok(refs) => refs,
+        Err(_) => return,
+    };
+    let ids = refs
+        .flatten()
+        .filter_map(|r| Namespace::from_refname(r.name()?).map(|(_, id)| id))
+        .collect::<BTreeSet<_>>();
+    for id in ids {
+        let theirs = match namespace::find(advertised, &id) {
+            Ok(Some(r)) => r,
+            _ => continue,
+        };
+        if let Ok(Some((repo, ours))) = namespace::find_in_path(local, &id) {
+            if ours.target() != theirs.target() {
+                warn!(
+                    "{} in {} shadows the version advertised by {}",
+                    ours.name().unwrap_or_default(),
+                    repo.path().display(),
+                    advertised.path().display()
+                );
+            }
+        }
+    }
 }
 
 impl Default for IdSearchPath {
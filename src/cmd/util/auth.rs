@@ -0,0 +1,83 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::fmt::Write as _;
+
+use anyhow::bail;
+use url::Url;
+
+use crate::{
+    cfg::git::AllowedDrop,
+    cmd,
+    metadata::{
+        drop::RoleName,
+        Drop,
+        IdentityId,
+    },
+};
+
+/// Check that `id` holds the role `role` in the drop metadata `meta`
+///
+/// Intended to be called before any interactive input is requested from the
+/// user, so commands requiring a role the signer doesn't have fail early.
+/// `action` completes the sentence "... required to".
+pub fn ensure_role(meta: &Drop, id: &IdentityId, role: RoleName, action: &str) -> cmd::Result<()> {
+    if meta.roles.has_role(id, role) {
+        return Ok(());
+    }
+
+    let mut msg = format!("identity {id} does not have the '{role}' role required to {action}");
+    let members = meta.roles.members(role);
+    if members.is_empty() {
+        msg.push_str("\nno identities are currently assigned this role");
+    } else {
+        msg.push_str("\nthe role is currently held by:");
+        for member in members {
+            write!(msg, "\n  {member}")?;
+        }
+    }
+    bail!(msg)
+}
+
+/// Check that `id` is allowed to submit patches to the drop described by
+/// `meta`, optionally via `url`
+///
+/// The drop is allowed if `allowed` is empty, or if it contains either an
+/// identity in the root role of `meta`, or `url`. This guards against
+/// accidentally posting to the wrong drop when operating multiple identities.
+pub fn ensure_allowed_drop(
+    allowed: &[AllowedDrop],
+    meta: &Drop,
+    id: &IdentityId,
+    url: Option<&Url>,
+) -> cmd::Result<()> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let is_allowed = allowed.iter().any(|a| match a {
+        AllowedDrop::Root(root) => meta.roles.root.ids.contains(root),
+        AllowedDrop::Url(allowed) => url.map_or(false, |url| same_url(allowed, url)),
+    });
+    if is_allowed {
+        return Ok(());
+    }
+
+    let mut msg = format!("identity {id} is not bound to this drop");
+    msg.push_str("\nthe drop's root identities are:");
+    for root in &meta.roles.root.ids {
+        write!(msg, "\n  {root}")?;
+    }
+    msg.push_str("\nthe identity is bound to:");
+    for a in allowed {
+        match a {
+            AllowedDrop::Root(root) => write!(msg, "\n  {root}")?,
+            AllowedDrop::Url(url) => write!(msg, "\n  {url}")?,
+        }
+    }
+    msg.push_str("\nuse --force-drop to submit anyway");
+    bail!(msg)
+}
+
+fn same_url(a: &Url, b: &Url) -> bool {
+    a.as_str().trim_end_matches('/') == b.as_str().trim_end_matches('/')
+}
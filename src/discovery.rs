@@ -0,0 +1,234 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Drop discovery via DNS
+//!
+//! A domain advertises a drop by `SRV` records at `_it._tcp.<domain>`, whose
+//! targets serve the drop over HTTPS (or plain HTTP for onion services). `TXT`
+//! records at the same name carry whitespace-separated attributes:
+//!
+//! * `root=<identity id>` pins a root identity of the drop, may be repeated
+//! * `path=<path>` is the path of the drop below the target, default `/`
+//!
+//! Queries are made using the JSON flavour of DNS-over-HTTPS via [`net`], so
+//! they are subject to the configured proxy. The resolver is configured by
+//! [`cfg::git::IT_DNS_RESOLVER`].
+//!
+//! [`cfg::git::IT_DNS_RESOLVER`]: crate::cfg::git::IT_DNS_RESOLVER
+
+use std::{
+    collections::BTreeSet,
+    path::Path,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+    Context,
+};
+use log::debug;
+use url::Url;
+
+use crate::{
+    metadata::IdentityId,
+    net,
+    Result,
+};
+
+const SERVICE: &str = "_it._tcp";
+
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+const STATUS_NOERROR: u16 = 0;
+const STATUS_NXDOMAIN: u16 = 3;
+
+/// A drop advertised by a domain
+pub struct Discovered {
+    pub domain: String,
+    /// Candidate URLs of the drop, in order of preference
+    pub urls: Vec<Url>,
+    /// Pinned root identities
+    pub roots: BTreeSet<IdentityId>,
+}
+
+impl Discovered {
+    /// The most preferred URL
+    pub fn url(&self) -> &Url {
+        &self.urls[0]
+    }
+
+    /// Ensure that all of `roots` are pinned
+    ///
+    /// If the domain doesn't pin any identities, this always succeeds.
+    pub fn verify_roots<'a, I>(&self, roots: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a IdentityId>,
+    {
+        if self.roots.is_empty() {
+            return Ok(());
+        }
+        let unpinned = roots
+            .into_iter()
+            .filter(|id| !self.roots.contains(id))
+            .collect::<Vec<_>>();
+        ensure!(
+            unpinned.is_empty(),
+            "root identities not pinned by {}: {unpinned:?}",
+            self.domain
+        );
+
+        Ok(())
+    }
+}
+
+/// Heuristically determine whether `s` is a domain name rather than a URL or
+/// local path
+pub fn is_domain(s: &str) -> bool {
+    !s.contains(['/', ':', '\\'])
+        && s.contains('.')
+        && !s.starts_with('.')
+        && !Path::new(s).exists()
+}
+
+/// Discover the drop advertised by `domain`, using the DoH `resolver`
+pub fn discover(resolver: &Url, domain: &str) -> Result<Discovered> {
+    let domain = domain.trim_end_matches('.');
+    let name = format!("{SERVICE}.{domain}");
+
+    let mut path = None;
+    let mut roots = BTreeSet::new();
+    for txt in query(resolver, &name, TYPE_TXT)? {
+        for attr in txt_strings(&txt).split_whitespace() {
+            match attr.split_once('=') {
+                Some(("root", id)) => {
+                    roots.insert(
+                        id.parse()
+                            .with_context(|| format!("invalid root pin {id}"))?,
+                    );
+                },
+                Some(("path", p)) => path = Some(p.to_owned()),
+                _ => debug!("ignoring unknown attribute {attr} in TXT record of {name}"),
+            }
+        }
+    }
+
+    let mut srv = query(resolver, &name, TYPE_SRV)?
+        .iter()
+        .map(|data| Srv::parse(data))
+        .collect::<Result<Vec<_>>>()?;
+    // Lacking a source of randomness, weights only serve to order records of
+    // equal priority
+    srv.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+    let urls = srv
+        .into_iter()
+        .filter(|srv| srv.target != ".")
+        .map(|srv| srv.url(path.as_deref().unwrap_or("/")))
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(!urls.is_empty(), "no drop advertised at {name}");
+
+    Ok(Discovered {
+        domain: domain.to_owned(),
+        urls,
+        roots,
+    })
+}
+
+struct Srv {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+impl Srv {
+    fn parse(data: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid SRV record: {data}");
+        let mut parts = data.split_whitespace();
+        let mut num = || -> Result<u16> {
+            parts
+                .next()
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())
+        };
+        let priority = num()?;
+        let weight = num()?;
+        let port = num()?;
+        let target = parts.next().ok_or_else(invalid)?;
+        let target = match target.trim_end_matches('.') {
+            "" => ".",
+            t => t,
+        };
+
+        Ok(Self {
+            priority,
+            weight,
+            port,
+            target: target.to_owned(),
+        })
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        let scheme = if self.target.ends_with(".onion") {
+            "http"
+        } else {
+            "https"
+        };
+        let path = path.trim_start_matches('/');
+        Url::parse(&format!("{scheme}://{}:{}/{path}", self.target, self.port)).map_err(Into::into)
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Response {
+    status: u16,
+    #[serde(default)]
+    answer: Vec<Answer>,
+}
+
+#[derive(serde::Deserialize)]
+struct Answer {
+    #[serde(rename = "type")]
+    typ: u16,
+    data: String,
+}
+
+fn query(resolver: &Url, name: &str, typ: u16) -> Result<Vec<String>> {
+    let mut url = resolver.clone();
+    url.query_pairs_mut()
+        .append_pair("name", name)
+        .append_pair("type", &typ.to_string());
+    debug!("querying {url}");
+    let resp: Response = net::request("GET", &url)?
+        .set("Accept", "application/dns-json")
+        .call()?
+        .into_json()?;
+    match resp.status {
+        STATUS_NOERROR => Ok(resp
+            .answer
+            .into_iter()
+            .filter(|a| a.typ == typ)
+            .map(|a| a.data)
+            .collect()),
+        STATUS_NXDOMAIN => Ok(vec![]),
+        status => Err(anyhow!("DNS query for {name} failed with status {status}")),
+    }
+}
+
+/// Unquote the character strings of a TXT record as presented by DoH
+/// resolvers
+///
+/// Attributes are short enough to never span multiple strings, so the strings
+/// are joined by whitespace.
+fn txt_strings(data: &str) -> String {
+    if !data.contains('"') {
+        return data.to_owned();
+    }
+    data.split('"')
+        .enumerate()
+        .filter_map(|(i, s)| (i % 2 == 1).then_some(s))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
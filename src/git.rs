@@ -20,8 +20,14 @@ pub use commit::{
     commit_signed,
     verify_commit_signature,
 };
+#[cfg(feature = "fixtures")]
+pub use commit::{
+    next_fixed_time,
+    set_fixed_time,
+};
 
 pub mod config;
+pub mod maintenance;
 
 pub mod refs;
 pub use refs::{
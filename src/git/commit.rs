@@ -1,10 +1,60 @@
 // Copyright © 2022 Kim Altintop <kim@eagain.io>
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
+#[cfg(feature = "fixtures")]
+use std::sync::Mutex;
+
+#[cfg(feature = "fixtures")]
+use once_cell::sync::Lazy;
+
 use crate::ssh;
 
 const SSHSIG_NAMESPACE: &str = "git";
 
+/// Seconds between the timestamps handed out by [`next_fixed_time`]
+#[cfg(feature = "fixtures")]
+const FIXED_TIME_STEP: i64 = 60;
+
+#[cfg(feature = "fixtures")]
+static FIXED_TIME: Lazy<Mutex<Option<i64>>> = Lazy::new(|| Mutex::new(None));
+
+/// Timestamp commits created by this process starting at `start` (in seconds
+/// since the epoch) instead of using the current time, or stop doing so if
+/// `None`
+///
+/// Every commit advances the clock by a minute, so that commit order is
+/// preserved. Intended for generating reproducible fixtures.
+#[cfg(feature = "fixtures")]
+pub fn set_fixed_time(start: Option<i64>) {
+    *FIXED_TIME.lock().unwrap() = start;
+}
+
+/// Advance the clock set by [`set_fixed_time`], if any, returning the current
+/// time
+#[cfg(feature = "fixtures")]
+pub fn next_fixed_time() -> Option<i64> {
+    let mut time = FIXED_TIME.lock().unwrap();
+    let now = (*time)?;
+    *time = Some(now + FIXED_TIME_STEP);
+    Some(now)
+}
+
+fn signature(repo: &git2::Repository) -> crate::Result<git2::Signature<'static>> {
+    let sig = repo.signature()?;
+    #[cfg(feature = "fixtures")]
+    if let Some(time) = next_fixed_time() {
+        let name = String::from_utf8_lossy(sig.name_bytes());
+        let email = String::from_utf8_lossy(sig.email_bytes());
+        return Ok(git2::Signature::new(
+            &name,
+            &email,
+            &git2::Time::new(time, 0),
+        )?);
+    }
+
+    Ok(sig)
+}
+
 pub fn commit_signed<'a, S>(
     signer: &mut S,
     repo: &'a git2::Repository,
@@ -15,7 +65,7 @@ pub fn commit_signed<'a, S>(
 where
     S: crate::keys::Signer + ?Sized,
 {
-    let aut = repo.signature()?;
+    let aut = signature(repo)?;
     let buf = repo.commit_create_buffer(&aut, &aut, msg.as_ref(), tree, parents)?;
     let sig = {
         let hash = ssh::HashAlg::Sha512;
@@ -0,0 +1,187 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Repository maintenance after accepting patches
+//!
+//! Repacking must not run concurrently with accepting patches, which may be
+//! done by a `drop serve` process as well as locally, eg. by `patch record`.
+//! [`run`] thus holds a lock file, and every accept leaves an [`Accepting`]
+//! marker while in progress. An accept waits for the lock to be released,
+//! while [`run`] refuses to start if there are markers. Both create their
+//! file before checking for the other, so at least one of them will notice
+//! the other.
+
+use std::{
+    fs,
+    io::{
+        self,
+        Write,
+    },
+    path::Path,
+    process::{
+        Command,
+        Stdio,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use log::{
+    debug,
+    info,
+};
+use tempfile::NamedTempFile;
+
+use crate::fs::LockedFile;
+
+/// Directory (relative to GIT_DIR) of the files below
+const DIR: &str = "it";
+/// File (relative to GIT_DIR) counting the accepts since the last maintenance
+const ACCEPT_COUNTER: &str = "it/accepts-since-maintenance";
+/// Base name of the lock file (relative to GIT_DIR) held by [`run`]
+const MAINTENANCE_LOCK: &str = "it/maintenance";
+/// Prefix of the markers held by [`Accepting`], within [`DIR`]
+const ACCEPT_MARKER: &str = "accepting-";
+/// Markers older than this are assumed to be left behind by a process which
+/// didn't exit cleanly
+const STALE_MARKER: Duration = Duration::from_secs(60 * 60);
+/// How long [`Accepting::begin`] waits for a concurrent [`run`] to finish
+const MAINTENANCE_WAIT: Duration = Duration::from_secs(5 * 60);
+
+/// The maintenance tasks, as arguments to the `git` CLI
+///
+/// libgit2 doesn't support any of them, so we shell out.
+const TASKS: &[&[&str]] = &[
+    &["repack", "-a", "-d", "-l", "-q"],
+    &["commit-graph", "write", "--reachable", "--no-progress"],
+    &["multi-pack-index", "write", "--no-progress"],
+];
+
+/// Record that a patch was accepted into the repository at `git_dir`
+///
+/// Returns `true` if `interval` accepts have been recorded since the last
+/// time this function returned `true`, ie. if [`run`] should be called. An
+/// `interval` of zero disables maintenance.
+///
+/// If the counter is currently locked by a concurrent process, the accept is
+/// not counted.
+pub fn record_accept(git_dir: &Path, interval: u64) -> crate::Result<bool> {
+    if interval == 0 {
+        return Ok(false);
+    }
+
+    let path = git_dir.join(ACCEPT_COUNTER);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut lock = match LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS) {
+        Ok(lock) => lock,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            debug!("{} is locked, not counting accept", path.display());
+            return Ok(false);
+        },
+        Err(e) => return Err(e.into()),
+    };
+    // Holding the lock, nobody else is going to write to `path`
+    let count = match fs::read_to_string(&path) {
+        Ok(s) => s.trim().parse::<u64>().unwrap_or(0),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    } + 1;
+    let due = count >= interval;
+    writeln!(lock, "{}", if due { 0 } else { count })?;
+    lock.persist()?;
+
+    Ok(due)
+}
+
+/// Repack the repository at `git_dir`, and write a commit-graph and
+/// multi-pack-index
+///
+/// Fails if patches are being accepted concurrently, cf. [`Accepting`].
+pub fn run(git_dir: &Path) -> crate::Result<()> {
+    let dir = git_dir.join(DIR);
+    fs::create_dir_all(&dir)?;
+    let _lock = LockedFile::atomic(
+        git_dir.join(MAINTENANCE_LOCK),
+        true,
+        LockedFile::DEFAULT_PERMISSIONS,
+    )
+    .map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => {
+            anyhow!("maintenance of {} already in progress", git_dir.display())
+        },
+        _ => e.into(),
+    })?;
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(ACCEPT_MARKER)
+        {
+            continue;
+        }
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        ensure!(
+            age > STALE_MARKER,
+            "patches are being accepted into {}, try again later",
+            git_dir.display()
+        );
+        fs::remove_file(entry.path())?;
+    }
+
+    info!("Running maintenance on {}", git_dir.display());
+    for args in TASKS {
+        debug!("git {}", args.join(" "));
+        let status = Command::new("git")
+            .arg("--git-dir")
+            .arg(git_dir)
+            .args(*args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .status()?;
+        ensure!(status.success(), "git {} failed: {status}", args[0]);
+    }
+
+    Ok(())
+}
+
+/// Marker of an accept in progress, removed when dropped
+pub struct Accepting {
+    _marker: NamedTempFile,
+}
+
+impl Accepting {
+    /// Mark an accept into the repository at `git_dir` as in progress
+    ///
+    /// If [`run`] is in progress, waits up to [`MAINTENANCE_WAIT`] for it to
+    /// finish.
+    pub fn begin(git_dir: &Path) -> crate::Result<Self> {
+        let dir = git_dir.join(DIR);
+        fs::create_dir_all(&dir)?;
+        let marker = tempfile::Builder::new()
+            .prefix(ACCEPT_MARKER)
+            .tempfile_in(&dir)?;
+        let lock = git_dir.join(MAINTENANCE_LOCK).with_extension("lock");
+        let start = Instant::now();
+        while lock.exists() {
+            ensure!(
+                start.elapsed() < MAINTENANCE_WAIT,
+                "timed out waiting for maintenance of {} to finish",
+                git_dir.display()
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(Self { _marker: marker })
+    }
+}
@@ -280,7 +280,6 @@ enum Op {
         target: Refname,
         reflog: Cow<'static, str>,
     },
-    #[allow(unused)]
     Remove,
 }
 
@@ -308,7 +307,6 @@ impl LockedRef {
         })
     }
 
-    #[allow(unused)]
     pub fn remove(&self) {
         self.op.set(Op::Remove)
     }
@@ -91,3 +91,19 @@ where
 
     Ok(())
 }
+
+/// Like [`add_alternates`], but only for the handle `repo`
+///
+/// The alternates file is not modified, so the objects of `alt` are only
+/// available to `repo` for as long as it is open.
+pub fn add_transient_alternates<'a, I>(repo: &git2::Repository, alt: I) -> Result<()>
+where
+    I: IntoIterator<Item = &'a git2::Repository>,
+{
+    let odb = repo.odb()?;
+    for alternate in alt {
+        odb.add_disk_alternate(&format!("{}", alternate.path().join("objects").display()))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Signing with keys managed by GnuPG
+//!
+//! `it` signatures are SSH signatures, so a GnuPG key is used by way of
+//! `gpg-agent`'s SSH agent emulation: the public key is obtained in OpenSSH
+//! format from `gpg --export-ssh-key`, and signing requests are sent to the
+//! socket reported by `gpgconf --list-dirs agent-ssh-socket`. The key must be
+//! of a type supported by both, ie. Ed25519, ECDSA over NIST P-256 or P-384,
+//! or RSA.
+
+use std::{
+    path::PathBuf,
+    process::{
+        self,
+        Command,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+    Context,
+};
+
+use crate::ssh::{
+    self,
+    agent::{
+        self,
+        UnixStream,
+    },
+};
+
+/// The default program to invoke if not configured otherwise
+pub const DEFAULT_PROGRAM: &str = "gpg";
+
+/// A GnuPG key, identified by anything `gpg` accepts as a key specifier
+pub struct Key {
+    pub spec: String,
+    pub public: ssh::PublicKey,
+}
+
+impl Key {
+    /// Look up the key `spec` using `program`
+    ///
+    /// `spec` is usually a key id or fingerprint. Subkeys may be selected by
+    /// appending a `!`, otherwise `gpg` picks an authentication capable
+    /// (sub)key.
+    pub fn lookup(program: &str, spec: &str) -> crate::Result<Self> {
+        let out = run(Command::new(program).arg("--export-ssh-key").arg(spec))?;
+        let public = ssh::PublicKey::from_openssh(out.trim())
+            .with_context(|| format!("invalid SSH key exported for gpg key {spec}"))?;
+
+        Ok(Self {
+            spec: spec.to_owned(),
+            public,
+        })
+    }
+}
+
+/// Path of the SSH agent socket of `gpg-agent`
+pub fn agent_ssh_socket() -> crate::Result<PathBuf> {
+    let out = run(Command::new("gpgconf").args(["--list-dirs", "agent-ssh-socket"]))?;
+    let path = out.trim();
+    ensure!(
+        !path.is_empty(),
+        "gpgconf did not report an agent-ssh-socket"
+    );
+
+    Ok(PathBuf::from(path))
+}
+
+/// Connect to the SSH agent socket of `gpg-agent`
+pub fn agent() -> crate::Result<agent::Client<UnixStream>> {
+    let path = agent_ssh_socket()?;
+    let conn = UnixStream::connect(&path)
+        .with_context(|| format!("unable to connect to gpg-agent at {}", path.display()))?;
+
+    Ok(conn.into())
+}
+
+fn run(cmd: &mut Command) -> crate::Result<String> {
+    let prog = cmd.get_program().to_string_lossy().into_owned();
+    let process::Output { status, stdout, .. } = cmd
+        .stderr(process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run {prog}"))?;
+    ensure!(status.success(), "{prog} exited with {status}");
+    String::from_utf8(stdout).map_err(|_| anyhow!("invalid output from {prog}"))
+}
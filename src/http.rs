@@ -2,28 +2,56 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
+    fmt,
     fs::File,
-    io::Cursor,
-    net::ToSocketAddrs,
+    io::{
+        self,
+        Cursor,
+        Read,
+    },
+    net::{
+        SocketAddr,
+        TcpListener,
+    },
+    num::NonZeroU64,
     path::{
         Path,
         PathBuf,
     },
+    process,
     sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        mpsc,
         Arc,
         Mutex,
     },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+use anyhow::{
+    anyhow,
+    ensure,
+};
 use digest::Digest;
 use log::{
     debug,
     error,
+    info,
+    warn,
 };
 use once_cell::sync::Lazy;
 use sha2::Sha256;
 use threadpool::ThreadPool;
 use tiny_http::{
+    ConfigListenAddr,
     Header,
     HeaderField,
     Method,
@@ -36,85 +64,305 @@ use url::Url;
 
 use crate::{
     bundle,
-    git,
-    keys,
+    cfg,
+    metadata,
     patches::{
         self,
-        AcceptArgs,
-        AcceptOptions,
+        composite,
+        upload,
+        HTTP_HEADER_CHUNK_HASH,
+        MAX_LEN_INFO,
     },
-    ssh::agent,
+    service::Service,
+    trace,
 };
 
+mod html;
+pub(crate) mod limit;
+mod proxy;
+
+pub use limit::Limits;
+pub use proxy::Trusted as TrustedProxy;
 pub use tiny_http::SslConfig;
 
+/// Configuration of the HTTP server
+#[derive(Debug, Default)]
 pub struct Options {
-    /// Directory of the drop repo
-    pub git_dir: PathBuf,
-    /// Directory from where to serve bundles
-    ///
-    /// Unless absolute, treated as relative to GIT_DIR.
-    pub bundle_dir: PathBuf,
-    /// Ref prefix under which to store the refs contained in patch bundles
-    pub unbundle_prefix: String,
-    /// The refname of the drop history
-    pub drop_ref: String,
-    /// The refname anchoring the seen objects tree
-    pub seen_ref: String,
     /// Size of the server's threadpool
     ///
     /// If `None`, the number of available CPUs is used.
     pub threads: Option<usize>,
+    /// Limits imposed on patch submissions
+    pub limits: Limits,
+    /// Maximum size in bytes of bundles uploaded in chunks
+    ///
+    /// If `None`, chunked uploads are disabled, and only bundles of up to
+    /// [`patches::MAX_LEN_BUNDLE`] can be submitted.
+    pub max_upload_len: Option<NonZeroU64>,
+    /// Peers whose `X-Forwarded-*` headers are trusted
+    ///
+    /// The client address used for rate limiting and logging is taken from
+    /// `X-Forwarded-For` if the peer is a trusted proxy, and absolute URLs are
+    /// generated from `X-Forwarded-Proto` and `X-Forwarded-Host` unless the
+    /// service has a public URL configured.
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Stop accepting requests once this yields, cf. [`shutdown_signals`]
+    ///
+    /// Requests already accepted are served before [`serve`] returns.
+    pub shutdown: Option<mpsc::Receiver<()>>,
+}
+
+/// A socket to accept connections on
+pub struct Listener {
+    pub listen: Listen,
     /// Certificate and key for `serve`ing over TLS.
     ///
     /// It is generally recommended to proxy behind a terminating web server and
     /// set this to `None`.
     pub tls: Option<SslConfig>,
-    /// IPFS API to publish received bundles to
-    pub ipfs_api: Option<Url>,
 }
 
-pub fn serve<A>(addr: A, opts: Options) -> !
+pub enum Listen {
+    /// Bind to a TCP socket address
+    ///
+    /// To serve both IPv4 and IPv6, use one [`Listener`] per address family.
+    Tcp(SocketAddr),
+    /// Bind to a Unix domain socket at the given path
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// Accept connections on an already bound socket
+    ///
+    /// See [`listen_fds`].
+    Inherited(tiny_http::Listener),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Inherited(tiny_http::Listener::Tcp(l)) => match l.local_addr() {
+                Ok(addr) => write!(f, "{addr} (inherited)"),
+                Err(_) => f.write_str("<unknown> (inherited)"),
+            },
+            #[cfg(unix)]
+            Self::Inherited(tiny_http::Listener::Unix(l)) => {
+                match l.local_addr().ok().as_ref().and_then(|a| a.as_pathname()) {
+                    Some(path) => write!(f, "unix:{} (inherited)", path.display()),
+                    None => f.write_str("unix:<unnamed> (inherited)"),
+                }
+            },
+        }
+    }
+}
+
+/// Obtain the sockets passed to us by the service manager, if any
+///
+/// Implements the `LISTEN_FDS` / `LISTEN_PID` protocol used by systemd's
+/// socket activation. The environment variables are removed, so child
+/// processes do not inherit them.
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Vec<tiny_http::Listener>> {
+    use std::{
+        env,
+        os::unix::{
+            io::{
+                FromRawFd,
+                IntoRawFd,
+                RawFd,
+            },
+            net::UnixListener,
+        },
+        process,
+    };
+
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let nfds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let nfds = match (pid, nfds) {
+        (Some(pid), Some(nfds)) if pid == process::id() => nfds,
+        _ => return Ok(vec![]),
+    };
+
+    let mut listeners = Vec::with_capacity(nfds as usize);
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + nfds {
+        // SAFETY: the service manager hands ownership of the fds in this range
+        // to us, and nothing else in this process refers to them.
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        // `local_addr` fails with `InvalidInput` for non-IP address families
+        let listener = match tcp.local_addr() {
+            Ok(_) => tiny_http::Listener::from(tcp),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                // SAFETY: see above
+                let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                unix.local_addr()?;
+                tiny_http::Listener::from(unix)
+            },
+            Err(e) => return Err(e),
+        };
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> io::Result<Vec<tiny_http::Listener>> {
+    Ok(vec![])
+}
+
+/// Block `SIGTERM` and `SIGINT`, and return a receiver which yields whenever
+/// either is received
+///
+/// The signals are blocked in the calling thread and all threads it spawns
+/// hereafter, and received by a dedicated thread. A signal delivered to a
+/// thread spawned before this is called terminates the process as usual, so
+/// it should be called early.
+#[cfg(unix)]
+pub fn shutdown_signals() -> io::Result<mpsc::Receiver<()>> {
+    use std::{
+        mem::MaybeUninit,
+        ptr,
+    };
+
+    // SAFETY: `sigemptyset` initialises the set, and both functions only fail
+    // for invalid signal numbers
+    let set = unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGINT);
+        set.assume_init()
+    };
+    // SAFETY: `set` is initialised, and the old mask is not requested
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || loop {
+            let mut sig = 0;
+            // SAFETY: `set` is initialised, and `sig` is a valid pointer
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 || tx.send(()).is_err() {
+                break;
+            }
+        })?;
+
+    Ok(rx)
+}
+
+#[cfg(not(unix))]
+pub fn shutdown_signals() -> io::Result<mpsc::Receiver<()>> {
+    let (_, rx) = mpsc::channel();
+    Ok(rx)
+}
+
+/// Serve `service` over HTTP on all `listeners`
+///
+/// Runs until [`Options::shutdown`] yields, after which requests already
+/// accepted are served to completion. If it yields again in the meantime, the
+/// process exits immediately.
+pub fn serve<I>(listeners: I, opts: Options, service: Arc<Service>) -> crate::Result<()>
 where
-    A: ToSocketAddrs,
+    I: IntoIterator<Item = Listener>,
 {
     let executor = ThreadPool::new(opts.threads.unwrap_or_else(num_cpus::get));
-    let server = tiny_http::Server::new(ServerConfig {
-        addr,
-        ssl: opts.tls,
-    })
-    .unwrap();
-
-    let repo = git::repo::open(&opts.git_dir).unwrap();
-    let config = repo.config().unwrap();
-
-    let git_dir = repo.path().to_owned();
-    let bundle_dir = if opts.bundle_dir.is_relative() {
-        git_dir.join(opts.bundle_dir)
-    } else {
-        opts.bundle_dir
-    };
-
-    let signer = keys::Agent::from_gitconfig(&config).unwrap();
+    let servers = listeners
+        .into_iter()
+        .map(|Listener { listen, tls }| {
+            let what = listen.to_string();
+            match listen {
+                Listen::Tcp(addr) => tiny_http::Server::new(ServerConfig {
+                    addr: ConfigListenAddr::IP(vec![addr]),
+                    ssl: tls,
+                }),
+                #[cfg(unix)]
+                Listen::Unix(path) => tiny_http::Server::new(ServerConfig {
+                    addr: ConfigListenAddr::Unix(path),
+                    ssl: tls,
+                }),
+                Listen::Inherited(listener) => tiny_http::Server::from_listener(listener, tls),
+            }
+            .map(Arc::new)
+            .map_err(|e| anyhow!("failed to listen on {what}: {e}"))
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    ensure!(!servers.is_empty(), "no listeners configured");
 
+    let uploads = opts
+        .max_upload_len
+        .map(|max| upload::Store::new(service.git_dir().join(cfg::paths::uploads()), max.get()));
     let handler = Arc::new(Handler {
-        repo: Mutex::new(repo),
-        signer: Mutex::new(signer),
-        bundle_dir,
-        unbundle_prefix: opts.unbundle_prefix,
-        drop_ref: opts.drop_ref,
-        seen_ref: opts.seen_ref,
-        ipfs_api: opts.ipfs_api,
+        service,
+        limiter: limit::Limiter::new(opts.limits, opts.max_upload_len),
+        trusted_proxies: opts.trusted_proxies,
+        uploads,
+        last_upload_gc: Mutex::new(None),
+        requests: AtomicU64::new(0),
     });
-    for req in server.incoming_requests() {
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    if let Some(shutdown) = opts.shutdown {
+        let servers = servers.clone();
+        let stopping = Arc::clone(&stopping);
+        thread::spawn(move || {
+            if shutdown.recv().is_err() {
+                return;
+            }
+            info!("Shutting down, finishing requests in flight");
+            stopping.store(true, Ordering::Release);
+            for server in &servers {
+                server.unblock();
+            }
+            if shutdown.recv().is_ok() {
+                warn!("Terminating");
+                process::exit(1);
+            }
+        });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    for server in &servers {
+        let server = Arc::clone(server);
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for req in server.incoming_requests() {
+                if tx.send(req).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    for req in rx {
         let handler = Arc::clone(&handler);
         executor.execute(move || handler.route(req))
     }
+    ensure!(stopping.load(Ordering::Acquire), "server died unexpectedly");
+    executor.join();
 
-    panic!("server died unexpectedly");
+    Ok(())
 }
 
+/// Interval at which unfinished chunked uploads are garbage collected
+const UPLOAD_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 static CONTENT_TYPE: Lazy<HeaderField> = Lazy::new(|| "Content-Type".parse().unwrap());
+static ETAG: Lazy<HeaderField> = Lazy::new(|| "ETag".parse().unwrap());
 
 static OCTET_STREAM: Lazy<Header> = Lazy::new(|| Header {
     field: CONTENT_TYPE.clone(),
@@ -124,6 +372,10 @@ static TEXT_PLAIN: Lazy<Header> = Lazy::new(|| Header {
     field: CONTENT_TYPE.clone(),
     value: "text/plain".parse().unwrap(),
 });
+static TEXT_HTML: Lazy<Header> = Lazy::new(|| Header {
+    field: CONTENT_TYPE.clone(),
+    value: "text/html; charset=utf-8".parse().unwrap(),
+});
 static JSON: Lazy<Header> = Lazy::new(|| Header {
     field: CONTENT_TYPE.clone(),
     value: "application/json".parse().unwrap(),
@@ -143,8 +395,20 @@ enum Resp {
         code: StatusCode,
         body: String,
     },
+    Html {
+        body: String,
+    },
     File {
-        file: File,
+        path: PathBuf,
+        len: usize,
+        etag: Header,
+        content_type: Header,
+    },
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: usize,
+        etag: Header,
+        content_type: Header,
     },
     Json {
         code: StatusCode,
@@ -167,35 +431,82 @@ impl Resp {
     };
 
     fn respond_to(self, req: Request) {
-        let remote_addr = *req.remote_addr();
+        let remote_addr = RemoteAddr(req.remote_addr().copied());
+        // tiny_http omits the body when responding to HEAD, but still emits
+        // the Content-Length given by the data length
+        let head = *req.method() == Method::Head;
         let response = Response::empty(500).with_header(SERVER.clone());
         let res = match self {
             Self::Empty { code } => req.respond(response.with_status_code(code)),
             Self::Text { code, body } => {
                 let len = body.len();
+                let etag = etag_of(body.as_bytes());
                 req.respond(
                     response
                         .with_status_code(code)
                         .with_header(TEXT_PLAIN.clone())
+                        .with_header(etag)
                         .with_data(Cursor::new(body.into_bytes()), Some(len)),
                 )
             },
-            Self::File { file } => {
-                let len = file.metadata().ok().and_then(|v| v.len().try_into().ok());
+            Self::Html { body } => {
+                let len = body.len();
+                let etag = etag_of(body.as_bytes());
                 req.respond(
                     response
                         .with_status_code(200)
-                        .with_header(OCTET_STREAM.clone())
-                        .with_data(file, len),
+                        .with_header(TEXT_HTML.clone())
+                        .with_header(etag)
+                        .with_data(Cursor::new(body.into_bytes()), Some(len)),
                 )
             },
+            Self::File {
+                path,
+                len,
+                etag,
+                content_type,
+            } => {
+                let response = response
+                    .with_status_code(200)
+                    .with_header(content_type)
+                    .with_header(etag);
+                if head {
+                    req.respond(response.with_data(io::empty(), Some(len)))
+                } else {
+                    match File::open(&path) {
+                        Ok(file) => req.respond(response.with_data(file, Some(len))),
+                        Err(e) => {
+                            error!("failed to open file {}: {e}", path.display());
+                            req.respond(Response::empty(500).with_header(SERVER.clone()))
+                        },
+                    }
+                }
+            },
+            Self::Reader {
+                reader,
+                len,
+                etag,
+                content_type,
+            } => {
+                let response = response
+                    .with_status_code(200)
+                    .with_header(content_type)
+                    .with_header(etag);
+                if head {
+                    req.respond(response.with_data(io::empty(), Some(len)))
+                } else {
+                    req.respond(response.with_data(reader, Some(len)))
+                }
+            },
             Self::Json { code, body } => {
                 let json = serde_json::to_vec(&body).unwrap();
                 let len = json.len();
+                let etag = etag_of(&json);
                 req.respond(
                     response
                         .with_status_code(code)
                         .with_header(JSON.clone())
+                        .with_header(etag)
                         .with_data(Cursor::new(json), Some(len)),
                 )
             },
@@ -207,6 +518,35 @@ impl Resp {
     }
 }
 
+struct RemoteAddr(Option<SocketAddr>);
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(addr) => write!(f, "{addr}"),
+            None => f.write_str("<unix socket>"),
+        }
+    }
+}
+
+impl From<limit::Rejected> for Resp {
+    fn from(rejected: limit::Rejected) -> Self {
+        match rejected {
+            limit::Rejected::Quota(retry_after) => Self::Text {
+                code: 429.into(),
+                body: format!(
+                    "submission quota exceeded, retry in {} seconds",
+                    retry_after.as_secs() + 1
+                ),
+            },
+            limit::Rejected::Busy => Self::Text {
+                code: 503.into(),
+                body: "too many uploads in progress, retry later".into(),
+            },
+        }
+    }
+}
+
 impl From<StatusCode> for Resp {
     fn from(code: StatusCode) -> Self {
         Self::Empty { code }
@@ -214,39 +554,169 @@ impl From<StatusCode> for Resp {
 }
 
 struct Handler {
-    repo: Mutex<git2::Repository>,
-    signer: Mutex<keys::Agent<agent::UnixStream>>,
-    bundle_dir: PathBuf,
-    unbundle_prefix: String,
-    drop_ref: String,
-    seen_ref: String,
-    ipfs_api: Option<Url>,
+    service: Arc<Service>,
+    limiter: limit::Limiter,
+    trusted_proxies: Vec<TrustedProxy>,
+    uploads: Option<upload::Store>,
+    last_upload_gc: Mutex<Option<Instant>>,
+    /// Number of requests received, used as request ids in the logs
+    requests: AtomicU64,
 }
 
 impl Handler {
     fn route(&self, mut req: Request) {
         use Method::*;
 
+        let client = proxy::client(&self.trusted_proxies, &req);
+        let _span = trace::span("request")
+            .with("id", self.requests.fetch_add(1, Ordering::Relaxed))
+            .with("remote", &client);
         debug!("{} {}", req.method(), req.url());
         let resp = match req.method() {
-            Get => match &request_target(&req)[..] {
-                ["-", "status"] => Resp::OK,
-                ["bundles", hash] => self.get_bundle(hash),
+            Get | Head => match &request_target(&req)[..] {
+                ["-", "healthz"] => Resp::OK,
+                ["-", "readyz"] => self.readiness(),
+                ["-", "status"] => self.status(&client),
+                [] if self.service.serve_html() => self.html_index(),
+                ["topics", topic] if self.service.serve_html() => self.html_topic(topic),
+                ["issues"] => self.issues(),
+                ["bundles", hash] => self.get_bundle(hash, &client),
+                ["patches", "uploads", id] => self.get_upload(id),
+                [".well-known", "it", "profile", id] => self.get_profile(id),
                 _ => Resp::NOT_FOUND,
             },
 
             Post => match &request_target(&req)[..] {
-                ["patches"] => self.post_patch(&mut req),
+                ["patches"] => self.post_patch(&client, &mut req),
+                ["patches", "uploads"] => self.post_upload(&client, &mut req),
+                ["patches", "composite"] => self.post_composite(&client, &mut req),
+                ["patches", "uploads", id] => {
+                    let id = id.to_string();
+                    self.finish_upload(&id, &client, &req)
+                },
+                ["patches", hash] => {
+                    let hash = hash.to_string();
+                    self.post_patch_stored(&hash, &client, &mut req)
+                },
+                _ => Resp::NOT_FOUND,
+            },
+
+            Put => match &request_target(&req)[..] {
+                ["patches", "uploads", id, index] => {
+                    let (id, index) = (id.to_string(), index.to_string());
+                    self.put_upload_chunk(&id, &index, &client, &mut req)
+                },
+                _ => Resp::NOT_FOUND,
+            },
+
+            Delete => match &request_target(&req)[..] {
+                ["patches", "uploads", id] => self.delete_upload(id),
                 _ => Resp::NOT_FOUND,
             },
 
             _ => Resp::METHOD_NOT_ALLOWED,
         };
 
-        resp.respond_to(req)
+        resp.respond_to(req);
+        self.service.maintain_if_due();
+        self.gc_uploads_if_due();
+    }
+
+    /// The externally visible base URL of the drop, if known
+    ///
+    /// A configured public URL takes precedence over the one conveyed by a
+    /// trusted proxy.
+    fn base_url<'a>(&'a self, client: &'a proxy::Client) -> Option<&'a Url> {
+        self.service.public_url().or(client.base_url.as_ref())
+    }
+
+    fn status(&self, client: &proxy::Client) -> Resp {
+        match self.service.status() {
+            Ok(mut status) => {
+                status.url = self.base_url(client).cloned();
+                Resp::Json {
+                    code: 200.into(),
+                    body: Box::new(status),
+                }
+            },
+            Err(e) => {
+                error!("failed to determine drop status: {e:#}");
+                Resp::INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+
+    fn html_index(&self) -> Resp {
+        let render = || -> crate::Result<String> {
+            let overview = self.service.overview()?;
+            let topics = self.service.topics()?;
+            Ok(html::index(&overview, &topics))
+        };
+        match render() {
+            Ok(body) => Resp::Html { body },
+            Err(e) => {
+                error!("failed to render drop overview: {e:#}");
+                Resp::INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+
+    fn html_topic(&self, topic: &str) -> Resp {
+        let topic = match topic.parse::<patches::Topic>() {
+            Ok(topic) => topic,
+            Err(_) => {
+                return Resp::Text {
+                    code: 400.into(),
+                    body: "invalid topic".into(),
+                }
+            },
+        };
+        let render = || -> crate::Result<String> {
+            let notes = self.service.topic(&topic)?;
+            let issues = self
+                .service
+                .issues()?
+                .remove(&topic)
+                .unwrap_or_default()
+                .into_iter()
+                .collect::<Vec<_>>();
+            Ok(html::topic(&topic, &issues, &notes))
+        };
+        match render() {
+            Ok(body) => Resp::Html { body },
+            Err(e) => match e.downcast_ref::<git2::Error>() {
+                Some(e) if e.code() == git2::ErrorCode::NotFound => Resp::NOT_FOUND,
+                _ => {
+                    error!("failed to render topic {topic}: {e:#}");
+                    Resp::INTERNAL_SERVER_ERROR
+                },
+            },
+        }
+    }
+
+    fn issues(&self) -> Resp {
+        match self.service.issues() {
+            Ok(links) => Resp::Json {
+                code: 200.into(),
+                body: Box::new(links),
+            },
+            Err(e) => {
+                error!("failed to determine linked issues: {e:#}");
+                Resp::INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+
+    fn readiness(&self) -> Resp {
+        let readiness = self.service.readiness();
+        let code = if readiness.is_ready() { 200 } else { 503 };
+        Resp::Json {
+            code: code.into(),
+            body: Box::new(readiness),
+        }
     }
 
-    fn get_bundle(&self, hash: &str) -> Resp {
+    fn get_bundle(&self, hash: &str, client: &proxy::Client) -> Resp {
         fn base_path(root: &Path, s: &str) -> Result<PathBuf, Resp> {
             bundle::Hash::is_valid(s)
                 .then(|| root.join(s))
@@ -257,62 +727,315 @@ impl Handler {
         }
 
         if let Some(hash) = hash.strip_suffix(bundle::list::DOT_FILE_EXTENSION) {
-            base_path(&self.bundle_dir, hash).map_or_else(
+            base_path(self.service.bundle_dir(), hash).map_or_else(
                 |x| x,
                 |base| {
                     let path = base.with_extension(bundle::list::FILE_EXTENSION);
-                    if !path.exists() && base.with_extension(bundle::FILE_EXTENSION).exists() {
-                        default_bundle_list(hash)
+                    if !path.exists()
+                        && bundle::chunks::exists(&base.with_extension(bundle::FILE_EXTENSION))
+                    {
+                        default_bundle_list(self.base_url(client), hash)
                     } else {
                         serve_file(path)
                     }
                 },
             )
         } else if let Some(hash) = hash.strip_suffix(bundle::DOT_FILE_EXTENSION) {
-            base_path(&self.bundle_dir, hash).map_or_else(
+            base_path(self.service.bundle_dir(), hash).map_or_else(
                 |x| x,
                 |mut path| {
                     path.set_extension(bundle::FILE_EXTENSION);
-                    serve_file(path)
+                    serve_bundle(path)
                 },
             )
         } else {
-            base_path(&self.bundle_dir, hash).map_or_else(
+            base_path(self.service.bundle_dir(), hash).map_or_else(
                 |x| x,
                 |mut base| {
                     base.set_extension(bundle::FILE_EXTENSION);
-                    if !base.exists() {
+                    if bundle::chunks::exists(&base) {
+                        serve_bundle(base)
+                    } else {
                         base.set_extension(bundle::list::FILE_EXTENSION);
+                        serve_file(base)
                     }
-                    serve_file(base)
                 },
             )
         }
     }
 
-    fn post_patch(&self, req: &mut Request) -> Resp {
-        patches::Submission::from_http(&self.bundle_dir, req)
-            .and_then(|mut sub| {
-                let repo = self.repo.lock().unwrap();
-                let mut signer = self.signer.lock().unwrap();
-                sub.try_accept(AcceptArgs {
-                    unbundle_prefix: &self.unbundle_prefix,
-                    drop_ref: &self.drop_ref,
-                    seen_ref: &self.seen_ref,
-                    repo: &repo,
-                    signer: &mut *signer,
-                    ipfs_api: self.ipfs_api.as_ref(),
-                    options: AcceptOptions::default(),
-                })
-            })
-            .map(|record| Resp::Json {
+    fn get_profile(&self, id: &str) -> Resp {
+        let id = match id.parse::<metadata::IdentityId>() {
+            Ok(id) => id,
+            Err(_) => {
+                return Resp::Text {
+                    code: 400.into(),
+                    body: "invalid identity id".into(),
+                }
+            },
+        };
+        match self.service.profile(&id) {
+            Ok(Some(path)) => match serve_file(path) {
+                Resp::File {
+                    path, len, etag, ..
+                } => Resp::File {
+                    path,
+                    len,
+                    etag,
+                    content_type: JSON.clone(),
+                },
+                resp => resp,
+            },
+            Ok(None) => Resp::NOT_FOUND,
+            Err(e) => {
+                error!("failed to look up profile of {id}: {e:#}");
+                Resp::INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+
+    fn post_patch(&self, client: &proxy::Client, req: &mut Request) -> Resp {
+        let upload = match self
+            .limiter
+            .submission(client.addr)
+            .and_then(|()| self.limiter.upload())
+        {
+            Ok(upload) => upload,
+            Err(rejected) => return rejected.into(),
+        };
+        self.accept(patches::Submission::from_http(
+            self.service.bundle_dir(),
+            req,
+            |body| Box::new(upload.throttle(body)),
+        ))
+    }
+
+    fn post_composite(&self, client: &proxy::Client, req: &mut Request) -> Resp {
+        let upload = match self
+            .limiter
+            .submission(client.addr)
+            .and_then(|()| self.limiter.upload())
+        {
+            Ok(upload) => upload,
+            Err(rejected) => return rejected.into(),
+        };
+        let parts = composite::read_body(upload.throttle(req.as_reader()), |name| {
+            self.service.sibling_bundle_dir(name)
+        });
+        match parts.and_then(|parts| self.service.accept_composite(parts)) {
+            Ok(records) => Resp::Json {
                 code: 200.into(),
-                body: Box::new(record),
-            })
-            .unwrap_or_else(|e| Resp::Text {
+                body: Box::new(records),
+            },
+            Err(e) => Resp::Text {
+                code: 400.into(),
+                body: e.to_string(),
+            },
+        }
+    }
+
+    fn post_patch_stored(&self, hash: &str, client: &proxy::Client, req: &mut Request) -> Resp {
+        let hash = match hash.parse::<bundle::Hash>() {
+            Ok(hash) => hash,
+            Err(_) => {
+                return Resp::Text {
+                    code: 400.into(),
+                    body: "invalid bundle hash".into(),
+                }
+            },
+        };
+        if self.service.stored_bundle(&hash).is_none() {
+            return Resp::NOT_FOUND;
+        }
+        if let Err(rejected) = self.limiter.submission(client.addr) {
+            return rejected.into();
+        }
+
+        self.accept(patches::Submission::from_http_stored(
+            self.service.bundle_dir(),
+            &hash,
+            req,
+        ))
+    }
+
+    fn uploads(&self) -> Result<&upload::Store, Resp> {
+        self.uploads.as_ref().ok_or_else(|| Resp::Text {
+            code: 403.into(),
+            body: "chunked uploads are not enabled".into(),
+        })
+    }
+
+    fn post_upload(&self, client: &proxy::Client, req: &mut Request) -> Resp {
+        let uploads = match self.uploads() {
+            Ok(uploads) => uploads,
+            Err(resp) => return resp,
+        };
+        if let Err(rejected) = self.limiter.submission(client.addr) {
+            return rejected.into();
+        }
+        let start = match req.body_length() {
+            Some(len) if len <= MAX_LEN_INFO => {
+                serde_json::from_reader::<_, upload::Start>(req.as_reader())
+                    .map_err(crate::Error::from)
+            },
+            _ => Err(anyhow::anyhow!("invalid upload request")),
+        };
+        match start.and_then(|start| uploads.create(start.len, client.addr)) {
+            Ok(status) => Resp::Json {
+                code: 201.into(),
+                body: Box::new(status),
+            },
+            Err(e) => Resp::Text {
                 code: 400.into(),
                 body: e.to_string(),
-            })
+            },
+        }
+    }
+
+    fn get_upload(&self, id: &str) -> Resp {
+        let uploads = match self.uploads() {
+            Ok(uploads) => uploads,
+            Err(resp) => return resp,
+        };
+        upload_status(uploads.status(id))
+    }
+
+    fn put_upload_chunk(
+        &self,
+        id: &str,
+        index: &str,
+        client: &proxy::Client,
+        req: &mut Request,
+    ) -> Resp {
+        let uploads = match self.uploads() {
+            Ok(uploads) => uploads,
+            Err(resp) => return resp,
+        };
+        if let Err(rejected) = self.limiter.upload_request(client.addr) {
+            return rejected.into();
+        }
+        let index = match index.parse::<u64>() {
+            Ok(index) => index,
+            Err(_) => {
+                return Resp::Text {
+                    code: 400.into(),
+                    body: "invalid chunk index".into(),
+                }
+            },
+        };
+        let hash = match req
+            .headers()
+            .iter()
+            .find(|hdr| hdr.field.equiv(HTTP_HEADER_CHUNK_HASH))
+        {
+            Some(hdr) => hdr.value.to_string(),
+            None => {
+                return Resp::Text {
+                    code: 400.into(),
+                    body: format!("missing header {HTTP_HEADER_CHUNK_HASH}"),
+                }
+            },
+        };
+        let upload = match self.limiter.upload() {
+            Ok(upload) => upload,
+            Err(rejected) => return rejected.into(),
+        };
+        upload_status(uploads.put_chunk(id, index, &hash, upload.throttle(req.as_reader())))
+    }
+
+    fn finish_upload(&self, id: &str, client: &proxy::Client, req: &Request) -> Resp {
+        let uploads = match self.uploads() {
+            Ok(uploads) => uploads,
+            Err(resp) => return resp,
+        };
+        if let Err(rejected) = self.limiter.upload_request(client.addr) {
+            return rejected.into();
+        }
+        match patches::Submission::from_upload(self.service.bundle_dir(), uploads, id, req)
+            .transpose()
+        {
+            Some(sub) => self.accept(sub),
+            None => Resp::NOT_FOUND,
+        }
+    }
+
+    fn delete_upload(&self, id: &str) -> Resp {
+        let uploads = match self.uploads() {
+            Ok(uploads) => uploads,
+            Err(resp) => return resp,
+        };
+        match uploads.remove(id) {
+            Ok(()) => Resp::OK,
+            Err(e) => {
+                error!("failed to remove upload {id}: {e:#}");
+                Resp::INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+
+    /// Remove stale chunked uploads, at most once per [`UPLOAD_GC_INTERVAL`]
+    fn gc_uploads_if_due(&self) {
+        let uploads = match &self.uploads {
+            Some(uploads) => uploads,
+            None => return,
+        };
+        // Someone else is on it
+        let mut last = match self.last_upload_gc.try_lock() {
+            Ok(last) => last,
+            Err(_) => return,
+        };
+        if last.map_or(false, |t| t.elapsed() < UPLOAD_GC_INTERVAL) {
+            return;
+        }
+        *last = Some(Instant::now());
+        match uploads.gc() {
+            Ok(0) => {},
+            Ok(n) => debug!("removed {n} stale uploads"),
+            Err(e) => error!("failed to remove stale uploads: {e:#}"),
+        }
+    }
+
+    fn accept(&self, sub: crate::Result<patches::Submission>) -> Resp {
+        match sub.and_then(|sub| self.service.accept(sub)) {
+            Ok(record) => Resp::Json {
+                code: 200.into(),
+                body: Box::new(record),
+            },
+            Err(e) => match e.downcast::<patches::Quarantined>() {
+                Ok(patches::Quarantined { record, .. }) => Resp::Json {
+                    code: 202.into(),
+                    body: Box::new(record),
+                },
+                Err(e) => match e.downcast_ref::<patches::spam::Rejected>() {
+                    Some(rejected) => Resp::Text {
+                        code: match rejected {
+                            patches::spam::Rejected::Rate { .. } => 429,
+                            _ => 403,
+                        }
+                        .into(),
+                        body: rejected.to_string(),
+                    },
+                    None => Resp::Text {
+                        code: 400.into(),
+                        body: e.to_string(),
+                    },
+                },
+            },
+        }
+    }
+}
+
+fn upload_status(status: crate::Result<Option<upload::Status>>) -> Resp {
+    match status {
+        Ok(Some(status)) => Resp::Json {
+            code: 200.into(),
+            body: Box::new(status),
+        },
+        Ok(None) => Resp::NOT_FOUND,
+        Err(e) => Resp::Text {
+            code: 400.into(),
+            body: e.to_string(),
+        },
     }
 }
 
@@ -322,22 +1045,95 @@ fn request_target(req: &Request) -> Vec<&str> {
     req.url().split('/').filter(|s| !s.is_empty()).collect()
 }
 
+/// Serve the bundle file at `path`, reassembling it if it is stored in chunks
+fn serve_bundle(path: PathBuf) -> Resp {
+    if !bundle::chunks::is_chunked(&path) {
+        return serve_file(path);
+    }
+    let (len, reader) = match bundle::chunks::reader(&path) {
+        Ok(Some(x)) => x,
+        Ok(None) => return Resp::NOT_FOUND,
+        Err(e) => {
+            error!("failed to read chunked bundle {}: {e:#}", path.display());
+            return Resp::INTERNAL_SERVER_ERROR;
+        },
+    };
+    let len = match usize::try_from(len) {
+        Ok(len) => len,
+        Err(_) => return Resp::INTERNAL_SERVER_ERROR,
+    };
+    let etag = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(hash) => etag_header(&format!("\"{hash}\"")),
+        None => return Resp::NOT_FOUND,
+    };
+
+    Resp::Reader {
+        reader: Box::new(reader),
+        len,
+        etag,
+        content_type: OCTET_STREAM.clone(),
+    }
+}
+
 fn serve_file<P: AsRef<Path>>(path: P) -> Resp {
     let path = path.as_ref();
-    if path.exists() {
-        File::open(path)
-            .map(|file| Resp::File { file })
-            .unwrap_or_else(|e| {
-                error!("failed to open file {}: {e}", path.display());
-                Resp::INTERNAL_SERVER_ERROR
-            })
-    } else {
-        Resp::NOT_FOUND
+    let meta = match path.metadata() {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Resp::NOT_FOUND,
+        Err(e) => {
+            error!("failed to stat file {}: {e}", path.display());
+            return Resp::INTERNAL_SERVER_ERROR;
+        },
+    };
+    let len = match usize::try_from(meta.len()) {
+        Ok(len) => len,
+        Err(_) => return Resp::INTERNAL_SERVER_ERROR,
+    };
+
+    // Bundles are content-addressed, so their name makes for a strong
+    // validator. Other files may change in place.
+    let is_bundle = path
+        .extension()
+        .map_or(false, |ext| ext == bundle::FILE_EXTENSION);
+    let etag = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(hash) if is_bundle => format!("\"{hash}\""),
+        _ => {
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_nanos());
+            format!("W/\"{len:x}-{mtime:x}\"")
+        },
+    };
+
+    Resp::File {
+        path: path.to_owned(),
+        len,
+        etag: etag_header(&etag),
+        content_type: OCTET_STREAM.clone(),
     }
 }
 
-fn default_bundle_list(hash: &str) -> Resp {
-    let uri = bundle::Uri::Relative(format!("/bundle/{}.bundle", hash));
+fn etag_of(body: &[u8]) -> Header {
+    etag_header(&format!("\"{}\"", hex::encode(Sha256::digest(body))))
+}
+
+fn etag_header(value: &str) -> Header {
+    Header {
+        field: ETAG.clone(),
+        value: value.parse().unwrap(),
+    }
+}
+
+fn default_bundle_list(public_url: Option<&Url>, hash: &str) -> Resp {
+    let path = format!("bundles/{}.{}", hash, bundle::FILE_EXTENSION);
+    let uri = match public_url.and_then(|base| {
+        Url::parse(&format!("{}/{path}", base.as_str().trim_end_matches('/'))).ok()
+    }) {
+        Some(url) => bundle::Uri::Absolute(url),
+        None => bundle::Uri::Relative(format!("/{path}")),
+    };
     let id = hex::encode(Sha256::digest(uri.as_str()));
 
     let body = bundle::List {
@@ -0,0 +1,307 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Plain HTML views of a drop
+//!
+//! Rendered server-side without any JavaScript, so a drop can be browsed
+//! without cloning it. Links are relative, so the pages work behind a
+//! proxy serving the drop below a path prefix.
+
+use std::{
+    collections::HashMap,
+    fmt::{
+        self,
+        Write as _,
+    },
+};
+
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    patches::{
+        issues::Issue,
+        iter::Note,
+        notes::{
+            self,
+            Predef,
+            TopicState,
+        },
+        record::Diffstat,
+        Topic,
+    },
+    service::Overview,
+};
+
+/// Notes nested deeper than this are not indented any further
+const MAX_DEPTH: usize = 8;
+
+/// The front page: drop description, branches, and topics
+pub fn index(
+    overview: &Overview,
+    topics: &[(Topic, String, TopicState, Option<Diffstat>)],
+) -> String {
+    let mut body = String::new();
+    let title = Escape(&overview.description);
+
+    writeln!(body, "<h1>{title}</h1>").ok();
+
+    writeln!(body, "<h2>Branches</h2>").ok();
+    if overview.branches.is_empty() {
+        writeln!(body, "<p>No branches.</p>").ok();
+    } else {
+        writeln!(body, "<table>").ok();
+        for branch in &overview.branches {
+            let tip = branch
+                .tip
+                .map(|oid| oid.to_string())
+                .unwrap_or_else(|| "(not yet checkpointed)".to_owned());
+            writeln!(
+                body,
+                "<tr><td><code>{}</code></td><td><code>{}</code></td><td>{}</td></tr>",
+                Escape(&branch.name),
+                tip,
+                Escape(&branch.description),
+            )
+            .ok();
+        }
+        writeln!(body, "</table>").ok();
+    }
+
+    writeln!(body, "<h2>Topics</h2>").ok();
+    if topics.is_empty() {
+        writeln!(body, "<p>No topics.</p>").ok();
+    } else {
+        writeln!(body, "<ul>").ok();
+        for (topic, subject, state, diffstat) in topics {
+            write!(
+                body,
+                "<li><a href=\"topics/{topic}\">{}</a>",
+                Escape(subject)
+            )
+            .ok();
+            if *state != TopicState::Open {
+                write!(body, " <small>[{state}]</small>").ok();
+            }
+            if let Some(diffstat) = diffstat {
+                write!(body, " <small>{diffstat}</small>").ok();
+            }
+            writeln!(body, "</li>").ok();
+        }
+        writeln!(body, "</ul>").ok();
+    }
+
+    page(&overview.description, &body)
+}
+
+/// The notes of a topic, oldest first, indented by reply depth
+///
+/// `notes` are expected in the order returned by
+/// [`crate::patches::iter::topic`], ie. newest first. Linked `issues` given by
+/// URL are rendered as links.
+pub fn topic(topic: &Topic, issues: &[Issue], notes: &[Note]) -> String {
+    let subject = notes
+        .last()
+        .and_then(|note| match &note.message {
+            notes::Note::Simple(simple) => simple.subject(),
+            notes::Note::Automerge(_) => None,
+        })
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| topic.to_string());
+
+    let mut body = String::new();
+    writeln!(body, "<p><a href=\"../\">&larr; Back</a></p>").ok();
+    writeln!(body, "<h1>{}</h1>", Escape(&subject)).ok();
+    writeln!(body, "<p><small>Topic <code>{topic}</code></small></p>").ok();
+    if !issues.is_empty() {
+        write!(body, "<p>Issues:").ok();
+        for (i, issue) in issues.iter().enumerate() {
+            let sep = if i > 0 { "," } else { "" };
+            let escaped = Escape(issue.as_str());
+            if issue.is_url() {
+                write!(body, "{sep} <a href=\"{escaped}\">{escaped}</a>").ok();
+            } else {
+                write!(body, "{sep} <code>{escaped}</code>").ok();
+            }
+        }
+        writeln!(body, "</p>").ok();
+    }
+
+    let mut depths = HashMap::new();
+    for note in notes.iter().rev() {
+        let hdr = &note.header;
+        let depth = hdr
+            .in_reply_to
+            .and_then(|parent| depths.get(&parent).map(|d| d + 1))
+            .unwrap_or(0);
+        depths.insert(hdr.id, depth);
+
+        writeln!(
+            body,
+            "<article id=\"{}\" style=\"margin-left: {}em\">",
+            hdr.id,
+            2 * depth.min(MAX_DEPTH)
+        )
+        .ok();
+        write!(
+            body,
+            "<p><strong>{}</strong> &lt;{}&gt; <small>{}",
+            Escape(&hdr.author.name),
+            Escape(&hdr.author.email),
+            hdr.time.format(&Rfc3339).unwrap_or_default(),
+        )
+        .ok();
+        if let Some(parent) = hdr.in_reply_to {
+            write!(
+                body,
+                " &middot; in reply to <a href=\"#{parent}\">{}</a>",
+                &parent.to_string()[..8]
+            )
+            .ok();
+        }
+        writeln!(
+            body,
+            " &middot; patch <code>{}</code></small></p>",
+            hdr.patch.id
+        )
+        .ok();
+        message(&mut body, &note.message);
+        writeln!(body, "</article>").ok();
+    }
+
+    page(&subject, &body)
+}
+
+fn message(out: &mut String, note: &notes::Note) {
+    let simple = match note {
+        notes::Note::Simple(simple) => simple,
+        notes::Note::Automerge(_) => {
+            writeln!(out, "<p><em>Automerge document, not rendered</em></p>").ok();
+            return;
+        },
+    };
+    match simple {
+        notes::Simple::Known(Predef::Basic { message }) => {
+            writeln!(out, "<pre>{}</pre>", Escape(message)).ok();
+        },
+        notes::Simple::Known(Predef::CodeComment { loc, message }) => {
+            write!(out, "<p><small>On blob <code>{}</code>", loc.file).ok();
+            if let Some(lines) = &loc.line {
+                write!(out, ", lines {}&ndash;{}", lines.start, lines.end).ok();
+            }
+            writeln!(out, "</small></p>").ok();
+            writeln!(out, "<pre>{}</pre>", Escape(message)).ok();
+        },
+        notes::Simple::Known(Predef::Checkpoint {
+            kind,
+            refs,
+            message,
+            squashed,
+        }) => {
+            let kind = match kind {
+                notes::CheckpointKind::Merge => "Merge",
+                notes::CheckpointKind::Snapshot => "Snapshot",
+            };
+            writeln!(out, "<p>{kind} checkpoint</p>").ok();
+            writeln!(out, "<ul>").ok();
+            for (name, oid) in refs {
+                writeln!(
+                    out,
+                    "<li><code>{}</code> &rarr; <code>{oid}</code></li>",
+                    Escape(name)
+                )
+                .ok();
+            }
+            writeln!(out, "</ul>").ok();
+            if !squashed.is_empty() {
+                writeln!(
+                    out,
+                    "<p><small>{} patch(es) squash-merged</small></p>",
+                    squashed.len()
+                )
+                .ok();
+            }
+            if let Some(message) = message {
+                writeln!(out, "<pre>{}</pre>", Escape(message)).ok();
+            }
+        },
+        notes::Simple::Known(Predef::State { state, message }) => {
+            writeln!(out, "<p>Marked the topic as <strong>{state}</strong></p>").ok();
+            if let Some(message) = message {
+                writeln!(out, "<pre>{}</pre>", Escape(message)).ok();
+            }
+        },
+        notes::Simple::Known(Predef::Review {
+            patch,
+            verdict,
+            message,
+            comments,
+        }) => {
+            write!(out, "<p>Review of patch <code>{patch}</code>").ok();
+            if let Some(verdict) = verdict {
+                write!(out, ": <strong>{verdict}</strong>").ok();
+            }
+            writeln!(out, "</p>").ok();
+            if let Some(message) = message {
+                writeln!(out, "<pre>{}</pre>", Escape(message)).ok();
+            }
+            for comment in comments {
+                write!(out, "<p><small><code>{}</code>", Escape(&comment.path)).ok();
+                if let Some(lines) = &comment.lines {
+                    write!(
+                        out,
+                        ", lines {}&ndash;{}",
+                        lines.start,
+                        lines.end.saturating_sub(1)
+                    )
+                    .ok();
+                }
+                writeln!(out, "</small></p>").ok();
+                writeln!(out, "<pre>{}</pre>", Escape(&comment.message)).ok();
+            }
+        },
+        notes::Simple::Unknown(map) => {
+            let json = serde_json::to_string_pretty(map).unwrap_or_default();
+            writeln!(out, "<pre>{}</pre>", Escape(&json)).ok();
+        },
+    }
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n\
+         <style>body {{ max-width: 60em; margin: auto; padding: 1em; font-family: sans-serif; }} \
+         pre {{ white-space: pre-wrap; }} td {{ padding-right: 1em; }}</style>\n\
+         </head>\n\
+         <body>\n{body}</body>\n\
+         </html>\n",
+        Escape(title)
+    )
+}
+
+/// Escapes a string for use in HTML text and attribute values
+struct Escape<'a>(&'a str);
+
+impl fmt::Display for Escape<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut last = 0;
+        for (i, c) in self.0.char_indices() {
+            let esc = match c {
+                '&' => "&amp;",
+                '<' => "&lt;",
+                '>' => "&gt;",
+                '"' => "&quot;",
+                '\'' => "&#39;",
+                _ => continue,
+            };
+            f.write_str(&self.0[last..i])?;
+            f.write_str(esc)?;
+            last = i + 1;
+        }
+        f.write_str(&self.0[last..])
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Rate limiting of patch submissions
+
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    io,
+    net::IpAddr,
+    num::{
+        NonZeroU32,
+        NonZeroU64,
+        NonZeroUsize,
+    },
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::patches::upload;
+
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Limits imposed on patch submissions
+///
+/// `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Maximum number of submissions per client IP address per hour
+    ///
+    /// Connections via a unix domain socket are exempt.
+    pub submissions_per_hour: Option<NonZeroU32>,
+    /// Maximum number of patch bundles being uploaded at the same time
+    pub concurrent_uploads: Option<NonZeroUsize>,
+    /// Maximum number of bytes per second read from all uploads combined
+    pub bandwidth: Option<NonZeroU64>,
+}
+
+/// Why a submission was rejected
+pub(crate) enum Rejected {
+    /// The client exceeded its quota, and may retry after the given duration
+    Quota(Duration),
+    /// Too many uploads in progress
+    Busy,
+}
+
+pub(crate) struct Limiter {
+    limits: Limits,
+    /// Maximum number of requests to chunked uploads per submission
+    chunks_per_submission: usize,
+    submissions: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    chunks: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    uploads: AtomicUsize,
+    bandwidth: Option<Arc<Bandwidth>>,
+}
+
+struct Bandwidth {
+    /// Bytes per second
+    rate: u64,
+    /// Point in time at which the budget is available again
+    next_read: Mutex<Instant>,
+}
+
+impl Limiter {
+    /// Create a [`Limiter`] enforcing `limits`
+    ///
+    /// `max_upload_len` is the maximum size of chunked uploads, if enabled.
+    /// Each client may send as many chunks per hour as it takes to upload
+    /// bundles of that size twice for each submission allowed per hour.
+    pub fn new(limits: Limits, max_upload_len: Option<NonZeroU64>) -> Self {
+        let chunks_per_submission = max_upload_len.map_or(0, |max| {
+            usize::try_from(upload::chunks(max.get(), upload::CHUNK_SIZE))
+                .unwrap_or(usize::MAX)
+                .saturating_mul(2)
+        });
+        Self {
+            limits,
+            // Account for the request finalising the upload
+            chunks_per_submission: chunks_per_submission.saturating_add(1),
+            submissions: Mutex::new(HashMap::new()),
+            chunks: Mutex::new(HashMap::new()),
+            uploads: AtomicUsize::new(0),
+            bandwidth: limits.bandwidth.map(|rate| {
+                Arc::new(Bandwidth {
+                    rate: rate.get(),
+                    next_read: Mutex::new(Instant::now()),
+                })
+            }),
+        }
+    }
+
+    /// Count a submission from `addr` against its hourly quota
+    ///
+    /// The submission is counted even if it later fails, so as to not give
+    /// an advantage to clients sending garbage.
+    pub fn submission(&self, addr: Option<IpAddr>) -> Result<(), Rejected> {
+        let max = self
+            .limits
+            .submissions_per_hour
+            .map(|max| max.get() as usize);
+        count(&self.submissions, max, addr)
+    }
+
+    /// Count a request to a chunked upload (sending a chunk, or finalising
+    /// the upload) from `addr` against its hourly quota
+    pub fn upload_request(&self, addr: Option<IpAddr>) -> Result<(), Rejected> {
+        let max = self
+            .limits
+            .submissions_per_hour
+            .map(|max| (max.get() as usize).saturating_mul(self.chunks_per_submission));
+        count(&self.chunks, max, addr)
+    }
+
+    /// Reserve one of the concurrent upload slots
+    ///
+    /// The slot is released when the returned [`Upload`] is dropped.
+    pub fn upload(&self) -> Result<Upload<'_>, Rejected> {
+        let prev = self.uploads.fetch_add(1, Ordering::AcqRel);
+        // Releases the slot again if we bail out
+        let upload = Upload { limiter: self };
+        match self.limits.concurrent_uploads {
+            Some(max) if prev >= max.get() => Err(Rejected::Busy),
+            _ => Ok(upload),
+        }
+    }
+}
+
+/// Count an event from `addr` in `events`, unless `max` events have occurred
+/// during the last [`WINDOW`]
+fn count(
+    events: &Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    max: Option<usize>,
+    addr: Option<IpAddr>,
+) -> Result<(), Rejected> {
+    let (max, addr) = match (max, addr) {
+        (Some(max), Some(addr)) => (max, addr),
+        _ => return Ok(()),
+    };
+    let now = Instant::now();
+    let mut events = events.lock().unwrap();
+    // Forget about clients which have been quiet for a while, so the map
+    // doesn't grow indefinitely
+    events.retain(|_, times| {
+        while times.front().map_or(false, |t| now - *t >= WINDOW) {
+            times.pop_front();
+        }
+        !times.is_empty()
+    });
+    let times = events.entry(addr).or_default();
+    if times.len() >= max {
+        let oldest = times.front().copied().unwrap_or(now);
+        return Err(Rejected::Quota(WINDOW - (now - oldest)));
+    }
+    times.push_back(now);
+
+    Ok(())
+}
+
+pub(crate) struct Upload<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Upload<'_> {
+    /// Wrap `reader` such that reading from it adheres to the bandwidth limit
+    pub fn throttle<R: io::Read>(&self, reader: R) -> Throttled<R> {
+        Throttled {
+            bandwidth: self.limiter.bandwidth.clone(),
+            inner: reader,
+        }
+    }
+}
+
+impl Drop for Upload<'_> {
+    fn drop(&mut self) {
+        self.limiter.uploads.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub(crate) struct Throttled<R> {
+    bandwidth: Option<Arc<Bandwidth>>,
+    inner: R,
+}
+
+impl<R: io::Read> io::Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bandwidth = match &self.bandwidth {
+            Some(bandwidth) => bandwidth,
+            None => return self.inner.read(buf),
+        };
+        let rate = bandwidth.rate;
+        // Don't read more than a second's worth at a time, so concurrent
+        // uploads get a fair share
+        let max = usize::try_from(rate).unwrap_or(usize::MAX).min(buf.len());
+        let n = self.inner.read(&mut buf[..max])?;
+
+        let now = Instant::now();
+        let wait = {
+            let mut next = bandwidth.next_read.lock().unwrap();
+            let start = (*next).max(now);
+            *next = start + Duration::from_secs_f64(n as f64 / rate as f64);
+            *next - now
+        };
+        // Allow for a bit of burst
+        if wait > Duration::from_secs(1) {
+            thread::sleep(wait - Duration::from_secs(1));
+        }
+
+        Ok(n)
+    }
+}
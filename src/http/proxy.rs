@@ -0,0 +1,199 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Handling of requests relayed by a reverse proxy
+//!
+//! When running behind a reverse proxy, the peer address of every connection
+//! is the proxy's. The proxy is expected to convey the address of the actual
+//! client in the `X-Forwarded-For` header, and the scheme and host the client
+//! used in `X-Forwarded-Proto` and `X-Forwarded-Host` respectively. Those
+//! headers are only honoured if the peer is a [`Trusted`] proxy, as they can
+//! otherwise be set by anyone to evade rate limits.
+
+use std::{
+    fmt,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        SocketAddr,
+    },
+    str::FromStr,
+};
+
+use anyhow::ensure;
+use tiny_http::Request;
+use url::Url;
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+const HOST: &str = "Host";
+
+/// A peer whose `X-Forwarded-*` headers are trusted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trusted {
+    /// Any connection accepted on a unix domain socket
+    Unix,
+    /// Addresses within the given network, in CIDR notation
+    Net { addr: IpAddr, prefix: u8 },
+}
+
+impl Trusted {
+    fn contains(&self, peer: Option<IpAddr>) -> bool {
+        match (self, peer) {
+            (Self::Unix, None) => true,
+            (Self::Net { addr, prefix }, Some(peer)) => match (addr, canonical(peer)) {
+                (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                    u32::from(*net) & mask == u32::from(ip) & mask
+                },
+                (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                    u128::from(*net) & mask == u128::from(ip) & mask
+                },
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Trusted {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(Self::Unix);
+        }
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        ensure!(prefix <= max, "invalid prefix length {prefix} for {addr}");
+
+        Ok(Self::Net {
+            addr: canonical(addr),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for Trusted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix => f.write_str("unix"),
+            Self::Net { addr, prefix } => write!(f, "{addr}/{prefix}"),
+        }
+    }
+}
+
+/// The client of a request, as far as it can be determined
+pub(super) struct Client {
+    /// Address of the client, `None` for connections on a unix domain socket
+    /// not relayed by a trusted proxy
+    pub addr: Option<IpAddr>,
+    /// Base URL the client addressed, if relayed by a trusted proxy which
+    /// conveyed it
+    pub base_url: Option<Url>,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{addr}"),
+            None => f.write_str("<unix socket>"),
+        }
+    }
+}
+
+/// Determine the [`Client`] of `req`, honouring `X-Forwarded-*` headers if the
+/// peer is in `trusted`
+pub(super) fn client(trusted: &[Trusted], req: &Request) -> Client {
+    let peer = req.remote_addr().map(|addr| addr.ip());
+    let is_trusted = |addr: Option<IpAddr>| trusted.iter().any(|t| t.contains(addr));
+    if !is_trusted(peer) {
+        return Client {
+            addr: peer,
+            base_url: None,
+        };
+    }
+
+    // Each proxy appends the address of its peer, so the rightmost address not
+    // belonging to a trusted proxy is the client's. Anything left of it may
+    // have been made up by the client.
+    let forwarded = header(req, X_FORWARDED_FOR)
+        .map(|value| {
+            value
+                .split(',')
+                .filter_map(|addr| parse_addr(addr.trim()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let addr = forwarded
+        .iter()
+        .rev()
+        .find(|addr| !is_trusted(Some(**addr)))
+        .or_else(|| forwarded.first())
+        .copied()
+        .or(peer);
+
+    let base_url = header(req, X_FORWARDED_PROTO).and_then(|proto| {
+        let proto = proto.split(',').next()?.trim();
+        let host = header(req, X_FORWARDED_HOST)
+            .and_then(|host| host.split(',').next())
+            .or_else(|| header(req, HOST))?
+            .trim();
+        base_url(proto, host).ok()
+    });
+
+    Client { addr, base_url }
+}
+
+fn header<'a>(req: &'a Request, name: &'static str) -> Option<&'a str> {
+    req.headers()
+        .iter()
+        .find(|hdr| hdr.field.equiv(name))
+        .map(|hdr| hdr.value.as_str())
+}
+
+/// Parse an address from `X-Forwarded-For`, which some proxies include the
+/// port in
+fn parse_addr(s: &str) -> Option<IpAddr> {
+    s.parse::<IpAddr>()
+        .or_else(|_| s.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(canonical)
+}
+
+fn base_url(proto: &str, host: &str) -> crate::Result<Url> {
+    ensure!(
+        proto == "http" || proto == "https",
+        "unsupported scheme {proto}"
+    );
+    let url = Url::parse(&format!("{proto}://{host}/"))?;
+    ensure!(
+        url.host().is_some()
+            && url.path() == "/"
+            && url.query().is_none()
+            && url.username().is_empty(),
+        "invalid host {host}"
+    );
+
+    Ok(url)
+}
+
+/// Treat IPv4-mapped IPv6 addresses as IPv4, as dual-stack sockets report them
+/// that way
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            },
+            _ => addr,
+        },
+        v4 => v4,
+    }
+}
@@ -3,36 +3,6 @@
 
 use digest::Digest;
 
-/// Created by [`Lines::until_blank`], stops iteration at the first blank line.
-pub struct UntilBlank<B> {
-    inner: Lines<B>,
-}
-
-impl<B: std::io::BufRead> Iterator for UntilBlank<B> {
-    type Item = std::io::Result<String>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().and_then(|res| match res {
-            Ok(line) => {
-                if line.is_empty() {
-                    None
-                } else {
-                    Some(Ok(line))
-                }
-            },
-            Err(e) => Some(Err(e)),
-        })
-    }
-}
-
-impl<B: std::io::Seek> std::io::Seek for UntilBlank<B> {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        self.inner.seek(pos)
-    }
-}
-
-/// Like [`std::io::Lines`], but allows to retain ownership of the underlying
-/// [`std::io::BufRead`].
 pub struct Lines<B> {
     buf: B,
 }
@@ -41,10 +11,6 @@ impl<B: std::io::BufRead> Lines<B> {
     pub fn new(buf: B) -> Self {
         Self { buf }
     }
-
-    pub fn until_blank(self) -> UntilBlank<B> {
-        UntilBlank { inner: self }
-    }
 }
 
 impl<B: std::io::BufRead> Iterator for Lines<B> {
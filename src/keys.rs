@@ -12,11 +12,17 @@ use std::{
     str::FromStr,
 };
 
-use anyhow::anyhow;
+use anyhow::{
+    anyhow,
+    bail,
+    ensure,
+};
+use log::info;
 use signature::SignerMut;
 
 use crate::{
     cfg,
+    gpg,
     metadata,
     ssh::{
         self,
@@ -26,6 +32,13 @@ use crate::{
 
 pub type Signature = ssh::Signature;
 
+/// Flag set in signatures made by security keys if the user confirmed their
+/// presence, usually by touching the key
+const SK_USER_PRESENT: u8 = 0x01;
+/// Length of the flags and counter appended to signatures made by security
+/// keys
+const SK_SIGNATURE_TRAILER_LEN: usize = 5;
+
 pub trait Signer {
     fn ident(&self) -> VerificationKey;
     fn sign(&mut self, msg: &[u8]) -> Result<ssh::Signature, signature::Error>;
@@ -60,9 +73,18 @@ pub struct Agent<T> {
 }
 
 impl Agent<agent::UnixStream> {
+    /// Connect to the agent holding the signing key configured in `cfg`
+    ///
+    /// This is `gpg-agent` if the key is a GnuPG key, and the agent listening
+    /// on `SSH_AUTH_SOCK` otherwise.
     pub fn from_gitconfig(cfg: &git2::Config) -> crate::Result<Self> {
-        let client = agent::Client::from_env()?;
-        let ident = VerificationKey::from_gitconfig(cfg)?.0.into_owned();
+        let key = cfg::git::signing_key(cfg)?
+            .ok_or_else(|| anyhow!("unable to determine signing key from git config"))?;
+        let client = match key {
+            cfg::git::Key::Gpg(_) => gpg::agent()?,
+            _ => agent::Client::from_env()?,
+        };
+        let ident = key.public().to_owned();
 
         Ok(Self { client, ident })
     }
@@ -87,6 +109,53 @@ impl<T> Agent<T> {
     }
 }
 
+impl<T> Agent<T>
+where
+    T: io::Read + io::Write,
+{
+    fn sign_msg(&mut self, msg: &[u8]) -> Result<ssh::Signature, signature::Error> {
+        let key = self.verification_key();
+        if !key.is_security_key() {
+            return self
+                .client
+                .sign(&self.ident, msg)
+                .map_err(signature::Error::from_source);
+        }
+
+        info!(
+            "Confirm user presence for key {}, eg. by touching the security key",
+            self.ident.fingerprint(ssh::HashAlg::Sha256)
+        );
+        self.client.sign(&self.ident, msg).map_err(|e| {
+            signature::Error::from_source(anyhow!(
+                "security key did not sign ({e}), is it plugged in, and was user presence confirmed in time?"
+            ))
+        })
+    }
+}
+
+impl<T> Agent<T>
+where
+    T: io::Read + io::Write,
+{
+    /// Check that the agent is reachable, and holds the signing key
+    pub fn ensure_key(&mut self) -> crate::Result<()> {
+        let keys = self.client.list_keys()?;
+        let found = keys
+            .iter()
+            .any(|key| key.key_data() == self.ident.key_data());
+        if !found && self.verification_key().is_security_key() {
+            bail!(
+                "security key {} not found in agent, add it using 'ssh-add'",
+                self.ident.fingerprint(ssh::HashAlg::Sha256)
+            );
+        }
+        ensure!(found, "signing key not found in agent");
+
+        Ok(())
+    }
+}
+
 impl<T> Signer for Agent<T>
 where
     T: io::Read + io::Write,
@@ -96,9 +165,7 @@ where
     }
 
     fn sign(&mut self, msg: &[u8]) -> Result<ssh::Signature, signature::Error> {
-        self.client
-            .sign(&self.ident, msg)
-            .map_err(signature::Error::from_source)
+        self.sign_msg(msg)
     }
 }
 
@@ -111,9 +178,7 @@ where
     }
 
     fn sign(&mut self, msg: &[u8]) -> Result<ssh::Signature, signature::Error> {
-        self.client
-            .sign(&self.ident, msg)
-            .map_err(signature::Error::from_source)
+        self.sign_msg(msg)
     }
 }
 
@@ -141,6 +206,52 @@ impl<'a> VerificationKey<'a> {
         self.0.algorithm()
     }
 
+    /// Whether signatures made with this key can be verified
+    ///
+    /// Supported are Ed25519 keys, ECDSA keys over the NIST P-256 and P-384
+    /// curves, RSA keys, and Ed25519 and ECDSA P-256 keys backed by a FIDO2
+    /// security key. Note that signatures made by RSA keys are only accepted
+    /// if they use SHA-512 (rsa-sha2-512), not rsa-sha2-256 or SHA-1 (ssh-rsa).
+    pub fn is_supported(&self) -> bool {
+        use ssh::{
+            Algorithm::*,
+            EcdsaCurve::*,
+        };
+
+        matches!(
+            self.algorithm(),
+            Ed25519
+                | Ecdsa {
+                    curve: NistP256 | NistP384
+                }
+                | Rsa { .. }
+                | SkEd25519
+                | SkEcdsaSha2NistP256
+        )
+    }
+
+    pub fn ensure_supported(&self) -> crate::Result<()> {
+        ensure!(
+            self.is_supported(),
+            "unsupported key algorithm {}, expected one of ssh-ed25519, ecdsa-sha2-nistp256, ecdsa-sha2-nistp384, ssh-rsa, sk-ssh-ed25519@openssh.com or sk-ecdsa-sha2-nistp256@openssh.com",
+            self.algorithm()
+        );
+        Ok(())
+    }
+
+    /// Whether this is the key of a FIDO2 security key (hardware token)
+    ///
+    /// The private part of such keys never leaves the token, so signing
+    /// requires the key to be added to `ssh-agent`. Making a signature
+    /// requires the user to confirm their presence, usually by touching the
+    /// token.
+    pub fn is_security_key(&self) -> bool {
+        matches!(
+            self.algorithm(),
+            ssh::Algorithm::SkEd25519 | ssh::Algorithm::SkEcdsaSha2NistP256
+        )
+    }
+
     pub fn strip_comment(&mut self) {
         self.0.to_mut().set_comment("")
     }
@@ -201,6 +312,121 @@ impl FromStr for VerificationKey<'_> {
 
 impl signature::Verifier<ssh::Signature> for VerificationKey<'_> {
     fn verify(&self, msg: &[u8], signature: &ssh::Signature) -> Result<(), signature::Error> {
+        // Security keys can be configured to sign without confirming user
+        // presence, which we don't accept
+        if self.is_security_key() {
+            let sig = signature.as_bytes();
+            let flags = sig
+                .len()
+                .checked_sub(SK_SIGNATURE_TRAILER_LEN)
+                .map(|i| sig[i])
+                .ok_or_else(signature::Error::new)?;
+            if flags & SK_USER_PRESENT == 0 {
+                return Err(signature::Error::new());
+            }
+        }
         signature::Verifier::verify(&*self.0, msg, signature)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use signature::Verifier as _;
+
+    use super::*;
+
+    const MSG: &[u8] = b"it signature test vector";
+
+    const ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICdFvUhbrNzW3ddZIiAtyUsvfbppqhYt5wkVB2fCZMTR";
+    const P256: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBNW85TqQMobfnA2th1q2UMFuOTgxZU3penrywscGkLl9b0aYAkVKTGkjhOjEc3mmOxeHx4fS77iiBOxAAvGmlDg=";
+    const P384: &str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBG7KdQY2nCyK6eM4ipE5kbbJz5HaF2xtRT8W0sc3X6TBBvRo2mUQUlL26EEcWxyZuQa96EQoSnwz6Xpeog327bZ+LYAN2aXyJElB9GPrNUNkeOD2kvCS+OgRgqqN1UqxKg==";
+    const P521: &str = "ecdsa-sha2-nistp521 AAAAE2VjZHNhLXNoYTItbmlzdHA1MjEAAAAIbmlzdHA1MjEAAACFBACV3C9W1RJ+7l/hUonpbfzZRl6h3n+CJ14Op3JlnPW4R8ATj2CLiUwqVdV8Iy4f6iIqLlfBeopJWISMzKg2fRX86gFQiavLTblpip2LTzs/aoRgT9D6AN2ths+MNrP4mRSsBjI+zVtqSubB+aDHIgci4UanQtIWtgQ3WYNlaS26hD+3Og==";
+    const RSA: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC9OIqe5aJcH0WwPjUvSujB9auT+3tiBAxJi1lCwfu6Gru+J1L9kzYgpddC7P1PGxAyMy2UXG/rxgAEp19t82zbNbl7deQurtF94lEXlL6mVIB1Qih4us/iq73mx6qcUbwAOoHAx022hM4b5CTUBsyXfT8J2ie+xNAPPAn9/iGpXPBTZ5YHaZnSeVu8eXSh1Vrx4V4QBY93j0D2GtMk7klqbYK3FA/achT05dLj4U00lDFiN/Hvj3tCdYnYxbGVdqzVJUYtnYOA+YkpeCXR2SVIh0Ix+wzpujpJYeZvrsGDGzGt1C+1c9PEiz28oqIImDl2QtylyNGMPD98DEb1vmFn";
+
+    const P256_SIG: &str = "0000002100a43973c142465af6cc30bdbc887da94912b77e87ba4203abf515e2c8d48cc9de0000002055007802566e8e1af6d8e33ed27fda4726862f8c58b8b7e9e04950a4b3563971";
+    const P384_SIG: &str = "0000003100d1f73fa6e69288887d0524d9e77c17c54c6c86d0dbdf598fedb8439d57daaa65cf6ffb7c5618018a3d7636964d5122730000003100f54b936bd1760ef849d865bb35732437091a6c946e0f07b37bcddf7c806a2c92e07d44bd6295858cf778a286f097df3e";
+    /// PKCS#1 v1.5 signatures of `MSG` by `RSA`, using SHA-512, SHA-256
+    /// (rsa-sha2-256) and SHA-1 (ssh-rsa) respectively
+    const RSA_SHA512_SIG: &str = "10a86000133f0d53f8db0a89302fa7b8ed06fde3efc767c0a1d1c22815eb60dace658d46c6695004ac25bdd572f242135253d1316f0a4a7829fa4f177e562cb7c3bbb1696b894ad4a34823beb5404465b22fdfe0f5b1bfe064b9a35bec2c57860d4ad3ed8dea57667af62060719d17de46bbff8a774525247950ee0f6fca79da6a14ac92215eabfe7276d4d62b1623267ebdfba65036195121df4a029f4dbb20da1dc4f728494054bbd230d738a5991d9c42c96d9b0b4d64498971aaeaad500d3bd2b3f0224e9ddcd042ebb7e6ebe034a823b9b6f2830fcc7767794a13cc0ccf5313b93a85509f4e4c0b2fd5d48a41fb6506ccaf951a8e2d2302c3b1f9fba943";
+    const RSA_SHA256_SIG: &str = "5565b90ae1e07410be8a06f78bb425a350f4fee6ab033b1ac145f94c5feae4b7e8b7a9c5d65a80529d6604cc0913f3a260c1e989f9d9be299f57fd564a575392b750debd69ac3d2cb43451b18150b874aa891d2a5268684ad11b4761efb0990162aabd4caed323bb3082d95bd24c977be63d829f04232c48acbda9b39562537e6291ca4ba4b7fffaf5b50ae07545d89e867bac6dd17247f22b1826170908445fc4b76c36e4d9defeb9b99f2da6298617a0e14822355e618b3e7104aaeb3f45c0b05d7753bdaa1dd746ca3310e5029c1fb92bf2c34aadca6a71c055a19c6d1fabf7c605663112e630089fe127083a13edb93f7c32d93386ab22f25101be9c5fff";
+    const RSA_SHA1_SIG: &str = "85d47a09ce607bc36a51db4730379afb43f630b065f2fac78984f951c9b57642531538ff6ee5649673d6c27bbbaa837ab10cb358108429ad53588c437e7dd64df82a3a096d5433715085c3c103f8f0cbb0037188901caaece4f2d91031581a98937ff29ff060a0b1436ca00daf8074a17a3c7b77b4da1055df517420770824507243fd129955c61e014de03d9478c598eb70bbc97d420691aa9e5b3fc349dbf5d99d6c73de7ba14214d43fe7a217c21c28c9527b9ab1c1dfe7c07b19225872029efb95d562ee45879dcc90c8492d5b3d70a86aa91f454061b4766acbeeb24ddf0915c95b41a3502981280ea796bcc98b0f59e3d15cee3bb7f595b8a7b90b5548";
+
+    fn key(openssh: &str) -> metadata::Key<'static> {
+        openssh.parse().unwrap()
+    }
+
+    fn sig(hex: &str) -> metadata::Signature {
+        serde_json::from_value(serde_json::Value::from(hex)).unwrap()
+    }
+
+    fn verifies(key: &metadata::Key, msg: &[u8], sig: &metadata::Signature) -> bool {
+        key.verify(msg, sig).is_ok()
+    }
+
+    #[test]
+    fn supported_algorithms() {
+        for k in [ED25519, P256, P384, RSA] {
+            let vk = VerificationKey::from_openssh(k).unwrap();
+            assert!(vk.is_supported(), "{}", vk.algorithm());
+            vk.ensure_supported().unwrap();
+        }
+
+        let p521 = VerificationKey::from_openssh(P521).unwrap();
+        assert!(!p521.is_supported());
+        assert!(p521.ensure_supported().is_err());
+        assert!(P521.parse::<metadata::Key>().is_err());
+    }
+
+    #[test]
+    fn p256() {
+        let key = key(P256);
+        assert!(verifies(&key, MSG, &sig(P256_SIG)));
+        assert!(!verifies(
+            &key,
+            b"it signature test vectors",
+            &sig(P256_SIG)
+        ));
+        assert!(!verifies(&key, MSG, &sig(&corrupt(P256_SIG))));
+        assert!(!verifies(&key, MSG, &sig(P384_SIG)));
+    }
+
+    #[test]
+    fn p384() {
+        let key = key(P384);
+        assert!(verifies(&key, MSG, &sig(P384_SIG)));
+        assert!(!verifies(
+            &key,
+            b"it signature test vectors",
+            &sig(P384_SIG)
+        ));
+        assert!(!verifies(&key, MSG, &sig(&corrupt(P384_SIG))));
+        assert!(!verifies(&key, MSG, &sig(P256_SIG)));
+    }
+
+    #[test]
+    fn rsa_sha512() {
+        let key = key(RSA);
+        assert!(verifies(&key, MSG, &sig(RSA_SHA512_SIG)));
+        assert!(!verifies(
+            &key,
+            b"it signature test vectors",
+            &sig(RSA_SHA512_SIG)
+        ));
+        assert!(!verifies(&key, MSG, &sig(&corrupt(RSA_SHA512_SIG))));
+    }
+
+    #[test]
+    fn rsa_rejects_weaker_hashes() {
+        let key = key(RSA);
+        assert!(!verifies(&key, MSG, &sig(RSA_SHA256_SIG)));
+        assert!(!verifies(&key, MSG, &sig(RSA_SHA1_SIG)));
+    }
+
+    /// Flip a bit in the last byte of the hex-encoded signature `sig`
+    fn corrupt(sig: &str) -> String {
+        let mut bytes = hex::decode(sig).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        hex::encode(bytes)
+    }
+}
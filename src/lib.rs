@@ -1,22 +1,42 @@
 // Copyright © 2022 Kim Altintop <kim@eagain.io>
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
-mod bundle;
+//! Core library of `it`
+//!
+//! The command line interface and the HTTP server are only available with the
+//! (default) `cli` feature. Without it, only the core modules for dealing with
+//! bundles, metadata, patches and the underlying git repositories are built.
+
+// Much of the configuration and discovery plumbing is only used by the CLI
+#![cfg_attr(not(feature = "cli"), allow(dead_code, unused_imports))]
+
+pub mod bundle;
 mod cfg;
+mod discovery;
 mod fs;
-mod git;
+pub mod git;
+mod gpg;
+#[cfg(feature = "cli")]
 mod http;
 mod io;
 mod iter;
 mod json;
 mod keys;
-mod metadata;
-mod patches;
+pub mod metadata;
+mod net;
+pub mod patches;
+#[cfg(feature = "rpc")]
+mod rpc;
 mod serde;
+#[cfg(feature = "cli")]
+mod service;
 mod ssh;
 mod str;
+mod trace;
 
+#[cfg(feature = "cli")]
 pub mod cmd;
+#[cfg(feature = "cli")]
 pub use cmd::{
     ui::Output,
     Cmd,
@@ -35,6 +35,8 @@ use crate::{
     ssh,
 };
 
+pub mod ancestry;
+
 pub mod drop;
 pub use drop::Drop;
 
@@ -44,6 +46,8 @@ pub mod git;
 mod mirrors;
 pub use mirrors::{
     Alternates,
+    Kind as MirrorKind,
+    Mirror,
     Mirrors,
 };
 
@@ -53,6 +57,12 @@ pub use identity::{
     IdentityId,
 };
 
+pub mod policy;
+pub use policy::Policy;
+
+pub mod profile;
+pub use profile::Profile;
+
 #[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
 pub struct FmtVersion(SemVer);
 
@@ -182,13 +192,21 @@ impl fmt::Display for KeyId {
     }
 }
 
+impl FromStr for KeyId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::FromHex::from_hex(s).map(Self)
+    }
+}
+
 impl fmt::Debug for KeyId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("KeyId").field(&hex::encode(self.0)).finish()
     }
 }
 
-#[derive(Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Eq, Hash, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ContentHash {
     #[serde(with = "hex::serde")]
     pub sha1: [u8; 20],
@@ -261,6 +279,12 @@ impl DateTime {
     }
 }
 
+impl From<OffsetDateTime> for DateTime {
+    fn from(dt: OffsetDateTime) -> Self {
+        Self(dt.to_offset(UtcOffset::UTC))
+    }
+}
+
 impl FromStr for DateTime {
     type Err = time::error::Parse;
 
@@ -290,6 +314,10 @@ pub enum Metadata<'a> {
     Mirrors(Cow<'a, Mirrors>),
     #[serde(rename = "eagain.io/it/alternates")]
     Alternates(Cow<'a, Alternates>),
+    #[serde(rename = "eagain.io/it/profile")]
+    Profile(Cow<'a, Profile>),
+    #[serde(rename = "eagain.io/it/policy")]
+    Policy(Cow<'a, Policy>),
 }
 
 impl<'a> Metadata<'a> {
@@ -321,6 +349,20 @@ impl<'a> Metadata<'a> {
         Self::Alternates(a.into())
     }
 
+    pub fn profile<T>(p: T) -> Self
+    where
+        T: Into<Cow<'a, Profile>>,
+    {
+        Self::Profile(p.into())
+    }
+
+    pub fn policy<T>(p: T) -> Self
+    where
+        T: Into<Cow<'a, Policy>>,
+    {
+        Self::Policy(p.into())
+    }
+
     pub fn sign<'b, I, S>(self, keys: I) -> crate::Result<Signed<Self>>
     where
         I: IntoIterator<Item = &'b mut S>,
@@ -391,6 +433,30 @@ impl<'a> From<&'a Alternates> for Metadata<'a> {
     }
 }
 
+impl From<Profile> for Metadata<'static> {
+    fn from(p: Profile) -> Self {
+        Self::profile(p)
+    }
+}
+
+impl<'a> From<&'a Profile> for Metadata<'a> {
+    fn from(p: &'a Profile) -> Self {
+        Self::profile(p)
+    }
+}
+
+impl From<Policy> for Metadata<'static> {
+    fn from(p: Policy) -> Self {
+        Self::policy(p)
+    }
+}
+
+impl<'a> From<&'a Policy> for Metadata<'a> {
+    fn from(p: &'a Policy) -> Self {
+        Self::policy(p)
+    }
+}
+
 impl<'a> TryFrom<Metadata<'a>> for Cow<'a, Identity> {
     type Error = Metadata<'a>;
 
@@ -435,6 +501,28 @@ impl<'a> TryFrom<Metadata<'a>> for Cow<'a, Alternates> {
     }
 }
 
+impl<'a> TryFrom<Metadata<'a>> for Cow<'a, Profile> {
+    type Error = Metadata<'a>;
+
+    fn try_from(value: Metadata<'a>) -> Result<Self, Self::Error> {
+        match value {
+            Metadata::Profile(inner) => Ok(inner),
+            _ => Err(value),
+        }
+    }
+}
+
+impl<'a> TryFrom<Metadata<'a>> for Cow<'a, Policy> {
+    type Error = Metadata<'a>;
+
+    fn try_from(value: Metadata<'a>) -> Result<Self, Self::Error> {
+        match value {
+            Metadata::Policy(inner) => Ok(inner),
+            _ => Err(value),
+        }
+    }
+}
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Signed<T> {
     pub signed: T,
@@ -478,18 +566,7 @@ impl<T: HasPrev> Signed<T> {
     {
         match self.signed.prev() {
             None => Ok(false),
-            Some(parent) if parent == ancestor => Ok(true),
-            Some(_) => {
-                for prev in self.ancestors(find_prev) {
-                    match prev?.signed.prev() {
-                        None => return Ok(false),
-                        Some(parent) if parent == ancestor => return Ok(true),
-                        _ => continue,
-                    }
-                }
-
-                Ok(false)
-            },
+            Some(parent) => ancestry::is_ancestor_or_self(parent, ancestor, find_prev),
         }
     }
 }
@@ -525,6 +602,12 @@ impl Signed<Identity> {
     }
 }
 
+impl Signed<Profile> {
+    pub fn verify(&self, signer: &identity::Verified) -> Result<(), error::Verification> {
+        self.signed.verify(&self.signatures, signer)
+    }
+}
+
 impl<T> AsRef<T> for Signed<T> {
     fn as_ref(&self) -> &T {
         &self.signed
@@ -578,6 +661,10 @@ impl Key<'_> {
     pub fn id(&self) -> KeyId {
         self.into()
     }
+
+    pub fn ensure_supported(&self) -> crate::Result<()> {
+        self.0.ensure_supported()
+    }
 }
 
 impl fmt::Debug for Key<'_> {
@@ -594,7 +681,15 @@ impl<'a> From<VerificationKey<'a>> for Key<'a> {
 
 impl signature::Verifier<Signature> for Key<'_> {
     fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), signature::Error> {
-        let ssh = ssh::Signature::new(self.0.algorithm(), signature.as_ref())?;
+        // Signatures are stored without their algorithm, which is implied by
+        // the key -- except for RSA, where we always sign using SHA-512
+        let alg = match self.0.algorithm() {
+            ssh::Algorithm::Rsa { hash: None } => ssh::Algorithm::Rsa {
+                hash: Some(ssh::HashAlg::Sha512),
+            },
+            alg => alg,
+        };
+        let ssh = ssh::Signature::new(alg, signature.as_ref())?;
         self.0.verify(msg, &ssh)
     }
 }
@@ -621,10 +716,12 @@ impl<'de> serde::Deserialize<'de> for Key<'_> {
 }
 
 impl FromStr for Key<'_> {
-    type Err = ssh_key::Error;
+    type Err = crate::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        VerificationKey::from_openssh(s).map(Self)
+        let key = VerificationKey::from_openssh(s)?;
+        key.ensure_supported()?;
+        Ok(Self(key))
     }
 }
 
@@ -720,6 +817,7 @@ impl signature::Signature for Signature {
     }
 }
 
+#[derive(Clone)]
 pub struct Verified<T>(T);
 
 impl<T> Verified<T> {
@@ -0,0 +1,180 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Index of metadata histories
+//!
+//! Answering whether a revision of an identity (or drop) is an ancestor of
+//! another requires loading the intermediate revisions. The [`Index`] records
+//! the generation number and parent of each revision seen, so each revision
+//! needs to be loaded at most once, and ancestry checks are linear in the
+//! number of generations between the two revisions.
+//!
+//! A process-wide index is kept in memory, and persisted in the user's cache
+//! directory.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use log::debug;
+use once_cell::sync::Lazy;
+
+use super::{
+    ContentHash,
+    HasPrev,
+    Signed,
+};
+use crate::{
+    cfg::paths,
+    fs::LockedFile,
+};
+
+static INDEX: Lazy<Mutex<Index>> = Lazy::new(|| Mutex::new(Index::load(paths::ancestry_index())));
+
+/// Whether `ancestor` is `hash`, or an ancestor of the revision `hash`
+///
+/// Uses the process-wide index.
+pub fn is_ancestor_or_self<T, F>(
+    hash: &ContentHash,
+    ancestor: &ContentHash,
+    find_prev: F,
+) -> io::Result<bool>
+where
+    T: HasPrev,
+    F: FnMut(&ContentHash) -> io::Result<Signed<T>>,
+{
+    let mut index = INDEX.lock().unwrap();
+    let res = index.is_ancestor_or_self(hash, ancestor, find_prev);
+    if index.dirty {
+        if let Err(e) = index.persist(paths::ancestry_index()) {
+            debug!("failed to persist ancestry index: {e}");
+        }
+    }
+    res
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Entry {
+    generation: u64,
+    prev: Option<ContentHash>,
+}
+
+#[derive(Default)]
+pub struct Index {
+    entries: HashMap<ContentHash, Entry>,
+    dirty: bool,
+}
+
+impl Index {
+    /// Load a persisted index from `path`
+    ///
+    /// If the file doesn't exist or can't be read, an empty index is
+    /// returned.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let entries: Vec<(ContentHash, Entry)> = match path.map(fs::read) {
+            Some(Ok(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                debug!("ignoring corrupt ancestry index: {e}");
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+
+        Self {
+            entries: entries.into_iter().collect(),
+            dirty: false,
+        }
+    }
+
+    pub fn persist(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        if let Some(path) = path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut lock = LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+            serde_json::to_writer(&mut lock, &self.entries.iter().collect::<Vec<_>>())?;
+            lock.persist()?;
+        }
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Whether `ancestor` is `hash`, or an ancestor of the revision `hash`
+    pub fn is_ancestor_or_self<T, F>(
+        &mut self,
+        hash: &ContentHash,
+        ancestor: &ContentHash,
+        find_prev: F,
+    ) -> io::Result<bool>
+    where
+        T: HasPrev,
+        F: FnMut(&ContentHash) -> io::Result<Signed<T>>,
+    {
+        if hash == ancestor {
+            return Ok(true);
+        }
+        let generation = self.insert(hash, find_prev)?;
+        let target = match self.entries.get(ancestor) {
+            // Not in the history of `hash`, otherwise `insert` would've seen it
+            None => return Ok(false),
+            Some(entry) => entry.generation,
+        };
+        if target >= generation {
+            return Ok(false);
+        }
+
+        let mut cur = self.entries[hash].prev.as_ref();
+        while let Some(prev) = cur {
+            if prev == ancestor {
+                return Ok(true);
+            }
+            let entry = &self.entries[prev];
+            if entry.generation <= target {
+                break;
+            }
+            cur = entry.prev.as_ref();
+        }
+
+        Ok(false)
+    }
+
+    /// Index the history of `hash`, and return its generation
+    fn insert<T, F>(&mut self, hash: &ContentHash, mut find_prev: F) -> io::Result<u64>
+    where
+        T: HasPrev,
+        F: FnMut(&ContentHash) -> io::Result<Signed<T>>,
+    {
+        if let Some(entry) = self.entries.get(hash) {
+            return Ok(entry.generation);
+        }
+
+        let mut chain = Vec::new();
+        let mut cur = Some(hash.clone());
+        let base = loop {
+            match cur {
+                None => break None,
+                Some(h) => match self.entries.get(&h) {
+                    Some(entry) => break Some(entry.generation),
+                    None => {
+                        let prev = find_prev(&h)?.signed.prev().cloned();
+                        chain.push((h, prev.clone()));
+                        cur = prev;
+                    },
+                },
+            }
+        };
+
+        let mut generation = base.map_or(0, |g| g + 1);
+        for (h, prev) in chain.into_iter().rev() {
+            self.entries.insert(h, Entry { generation, prev });
+            generation += 1;
+        }
+        self.dirty = true;
+
+        Ok(self.entries[hash].generation)
+    }
+}
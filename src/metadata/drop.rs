@@ -8,6 +8,7 @@ use std::{
         BTreeSet,
         HashMap,
     },
+    fmt,
     io,
     num::NonZeroUsize,
     ops::Deref,
@@ -29,6 +30,7 @@ use super::{
     KeySet,
     Metadata,
     Mirrors,
+    Policy,
     Signature,
     Signed,
 };
@@ -81,6 +83,64 @@ impl Roles {
         ids.extend(branches.values().flat_map(|a| &a.role.ids));
         ids
     }
+
+    /// Test whether `id` is a member of the role `name`
+    pub fn has_role(&self, id: &IdentityId, name: RoleName) -> bool {
+        match name {
+            RoleName::Root => self.root.ids.contains(id),
+            RoleName::Snapshot => self.snapshot.ids.contains(id),
+            RoleName::Mirrors => self.mirrors.ids.contains(id),
+            RoleName::Branch(branch) => self
+                .branches
+                .get(branch)
+                .map(|a| a.role.ids.contains(id))
+                .unwrap_or(false),
+            RoleName::AnyBranch => self.branches.values().any(|a| a.role.ids.contains(id)),
+        }
+    }
+
+    /// The identities holding the role `name`
+    pub fn members(&self, name: RoleName) -> BTreeSet<IdentityId> {
+        match name {
+            RoleName::Root => self.root.ids.clone(),
+            RoleName::Snapshot => self.snapshot.ids.clone(),
+            RoleName::Mirrors => self.mirrors.ids.clone(),
+            RoleName::Branch(branch) => self
+                .branches
+                .get(branch)
+                .map(|a| a.role.ids.clone())
+                .unwrap_or_default(),
+            RoleName::AnyBranch => self
+                .branches
+                .values()
+                .flat_map(|a| &a.role.ids)
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+/// Names the roles defined in [`Roles`]
+#[derive(Clone, Copy, Debug)]
+pub enum RoleName<'a> {
+    Root,
+    Snapshot,
+    Mirrors,
+    Branch(&'a Refname),
+    /// Any of the branch roles
+    AnyBranch,
+}
+
+impl fmt::Display for RoleName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Root => f.write_str("root"),
+            Self::Snapshot => f.write_str("snapshot"),
+            Self::Mirrors => f.write_str("mirrors"),
+            Self::Branch(name) => write!(f, "branches[{name}]"),
+            Self::AnyBranch => f.write_str("branches"),
+        }
+    }
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -98,6 +158,97 @@ pub struct Annotated {
     pub description: Description,
 }
 
+/// Ref globs permitted in patch bundles, by role of the submitter
+///
+/// `contributors` applies to any submitter, and members of a role may
+/// additionally convey the refs matching the globs given for that role. If
+/// `contributors` is not set, no restrictions apply beyond the ones built into
+/// the implementation, and the per-role globs are meaningless.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct RefPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contributors: Option<BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub root: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub snapshot: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub mirrors: BTreeSet<String>,
+    /// Applies to members of any of the branch roles
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub branches: BTreeSet<String>,
+}
+
+impl RefPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.contributors.is_none()
+            && self.root.is_empty()
+            && self.snapshot.is_empty()
+            && self.mirrors.is_empty()
+            && self.branches.is_empty()
+    }
+
+    /// The globs permitted to `id`, given its membership in `roles`
+    ///
+    /// `None` means no restrictions apply.
+    pub fn globs_for<'a>(&'a self, roles: &Roles, id: &IdentityId) -> Option<BTreeSet<&'a str>> {
+        let contributors = self.contributors.as_ref()?;
+        let mut globs = contributors
+            .iter()
+            .map(String::as_str)
+            .collect::<BTreeSet<_>>();
+        for (name, extra) in [
+            (RoleName::Root, &self.root),
+            (RoleName::Snapshot, &self.snapshot),
+            (RoleName::Mirrors, &self.mirrors),
+            (RoleName::AnyBranch, &self.branches),
+        ] {
+            if roles.has_role(id, name) {
+                globs.extend(extra.iter().map(String::as_str));
+            }
+        }
+
+        Some(globs)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.contributors
+            .iter()
+            .flatten()
+            .chain(&self.root)
+            .chain(&self.snapshot)
+            .chain(&self.mirrors)
+            .chain(&self.branches)
+    }
+}
+
+/// Limits on the number of commits per ref of a patch bundle
+///
+/// The limit configured by the drop operator is raised by `per_record` for
+/// every record previously accepted from the submitter, up to `max`. Refs
+/// matching a glob in `refs` start out from the given limit instead of the
+/// operator's. Checkpoints by members of the respective roles are not subject
+/// to these limits.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct CommitLimits {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub per_record: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<NonZeroUsize>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub refs: BTreeMap<String, NonZeroUsize>,
+}
+
+impl CommitLimits {
+    pub fn is_empty(&self) -> bool {
+        self.per_record == 0 && self.max.is_none() && self.refs.is_empty()
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
 pub type Verified = super::Verified<Drop>;
 
 #[derive(Clone, serde::Deserialize)]
@@ -108,6 +259,21 @@ pub struct Drop {
     pub description: Description,
     pub prev: Option<ContentHash>,
     pub roles: Roles,
+    /// Paths to identity repositories to consult in addition to the local
+    /// search path
+    ///
+    /// Paths are relative to a directory configured by the client, cf.
+    /// [`crate::cfg::git::IT_ID_PATH_ROOT`], and must not point outside of it.
+    #[serde(default)]
+    pub id_path: Vec<String>,
+    /// Restrictions on the refs patch bundles may convey, depending on the
+    /// roles of the submitter
+    #[serde(default)]
+    pub ref_policy: RefPolicy,
+    /// Limits on the size of patches, depending on the track record of the
+    /// submitter
+    #[serde(default)]
+    pub commit_limits: CommitLimits,
     #[serde(default)]
     pub custom: Custom,
 }
@@ -204,6 +370,34 @@ impl Drop {
             .verify_signatures(&payload, self.roles.mirrors.threshold, &alt.signatures)
     }
 
+    /// Verify that `policy` is signed by the root role
+    pub fn verify_policy<'a, F>(
+        &self,
+        policy: &Signed<Policy>,
+        find_signer: F,
+    ) -> Result<(), error::Verification>
+    where
+        F: FnMut(&IdentityId) -> io::Result<KeySet<'a>>,
+    {
+        use error::Verification::*;
+
+        if let Some(deadline) = &policy.signed.expires {
+            if deadline < &DateTime::now() {
+                return Err(Expired);
+            }
+        }
+        if !super::policy::FMT_VERSION.is_compatible(&policy.signed.fmt_version) {
+            return Err(IncompatibleVersion);
+        }
+
+        let payload = Sha512::digest(policy.signed.canonicalise()?);
+        verify::AuthorisedSigners::from_ids(&self.roles.root.ids, find_signer)?.verify_signatures(
+            &payload,
+            self.roles.root.threshold,
+            &policy.signatures,
+        )
+    }
+
     pub fn canonicalise(&self) -> Result<Vec<u8>, canonical::error::Canonicalise> {
         canonical::to_vec(Metadata::drop(self))
     }
@@ -228,7 +422,7 @@ impl serde::Serialize for Drop {
     {
         use serde::ser::SerializeStruct;
 
-        let mut s = serializer.serialize_struct("Drop", 5)?;
+        let mut s = serializer.serialize_struct("Drop", 8)?;
         let version_field = if self.fmt_version < FMT_VERSION {
             "spec_version"
         } else {
@@ -238,6 +432,23 @@ impl serde::Serialize for Drop {
         s.serialize_field("description", &self.description)?;
         s.serialize_field("prev", &self.prev)?;
         s.serialize_field("roles", &self.roles)?;
+        // Omit if empty, so as to not change the canonical form of drops which
+        // don't make use of this
+        if self.id_path.is_empty() {
+            s.skip_field("id_path")?;
+        } else {
+            s.serialize_field("id_path", &self.id_path)?;
+        }
+        if self.ref_policy.is_empty() {
+            s.skip_field("ref_policy")?;
+        } else {
+            s.serialize_field("ref_policy", &self.ref_policy)?;
+        }
+        if self.commit_limits.is_empty() {
+            s.skip_field("commit_limits")?;
+        } else {
+            s.serialize_field("commit_limits", &self.commit_limits)?;
+        }
         s.serialize_field("custom", &self.custom)?;
         s.end()
     }
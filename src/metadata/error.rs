@@ -35,6 +35,12 @@ pub enum Verification {
     #[error("duplicate key: key {0} appears in more than one identity")]
     DuplicateKey(KeyId),
 
+    #[error("key {0} is revoked")]
+    Revoked(KeyId),
+
+    #[error("revocation of key {0} was removed or postponed")]
+    Unrevoked(KeyId),
+
     #[error(transparent)]
     Io(#[from] io::Error),
 }
@@ -19,10 +19,10 @@ use super::{
     KeySet,
     Metadata,
     Mirrors,
+    Policy,
     Signed,
 };
 use crate::{
-    cmd,
     git::if_not_found_none,
     json,
 };
@@ -31,6 +31,7 @@ pub const META_FILE_ALTERNATES: &str = "alternates.json";
 pub const META_FILE_DROP: &str = "drop.json";
 pub const META_FILE_ID: &str = "id.json";
 pub const META_FILE_MIRRORS: &str = "mirrors.json";
+pub const META_FILE_POLICY: &str = "policy.json";
 
 pub mod error {
     use thiserror::Error;
@@ -55,6 +56,7 @@ pub type GitIdentity = GitMeta<Identity>;
 pub type GitDrop = GitMeta<Drop>;
 pub type GitMirrors = GitMeta<Mirrors>;
 pub type GitAlternates = GitMeta<Alternates>;
+pub type GitPolicy = GitMeta<Policy>;
 
 impl GitMeta<Drop> {
     pub fn verified<'a, F, G>(
@@ -165,6 +167,10 @@ impl FromGit for Alternates {
     const METADATA_JSON: &'static str = META_FILE_ALTERNATES;
 }
 
+impl FromGit for Policy {
+    const METADATA_JSON: &'static str = META_FILE_POLICY;
+}
+
 pub fn find_parent<T>(
     repo: &git2::Repository,
 ) -> impl Fn(&ContentHash) -> io::Result<Signed<T>> + '_
@@ -213,7 +219,7 @@ where
 pub fn find_ref_in_path<'a>(
     search_path: &'a [git2::Repository],
     name: &str,
-) -> cmd::Result<Option<(&'a git2::Repository, git2::Reference<'a>)>> {
+) -> crate::Result<Option<(&'a git2::Repository, git2::Reference<'a>)>> {
     for repo in search_path {
         let have_ref = if_not_found_none(repo.resolve_reference_from_short_name(name))?;
         if let Some(r) = have_ref {
@@ -31,6 +31,7 @@ use signature::Verifier;
 use url::Url;
 
 use super::{
+    ancestry,
     error,
     git::{
         find_parent_in_tree,
@@ -146,6 +147,46 @@ impl Verified {
             .values()
             .any(|key| key.verify(msg.as_ref(), sig).is_ok())
     }
+
+    /// The unexpired bot key of the signer's _current_ identity for which
+    /// signature is valid over message, if any
+    pub fn bot_signed<T: AsRef<[u8]>>(&self, msg: T, sig: &Signature) -> Option<&BotKey> {
+        self.cur
+            .bots
+            .values()
+            .filter(|bot| !bot.is_expired())
+            .find(|bot| bot.key.verify(msg.as_ref(), sig).is_ok())
+    }
+
+    /// The unexpired bot key with the given id, if any
+    pub fn bot_key(&self, id: &KeyId) -> Option<&BotKey> {
+        self.cur.bots.get(id).filter(|bot| !bot.is_expired())
+    }
+
+    /// The revoked key for which signature is valid over message, if any
+    pub fn revoked_signer<T: AsRef<[u8]>>(
+        &self,
+        msg: T,
+        sig: &Signature,
+    ) -> Option<(&KeyId, &Revocation)> {
+        self.cur
+            .revoked
+            .iter()
+            .find(|(_, revocation)| revocation.key.verify(msg.as_ref(), sig).is_ok())
+    }
+
+    /// The revocation of the key with the given id, if it is in effect at
+    /// `at`
+    ///
+    /// Signatures made by the key at or after `at` must be rejected, even if
+    /// the key is in the revision of the identity referenced by the
+    /// signature.
+    pub fn revoked_at(&self, id: &KeyId, at: &DateTime) -> Option<&Revocation> {
+        self.cur
+            .revoked
+            .get(id)
+            .filter(|revocation| revocation.is_effective_at(at))
+    }
 }
 
 impl AsRef<Identity> for Verified {
@@ -154,6 +195,21 @@ impl AsRef<Identity> for Verified {
     }
 }
 
+/// How to treat identities past their expiry date during verification
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ExpiryPolicy {
+    /// Fail with [`error::Verification::Expired`]
+    #[default]
+    Strict,
+    /// Log a warning, but otherwise verify the identity as if it had not
+    /// expired
+    ///
+    /// Signatures made after the expiry can not be told apart from earlier
+    /// ones, so this is only appropriate for signatures known to predate the
+    /// expiry, such as over metadata already recorded in a drop.
+    Warn,
+}
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Roles {
@@ -182,6 +238,100 @@ pub struct Role {
     pub threshold: NonZeroUsize,
 }
 
+/// A key for automated use, eg. by CI bots
+///
+/// Bot keys can not sign the identity document, and can only sign patches
+/// within their [`BotScope`].
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct BotKey {
+    pub key: Key<'static>,
+    /// Human-readable name of the bot
+    pub name: Option<String>,
+    pub scope: BotScope,
+    pub expires: Option<DateTime>,
+}
+
+impl BotKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .as_ref()
+            .map_or(false, |deadline| deadline < &DateTime::now())
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct BotScope {
+    /// Only allow comments, ie. no branches, tags, or checkpoints
+    pub comment_only: bool,
+    /// Only allow drops with any of these identities in their root role
+    ///
+    /// If empty, any drop is allowed.
+    pub drops: BTreeSet<IdentityId>,
+}
+
+impl BotScope {
+    /// Whether a patch is permitted by this scope
+    ///
+    /// `drop_root` are the identities in the root role of the drop the patch
+    /// is submitted to, `is_comment` tells whether the patch is a comment.
+    pub fn permits(&self, drop_root: &BTreeSet<IdentityId>, is_comment: bool) -> bool {
+        (is_comment || !self.comment_only)
+            && (self.drops.is_empty() || !self.drops.is_disjoint(drop_root))
+    }
+}
+
+/// Tombstone of a key removed from the identity
+///
+/// Signatures made by the key at or after `effective` are invalid, while
+/// earlier ones remain valid. Once revoked, a key can not be re-added, and
+/// the revocation can not be removed nor postponed by later revisions.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Revocation {
+    pub key: Key<'static>,
+    pub reason: RevocationReason,
+    pub effective: DateTime,
+}
+
+impl Revocation {
+    pub fn is_effective_at(&self, at: &DateTime) -> bool {
+        &self.effective <= at
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RevocationReason {
+    /// The secret key was (possibly) disclosed
+    Compromised,
+    /// The key was replaced by a new one
+    Superseded,
+    /// The key is no longer in use
+    Retired,
+}
+
+impl fmt::Display for RevocationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Compromised => "compromised",
+            Self::Superseded => "superseded",
+            Self::Retired => "retired",
+        })
+    }
+}
+
+impl FromStr for RevocationReason {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compromised" => Ok(Self::Compromised),
+            "superseded" => Ok(Self::Superseded),
+            "retired" => Ok(Self::Retired),
+            _ => Err("expected one of 'compromised', 'superseded', 'retired'"),
+        }
+    }
+}
+
 #[derive(Clone, serde::Deserialize)]
 pub struct Identity {
     #[serde(alias = "spec_version")]
@@ -194,6 +344,10 @@ pub struct Identity {
     pub expires: Option<DateTime>,
     #[serde(default)]
     pub custom: Custom,
+    #[serde(default)]
+    pub bots: BTreeMap<KeyId, BotKey>,
+    #[serde(default)]
+    pub revoked: BTreeMap<KeyId, Revocation>,
 }
 
 impl Identity {
@@ -209,22 +363,58 @@ impl Identity {
         Ok(Verified { id, cur: self })
     }
 
+    /// Like [`Self::verified`], but treat an expired identity according to
+    /// `expiry`
+    pub fn verified_with<F>(
+        self,
+        signatures: &BTreeMap<KeyId, Signature>,
+        find_prev: F,
+        expiry: ExpiryPolicy,
+    ) -> Result<Verified, error::Verification>
+    where
+        F: FnMut(&ContentHash) -> io::Result<Signed<Self>>,
+    {
+        let id = self.verify_with(signatures, find_prev, expiry)?;
+        Ok(Verified { id, cur: self })
+    }
+
     pub fn verify<F>(
         &self,
         signatures: &BTreeMap<KeyId, Signature>,
         find_prev: F,
     ) -> Result<IdentityId, error::Verification>
+    where
+        F: FnMut(&ContentHash) -> io::Result<Signed<Self>>,
+    {
+        self.verify_with(signatures, find_prev, ExpiryPolicy::Strict)
+    }
+
+    /// Like [`Self::verify`], but treat an expired identity according to
+    /// `expiry`
+    pub fn verify_with<F>(
+        &self,
+        signatures: &BTreeMap<KeyId, Signature>,
+        find_prev: F,
+        expiry: ExpiryPolicy,
+    ) -> Result<IdentityId, error::Verification>
     where
         F: FnMut(&ContentHash) -> io::Result<Signed<Self>>,
     {
         use error::Verification::Expired;
 
-        if let Some(deadline) = &self.expires {
-            if deadline < &DateTime::now() {
-                return Err(Expired);
-            }
+        let expired = self
+            .expires
+            .as_ref()
+            .filter(|deadline| *deadline < &DateTime::now());
+        if expired.is_some() && expiry == ExpiryPolicy::Strict {
+            return Err(Expired);
         }
-        self.verify_tail(Cow::Borrowed(signatures), find_prev)
+        let id = self.verify_tail(Cow::Borrowed(signatures), find_prev)?;
+        if let Some(deadline) = expired {
+            warn!("Identity {id} expired at {}", **deadline);
+        }
+
+        Ok(id)
     }
 
     fn verify_tail<F>(
@@ -241,10 +431,17 @@ impl Identity {
             return Err(IncompatibleVersion);
         }
 
+        self.verify_revocations()?;
         let canonical = self.canonicalise()?;
         let signed = Sha512::digest(&canonical);
         self.verify_signatures(signatures.iter(), &signed)?;
         if let Some(prev) = self.prev.as_ref().map(&mut find_prev).transpose()? {
+            for (id, revocation) in &prev.signed.revoked {
+                match self.revoked.get(id) {
+                    Some(ours) if ours.effective <= revocation.effective => {},
+                    _ => return Err(error::Verification::Unrevoked(*id)),
+                }
+            }
             prev.signed.verify_signatures(signatures.iter(), &signed)?;
             return prev
                 .signed
@@ -254,6 +451,20 @@ impl Identity {
         Ok(IdentityId(Sha256::digest(canonical).into()))
     }
 
+    /// Check that no revoked key is in use
+    fn verify_revocations(&self) -> Result<(), error::Verification> {
+        for (id, revocation) in &self.revoked {
+            if self.keys.contains_key(id)
+                || self.bots.contains_key(id)
+                || revocation.key.id() != *id
+            {
+                return Err(error::Verification::Revoked(*id));
+            }
+        }
+
+        Ok(())
+    }
+
     fn verify_signatures<'a, I>(
         &self,
         signatures: I,
@@ -291,6 +502,15 @@ impl Identity {
         canonical::to_vec(Metadata::identity(self))
     }
 
+    /// The URL of the identity's [`super::Profile`], if it has one
+    pub fn profile_url(&self) -> Option<Url> {
+        self.custom
+            .get(super::profile::CUSTOM_KEY)?
+            .as_str()?
+            .parse()
+            .ok()
+    }
+
     pub fn ancestors<F>(&self, find_prev: F) -> impl Iterator<Item = io::Result<Signed<Self>>>
     where
         F: FnMut(&ContentHash) -> io::Result<Signed<Self>>,
@@ -308,18 +528,7 @@ impl Identity {
     {
         match &self.prev {
             None => Ok(false),
-            Some(parent) if parent == ancestor => Ok(true),
-            Some(_) => {
-                for prev in self.ancestors(find_prev) {
-                    match &prev?.signed.prev {
-                        None => return Ok(false),
-                        Some(parent) if parent == ancestor => return Ok(true),
-                        _ => continue,
-                    }
-                }
-
-                Ok(false)
-            },
+            Some(parent) => ancestry::is_ancestor_or_self(parent, ancestor, find_prev),
         }
     }
 }
@@ -345,7 +554,7 @@ impl serde::Serialize for Identity {
 
         const HAVE_FMT_VERSION: FmtVersion = FmtVersion(super::FmtVersion::new(0, 2, 0));
 
-        let mut s = serializer.serialize_struct("Identity", 7)?;
+        let mut s = serializer.serialize_struct("Identity", 9)?;
         let version_field = if self.fmt_version < HAVE_FMT_VERSION {
             "spec_version"
         } else {
@@ -368,6 +577,18 @@ impl serde::Serialize for Identity {
         s.serialize_field("mirrors", &self.mirrors)?;
         s.serialize_field("expires", &self.expires)?;
         s.serialize_field("custom", &self.custom)?;
+        // Omit if empty, so as to not change the canonical form of identities
+        // without bots
+        if self.bots.is_empty() {
+            s.skip_field("bots")?;
+        } else {
+            s.serialize_field("bots", &self.bots)?;
+        }
+        if self.revoked.is_empty() {
+            s.skip_field("revoked")?;
+        } else {
+            s.serialize_field("revoked", &self.revoked)?;
+        }
 
         s.end()
     }
@@ -448,6 +669,16 @@ pub fn find_in_tree(
     repo: &git2::Repository,
     root: &git2::Tree,
     id: &IdentityId,
+) -> crate::Result<Verified> {
+    find_in_tree_with(repo, root, id, ExpiryPolicy::Strict)
+}
+
+/// Like [`find_in_tree`], but treat an expired identity according to `expiry`
+pub fn find_in_tree_with(
+    repo: &git2::Repository,
+    root: &git2::Tree,
+    id: &IdentityId,
+    expiry: ExpiryPolicy,
 ) -> crate::Result<Verified> {
     let (id_path, hist_path) = {
         let base = PathBuf::from(id.to_string());
@@ -466,9 +697,9 @@ pub fn find_in_tree(
         .into_tree()
         .map_err(|_| anyhow!("{} is not a directory", hist_path.display()))?;
 
-    let verified = meta
-        .signed
-        .verified(&meta.signatures, find_parent_in_tree(repo, &hist))?;
+    let verified =
+        meta.signed
+            .verified_with(&meta.signatures, find_parent_in_tree(repo, &hist), expiry)?;
     ensure!(
         verified.id() == id,
         "ids don't match after verification: expected {} found {}",
@@ -0,0 +1,76 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Usage policy of a drop, such as a contributor license agreement
+//!
+//! The policy is stored in the drop tree, signed by the drop's root role.
+//! Submitters must acknowledge it once per identity, see
+//! [`crate::patches::PolicyAck`].
+
+use std::{
+    borrow::Cow,
+    ops::Deref,
+};
+
+use super::{
+    Custom,
+    DateTime,
+    Metadata,
+};
+use crate::json::canonical;
+
+pub const FMT_VERSION: FmtVersion = FmtVersion(super::FmtVersion::new(0, 1, 0));
+
+#[derive(Clone, Eq, Ord, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
+pub struct FmtVersion(super::FmtVersion);
+
+impl Deref for FmtVersion {
+    type Target = super::FmtVersion;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Default for FmtVersion {
+    fn default() -> Self {
+        FMT_VERSION
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Policy {
+    pub fmt_version: FmtVersion,
+    /// The policy text submitters must agree to
+    pub text: String,
+    #[serde(default)]
+    pub custom: Custom,
+    pub expires: Option<DateTime>,
+}
+
+impl Policy {
+    pub fn new(text: String) -> Self {
+        Self {
+            fmt_version: FMT_VERSION,
+            text,
+            custom: Custom::default(),
+            expires: None,
+        }
+    }
+
+    pub fn canonicalise(&self) -> Result<Vec<u8>, canonical::error::Canonicalise> {
+        canonical::to_vec(Metadata::policy(self))
+    }
+}
+
+impl From<Policy> for Cow<'static, Policy> {
+    fn from(p: Policy) -> Self {
+        Self::Owned(p)
+    }
+}
+
+impl<'a> From<&'a Policy> for Cow<'a, Policy> {
+    fn from(p: &'a Policy) -> Self {
+        Self::Borrowed(p)
+    }
+}
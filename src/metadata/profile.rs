@@ -0,0 +1,271 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Optional profile data of an identity, such as a display name and avatar
+//!
+//! A profile is a separate document signed by any of the identity's keys, so
+//! it can be changed without updating the identity itself. The identity
+//! references it by URL via the [`CUSTOM_KEY`] of its custom metadata, which
+//! would typically be the [`WELL_KNOWN_PATH`] of an `it serve` instance.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    fs,
+    io,
+    ops::Deref,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use digest::Digest;
+use sha2::Sha512;
+
+use super::{
+    error,
+    git::error::TypeMismatch,
+    identity,
+    Custom,
+    DateTime,
+    IdentityId,
+    KeyId,
+    Metadata,
+    Signature,
+    Signed,
+};
+use crate::{
+    fs::LockedFile,
+    json::canonical,
+    str::Varchar,
+};
+
+pub const FMT_VERSION: FmtVersion = FmtVersion(super::FmtVersion::new(0, 1, 0));
+
+/// Key in the custom metadata of an identity holding the URL of its profile
+pub const CUSTOM_KEY: &str = "profile";
+
+/// Path below which `it serve` serves profiles, by identity id
+pub const WELL_KNOWN_PATH: &str = ".well-known/it/profile";
+
+/// Maximum size of the JSON-encoded, signed profile document
+pub const MAX_SIZE: usize = 512 * 1024;
+
+/// Maximum size of the (decoded) avatar image
+pub const MAX_AVATAR_SIZE: usize = 256 * 1024;
+
+/// Location of stored profiles, relative to `$GIT_DIR`
+const PROFILES_DIR: &str = "it/profiles";
+
+#[derive(Clone, Eq, Ord, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
+pub struct FmtVersion(super::FmtVersion);
+
+impl Deref for FmtVersion {
+    type Target = super::FmtVersion;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Default for FmtVersion {
+    fn default() -> Self {
+        FMT_VERSION
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Profile {
+    pub fmt_version: FmtVersion,
+    /// Display name
+    pub name: Option<Varchar<String, 128>>,
+    /// Short free-form description
+    pub bio: Option<Varchar<String, 2048>>,
+    pub avatar: Option<Avatar>,
+    #[serde(default)]
+    pub custom: Custom,
+    pub expires: Option<DateTime>,
+}
+
+impl Profile {
+    pub fn canonicalise(&self) -> Result<Vec<u8>, canonical::error::Canonicalise> {
+        canonical::to_vec(Metadata::profile(self))
+    }
+
+    /// Check that the avatar, if any, is within size limits and its content
+    /// matches its declared media type
+    pub fn validate(&self) -> crate::Result<()> {
+        if let Some(avatar) = &self.avatar {
+            avatar.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Verify that the profile is signed by any of the _current_ keys of
+    /// `signer`
+    pub fn verify(
+        &self,
+        signatures: &BTreeMap<KeyId, Signature>,
+        signer: &identity::Verified,
+    ) -> Result<(), error::Verification> {
+        use error::Verification::*;
+
+        if let Some(deadline) = &self.expires {
+            if deadline < &DateTime::now() {
+                return Err(Expired);
+            }
+        }
+        if !FMT_VERSION.is_compatible(&self.fmt_version) {
+            return Err(IncompatibleVersion);
+        }
+
+        let payload = Sha512::digest(self.canonicalise()?);
+        if signatures.values().any(|sig| signer.did_sign(payload, sig)) {
+            Ok(())
+        } else {
+            Err(SignatureThreshold)
+        }
+    }
+}
+
+impl From<Profile> for Cow<'static, Profile> {
+    fn from(p: Profile) -> Self {
+        Self::Owned(p)
+    }
+}
+
+impl<'a> From<&'a Profile> for Cow<'a, Profile> {
+    fn from(p: &'a Profile) -> Self {
+        Self::Borrowed(p)
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Avatar {
+    pub media_type: MediaType,
+    #[serde(with = "crate::serde::base64")]
+    pub data: Vec<u8>,
+}
+
+impl Avatar {
+    /// Create an avatar from raw image data, detecting its media type
+    pub fn from_bytes(data: Vec<u8>) -> crate::Result<Self> {
+        let media_type = MediaType::sniff(&data)
+            .ok_or_else(|| anyhow!("avatar must be a PNG, JPEG or WebP image"))?;
+        let avatar = Self { media_type, data };
+        avatar.validate()?;
+        Ok(avatar)
+    }
+
+    pub fn validate(&self) -> crate::Result<()> {
+        let len = self.data.len();
+        ensure!(
+            len <= MAX_AVATAR_SIZE,
+            "avatar size exceeds {MAX_AVATAR_SIZE} bytes: {len}"
+        );
+        ensure!(
+            MediaType::sniff(&self.data).as_ref() == Some(&self.media_type),
+            "avatar content does not match media type {}",
+            self.media_type
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum MediaType {
+    #[serde(rename = "image/png")]
+    Png,
+    #[serde(rename = "image/jpeg")]
+    Jpeg,
+    #[serde(rename = "image/webp")]
+    Webp,
+}
+
+impl MediaType {
+    /// Determine the media type from the magic bytes of `data`
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if data.starts_with(b"\xff\xd8\xff") {
+            Some(Self::Jpeg)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Decode a signed profile document, enforcing [`MAX_SIZE`]
+///
+/// The profile is validated, but signatures are not verified.
+pub fn from_slice(data: &[u8]) -> crate::Result<Signed<Profile>> {
+    ensure!(
+        data.len() <= MAX_SIZE,
+        "profile size exceeds {MAX_SIZE} bytes"
+    );
+    let signed = serde_json::from_slice::<Signed<Metadata>>(data)?
+        .fmap(Cow::<Profile>::try_from)
+        .transpose()
+        .map_err(|_| TypeMismatch)?
+        .fmap(Cow::into_owned);
+    signed.signed.validate()?;
+
+    Ok(signed)
+}
+
+/// Path of the stored profile of `id` in the repository at `git_dir`
+pub fn path(git_dir: &Path, id: &IdentityId) -> PathBuf {
+    git_dir.join(PROFILES_DIR).join(format!("{id}.json"))
+}
+
+/// Load the stored profile of `id`, if any
+pub fn load(git_dir: &Path, id: &IdentityId) -> crate::Result<Option<Signed<Profile>>> {
+    match fs::read(path(git_dir, id)) {
+        Ok(data) => from_slice(&data).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Store the profile of `id`, replacing any previous one
+///
+/// The caller is responsible for verifying the profile beforehand.
+pub fn store(git_dir: &Path, id: &IdentityId, profile: &Signed<Profile>) -> crate::Result<PathBuf> {
+    let path = path(git_dir, id);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let signed = Signed {
+        signed: Metadata::profile(&profile.signed),
+        signatures: profile.signatures.clone(),
+    };
+    let mut lock = LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+    serde_json::to_writer_pretty(&mut lock, &signed)?;
+    lock.persist()?;
+
+    Ok(path)
+}
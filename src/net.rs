@@ -0,0 +1,108 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Outbound HTTP
+//!
+//! All requests to remote drops, bundle locations and IPFS APIs go through a
+//! shared [`ureq::Agent`] via [`request`], so a proxy configured via
+//! [`cfg::git::IT_PROXY`] (or the `IT_PROXY` environment variable) applies
+//! uniformly.
+//!
+//! Hosts in the `.onion` domain are only contacted through a SOCKS proxy,
+//! which must resolve names remotely (eg. Tor's SOCKS port).
+
+use std::env;
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+use log::debug;
+use once_cell::sync::Lazy;
+use url::Url;
+
+use crate::{
+    cfg,
+    git::if_not_found_none,
+};
+
+pub const ENV_PROXY: &str = "IT_PROXY";
+
+struct Client {
+    agent: ureq::Agent,
+    proxy: Option<String>,
+}
+
+static CLIENT: Lazy<Result<Client, String>> =
+    Lazy::new(|| Client::from_env().map_err(|e| format!("{e:#}")));
+
+impl Client {
+    fn from_env() -> crate::Result<Self> {
+        let proxy = match env::var(ENV_PROXY) {
+            Ok(proxy) => Some(proxy),
+            Err(env::VarError::NotPresent) => {
+                let cfg = cfg::git::open_default()?;
+                if_not_found_none(cfg.get_string(cfg::git::IT_PROXY))?
+            },
+            Err(e) => return Err(e.into()),
+        }
+        .filter(|proxy| !proxy.is_empty());
+
+        let mut builder = ureq::AgentBuilder::new();
+        if let Some(proxy) = &proxy {
+            debug!("using proxy {proxy}");
+            builder = builder.proxy(ureq::Proxy::new(proxy)?);
+        }
+
+        Ok(Self {
+            agent: builder.build(),
+            proxy,
+        })
+    }
+
+    fn is_socks(&self) -> bool {
+        self.proxy
+            .as_deref()
+            .map_or(false, |proxy| proxy.starts_with("socks"))
+    }
+}
+
+fn client() -> crate::Result<&'static Client> {
+    CLIENT
+        .as_ref()
+        .map_err(|e| anyhow!("invalid proxy configuration: {e}"))
+}
+
+/// Prepare a request to `url` using the shared agent
+///
+/// Fails if `url` is an onion service, but no SOCKS proxy is configured.
+pub fn request(method: &str, url: &Url) -> crate::Result<ureq::Request> {
+    let client = client()?;
+    ensure!(
+        !is_onion(url) || client.is_socks(),
+        "{url} is an onion service, but no SOCKS proxy is configured (set {} or {ENV_PROXY})",
+        cfg::git::IT_PROXY
+    );
+
+    Ok(client.agent.request_url(method, url))
+}
+
+/// The proxy in a form suitable for git's `http.proxy`, if one is configured
+///
+/// SOCKS5 proxies are made to resolve host names remotely, so that onion
+/// services can be reached.
+pub fn git_proxy() -> crate::Result<Option<String>> {
+    let client = client()?;
+    Ok(client
+        .proxy
+        .as_ref()
+        .map(|proxy| match proxy.strip_prefix("socks5://") {
+            Some(rest) => format!("socks5h://{rest}"),
+            None => proxy.clone(),
+        }))
+}
+
+pub fn is_onion(url: &Url) -> bool {
+    url.host_str()
+        .map_or(false, |host| host.trim_end_matches('.').ends_with(".onion"))
+}
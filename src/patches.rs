@@ -15,11 +15,7 @@ use anyhow::{
     bail,
 };
 
-use digest::{
-    generic_array::GenericArray,
-    typenum::U32,
-    Digest,
-};
+use digest::Digest;
 use hex::FromHex;
 use once_cell::sync::Lazy;
 use sha2::Sha256;
@@ -27,46 +23,90 @@ use sha2::Sha256;
 use crate::{
     git::Refname,
     iter::IteratorExt,
+    metadata::{
+        ContentHash,
+        KeyId,
+    },
+};
+
+pub mod audit;
+
+mod auto_reply;
+pub use auto_reply::{
+    AutoReply,
+    FILE_AUTO_REPLY,
 };
 
 mod traits;
+use traits::Blob;
 pub use traits::{
     to_blob,
     to_tree,
-    Seen,
-};
-use traits::{
     write_sharded,
-    Blob,
+    Seen,
 };
 
 mod bundle;
 pub use bundle::Bundle;
 
+pub mod composite;
+
 mod error;
-pub use error::FromTree;
+pub use error::{
+    FromTree,
+    Quarantined,
+};
 
+pub mod hooks;
+pub use hooks::Hooks;
+
+pub mod issues;
 pub mod iter;
+pub mod large_blobs;
+pub mod lint;
+pub mod mbox;
 pub mod notes;
+pub mod pins;
+
+pub mod policy;
+pub use policy::PolicyAck;
 
+pub mod reachable;
 pub mod record;
+pub mod roles;
 pub use record::{
     Record,
     Signature,
 };
 
+pub mod spam;
+pub mod squash;
+pub mod ssh;
+pub mod subscriptions;
+
+pub mod upload;
+
+pub mod view;
+
 mod state;
 pub use state::{
+    is_unbundled,
+    materialise,
     merge_notes,
+    parse_update_tip_reflog,
     unbundle,
     unbundled_ref,
+    verified_tree,
     DropHead,
+    DropHeadCache,
 };
 
 mod submit;
 pub use submit::{
+    quarantined_ref,
     AcceptArgs,
     AcceptOptions,
+    Prepared,
     Submission,
     ALLOWED_REFS,
     GLOB_HEADS,
@@ -78,16 +118,28 @@ pub use submit::{
 };
 
 pub const MAX_LEN_BUNDLE: usize = 5_000_000;
+/// Maximum size of a serialised [`crate::bundle::Info`] submitted in lieu of a
+/// bundle
+pub const MAX_LEN_INFO: usize = 100_000;
 
 pub const HTTP_HEADER_SIGNATURE: &str = "X-it-Signature";
+/// The [`crate::bundle::HashAlgorithm`] of an uploaded bundle, if not sha256
+pub const HTTP_HEADER_HASH_ALGORITHM: &str = "X-it-Hash-Algorithm";
+/// Hex-encoded SHA-256 hash of a chunk of a bundle uploaded in chunks, see
+/// [`upload`]
+pub const HTTP_HEADER_CHUNK_HASH: &str = "X-it-Chunk-Hash";
 
 pub const REF_HEADS_PATCHES: &str = "refs/heads/patches";
 
+pub const REF_IT_AUDIT: &str = "refs/it/audit";
 pub const REF_IT_BRANCHES: &str = "refs/it/branches";
 pub const REF_IT_BUNDLES: &str = "refs/it/bundles";
+pub const REF_IT_NOTIFIED: &str = "refs/it/notified";
 pub const REF_IT_PATCHES: &str = "refs/it/patches";
+pub const REF_IT_QUARANTINE: &str = "refs/it/quarantine";
 pub const REF_IT_SEEN: &str = "refs/it/seen";
 pub const REF_IT_TOPICS: &str = "refs/it/topics";
+pub const REF_IT_VERIFIED: &str = "refs/it/verified";
 
 pub const BLOB_HEADS: &str = "heads";
 pub const BLOB_META: &str = "record.json";
@@ -95,16 +147,32 @@ pub const BLOB_META: &str = "record.json";
 pub static TOPIC_MERGES: Lazy<Topic> = Lazy::new(|| Topic::hashed("merges"));
 pub static TOPIC_SNAPSHOTS: Lazy<Topic> = Lazy::new(|| Topic::hashed("snapshots"));
 
-#[derive(Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Eq, Ord, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
 pub struct Topic(#[serde(with = "hex::serde")] [u8; 32]);
 
 impl Topic {
     const TRAILER_PREFIX: &str = "Re:";
 
-    pub fn hashed<T: AsRef<[u8]>>(v: T) -> Self {
+    pub(crate) fn hashed<T: AsRef<[u8]>>(v: T) -> Self {
         Self(Sha256::digest(v).into())
     }
 
+    /// Derive the topic for a new patch
+    ///
+    /// The topic is determined by the patch's [`record::Heads`], the cover
+    /// letter, and the [`KeyId`] of the submitter's signing key.
+    pub fn derive(
+        cover: &notes::Simple,
+        heads: &record::Heads,
+        keyid: &KeyId,
+    ) -> crate::Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update(heads.to_bytes());
+        serde_json::to_writer(&mut hasher, cover)?;
+        hasher.update(keyid);
+        Ok(Self(hasher.finalize().into()))
+    }
+
     pub fn from_commit(commit: &git2::Commit) -> crate::Result<Option<Self>> {
         commit
             .message_raw_bytes()
@@ -164,9 +232,40 @@ impl fmt::Debug for Topic {
     }
 }
 
-impl From<GenericArray<u8, U32>> for Topic {
-    fn from(a: GenericArray<u8, U32>) -> Self {
-        Self(a.into())
+/// The revision of the submitter's identity a note was signed under
+///
+/// Recorded as a commit trailer on topic notes, so the signature can be
+/// verified against the keys valid at the time, even if they have since been
+/// rotated.
+pub struct IdentityRevision(git2::Oid);
+
+impl IdentityRevision {
+    const TRAILER_PREFIX: &str = "Signed-under:";
+
+    pub fn new(hash: &ContentHash) -> Self {
+        Self(hash.as_oid())
+    }
+
+    pub fn from_commit(commit: &git2::Commit) -> crate::Result<Option<Self>> {
+        commit.message_raw_bytes().lines().try_find_map(|line| {
+            line?
+                .strip_prefix(Self::TRAILER_PREFIX)
+                .map(|v| {
+                    git2::Oid::from_str(v.trim())
+                        .map(Self)
+                        .map_err(crate::Error::from)
+                })
+                .transpose()
+        })
+    }
+
+    pub fn as_trailer(&self) -> String {
+        format!("{} {}", Self::TRAILER_PREFIX, self.0)
+    }
+
+    /// The blob id of the identity document
+    pub fn oid(&self) -> git2::Oid {
+        self.0
     }
 }
 
@@ -176,6 +275,18 @@ impl From<GenericArray<u8, U32>> for Topic {
 pub struct TrackingBranch(String);
 
 impl TrackingBranch {
+    /// Map the branch `r` to its tracking branch
+    ///
+    /// Fails if `r` is not a branch, or is the reserved name
+    /// [`REF_HEADS_PATCHES`].
+    pub fn for_branch(r: &Refname) -> crate::Result<Self> {
+        match r.strip_prefix("refs/heads/") {
+            None => bail!("not a branch: {r}"),
+            Some("patches") => bail!("reserved name: {r}"),
+            Some(suf) => Ok(Self([REF_IT_BRANCHES, suf].join("/"))),
+        }
+    }
+
     pub fn master() -> Self {
         Self([REF_IT_BRANCHES, "master"].join("/"))
     }
@@ -196,15 +307,3 @@ impl Deref for TrackingBranch {
         &self.0
     }
 }
-
-impl TryFrom<&Refname> for TrackingBranch {
-    type Error = crate::Error;
-
-    fn try_from(r: &Refname) -> Result<Self, Self::Error> {
-        match r.strip_prefix("refs/heads/") {
-            None => bail!("not a branch: {r}"),
-            Some("patches") => bail!("reserved name: {r}"),
-            Some(suf) => Ok(Self([REF_IT_BRANCHES, suf].join("/"))),
-        }
-    }
-}
@@ -0,0 +1,364 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Append-only log of drop operations
+//!
+//! If enabled (cf. [`crate::cfg::git::audit_log`]), the drop records an
+//! [`Entry`] for every submission it accepts or quarantines, every edit of the
+//! drop metadata, and every unbundling of records. Rejected submissions are
+//! only recorded if they were signed by an identity already known to the drop,
+//! so that anonymous submitters can not make the log grow without bound.
+//! Entries are commits on [`REF_IT_AUDIT`], each with a single parent, and
+//! signed by the drop's signer. Thus, the log can be published along with the
+//! drop, and removing or altering an entry is detectable by [`verify`].
+//!
+//! Entries are expected to be signed by a member of one of the drop's roles.
+//! In case the signer's keys have since been rotated, the revision of their
+//! identity at the time is recorded as an [`IdentityRevision`] trailer.
+
+use std::{
+    fmt,
+    path::PathBuf,
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+};
+use time::OffsetDateTime;
+
+use super::{
+    record::Heads,
+    state,
+    IdentityRevision,
+    Record,
+    Topic,
+    REF_IT_AUDIT,
+};
+use crate::{
+    bundle,
+    git::{
+        self,
+        if_not_found_none,
+        refs,
+    },
+    json,
+    keys::{
+        Signer,
+        VerificationKey,
+    },
+    metadata::{
+        self,
+        git::{
+            FromGit,
+            GitMeta,
+            META_FILE_ID,
+        },
+        identity,
+        ContentHash,
+        IdentityId,
+        KeyId,
+    },
+    Result,
+};
+
+const BLOB_ENTRY: &str = "entry.json";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// Position in the log, starting at 1
+    pub seq: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Entry {
+    pub fn from_commit(repo: &git2::Repository, commit: &git2::Commit) -> Result<Self> {
+        let blob = commit
+            .tree()?
+            .get_name(BLOB_ENTRY)
+            .ok_or_else(|| anyhow!("{BLOB_ENTRY} not found in {}", commit.id()))?
+            .to_object(repo)?
+            .into_blob()
+            .map_err(|_| anyhow!("{BLOB_ENTRY} is not a blob"))?;
+        json::from_blob(&blob)
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A submission was recorded in the drop history
+    Accept {
+        topic: Topic,
+        heads: Heads,
+        /// Content hash of the submitter's identity
+        signer: ContentHash,
+        bundle: bundle::Hash,
+    },
+    /// A submission by a first-time submitter was held for review
+    Quarantine {
+        topic: Topic,
+        heads: Heads,
+        /// Content hash of the submitter's identity
+        signer: ContentHash,
+    },
+    /// A submission was rejected, or discarded after review
+    Reject {
+        heads: Heads,
+        /// Content hash of the submitter's identity
+        signer: ContentHash,
+        reason: String,
+    },
+    /// The drop metadata was edited
+    Edit {
+        /// What was edited, eg. "mirrors"
+        what: String,
+        /// The commit on the drop history
+        #[serde(with = "git::serde::oid")]
+        commit: git2::Oid,
+    },
+    /// The refs of records were materialised
+    Unbundle {
+        /// The records unbundled, or `None` if the entire drop history was
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        records: Option<Vec<Heads>>,
+        /// Number of refs updated
+        refs: usize,
+    },
+}
+
+impl Event {
+    pub fn accept(record: &Record) -> Self {
+        Self::Accept {
+            topic: record.topic.clone(),
+            heads: record.heads,
+            signer: record.meta.signature.signer.clone(),
+            bundle: *record.bundle_hash(),
+        }
+    }
+
+    pub fn quarantine(record: &Record) -> Self {
+        Self::Quarantine {
+            topic: record.topic.clone(),
+            heads: record.heads,
+            signer: record.meta.signature.signer.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Accept { heads, topic, .. } => write!(f, "accept {heads} ({topic})"),
+            Self::Quarantine { heads, .. } => write!(f, "quarantine {heads}"),
+            Self::Reject { heads, .. } => write!(f, "reject {heads}"),
+            Self::Edit { what, .. } => write!(f, "edit {what}"),
+            Self::Unbundle { refs, .. } => write!(f, "unbundle {refs} refs"),
+        }
+    }
+}
+
+/// Append `event` to the audit log in `repo`
+///
+/// The entry is signed by `signer`, which is looked up among the role members
+/// of the drop at `drop_ref` in order to record the revision of its identity.
+pub fn append<S>(
+    repo: &git2::Repository,
+    signer: &mut S,
+    drop_ref: &str,
+    event: Event,
+) -> Result<git2::Oid>
+where
+    S: Signer + ?Sized,
+{
+    let mut tx = refs::Transaction::new(repo)?;
+    let audit_ref = tx.lock_ref(REF_IT_AUDIT.parse()?)?;
+    let parent = if_not_found_none(repo.find_reference(audit_ref.name()))?
+        .map(|r| r.peel_to_commit())
+        .transpose()?;
+    let seq = match &parent {
+        Some(parent) => Entry::from_commit(repo, parent)?.seq + 1,
+        None => 1,
+    };
+
+    let subject = format!("{seq}: {event}");
+    let entry = Entry {
+        seq,
+        time: OffsetDateTime::now_utc(),
+        event,
+    };
+    let tree = {
+        let mut tb = repo.treebuilder(None)?;
+        tb.insert(
+            BLOB_ENTRY,
+            json::to_blob(repo, &entry)?,
+            git2::FileMode::Blob.into(),
+        )?;
+        repo.find_tree(tb.write()?)?
+    };
+    let keyid = KeyId::from(signer.ident());
+    let msg = match signed_under(repo, drop_ref, &keyid)? {
+        Some(rev) => format!("{subject}\n\n{}", rev.as_trailer()),
+        None => subject,
+    };
+    let commit = git::commit_signed(
+        signer,
+        repo,
+        msg,
+        &tree,
+        parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+    )?;
+    audit_ref.set_target(commit, format!("it: audit entry {seq}"));
+    tx.commit()?;
+
+    Ok(commit)
+}
+
+/// The current revision of the role member of the drop at `drop_ref` holding
+/// `keyid`, if any
+///
+/// This is only a hint for [`verify`], so the drop metadata is not verified
+/// here.
+fn signed_under(
+    repo: &git2::Repository,
+    drop_ref: &str,
+    keyid: &KeyId,
+) -> Result<Option<IdentityRevision>> {
+    let root = match if_not_found_none(repo.find_reference(drop_ref))? {
+        Some(r) => r.peel_to_tree()?,
+        None => return Ok(None),
+    };
+    let ids = match root.get_name("ids") {
+        Some(entry) => entry.to_object(repo)?.peel_to_tree()?,
+        None => return Ok(None),
+    };
+    let meta = metadata::Drop::from_tree(repo, &root)?;
+    for id in meta.signed.signed.roles.ids() {
+        let path = PathBuf::from(id.to_string()).join(META_FILE_ID);
+        if let Some(entry) = if_not_found_none(ids.get_path(&path))? {
+            let GitMeta { hash, signed } =
+                metadata::Identity::from_blob(&repo.find_blob(entry.id())?)?;
+            if signed.signed.keys.contains_key(keyid) {
+                return Ok(Some(IdentityRevision::new(&hash)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// An [`Entry`] read from the log by [`verify`]
+#[derive(serde::Serialize)]
+pub struct Logged {
+    #[serde(with = "git::serde::oid")]
+    pub commit: git2::Oid,
+    /// The role member who signed the entry, unless the signature could not be
+    /// verified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<IdentityId>,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+#[derive(Default)]
+pub struct Verification {
+    /// Problems found with the log
+    pub problems: Vec<String>,
+    /// The entries, oldest first
+    pub entries: Vec<Logged>,
+}
+
+/// Read and verify the audit log in `repo`
+///
+/// Every entry must be signed by a member of one of the roles of the drop at
+/// `drop_ref`, or by a past revision of the identity of such a member. The
+/// entries must form a linear history, numbered without gaps.
+pub fn verify(repo: &git2::Repository, drop_ref: &str) -> Result<Verification> {
+    let tip = match if_not_found_none(repo.refname_to_id(REF_IT_AUDIT))? {
+        Some(tip) => tip,
+        None => return Ok(Verification::default()),
+    };
+    let drop = state::DropHead::from_refname(repo, drop_ref)?;
+    let members = drop
+        .meta
+        .roles
+        .ids()
+        .iter()
+        .map(|id| identity::find_in_tree(repo, &drop.ids, id))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut problems = Vec::new();
+    let mut commits = Vec::new();
+    let mut next = Some(repo.find_commit(tip)?);
+    while let Some(commit) = next {
+        if commit.parent_count() > 1 {
+            problems.push(format!("{}: unexpected merge commit", commit.id()));
+        }
+        next = commit.parents().next();
+        commits.push(commit);
+    }
+
+    let mut entries = Vec::with_capacity(commits.len());
+    let mut expect_seq = 1;
+    for commit in commits.iter().rev() {
+        let signed_by = match signed_by(repo, &members, commit) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                problems.push(format!("{}: {e:#}", commit.id()));
+                None
+            },
+        };
+        let entry = match Entry::from_commit(repo, commit) {
+            Ok(entry) => entry,
+            Err(e) => {
+                problems.push(format!("{}: unreadable entry: {e:#}", commit.id()));
+                expect_seq += 1;
+                continue;
+            },
+        };
+        if entry.seq != expect_seq {
+            problems.push(format!(
+                "entry {}: expected sequence number {expect_seq}",
+                entry.seq
+            ));
+        }
+        expect_seq = entry.seq + 1;
+        entries.push(Logged {
+            commit: commit.id(),
+            signed_by,
+            entry,
+        });
+    }
+
+    Ok(Verification { problems, entries })
+}
+
+fn signed_by(
+    repo: &git2::Repository,
+    members: &[identity::Verified],
+    commit: &git2::Commit,
+) -> Result<IdentityId> {
+    fn has_key(id: &identity::Verified, keyid: &KeyId) -> bool {
+        id.identity().keys.contains_key(keyid) || id.bot_key(keyid).is_some()
+    }
+
+    let pk = git::verify_commit_signature(repo, &commit.id())?;
+    let keyid = VerificationKey::from(pk).keyid();
+    if let Some(member) = members.iter().find(|id| has_key(id, &keyid)) {
+        return Ok(*member.id());
+    }
+    if let Some(rev) = IdentityRevision::from_commit(commit)? {
+        for member in members {
+            if let Ok(past) = state::identity_at(repo, member, &rev) {
+                if has_key(&past, &keyid) {
+                    return Ok(*member.id());
+                }
+            }
+        }
+    }
+
+    bail!("signed by {keyid}, which is not a key of any member of the drop roles")
+}
@@ -0,0 +1,165 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Automated replies to first-time submitters
+//!
+//! A drop may carry a template in its tree, which is rendered and appended to
+//! the topic of a patch accepted from an identity not previously known to the
+//! drop.
+//!
+//! The reply is submitted to the drop on behalf of the drop itself, and
+//! recorded like any other note on the topic.
+//!
+//! The template is plain text, where the placeholders `{topic}`,
+//! `{submitter}` and `{patch}` are substituted with the respective values of
+//! the accepted patch.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use log::debug;
+
+use super::{
+    notes,
+    to_tree,
+    Bundle,
+    DropHead,
+    IdentityRevision,
+    Record,
+    Signature,
+    Submission,
+};
+use crate::{
+    bundle,
+    git,
+    keys::Signer,
+    metadata::{
+        git::META_FILE_ID,
+        identity,
+        ContentHash,
+        IdentityId,
+        KeyId,
+    },
+    Result,
+};
+
+/// Name of the template file in the drop tree
+pub const FILE_AUTO_REPLY: &str = "auto-reply.txt";
+
+const TRAILER_AUTOMATED: &str = "Automated: yes";
+const FOOTER: &str = "This is an automated message sent by the drop.";
+
+pub struct AutoReply {
+    template: String,
+}
+
+impl AutoReply {
+    /// Load the template from the tree of the drop tip `drop_ref`
+    pub fn from_drop(repo: &git2::Repository, drop_ref: &str) -> Result<Option<Self>> {
+        let tree = repo.find_reference(drop_ref)?.peel_to_tree()?;
+        let entry = match tree.get_name(FILE_AUTO_REPLY) {
+            None => return Ok(None),
+            Some(entry) => entry,
+        };
+        let blob = repo.find_blob(entry.id())?;
+        let template = std::str::from_utf8(blob.content())?.trim().to_owned();
+
+        Ok((!template.is_empty()).then_some(Self { template }))
+    }
+
+    pub fn render(&self, record: &Record, submitter: &IdentityId) -> String {
+        let body = self
+            .template
+            .replace("{topic}", &record.topic.to_string())
+            .replace("{submitter}", &submitter.to_string())
+            .replace("{patch}", &record.heads.to_string());
+        format!("{body}\n\n-- \n{FOOTER}")
+    }
+
+    /// Prepare the rendered reply to `record` as a [`Submission`]
+    ///
+    /// The reply is a note on the topic of `record`, following up on the note
+    /// of the patch, and marked as automated by a trailer. It is signed by
+    /// `signer`, which must be a key of one of the identities holding the
+    /// snapshot role of `drop`, and conveyed in a bundle stored in
+    /// `bundle_dir`.
+    ///
+    /// The submission is to be accepted like any other, so the reply becomes a
+    /// record of the drop history.
+    pub fn submission<S>(
+        &self,
+        repo: &git2::Repository,
+        signer: &mut S,
+        drop: &DropHead,
+        bundle_dir: &Path,
+        record: &Record,
+        submitter: &IdentityId,
+    ) -> Result<Submission>
+    where
+        S: Signer,
+    {
+        let ids = &drop.ids;
+        let (id, signer_hash) = {
+            let keyid = KeyId::from(signer.ident());
+            let mut found = None;
+            for id in &drop.meta.roles.snapshot.ids {
+                let verified = identity::find_in_tree(repo, ids, id)?;
+                if verified.identity().keys.contains_key(&keyid) {
+                    let blob = ids
+                        .get_path(&Path::new(&id.to_string()).join(META_FILE_ID))?
+                        .to_object(repo)?
+                        .peel_to_blob()?;
+                    found = Some((*id, ContentHash::from(&blob)));
+                    break;
+                }
+            }
+            found.ok_or_else(|| {
+                anyhow!("signing key {keyid} is not a key of any snapshot identity of the drop")
+            })?
+        };
+
+        let topic_ref = record.topic.as_refname();
+        let parent = record
+            .meta
+            .bundle
+            .references
+            .get(&topic_ref)
+            .ok_or_else(|| anyhow!("record does not contain a note on {}", record.topic))
+            .and_then(|oid| Ok(repo.find_commit(oid.try_into()?)?))?;
+
+        let note = notes::Simple::new(self.render(record, submitter));
+        let tree = {
+            let mut tb = repo.treebuilder(None)?;
+            to_tree(repo, &mut tb, &note)?;
+            repo.find_tree(tb.write()?)?
+        };
+        let trailers = format!(
+            "{}\n{}\n{}",
+            record.topic.as_trailer(),
+            IdentityRevision::new(&signer_hash).as_trailer(),
+            TRAILER_AUTOMATED
+        );
+        let msg = match note.subject() {
+            Some(s) => format!("{s}\n\n{trailers}"),
+            None => trailers,
+        };
+        let commit = git::commit_signed(signer, repo, msg, &tree, &[&parent])?;
+        debug!("created automated reply {commit} by {id}");
+
+        let mut header = bundle::Header::default();
+        header.add_prerequisite(&parent.id());
+        header.add_reference(topic_ref, &commit);
+        let bundle = Bundle::create(
+            bundle_dir,
+            repo,
+            header,
+            record.meta.bundle.info.hash.algorithm(),
+        )?;
+        let signature = bundle.sign(signer).map(|signature| Signature {
+            signer: signer_hash,
+            signature: signature.into(),
+        })?;
+
+        Ok(Submission { signature, bundle })
+    }
+}
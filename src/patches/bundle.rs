@@ -2,7 +2,12 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
-    fs::File,
+    cell::Cell,
+    collections::HashSet,
+    fs::{
+        self,
+        File,
+    },
     io::{
         self,
         Read,
@@ -14,9 +19,15 @@ use std::{
         Path,
         PathBuf,
     },
+    process::{
+        Command,
+        Stdio,
+    },
+    rc::Rc,
 };
 
 use anyhow::{
+    anyhow,
     bail,
     ensure,
     Context,
@@ -24,7 +35,10 @@ use anyhow::{
 use digest::Digest;
 use multipart::client::lazy::Multipart;
 use sha2::Sha256;
-use tempfile::NamedTempFile;
+use tempfile::{
+    NamedTempFile,
+    TempPath,
+};
 use url::Url;
 
 use super::record::{
@@ -33,21 +47,36 @@ use super::record::{
 };
 use crate::{
     bundle,
-    io::HashWriter,
+    io::{
+        HashWriter,
+        LenWriter,
+    },
     keys::Signature,
+    net,
     Result,
 };
 
+/// The program to encrypt bundles with, see [`Bundle::encrypt_age`]
+const AGE_PROGRAM: &str = "age";
+
 pub struct Bundle {
     pub(super) header: bundle::Header,
     pub(super) path: PathBuf,
     pub(super) info: bundle::Info,
     pub(super) encryption: Option<Encryption>,
     pack_start: u64,
+    /// If the bundle is stored in chunks, a temporary copy of the reassembled
+    /// bundle
+    restored: Option<TempPath>,
 }
 
 impl Bundle {
-    pub fn create<P>(bundle_dir: P, repo: &git2::Repository, header: bundle::Header) -> Result<Self>
+    pub fn create<P>(
+        bundle_dir: P,
+        repo: &git2::Repository,
+        header: bundle::Header,
+        alg: bundle::HashAlgorithm,
+    ) -> Result<Self>
     where
         P: AsRef<Path>,
     {
@@ -55,7 +84,7 @@ impl Bundle {
         std::fs::create_dir_all(bundle_dir)?;
 
         let mut tmp = NamedTempFile::new_in(bundle_dir)?;
-        let info = bundle::create(&mut tmp, repo, &header)?;
+        let info = bundle::create(&mut tmp, repo, &header, alg)?;
         let path = bundle_dir
             .join(info.hash.to_string())
             .with_extension(bundle::FILE_EXTENSION);
@@ -70,6 +99,7 @@ impl Bundle {
             info,
             encryption: None,
             pack_start,
+            restored: None,
         })
     }
 
@@ -85,6 +115,7 @@ impl Bundle {
             info,
             encryption,
             pack_start,
+            restored: None,
         })
     }
 
@@ -97,16 +128,24 @@ impl Bundle {
             .as_ref()
             .join(expect.hash.to_string())
             .with_extension(bundle::FILE_EXTENSION);
+        let restored = if bundle::chunks::is_chunked(&path) {
+            let mut tmp = NamedTempFile::new_in(bundle_dir.as_ref())?;
+            bundle::chunks::restore(&path, &mut tmp)?;
+            Some(tmp.into_temp_path())
+        } else {
+            None
+        };
+        let data = restored.as_deref().unwrap_or(&path);
 
-        let (header, mut pack) = split(&path)?;
+        let (header, mut pack) = split(data)?;
         let pack_start = pack.offset;
         let encryption = pack.encryption()?;
         drop(pack);
-        let mut file = File::open(&path)?;
+        let mut file = File::open(data)?;
         let mut hasher = blake3::Hasher::new();
 
         let len = io::copy(&mut file, &mut hasher)?;
-        let hash = header.hash();
+        let hash = header.hash(expect.hash.algorithm());
         ensure!(expect.hash == &hash, "header hash mismatch");
         let checksum = bundle::Checksum::from(&hasher);
         if let Some(expect) = expect.checksum {
@@ -126,25 +165,29 @@ impl Bundle {
             info,
             encryption,
             pack_start,
+            restored,
         })
     }
 
-    pub fn copy<R, P>(mut from: R, to: P) -> Result<Self>
+    pub fn copy<R, P>(mut from: R, to: P, alg: bundle::HashAlgorithm) -> Result<Self>
     where
         R: Read,
         P: AsRef<Path>,
     {
         std::fs::create_dir_all(&to)?;
         let mut tmp = NamedTempFile::new_in(&to)?;
-        let mut out = HashWriter::new(blake3::Hasher::new(), &mut tmp);
+        let mut out = bundle::ParseWriter::new(HashWriter::new(blake3::Hasher::new(), &mut tmp));
 
         let len = io::copy(&mut from, &mut out)?;
-        let checksum = bundle::Checksum::from(out.hasher());
+        let checksum = bundle::Checksum::from(out.get_ref().hasher());
 
-        let (header, mut pack) = split(tmp.path())?;
-        let hash = header.hash();
-        let pack_start = pack.offset;
-        let encryption = pack.encryption()?;
+        let (header, pack_start) = out.finish()?;
+        let hash = header.hash(alg);
+        let encryption = Packdata {
+            offset: pack_start,
+            bundle: tmp.reopen()?,
+        }
+        .encryption()?;
 
         let info = bundle::Info {
             len,
@@ -165,6 +208,7 @@ impl Bundle {
             info,
             encryption,
             pack_start,
+            restored: None,
         })
     }
 
@@ -172,12 +216,156 @@ impl Bundle {
         self.encryption
     }
 
+    /// Encrypt the packdata to the given age `recipients`
+    ///
+    /// The bundle header is left in the clear, so the bundle can be routed
+    /// (and its hash verified) without decrypting it. The packdata is piped
+    /// through the `age` program, which must be installed. Recipients are
+    /// anything `age -r` accepts, ie. `age1...` or SSH public keys.
+    pub fn encrypt_age<S: AsRef<str>>(&mut self, recipients: &[S]) -> Result<()> {
+        ensure!(!recipients.is_empty(), "no recipients to encrypt to");
+        ensure!(!self.is_encrypted(), "bundle is already encrypted");
+
+        let mut plain = File::open(self.data_path())?;
+        let mut header = vec![0; self.pack_start as usize];
+        plain.read_exact(&mut header)?;
+
+        let mut age = Command::new(AGE_PROGRAM);
+        for r in recipients {
+            age.arg("-r").arg(r.as_ref());
+        }
+        let mut child = age
+            .stdin(Stdio::from(plain))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("failed to run {AGE_PROGRAM}"))?;
+
+        let dir = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("bundle path has no parent directory"))?;
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        let (len, checksum) = {
+            let mut hasher = HashWriter::new(blake3::Hasher::new(), &mut tmp);
+            let mut out = LenWriter::new(&mut hasher);
+            io::Write::write_all(&mut out, &header)?;
+            io::copy(child.stdout.as_mut().unwrap(), &mut out)?;
+            let len = out.bytes_written();
+            (len, bundle::Checksum::from(hasher.hasher()))
+        };
+        let status = child.wait()?;
+        ensure!(status.success(), "{AGE_PROGRAM} exited with {status}");
+        tmp.persist(&self.path)?;
+        self.restored = None;
+
+        self.info.len = len;
+        self.info.checksum = checksum;
+        self.encryption = Some(Encryption::Age);
+        ensure!(
+            Packdata {
+                offset: self.pack_start,
+                bundle: File::open(&self.path)?,
+            }
+            .encryption()?
+                == self.encryption,
+            "{AGE_PROGRAM} produced unexpected output"
+        );
+
+        Ok(())
+    }
+
+    /// Decrypt the packdata
+    ///
+    /// The plaintext is written to an anonymous temporary file, the bundle
+    /// itself is left as is. Age-encrypted packdata is piped through `age -d`
+    /// using the given `identities` (files as accepted by `age -i`), OpenPGP
+    /// encrypted packdata through `gpg_program --decrypt`, which finds the
+    /// secret key by itself.
+    ///
+    /// Fails if the bundle is not encrypted, or the plaintext is not a
+    /// packfile.
+    pub fn decrypt<P: AsRef<Path>>(&self, gpg_program: &str, identities: &[P]) -> Result<Packdata> {
+        let mut cmd = match self.encryption {
+            None => bail!("bundle is not encrypted"),
+            Some(Encryption::Age) => {
+                ensure!(
+                    !identities.is_empty(),
+                    "decrypting age-encrypted bundles requires an identity file"
+                );
+                let mut age = Command::new(AGE_PROGRAM);
+                age.arg("-d");
+                for i in identities {
+                    age.arg("-i").arg(i.as_ref());
+                }
+                age
+            },
+            Some(Encryption::Gpg) => {
+                let mut gpg = Command::new(gpg_program);
+                gpg.args(["--quiet", "--decrypt"]);
+                gpg
+            },
+        };
+        let prog = cmd.get_program().to_string_lossy().into_owned();
+
+        let mut cipher = File::open(self.data_path())?;
+        cipher.seek(SeekFrom::Start(self.pack_start))?;
+        let mut child = cmd
+            .stdin(Stdio::from(cipher))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("failed to run {prog}"))?;
+        let mut plain = tempfile::tempfile()?;
+        io::copy(child.stdout.as_mut().unwrap(), &mut plain)?;
+        let status = child.wait()?;
+        ensure!(status.success(), "{prog} exited with {status}");
+
+        let mut pack = Packdata {
+            offset: 0,
+            bundle: plain,
+        };
+        ensure!(
+            matches!(pack.encryption(), Ok(None)),
+            "decrypted packdata is not a packfile"
+        );
+
+        Ok(pack)
+    }
+
+    /// Path of the file holding the bundle data
+    ///
+    /// This is a temporary file if the bundle is stored in chunks, cf.
+    /// [`bundle::chunks`].
+    pub(super) fn data_path(&self) -> &Path {
+        self.restored.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Store the bundle in chunks, deduplicating them against the chunks
+    /// already stored
+    ///
+    /// The bundle file is replaced by a [`bundle::chunks::Manifest`], but
+    /// kept as a temporary file for the lifetime of `self`.
+    pub fn dehydrate(&mut self) -> Result<bundle::chunks::Stats> {
+        ensure!(self.restored.is_none(), "bundle is already chunked");
+        let stats = bundle::chunks::store(&self.path, File::open(&self.path)?)?;
+        let dir = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("bundle path has no parent directory"))?;
+        let tmp = NamedTempFile::new_in(dir)?.into_temp_path();
+        fs::rename(&self.path, &tmp)?;
+        self.restored = Some(tmp);
+
+        Ok(stats)
+    }
+
     pub fn is_encrypted(&self) -> bool {
         self.encryption.is_some()
     }
 
     pub fn reader(&self) -> Result<impl io::Read> {
-        Ok(File::open(&self.path)?)
+        Ok(File::open(self.data_path())?)
     }
 
     pub fn header(&self) -> &bundle::Header {
@@ -188,8 +376,27 @@ impl Bundle {
         &self.info
     }
 
+    /// Measure the length of the bundle file, and normalise [`bundle::Info`]
+    /// to it
+    ///
+    /// Fails if the recorded length differs from the measured one, which
+    /// indicates that the bundle was truncated or padded after its info was
+    /// computed.
+    pub fn verify_len(&mut self) -> Result<()> {
+        let len = std::fs::metadata(self.data_path())?.len();
+        ensure!(
+            len == self.info.len,
+            "bundle length mismatch: recorded {}, actual {len} (truncated or padded?)",
+            self.info.len
+        );
+        ensure!(len > self.pack_start, "bundle contains no packdata");
+        self.info.len = len;
+
+        Ok(())
+    }
+
     pub fn packdata(&self) -> Result<Packdata> {
-        let bundle = File::open(&self.path)?;
+        let bundle = File::open(self.data_path())?;
         Ok(Packdata {
             offset: self.pack_start,
             bundle,
@@ -245,7 +452,8 @@ impl Bundle {
     where
         S: crate::keys::Signer,
     {
-        Ok(signer.sign(record::Heads::from(&self.header).as_slice())?)
+        let heads = record::Heads::from_header(&self.header, self.info.hash.algorithm());
+        Ok(signer.sign(&heads.to_bytes())?)
     }
 
     pub fn ipfs_add(&mut self, via: &Url) -> Result<Url> {
@@ -257,7 +465,7 @@ impl Bundle {
             // .append_pair("to-files", &name)
             .append_pair("quiet", "true");
         let mpart = Multipart::new()
-            .add_file(name, self.path.as_path())
+            .add_file(name, self.data_path())
             .prepare()?;
 
         #[derive(serde::Deserialize)]
@@ -266,7 +474,7 @@ impl Bundle {
             cid: String,
         }
 
-        let Response { cid } = ureq::post(api.as_str())
+        let Response { cid } = net::request("POST", &api)?
             .set(
                 "Content-Length",
                 &mpart
@@ -309,15 +517,101 @@ pub struct Packdata {
     bundle: File,
 }
 
+/// Statistics gathered while indexing [`Packdata`]
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+pub struct IndexStats {
+    /// Number of objects in the pack
+    pub objects: usize,
+    /// Number of objects stored as deltas
+    pub deltas: usize,
+    /// Number of delta bases which were not in the pack, but found locally
+    pub local_objects: usize,
+}
+
 impl Packdata {
     pub fn index(&mut self, odb: &git2::Odb) -> Result<()> {
+        self.index_with_stats(odb).map(|_| ())
+    }
+
+    pub fn index_with_stats(&mut self, odb: &git2::Odb) -> Result<IndexStats> {
         self.bundle.seek(SeekFrom::Start(self.offset))?;
 
+        let stats = Rc::new(Cell::new(IndexStats::default()));
         let mut pw = odb.packwriter()?;
+        {
+            let stats = Rc::clone(&stats);
+            pw.progress(move |p| {
+                stats.set(IndexStats {
+                    objects: p.total_objects(),
+                    deltas: p.total_deltas(),
+                    local_objects: p.local_objects(),
+                });
+                true
+            });
+        }
         io::copy(&mut self.bundle, &mut pw)?;
         pw.commit()?;
 
-        Ok(())
+        Ok(stats.get())
+    }
+
+    /// Walk the pack without indexing it, see [`bundle::pack::scan`]
+    pub fn scan<F>(&mut self, f: F) -> Result<u32>
+    where
+        F: FnMut(&bundle::pack::Object) -> Result<()>,
+    {
+        self.bundle.seek(SeekFrom::Start(self.offset))?;
+        bundle::pack::scan(io::BufReader::new(&mut self.bundle), f)
+    }
+
+    /// Index the pack into `repo`, returning the ids of the objects contained
+    /// in it
+    ///
+    /// Equivalent to [`Packdata::quarantine`] followed by
+    /// [`Quarantine::migrate`].
+    pub fn index_objects(&mut self, repo: &git2::Repository) -> Result<HashSet<git2::Oid>> {
+        self.quarantine(repo)?.migrate(repo)
+    }
+
+    /// Index the pack into a scratch object directory within `repo`
+    ///
+    /// The scratch directory has `repo` as an alternate to resolve delta bases.
+    /// Since it holds no other packs, the objects listed in the resulting pack
+    /// index are exactly the ones introduced by this pack, including any delta
+    /// bases appended to complete a thin pack.
+    ///
+    /// The objects are not visible in `repo` until [`Quarantine::migrate`] is
+    /// called, and are discarded if the [`Quarantine`] is dropped.
+    pub fn quarantine(&mut self, repo: &git2::Repository) -> Result<Quarantine> {
+        let objects = repo.path().join("objects");
+        let tmp = tempfile::Builder::new()
+            .prefix("tmp_objdir-incoming-")
+            .tempdir_in(&objects)?;
+        let scratch = git2::Repository::init_bare(tmp.path())?;
+        scratch.odb()?.add_disk_alternate(
+            objects
+                .to_str()
+                .ok_or_else(|| anyhow!("objects directory is not valid UTF-8"))?,
+        )?;
+        self.index(&scratch.odb()?)?;
+
+        let pack_dir = tmp.path().join("objects").join("pack");
+        let files = fs::read_dir(&pack_dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut oids = HashSet::new();
+        for path in &files {
+            if path.extension().map_or(false, |ext| ext == "idx") {
+                oids.extend(read_idx(path)?);
+            }
+        }
+
+        Ok(Quarantine {
+            _tmp: tmp,
+            scratch,
+            files,
+            objects: oids,
+        })
     }
 
     pub fn encryption(&mut self) -> Result<Option<Encryption>> {
@@ -340,3 +634,66 @@ impl Packdata {
         }
     }
 }
+
+/// Objects indexed by [`Packdata::quarantine`]
+pub struct Quarantine {
+    _tmp: tempfile::TempDir,
+    scratch: git2::Repository,
+    files: Vec<PathBuf>,
+    objects: HashSet<git2::Oid>,
+}
+
+impl Quarantine {
+    /// A repository in which both the quarantined objects and the objects of
+    /// the repository they were quarantined from are visible
+    pub fn repo(&self) -> &git2::Repository {
+        &self.scratch
+    }
+
+    /// The ids of the quarantined objects
+    pub fn objects(&self) -> &HashSet<git2::Oid> {
+        &self.objects
+    }
+
+    /// Move the quarantined objects into `repo`
+    pub fn migrate(mut self, repo: &git2::Repository) -> Result<HashSet<git2::Oid>> {
+        // Move the index last, so the pack is never visible without its data
+        self.files
+            .sort_by_key(|path| path.extension().map_or(false, |ext| ext == "idx"));
+        let dest = repo.path().join("objects").join("pack");
+        for path in &self.files {
+            if let Some(name) = path.file_name() {
+                fs::rename(path, dest.join(name))?;
+            }
+        }
+        repo.odb()?.refresh()?;
+
+        Ok(self.objects)
+    }
+}
+
+/// Read the object ids listed in a version 2 pack index
+fn read_idx(path: &Path) -> Result<Vec<git2::Oid>> {
+    const MAGIC: &[u8] = b"\xfftOc";
+    const HEADER_LEN: usize = 8;
+    const FANOUT_LEN: usize = 256 * 4;
+    const OID_LEN: usize = 20;
+
+    let idx = fs::read(path)?;
+    ensure!(
+        idx.len() >= HEADER_LEN + FANOUT_LEN && idx.starts_with(MAGIC) && idx[4..8] == [0, 0, 0, 2],
+        "unsupported pack index format: {}",
+        path.display()
+    );
+    let mut count = [0; 4];
+    count.copy_from_slice(&idx[HEADER_LEN + FANOUT_LEN - 4..HEADER_LEN + FANOUT_LEN]);
+    let count = u32::from_be_bytes(count) as usize;
+    let names = idx
+        .get(HEADER_LEN + FANOUT_LEN..HEADER_LEN + FANOUT_LEN + count * OID_LEN)
+        .ok_or_else(|| anyhow!("truncated pack index: {}", path.display()))?;
+
+    names
+        .chunks(OID_LEN)
+        .map(|name| git2::Oid::from_bytes(name).map_err(Into::into))
+        .collect()
+}
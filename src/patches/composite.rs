@@ -0,0 +1,540 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Patches spanning several drops
+//!
+//! Projects split across several repositories (each with its own drop)
+//! sometimes need a single logical change to land in all of them. A composite
+//! patch consists of one patch bundle per drop, posted to the same topic. The
+//! cover letter of every part carries the [`Manifest`] of the composite patch,
+//! which names each part by the drop it is destined for, along with the head of
+//! the branch it proposes.
+//!
+//! The bundles are collected in a [`Container`], which is a bundle list in
+//! mode `all` referring to the bundle of each part, extended by the
+//! submitter's signature over it.
+//!
+//! Drops configured with their siblings (`it.<NAME>.siblingDrop`) accept
+//! composite patches all or none, cf. [`accept`].
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{
+        self,
+        Read,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+    ensure,
+    Context as _,
+};
+use log::error;
+use url::Url;
+
+use super::{
+    record::Signature,
+    AcceptArgs,
+    Bundle,
+    Record,
+    Submission,
+    Topic,
+    MAX_LEN_BUNDLE,
+    MAX_LEN_INFO,
+};
+use crate::{
+    bundle,
+    git,
+    keys::Signer,
+    metadata::KeyId,
+    net,
+    Result,
+};
+
+/// Maximum number of parts of a composite patch
+pub const MAX_PARTS: usize = 16;
+
+/// Check that `name` is usable as the name of a part
+///
+/// Names are used as git config subsections and bundle ids, and so are
+/// restricted to ASCII alphanumerics, '-', '_', and '.'.
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')),
+        "invalid part name '{name}'"
+    );
+
+    Ok(())
+}
+
+/// The parts of a composite patch, by name, along with the head of the branch
+/// each proposes
+///
+/// Conveyed as commit trailers on the topic note of every part.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest(BTreeMap<String, git2::Oid>);
+
+impl Manifest {
+    const TRAILER_PREFIX: &str = "Composite-Part:";
+
+    pub fn insert(&mut self, name: String, head: git2::Oid) -> Result<()> {
+        validate_name(&name)?;
+        ensure!(
+            self.0.insert(name.clone(), head).is_none(),
+            "duplicate part '{name}'"
+        );
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&git2::Oid> {
+        self.0.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// The manifest conveyed by `commit`, if any
+    pub fn from_commit(commit: &git2::Commit) -> Result<Option<Self>> {
+        let mut this = Self::default();
+        for line in io::BufRead::lines(commit.message_raw_bytes()) {
+            let line = line?;
+            if let Some(value) = line.strip_prefix(Self::TRAILER_PREFIX) {
+                let (name, head) = value
+                    .trim()
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("malformed trailer: {line}"))?;
+                this.insert(name.to_owned(), head.trim().parse()?)?;
+            }
+        }
+
+        Ok((!this.is_empty()).then_some(this))
+    }
+
+    /// The topic to post the parts of a new composite patch to
+    pub fn derive_topic(&self, keyid: &KeyId) -> Topic {
+        Topic::hashed(format!("{}\n{keyid}", self.as_trailers()))
+    }
+
+    pub fn as_trailers(&self) -> String {
+        self.0
+            .iter()
+            .map(|(name, head)| format!("{} {name} {head}", Self::TRAILER_PREFIX))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A bundle list referring to the bundles of the parts of a composite patch
+pub struct Container {
+    pub parts: Vec<Part>,
+}
+
+pub struct Part {
+    /// Name of the part, which is also the id of its bundle list entry
+    pub name: String,
+    pub hash: bundle::Hash,
+    pub len: u64,
+    pub signature: Signature,
+    /// Location of the bundle
+    ///
+    /// For containers read from a file, relative URIs are resolved against
+    /// the directory of the file.
+    pub path: PathBuf,
+}
+
+impl Container {
+    /// Write the container as a bundle list to `path`
+    ///
+    /// The URIs of bundles residing in the same directory as `path` are
+    /// relative, all others are absolute 'file://' URLs.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let mut cfg = git2::Config::open(path)?;
+        self.write_config(&mut cfg, |part| {
+            let bundle = &part.path;
+            if bundle.parent() == Some(base) {
+                let name = bundle
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| anyhow!("invalid bundle path {}", bundle.display()))?;
+                Ok(bundle::Uri::Relative(name.to_owned()))
+            } else {
+                let abs = fs::canonicalize(bundle)?;
+                Url::from_file_path(&abs)
+                    .map(bundle::Uri::Absolute)
+                    .map_err(|()| anyhow!("invalid bundle path {}", abs.display()))
+            }
+        })
+    }
+
+    /// Read a container previously [`Container::write`]n to `path`
+    pub fn read(path: &Path) -> Result<Self> {
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        Self::read_config(path, |uri| match uri {
+            bundle::Uri::Relative(rel) => Ok(base.join(rel)),
+            bundle::Uri::Absolute(url) if url.scheme() == "file" => url
+                .to_file_path()
+                .map_err(|()| anyhow!("invalid file URL {url}")),
+            bundle::Uri::Absolute(url) => bail!("only local bundles are supported, not {url}"),
+        })
+    }
+
+    /// Serialise the container for submission over HTTP
+    ///
+    /// All bundle URIs are relative, naming the bundle by its hash.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let tmp = tempfile::NamedTempFile::new()?;
+        let mut cfg = git2::Config::open(tmp.path())?;
+        self.write_config(&mut cfg, |part| {
+            Ok(bundle::Uri::Relative(format!(
+                "{}.{}",
+                part.hash,
+                bundle::FILE_EXTENSION
+            )))
+        })?;
+        drop(cfg);
+
+        Ok(fs::read(tmp.path())?)
+    }
+
+    /// Parse a container serialised by [`Container::to_bytes`]
+    ///
+    /// The [`Part::path`]s are the bundle file names.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let tmp = tempfile::NamedTempFile::new()?;
+        fs::write(tmp.path(), bytes)?;
+        Self::read_config(tmp.path(), |uri| match uri {
+            bundle::Uri::Relative(rel) => Ok(PathBuf::from(rel)),
+            bundle::Uri::Absolute(url) => bail!("unexpected absolute URI {url}"),
+        })
+    }
+
+    fn write_config<F>(&self, cfg: &mut git2::Config, mut uri: F) -> Result<()>
+    where
+        F: FnMut(&Part) -> Result<bundle::Uri>,
+    {
+        let mut list = bundle::List {
+            mode: bundle::list::Mode::All,
+            heuristic: None,
+            bundles: Vec::with_capacity(self.parts.len()),
+        };
+        for part in &self.parts {
+            validate_name(&part.name)?;
+            list.bundles
+                .push(bundle::Location::new(part.name.clone(), uri(part)?));
+        }
+        list.to_config(cfg)?;
+        for part in &self.parts {
+            let section = format!("bundle.{}", part.name);
+            cfg.set_str(&format!("{section}.length"), &part.len.to_string())?;
+            cfg.set_str(
+                &format!("{section}.signature"),
+                &part.signature.to_header_value(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn read_config<F>(file: &Path, mut path: F) -> Result<Self>
+    where
+        F: FnMut(&bundle::Uri) -> Result<PathBuf>,
+    {
+        let cfg = git::config::Snapshot::try_from(git2::Config::open(file)?)?;
+        let list =
+            bundle::List::from_config(git::config::Snapshot::try_from(git2::Config::open(file)?)?)?;
+        ensure!(
+            matches!(list.mode, bundle::list::Mode::All),
+            "bundle list of a composite patch must be in mode 'all'"
+        );
+        ensure!(!list.bundles.is_empty(), "composite patch has no parts");
+        ensure!(
+            list.bundles.len() <= MAX_PARTS,
+            "composite patch has more than {MAX_PARTS} parts"
+        );
+
+        let mut parts = Vec::with_capacity(list.bundles.len());
+        for loc in list.bundles {
+            validate_name(&loc.id)?;
+            let section = format!("bundle.{}", loc.id);
+            let path = path(&loc.uri)?;
+            let hash = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(bundle::DOT_FILE_EXTENSION))
+                .ok_or_else(|| anyhow!("{}: not a bundle file", loc.uri.as_str()))?
+                .parse()?;
+            let len = cfg
+                .get_str(&format!("{section}.length"))?
+                .parse()
+                .with_context(|| format!("{section}.length"))?;
+            let signature =
+                Signature::from_header_value(cfg.get_str(&format!("{section}.signature"))?)?;
+            parts.push(Part {
+                name: loc.id,
+                hash,
+                len,
+                signature,
+                path,
+            });
+        }
+        parts.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(dup) = parts.windows(2).find(|w| w[0].name == w[1].name) {
+            bail!("duplicate part '{}'", dup[0].name);
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+/// Read the body of a composite submission over HTTP
+///
+/// The body is the serialised [`Container`], prefixed by its length as a
+/// big-endian `u32`, followed by the bundles of the parts in the order they
+/// appear in the container. The bundle of each part is copied into the
+/// directory `bundle_dir` yields for its name.
+pub fn read_body<R, F>(mut body: R, mut bundle_dir: F) -> Result<Vec<(String, Submission)>>
+where
+    R: Read,
+    F: FnMut(&str) -> Result<PathBuf>,
+{
+    let mut len = [0; 4];
+    body.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    ensure!(len <= MAX_LEN_INFO, "container exceeds {MAX_LEN_INFO}");
+    let mut buf = vec![0; len];
+    body.read_exact(&mut buf)?;
+    let container = Container::from_bytes(&buf)?;
+
+    container
+        .parts
+        .into_iter()
+        .map(|part| {
+            ensure!(
+                part.len <= MAX_LEN_BUNDLE as u64,
+                "bundle of part {} exceeds {MAX_LEN_BUNDLE}",
+                part.name
+            );
+            let dir = bundle_dir(&part.name)?;
+            let sub = Submission::from_reader(
+                dir,
+                part.signature,
+                part.hash.algorithm(),
+                (&mut body).take(part.len),
+            )?;
+            ensure!(
+                sub.bundle.info.len == part.len && sub.bundle.info.hash == part.hash,
+                "bundle of part {} does not match the container",
+                part.name
+            );
+            Ok((part.name, sub))
+        })
+        .collect()
+}
+
+/// Submit a composite patch to the drop at `base_url`
+///
+/// The body of the request is as expected by [`read_body`].
+pub fn submit(container: &Container, mut base_url: Url) -> Result<Vec<Record>> {
+    base_url
+        .path_segments_mut()
+        .map_err(|()| anyhow!("invalid url"))?
+        .push("patches")
+        .push("composite");
+    let info = container.to_bytes()?;
+    let mut len = 4 + info.len() as u64;
+    let mut body: Box<dyn Read> = Box::new(io::Cursor::new(
+        [&(info.len() as u32).to_be_bytes()[..], &info].concat(),
+    ));
+    for part in &container.parts {
+        body = Box::new(body.chain(part.submission()?.bundle.reader()?));
+        len += part.len;
+    }
+    let res = net::request("POST", &base_url)?
+        .set("Content-Length", &len.to_string())
+        .send(body)?;
+
+    Ok(res.into_json()?)
+}
+
+/// A part of a composite patch to accept
+pub struct Accept<'a, S> {
+    pub name: &'a str,
+    pub args: AcceptArgs<'a, S>,
+    pub submission: &'a mut Submission,
+}
+
+/// Accept the parts of a composite patch, all or none
+///
+/// Every part is checked against its drop as if it was submitted on its own,
+/// and must carry the same [`Manifest`] on the same topic, naming exactly the
+/// parts given. Only if all of them pass are they recorded. Parts by
+/// submitters which would be quarantined are rejected.
+///
+/// Recording is not atomic across drops: if updating the refs of a drop fails
+/// after others were updated, those remain recorded, and an error is returned.
+///
+/// Unlike [`Submission::try_accept`], the outcome is not audited.
+pub fn accept<S: Signer>(parts: Vec<Accept<'_, S>>) -> Result<Vec<Record>> {
+    ensure!(!parts.is_empty(), "composite patch has no parts");
+    ensure!(
+        parts.len() <= MAX_PARTS,
+        "composite patch has more than {MAX_PARTS} parts"
+    );
+
+    let mut prepared = Vec::with_capacity(parts.len());
+    for Accept {
+        name,
+        args,
+        submission,
+    } in parts
+    {
+        let repo = args.repo;
+        let part = submission
+            .prepare(args)
+            .with_context(|| format!("part {name}"))?;
+        ensure!(
+            !part.is_quarantined(),
+            "part {name}: submissions by first-time submitters are held for review, and can \
+            not be part of a composite patch"
+        );
+        prepared.push((name, repo, part));
+    }
+
+    let mut expect: Option<(&Topic, Manifest)> = None;
+    for (name, repo, part) in &prepared {
+        let record = part.record();
+        let manifest = manifest_of(repo, record)
+            .with_context(|| format!("part {name}"))?
+            .ok_or_else(|| anyhow!("part {name} does not carry a composite manifest"))?;
+        let head = branch_head(record).with_context(|| format!("part {name}"))?;
+        ensure!(
+            manifest.get(name) == Some(&head),
+            "part {name} does not match its manifest entry"
+        );
+        match &expect {
+            None => expect = Some((&record.topic, manifest)),
+            Some((topic, expect)) => {
+                ensure!(
+                    &record.topic == *topic,
+                    "part {name} is posted to topic {}, expected {topic}",
+                    record.topic
+                );
+                ensure!(
+                    &manifest == expect,
+                    "manifest of part {name} differs from the other parts"
+                );
+            },
+        }
+    }
+    if let Some((_, manifest)) = &expect {
+        ensure!(
+            manifest.len() == prepared.len()
+                && manifest
+                    .names()
+                    .all(|name| prepared.iter().any(|(n, _, _)| *n == name)),
+            "the parts submitted do not match the manifest ({})",
+            manifest.names().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let mut records = Vec::with_capacity(prepared.len());
+    for (name, _, part) in prepared {
+        match part.commit() {
+            Ok(record) => records.push(record),
+            Err(e) if records.is_empty() => return Err(e.context(format!("part {name}"))),
+            Err(e) => {
+                error!(
+                    "Recording part {name} of composite patch failed after {} other part(s) \
+                    were recorded: {e:#}",
+                    records.len()
+                );
+                return Err(e.context(format!("part {name}, composite patch recorded partially")));
+            },
+        }
+    }
+
+    Ok(records)
+}
+
+/// The [`Manifest`] carried by the topic note of `record`
+fn manifest_of(repo: &git2::Repository, record: &Record) -> Result<Option<Manifest>> {
+    let topic_ref = record.topic.as_refname();
+    let oid = record
+        .meta
+        .bundle
+        .references
+        .iter()
+        .find_map(|(name, oid)| (*name == topic_ref).then_some(oid))
+        .ok_or_else(|| anyhow!("no topic note"))?;
+    let commit = repo.find_commit(oid.try_into()?)?;
+
+    Manifest::from_commit(&commit)
+}
+
+/// The head of the single branch `record` proposes
+fn branch_head(record: &Record) -> Result<git2::Oid> {
+    let mut heads = record
+        .meta
+        .bundle
+        .references
+        .iter()
+        .filter(|(name, _)| name.starts_with("refs/heads/"));
+    match (heads.next(), heads.next()) {
+        (Some((_, oid)), None) => Ok(oid.try_into()?),
+        (None, _) => bail!("no branch"),
+        (Some(_), Some(_)) => bail!("more than one branch"),
+    }
+}
+
+impl Part {
+    /// Load the submission of this part from [`Part::path`]
+    pub fn submission(&self) -> Result<Submission> {
+        let dir = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("invalid bundle path {}", self.path.display()))?;
+        let bundle = Bundle::from_stored(
+            dir,
+            bundle::Expect {
+                len: self.len,
+                hash: &self.hash,
+                checksum: None,
+            },
+        )?;
+        ensure!(
+            bundle.info.len == self.len,
+            "bundle of part {} does not match the container",
+            self.name
+        );
+
+        Ok(Submission {
+            signature: self.signature.clone(),
+            bundle,
+        })
+    }
+}
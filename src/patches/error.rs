@@ -3,6 +3,9 @@
 
 use thiserror::Error;
 
+use super::Record;
+use crate::metadata::IdentityId;
+
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum FromTree {
@@ -27,3 +30,15 @@ pub enum FromTree {
     #[error(transparent)]
     Git(#[from] git2::Error),
 }
+
+/// A submission was held for review instead of being recorded
+///
+/// Returned by [`super::Submission::try_accept`] if the submitter is not yet
+/// known to the drop and [`super::AcceptOptions::quarantine_new`] is set.
+#[derive(Debug, Error)]
+#[error("submission by first-time submitter {submitter} is pending review")]
+pub struct Quarantined {
+    pub submitter: IdentityId,
+    pub record: Record,
+    pub commit: git2::Oid,
+}
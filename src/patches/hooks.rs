@@ -0,0 +1,146 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+use std::{
+    io::{
+        self,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+};
+use log::debug;
+
+use super::Record;
+use crate::metadata::IdentityId;
+
+pub mod webhook;
+pub use webhook::Webhook;
+
+/// Executables to run when accepting a patch
+///
+/// Each hook is invoked with a JSON [`Summary`] of the submission on stdin,
+/// and `GIT_DIR` set to the drop repository.
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    /// Run before the patch is recorded
+    ///
+    /// A non-zero exit status rejects the submission. The first line of the
+    /// hook's stdout is included in the error message.
+    pub pre_accept: Option<PathBuf>,
+    /// Run after the patch was recorded
+    ///
+    /// The exit status is ignored.
+    pub post_accept: Option<PathBuf>,
+    /// Run after the patch was quarantined for review
+    ///
+    /// The exit status is ignored.
+    pub quarantine: Option<PathBuf>,
+    /// URLs to notify after the patch was recorded, see [`webhook`]
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    PreAccept,
+    PostAccept,
+    Quarantine,
+}
+
+/// Summary of a submission, passed to the hooks on stdin
+#[derive(serde::Serialize)]
+pub struct Summary<'a> {
+    pub phase: Phase,
+    pub drop_ref: &'a str,
+    pub submitter: &'a IdentityId,
+    pub record: &'a Record,
+    /// The new tip of the drop history for [`Phase::PostAccept`], or the
+    /// quarantine commit for [`Phase::Quarantine`]
+    #[serde(
+        with = "crate::git::serde::oid::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub commit: Option<git2::Oid>,
+}
+
+impl Hooks {
+    pub fn pre_accept(&self, git_dir: &Path, summary: &Summary) -> crate::Result<()> {
+        if let Some(hook) = &self.pre_accept {
+            let (ok, out) = run(hook, git_dir, summary)?;
+            if !ok {
+                let reason = out.lines().next().unwrap_or("no reason given");
+                bail!("rejected by pre-accept hook: {reason}");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn post_accept(&self, git_dir: &Path, summary: &Summary) -> crate::Result<()> {
+        if let Some(hook) = &self.post_accept {
+            run(hook, git_dir, summary)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn webhooks(&self, git_dir: &Path, payload: &webhook::Payload) -> crate::Result<()> {
+        if !self.webhooks.is_empty() {
+            webhook::notify(git_dir, &self.webhooks, payload)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn quarantine(&self, git_dir: &Path, summary: &Summary) -> crate::Result<()> {
+        if let Some(hook) = &self.quarantine {
+            run(hook, git_dir, summary)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run `hook` with `input` as JSON on stdin, returning whether it exited
+/// successfully along with its stdout
+pub(super) fn run<T>(hook: &Path, git_dir: &Path, input: &T) -> crate::Result<(bool, String)>
+where
+    T: serde::Serialize,
+{
+    debug!("running hook {}", hook.display());
+    let mut child = Command::new(hook)
+        .env("GIT_DIR", git_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| anyhow!("failed to run hook {}: {e}", hook.display()))?;
+    {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut json = serde_json::to_vec(input)?;
+        json.push(b'\n');
+        match stdin.write_all(&json) {
+            // Hooks may not care about their input
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+            x => x?,
+        }
+    }
+    let out = child.wait_with_output()?;
+
+    Ok((
+        out.status.success(),
+        String::from_utf8_lossy(&out.stdout).into_owned(),
+    ))
+}
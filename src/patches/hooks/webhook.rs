@@ -0,0 +1,334 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Webhooks notified when a patch is accepted
+//!
+//! Each webhook receives a `POST` request with a JSON [`Payload`]. If the
+//! webhook has a secret, the request carries the hex-encoded HMAC-SHA256 of
+//! the body keyed by the secret in the [`HTTP_HEADER_HMAC`] header, as
+//! `sha256=<hex>`. The [`HTTP_HEADER_DELIVERY`] header identifies the
+//! delivery, and stays the same when it is retried.
+//!
+//! A delivery which fails is stored under
+//! `$GIT_DIR/it/pending-webhooks/<delivery>.<name>.json`, and attempted again
+//! by [`retry`] once its backoff elapsed. After [`MAX_ATTEMPTS`], it is
+//! dropped.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+
+use anyhow::ensure;
+use log::{
+    debug,
+    info,
+    warn,
+};
+use sha2::{
+    Digest as _,
+    Sha256,
+};
+use time::OffsetDateTime;
+use url::Url;
+
+use crate::{
+    fs::LockedFile,
+    git,
+    net,
+    patches::{
+        Record,
+        Topic,
+    },
+    Result,
+};
+
+pub const HTTP_HEADER_EVENT: &str = "X-it-Event";
+pub const HTTP_HEADER_DELIVERY: &str = "X-it-Delivery";
+pub const HTTP_HEADER_HMAC: &str = "X-it-Hmac-Sha256";
+
+/// Number of attempts after which a delivery is given up
+pub const MAX_ATTEMPTS: u32 = 12;
+/// Delay before the first retry, doubled for every subsequent one
+pub const BACKOFF: Duration = Duration::from_secs(30);
+/// Upper bound of the delay between retries
+pub const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+const PENDING_DIR: &str = "it/pending-webhooks";
+const FILE_EXTENSION: &str = "json";
+const EVENT_ACCEPTED: &str = "accepted";
+
+/// A URL to notify of accepted patches
+#[derive(Clone)]
+pub struct Webhook {
+    /// Name of the webhook, as configured
+    pub name: String,
+    pub url: Url,
+    /// Key to sign the payload with
+    pub secret: Option<String>,
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("name", &self.name)
+            .field("url", &self.url.as_str())
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Ensure `name` is usable as a webhook name
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "invalid webhook name '{name}': only ASCII alphanumerics, '-' and '_' are allowed"
+    );
+    Ok(())
+}
+
+/// Notification about an accepted patch
+#[derive(serde::Serialize)]
+pub struct Payload<'a> {
+    pub event: &'static str,
+    pub drop_ref: &'a str,
+    /// The new tip of the drop history
+    #[serde(with = "git::serde::oid")]
+    pub commit: git2::Oid,
+    pub topic: &'a Topic,
+    pub record: &'a Record,
+    /// The refs updated by unbundling the patch, if it was unbundled
+    pub refs: BTreeMap<&'a str, String>,
+}
+
+impl<'a> Payload<'a> {
+    /// Payload for a patch accepted into `drop_ref` at `commit`
+    pub fn accepted(
+        drop_ref: &'a str,
+        commit: git2::Oid,
+        record: &'a Record,
+        updated: &'a [(git::Refname, git2::Oid)],
+    ) -> Self {
+        Self {
+            event: EVENT_ACCEPTED,
+            drop_ref,
+            commit,
+            topic: &record.topic,
+            record,
+            refs: updated
+                .iter()
+                .map(|(name, oid)| (name.as_ref(), oid.to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// A delivery waiting to be retried
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Pending {
+    /// Name of the webhook
+    pub name: String,
+    pub delivery: String,
+    /// The serialised [`Payload`]
+    pub body: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub queued: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub next_attempt: OffsetDateTime,
+    /// Number of failed attempts, including the initial one
+    pub attempts: u32,
+    /// The error the last attempt failed with
+    pub error: String,
+}
+
+impl Pending {
+    fn path(git_dir: &Path, delivery: &str, name: &str) -> PathBuf {
+        let mut path = git_dir.join(PENDING_DIR).join(format!("{delivery}.{name}"));
+        path.set_extension(FILE_EXTENSION);
+        path
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+
+    fn save(&self, git_dir: &Path) -> Result<()> {
+        let path = Self::path(git_dir, &self.delivery, &self.name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lock = LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+        serde_json::to_writer_pretty(&mut lock, self)?;
+        lock.persist()?;
+
+        Ok(())
+    }
+
+    fn failed(&mut self, error: &crate::Error) {
+        let backoff = BACKOFF
+            .checked_mul(1 << self.attempts.min(16))
+            .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+        self.attempts += 1;
+        self.next_attempt = OffsetDateTime::now_utc() + backoff;
+        self.error = format!("{error:#}");
+    }
+}
+
+/// A previously pending delivery which succeeded
+#[derive(serde::Serialize)]
+pub struct Delivered {
+    pub name: String,
+    pub delivery: String,
+}
+
+/// Deliver `payload` to all `hooks`, queueing failed deliveries for retry
+pub fn notify(git_dir: &Path, hooks: &[Webhook], payload: &Payload) -> Result<()> {
+    let body = serde_json::to_string(payload)?;
+    let delivery = payload.commit.to_string();
+    for hook in hooks {
+        if let Err(e) = post(hook, &delivery, &body) {
+            warn!("webhook {} failed, will retry: {e:#}", hook.name);
+            let now = OffsetDateTime::now_utc();
+            let mut pending = Pending {
+                name: hook.name.clone(),
+                delivery: delivery.clone(),
+                body: body.clone(),
+                queued: now,
+                next_attempt: now,
+                attempts: 0,
+                error: String::new(),
+            };
+            pending.failed(&e);
+            pending.save(git_dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// All pending deliveries, oldest first
+pub fn pending(git_dir: &Path) -> Result<Vec<Pending>> {
+    let dir = match fs::read_dir(git_dir.join(PENDING_DIR)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut pending = Vec::new();
+    for entry in dir {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == FILE_EXTENSION) {
+            pending.push(Pending::load(&path)?);
+        }
+    }
+    pending.sort_by_key(|p| p.queued);
+
+    Ok(pending)
+}
+
+/// Retry the pending deliveries whose backoff elapsed
+///
+/// Deliveries to webhooks no longer in `hooks` are dropped, as are those
+/// which failed [`MAX_ATTEMPTS`] times.
+pub fn retry(git_dir: &Path, hooks: &[Webhook]) -> Result<Vec<Delivered>> {
+    let now = OffsetDateTime::now_utc();
+    let mut delivered = Vec::new();
+    for mut p in pending(git_dir)? {
+        let path = Pending::path(git_dir, &p.delivery, &p.name);
+        let hook = match hooks.iter().find(|hook| hook.name == p.name) {
+            Some(hook) => hook,
+            None => {
+                warn!(
+                    "Dropping delivery {} to removed webhook {}",
+                    p.delivery, p.name
+                );
+                remove(&path)?;
+                continue;
+            },
+        };
+        if p.next_attempt > now {
+            continue;
+        }
+        match post(hook, &p.delivery, &p.body) {
+            Ok(()) => {
+                info!("Delivered {} to webhook {}", p.delivery, p.name);
+                remove(&path)?;
+                delivered.push(Delivered {
+                    name: p.name,
+                    delivery: p.delivery,
+                });
+            },
+            Err(e) if p.attempts + 1 >= MAX_ATTEMPTS => {
+                warn!(
+                    "Giving up delivering {} to webhook {} after {MAX_ATTEMPTS} attempts: {e:#}",
+                    p.delivery, p.name
+                );
+                remove(&path)?;
+            },
+            Err(e) => {
+                warn!(
+                    "Delivering {} to webhook {} failed again: {e:#}",
+                    p.delivery, p.name
+                );
+                p.failed(&e);
+                p.save(git_dir)?;
+            },
+        }
+    }
+
+    Ok(delivered)
+}
+
+fn post(hook: &Webhook, delivery: &str, body: &str) -> Result<()> {
+    debug!("notifying webhook {} at {}", hook.name, hook.url);
+    let mut req = net::request("POST", &hook.url)?
+        .set("Content-Type", "application/json")
+        .set(HTTP_HEADER_EVENT, EVENT_ACCEPTED)
+        .set(HTTP_HEADER_DELIVERY, delivery);
+    if let Some(secret) = &hook.secret {
+        let mac = hmac_sha256(secret.as_bytes(), body.as_bytes());
+        req = req.set(HTTP_HEADER_HMAC, &format!("sha256={}", hex::encode(mac)));
+    }
+    req.send_string(body)?;
+
+    Ok(())
+}
+
+/// HMAC-SHA256 as per RFC 2104
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    const BLOCK_LEN: usize = 64;
+
+    let mut block = [0; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x36))
+        .chain_update(msg)
+        .finalize();
+
+    Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Links between topics and the issues of an external issue tracker
+//!
+//! Maintainers link issues to topics explicitly via a table in the custom
+//! metadata of the drop, under [`CUSTOM_KEY`]. In addition, the commits of a
+//! patch may reference the issues they resolve by a [`TRAILER`] trailer, eg.
+//! `Closes: PROJ-123`. Those are detected when the patch is accepted, and
+//! stored in the metadata of its [`Record`].
+
+use core::fmt;
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    str::FromStr,
+};
+
+use anyhow::{
+    ensure,
+    Context as _,
+};
+
+use super::{
+    iter::dropped,
+    Record,
+    Topic,
+};
+use crate::{
+    bundle,
+    metadata::{
+        self,
+        git::FromGit as _,
+        Custom,
+    },
+    Result,
+};
+
+/// Key in the custom metadata of a drop holding the issues linked to topics
+///
+/// The value is an object mapping topics to a list of issues.
+pub const CUSTOM_KEY: &str = "issues";
+
+/// Trailer referencing an issue resolved by a commit
+///
+/// Compared case-insensitively. The value may list several issues, separated
+/// by commas.
+pub const TRAILER: &str = "Closes";
+
+/// Maximum length of an issue key or URL
+pub const MAX_LEN: usize = 256;
+
+/// Maximum number of issues detected in the commits of a single patch
+///
+/// Any further issues are ignored.
+const MAX_DETECTED: usize = 32;
+
+/// The issues linked to each topic
+pub type Links = BTreeMap<Topic, BTreeSet<Issue>>;
+
+/// The key of an issue, eg. `PROJ-123`, or its URL
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize)]
+pub struct Issue(String);
+
+impl Issue {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the issue is given by URL, rather than key
+    pub fn is_url(&self) -> bool {
+        self.0.starts_with("https://") || self.0.starts_with("http://")
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Issue {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ensure!(!s.is_empty(), "issue must not be empty");
+        ensure!(
+            s.len() <= MAX_LEN,
+            "issue exceeds {MAX_LEN} bytes: {}",
+            s.len()
+        );
+        ensure!(
+            !s.chars().any(|c| c.is_whitespace() || c.is_control()),
+            "issue must not contain whitespace or control characters"
+        );
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Issue {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The issues linked to topics in the custom metadata of a drop
+pub fn from_custom(custom: &Custom) -> Result<Links> {
+    match custom.get(CUSTOM_KEY) {
+        None => Ok(Links::new()),
+        Some(v) => serde_json::from_value(v.clone())
+            .with_context(|| format!("invalid '{CUSTOM_KEY}' in drop metadata")),
+    }
+}
+
+/// Store `links` in the custom metadata of a drop
+///
+/// Topics without issues are omitted, and the [`CUSTOM_KEY`] is removed
+/// altogether if no topic has any.
+pub fn to_custom(custom: &mut Custom, mut links: Links) -> Result<()> {
+    links.retain(|_, issues| !issues.is_empty());
+    if links.is_empty() {
+        custom.remove(CUSTOM_KEY);
+    } else {
+        custom.insert(CUSTOM_KEY.to_owned(), serde_json::to_value(links)?);
+    }
+
+    Ok(())
+}
+
+/// The issues referenced by [`TRAILER`] trailers in `message`
+///
+/// Values which are not valid [`Issue`]s are ignored.
+pub fn from_message(message: &str) -> Vec<Issue> {
+    let trailers = match git2::message_trailers_strs(message) {
+        Ok(trailers) => trailers,
+        Err(_) => return Vec::new(),
+    };
+    trailers
+        .iter()
+        .filter(|(token, _)| token.eq_ignore_ascii_case(TRAILER))
+        .flat_map(|(_, value)| value.split(','))
+        .filter_map(|issue| issue.trim().parse().ok())
+        .collect()
+}
+
+/// Detect the issues referenced by the commits of the branches in the bundle
+/// `header`
+///
+/// The objects of the bundle must be present in `repo`. At most
+/// [`MAX_DETECTED`] issues are returned.
+pub fn detect(repo: &git2::Repository, header: &bundle::Header) -> Result<BTreeSet<Issue>> {
+    let mut issues = BTreeSet::new();
+    let mut walk = repo.revwalk()?;
+    for (name, oid) in &header.references {
+        // Identity updates are conveyed as branches, too
+        if !name.starts_with("refs/heads/") || name.starts_with("refs/heads/it/") {
+            continue;
+        }
+        walk.push(oid.try_into()?)?;
+    }
+    for prereq in &header.prerequisites {
+        walk.hide(prereq.try_into()?)?;
+    }
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        for issue in from_message(&String::from_utf8_lossy(commit.message_bytes())) {
+            if issues.len() >= MAX_DETECTED {
+                return Ok(issues);
+            }
+            issues.insert(issue);
+        }
+    }
+
+    Ok(issues)
+}
+
+/// All issues linked to topics, either in the drop metadata's `custom` table,
+/// or by trailers of the patches in `records`
+pub fn collect<I>(custom: &Custom, records: I) -> Result<Links>
+where
+    I: IntoIterator<Item = Result<Record>>,
+{
+    let mut links = from_custom(custom)?;
+    for record in records {
+        let Record { topic, meta, .. } = record?;
+        if !meta.issues.is_empty() {
+            links.entry(topic).or_default().extend(meta.issues);
+        }
+    }
+
+    Ok(links)
+}
+
+/// All issues linked to topics of the drop at `drop_ref`, cf. [`collect`]
+///
+/// The drop metadata is not verified.
+pub fn linked(repo: &git2::Repository, drop_ref: &str) -> Result<Links> {
+    let meta = metadata::Drop::from_tip(repo, drop_ref)?;
+    collect(&meta.signed.signed.custom, dropped::records(repo, drop_ref))
+}
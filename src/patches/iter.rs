@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
-    collections::BTreeSet,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
     rc::Rc,
     str::FromStr,
 };
@@ -16,6 +19,7 @@ use time::{
 use super::{
     notes,
     record::{
+        Diffstat,
         Heads,
         Record,
     },
@@ -99,6 +103,19 @@ pub mod dropped {
         _records(repo, drop_ref, true)
     }
 
+    /// Total diffstat of the records of each topic, for topics which have any
+    pub fn diffstats(repo: &git2::Repository, drop_ref: &str) -> Result<BTreeMap<Topic, Diffstat>> {
+        let mut stats = BTreeMap::new();
+        for record in records(repo, drop_ref) {
+            let Record { topic, meta, .. } = record?;
+            if let Some(diffstat) = meta.diffstat {
+                *stats.entry(topic).or_insert_with(Diffstat::default) += diffstat;
+            }
+        }
+
+        Ok(stats)
+    }
+
     fn _records<'a>(
         repo: &'a git2::Repository,
         drop_ref: &'a str,
@@ -141,13 +158,15 @@ pub mod unbundled {
         )
     }
 
+    /// The unbundled topics, along with their subject line and state
     pub fn topics_with_subject(
         repo: &git2::Repository,
-    ) -> impl Iterator<Item = Result<(Topic, String)>> + '_ {
-        let topic_and_subject = move |refname: &str| -> Result<(Topic, String)> {
+    ) -> impl Iterator<Item = Result<(Topic, String, notes::TopicState)>> + '_ {
+        let topic_and_subject = move |refname: &str| -> Result<(Topic, String, notes::TopicState)> {
             let topic = Topic::from_refname(refname)?;
             let subject = find_subject(repo, refname)?;
-            Ok((topic, subject))
+            let state = find_state(repo, refname)?;
+            Ok((topic, subject, state))
         };
         iter::Iter::new(
             move || {
@@ -159,34 +178,64 @@ pub mod unbundled {
     }
 
     // TODO: cache this somewhere
-    fn find_subject(repo: &git2::Repository, topic_ref: &str) -> Result<String> {
+    pub fn find_subject(repo: &git2::Repository, topic_ref: &str) -> Result<String> {
         let mut walk = repo.revwalk()?;
         walk.push_ref(topic_ref)?;
         walk.simplify_first_parent()?;
         walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
         match walk.next() {
             None => Ok(String::default()),
-            Some(oid) => {
-                let tree = repo.find_commit(oid?)?.tree()?;
-                let note = notes::Note::from_tree(repo, &tree)?;
-                let subj = match note {
-                    notes::Note::Simple(n) => n
-                        .checkpoint_kind()
-                        .map(|k| {
-                            match k {
-                                notes::CheckpointKind::Merge => "Merges",
-                                notes::CheckpointKind::Snapshot => "Snapshots",
-                            }
-                            .to_owned()
-                        })
-                        .unwrap_or_else(|| n.subject().unwrap_or_default().to_owned()),
-                    _ => String::default(),
-                };
+            Some(oid) => subject(repo, &repo.find_commit(oid?)?),
+        }
+    }
 
-                Ok(subj)
-            },
+    pub fn find_state(repo: &git2::Repository, topic_ref: &str) -> Result<notes::TopicState> {
+        state(repo, repo.refname_to_id(topic_ref)?)
+    }
+}
+
+/// The state set by the most recent state note reachable from `tip`
+///
+/// State notes are checked against the drop roles when they are accepted, so
+/// they are not verified again here.
+pub fn state(repo: &git2::Repository, tip: git2::Oid) -> Result<notes::TopicState> {
+    let mut walk = repo.revwalk()?;
+    walk.push(tip)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        // Patches are merged into the topic with an empty tree
+        if commit.tree_id() == *EMPTY_TREE {
+            continue;
+        }
+        if let Ok(note) = notes::Simple::from_commit(repo, &commit) {
+            if let Some(state) = note.topic_state() {
+                return Ok(state);
+            }
         }
     }
+
+    Ok(notes::TopicState::default())
+}
+
+/// The subject of the topic whose first note is `commit`
+pub fn subject(repo: &git2::Repository, commit: &git2::Commit) -> Result<String> {
+    let note = notes::Note::from_tree(repo, &commit.tree()?)?;
+    let subj = match note {
+        notes::Note::Simple(n) => n
+            .checkpoint_kind()
+            .map(|k| {
+                match k {
+                    notes::CheckpointKind::Merge => "Merges",
+                    notes::CheckpointKind::Snapshot => "Snapshots",
+                }
+                .to_owned()
+            })
+            .unwrap_or_else(|| n.subject().unwrap_or_default().to_owned()),
+        _ => String::default(),
+    };
+
+    Ok(subj)
 }
 
 #[derive(Eq, PartialEq, serde::Serialize)]
@@ -256,31 +305,31 @@ pub fn topic<'a>(
             is_merge.then(parse).transpose()
         }
 
-        fn patch_info(repo: &git2::Repository, id: Heads) -> Result<PatchInfo> {
-            let prefix = format!("{}/{}", REF_IT_BUNDLES, id);
-            let glob = format!("{prefix}/**");
-            let mut iter = repo.references_glob(&glob)?;
-            let tips = iter
-                .names()
-                .filter_map(|i| match i {
-                    Err(e) => Some(Err(e.into())),
-                    Ok(name)
-                        if name
-                            .strip_prefix(&prefix)
-                            .expect("glob yields prefix")
-                            .starts_with("/it/") =>
-                    {
-                        None
-                    },
-                    Ok(name) => Refname::from_str(name)
-                        .map_err(Into::into)
-                        .map(Some)
-                        .transpose(),
-                })
-                .collect::<Result<_>>()?;
-
-            Ok(PatchInfo { id, tips })
-        }
+        // Index the unbundled tips once, instead of globbing the refs of
+        // each patch: the refdb is scanned in full either way
+        let mut tips = {
+            let prefix = format!("{}/", REF_IT_BUNDLES);
+            let mut tips: BTreeMap<Heads, BTreeSet<Refname>> = BTreeMap::new();
+            let mut iter = repo.references_glob(&format!("{prefix}**"))?;
+            for name in iter.names() {
+                let name = name?;
+                let (id, tip) = match name.strip_prefix(&prefix).and_then(|s| s.split_once('/')) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if tip.starts_with("it/") {
+                    continue;
+                }
+                if let Ok(id) = id.parse() {
+                    tips.entry(id).or_default().insert(Refname::from_str(name)?);
+                }
+            }
+            tips
+        };
+        let mut patch_info = move |id: Heads| PatchInfo {
+            id,
+            tips: tips.remove(&id).unwrap_or_default(),
+        };
 
         let mut patches: Vec<Rc<PatchInfo>> = Vec::new();
         let mut commits: Vec<(git2::Tree<'a>, NoteHeader)> = Vec::new();
@@ -292,17 +341,13 @@ pub fn topic<'a>(
                 let id = patch_id(&tip)?.ok_or_else(|| {
                     anyhow!("invalid topic '{topic_ref}': tip must be a merge commit")
                 })?;
-                let patch = patch_info(repo, id)?;
-                patches.push(Rc::new(patch));
+                patches.push(Rc::new(patch_info(id)));
             }
 
             for id in walk {
                 let commit = repo.find_commit(id?)?;
                 match patch_id(&commit)? {
-                    Some(id) => {
-                        let patch = patch_info(repo, id)?;
-                        patches.push(Rc::new(patch))
-                    },
+                    Some(id) => patches.push(Rc::new(patch_info(id))),
                     None => {
                         let id = commit.id();
                         let (author, committer) = {
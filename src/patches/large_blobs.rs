@@ -0,0 +1,202 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Detection and removal of large blobs in patches
+//!
+//! Accidentally committed binaries can blow up the size of patch bundles far
+//! beyond what a drop is willing to accept. Blobs introduced by the commits of
+//! a patch which exceed a size threshold can be found using [`find`], and the
+//! patch rewritten to exclude them using [`strip`].
+
+use core::fmt;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    HashMap,
+};
+
+use log::debug;
+
+use crate::Result;
+
+/// Default size in bytes above which a blob is considered large
+pub const DEFAULT_THRESHOLD: u64 = 10 * 1024 * 1024;
+
+/// Options for handling large blobs when preparing a patch
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Options {
+    /// Size in bytes above which a blob is considered large
+    pub threshold: u64,
+    /// Rewrite the patch to exclude large blobs
+    pub strip: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            strip: false,
+        }
+    }
+}
+
+/// A blob exceeding the size threshold
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct LargeBlob {
+    /// Path of the blob in the first commit introducing it
+    pub path: String,
+    #[serde(with = "crate::git::serde::oid")]
+    pub oid: git2::Oid,
+    pub size: usize,
+}
+
+impl fmt::Display for LargeBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ({} bytes)", self.path, self.oid, self.size)
+    }
+}
+
+/// Find the blobs larger than `threshold` bytes introduced by the commits in
+/// `base..head`
+///
+/// Each commit is compared to its first parent, so blobs introduced by merged
+/// side branches are attributed to the merge commit.
+pub fn find(
+    repo: &git2::Repository,
+    base: git2::Oid,
+    head: git2::Oid,
+    threshold: u64,
+) -> Result<Vec<LargeBlob>> {
+    let odb = repo.odb()?;
+    let mut walk = repo.revwalk()?;
+    walk.push(head)?;
+    walk.hide(base)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+    let mut found = BTreeMap::new();
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        let old = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree()?),
+        };
+        let diff = repo.diff_tree_to_tree(old.as_ref(), Some(&commit.tree()?), None)?;
+        for delta in diff.deltas() {
+            let new = delta.new_file();
+            if !new.exists()
+                || new.mode() == git2::FileMode::Commit
+                || found.contains_key(&new.id())
+            {
+                continue;
+            }
+            let (size, _) = odb.read_header(new.id())?;
+            if size as u64 > threshold {
+                let path = new
+                    .path()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                debug!(
+                    "{path} ({size} bytes) in {} exceeds {threshold}",
+                    commit.id()
+                );
+                found.insert(
+                    new.id(),
+                    LargeBlob {
+                        path,
+                        oid: new.id(),
+                        size,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(found.into_values().collect())
+}
+
+/// Rewrite the commits in `base..head` to exclude the `blobs`
+///
+/// Commits whose tree is unaffected are retained as-is, unless one of their
+/// parents was rewritten. Note that rewritten commits lose their signatures,
+/// if any.
+///
+/// Returns the new head.
+pub fn strip(
+    repo: &git2::Repository,
+    base: git2::Oid,
+    head: git2::Oid,
+    blobs: &[LargeBlob],
+) -> Result<git2::Oid> {
+    let blobs = blobs.iter().map(|b| b.oid).collect::<BTreeSet<_>>();
+    let mut trees = HashMap::new();
+    let mut commits: HashMap<git2::Oid, git2::Oid> = HashMap::new();
+
+    let mut walk = repo.revwalk()?;
+    walk.push(head)?;
+    walk.hide(base)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        let tree = filter_tree(repo, &commit.tree()?, &blobs, &mut trees)?;
+        let parents = commit
+            .parent_ids()
+            .map(|id| commits.get(&id).copied().unwrap_or(id))
+            .collect::<Vec<_>>();
+        let unchanged = tree == commit.tree_id() && parents.iter().copied().eq(commit.parent_ids());
+        let new = if unchanged {
+            commit.id()
+        } else {
+            let parents = parents
+                .iter()
+                .map(|id| repo.find_commit(*id))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            repo.commit(
+                None,
+                &commit.author(),
+                &commit.committer(),
+                &String::from_utf8_lossy(commit.message_raw_bytes()),
+                &repo.find_tree(tree)?,
+                &parents.iter().collect::<Vec<_>>(),
+            )?
+        };
+        commits.insert(commit.id(), new);
+    }
+
+    Ok(commits.get(&head).copied().unwrap_or(head))
+}
+
+fn filter_tree(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    blobs: &BTreeSet<git2::Oid>,
+    cache: &mut HashMap<git2::Oid, git2::Oid>,
+) -> Result<git2::Oid> {
+    if let Some(oid) = cache.get(&tree.id()) {
+        return Ok(*oid);
+    }
+
+    let mut tb = repo.treebuilder(Some(tree))?;
+    let mut changed = false;
+    for entry in tree {
+        let name = entry.name_bytes();
+        match entry.kind() {
+            Some(git2::ObjectType::Blob) if blobs.contains(&entry.id()) => {
+                tb.remove(name)?;
+                changed = true;
+            },
+            Some(git2::ObjectType::Tree) => {
+                let sub = repo.find_tree(entry.id())?;
+                let new = filter_tree(repo, &sub, blobs, cache)?;
+                if new != entry.id() {
+                    tb.insert(name, new, entry.filemode())?;
+                    changed = true;
+                }
+            },
+            _ => continue,
+        }
+    }
+    let oid = if changed { tb.write()? } else { tree.id() };
+    cache.insert(tree.id(), oid);
+
+    Ok(oid)
+}
@@ -0,0 +1,115 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Commit message linting of submitted patches
+//!
+//! Drops may require the commits of submitted patches to follow a message
+//! convention, eg. carry a `Signed-off-by` trailer (as per the Developer
+//! Certificate of Origin), or have a subject line of the form `area: summary`.
+//! The [`Rules`] are checked against each commit of the branches conveyed by a
+//! patch bundle, before the patch is recorded.
+
+use core::fmt;
+
+use regex::Regex;
+use thiserror::Error;
+
+/// Rules the commit messages of submitted patches must adhere to
+///
+/// The default is to not impose any rules.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    /// Regular expression the subject line must match
+    pub subject_pattern: Option<Regex>,
+    /// Maximum number of characters of the subject line
+    pub max_subject_len: Option<usize>,
+    /// Trailer tokens which must be present, eg. `Signed-off-by`
+    ///
+    /// Tokens are compared case-insensitively.
+    pub required_trailers: Vec<String>,
+}
+
+impl Rules {
+    pub fn is_empty(&self) -> bool {
+        self.subject_pattern.is_none()
+            && self.max_subject_len.is_none()
+            && self.required_trailers.is_empty()
+    }
+
+    /// Check the message of `commit`, returning the problems found, if any
+    pub fn check(&self, commit: &git2::Commit) -> Option<Violation> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let message = String::from_utf8_lossy(commit.message_bytes());
+        let subject = message.lines().next().unwrap_or_default();
+        let mut problems = Vec::new();
+
+        if let Some(pat) = &self.subject_pattern {
+            if !pat.is_match(subject) {
+                problems.push(format!("subject does not match '{pat}'"));
+            }
+        }
+        if let Some(max) = self.max_subject_len {
+            let len = subject.chars().count();
+            if len > max {
+                problems.push(format!("subject exceeds {max} characters ({len})"));
+            }
+        }
+        if !self.required_trailers.is_empty() {
+            let present = match git2::message_trailers_strs(&message) {
+                Ok(trailers) => trailers
+                    .iter()
+                    .map(|(token, _)| token.to_lowercase())
+                    .collect::<Vec<_>>(),
+                Err(_) => Vec::new(),
+            };
+            for required in &self.required_trailers {
+                if !present.contains(&required.to_lowercase()) {
+                    problems.push(format!("missing '{required}' trailer"));
+                }
+            }
+        }
+
+        (!problems.is_empty()).then(|| Violation {
+            commit: commit.id(),
+            subject: subject.to_owned(),
+            problems,
+        })
+    }
+}
+
+/// The problems found with a single commit
+#[derive(Debug)]
+pub struct Violation {
+    pub commit: git2::Oid,
+    pub subject: String,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} '{}': {}",
+            self.commit,
+            self.subject,
+            self.problems.join(", ")
+        )
+    }
+}
+
+/// Commits of a submitted patch violated the lint [`Rules`]
+#[derive(Debug, Error)]
+pub struct Failed(pub Vec<Violation>);
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("commit lint failed:")?;
+        for v in &self.0 {
+            write!(f, "\n  {v}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Import of patch series from a mailbox
+//!
+//! Parses the output of `git format-patch --stdout` (or a mailbox of such
+//! emails, as saved from a mail client), and applies the patches onto a base
+//! commit, similar to `git am`. Unlike `git am`, no working tree is needed, so
+//! this works in bare repositories.
+
+use std::str;
+
+use anyhow::{
+    anyhow,
+    bail,
+    ensure,
+};
+use time::{
+    format_description::well_known::Rfc2822,
+    OffsetDateTime,
+};
+
+use crate::Result;
+
+/// A single email of a patch series
+pub struct Email {
+    /// Position in the series, if the subject carries one
+    ///
+    /// The cover letter of a series has position 0.
+    pub number: Option<(usize, usize)>,
+    pub author_name: String,
+    pub author_email: String,
+    pub date: git2::Time,
+    /// The subject, stripped of any `[PATCH ...]` prefix
+    pub subject: String,
+    /// The message body, excluding the diffstat and diff
+    pub body: String,
+    /// The diff, empty for a cover letter
+    pub diff: Vec<u8>,
+}
+
+impl Email {
+    pub fn is_cover_letter(&self) -> bool {
+        matches!(self.number, Some((0, _)))
+    }
+
+    /// The commit message, or cover letter text
+    pub fn message(&self) -> String {
+        let body = self.body.trim();
+        if body.is_empty() {
+            format!("{}\n", self.subject)
+        } else {
+            format!("{}\n\n{body}\n", self.subject)
+        }
+    }
+}
+
+/// A parsed patch series
+pub struct Series {
+    pub cover_letter: Option<Email>,
+    pub patches: Vec<Email>,
+}
+
+/// Parse the mailbox `mbox`
+///
+/// Patches are ordered by their position in the series if all of them carry
+/// one, and by their order in the mailbox otherwise.
+pub fn parse(mbox: &[u8]) -> Result<Series> {
+    let mut cover_letter = None;
+    let mut patches = Vec::new();
+    for msg in split(mbox) {
+        let email = parse_email(msg)?;
+        if email.is_cover_letter() {
+            ensure!(cover_letter.is_none(), "multiple cover letters in mailbox");
+            cover_letter = Some(email);
+        } else if email.diff.is_empty() {
+            bail!("email '{}' does not contain a patch", email.subject);
+        } else {
+            patches.push(email);
+        }
+    }
+    ensure!(!patches.is_empty(), "no patches found in mailbox");
+    if patches.iter().all(|p| p.number.is_some()) {
+        patches.sort_by_key(|p| p.number);
+    }
+
+    Ok(Series {
+        cover_letter,
+        patches,
+    })
+}
+
+/// Apply `patches` onto `base`, committing each as authored by the sender
+///
+/// Returns the resulting head commit.
+pub fn apply(repo: &git2::Repository, base: git2::Oid, patches: &[Email]) -> Result<git2::Oid> {
+    let committer = repo.signature().ok();
+    let mut head = repo.find_commit(base)?;
+    for patch in patches {
+        let diff = git2::Diff::from_buffer(&patch.diff)?;
+        let tree = repo
+            .apply_to_tree(&head.tree()?, &diff, None)
+            .map_err(|e| anyhow!("failed to apply '{}': {}", patch.subject, e.message()))?
+            .write_tree_to(repo)?;
+        let author = git2::Signature::new(&patch.author_name, &patch.author_email, &patch.date)?;
+        let oid = repo.commit(
+            None,
+            &author,
+            committer.as_ref().unwrap_or(&author),
+            &patch.message(),
+            &repo.find_tree(tree)?,
+            &[&head],
+        )?;
+        head = repo.find_commit(oid)?;
+    }
+
+    Ok(head.id())
+}
+
+fn split(mbox: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut pos = 0;
+    let mut prev_blank = true;
+    for line in mbox.split_inclusive(|b| *b == b'\n') {
+        if prev_blank && line.starts_with(b"From ") {
+            starts.push(pos);
+        }
+        prev_blank = trim_eol(line).is_empty();
+        pos += line.len();
+    }
+    if starts.first() != Some(&0) {
+        // Not an mbox, but a single email
+        starts.insert(0, 0);
+    }
+    let ends = starts
+        .iter()
+        .skip(1)
+        .copied()
+        .chain(Some(mbox.len()))
+        .collect::<Vec<_>>();
+
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &mbox[start..end])
+        .filter(|msg| !msg.iter().all(u8::is_ascii_whitespace))
+}
+
+fn parse_email(msg: &[u8]) -> Result<Email> {
+    let mut lines = msg.split_inclusive(|b| *b == b'\n').peekable();
+    if lines.peek().map_or(false, |l| l.starts_with(b"From ")) {
+        lines.next();
+    }
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines.by_ref() {
+        let line = trim_eol(line);
+        if line.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with(|c: char| c == ' ' || c == '\t') {
+            if let Some((_, v)) = headers.last_mut() {
+                v.push(' ');
+                v.push_str(line.trim());
+            }
+        } else if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_ascii_lowercase(), v.trim().to_owned()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| decode_header(v))
+    };
+
+    let mut body = Vec::new();
+    let mut diff = Vec::new();
+    let mut in_diff = false;
+    for line in lines {
+        let bare = trim_eol(line);
+        // Signature appended by format-patch
+        if bare == b"-- " {
+            break;
+        }
+        if in_diff {
+            diff.extend_from_slice(line);
+        } else if bare.starts_with(b"diff --git ") {
+            in_diff = true;
+            diff.extend_from_slice(line);
+        } else {
+            body.extend_from_slice(line);
+        }
+    }
+    let mut body = String::from_utf8(body).map_err(|e| anyhow!("non-UTF-8 message body: {e}"))?;
+    // The diffstat is separated from the message by a line of three dashes
+    if let Some(pos) = body.find("\n---\n").map(|p| p + 1) {
+        body.truncate(pos);
+    } else if body.starts_with("---\n") {
+        body.clear();
+    }
+
+    let (mut author_name, mut author_email) = header("from")
+        .as_deref()
+        .map(parse_address)
+        .ok_or_else(|| anyhow!("missing From header"))?;
+    // An in-body From: overrides the sender, cf. git-format-patch(1) --from
+    if let Some(rest) = body.strip_prefix("From: ") {
+        if let Some((from, rest)) = rest.split_once('\n') {
+            (author_name, author_email) = parse_address(from);
+            body = rest.trim_start().to_owned();
+        }
+    }
+    let date = header("date")
+        .ok_or_else(|| anyhow!("missing Date header"))
+        .and_then(|date| {
+            let date = OffsetDateTime::parse(&date, &Rfc2822)?;
+            Ok(git2::Time::new(
+                date.unix_timestamp(),
+                date.offset().whole_minutes() as i32,
+            ))
+        })?;
+    let (number, subject) = parse_subject(&header("subject").unwrap_or_default());
+    if matches!(number, Some((0, _))) {
+        body = strip_shortlog(&body);
+    }
+
+    Ok(Email {
+        number,
+        author_name,
+        author_email,
+        date,
+        subject,
+        body,
+        diff,
+    })
+}
+
+fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Strip any `Re:` and `[...]` prefixes from `subject`, and extract the
+/// position in the series from a `[PATCH n/m]` prefix
+fn parse_subject(subject: &str) -> (Option<(usize, usize)>, String) {
+    let mut number = None;
+    let mut rest = subject.trim();
+    loop {
+        if let Some(r) = rest
+            .strip_prefix("Re:")
+            .or_else(|| rest.strip_prefix("RE:"))
+        {
+            rest = r.trim_start();
+        } else if let Some((tag, r)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            number = number.or_else(|| {
+                tag.split_whitespace().find_map(|word| {
+                    let (n, m) = word.split_once('/')?;
+                    Some((n.parse().ok()?, m.parse().ok()?))
+                })
+            });
+            rest = r.trim_start();
+        } else {
+            break;
+        }
+    }
+
+    (number, rest.to_owned())
+}
+
+/// Split an address like `A U Thor <author@example.com>`
+fn parse_address(addr: &str) -> (String, String) {
+    match addr.rsplit_once('<') {
+        Some((name, email)) => {
+            let name = name.trim().trim_matches('"').to_owned();
+            let email = email.trim_end_matches('>').trim().to_owned();
+            (name, email)
+        },
+        None => (String::new(), addr.trim().to_owned()),
+    }
+}
+
+/// Remove the shortlog and diffstat from a cover letter generated by `git
+/// format-patch --cover-letter`
+///
+/// The shortlog starts with a line like `A U Thor (3):`.
+fn strip_shortlog(body: &str) -> String {
+    let mut out = String::new();
+    for line in body.lines() {
+        let is_shortlog = line
+            .strip_suffix("):")
+            .and_then(|l| l.rsplit_once(" ("))
+            .map_or(false, |(name, n)| {
+                !name.is_empty() && !line.starts_with(' ') && n.parse::<usize>().is_ok()
+            });
+        if is_shortlog {
+            break;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out.trim_end().to_owned()
+}
+
+/// Decode RFC 2047 encoded words in a header value
+fn decode_header(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut prev_encoded = false;
+    while let Some(start) = rest.find("=?") {
+        let decoded = rest[start + 2..].split_once("?=").and_then(|(word, tail)| {
+            let mut parts = word.splitn(3, '?');
+            let (_charset, enc, text) = (parts.next()?, parts.next()?, parts.next()?);
+            let bytes = match enc {
+                "q" | "Q" => decode_q(text)?,
+                "b" | "B" => base64::decode(text).ok()?,
+                _ => return None,
+            };
+            Some((String::from_utf8_lossy(&bytes).into_owned(), tail))
+        });
+        match decoded {
+            Some((text, tail)) => {
+                let between = &rest[..start];
+                // Whitespace between adjacent encoded words is ignored
+                if !(prev_encoded && between.trim().is_empty()) {
+                    out.push_str(between);
+                }
+                out.push_str(&text);
+                rest = tail;
+                prev_encoded = true;
+            },
+            None => {
+                out.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                prev_encoded = false;
+            },
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'_' => out.push(b' '),
+            b'=' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            b => out.push(b),
+        }
+    }
+
+    Some(out)
+}
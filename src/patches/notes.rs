@@ -5,12 +5,14 @@ use std::{
     cmp,
     collections::BTreeMap,
     convert::Infallible,
+    fmt,
     io,
     ops::Range,
 };
 
 use super::{
     error,
+    record::Heads,
     traits::{
         Blob,
         BlobData,
@@ -20,6 +22,7 @@ use super::{
 use crate::{
     bundle::ObjectId,
     git::Refname,
+    patches::Topic,
 };
 
 #[derive(serde::Serialize)]
@@ -84,14 +87,54 @@ impl Simple {
         kind: CheckpointKind,
         refs: BTreeMap<Refname, ObjectId>,
         message: Option<String>,
+        squashed: Vec<Squashed>,
     ) -> Self {
         Self::Known(Predef::Checkpoint {
             kind,
             refs,
             message,
+            squashed,
         })
     }
 
+    pub fn state(state: TopicState, message: Option<String>) -> Self {
+        Self::Known(Predef::State { state, message })
+    }
+
+    pub fn review(patch: Heads, verdict: Option<Verdict>, comments: Vec<ReviewComment>) -> Self {
+        Self::Known(Predef::Review {
+            patch,
+            verdict,
+            message: None,
+            comments,
+        })
+    }
+
+    /// Set the message of a state or review note
+    ///
+    /// Has no effect on other kinds of notes.
+    pub fn set_message(&mut self, msg: Option<String>) {
+        if let Self::Known(Predef::State { message, .. } | Predef::Review { message, .. }) = self {
+            *message = msg;
+        }
+    }
+
+    /// Append a paragraph to the message of a basic note
+    ///
+    /// Has no effect on other kinds of notes, or if the message already
+    /// contains `text`.
+    pub fn append_paragraph(&mut self, text: &str) {
+        if let Self::Known(Predef::Basic { message }) = self {
+            if message.contains(text) {
+                return;
+            }
+            let trimmed = message.trim_end().len();
+            message.truncate(trimmed);
+            message.push_str("\n\n");
+            message.push_str(text);
+        }
+    }
+
     pub fn from_commit(repo: &git2::Repository, commit: &git2::Commit) -> crate::Result<Self> {
         let tree = commit.tree()?;
         let blob = Blob::from_tree(repo, &tree)?;
@@ -116,6 +159,22 @@ impl Simple {
             _ => None,
         }
     }
+
+    /// The state a state note sets its topic to
+    pub fn topic_state(&self) -> Option<TopicState> {
+        match self {
+            Self::Known(Predef::State { state, .. }) => Some(*state),
+            _ => None,
+        }
+    }
+
+    /// The patches recorded as squash-merged by a checkpoint
+    pub fn squashed(&self) -> &[Squashed] {
+        match self {
+            Self::Known(Predef::Checkpoint { squashed, .. }) => squashed,
+            _ => &[],
+        }
+    }
 }
 
 impl BlobData for Simple {
@@ -149,6 +208,30 @@ pub enum Predef {
         refs: BTreeMap<Refname, ObjectId>,
         #[serde(skip_serializing_if = "Option::is_none")]
         message: Option<String>,
+        /// Patches merged into the checkpointed branches as squash commits
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        squashed: Vec<Squashed>,
+    },
+    /// Change of the [`TopicState`]
+    ///
+    /// Only honoured if signed by an identity holding a branch role.
+    #[serde(rename = "eagain.io/it/notes/state")]
+    State {
+        state: TopicState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// Review of a patch, with comments anchored to lines of its files
+    #[serde(rename = "eagain.io/it/notes/review")]
+    Review {
+        /// The patch reviewed
+        patch: Heads,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        verdict: Option<Verdict>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        comments: Vec<ReviewComment>,
     },
 }
 
@@ -156,7 +239,9 @@ impl Predef {
     pub fn subject(&self) -> Option<&str> {
         let msg = match self {
             Self::Basic { message } | Self::CodeComment { message, .. } => Some(message),
-            Self::Checkpoint { message, .. } => message.as_ref(),
+            Self::Checkpoint { message, .. }
+            | Self::State { message, .. }
+            | Self::Review { message, .. } => message.as_ref(),
         }?;
         let line = msg.lines().next()?;
         let subj = &line[..cmp::min(72, line.len())];
@@ -173,9 +258,75 @@ pub struct SourceLoc {
     pub line: Option<Range<usize>>,
 }
 
+/// Outcome of a [`Predef::Review`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Approve,
+    RequestChanges,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Approve => "approve",
+            Self::RequestChanges => "request-changes",
+        })
+    }
+}
+
+/// A comment on some lines of a file, as of a commit of the patch
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ReviewComment {
+    pub path: String,
+    pub commit: ObjectId,
+    /// The blob at `path` in `commit`
+    pub blob: ObjectId,
+    /// The lines commented on, 1-based and end-exclusive. The comment applies
+    /// to the whole file if not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<Range<usize>>,
+    pub message: String,
+}
+
+/// A patch whose changes were merged as a single commit
+///
+/// The original patch commits never appear in the history of the branch, so
+/// this is recorded explicitly.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Squashed {
+    pub topic: Topic,
+    pub patch: Heads,
+    /// The squash commit
+    pub commit: ObjectId,
+}
+
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum CheckpointKind {
     Merge,
     Snapshot,
 }
+
+/// Lifecycle state of a topic
+///
+/// Topics are open until a [`Predef::State`] note says otherwise. The most
+/// recent such note determines the state.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicState {
+    #[default]
+    Open,
+    Closed,
+    Merged,
+}
+
+impl fmt::Display for TopicState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+            Self::Merged => "merged",
+        })
+    }
+}
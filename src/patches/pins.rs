@@ -0,0 +1,164 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! IPFS pins which failed when a patch was accepted
+//!
+//! Publishing a bundle to IPFS is not essential for accepting a patch, so a
+//! failure is recorded under `$GIT_DIR/it/pending-pins/<bundle hash>.json`
+//! instead of failing the accept. [`retry`] attempts to publish the pending
+//! bundles again, and on success adds the IPFS location to the bundle's
+//! `.uris` list.
+
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use log::{
+    info,
+    warn,
+};
+use time::OffsetDateTime;
+use url::Url;
+
+use super::Bundle;
+use crate::{
+    bundle,
+    fs::LockedFile,
+    Result,
+};
+
+const PENDING_DIR: &str = "it/pending-pins";
+const FILE_EXTENSION: &str = "json";
+
+/// A bundle waiting to be published to IPFS
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Pending {
+    pub info: bundle::Info,
+    pub bundle_dir: PathBuf,
+    /// The IPFS API to publish via
+    pub api: Url,
+    #[serde(with = "time::serde::rfc3339")]
+    pub queued: OffsetDateTime,
+    /// Number of failed attempts, including the initial one
+    pub attempts: u32,
+    /// The error the last attempt failed with
+    pub error: String,
+}
+
+impl Pending {
+    fn path(git_dir: &Path, hash: &bundle::Hash) -> PathBuf {
+        let mut path = git_dir.join(PENDING_DIR).join(hash.to_string());
+        path.set_extension(FILE_EXTENSION);
+        path
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+
+    fn save(&self, git_dir: &Path) -> Result<()> {
+        let path = Self::path(git_dir, &self.info.hash);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut lock = LockedFile::atomic(&path, true, LockedFile::DEFAULT_PERMISSIONS)?;
+        serde_json::to_writer_pretty(&mut lock, self)?;
+        lock.persist()?;
+
+        Ok(())
+    }
+}
+
+/// A previously pending bundle which is now published to IPFS
+#[derive(serde::Serialize)]
+pub struct Pinned {
+    pub hash: bundle::Hash,
+    pub url: Url,
+}
+
+/// Record that publishing `bundle` via `api` failed with `error`
+pub fn enqueue(git_dir: &Path, bundle: &Bundle, api: &Url, error: &crate::Error) -> Result<()> {
+    let bundle_dir = bundle
+        .path
+        .parent()
+        .expect("bundle path has a parent")
+        .to_owned();
+    Pending {
+        info: bundle.info.clone(),
+        bundle_dir,
+        api: api.clone(),
+        queued: OffsetDateTime::now_utc(),
+        attempts: 1,
+        error: format!("{error:#}"),
+    }
+    .save(git_dir)
+}
+
+/// All pending pins, oldest first
+pub fn pending(git_dir: &Path) -> Result<Vec<Pending>> {
+    let dir = match fs::read_dir(git_dir.join(PENDING_DIR)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut pending = Vec::new();
+    for entry in dir {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == FILE_EXTENSION) {
+            pending.push(Pending::load(&path)?);
+        }
+    }
+    pending.sort_by_key(|p| p.queued);
+
+    Ok(pending)
+}
+
+/// Retry all pending pins
+///
+/// Pins which fail again remain pending. Bundles which no longer exist are
+/// dropped from the queue.
+pub fn retry(git_dir: &Path) -> Result<Vec<Pinned>> {
+    let mut pinned = Vec::new();
+    for mut p in pending(git_dir)? {
+        let hash = p.info.hash;
+        let path = Pending::path(git_dir, &hash);
+        let mut bundle = match Bundle::from_stored(&p.bundle_dir, bundle::Expect::from(&p.info)) {
+            Ok(bundle) => bundle,
+            Err(e) => {
+                warn!("Dropping pending pin of {hash}: {e:#}");
+                remove(&path)?;
+                continue;
+            },
+        };
+        bundle.info.uris = p.info.uris.clone();
+        match bundle.ipfs_add(&p.api) {
+            Ok(url) => {
+                info!("Published bundle {hash} to IPFS as {url}");
+                bundle.write_bundle_list(None)?;
+                remove(&path)?;
+                pinned.push(Pinned { hash, url });
+            },
+            Err(e) => {
+                warn!("Publishing bundle {hash} to IPFS failed again: {e:#}");
+                p.attempts += 1;
+                p.error = format!("{e:#}");
+                p.save(git_dir)?;
+            },
+        }
+    }
+
+    Ok(pinned)
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
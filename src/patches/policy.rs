@@ -0,0 +1,189 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Acknowledgment of the usage policy of a drop
+//!
+//! A drop may carry a [`metadata::Policy`] in its tree, signed by the root
+//! role, such as a contributor license agreement. Submitters must acknowledge
+//! it before their patches are accepted, by including a [`PolicyAck`] trailer
+//! in the commit of a topic note -- typically the cover letter of their first
+//! patch.
+//!
+//! Acknowledgments are recorded per identity in the [`TREE_ACKS`] tree of the
+//! drop, so subsequent submissions need not carry the trailer again, unless
+//! the policy changes.
+
+use std::io::{
+    self,
+    BufRead as _,
+};
+
+use anyhow::ensure;
+
+use super::{
+    record::Heads,
+    Record,
+};
+use crate::{
+    git::{
+        self,
+        if_not_found_none,
+    },
+    iter::IteratorExt,
+    json,
+    metadata::{
+        self,
+        drop,
+        git::FromGit as _,
+        IdentityId,
+    },
+    Result,
+};
+
+/// Name of the tree holding the acknowledgments, in the drop tree
+pub const TREE_ACKS: &str = "policy-acks";
+
+/// Commit trailer acknowledging the policy document with the given blob id
+pub struct PolicyAck(git2::Oid);
+
+impl PolicyAck {
+    const TRAILER_PREFIX: &str = "Policy-Acknowledged:";
+
+    pub fn new(policy: git2::Oid) -> Self {
+        Self(policy)
+    }
+
+    pub fn from_commit(commit: &git2::Commit) -> Result<Option<Self>> {
+        commit.message_raw_bytes().lines().try_find_map(|line| {
+            line?
+                .strip_prefix(Self::TRAILER_PREFIX)
+                .map(|v| {
+                    git2::Oid::from_str(v.trim())
+                        .map(Self)
+                        .map_err(crate::Error::from)
+                })
+                .transpose()
+        })
+    }
+
+    pub fn as_trailer(&self) -> String {
+        format!("{} {}", Self::TRAILER_PREFIX, self.0)
+    }
+
+    /// The blob id of the acknowledged policy document
+    pub fn oid(&self) -> git2::Oid {
+        self.0
+    }
+}
+
+/// An acknowledgment recorded in the drop
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Ack {
+    /// Blob id of the acknowledged policy document
+    #[serde(with = "git::serde::oid")]
+    pub policy: git2::Oid,
+    /// The patch which carried the acknowledgment
+    pub patch: Heads,
+}
+
+/// The policy of the drop tree `root`, if any
+///
+/// Returns the blob id of the policy document, after verifying that it is
+/// signed by the root role.
+pub fn find(
+    repo: &git2::Repository,
+    root: &git2::Tree,
+    ids: &git2::Tree,
+    meta: &drop::Verified,
+) -> Result<Option<git2::Oid>> {
+    let entry = match root.get_name(metadata::git::META_FILE_POLICY) {
+        None => return Ok(None),
+        Some(entry) => entry,
+    };
+    let blob = entry.to_object(repo)?.peel_to_blob()?;
+    let policy = metadata::Policy::from_blob(&blob)?.signed;
+    meta.verify_policy(&policy, |id| {
+        metadata::identity::find_in_tree(repo, ids, id)
+            .map(|verified| verified.into_parts().1.keys)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    })?;
+
+    Ok(Some(blob.id()))
+}
+
+/// Whether `id` need not acknowledge the policy
+///
+/// Members of the root role have signed the policy in the first place.
+pub fn is_exempt(meta: &drop::Verified, id: &IdentityId) -> bool {
+    meta.roles.root.ids.contains(id)
+}
+
+/// Whether `id` has acknowledged the policy document with blob id `policy`
+pub fn is_acknowledged(
+    repo: &git2::Repository,
+    acks: Option<&git2::Tree>,
+    id: &IdentityId,
+    policy: git2::Oid,
+) -> Result<bool> {
+    let entry = match acks.and_then(|tree| tree.get_name(&id.to_string())) {
+        None => return Ok(false),
+        Some(entry) => entry,
+    };
+    let ack: Ack = json::from_blob(&entry.to_object(repo)?.peel_to_blob()?)?;
+
+    Ok(ack.policy == policy)
+}
+
+/// Find the acknowledgment of `policy` in the topic note conveyed by `record`
+///
+/// The objects of the record's bundle must be present in `repo`.
+pub fn ack_from_record(
+    repo: &git2::Repository,
+    record: &Record,
+    policy: git2::Oid,
+) -> Result<Option<Ack>> {
+    let tip = match record
+        .meta
+        .bundle
+        .references
+        .get(&record.topic.as_refname())
+    {
+        None => return Ok(None),
+        Some(tip) => git2::Oid::try_from(tip)?,
+    };
+    let commit = match if_not_found_none(repo.find_commit(tip))? {
+        None => return Ok(None),
+        Some(commit) => commit,
+    };
+    match PolicyAck::from_commit(&commit)? {
+        None => Ok(None),
+        Some(ack) => {
+            ensure!(
+                ack.oid() == policy,
+                "acknowledged policy {} is not the current policy {policy} of the drop",
+                ack.oid()
+            );
+            Ok(Some(Ack {
+                policy,
+                patch: record.heads,
+            }))
+        },
+    }
+}
+
+/// Record `ack` for `id`, returning the updated acknowledgments tree
+pub fn record_ack<'a>(
+    repo: &'a git2::Repository,
+    acks: Option<&git2::Tree>,
+    id: &IdentityId,
+    ack: &Ack,
+) -> Result<git2::Tree<'a>> {
+    let mut tb = repo.treebuilder(acks)?;
+    tb.insert(
+        id.to_string(),
+        json::to_blob(repo, ack)?,
+        git2::FileMode::Blob.into(),
+    )?;
+
+    Ok(repo.find_tree(tb.write()?)?)
+}
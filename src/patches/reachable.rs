@@ -0,0 +1,198 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Reachability of prerequisite commits from previously accepted patches
+//!
+//! The prerequisites (ie. delta bases) of a patch must be known to the drop,
+//! that is, share history with a ref matching [`GLOB_IT_BUNDLES`]. Usually,
+//! they are reachable from one of those refs, so instead of computing merge
+//! bases against every such ref, [`missing`] walks the history of all of them
+//! at once, stopping as soon as all prerequisites were found. Only the
+//! prerequisites not found this way are checked for a merge base.
+//!
+//! Processes accepting many patches over their lifetime can retain the set of
+//! reachable commits in a [`Cache`]. The cache is keyed by the seen tree,
+//! which changes with every accepted record, and brought up to date by walking
+//! only the history of the refs added since. It holds the ids of all commits
+//! in the drop, so is best reserved for long-running processes.
+
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+};
+
+use log::debug;
+
+use super::GLOB_IT_BUNDLES;
+use crate::{
+    git::if_not_found_none,
+    Result,
+};
+
+#[derive(Default)]
+pub struct Cache(Mutex<Option<Entry>>);
+
+struct Entry {
+    /// The seen tree the entry is current as of
+    seen: git2::Oid,
+    tips: HashSet<git2::Oid>,
+    reachable: HashSet<git2::Oid>,
+}
+
+impl Entry {
+    fn update(repo: &git2::Repository, seen: git2::Oid, prev: Option<Self>) -> Result<Self> {
+        let tips = tips(repo)?;
+        match prev {
+            // Refs were only added, so what was reachable still is
+            Some(mut entry) if entry.tips.is_subset(&tips) => {
+                debug!("updating reachable commits incrementally");
+                let mut walk = repo.revwalk()?;
+                for tip in tips.difference(&entry.tips) {
+                    walk.push(*tip)?;
+                }
+                for tip in &entry.tips {
+                    walk.hide(*tip)?;
+                }
+                for oid in walk {
+                    entry.reachable.insert(oid?);
+                }
+                entry.seen = seen;
+                entry.tips = tips;
+
+                Ok(entry)
+            },
+            _ => {
+                debug!("computing reachable commits");
+                let mut walk = repo.revwalk()?;
+                for tip in &tips {
+                    walk.push(*tip)?;
+                }
+                let reachable = walk.collect::<std::result::Result<_, _>>()?;
+
+                Ok(Self {
+                    seen,
+                    tips,
+                    reachable,
+                })
+            },
+        }
+    }
+}
+
+/// The `prereqs` which do not share history with any ref matching
+/// [`GLOB_IT_BUNDLES`]
+///
+/// `seen` is the id of the seen tree, which is used to determine whether
+/// `cache` is current.
+pub fn missing(
+    repo: &git2::Repository,
+    seen: git2::Oid,
+    prereqs: &[git2::Oid],
+    cache: Option<&Cache>,
+) -> Result<Vec<git2::Oid>> {
+    if prereqs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let (tips, unreachable) = match cache {
+        None => {
+            let tips = tips(repo)?;
+            let mut pending = prereqs
+                .iter()
+                .filter(|oid| !tips.contains(oid))
+                .copied()
+                .collect::<HashSet<_>>();
+            if !pending.is_empty() {
+                // Prerequisites are typically recent, so visit newer commits
+                // first
+                let mut walk = repo.revwalk()?;
+                walk.set_sorting(git2::Sort::TIME)?;
+                for tip in &tips {
+                    walk.push(*tip)?;
+                }
+                for oid in walk {
+                    pending.remove(&oid?);
+                    if pending.is_empty() {
+                        break;
+                    }
+                }
+            }
+
+            let unreachable = prereqs
+                .iter()
+                .filter(|oid| pending.contains(oid))
+                .copied()
+                .collect::<Vec<_>>();
+            (tips, unreachable)
+        },
+
+        Some(cache) => {
+            let mut guard = cache.0.lock().unwrap();
+            let entry = match guard.take() {
+                Some(entry) if entry.seen == seen => entry,
+                prev => Entry::update(repo, seen, prev)?,
+            };
+            let unreachable = prereqs
+                .iter()
+                .filter(|oid| !entry.reachable.contains(oid))
+                .copied()
+                .collect::<Vec<_>>();
+            let tips = if unreachable.is_empty() {
+                HashSet::new()
+            } else {
+                entry.tips.clone()
+            };
+            *guard = Some(entry);
+            (tips, unreachable)
+        },
+    };
+
+    let mut missing = Vec::with_capacity(unreachable.len());
+    for oid in unreachable {
+        if !shares_history(repo, &tips, oid)? {
+            missing.push(oid);
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Whether `oid` has a merge base with any of `tips`
+///
+/// Walks the ancestry of `oid` up to where it meets the history of `tips`, so
+/// is only cheap if `oid` is close to it.
+fn shares_history(
+    repo: &git2::Repository,
+    tips: &HashSet<git2::Oid>,
+    oid: git2::Oid,
+) -> Result<bool> {
+    let mut walk = repo.revwalk()?;
+    if if_not_found_none(walk.push(oid))?.is_none() {
+        return Ok(false);
+    }
+    for tip in tips {
+        walk.hide(*tip)?;
+    }
+    let ancestors = walk.collect::<std::result::Result<HashSet<_>, _>>()?;
+    for ancestor in &ancestors {
+        let commit = repo.find_commit(*ancestor)?;
+        if commit
+            .parent_ids()
+            .any(|parent| !ancestors.contains(&parent))
+        {
+            return Ok(true);
+        }
+    }
+
+    // Empty if `oid` is reachable from `tips`
+    Ok(ancestors.is_empty())
+}
+
+fn tips(repo: &git2::Repository) -> Result<HashSet<git2::Oid>> {
+    let mut tips = HashSet::new();
+    for r in repo.references_glob(GLOB_IT_BUNDLES.glob())? {
+        tips.insert(r?.peel_to_commit()?.id());
+    }
+
+    Ok(tips)
+}
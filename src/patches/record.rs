@@ -1,7 +1,10 @@
 // Copyright © 2022 Kim Altintop <kim@eagain.io>
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
-use core::ops::Deref;
+use core::ops::{
+    AddAssign,
+    Deref,
+};
 use std::{
     collections::{
         BTreeMap,
@@ -25,18 +28,14 @@ use anyhow::{
     ensure,
     Context,
 };
-use digest::Digest;
-use hex::{
-    FromHex,
-    ToHex,
-};
-use sha2::Sha256;
+use hex::FromHex;
 use signature::{
     Signature as _,
     Verifier,
 };
 
 use super::{
+    issues::Issue,
     traits::{
         to_tree,
         BlobData,
@@ -65,15 +64,29 @@ use crate::{
         self,
         identity,
         ContentHash,
+        KeyId,
     },
 };
 
-#[derive(Clone, Copy, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct Heads(#[serde(with = "hex::serde")] [u8; 32]);
+/// Hash over the tips of a patch bundle
+///
+/// Uses the same [`bundle::HashAlgorithm`] as the bundle's [`bundle::Hash`].
+#[derive(
+    Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize,
+)]
+pub struct Heads(bundle::Hash);
 
 impl Heads {
     const TRAILER_PREFIX: &str = "Patch:";
 
+    pub fn from_header(h: &bundle::Header, alg: bundle::HashAlgorithm) -> Self {
+        let tips = h.references.values().collect::<BTreeSet<_>>();
+        Self(bundle::Hash::digest(
+            alg,
+            tips.into_iter().map(bundle::ObjectId::as_bytes),
+        ))
+    }
+
     pub fn from_commit(commit: &git2::Commit) -> crate::Result<Option<Self>> {
         commit.message_raw_bytes().lines().try_find_map(|line| {
             line?
@@ -89,30 +102,13 @@ impl Heads {
 }
 
 impl Deref for Heads {
-    type Target = [u8; 32];
+    type Target = bundle::Hash;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl AsRef<[u8]> for Heads {
-    fn as_ref(&self) -> &[u8] {
-        &self.0
-    }
-}
-
-impl From<&bundle::Header> for Heads {
-    fn from(h: &bundle::Header) -> Self {
-        let tips = h.references.values().collect::<BTreeSet<_>>();
-        let mut hasher = Sha256::new();
-        for sha in tips {
-            hasher.update(sha.as_bytes());
-        }
-        Self(hasher.finalize().into())
-    }
-}
-
 impl TryFrom<&git2::Commit<'_>> for Heads {
     type Error = crate::Error;
 
@@ -127,44 +123,36 @@ impl TryFrom<&git2::Commit<'_>> for Heads {
 }
 
 impl FromStr for Heads {
-    type Err = hex::FromHexError;
+    type Err = bundle::error::Hash;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Self::from_hex(s)
-    }
-}
-
-impl FromHex for Heads {
-    type Error = hex::FromHexError;
-
-    fn from_hex<T: AsRef<[u8]>>(hex: T) -> Result<Self, Self::Error> {
-        <[u8; 32]>::from_hex(hex).map(Self)
+        s.parse().map(Self)
     }
 }
 
 impl fmt::Display for Heads {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&hex::encode(self.0))
+        fmt::Display::fmt(&self.0, f)
     }
 }
 
 impl fmt::Debug for Heads {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&hex::encode(self.0))
+        fmt::Display::fmt(&self.0, f)
     }
 }
 
 impl BlobData for Heads {
-    type Error = <[u8; 32] as FromHex>::Error;
+    type Error = bundle::error::Hash;
 
-    const MAX_BYTES: usize = 64;
+    const MAX_BYTES: usize = 68;
 
     fn from_blob(data: &[u8]) -> Result<Self, Self::Error> {
-        Self::from_hex(data)
+        bundle::Hash::from_hex(data).map(Self)
     }
 
     fn write_blob<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
-        writer.write_all(self.encode_hex::<String>().as_bytes())
+        writer.write_all(self.to_string().as_bytes())
     }
 }
 
@@ -174,7 +162,7 @@ impl TreeData for Heads {
 
 impl Foldable for Heads {
     fn folded_name(&self) -> String {
-        self.encode_hex()
+        self.to_string()
     }
 }
 
@@ -184,33 +172,24 @@ pub struct Signature {
     pub signature: metadata::Signature,
 }
 
-impl From<Signature> for tiny_http::Header {
-    fn from(s: Signature) -> Self {
-        let value = format!(
+impl Signature {
+    /// Encode as the value of a [`HTTP_HEADER_SIGNATURE`] header
+    pub fn to_header_value(&self) -> String {
+        format!(
             "s1={}; s2={}; sd={}",
-            hex::encode(s.signer.sha1),
-            hex::encode(s.signer.sha2),
-            hex::encode(s.signature.as_ref())
-        );
-
-        Self::from_bytes(HTTP_HEADER_SIGNATURE.as_bytes(), value).unwrap()
+            hex::encode(self.signer.sha1),
+            hex::encode(self.signer.sha2),
+            hex::encode(self.signature.as_ref())
+        )
     }
-}
-
-impl TryFrom<&tiny_http::Header> for Signature {
-    type Error = crate::Error;
-
-    fn try_from(hdr: &tiny_http::Header) -> Result<Self, Self::Error> {
-        ensure!(
-            hdr.field.equiv(HTTP_HEADER_SIGNATURE),
-            "not a {HTTP_HEADER_SIGNATURE} header"
-        );
 
+    /// Decode from the value of a [`HTTP_HEADER_SIGNATURE`] header
+    pub fn from_header_value(value: &str) -> crate::Result<Self> {
         let mut sha1: Option<[u8; 20]> = None;
         let mut sha2: Option<[u8; 32]> = None;
         let mut signature = None;
-        for part in hdr.value.as_str().split(';') {
-            match part.trim().split_at(2) {
+        for part in value.split(';') {
+            match part.trim().split_once('=').unwrap_or_default() {
                 ("s1", val) => {
                     let bytes = <[u8; 20]>::from_hex(val)?;
                     sha1 = Some(bytes);
@@ -239,10 +218,38 @@ impl TryFrom<&tiny_http::Header> for Signature {
     }
 }
 
+#[cfg(feature = "cli")]
+impl From<Signature> for tiny_http::Header {
+    fn from(s: Signature) -> Self {
+        Self::from_bytes(HTTP_HEADER_SIGNATURE.as_bytes(), s.to_header_value()).unwrap()
+    }
+}
+
+#[cfg(feature = "cli")]
+impl TryFrom<&tiny_http::Header> for Signature {
+    type Error = crate::Error;
+
+    fn try_from(hdr: &tiny_http::Header) -> Result<Self, Self::Error> {
+        ensure!(
+            hdr.field.equiv(HTTP_HEADER_SIGNATURE),
+            "not a {HTTP_HEADER_SIGNATURE} header"
+        );
+        Self::from_header_value(hdr.value.as_str())
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Meta {
     pub bundle: BundleInfo,
     pub signature: Signature,
+    /// Summary of the changes to the branches of the patch, absent for
+    /// encrypted bundles and patches which only update notes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diffstat: Option<Diffstat>,
+    /// Issues referenced by trailers of the commits of the patch, cf.
+    /// [`super::issues`]
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub issues: BTreeSet<Issue>,
 }
 
 impl BlobData for Meta {
@@ -263,6 +270,87 @@ impl TreeData for Meta {
     const BLOB_NAME: &'static str = BLOB_META;
 }
 
+/// Number of files changed and lines inserted / deleted by a patch
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Diffstat {
+    pub files: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+impl Diffstat {
+    /// Compute the diffstat of the branches in the bundle `header`
+    ///
+    /// Each branch is compared against the first-parent ancestor of its
+    /// oldest commit not among the prerequisites, or the empty tree if there
+    /// is none. The objects of the bundle must be present in `repo`. Returns
+    /// `None` if the bundle doesn't contain any branches.
+    pub fn compute(
+        repo: &git2::Repository,
+        header: &bundle::Header,
+    ) -> crate::Result<Option<Self>> {
+        let prereqs = header
+            .prerequisites
+            .iter()
+            .map(git2::Oid::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut total = None;
+        for (name, oid) in &header.references {
+            // Identity updates are conveyed as branches, too
+            if !name.starts_with("refs/heads/") || name.starts_with("refs/heads/it/") {
+                continue;
+            }
+            let tip = repo.find_commit(oid.try_into()?)?;
+            let mut walk = repo.revwalk()?;
+            walk.push(tip.id())?;
+            for hide in &prereqs {
+                walk.hide(*hide)?;
+            }
+            walk.simplify_first_parent()?;
+            let first = match walk.last() {
+                None => continue,
+                Some(oid) => repo.find_commit(oid?)?,
+            };
+            let base = match first.parents().next() {
+                None => None,
+                Some(parent) => Some(parent.tree()?),
+            };
+            let stats = repo
+                .diff_tree_to_tree(base.as_ref(), Some(&tip.tree()?), None)?
+                .stats()?;
+            *total.get_or_insert_with(Self::default) += Self {
+                files: stats.files_changed(),
+                insertions: stats.insertions(),
+                deletions: stats.deletions(),
+            };
+        }
+
+        Ok(total)
+    }
+}
+
+impl AddAssign for Diffstat {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+    }
+}
+
+impl fmt::Display for Diffstat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "+{}/-{}, {} file{}",
+            self.insertions,
+            self.deletions,
+            self.files,
+            if self.files == 1 { "" } else { "s" }
+        )
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Encryption {
@@ -372,6 +460,7 @@ impl Record {
         signer: &mut S,
         repo: &git2::Repository,
         ids: &git2::Tree,
+        acks: Option<&git2::Tree>,
         parent: Option<&git2::Commit>,
         seen: Option<&mut git2::TreeBuilder>,
     ) -> crate::Result<git2::Oid>
@@ -381,6 +470,13 @@ impl Record {
         let tree = {
             let mut tb = repo.treebuilder(parent.map(|p| p.tree()).transpose()?.as_ref())?;
             tb.insert("ids", ids.id(), git2::FileMode::Tree.into())?;
+            if let Some(acks) = acks {
+                tb.insert(
+                    super::policy::TREE_ACKS,
+                    acks.id(),
+                    git2::FileMode::Tree.into(),
+                )?;
+            }
             to_tree(repo, &mut tb, &self.heads)?;
             to_tree(repo, &mut tb, &self.meta)?;
             repo.find_tree(tb.write()?)?
@@ -407,11 +503,13 @@ impl Record {
         Ok(oid)
     }
 
-    pub fn signed_part(&self) -> [u8; 32] {
-        *self.heads
+    pub fn signed_part(&self) -> Vec<u8> {
+        self.heads.to_bytes().into_owned()
     }
 
-    pub fn verify_signature<F>(&self, mut find_id: F) -> crate::Result<()>
+    /// Verify the signature over the record, returning the id of the key
+    /// which made it
+    pub fn verify_signature<F>(&self, mut find_id: F) -> crate::Result<KeyId>
     where
         F: FnMut(&ContentHash) -> crate::Result<identity::Verified>,
     {
@@ -420,9 +518,11 @@ impl Record {
         let signature = &self.meta.signature.signature;
         let id =
             find_id(addr).with_context(|| format!("invalid or non-existent id at {:?}", addr))?;
-        for key in id.identity().keys.values() {
+        // Bot keys may have expired since the record was made
+        let bot_keys = id.identity().bots.iter().map(|(id, bot)| (id, &bot.key));
+        for (key_id, key) in id.identity().keys.iter().chain(bot_keys) {
             if key.verify(&signed_data, signature).is_ok() {
-                return Ok(());
+                return Ok(*key_id);
             }
         }
         bail!("signature key not in id at {:?}", addr);
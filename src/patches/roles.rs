@@ -0,0 +1,140 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Resolved role membership of a drop
+//!
+//! The drop metadata only lists the [`IdentityId`]s of each role. [`resolve`]
+//! looks up and verifies the identities in the `ids` tree of the drop, so
+//! tools can determine who can currently do what on the drop without
+//! re-implementing identity verification.
+
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+};
+
+use super::DropHead;
+use crate::{
+    git::Refname,
+    metadata::{
+        self,
+        identity,
+        DateTime,
+        IdentityId,
+        KeyId,
+    },
+};
+
+#[derive(serde::Serialize)]
+pub struct Roles {
+    pub root: Role,
+    pub snapshot: Role,
+    pub mirrors: Role,
+    pub branches: BTreeMap<Refname, BranchRole>,
+}
+
+#[derive(serde::Serialize)]
+pub struct BranchRole {
+    #[serde(flatten)]
+    pub role: Role,
+    pub description: metadata::drop::Description,
+}
+
+#[derive(serde::Serialize)]
+pub struct Role {
+    /// Number of signatures required, as stated in the drop metadata
+    pub threshold: NonZeroUsize,
+    /// Number of members whose identity could be verified
+    pub verified: usize,
+    /// Whether enough members have a valid identity to meet the threshold
+    ///
+    /// If not, the role can not currently act.
+    pub satisfiable: bool,
+    pub members: Vec<Member>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct Member {
+    pub id: IdentityId,
+    /// The current keys of the identity, if it could be verified
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<KeyId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime>,
+    /// Why the identity could not be verified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Member {
+    pub fn is_verified(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Resolve the members of all roles of `drop`
+///
+/// Identities which are missing from the drop, or fail to verify, are
+/// included with an [`Member::error`] instead of failing the whole operation.
+pub fn resolve(repo: &git2::Repository, drop: &DropHead) -> crate::Result<Roles> {
+    let mut cache = BTreeMap::new();
+    let mut role = |role: &metadata::drop::Role| {
+        let members = role
+            .ids
+            .iter()
+            .map(|id| {
+                cache
+                    .entry(*id)
+                    .or_insert_with(|| member(repo, &drop.ids, id))
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        let verified = members.iter().filter(|m| m.is_verified()).count();
+        Role {
+            threshold: role.threshold,
+            verified,
+            satisfiable: verified >= role.threshold.get(),
+            members,
+        }
+    };
+
+    let meta = &drop.meta.roles;
+    Ok(Roles {
+        root: role(&meta.root),
+        snapshot: role(&meta.snapshot),
+        mirrors: role(&meta.mirrors),
+        branches: meta
+            .branches
+            .iter()
+            .map(|(name, ann)| {
+                (
+                    name.clone(),
+                    BranchRole {
+                        role: role(&ann.role),
+                        description: ann.description.clone(),
+                    },
+                )
+            })
+            .collect(),
+    })
+}
+
+fn member(repo: &git2::Repository, ids: &git2::Tree, id: &IdentityId) -> Member {
+    match identity::find_in_tree(repo, ids, id) {
+        Ok(verified) => {
+            let (id, cur) = verified.into_parts();
+            Member {
+                id,
+                keys: cur.keys.keys().cloned().collect(),
+                expires: cur.expires,
+                error: None,
+            }
+        },
+        Err(e) => Member {
+            id: *id,
+            keys: vec![],
+            expires: None,
+            error: Some(format!("{e:#}")),
+        },
+    }
+}
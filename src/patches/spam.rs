@@ -0,0 +1,335 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Anti-spam policy for submissions
+//!
+//! [`super::AcceptOptions`] limit what a single submission may convey. On top
+//! of that, drop operators may want to restrict who submits, and how often. A
+//! [`Policy`] is consulted by [`super::Submission::try_accept`] once the
+//! submitter and topic of a submission are known, and the signature is
+//! verified, but before the bundle is indexed.
+//!
+//! The default implementation, [`Rules`], is read from a JSON file, eg.:
+//!
+//! ```json
+//! {
+//!     "deny": ["<identity id>"],
+//!     "min_identity_age_secs": 86400,
+//!     "max_submissions": 10,
+//!     "window_secs": 3600,
+//!     "max_bundle_len": { "patch": 1000000, "comment": 50000 }
+//! }
+//! ```
+
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+        HashMap,
+    },
+    fmt,
+    fs,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use log::warn;
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use super::{
+    Record,
+    Topic,
+};
+use crate::{
+    metadata::IdentityId,
+    Result,
+};
+
+/// Decides whether a submission may be accepted
+pub trait Policy: Send + Sync {
+    /// Check `sub`, failing with [`Rejected`] if it is not acceptable
+    ///
+    /// Other errors are treated as failures to evaluate the policy.
+    fn check(&self, sub: &Candidate) -> Result<()>;
+}
+
+/// A submission, as presented to a [`Policy`]
+pub struct Candidate<'a> {
+    pub repo: &'a git2::Repository,
+    /// The drop history the submission would be recorded in
+    pub drop_ref: &'a str,
+    pub submitter: &'a IdentityId,
+    /// Content hashes of all revisions of the submitter's identity
+    ///
+    /// The signer of any record submitted by the same identity is one of
+    /// these.
+    pub revisions: &'a BTreeSet<git2::Oid>,
+    /// Whether the drop already knew about the submitter
+    pub known: bool,
+    /// Whether the submitter is a member of the root role, or submits a
+    /// checkpoint their role permits
+    pub privileged: bool,
+    pub kind: Kind,
+    pub topic: &'a Topic,
+    /// Length of the bundle in bytes
+    pub bundle_len: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Patch,
+    Comment,
+    Merges,
+    Snapshot,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Patch => "patch",
+            Self::Comment => "comment",
+            Self::Merges => "merges",
+            Self::Snapshot => "snapshot",
+        })
+    }
+}
+
+/// A submission was rejected by a [`Policy`]
+#[derive(Debug, Error)]
+pub enum Rejected {
+    #[error("submissions by {0} are not permitted")]
+    Denied(IdentityId),
+    #[error("identity {id} must be known to the drop for {min}s before submitting a {kind}")]
+    TooNew {
+        id: IdentityId,
+        kind: Kind,
+        min: u64,
+    },
+    #[error("{id} exceeded {max} submissions per {window}s, retry in {}s", retry_after.as_secs() + 1)]
+    Rate {
+        id: IdentityId,
+        max: usize,
+        window: u64,
+        retry_after: Duration,
+    },
+    #[error("{kind} bundle exceeds {max} bytes ({len})")]
+    TooLarge { kind: Kind, len: u64, max: u64 },
+}
+
+/// Configurable [`Policy`]
+///
+/// The default is to not impose any rules. Privileged submitters (cf.
+/// [`Candidate::privileged`]) are only subject to `deny`.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// If not empty, only these identities may submit
+    pub allow: BTreeSet<IdentityId>,
+    /// Identities which may not submit
+    pub deny: BTreeSet<IdentityId>,
+    /// Number of seconds which must have passed since the first record by an
+    /// identity before it may submit anything but comments
+    pub min_identity_age_secs: Option<u64>,
+    /// Maximum number of records per identity within `window_secs`
+    pub max_submissions: Option<NonZeroUsize>,
+    /// Default: 3600
+    pub window_secs: Option<u64>,
+    /// Maximum length of the bundle in bytes, by kind of submission
+    pub max_bundle_len: BTreeMap<Kind, u64>,
+    #[serde(skip)]
+    first_seen: FirstSeen,
+}
+
+/// Time of the earliest record found for an identity, by drop ref
+///
+/// Once an identity is old enough, the drop history need not be scanned for
+/// its first record again. Shared between clones of [`Rules`].
+#[derive(Clone, Debug, Default)]
+struct FirstSeen(Arc<Mutex<HashMap<(String, IdentityId), i64>>>);
+
+impl FirstSeen {
+    fn get(&self, drop_ref: &str, id: &IdentityId) -> Option<i64> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&(drop_ref.to_owned(), *id))
+            .copied()
+    }
+
+    fn update(&self, drop_ref: &str, id: &IdentityId, time: i64) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry((drop_ref.to_owned(), *id))
+            .and_modify(|t| *t = (*t).min(time))
+            .or_insert(time);
+    }
+}
+
+impl Rules {
+    const DEFAULT_WINDOW_SECS: u64 = 60 * 60;
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = fs::read(path)
+            .with_context(|| format!("failed to read spam policy {}", path.display()))?;
+        serde_json::from_slice(&json)
+            .with_context(|| format!("invalid spam policy {}", path.display()))
+    }
+
+    fn window_secs(&self) -> u64 {
+        self.window_secs.unwrap_or(Self::DEFAULT_WINDOW_SECS)
+    }
+}
+
+impl Policy for Rules {
+    fn check(&self, sub: &Candidate) -> Result<()> {
+        let id = sub.submitter;
+        if self.deny.contains(id) {
+            return Err(Rejected::Denied(*id).into());
+        }
+        if sub.privileged {
+            return Ok(());
+        }
+        if !self.allow.is_empty() && !self.allow.contains(id) {
+            return Err(Rejected::Denied(*id).into());
+        }
+        if let Some(max) = self.max_bundle_len.get(&sub.kind) {
+            if sub.bundle_len > *max {
+                return Err(Rejected::TooLarge {
+                    kind: sub.kind,
+                    len: sub.bundle_len,
+                    max: *max,
+                }
+                .into());
+            }
+        }
+
+        let min_age = self
+            .min_identity_age_secs
+            .filter(|_| sub.kind != Kind::Comment);
+        if min_age.is_none() && self.max_submissions.is_none() {
+            return Ok(());
+        }
+        let history = History::scan(
+            sub,
+            min_age.unwrap_or(0),
+            self.window_secs(),
+            self.first_seen.get(sub.drop_ref, id),
+        )?;
+        if let Some(earliest) = history.earliest {
+            self.first_seen.update(sub.drop_ref, id, earliest);
+        }
+        if let Some(min) = min_age {
+            if !history.old_enough {
+                return Err(Rejected::TooNew {
+                    id: *id,
+                    kind: sub.kind,
+                    min,
+                }
+                .into());
+            }
+        }
+        if let Some(max) = self.max_submissions.map(NonZeroUsize::get) {
+            if history.recent.len() >= max {
+                let window = self.window_secs();
+                // Once the max-th most recent record falls out of the window,
+                // the identity is below the limit again
+                let expires = history.recent[max - 1] + window as i64;
+                let retry_after = expires.saturating_sub(history.now).max(0) as u64;
+                return Err(Rejected::Rate {
+                    id: *id,
+                    max,
+                    window,
+                    retry_after: Duration::from_secs(retry_after),
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What the drop history tells about previous submissions by an identity
+struct History {
+    now: i64,
+    /// Whether a record by the identity is at least `min_age` old
+    old_enough: bool,
+    /// Times of the records by the identity within the window, most recent
+    /// first
+    recent: Vec<i64>,
+    /// Time of the earliest record by the identity encountered, or known
+    /// beforehand
+    earliest: Option<i64>,
+}
+
+impl History {
+    /// Scan the drop history for records by the submitter of `sub`
+    ///
+    /// `first_seen` is the time of the earliest record by the submitter known
+    /// from a previous scan, if any. If it is at least `min_age` old, only the
+    /// `window` needs to be scanned.
+    fn scan(sub: &Candidate, min_age: u64, window: u64, first_seen: Option<i64>) -> Result<Self> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let min_age_cutoff = now - min_age as i64;
+        let window_cutoff = now - window as i64;
+
+        let mut old_enough = min_age == 0 || first_seen.map_or(false, |t| t <= min_age_cutoff);
+        let mut earliest = first_seen;
+        let mut recent = Vec::new();
+        let mut walk = sub.repo.revwalk()?;
+        walk.push_ref(sub.drop_ref)?;
+        walk.set_sorting(git2::Sort::TIME)?;
+        for oid in walk {
+            let commit = sub.repo.find_commit(oid?)?;
+            let time = commit.time().seconds();
+            if old_enough && time < window_cutoff {
+                break;
+            }
+            match Topic::from_commit(&commit) {
+                Ok(Some(_)) => {},
+                // Not a record, eg. a drop metadata update
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Skipping commit {} with invalid topic: {e:#}", commit.id());
+                    continue;
+                },
+            }
+            let record = match Record::from_commit(sub.repo, &commit) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Skipping invalid record {}: {e:#}", commit.id());
+                    continue;
+                },
+            };
+            if !sub
+                .revisions
+                .contains(&git2::Oid::from(&record.meta.signature.signer))
+            {
+                continue;
+            }
+            if time >= window_cutoff {
+                recent.push(time);
+            }
+            if time <= min_age_cutoff {
+                old_enough = true;
+            }
+            earliest = Some(earliest.map_or(time, |t| t.min(time)));
+        }
+
+        Ok(Self {
+            now,
+            old_enough,
+            recent,
+            earliest,
+        })
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Detection of patches merged as squash commits
+//!
+//! A patch is considered squash-merged into a branch if a (non-merge) commit
+//! on the branch has the same patch-id as the combined diff of the patch, ie.
+//! the diff between its prerequisite and its tip.
+
+use std::collections::HashMap;
+
+use log::debug;
+
+use super::{
+    iter::dropped,
+    notes::Squashed,
+    GLOB_HEADS,
+};
+use crate::{
+    bundle::ObjectId,
+    Result,
+};
+
+/// Find the patches recorded in `drop_ref` of `drp` which were squash-merged
+/// as one of `commits` of `src`
+pub fn detect<I>(
+    drp: &git2::Repository,
+    drop_ref: &str,
+    src: &git2::Repository,
+    commits: I,
+) -> Result<Vec<Squashed>>
+where
+    I: IntoIterator<Item = git2::Oid>,
+{
+    let mut candidates = HashMap::new();
+    for oid in commits {
+        let commit = src.find_commit(oid)?;
+        if commit.parent_count() != 1 {
+            continue;
+        }
+        let parent = commit.parent(0)?.tree()?;
+        let id = patch_id(src, &parent, &commit.tree()?)?;
+        candidates.insert(id, oid);
+    }
+    if candidates.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let heads = GLOB_HEADS.compile_matcher();
+    let mut squashed = Vec::new();
+    for record in dropped::records(drp, drop_ref) {
+        let record = record?;
+        if record.is_mergepoint() || record.is_snapshot() || record.is_encrypted() {
+            continue;
+        }
+        let bundle = record.bundle_info();
+        let base = match bundle.prerequisites.iter().collect::<Vec<_>>()[..] {
+            [base] => base,
+            _ => continue,
+        };
+        for (name, tip) in &bundle.references {
+            if !heads.is_match(&**name) {
+                continue;
+            }
+            let range = || -> Result<git2::Oid> {
+                let base = drp.find_commit(base.try_into()?)?.tree()?;
+                let tip = drp.find_commit(tip.try_into()?)?.tree()?;
+                patch_id(drp, &base, &tip)
+            };
+            match range() {
+                Ok(id) => {
+                    if let Some(commit) = candidates.get(&id) {
+                        // Not a squash if the patch was applied as-is
+                        if ObjectId::from(commit) != *tip {
+                            squashed.push(Squashed {
+                                topic: record.topic.clone(),
+                                patch: record.heads,
+                                commit: commit.into(),
+                            });
+                        }
+                    }
+                },
+                Err(e) => debug!("skipping {} of {}: {e:#}", name, record.heads),
+            }
+        }
+    }
+
+    Ok(squashed)
+}
+
+fn patch_id(repo: &git2::Repository, old: &git2::Tree, new: &git2::Tree) -> Result<git2::Oid> {
+    let diff = repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+    Ok(diff.patchid(None)?)
+}
@@ -0,0 +1,52 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Patch submission over SSH
+//!
+//! Submitting to an `ssh://` URL runs [`REMOTE_COMMAND`] on the remote host,
+//! with the path of the URL (if any) as its `--git-dir`. The client
+//! writes a [`Request`] as a single line of JSON to its standard input,
+//! followed by exactly [`Request::len`] bytes of the bundle. The server
+//! replies with a [`Response`], also as a single line of JSON, on its standard
+//! output.
+//!
+//! Authentication is left to SSH, so drops will typically restrict the keys
+//! allowed to log in to the forced command `it drop serve-ssh` in their
+//! `authorized_keys`.
+
+use super::{
+    record::Signature,
+    Record,
+};
+use crate::bundle;
+
+/// Command run on the remote host
+pub const REMOTE_COMMAND: &[&str] = &["it", "drop", "serve-ssh"];
+/// Maximum length of the [`Request`] line
+pub const MAX_LEN_REQUEST: u64 = 16 * 1024;
+
+/// Announcement of the bundle to follow
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Request {
+    pub signature: Signature,
+    #[serde(default)]
+    pub hash_algorithm: bundle::HashAlgorithm,
+    /// Length of the bundle in bytes
+    pub len: u64,
+}
+
+/// Outcome of a submission
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Response {
+    Accepted {
+        record: Record,
+    },
+    /// Accepted, but held for review by the drop operators
+    Quarantined {
+        record: Record,
+    },
+    Rejected {
+        reason: String,
+    },
+}
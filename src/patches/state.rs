@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
+    cell::Cell,
     io,
     ops::Range,
+    sync::Mutex,
 };
 
 use anyhow::{
@@ -11,13 +13,20 @@ use anyhow::{
     ensure,
     Context,
 };
-use log::warn;
+use log::{
+    debug,
+    warn,
+};
 
 use super::{
+    policy,
+    IdentityRevision,
     Record,
     TrackingBranch,
+    TOPIC_MERGES,
 };
 use crate::{
+    bundle,
     git::{
         self,
         if_not_found_none,
@@ -30,41 +39,231 @@ use crate::{
     keys::VerificationKey,
     metadata::{
         self,
-        git::FromGit,
-        identity,
+        git::{
+            FromGit,
+            GitMeta,
+        },
+        identity::{
+            self,
+            ExpiryPolicy,
+        },
+        DateTime,
+        IdentityId,
+        KeyId,
     },
     Result,
 };
 
+const REFLOG_UPDATE_TIP: &str = "it: update tip from ";
+
 /// Somewhat ad-hoc view of the tip of a drop
 pub struct DropHead<'a> {
     pub tip: git2::Reference<'a>,
     pub ids: git2::Tree<'a>,
     pub meta: metadata::drop::Verified,
+    /// Blob id of the verified usage policy, if the drop has one
+    pub policy: Option<git2::Oid>,
+    /// Acknowledgments of the usage policy, see [`policy`]
+    pub acks: Option<git2::Tree<'a>>,
 }
 
 impl<'a> DropHead<'a> {
     pub fn from_refname<S: AsRef<str>>(repo: &'a git2::Repository, name: S) -> crate::Result<Self> {
+        Self::from_refname_cached(repo, name, None, ExpiryPolicy::Strict)
+    }
+
+    /// Like [`Self::from_refname`], but skip verification of the drop
+    /// metadata if it is unchanged since it was stored in `cache`
+    ///
+    /// Expired identities of role members are treated according to `expiry`.
+    pub fn from_refname_cached<S: AsRef<str>>(
+        repo: &'a git2::Repository,
+        name: S,
+        cache: Option<&DropHeadCache>,
+        expiry: ExpiryPolicy,
+    ) -> crate::Result<Self> {
         let tip = repo.find_reference(name.as_ref())?;
         let root = tip.peel_to_tree()?;
-        let ids = root
-            .get_name("ids")
-            .ok_or_else(|| anyhow!("invalid drop: 'ids' tree not found"))?
-            .to_object(repo)?
-            .into_tree()
-            .map_err(|_| anyhow!("invalid drop: 'ids' tree is not a tree"))?;
-        let meta = metadata::Drop::from_tree(repo, &root)
-            .context("error loading drop metadata")?
-            .verified(metadata::git::find_parent(repo), |id| {
-                metadata::identity::find_in_tree(repo, &ids, id)
-                    .map(|verified| verified.into_parts().1.keys)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-            })?;
-
-        Ok(Self { tip, ids, meta })
+        let (ids, meta, policy) = match cache {
+            None => verified_tree_and_policy(repo, &root, expiry)?,
+            Some(cache) => cache.get_or_verify(repo, &root, expiry)?,
+        };
+        let acks = root
+            .get_name(policy::TREE_ACKS)
+            .map(|entry| entry.to_object(repo)?.peel_to_tree())
+            .transpose()?;
+
+        Ok(Self {
+            tip,
+            ids,
+            meta,
+            policy,
+            acks,
+        })
     }
 }
 
+/// Verified drop metadata, reused across [`DropHead`]s
+///
+/// Verifying the drop metadata entails verifying the identities of all role
+/// members, which is wasteful to repeat for every patch accepted by a
+/// long-running process. Entries are keyed by the object ids of the metadata,
+/// identities, and policy, so a new record only invalidates the cache if it
+/// changes any of those. An entry is also invalidated once any of the
+/// identities involved in verifying it expires, unless expired identities are
+/// tolerated.
+#[derive(Default)]
+pub struct DropHeadCache(Mutex<Option<CacheEntry>>);
+
+struct CacheEntry {
+    key: CacheKey,
+    meta: metadata::drop::Verified,
+    policy: Option<git2::Oid>,
+    /// Earliest expiry of the identities the metadata was verified with
+    expires: Option<DateTime>,
+    expiry: ExpiryPolicy,
+}
+
+#[derive(PartialEq)]
+struct CacheKey {
+    meta: Option<git2::Oid>,
+    ids: Option<git2::Oid>,
+    policy: Option<git2::Oid>,
+}
+
+impl CacheKey {
+    fn of(root: &git2::Tree) -> Self {
+        let id = |name| root.get_name(name).map(|entry| entry.id());
+        Self {
+            meta: id(metadata::git::META_FILE_DROP),
+            ids: id("ids"),
+            policy: id(metadata::git::META_FILE_POLICY),
+        }
+    }
+}
+
+impl DropHeadCache {
+    fn get_or_verify<'a>(
+        &self,
+        repo: &'a git2::Repository,
+        root: &git2::Tree,
+        expiry: ExpiryPolicy,
+    ) -> Result<(git2::Tree<'a>, metadata::drop::Verified, Option<git2::Oid>)> {
+        let key = CacheKey::of(root);
+        let mut entry = self.0.lock().unwrap();
+        let valid = |entry: &&CacheEntry| {
+            entry.key == key
+                && ((entry.expiry == ExpiryPolicy::Warn && expiry == ExpiryPolicy::Warn)
+                    || entry.expires.map_or(true, |exp| exp > DateTime::now()))
+        };
+        if let Some(hit) = entry.as_ref().filter(valid) {
+            debug!("using cached drop metadata");
+            let ids = ids_tree(repo, root)?;
+            return Ok((ids, hit.meta.clone(), hit.policy));
+        }
+        let (ids, meta, expires) = verified_tree_expiring(repo, root, expiry)?;
+        let policy = policy::find(repo, root, &ids, &meta).context("invalid drop policy")?;
+        *entry = Some(CacheEntry {
+            key,
+            meta: meta.clone(),
+            policy,
+            expires,
+            expiry,
+        });
+
+        Ok((ids, meta, policy))
+    }
+}
+
+fn verified_tree_and_policy<'a>(
+    repo: &'a git2::Repository,
+    root: &git2::Tree,
+    expiry: ExpiryPolicy,
+) -> Result<(git2::Tree<'a>, metadata::drop::Verified, Option<git2::Oid>)> {
+    let (ids, meta, _) = verified_tree_expiring(repo, root, expiry)?;
+    let policy = policy::find(repo, root, &ids, &meta).context("invalid drop policy")?;
+
+    Ok((ids, meta, policy))
+}
+
+fn ids_tree<'a>(repo: &'a git2::Repository, root: &git2::Tree) -> Result<git2::Tree<'a>> {
+    root.get_name("ids")
+        .ok_or_else(|| anyhow!("invalid drop: 'ids' tree not found"))?
+        .to_object(repo)?
+        .into_tree()
+        .map_err(|_| anyhow!("invalid drop: 'ids' tree is not a tree"))
+}
+
+/// The identities and verified drop metadata stored in the drop tree `root`
+pub fn verified_tree<'a>(
+    repo: &'a git2::Repository,
+    root: &git2::Tree,
+) -> Result<(git2::Tree<'a>, metadata::drop::Verified)> {
+    verified_tree_expiring(repo, root, ExpiryPolicy::Strict).map(|(ids, meta, _)| (ids, meta))
+}
+
+/// Like [`verified_tree`], but also return the earliest expiry of the
+/// identities consulted during verification
+fn verified_tree_expiring<'a>(
+    repo: &'a git2::Repository,
+    root: &git2::Tree,
+    expiry: ExpiryPolicy,
+) -> Result<(git2::Tree<'a>, metadata::drop::Verified, Option<DateTime>)> {
+    let ids = ids_tree(repo, root)?;
+    let expires = Cell::new(None::<DateTime>);
+    let meta = metadata::Drop::from_tree(repo, root)
+        .context("error loading drop metadata")?
+        .verified(metadata::git::find_parent(repo), |id| {
+            metadata::identity::find_in_tree_with(repo, &ids, id, expiry)
+                .map(|verified| {
+                    let cur = verified.into_parts().1;
+                    if let Some(exp) = cur.expires {
+                        expires.set(Some(expires.get().map_or(exp, |prev| prev.min(exp))));
+                    }
+                    cur.keys
+                })
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        })?;
+
+    Ok((ids, meta, expires.get()))
+}
+
+/// Materialise the refs conveyed by `record`
+///
+/// That is, store its refs below `ref_prefix`, merge its notes into the topic
+/// ref, and update the tracking branches if it is a merge record. The bundle's
+/// objects must already be present in `repo`.
+pub fn materialise(
+    repo: &git2::Repository,
+    tx: &mut refs::Transaction,
+    ref_prefix: &str,
+    submitter: &identity::Verified,
+    meta: &metadata::drop::Verified,
+    record: &Record,
+) -> Result<Vec<(Refname, git2::Oid)>> {
+    let updated = unbundle(&repo.odb()?, tx, ref_prefix, record)?;
+    let topic_ref = tx.lock_ref(record.topic.as_refname())?;
+    merge_notes(repo, submitter, &topic_ref, record)?;
+    if record.topic == *TOPIC_MERGES {
+        update_branches(repo, tx, submitter, meta, record)?;
+    }
+
+    Ok(updated)
+}
+
+/// Whether all refs conveyed by `record` are present below `ref_prefix`
+pub fn is_unbundled(repo: &git2::Repository, ref_prefix: &str, record: &Record) -> Result<bool> {
+    for (name, oid) in &record.meta.bundle.references {
+        let refname = unbundled_ref(ref_prefix, record, name)?;
+        match if_not_found_none(repo.refname_to_id(&refname))? {
+            Some(have) if have == git2::Oid::try_from(oid)? => continue,
+            _ => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
 pub fn unbundle(
     odb: &git2::Odb,
     tx: &mut refs::Transaction,
@@ -171,7 +370,7 @@ pub fn update_branches(
         .iter()
         .filter_map(|(name, role)| role.role.ids.contains(submitter.id()).then_some(name));
     for branch in branches {
-        let sandboxed = match TrackingBranch::try_from(branch) {
+        let sandboxed = match TrackingBranch::for_branch(branch) {
             Ok(tracking) => tracking.into_refname(),
             Err(e) => {
                 warn!("Skipping invalid branch {branch}: {e}");
@@ -183,7 +382,7 @@ pub fn update_branches(
             let target = git2::Oid::try_from(target)?;
             let locked = tx.lock_ref(sandboxed.clone())?;
             let reflog = format!(
-                "it: update tip from {} by {}",
+                "{REFLOG_UPDATE_TIP}{} by {}",
                 record.bundle_hash(),
                 submitter.id()
             );
@@ -208,24 +407,74 @@ pub fn update_branches(
     Ok(())
 }
 
+/// Parse the bundle hash and submitter from a reflog message written by
+/// [`update_branches`]
+pub fn parse_update_tip_reflog(msg: &str) -> Option<(bundle::Hash, IdentityId)> {
+    let (hash, id) = msg.strip_prefix(REFLOG_UPDATE_TIP)?.split_once(" by ")?;
+    Some((hash.parse().ok()?, id.trim_end().parse().ok()?))
+}
+
+/// Verify that the commits in `start..end` were signed by `allowed`
+///
+/// A commit is accepted if it was signed by a key of the current revision of
+/// `allowed`, or by a key of the earlier revision named in its
+/// [`IdentityRevision`] trailer.
 fn verify_commit_range(
     repo: &git2::Repository,
     allowed: &identity::Verified,
     Range { start, end }: Range<git2::Oid>,
 ) -> Result<()> {
+    fn has_key(id: &identity::Verified, keyid: &KeyId) -> bool {
+        id.identity().keys.contains_key(keyid) || id.bot_key(keyid).is_some()
+    }
+
     let mut walk = repo.revwalk()?;
     walk.push(start)?;
     walk.hide(end)?;
     walk.simplify_first_parent()?;
     walk.set_sorting(git2::Sort::TOPOLOGICAL)?;
     for id in walk {
-        let pk = git::verify_commit_signature(repo, &id?)?;
+        let id = id?;
+        let pk = git::verify_commit_signature(repo, &id)?;
         let keyid = VerificationKey::from(pk).keyid();
+        if has_key(allowed, &keyid) {
+            continue;
+        }
+        let signed_under = IdentityRevision::from_commit(&repo.find_commit(id)?)?
+            .map(|rev| identity_at(repo, allowed, &rev))
+            .transpose()
+            .with_context(|| format!("invalid identity revision in {id}"))?;
         ensure!(
-            allowed.identity().keys.contains_key(&keyid),
+            signed_under.map_or(false, |rev| has_key(&rev, &keyid)),
             "good signature by unknown signer"
         );
     }
 
     Ok(())
 }
+
+/// Load the revision `rev` of the identity `current`
+///
+/// Fails if `rev` is not a past revision of `current`.
+pub(super) fn identity_at(
+    repo: &git2::Repository,
+    current: &identity::Verified,
+    rev: &IdentityRevision,
+) -> Result<identity::Verified> {
+    let find_parent = metadata::git::find_parent(repo);
+    let GitMeta { hash, signed } = metadata::Identity::from_blob(&repo.find_blob(rev.oid())?)?;
+    let verified = signed.verified(&find_parent)?;
+    ensure!(
+        verified.id() == current.id(),
+        "revision {hash} belongs to {}, not {}",
+        verified.id(),
+        current.id()
+    );
+    ensure!(
+        current.identity().has_ancestor(&hash, &find_parent)?,
+        "{hash} is not a past revision of {}",
+        current.id()
+    );
+
+    Ok(verified)
+}
@@ -2,11 +2,22 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Read,
+        Seek,
+        SeekFrom,
+    },
     path::{
         Path,
         PathBuf,
     },
     str::FromStr,
+    sync::Arc,
 };
 
 use anyhow::{
@@ -21,28 +32,60 @@ use globset::{
     GlobSet,
     GlobSetBuilder,
 };
-use log::info;
+use log::{
+    info,
+    warn,
+};
 use once_cell::sync::Lazy;
-use thiserror::Error;
+use sha2::{
+    Digest as _,
+    Sha256,
+};
+#[cfg(feature = "cli")]
 use tiny_http::Request;
 use url::Url;
 
+#[cfg(feature = "cli")]
+use super::MAX_LEN_INFO;
 use super::{
+    audit,
     bundle::Bundle,
+    error::Quarantined,
+    hooks::{
+        self,
+        Hooks,
+    },
+    issues,
+    iter::dropped,
+    lint,
+    notes,
+    pins,
+    policy::{
+        self,
+        PolicyAck,
+    },
+    reachable,
     record::{
         self,
         Heads,
         Signature,
     },
+    spam,
+    ssh,
     state,
+    upload,
     Record,
     Seen,
     Topic,
+    HTTP_HEADER_CHUNK_HASH,
+    HTTP_HEADER_HASH_ALGORITHM,
     HTTP_HEADER_SIGNATURE,
     MAX_LEN_BUNDLE,
     REF_IT_BUNDLES,
+    REF_IT_QUARANTINE,
     REF_IT_TOPICS,
     TOPIC_MERGES,
+    TOPIC_SNAPSHOTS,
 };
 use crate::{
     bundle,
@@ -50,22 +93,33 @@ use crate::{
         self,
         if_not_found_none,
         refs,
+        Refname,
     },
     metadata::{
         self,
+        drop::RoleName,
         git::{
             FromGit,
             GitMeta,
             META_FILE_ID,
         },
-        identity,
+        identity::{
+            self,
+            BotKey,
+            ExpiryPolicy,
+        },
         ContentHash,
         Signed,
         Verified,
     },
+    net,
+    trace,
     Result,
 };
 
+/// Number of times an interrupted chunked upload is resumed before giving up
+const MAX_UPLOAD_ATTEMPTS: usize = 5;
+
 pub static GLOB_HEADS: Lazy<Glob> = Lazy::new(|| Glob::new("refs/heads/**").unwrap());
 pub static GLOB_TAGS: Lazy<Glob> = Lazy::new(|| Glob::new("refs/tags/**").unwrap());
 pub static GLOB_NOTES: Lazy<Glob> = Lazy::new(|| Glob::new("refs/notes/**").unwrap());
@@ -109,6 +163,12 @@ pub struct AcceptArgs<'a, S> {
     pub signer: &'a mut S,
     /// IPFS API address
     pub ipfs_api: Option<&'a Url>,
+    /// Cache of the verified drop metadata, if the caller accepts multiple
+    /// patches over its lifetime
+    pub drop_cache: Option<&'a state::DropHeadCache>,
+    /// Cache of the commits reachable from previously accepted patches, if the
+    /// caller accepts multiple patches over its lifetime
+    pub reachable_cache: Option<&'a reachable::Cache>,
     /// Options
     pub options: AcceptOptions,
 }
@@ -133,6 +193,11 @@ pub struct AcceptOptions {
     /// - refs/it/topics/*
     /// - refs/it/ids/*
     pub allowed_refs: GlobSet,
+    /// Apply the [`metadata::drop::RefPolicy`] of the drop in addition to
+    /// `allowed_refs`
+    ///
+    /// Default: true
+    pub enforce_ref_policy: bool,
     /// Maximum number of branches the bundle is allowed to carry
     ///
     /// A branch is a ref which starts with `refs/heads/`.
@@ -157,8 +222,74 @@ pub struct AcceptOptions {
     pub max_refs: usize,
     /// Maximum number of commits a bundle ref can have
     ///
+    /// Subject to the [`metadata::drop::CommitLimits`] of the drop, if
+    /// `enforce_commit_limits` is true.
+    ///
     /// Default: 20
     pub max_commits: usize,
+    /// Maximum number of commits a bundle ref of a checkpoint can have
+    ///
+    /// Applies instead of `max_commits` to merge checkpoints submitted by
+    /// members of a branch role, and snapshots submitted by members of the
+    /// snapshot role.
+    ///
+    /// Default: 100,000
+    pub max_commits_checkpoint: usize,
+    /// Apply the [`metadata::drop::CommitLimits`] of the drop to
+    /// `max_commits`
+    ///
+    /// Default: true
+    pub enforce_commit_limits: bool,
+    /// Executables to run before and after recording the patch
+    ///
+    /// Default: none
+    pub hooks: Hooks,
+    /// Hold submissions by identities not yet known to the drop for review
+    ///
+    /// Instead of being recorded, such submissions are stored under
+    /// [`REF_IT_QUARANTINE`] without unbundling, and [`Quarantined`] is
+    /// returned as the error.
+    ///
+    /// Default: false
+    pub quarantine_new: bool,
+    /// Materialise the refs conveyed by the bundle upon recording it
+    ///
+    /// If false, the patch is only recorded in the drop history, and the refs
+    /// can be materialised later using [`super::materialise`].
+    ///
+    /// Default: true
+    pub unbundle: bool,
+    /// Rules the messages of the commits on the bundle's branches must
+    /// adhere to
+    ///
+    /// If violated, [`lint::Failed`] is returned as the error.
+    ///
+    /// Default: none
+    pub lint: lint::Rules,
+    /// Store the bundles of snapshots in deduplicated chunks once recorded,
+    /// cf. [`Bundle::dehydrate`]
+    ///
+    /// Default: false
+    pub dedup_snapshots: bool,
+    /// Anti-spam policy to consult before accepting the submission
+    ///
+    /// If violated, [`spam::Rejected`] is returned as the error.
+    ///
+    /// Default: none
+    pub spam_policy: Option<Arc<dyn spam::Policy>>,
+    /// Record the outcome in the [`audit`] log, signed by the drop's signer
+    ///
+    /// Default: false
+    pub audit: bool,
+    /// How to treat expired identities of the members of the drop's roles
+    ///
+    /// The drop metadata is signed before it is recorded, so tolerating
+    /// expired identities keeps a drop usable after a role member's identity
+    /// lapses. The submitter's identity must never be expired, as the
+    /// submission is signed at the time it is made.
+    ///
+    /// Default: [`ExpiryPolicy::Strict`]
+    pub expiry: ExpiryPolicy,
 }
 
 impl Default for AcceptOptions {
@@ -167,11 +298,22 @@ impl Default for AcceptOptions {
             allow_fat_pack: false,
             allow_encrypted: false,
             allowed_refs: ALLOWED_REFS.clone(),
+            enforce_ref_policy: true,
             max_branches: 1,
             max_tags: 1,
             max_notes: 1,
             max_refs: 10,
             max_commits: 20,
+            max_commits_checkpoint: 100_000,
+            enforce_commit_limits: true,
+            hooks: Hooks::default(),
+            quarantine_new: false,
+            unbundle: true,
+            lint: lint::Rules::default(),
+            dedup_snapshots: false,
+            spam_policy: None,
+            audit: false,
+            expiry: ExpiryPolicy::Strict,
         }
     }
 }
@@ -182,9 +324,15 @@ pub struct Submission {
 }
 
 impl Submission {
-    pub fn from_http<P>(bundle_dir: P, req: &mut Request) -> Result<Self>
+    /// Create a [`Submission`] from the body of `req`
+    ///
+    /// `wrap` is applied to the body reader before copying the bundle into
+    /// `bundle_dir`, which allows to eg. throttle the upload.
+    #[cfg(feature = "cli")]
+    pub fn from_http<P, F>(bundle_dir: P, req: &mut Request, wrap: F) -> Result<Self>
     where
         P: AsRef<Path>,
+        F: for<'a> FnOnce(&'a mut dyn Read) -> Box<dyn Read + 'a>,
     {
         let len = req
             .body_length()
@@ -194,41 +342,320 @@ impl Submission {
             "submitted patch bundle exceeds {MAX_LEN_BUNDLE}",
         );
 
-        let mut signature = None;
+        let signature = signature_from_headers(req)?;
+        let alg = hash_algorithm_from_headers(req)?;
+        let this = Self::from_reader(bundle_dir, signature, alg, wrap(req.as_reader()))?;
+        ensure!(
+            this.bundle.info.len == len as u64,
+            "received {} bytes, but Content-Length is {len}",
+            this.bundle.info.len
+        );
 
-        for hdr in req.headers() {
-            if hdr.field.equiv(HTTP_HEADER_SIGNATURE) {
-                let sig = Signature::try_from(hdr)?;
-                signature = Some(sig);
-                break;
-            }
-        }
+        Ok(this)
+    }
+
+    /// Create a [`Submission`] from a [`ssh::Request`] read from `reader`
+    ///
+    /// The request is expected to be followed by the bundle, of at most
+    /// `max_len` bytes.
+    #[cfg(feature = "cli")]
+    pub fn from_ssh<P, R>(bundle_dir: P, reader: &mut R, max_len: u64) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        R: BufRead,
+    {
+        let mut line = String::new();
+        reader.take(ssh::MAX_LEN_REQUEST).read_line(&mut line)?;
+        ensure!(line.ends_with('\n'), "incomplete or oversized request");
+        let req: ssh::Request = serde_json::from_str(&line)?;
+        ensure!(
+            req.len <= max_len,
+            "submitted patch bundle exceeds {max_len}"
+        );
+
+        let this = Self::from_reader(
+            bundle_dir,
+            req.signature,
+            req.hash_algorithm,
+            reader.take(req.len),
+        )?;
+        ensure!(
+            this.bundle.info.len == req.len,
+            "received {} bytes, but announced length is {}",
+            this.bundle.info.len,
+            req.len
+        );
+
+        Ok(this)
+    }
+
+    /// Create a [`Submission`] by copying the bundle read from `reader` into
+    /// `bundle_dir`
+    ///
+    /// The caller is responsible for limiting the size of the input.
+    pub fn from_reader<P, R>(
+        bundle_dir: P,
+        signature: Signature,
+        alg: bundle::HashAlgorithm,
+        reader: R,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        R: Read,
+    {
+        let bundle = Bundle::copy(reader, bundle_dir, alg)?;
+
+        Ok(Self { signature, bundle })
+    }
+
+    /// Like [`Submission::from_http`], but for a bundle already present in
+    /// `bundle_dir`
+    ///
+    /// The request body is expected to be the JSON-serialised
+    /// [`bundle::Info`] of the bundle, which must match the one stored
+    /// locally.
+    #[cfg(feature = "cli")]
+    pub fn from_http_stored<P>(
+        bundle_dir: P,
+        hash: &bundle::Hash,
+        req: &mut Request,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let len = req
+            .body_length()
+            .ok_or_else(|| anyhow!("chunked body not permitted"))?;
+        ensure!(len <= MAX_LEN_INFO, "bundle info exceeds {MAX_LEN_INFO}");
 
-        #[derive(Debug, Error)]
-        #[error("missing header {0}")]
-        struct Missing(&'static str);
+        let signature = signature_from_headers(req)?;
+        let info: bundle::Info = serde_json::from_reader(req.as_reader())?;
+        ensure!(&info.hash == hash, "bundle hash mismatch");
 
-        let signature = signature.ok_or(Missing(HTTP_HEADER_SIGNATURE))?;
-        let bundle = Bundle::copy(req.as_reader(), bundle_dir)?;
+        Self::from_stored(bundle_dir, signature, &info)
+    }
+
+    /// Create a [`Submission`] from the complete chunked upload `id`
+    ///
+    /// `req` finalises the upload, and is expected to carry the same headers
+    /// as if the bundle was submitted via [`Submission::from_http`]. The
+    /// upload is removed once the bundle is reassembled in `bundle_dir`.
+    ///
+    /// Returns `None` if the upload doesn't exist.
+    #[cfg(feature = "cli")]
+    pub fn from_upload<P>(
+        bundle_dir: P,
+        uploads: &upload::Store,
+        id: &str,
+        req: &Request,
+    ) -> Result<Option<Self>>
+    where
+        P: AsRef<Path>,
+    {
+        let signature = signature_from_headers(req)?;
+        let alg = hash_algorithm_from_headers(req)?;
+        let reader = match uploads.reader(id)? {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        let this = Self::from_reader(bundle_dir, signature, alg, reader)?;
+        uploads.remove(id)?;
+
+        Ok(Some(this))
+    }
+
+    /// Create a [`Submission`] for the bundle described by `info`, which must
+    /// already be present in `bundle_dir`
+    pub fn from_stored<P>(bundle_dir: P, signature: Signature, info: &bundle::Info) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let bundle = Bundle::from_stored(bundle_dir, bundle::Expect::from(info))?;
+        ensure!(
+            bundle.info.len == info.len,
+            "claimed and actual bundle length differ"
+        );
 
         Ok(Self { signature, bundle })
     }
 
-    pub fn submit(self, mut base_url: Url) -> Result<Record> {
+    pub fn submit(self, base_url: Url) -> Result<Record> {
+        if base_url.scheme() == "ssh" {
+            return self.submit_ssh(&base_url);
+        }
+        if self.remote_has_bundle(&base_url)? {
+            info!("Remote already has bundle {}", self.bundle.info.hash);
+            match self.register(base_url.clone()) {
+                Err(e) if is_not_found(&e) => {
+                    warn!("Remote could not find bundle after all, uploading");
+                },
+                x => return x,
+            }
+        }
+
+        if self.bundle.info.len > MAX_LEN_BUNDLE as u64 {
+            self.upload_chunked(base_url)
+        } else {
+            self.upload(base_url)
+        }
+    }
+
+    fn upload(self, mut base_url: Url) -> Result<Record> {
         base_url
             .path_segments_mut()
             .map_err(|()| anyhow!("invalid url"))?
             .push("patches");
-        let tiny_http::Header {
-            field: sig_hdr,
-            value: sig,
-        } = self.signature.into();
-        let req = ureq::request_url("POST", &base_url)
+        let (sig_hdr, sig) = self.signature_header();
+        let mut req = net::request("POST", &base_url)?
             .set("Content-Length", &self.bundle.info.len.to_string())
-            .set(sig_hdr.as_str().as_str(), sig.as_str());
+            .set(&sig_hdr, &sig);
+        let alg = self.bundle.info.hash.algorithm();
+        if alg != bundle::HashAlgorithm::default() {
+            req = req.set(HTTP_HEADER_HASH_ALGORITHM, &alg.to_string());
+        }
         let res = req.send(self.bundle.reader()?)?;
 
-        Ok(res.into_json()?)
+        record_from_response(res)
+    }
+
+    /// Upload a bundle too large for a single request in chunks, see
+    /// [`upload`]
+    ///
+    /// If the upload is interrupted, it is resumed with the chunks the remote
+    /// has not received yet.
+    fn upload_chunked(self, mut base_url: Url) -> Result<Record> {
+        base_url
+            .path_segments_mut()
+            .map_err(|()| anyhow!("invalid url"))?
+            .push("patches")
+            .push("uploads");
+        let len = self.bundle.info.len;
+        let mut status: upload::Status = net::request("POST", &base_url)?
+            .send_json(upload::Start { len })?
+            .into_json()?;
+        status.validate(len, MAX_LEN_BUNDLE as u64)?;
+        let mut url = base_url;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("invalid url"))?
+            .push(&status.id);
+        info!("Uploading bundle in {} chunks", status.chunks());
+
+        let mut file = File::open(self.bundle.data_path())?;
+        let mut attempts = 1;
+        loop {
+            match put_chunks(&url, &status, &mut file) {
+                Ok(()) => break,
+                Err(e) if attempts < MAX_UPLOAD_ATTEMPTS && is_transient(&e) => {
+                    attempts += 1;
+                    warn!("Upload interrupted, resuming: {e:#}");
+                    status = net::request("GET", &url)?.call()?.into_json()?;
+                    status.validate(len, MAX_LEN_BUNDLE as u64)?;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (sig_hdr, sig) = self.signature_header();
+        let mut req = net::request("POST", &url)?.set(&sig_hdr, &sig);
+        let alg = self.bundle.info.hash.algorithm();
+        if alg != bundle::HashAlgorithm::default() {
+            req = req.set(HTTP_HEADER_HASH_ALGORITHM, &alg.to_string());
+        }
+        let res = req.call()?;
+
+        record_from_response(res)
+    }
+
+    /// Register a bundle the remote already has, without uploading it again
+    fn register(&self, mut base_url: Url) -> Result<Record> {
+        base_url
+            .path_segments_mut()
+            .map_err(|()| anyhow!("invalid url"))?
+            .push("patches")
+            .push(&self.bundle.info.hash.to_string());
+        let (sig_hdr, sig) = self.signature_header();
+        let res = net::request("POST", &base_url)?
+            .set(&sig_hdr, &sig)
+            .send_json(&self.bundle.info)?;
+
+        record_from_response(res)
+    }
+
+    fn remote_has_bundle(&self, base_url: &Url) -> Result<bool> {
+        let mut url = base_url.clone();
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("invalid url"))?
+            .push("bundles")
+            .push(&format!(
+                "{}.{}",
+                self.bundle.info.hash,
+                bundle::FILE_EXTENSION
+            ));
+        match net::request("HEAD", &url)?.call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(_, _)) => Ok(false),
+            Err(e) => {
+                warn!(
+                    "Failed to query remote for bundle {}: {e}",
+                    self.bundle.info.hash
+                );
+                Ok(false)
+            },
+        }
+    }
+
+    /// Submit over SSH, see [`ssh`]
+    fn submit_ssh(self, url: &Url) -> Result<Record> {
+        use std::io::Write as _;
+
+        let mut argv = ssh::REMOTE_COMMAND.to_vec();
+        if let Some(path) = crate::ssh::transport::path(url) {
+            argv.extend(["--git-dir", path]);
+        }
+        let mut chan = crate::ssh::transport::Channel::open(url, &argv)?;
+
+        let req = ssh::Request {
+            signature: self.signature.clone(),
+            hash_algorithm: self.bundle.info.hash.algorithm(),
+            len: self.bundle.info.len,
+        };
+        let mut bundle = self.bundle.reader()?;
+        let sent = serde_json::to_writer(&mut chan, &req)
+            .map_err(io::Error::from)
+            .and_then(|()| chan.write_all(b"\n"))
+            .and_then(|()| io::copy(&mut bundle, &mut chan))
+            .and_then(|_| chan.flush());
+        // The remote may reject the submission before having read all of it
+        match sent {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+            x => {
+                x?;
+            },
+        }
+        chan.close_input();
+
+        let mut line = String::new();
+        BufReader::new((&mut chan).take(MAX_LEN_BUNDLE as u64)).read_line(&mut line)?;
+        chan.finish()?;
+        ensure!(
+            !line.is_empty(),
+            "remote closed the connection without response"
+        );
+        match serde_json::from_str(&line)? {
+            ssh::Response::Accepted { record } => Ok(record),
+            ssh::Response::Quarantined { record } => {
+                info!("Submission is pending review by the drop operators");
+                Ok(record)
+            },
+            ssh::Response::Rejected { reason } => bail!("submission rejected: {reason}"),
+        }
+    }
+
+    fn signature_header(&self) -> (String, String) {
+        (
+            HTTP_HEADER_SIGNATURE.to_owned(),
+            self.signature.to_header_value(),
+        )
     }
 
     pub fn try_accept<S>(
@@ -240,9 +667,109 @@ impl Submission {
             repo,
             signer,
             ipfs_api,
+            drop_cache,
+            reachable_cache,
             options,
         }: AcceptArgs<S>,
     ) -> Result<Record>
+    where
+        S: crate::keys::Signer,
+    {
+        let _span = trace::span("submission").with("bundle", self.bundle.info.hash);
+        let audit = options.audit;
+        let res = self.accept(AcceptArgs {
+            unbundle_prefix,
+            drop_ref,
+            seen_ref,
+            repo,
+            signer: &mut *signer,
+            ipfs_api,
+            drop_cache,
+            reachable_cache,
+            options,
+        });
+        if audit {
+            let event = match &res {
+                Ok(record) => Some(audit::Event::accept(record)),
+                Err(e) => match e.downcast_ref::<Quarantined>() {
+                    Some(Quarantined { record, .. }) => Some(audit::Event::quarantine(record)),
+                    None => {
+                        let heads = Heads::from_header(
+                            &self.bundle.header,
+                            self.bundle.info.hash.algorithm(),
+                        );
+                        // Anyone can submit garbage, so only rejections of
+                        // submitters known to the drop are worth a signed
+                        // entry. Otherwise, the log could be grown without
+                        // bound.
+                        if self.is_signed_by_known(repo, drop_ref, &heads) {
+                            Some(audit::Event::Reject {
+                                heads,
+                                signer: self.signature.signer.clone(),
+                                reason: format!("{e:#}"),
+                            })
+                        } else {
+                            warn!(
+                                "rejected {heads} by unknown submitter {}: {e:#}",
+                                self.signature.signer
+                            );
+                            None
+                        }
+                    },
+                },
+            };
+            if let Some(event) = event {
+                if let Err(e) = audit::append(repo, signer, drop_ref, event) {
+                    warn!("failed to append to the audit log: {e:#}");
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Whether the submission carries a valid signature by an identity
+    /// already known to the drop at `drop_ref`
+    fn is_signed_by_known(&self, repo: &git2::Repository, drop_ref: &str, heads: &Heads) -> bool {
+        let verify = || -> Result<bool> {
+            if !repo.odb()?.exists((&self.signature.signer).into()) {
+                return Ok(false);
+            }
+            let drop = state::DropHead::from_refname(repo, drop_ref)?;
+            let id = Identity::find(repo, &drop.ids, &self.signature.signer)?;
+            Ok(id.known
+                && id
+                    .verify_signature(&heads.to_bytes(), &self.signature)
+                    .is_ok())
+        };
+        verify().unwrap_or(false)
+    }
+
+    fn accept<S>(&mut self, args: AcceptArgs<S>) -> Result<Record>
+    where
+        S: crate::keys::Signer,
+    {
+        self.prepare(args)?.commit()
+    }
+
+    /// Check the submission against the drop, and prepare recording it
+    ///
+    /// Like [`Submission::try_accept`], but nothing is recorded until
+    /// [`Prepared::commit`] is called, nor is the outcome audited.
+    pub fn prepare<'a, S>(
+        &'a mut self,
+        AcceptArgs {
+            unbundle_prefix,
+            drop_ref,
+            seen_ref,
+            repo,
+            signer,
+            ipfs_api,
+            drop_cache,
+            reachable_cache,
+            options,
+        }: AcceptArgs<'a, S>,
+    ) -> Result<Prepared<'a>>
     where
         S: crate::keys::Signer,
     {
@@ -254,12 +781,16 @@ impl Submission {
             !self.bundle.is_encrypted() || options.allow_encrypted,
             "encrypted bundle rejected"
         );
+        self.bundle.verify_len()?;
+        let accepting = git::maintenance::Accepting::begin(repo.path())?;
 
         let header = &self.bundle.header;
 
+        // libgit2 (as of 1.5) can neither open sha256 repositories nor index
+        // sha256 packs, so there is no way to store such a patch
         ensure!(
             matches!(header.object_format, bundle::ObjectFormat::Sha1),
-            "object-format {} not (yet) supported",
+            "object-format {} not (yet) supported, drops must use sha1",
             header.object_format
         );
         ensure!(
@@ -270,7 +801,31 @@ impl Submission {
             header.references.len() <= options.max_refs,
             "max number of refs exceeded"
         );
-        let topic = {
+
+        let mut tx = refs::Transaction::new(repo)?;
+        let seen_ref = tx.lock_ref(seen_ref.parse()?)?;
+        let drop_ref = tx.lock_ref(drop_ref.parse()?)?;
+        let mut drop = state::DropHead::from_refname_cached(
+            repo,
+            drop_ref.name(),
+            drop_cache,
+            options.expiry,
+        )?;
+        // The identity is needed up front to determine which refs it may
+        // convey. A revision not yet known to the drop is conveyed by the
+        // bundle itself, though, and can only be resolved once the bundle is
+        // indexed.
+        let mut id = if repo.odb()?.exists((&self.signature.signer).into()) {
+            Some(Identity::find(repo, &drop.ids, &self.signature.signer)?)
+        } else {
+            None
+        };
+        ensure!(
+            id.is_some() || !self.bundle.is_encrypted(),
+            "encrypted patches must be signed by an identity known to the drop"
+        );
+
+        let (topic, only_notes) = {
             let mut topic: Option<Topic> = None;
 
             let mut heads = 0;
@@ -295,6 +850,9 @@ impl Submission {
                 GIT_IT.matches_candidate_into(&cand, &mut matches);
                 match &matches[..] {
                     [] => {},
+                    // Identity updates are conveyed as branches, too, but
+                    // don't count towards the limit
+                    [0] if r.starts_with("refs/heads/it/") => {},
                     [0] => heads += 1,
                     [1] => tags += 1,
                     [2] => notes += 1,
@@ -321,12 +879,70 @@ impl Submission {
                 "max number of git notes exceeded"
             );
 
-            topic.ok_or_else(|| anyhow!("missing '{}'", GLOB_IT_TOPICS.glob()))?
+            let topic = topic.ok_or_else(|| anyhow!("missing '{}'", GLOB_IT_TOPICS.glob()))?;
+            (topic, heads == 0 && tags == 0)
         };
-        let heads = Heads::from(header);
+        trace::record("topic", &topic);
+        let is_comment = only_notes && topic != *TOPIC_MERGES && topic != *TOPIC_SNAPSHOTS;
+        let is_checkpoint = |id: &Identity| {
+            let roles = &drop.meta.roles;
+            let id = id.verified.id();
+            (topic == *TOPIC_MERGES && roles.has_role(id, RoleName::AnyBranch))
+                || (topic == *TOPIC_SNAPSHOTS && roles.has_role(id, RoleName::Snapshot))
+        };
+        let heads = Heads::from_header(header, self.bundle.info.hash.algorithm());
+        // Checks on the submitter, performed as soon as its identity is
+        // resolved. `repo` is where the objects of the identity can be found.
+        let admit = |repo: &git2::Repository, id: &Identity| -> Result<()> {
+            trace::record("submitter", id.verified.id());
+            if options.enforce_ref_policy {
+                if let Some(policy) = ref_policy_for(&drop.meta, id.verified.id())? {
+                    for r in header.references.keys() {
+                        ensure!(policy.is_match(r), "ref not permitted by drop policy: {r}");
+                    }
+                }
+            }
+            // The signature is made over the heads only, so can be verified
+            // before consulting the spam policy: a forged submission must not
+            // count against the purported submitter
+            if let Some(bot) = id.verify_signature(&heads.to_bytes(), &self.signature)? {
+                ensure!(
+                    bot.scope.permits(&drop.meta.roles.root.ids, is_comment),
+                    "bot key {} of {} is not permitted to submit this patch to this drop",
+                    bot.key.id(),
+                    id.verified.id()
+                );
+            }
+            if let Some(spam_policy) = &options.spam_policy {
+                let kind = if topic == *TOPIC_MERGES {
+                    spam::Kind::Merges
+                } else if topic == *TOPIC_SNAPSHOTS {
+                    spam::Kind::Snapshot
+                } else if is_comment {
+                    spam::Kind::Comment
+                } else {
+                    spam::Kind::Patch
+                };
+                spam_policy.check(&spam::Candidate {
+                    repo,
+                    drop_ref: drop_ref.name(),
+                    submitter: id.verified.id(),
+                    revisions: &id.revisions(repo)?,
+                    known: id.known,
+                    privileged: is_checkpoint(id)
+                        || policy::is_exempt(&drop.meta, id.verified.id()),
+                    kind,
+                    topic: &topic,
+                    bundle_len: self.bundle.info.len,
+                })?;
+            }
+
+            Ok(())
+        };
+        if let Some(id) = &id {
+            admit(repo, id)?;
+        }
 
-        let mut tx = refs::Transaction::new(repo)?;
-        let seen_ref = tx.lock_ref(seen_ref.parse()?)?;
         let seen_tree = match if_not_found_none(repo.find_reference(seen_ref.name()))? {
             Some(seen) => seen.peel_to_tree()?,
             None => git::empty_tree(repo)?,
@@ -335,26 +951,15 @@ impl Submission {
 
         // In a bare drop, indexing the pack is enough to detect missing
         // prerequisites (ie. delta bases). Otherwise, or if the bundle is
-        // encrypted, we need to look for merge bases from the previously
-        // accepted patches.
+        // encrypted, we need to check that they are reachable from the
+        // previously accepted patches.
         if !repo.is_bare() || self.bundle.is_encrypted() {
-            let mut prereqs = header
+            let prereqs = header
                 .prerequisites
                 .iter()
                 .map(git2::Oid::try_from)
                 .collect::<std::result::Result<Vec<_>, _>>()?;
-
-            for r in repo.references_glob(GLOB_IT_BUNDLES.glob())? {
-                let commit = r?.peel_to_commit()?.id();
-                for (i, id) in prereqs.clone().into_iter().enumerate() {
-                    if if_not_found_none(repo.merge_base(commit, id))?.is_some() {
-                        prereqs.swap_remove(i);
-                    }
-                }
-                if prereqs.is_empty() {
-                    break;
-                }
-            }
+            let prereqs = reachable::missing(repo, seen_tree.id(), &prereqs, reachable_cache)?;
 
             ensure!(
                 prereqs.is_empty(),
@@ -368,39 +973,146 @@ impl Submission {
             );
         }
 
-        let odb = repo.odb()?;
+        let mut diffstat = None;
+        let mut issues = BTreeSet::new();
         if !self.bundle.is_encrypted() {
-            let mut pack = self.bundle.packdata()?;
-            pack.index(&odb)?;
-
             let prereqs = header
                 .prerequisites
                 .iter()
                 .map(git2::Oid::try_from)
                 .collect::<std::result::Result<Vec<_>, _>>()?;
-            let mut walk = repo.revwalk()?;
-            for (name, oid) in &header.references {
-                walk.push(oid.try_into()?)?;
-                for hide in &prereqs {
-                    walk.hide(*hide)?;
-                }
+
+            // Until its identity is resolved, the submitter is subject to the
+            // limits of one without any roles
+            let max_commits = if id.as_ref().map_or(false, is_checkpoint) {
+                CommitLimit::fixed(options.max_commits_checkpoint)
+            } else if options.enforce_commit_limits {
+                CommitLimit::new(
+                    repo,
+                    drop_ref.name(),
+                    &drop.meta,
+                    id.as_ref(),
+                    options.max_commits,
+                )?
+            } else {
+                CommitLimit::fixed(options.max_commits)
+            };
+
+            // Before indexing anything, make a streaming pass over the pack:
+            // every commit in a well-formed bundle is reachable from one of
+            // its refs, so it can't contain more commits than all of them may
+            // convey together.
+            let mut pack = self.bundle.packdata()?;
+            {
+                let max = header.references.keys().fold(0usize, |acc, name| {
+                    acc.saturating_add(max_commits.get(name))
+                });
                 let mut cnt = 0;
-                for x in &mut walk {
-                    let _ = x?;
-                    cnt += 1;
-                    ensure!(
-                        cnt <= options.max_commits,
-                        "{name} exceeds configured max number of commits ({})",
-                        options.max_commits
-                    );
+                pack.scan(|obj| {
+                    if obj.kind == bundle::pack::Kind::Commit {
+                        cnt += 1;
+                        ensure!(
+                            cnt <= max,
+                            "bundle exceeds configured max number of commits ({max})"
+                        );
+                    }
+                    Ok(())
+                })?;
+            }
+
+            // Index into quarantine, so the objects of a rejected submission
+            // never enter the object database
+            let quarantine = pack.quarantine(repo)?;
+            let objects = quarantine.objects();
+            let id = match &mut id {
+                Some(id) => id,
+                None => {
+                    let found =
+                        Identity::find(quarantine.repo(), &drop.ids, &self.signature.signer)?;
+                    admit(quarantine.repo(), &found)?;
+                    id.insert(found)
+                },
+            };
+            // A reference to an object which is only present locally could
+            // graft arbitrary history onto the patch
+            for (name, oid) in &header.references {
+                let oid = git2::Oid::try_from(oid)?;
+                ensure!(
+                    objects.contains(&oid) || prereqs.contains(&oid),
+                    "{name} points to {oid}, which is not contained in the bundle"
+                );
+            }
+            let mut violations = Vec::new();
+            {
+                // Topic state notes are only honoured from maintainers
+                let may_set_state = drop
+                    .meta
+                    .roles
+                    .has_role(id.verified.id(), RoleName::AnyBranch);
+                let mut walk = quarantine.repo().revwalk()?;
+                for (name, oid) in &header.references {
+                    walk.push(oid.try_into()?)?;
+                    for hide in &prereqs {
+                        walk.hide(*hide)?;
+                    }
+                    let lint = name.starts_with("refs/heads/") && !options.lint.is_empty();
+                    let check_state = !may_set_state && name.starts_with(REF_IT_TOPICS);
+                    let max = max_commits.get(name);
+                    let mut cnt = 0;
+                    for x in &mut walk {
+                        let oid = x?;
+                        cnt += 1;
+                        ensure!(
+                            cnt <= max,
+                            "{name} exceeds configured max number of commits ({max})"
+                        );
+                        if lint {
+                            violations
+                                .extend(options.lint.check(&quarantine.repo().find_commit(oid)?));
+                        }
+                        if check_state && objects.contains(&oid) {
+                            let commit = quarantine.repo().find_commit(oid)?;
+                            let state = notes::Simple::from_commit(quarantine.repo(), &commit)
+                                .ok()
+                                .and_then(|note| note.topic_state());
+                            ensure!(
+                                state.is_none(),
+                                "{} does not have the '{}' role required to change the state of topics",
+                                id.verified.id(),
+                                RoleName::AnyBranch
+                            );
+                        }
+                    }
+                    walk.reset()?;
                 }
-                walk.reset()?;
             }
+            if !violations.is_empty() {
+                return Err(lint::Failed(violations).into());
+            }
+            quarantine.migrate(repo)?;
+
+            diffstat = record::Diffstat::compute(repo, header).unwrap_or_else(|e| {
+                warn!("Failed to compute diffstat: {e:#}");
+                None
+            });
+            issues = issues::detect(repo, header).unwrap_or_else(|e| {
+                warn!("Failed to detect referenced issues: {e:#}");
+                BTreeSet::new()
+            });
         }
 
+        // Publish before assembling the record, so it includes the IPFS
+        // location. A failure is only queued for retry once the record is
+        // committed, see `Prepared::commit`.
+        let mut pending_pin = None;
         if let Some(url) = ipfs_api {
-            let ipfs = self.bundle.ipfs_add(url)?;
-            info!("Published bundle to IPFS as {ipfs}");
+            match self.bundle.ipfs_add(url) {
+                Ok(ipfs) => info!("Published bundle to IPFS as {ipfs}"),
+                Err(e) => {
+                    warn!("Publishing bundle to IPFS failed, will retry later: {e:#}");
+                    pending_pin = Some((url.clone(), e));
+                },
+            }
         }
 
         let record = Record {
@@ -409,11 +1121,11 @@ impl Submission {
             meta: record::Meta {
                 bundle: record::BundleInfo::from(&self.bundle),
                 signature: self.signature.clone(),
+                diffstat,
+                issues,
             },
         };
 
-        let drop_ref = tx.lock_ref(drop_ref.parse()?)?;
-        let mut drop = state::DropHead::from_refname(repo, drop_ref.name())?;
         ensure!(
             drop.meta.roles.snapshot.threshold.get() == 1,
             "threshold signatures for drop snapshots not yet supported"
@@ -423,41 +1135,302 @@ impl Submission {
             "supplied signer does not have the 'snapshot' role needed to record patches"
         );
 
-        let submitter = {
-            let mut id = Identity::find(repo, &drop.ids, &self.signature.signer)?;
-            id.verify_signature(&record.signed_part(), &self.signature)?;
+        let (submitter, known) = {
+            let mut id = id.ok_or_else(|| anyhow!("submitter identity not resolved"))?;
             if let Some(updated) = id.update(repo, &drop.ids)? {
                 drop.ids = updated;
             }
-            id.verified
+            (id.verified, id.known)
+        };
+
+        if let Some(policy) = drop.policy {
+            let id = submitter.id();
+            if !policy::is_exempt(&drop.meta, id)
+                && !policy::is_acknowledged(repo, drop.acks.as_ref(), id, policy)?
+            {
+                let ack = policy::ack_from_record(repo, &record, policy)?.ok_or_else(|| {
+                    anyhow!(
+                        "{id} has not yet acknowledged the usage policy of the drop, \
+                        a topic note carrying the trailer '{}' is required",
+                        PolicyAck::new(policy).as_trailer()
+                    )
+                })?;
+                drop.acks = Some(policy::record_ack(repo, drop.acks.as_ref(), id, &ack)?);
+            }
+        }
+
+        if !known && options.quarantine_new {
+            let quarantine_ref = tx.lock_ref(quarantined_ref(&record.heads)?)?;
+            ensure!(
+                if_not_found_none(repo.refname_to_id(quarantine_ref.name()))?.is_none(),
+                "submission is already pending review"
+            );
+            let commit = record.commit(signer, repo, &drop.ids, None, None, None)?;
+            quarantine_ref.set_target(commit, format!("quarantine: {}", record.topic));
+
+            return Ok(Prepared {
+                repo,
+                tx,
+                drop_ref: quarantine_ref.into(),
+                submitter: *submitter.id(),
+                record,
+                commit,
+                updated: Vec::new(),
+                quarantine: true,
+                hooks: options.hooks,
+                dedup: false,
+                bundle: &mut self.bundle,
+                pending_pin,
+                _accepting: accepting,
+            });
+        }
+
+        let summary = |phase, commit| hooks::Summary {
+            phase,
+            drop_ref: drop_ref.name(),
+            submitter: submitter.id(),
+            record: &record,
+            commit,
         };
+        options
+            .hooks
+            .pre_accept(repo.path(), &summary(hooks::Phase::PreAccept, None))?;
 
         let mut seen = repo.treebuilder(Some(&seen_tree))?;
         let new_head = record.commit(
             signer,
             repo,
             &drop.ids,
+            drop.acks.as_ref(),
             Some(&drop.tip.peel_to_commit()?),
             Some(&mut seen),
         )?;
         drop_ref.set_target(new_head, format!("commit: {}", record.topic));
         seen_ref.set_target(seen.write()?, format!("it: update to record {}", new_head));
 
-        if !self.bundle.is_encrypted() {
-            state::unbundle(&odb, &mut tx, unbundle_prefix, &record)?;
-            let topic_ref = tx.lock_ref(record.topic.as_refname())?;
-            state::merge_notes(repo, &submitter, &topic_ref, &record)?;
-            if record.topic == *TOPIC_MERGES {
-                state::update_branches(repo, &mut tx, &submitter, &drop.meta, &record)?;
+        let updated = if !self.bundle.is_encrypted() && options.unbundle {
+            state::materialise(
+                repo,
+                &mut tx,
+                unbundle_prefix,
+                &submitter,
+                &drop.meta,
+                &record,
+            )?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Prepared {
+            repo,
+            tx,
+            drop_ref: drop_ref.into(),
+            submitter: *submitter.id(),
+            record,
+            commit: new_head,
+            updated,
+            quarantine: false,
+            hooks: options.hooks,
+            dedup: options.dedup_snapshots,
+            bundle: &mut self.bundle,
+            pending_pin,
+            _accepting: accepting,
+        })
+    }
+}
+
+/// A [`Submission`] which passed all checks
+///
+/// The refs of the drop remain locked until the [`Prepared`] submission is
+/// either committed or dropped. Dropping it leaves the drop unchanged, which
+/// allows to accept several submissions all or none, cf.
+/// [`super::composite`].
+pub struct Prepared<'a> {
+    repo: &'a git2::Repository,
+    tx: refs::Transaction<'a>,
+    /// The drop ref, or the quarantine ref if `quarantine` is true
+    drop_ref: Refname,
+    submitter: metadata::IdentityId,
+    record: Record,
+    commit: git2::Oid,
+    /// Refs updated by unbundling
+    updated: Vec<(Refname, git2::Oid)>,
+    quarantine: bool,
+    hooks: Hooks,
+    dedup: bool,
+    bundle: &'a mut Bundle,
+    /// IPFS API publishing the bundle to failed with the given error
+    pending_pin: Option<(Url, crate::Error)>,
+    /// Keeps maintenance from running until committed or dropped
+    _accepting: git::maintenance::Accepting,
+}
+
+impl Prepared<'_> {
+    pub fn record(&self) -> &Record {
+        &self.record
+    }
+
+    /// Whether the submission will be quarantined instead of recorded
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantine
+    }
+
+    /// Update the refs, making the submission visible
+    ///
+    /// If the submission is quarantined, [`Quarantined`] is returned as the
+    /// error.
+    pub fn commit(self) -> Result<Record> {
+        let Self {
+            repo,
+            tx,
+            drop_ref,
+            submitter,
+            record,
+            commit,
+            updated,
+            quarantine,
+            hooks,
+            dedup,
+            bundle,
+            pending_pin,
+            _accepting,
+        } = self;
+        tx.commit()?;
+
+        if let Some((api, error)) = pending_pin {
+            if let Err(e) = pins::enqueue(repo.path(), bundle, &api, &error) {
+                warn!(
+                    "failed to queue IPFS pin of bundle {} for retry: {e:#}",
+                    bundle.info.hash
+                );
             }
         }
 
-        tx.commit()?;
+        let summary = |phase| hooks::Summary {
+            phase,
+            drop_ref: &drop_ref,
+            submitter: &submitter,
+            record: &record,
+            commit: Some(commit),
+        };
+        if quarantine {
+            info!(
+                "Quarantined submission {} by first-time submitter {submitter}",
+                record.heads,
+            );
+            if let Err(e) = hooks.quarantine(repo.path(), &summary(hooks::Phase::Quarantine)) {
+                warn!("quarantine hook failed: {e:#}");
+            }
+
+            return Err(Quarantined {
+                submitter,
+                record,
+                commit,
+            }
+            .into());
+        }
+
+        if let Err(e) = hooks.post_accept(repo.path(), &summary(hooks::Phase::PostAccept)) {
+            warn!("post-accept hook failed: {e:#}");
+        }
+        let payload = hooks::webhook::Payload::accepted(&drop_ref, commit, &record, &updated);
+        if let Err(e) = hooks.webhooks(repo.path(), &payload) {
+            warn!("failed to notify webhooks: {e:#}");
+        }
+        if dedup && record.is_snapshot() && !bundle.is_encrypted() {
+            match bundle.dehydrate() {
+                Ok(stats) => info!(
+                    "Stored snapshot bundle {} in {} chunks, {} of which new ({} of {} bytes)",
+                    bundle.info.hash, stats.chunks, stats.added, stats.added_len, stats.len
+                ),
+                Err(e) => warn!(
+                    "failed to deduplicate snapshot bundle {}: {e:#}",
+                    bundle.info.hash
+                ),
+            }
+        }
 
         Ok(record)
     }
 }
 
+fn record_from_response(res: ureq::Response) -> Result<Record> {
+    if res.status() == 202 {
+        info!("Submission is pending review by the drop operators");
+    }
+    Ok(res.into_json()?)
+}
+
+/// The ref under which a quarantined submission with the given heads is
+/// stored
+pub fn quarantined_ref(heads: &Heads) -> Result<Refname> {
+    Refname::try_from(format!("{REF_IT_QUARANTINE}/{heads}")).map_err(Into::into)
+}
+
+#[cfg(feature = "cli")]
+fn signature_from_headers(req: &Request) -> Result<Signature> {
+    #[derive(Debug, thiserror::Error)]
+    #[error("missing header {0}")]
+    struct Missing(&'static str);
+
+    let hdr = req
+        .headers()
+        .iter()
+        .find(|hdr| hdr.field.equiv(HTTP_HEADER_SIGNATURE))
+        .ok_or(Missing(HTTP_HEADER_SIGNATURE))?;
+
+    Signature::try_from(hdr)
+}
+
+#[cfg(feature = "cli")]
+fn hash_algorithm_from_headers(req: &Request) -> Result<bundle::HashAlgorithm> {
+    req.headers()
+        .iter()
+        .find(|hdr| hdr.field.equiv(HTTP_HEADER_HASH_ALGORITHM))
+        .map(|hdr| hdr.value.as_str().parse())
+        .transpose()
+        .map(Option::unwrap_or_default)
+        .map_err(Into::into)
+}
+
+/// Upload the chunks of `file` missing from the upload described by `status`
+fn put_chunks(url: &Url, status: &upload::Status, file: &mut File) -> Result<()> {
+    let mut buf = Vec::with_capacity(status.chunk_size as usize);
+    for index in status.missing() {
+        let len = status.chunk_len(index);
+        file.seek(SeekFrom::Start(index * status.chunk_size))?;
+        buf.clear();
+        file.by_ref().take(len).read_to_end(&mut buf)?;
+        ensure!(buf.len() as u64 == len, "bundle file truncated");
+
+        let mut url = url.clone();
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("invalid url"))?
+            .push(&index.to_string());
+        net::request("PUT", &url)?
+            .set(HTTP_HEADER_CHUNK_HASH, &hex::encode(Sha256::digest(&buf)))
+            .send_bytes(&buf)?;
+    }
+
+    Ok(())
+}
+
+/// Whether a request may succeed when retried
+fn is_transient(e: &crate::Error) -> bool {
+    match e.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Transport(_)) => true,
+        Some(ureq::Error::Status(code, _)) => *code == 429 || *code >= 500,
+        None => false,
+    }
+}
+
+fn is_not_found(e: &crate::Error) -> bool {
+    matches!(
+        e.downcast_ref::<ureq::Error>(),
+        Some(ureq::Error::Status(404, _))
+    )
+}
+
 fn is_signer_eligible<S>(
     signer: &S,
     repo: &git2::Repository,
@@ -478,9 +1451,111 @@ where
     Ok(false)
 }
 
+/// The [`metadata::drop::RefPolicy`] of `drop` for submitter `id` as a
+/// [`GlobSet`], or `None` if unrestricted
+///
+/// Topic and identity refs are always permitted, as no patch could be accepted
+/// otherwise.
+fn ref_policy_for(drop: &metadata::Drop, id: &metadata::IdentityId) -> Result<Option<GlobSet>> {
+    drop.ref_policy
+        .globs_for(&drop.roles, id)
+        .map(|globs| {
+            let mut builder = GlobSetBuilder::new();
+            builder.add(GLOB_IT_TOPICS.clone()).add(GLOB_IT_IDS.clone());
+            for glob in globs {
+                builder.add(Glob::new(glob)?);
+            }
+            Ok(builder.build()?)
+        })
+        .transpose()
+}
+
+/// The maximum number of commits per ref of a submission
+struct CommitLimit {
+    base: usize,
+    /// Base limits for refs matching the globs, overriding `base`
+    refs: Vec<(globset::GlobMatcher, usize)>,
+    /// Allowance earned by previously accepted records
+    bonus: usize,
+    /// Upper bound for `bonus` to raise the base limit to
+    max: Option<usize>,
+}
+
+impl CommitLimit {
+    fn fixed(base: usize) -> Self {
+        Self {
+            base,
+            refs: vec![],
+            bonus: 0,
+            max: None,
+        }
+    }
+
+    /// Determine the limit according to the [`metadata::drop::CommitLimits`]
+    /// of `drop` for submitter `id`
+    ///
+    /// If `id` is not known yet, it has not earned any allowance.
+    fn new(
+        repo: &git2::Repository,
+        drop_ref: &str,
+        drop: &metadata::Drop,
+        id: Option<&Identity>,
+        base: usize,
+    ) -> Result<Self> {
+        let limits = &drop.commit_limits;
+        let refs = limits
+            .refs
+            .iter()
+            .map(|(glob, max)| Ok((Glob::new(glob)?.compile_matcher(), max.get())))
+            .collect::<Result<_>>()?;
+        let max = limits.max.map(|max| max.get());
+
+        let mut bonus = 0;
+        if let Some(id) = id.filter(|_| limits.per_record > 0) {
+            let revs = id.revisions(repo)?;
+            for record in dropped::records(repo, drop_ref) {
+                // No need to look further back if the cap is reached already
+                if max.map_or(false, |max| bonus >= max) {
+                    break;
+                }
+                let oid = git2::Oid::from(&record?.meta.signature.signer);
+                if revs.contains(&oid) {
+                    bonus = limits.per_record.saturating_add(bonus);
+                }
+            }
+        }
+
+        Ok(Self {
+            base,
+            refs,
+            bonus,
+            max,
+        })
+    }
+
+    fn get(&self, name: &str) -> usize {
+        let base = self
+            .refs
+            .iter()
+            .filter(|(glob, _)| glob.is_match(name))
+            .map(|(_, max)| *max)
+            .max()
+            .unwrap_or(self.base);
+        let raised = base.saturating_add(self.bonus);
+        match self.max {
+            Some(max) => raised.min(max.max(base)),
+            None => raised,
+        }
+    }
+}
+
 struct Identity {
+    /// Content hash of `verified`
+    hash: ContentHash,
     verified: identity::Verified,
     to_update: Option<Signed<metadata::Identity>>,
+    /// Whether the drop already knew about this identity
+    known: bool,
 }
 
 impl Identity {
@@ -499,12 +1574,16 @@ impl Identity {
         let tree_path = PathBuf::from(theirs.id().to_string()).join(META_FILE_ID);
         let newer = match if_not_found_none(ids.get_path(&tree_path))? {
             None => Self {
+                hash: theirs_hash,
                 verified: theirs,
                 to_update: Some(theirs_signed),
+                known: false,
             },
             Some(in_tree) if theirs_hash == in_tree.id() => Self {
+                hash: theirs_hash,
                 verified: theirs,
                 to_update: None,
+                known: true,
             },
             Some(in_tree) => {
                 let (ours_hash, ours) = metadata::Identity::from_blob(
@@ -517,13 +1596,17 @@ impl Identity {
 
                 if ours.identity().has_ancestor(&theirs_hash, &find_parent)? {
                     Self {
+                        hash: ours_hash,
                         verified: ours,
                         to_update: None,
+                        known: true,
                     }
                 } else if theirs.identity().has_ancestor(&ours_hash, &find_parent)? {
                     Self {
+                        hash: theirs_hash,
                         verified: theirs,
                         to_update: Some(theirs_signed),
+                        known: true,
                     }
                 } else {
                     bail!(
@@ -538,14 +1621,42 @@ impl Identity {
         Ok(newer)
     }
 
-    fn verify_signature(&self, msg: &[u8], sig: &Signature) -> Result<()> {
-        ensure!(
-            self.verified.did_sign(msg, &sig.signature),
-            "signature not valid for current keys in id {}, provided signer at {}",
-            self.verified.id(),
-            sig.signer
-        );
-        Ok(())
+    /// The content hashes of all revisions of the identity, as referenced by
+    /// the signatures of records
+    fn revisions(&self, repo: &git2::Repository) -> Result<BTreeSet<git2::Oid>> {
+        let cur = self.verified.identity();
+        let mut revs = BTreeSet::from([git2::Oid::from(&self.hash)]);
+        revs.extend(cur.prev.as_ref().map(git2::Oid::from));
+        for rev in cur.ancestors(metadata::git::find_parent(repo)) {
+            revs.extend(rev?.signed.prev.as_ref().map(git2::Oid::from));
+        }
+
+        Ok(revs)
+    }
+
+    /// Verify `sig` over `msg`
+    ///
+    /// If the signature was made by a bot key, the key is returned so the
+    /// caller can enforce its scope.
+    fn verify_signature(&self, msg: &[u8], sig: &Signature) -> Result<Option<BotKey>> {
+        if self.verified.did_sign(msg, &sig.signature) {
+            return Ok(None);
+        }
+        if let Some((key, revocation)) = self.verified.revoked_signer(msg, &sig.signature) {
+            bail!(
+                "signature made by key {key} of id {}, which is revoked ({})",
+                self.verified.id(),
+                revocation.reason
+            );
+        }
+        match self.verified.bot_signed(msg, &sig.signature) {
+            Some(bot) => Ok(Some(bot.clone())),
+            None => bail!(
+                "signature not valid for current keys in id {}, provided signer at {}",
+                self.verified.id(),
+                sig.signer
+            ),
+        }
     }
 
     fn update<'a>(
@@ -572,3 +1683,46 @@ impl Identity {
         Ok(None)
     }
 }
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use tiny_http::{
+        Header,
+        TestRequest,
+    };
+
+    use super::*;
+
+    fn request(alg: Option<&str>) -> Request {
+        let req = TestRequest::new();
+        match alg {
+            Some(alg) => req
+                .with_header(Header::from_bytes(HTTP_HEADER_HASH_ALGORITHM, alg).unwrap())
+                .into(),
+            None => req.into(),
+        }
+    }
+
+    #[test]
+    fn hash_algorithm_header() {
+        assert_eq!(
+            hash_algorithm_from_headers(&request(None)).unwrap(),
+            bundle::HashAlgorithm::Sha256
+        );
+        assert_eq!(
+            hash_algorithm_from_headers(&request(Some("sha256"))).unwrap(),
+            bundle::HashAlgorithm::Sha256
+        );
+        #[cfg(feature = "blake3-hash")]
+        assert_eq!(
+            hash_algorithm_from_headers(&request(Some("blake3"))).unwrap(),
+            bundle::HashAlgorithm::Blake3
+        );
+        #[cfg(not(feature = "blake3-hash"))]
+        assert!(hash_algorithm_from_headers(&request(Some("blake3")))
+            .unwrap_err()
+            .to_string()
+            .contains("blake3-hash"));
+        assert!(hash_algorithm_from_headers(&request(Some("md5"))).is_err());
+    }
+}
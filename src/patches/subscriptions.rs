@@ -0,0 +1,157 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Notifications about new records on subscribed topics
+//!
+//! Topics of interest are listed in the git config (cf.
+//! [`crate::cfg::git::subscriptions`]). For each drop history, the tip up to
+//! which the user has been notified is kept in a ref below
+//! [`REF_IT_NOTIFIED`], so [`news`] only reports records which landed since.
+
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    path::Path,
+};
+
+use anyhow::bail;
+use time::OffsetDateTime;
+
+use super::{
+    hooks,
+    record::Heads,
+    Record,
+    Topic,
+    REF_IT_NOTIFIED,
+};
+use crate::{
+    git::{
+        self,
+        if_not_found_none,
+        Refname,
+    },
+    metadata::ContentHash,
+    Result,
+};
+
+/// Records on subscribed topics which landed since the last notification
+#[derive(serde::Serialize)]
+pub struct News {
+    pub drop_ref: String,
+    /// The tip of the drop history the news are current as of
+    #[serde(with = "git::serde::oid")]
+    pub tip: git2::Oid,
+    /// New records by topic, oldest first
+    pub topics: BTreeMap<Topic, Vec<Update>>,
+}
+
+impl News {
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Update {
+    /// The commit on the drop history recording the update
+    #[serde(with = "git::serde::oid")]
+    pub commit: git2::Oid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub heads: Heads,
+    /// Content hash of the submitter's identity
+    pub signer: ContentHash,
+    /// Whether the record only carries notes
+    pub comment: bool,
+}
+
+/// The ref holding the tip of `drop_ref` up to which the user was notified
+pub fn notified_ref(drop_ref: &str) -> Result<Refname> {
+    Ok(Refname::try_from(format!(
+        "{REF_IT_NOTIFIED}/{}",
+        drop_ref.strip_prefix("refs/").unwrap_or(drop_ref)
+    ))?)
+}
+
+/// Collect the records on `topics` which were added to `drop_ref` since
+/// [`mark_notified`] was last called
+///
+/// If it was never called, all records are considered old, so as to not
+/// flood the user with the entire history. Returns `None` if `drop_ref` does
+/// not exist.
+pub fn news(
+    repo: &git2::Repository,
+    drop_ref: &str,
+    topics: &BTreeSet<Topic>,
+) -> Result<Option<News>> {
+    let tip = match if_not_found_none(repo.refname_to_id(drop_ref))? {
+        Some(tip) => tip,
+        None => return Ok(None),
+    };
+    let mut news = News {
+        drop_ref: drop_ref.to_owned(),
+        tip,
+        topics: BTreeMap::new(),
+    };
+    let notified = match if_not_found_none(repo.refname_to_id(&notified_ref(drop_ref)?))? {
+        Some(oid) => oid,
+        None => return Ok(Some(news)),
+    };
+    if topics.is_empty() || notified == tip {
+        return Ok(Some(news));
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.push(tip)?;
+    walk.hide(notified)?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        match Topic::from_commit(&commit)? {
+            Some(topic) if topics.contains(&topic) => {},
+            _ => continue,
+        }
+        let record = Record::from_commit(repo, &commit)?;
+        let update = Update {
+            commit: commit.id(),
+            time: OffsetDateTime::from_unix_timestamp(commit.time().seconds())?,
+            heads: record.heads,
+            signer: record.meta.signature.signer.clone(),
+            comment: !record
+                .bundle_info()
+                .references
+                .keys()
+                .any(|r| r.starts_with("refs/heads/") || r.starts_with("refs/tags/")),
+        };
+        news.topics.entry(record.topic).or_default().push(update);
+    }
+
+    Ok(Some(news))
+}
+
+/// Record that the user was notified about `drop_ref` up to `tip`
+pub fn mark_notified(repo: &git2::Repository, drop_ref: &str, tip: git2::Oid) -> Result<()> {
+    repo.reference(
+        &notified_ref(drop_ref)?,
+        tip,
+        true,
+        &format!("it: notified up to {tip}"),
+    )?;
+
+    Ok(())
+}
+
+/// Run the notification `hook` with `news` as JSON on stdin
+///
+/// `GIT_DIR` is set to `git_dir`. Fails if the hook exits unsuccessfully, so
+/// the user is notified again next time.
+pub fn run_hook(hook: &Path, git_dir: &Path, news: &News) -> Result<()> {
+    let (ok, _) = hooks::run(hook, git_dir, news)?;
+    if !ok {
+        bail!("notify hook {} failed", hook.display());
+    }
+
+    Ok(())
+}
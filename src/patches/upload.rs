@@ -0,0 +1,399 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Resumable uploads of patch bundles in chunks
+//!
+//! Bundles larger than [`super::MAX_LEN_BUNDLE`] can not be submitted in a
+//! single request. Instead, the client announces the length of the bundle
+//! ([`Start`]), which allocates an upload on the server. The bundle is then
+//! sent in chunks of [`Status::chunk_size`] bytes, each carrying its SHA-256
+//! hash in the [`super::HTTP_HEADER_CHUNK_HASH`] header. Chunks may be sent in
+//! any order, and again if the upload was interrupted: the [`Status`] tells
+//! which chunks the server already has. Once all chunks are received, the
+//! client finalises the upload by submitting the patch signature, upon which
+//! the server reassembles the bundle and proceeds as if it was submitted in
+//! one piece.
+//!
+//! Uploads which are not finalised within [`MAX_AGE`] are garbage collected.
+
+use std::{
+    collections::BTreeSet,
+    time::Duration,
+};
+
+use anyhow::ensure;
+
+use crate::Result;
+
+/// Size of the chunks, except for the last one
+pub const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// Time after the last chunk was received after which an unfinished upload
+/// is removed
+pub const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Maximum number of unfinished uploads
+pub const MAX_PENDING: usize = 32;
+/// Maximum number of unfinished uploads started by the same client IP address
+pub const MAX_PENDING_PER_CLIENT: usize = 4;
+
+/// Request to start an upload
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Start {
+    /// Length of the bundle in bytes
+    pub len: u64,
+}
+
+/// State of an upload
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Status {
+    pub id: String,
+    /// Length of the bundle in bytes
+    pub len: u64,
+    pub chunk_size: u64,
+    /// Indices of the chunks received so far
+    pub received: BTreeSet<u64>,
+}
+
+impl Status {
+    /// Check that the upload is of `len` bytes, and the chunk size is within
+    /// `max_chunk_size`
+    ///
+    /// The client must validate every [`Status`] received from the server
+    /// before acting upon it.
+    pub fn validate(&self, len: u64, max_chunk_size: u64) -> Result<()> {
+        ensure!(self.len == len, "remote allocated upload of wrong size");
+        ensure!(
+            self.chunk_size > 0 && self.chunk_size <= max_chunk_size,
+            "remote requested invalid chunk size {}",
+            self.chunk_size
+        );
+        ensure!(
+            self.received.iter().all(|index| *index < self.chunks()),
+            "remote claims to have received chunks out of range"
+        );
+
+        Ok(())
+    }
+
+    /// Total number of chunks of the upload
+    pub fn chunks(&self) -> u64 {
+        chunks(self.len, self.chunk_size)
+    }
+
+    /// Length in bytes of the chunk at `index`
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        self.chunk_size.min(
+            self.len
+                .saturating_sub(index.saturating_mul(self.chunk_size)),
+        )
+    }
+
+    /// Indices of the chunks not yet received
+    pub fn missing(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.chunks()).filter(|i| !self.received.contains(i))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.len() as u64 == self.chunks()
+    }
+}
+
+/// Number of chunks of `chunk_size` bytes needed to hold `len` bytes
+///
+/// Zero if `chunk_size` is zero.
+pub(crate) fn chunks(len: u64, chunk_size: u64) -> u64 {
+    match (len.checked_div(chunk_size), len.checked_rem(chunk_size)) {
+        (Some(n), Some(0)) => n,
+        (Some(n), Some(_)) => n + 1,
+        _ => 0,
+    }
+}
+
+#[cfg(feature = "cli")]
+pub use store::Store;
+
+#[cfg(feature = "cli")]
+mod store {
+    use std::{
+        collections::BTreeSet,
+        fs::{
+            self,
+            File,
+        },
+        io::{
+            self,
+            Read,
+        },
+        net::IpAddr,
+        path::{
+            Path,
+            PathBuf,
+        },
+        time::SystemTime,
+    };
+
+    use anyhow::{
+        anyhow,
+        ensure,
+    };
+    use rand_core::{
+        OsRng,
+        RngCore as _,
+    };
+    use sha2::{
+        Digest as _,
+        Sha256,
+    };
+    use tempfile::NamedTempFile;
+
+    use super::{
+        Status,
+        CHUNK_SIZE,
+        MAX_AGE,
+        MAX_PENDING,
+        MAX_PENDING_PER_CLIENT,
+    };
+    use crate::{
+        io::HashWriter,
+        Result,
+    };
+
+    const META_FILE: &str = "upload.json";
+    const CHUNK_EXTENSION: &str = "chunk";
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Meta {
+        len: u64,
+        chunk_size: u64,
+        /// Address of the client which started the upload
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client: Option<IpAddr>,
+    }
+
+    /// Server-side storage of unfinished uploads
+    ///
+    /// Each upload is a directory below the root, holding one file per chunk
+    /// received.
+    pub struct Store {
+        root: PathBuf,
+        max_len: u64,
+    }
+
+    impl Store {
+        /// Store uploads of bundles of up to `max_len` bytes below `root`
+        pub fn new<P: Into<PathBuf>>(root: P, max_len: u64) -> Self {
+            Self {
+                root: root.into(),
+                max_len,
+            }
+        }
+
+        /// Allocate a new upload of a bundle of `len` bytes on behalf of
+        /// `client`
+        ///
+        /// Fails if `len` exceeds the maximum, or too many uploads are
+        /// pending, in total or from `client`. Clients without an address
+        /// (connected via a unix domain socket) are only subject to the
+        /// total.
+        pub fn create(&self, len: u64, client: Option<IpAddr>) -> Result<Status> {
+            ensure!(len > 0, "empty upload");
+            ensure!(
+                len <= self.max_len,
+                "upload exceeds maximum size of {} bytes",
+                self.max_len
+            );
+            fs::create_dir_all(&self.root)?;
+            let mut pending = 0;
+            let mut pending_client = 0;
+            for entry in fs::read_dir(&self.root)? {
+                pending += 1;
+                if client.is_none() {
+                    continue;
+                }
+                // Uploads may be removed concurrently
+                let meta = self.meta(&entry?.path()).ok().flatten();
+                if meta.map_or(false, |meta| meta.client == client) {
+                    pending_client += 1;
+                }
+            }
+            ensure!(pending < MAX_PENDING, "too many uploads in progress");
+            ensure!(
+                pending_client < MAX_PENDING_PER_CLIENT,
+                "too many uploads in progress from {}",
+                client.map(|addr| addr.to_string()).unwrap_or_default()
+            );
+
+            let mut id = [0; 16];
+            OsRng.fill_bytes(&mut id);
+            let id = hex::encode(id);
+            let dir = self.root.join(&id);
+            fs::create_dir(&dir)?;
+            let meta = Meta {
+                len,
+                chunk_size: CHUNK_SIZE,
+                client,
+            };
+            fs::write(dir.join(META_FILE), serde_json::to_vec(&meta)?)?;
+
+            Ok(Status {
+                id,
+                len,
+                chunk_size: CHUNK_SIZE,
+                received: Default::default(),
+            })
+        }
+
+        /// The state of the upload `id`, or `None` if it doesn't exist
+        pub fn status(&self, id: &str) -> Result<Option<Status>> {
+            let dir = match self.dir(id) {
+                Some(dir) => dir,
+                None => return Ok(None),
+            };
+            let meta = match self.meta(&dir)? {
+                Some(meta) => meta,
+                None => return Ok(None),
+            };
+            let mut received = BTreeSet::new();
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.extension().map_or(false, |ext| ext == CHUNK_EXTENSION) {
+                    if let Some(index) = path
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| s.parse().ok())
+                    {
+                        received.insert(index);
+                    }
+                }
+            }
+
+            Ok(Some(Status {
+                id: id.to_owned(),
+                len: meta.len,
+                chunk_size: meta.chunk_size,
+                received,
+            }))
+        }
+
+        /// Store the chunk at `index` of upload `id`, read from `reader`
+        ///
+        /// `hash` is the hex-encoded SHA-256 hash of the chunk. A chunk
+        /// received before is replaced. Returns `None` if the upload doesn't
+        /// exist.
+        pub fn put_chunk<R: Read>(
+            &self,
+            id: &str,
+            index: u64,
+            hash: &str,
+            reader: R,
+        ) -> Result<Option<Status>> {
+            let dir = match self.dir(id) {
+                Some(dir) => dir,
+                None => return Ok(None),
+            };
+            let meta = match self.meta(&dir)? {
+                Some(meta) => meta,
+                None => return Ok(None),
+            };
+            let mut status = match self.status(id)? {
+                Some(status) => status,
+                None => return Ok(None),
+            };
+            ensure!(index < status.chunks(), "chunk index out of range");
+            let expect_len = status.chunk_len(index);
+
+            let mut tmp = NamedTempFile::new_in(&dir)?;
+            let mut out = HashWriter::new(Sha256::new(), &mut tmp);
+            let len = io::copy(&mut reader.take(expect_len + 1), &mut out)?;
+            ensure!(
+                len == expect_len,
+                "chunk {index} must be {expect_len} bytes, got {len}"
+            );
+            let actual = hex::encode(out.hasher().clone().finalize());
+            ensure!(actual == hash, "chunk {index}: hash mismatch");
+            tmp.persist(chunk_path(&dir, index))?;
+            // Bump the modification time of the upload, cf. `gc`
+            fs::write(dir.join(META_FILE), serde_json::to_vec(&meta)?)?;
+
+            status.received.insert(index);
+            Ok(Some(status))
+        }
+
+        /// A reader over the chunks of the complete upload `id`, in order
+        ///
+        /// Returns `None` if the upload doesn't exist.
+        pub fn reader(&self, id: &str) -> Result<Option<impl Read>> {
+            let status = match self.status(id)? {
+                Some(status) => status,
+                None => return Ok(None),
+            };
+            ensure!(
+                status.is_complete(),
+                "upload incomplete, missing {} of {} chunks",
+                status.missing().count(),
+                status.chunks()
+            );
+            let dir = self.root.join(id);
+            let mut reader: Box<dyn Read> = Box::new(io::empty());
+            for index in 0..status.chunks() {
+                reader = Box::new(reader.chain(File::open(chunk_path(&dir, index))?));
+            }
+
+            Ok(Some(reader))
+        }
+
+        /// Remove the upload `id`, if it exists
+        pub fn remove(&self, id: &str) -> Result<()> {
+            match self.dir(id) {
+                Some(dir) => match fs::remove_dir_all(dir) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                    _ => Ok(()),
+                },
+                None => Ok(()),
+            }
+        }
+
+        /// Remove uploads which have not received a chunk for [`MAX_AGE`]
+        ///
+        /// Returns the number of uploads removed.
+        pub fn gc(&self) -> Result<usize> {
+            let entries = match fs::read_dir(&self.root) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+                Err(e) => return Err(e.into()),
+            };
+            let now = SystemTime::now();
+            let mut removed = 0;
+            for entry in entries {
+                let dir = entry?.path();
+                let mtime = fs::metadata(dir.join(META_FILE))
+                    .or_else(|_| fs::metadata(&dir))?
+                    .modified()?;
+                let age = now.duration_since(mtime).unwrap_or_default();
+                if age >= MAX_AGE {
+                    fs::remove_dir_all(&dir)
+                        .map_err(|e| anyhow!("failed to remove {}: {e}", dir.display()))?;
+                    removed += 1;
+                }
+            }
+
+            Ok(removed)
+        }
+
+        fn meta(&self, dir: &Path) -> Result<Option<Meta>> {
+            match fs::read(dir.join(META_FILE)) {
+                Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        fn dir(&self, id: &str) -> Option<PathBuf> {
+            let valid = id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit());
+            valid.then(|| self.root.join(id))
+        }
+    }
+
+    fn chunk_path(dir: &Path, index: u64) -> PathBuf {
+        dir.join(index.to_string()).with_extension(CHUNK_EXTENSION)
+    }
+}
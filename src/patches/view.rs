@@ -0,0 +1,187 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Read-only view of the drop state as of a past drop commit
+//!
+//! The drop metadata, identities and recorded topics are read from the tree
+//! and history of the commit. Tracking branch positions are not part of the
+//! drop history, so they are replayed from the mergepoint records, subject to
+//! the branch roles in effect when each was recorded.
+
+use core::fmt;
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+};
+
+use anyhow::{
+    anyhow,
+    ensure,
+};
+
+use super::{
+    Record,
+    Topic,
+};
+use crate::{
+    git::Refname,
+    metadata::{
+        self,
+        git::{
+            find_parent,
+            FromGit as _,
+            GitDrop,
+        },
+        DateTime,
+    },
+    Result,
+};
+
+/// A point in the drop history
+#[derive(Clone, Debug)]
+pub enum At {
+    /// The most recent drop commit not newer than the given date
+    Date(DateTime),
+    /// A commit in the drop history, in 'git rev-parse' syntax
+    Rev(String),
+}
+
+impl FromStr for At {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(s.parse()
+            .map(Self::Date)
+            .unwrap_or_else(|_| Self::Rev(s.to_owned())))
+    }
+}
+
+impl fmt::Display for At {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Date(date) => write!(f, "{}", **date),
+            Self::Rev(rev) => f.write_str(rev),
+        }
+    }
+}
+
+pub struct View<'a> {
+    repo: &'a git2::Repository,
+    commit: git2::Commit<'a>,
+}
+
+impl<'a> View<'a> {
+    /// Resolve `at` within the history of `drop_ref`
+    pub fn resolve(repo: &'a git2::Repository, drop_ref: &str, at: &At) -> Result<Self> {
+        let tip = repo.refname_to_id(drop_ref)?;
+        let commit = match at {
+            At::Date(date) => {
+                let mut walk = repo.revwalk()?;
+                walk.push(tip)?;
+                walk.set_sorting(git2::Sort::TIME)?;
+                let mut found = None;
+                for oid in walk {
+                    let commit = repo.find_commit(oid?)?;
+                    if commit.time().seconds() <= date.unix_timestamp() {
+                        found = Some(commit);
+                        break;
+                    }
+                }
+                found.ok_or_else(|| anyhow!("{drop_ref} has no commits as of {at}"))?
+            },
+            At::Rev(rev) => {
+                let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+                ensure!(
+                    commit.id() == tip || repo.graph_descendant_of(tip, commit.id())?,
+                    "{rev} is not in the history of {drop_ref}"
+                );
+                commit
+            },
+        };
+
+        Ok(Self { repo, commit })
+    }
+
+    pub fn commit(&self) -> &git2::Commit<'a> {
+        &self.commit
+    }
+
+    pub fn meta(&self) -> Result<GitDrop> {
+        metadata::Drop::from_commit(self.repo, &self.commit)
+    }
+
+    pub fn tree(&self) -> Result<git2::Tree<'a>> {
+        Ok(self.commit.tree()?)
+    }
+
+    /// The identities as of the view's commit
+    pub fn ids(&self) -> Result<git2::Tree<'a>> {
+        let tree = self.tree()?;
+        let entry = tree
+            .get_name("ids")
+            .ok_or_else(|| anyhow!("'ids' tree not found"))?;
+        Ok(entry.to_object(self.repo)?.peel_to_tree()?)
+    }
+
+    /// The records up to and including the view's commit, oldest first
+    pub fn records(&self) -> Result<Vec<(git2::Oid, Record)>> {
+        let mut walk = self.repo.revwalk()?;
+        walk.push(self.commit.id())?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+
+        let mut records = Vec::new();
+        for oid in walk {
+            let oid = oid?;
+            let commit = self.repo.find_commit(oid)?;
+            // Metadata edits are not records
+            if Topic::from_commit(&commit)?.is_none() {
+                continue;
+            }
+            records.push((oid, Record::from_commit(self.repo, &commit)?));
+        }
+
+        Ok(records)
+    }
+
+    /// The recorded topics, along with their records, oldest first
+    pub fn topics(&self) -> Result<BTreeMap<Topic, Vec<Record>>> {
+        let mut topics: BTreeMap<Topic, Vec<Record>> = BTreeMap::new();
+        for (_, record) in self.records()? {
+            topics.entry(record.topic.clone()).or_default().push(record);
+        }
+
+        Ok(topics)
+    }
+
+    /// The tracking branch positions
+    ///
+    /// A branch is advanced by a mergepoint record if the submitter held the
+    /// branch role at the time. Branches no longer present in the metadata of
+    /// the view's commit are omitted.
+    pub fn branches(&self) -> Result<BTreeMap<Refname, git2::Oid>> {
+        let mut branches = BTreeMap::new();
+        for (oid, record) in self.records()? {
+            if !record.is_mergepoint() {
+                continue;
+            }
+            let meta = metadata::Drop::from_commit(self.repo, &self.repo.find_commit(oid)?)?
+                .signed
+                .signed;
+            let submitter =
+                metadata::Identity::from_content_hash(self.repo, &record.meta.signature.signer)?
+                    .verified(find_parent(self.repo))?;
+            for (name, ann) in &meta.roles.branches {
+                if !ann.role.ids.contains(submitter.id()) {
+                    continue;
+                }
+                if let Some(target) = record.meta.bundle.references.get(name) {
+                    branches.insert(name.clone(), git2::Oid::try_from(target)?);
+                }
+            }
+        }
+        let meta = self.meta()?.signed.signed;
+        branches.retain(|name, _| meta.roles.branches.contains_key(name));
+
+        Ok(branches)
+    }
+}
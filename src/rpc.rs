@@ -0,0 +1,562 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! JSON-RPC 2.0 frontend to the drop [`Service`]
+//!
+//! Messages are exchanged as newline-delimited JSON over a stream socket. In
+//! addition to plain request / response calls, clients may subscribe to
+//! accepted records, which are then pushed as `records.accepted`
+//! notifications on the same connection.
+//!
+//! Each listener serves at most [`MAX_CONNECTIONS`] connections at a time.
+//! Submissions are subject to the same [`Limits`] as over HTTP, albeit
+//! accounted for separately.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Read,
+        Write,
+    },
+    net::{
+        IpAddr,
+        SocketAddr,
+        TcpListener,
+    },
+    path::PathBuf,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        mpsc::RecvTimeoutError,
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{
+    debug,
+    error,
+};
+use serde_json::Value;
+
+use crate::{
+    bundle,
+    http::{
+        limit::{
+            Limiter,
+            Rejected,
+        },
+        Limits,
+    },
+    patches::{
+        self,
+        notes::TopicState,
+        Signature,
+        Topic,
+        MAX_LEN_BUNDLE,
+        MAX_LEN_INFO,
+    },
+    service::Service,
+};
+
+/// Maximum length of a single message, enough to hold a base64-encoded bundle
+const MAX_LEN_MESSAGE: usize = MAX_LEN_BUNDLE / 3 * 4 + MAX_LEN_INFO;
+/// Capacity of the message buffer retained between messages
+///
+/// The buffer is shrunk to this size after receiving a larger message, so
+/// idle connections don't hold on to memory.
+const MAX_RETAINED_BUFFER: usize = 64 * 1024;
+/// Maximum number of concurrent connections per listener
+const MAX_CONNECTIONS: usize = 64;
+/// Maximum number of topics a single connection may subscribe to
+const MAX_SUBSCRIPTIONS: usize = 32;
+/// Interval at which subscriptions check if their connection is still open
+const SUBSCRIPTION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+const RATE_LIMITED: i64 = -32001;
+
+pub enum Listen {
+    /// Bind to the first of the given TCP socket addresses which succeeds
+    Tcp(Vec<SocketAddr>),
+    /// Bind to a Unix domain socket at the given path
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addrs) => match addrs.first() {
+                Some(addr) => write!(f, "{addr}"),
+                None => f.write_str("<none>"),
+            },
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// State shared by all connections to a listener
+struct Shared {
+    service: Arc<Service>,
+    limiter: Limiter,
+    connections: AtomicUsize,
+}
+
+/// Bind `listen` and serve `service` on a background thread
+///
+/// Submissions are subject to `limits`, where TCP connections are identified
+/// by the peer address. Connections via a Unix domain socket are exempt from
+/// the per-address quota.
+pub fn spawn(listen: Listen, service: Arc<Service>, limits: Limits) -> io::Result<()> {
+    let shared = Arc::new(Shared {
+        service,
+        limiter: Limiter::new(limits, None),
+        connections: AtomicUsize::new(0),
+    });
+    match listen {
+        Listen::Tcp(addrs) => {
+            let listener = TcpListener::bind(&addrs[..])?;
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream.and_then(|s| Ok((s.peer_addr()?.ip(), s.try_clone()?, s))) {
+                        Ok((addr, r, w)) => connection(Some(addr), r, w, &shared),
+                        Err(e) => error!("rpc: failed to accept connection: {e}"),
+                    }
+                }
+            });
+        },
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                        Ok((r, w)) => connection(None, r, w, &shared),
+                        Err(e) => error!("rpc: failed to accept connection: {e}"),
+                    }
+                }
+            });
+        },
+    }
+
+    Ok(())
+}
+
+fn connection<R, W>(addr: Option<IpAddr>, reader: R, writer: W, shared: &Arc<Shared>)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let writer = Arc::new(Mutex::new(writer));
+    let slot = match Slot::acquire(shared) {
+        Some(slot) => slot,
+        None => {
+            debug!("rpc: too many connections, rejecting");
+            let resp = Response {
+                jsonrpc: "2.0",
+                id: &Value::Null,
+                outcome: Outcome::Error(Error::new(SERVER_ERROR, "too many connections")),
+            };
+            send(&writer, &resp).ok();
+            return;
+        },
+    };
+    thread::spawn(move || {
+        let conn = Connection {
+            shared: Arc::clone(&slot.0),
+            addr,
+            writer,
+            subscriptions: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+        if let Err(e) = conn.run(BufReader::new(reader)) {
+            debug!("rpc: connection closed: {e}");
+        }
+        conn.closed.store(true, Ordering::Release);
+        drop(slot);
+    });
+}
+
+/// One of the [`MAX_CONNECTIONS`] of a listener, released when dropped
+struct Slot(Arc<Shared>);
+
+impl Slot {
+    fn acquire(shared: &Arc<Shared>) -> Option<Self> {
+        let prev = shared.connections.fetch_add(1, Ordering::AcqRel);
+        // Releases the slot again if we bail out
+        let slot = Self(Arc::clone(shared));
+        (prev < MAX_CONNECTIONS).then(|| slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(serde::Serialize)]
+struct Response<'a> {
+    jsonrpc: &'static str,
+    id: &'a Value,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Result(Value),
+    Error(Error),
+}
+
+#[derive(serde::Serialize)]
+struct Notification<'a, T> {
+    jsonrpc: &'static str,
+    method: &'a str,
+    params: T,
+}
+
+#[derive(serde::Serialize)]
+struct Error {
+    code: i64,
+    message: String,
+}
+
+impl Error {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<crate::Error> for Error {
+    fn from(e: crate::Error) -> Self {
+        Self::new(SERVER_ERROR, e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(INVALID_PARAMS, e)
+    }
+}
+
+impl From<Rejected> for Error {
+    fn from(rejected: Rejected) -> Self {
+        match rejected {
+            Rejected::Quota(retry_after) => Self::new(
+                RATE_LIMITED,
+                format!(
+                    "submission quota exceeded, retry after {}s",
+                    retry_after.as_secs()
+                ),
+            ),
+            Rejected::Busy => Self::new(SERVER_ERROR, "too many uploads in progress, retry later"),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TopicParams {
+    topic: Topic,
+}
+
+#[derive(serde::Deserialize)]
+struct SubmitParams {
+    signature: Signature,
+    /// The base64-encoded bundle
+    bundle: String,
+    /// The hash algorithm the bundle hash and heads were computed with
+    #[serde(default)]
+    alg: bundle::HashAlgorithm,
+}
+
+#[derive(serde::Deserialize)]
+struct RegisterParams {
+    signature: Signature,
+    info: bundle::Info,
+}
+
+#[derive(Default, serde::Deserialize)]
+struct SubscribeParams {
+    #[serde(default)]
+    topic: Option<Topic>,
+}
+
+#[derive(serde::Serialize)]
+struct TopicInfo {
+    topic: Topic,
+    subject: String,
+    state: TopicState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diffstat: Option<String>,
+}
+
+/// The topics a connection subscribed to
+#[derive(Default)]
+struct Subscriptions {
+    /// Subscribed to all topics
+    all: bool,
+    topics: BTreeSet<Topic>,
+}
+
+impl Subscriptions {
+    fn matches(&self, topic: &Topic) -> bool {
+        self.all || self.topics.contains(topic)
+    }
+}
+
+struct Connection<W> {
+    shared: Arc<Shared>,
+    /// Peer address, `None` for Unix domain sockets
+    addr: Option<IpAddr>,
+    writer: Arc<Mutex<W>>,
+    /// `None` until the first subscription
+    subscriptions: Arc<Mutex<Option<Subscriptions>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl<W: Write + Send + 'static> Connection<W> {
+    fn run<R: BufRead>(&self, mut reader: R) -> io::Result<()> {
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let n = (&mut reader)
+                .take(MAX_LEN_MESSAGE as u64 + 1)
+                .read_until(b'\n', &mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            if buf.last() != Some(&b'\n') {
+                let err = Error::new(INVALID_REQUEST, "message too large");
+                self.respond(&Value::Null, Outcome::Error(err))?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message too large",
+                ));
+            }
+            if buf.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let req = serde_json::from_slice::<Request>(&buf);
+            if buf.capacity() > MAX_RETAINED_BUFFER {
+                buf = Vec::with_capacity(MAX_RETAINED_BUFFER);
+            }
+            let req = match req {
+                Ok(req) => req,
+                Err(e) => {
+                    let code = if e.is_data() {
+                        INVALID_REQUEST
+                    } else {
+                        PARSE_ERROR
+                    };
+                    self.respond(&Value::Null, Outcome::Error(Error::new(code, e)))?;
+                    continue;
+                },
+            };
+            debug!("rpc: {}", req.method);
+            let outcome = match self.dispatch(&req.method, req.params) {
+                Ok(v) => Outcome::Result(v),
+                Err(e) => Outcome::Error(e),
+            };
+            // Requests without an id are notifications, which don't get a
+            // response
+            if let Some(id) = &req.id {
+                self.respond(id, outcome)?;
+            }
+            self.shared.service.maintain_if_due();
+        }
+    }
+
+    fn dispatch(&self, method: &str, params: Value) -> Result<Value, Error> {
+        match method {
+            "topics.list" => {
+                let topics = self
+                    .shared
+                    .service
+                    .topics()?
+                    .into_iter()
+                    .map(|(topic, subject, state, diffstat)| TopicInfo {
+                        topic,
+                        subject,
+                        state,
+                        diffstat: diffstat.map(|stat| stat.to_string()),
+                    })
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_value(topics)?)
+            },
+            "topics.show" => {
+                let TopicParams { topic } = serde_json::from_value(params)?;
+                Ok(serde_json::to_value(self.shared.service.topic(&topic)?)?)
+            },
+            "patches.submit" => {
+                let SubmitParams {
+                    signature,
+                    bundle,
+                    alg,
+                } = serde_json::from_value(params)?;
+                let bundle = base64::decode(bundle)
+                    .map_err(|e| Error::new(INVALID_PARAMS, format!("invalid bundle: {e}")))?;
+                if bundle.len() > MAX_LEN_BUNDLE {
+                    return Err(Error::new(
+                        INVALID_PARAMS,
+                        format!("submitted patch bundle exceeds {MAX_LEN_BUNDLE}"),
+                    ));
+                }
+                let limiter = &self.shared.limiter;
+                limiter.submission(self.addr)?;
+                let _upload = limiter.upload()?;
+                let service = &self.shared.service;
+                let sub = patches::Submission::from_reader(
+                    service.bundle_dir(),
+                    signature,
+                    alg,
+                    &bundle[..],
+                )?;
+                Ok(serde_json::to_value(&*service.accept(sub)?)?)
+            },
+            "patches.register" => {
+                let RegisterParams { signature, info } = serde_json::from_value(params)?;
+                let service = &self.shared.service;
+                if service.stored_bundle(&info.hash).is_none() {
+                    return Err(anyhow!("bundle {} not found", info.hash).into());
+                }
+                self.shared.limiter.submission(self.addr)?;
+                let sub = patches::Submission::from_stored(service.bundle_dir(), signature, &info)?;
+                Ok(serde_json::to_value(&*service.accept(sub)?)?)
+            },
+            "records.subscribe" => {
+                let SubscribeParams { topic } = if params.is_null() {
+                    SubscribeParams::default()
+                } else {
+                    serde_json::from_value(params)?
+                };
+                self.subscribe(topic)?;
+                Ok(Value::Bool(true))
+            },
+            _ => Err(Error::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {method}"),
+            )),
+        }
+    }
+
+    /// Forward accepted records to `topic`, or all topics if `None`, until
+    /// the connection is closed
+    ///
+    /// All subscriptions of a connection are served by a single thread,
+    /// spawned upon the first one. Subscribing to the same topic twice, or to
+    /// more than [`MAX_SUBSCRIPTIONS`] topics, is an error.
+    fn subscribe(&self, topic: Option<Topic>) -> Result<(), Error> {
+        let mut guard = self.subscriptions.lock().unwrap();
+        let first = guard.is_none();
+        let subs = guard.get_or_insert_with(Subscriptions::default);
+        match topic {
+            None if subs.all => {
+                return Err(Error::new(INVALID_PARAMS, "already subscribed"));
+            },
+            None => subs.all = true,
+            Some(topic) => {
+                if subs.topics.contains(&topic) {
+                    return Err(Error::new(
+                        INVALID_PARAMS,
+                        format!("already subscribed to {topic}"),
+                    ));
+                }
+                if subs.topics.len() >= MAX_SUBSCRIPTIONS {
+                    return Err(Error::new(
+                        INVALID_PARAMS,
+                        format!("at most {MAX_SUBSCRIPTIONS} topics may be subscribed to"),
+                    ));
+                }
+                subs.topics.insert(topic);
+            },
+        }
+        if !first {
+            return Ok(());
+        }
+
+        let rx = self.shared.service.subscribe();
+        let writer = Arc::clone(&self.writer);
+        let subscriptions = Arc::clone(&self.subscriptions);
+        let closed = Arc::clone(&self.closed);
+        thread::spawn(move || loop {
+            let record = match rx.recv_timeout(SUBSCRIPTION_POLL_INTERVAL) {
+                Ok(record) => record,
+                Err(RecvTimeoutError::Timeout) if !closed.load(Ordering::Acquire) => continue,
+                Err(_) => break,
+            };
+            let matches = subscriptions
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map_or(false, |subs| subs.matches(&record.topic));
+            if !matches {
+                continue;
+            }
+            let notification = Notification {
+                jsonrpc: "2.0",
+                method: "records.accepted",
+                params: &*record,
+            };
+            if let Err(e) = send(&writer, &notification) {
+                debug!("rpc: dropping subscription: {e}");
+                break;
+            }
+        });
+
+        Ok(())
+    }
+
+    fn respond(&self, id: &Value, outcome: Outcome) -> io::Result<()> {
+        send(
+            &self.writer,
+            &Response {
+                jsonrpc: "2.0",
+                id,
+                outcome,
+            },
+        )
+    }
+}
+
+fn send<W, T>(writer: &Mutex<W>, msg: &T) -> io::Result<()>
+where
+    W: Write,
+    T: serde::Serialize,
+{
+    let mut buf = serde_json::to_vec(msg)?;
+    buf.push(b'\n');
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(&buf)?;
+    writer.flush()
+}
@@ -15,14 +15,31 @@ pub mod display {
         serializer.serialize_str(&v.to_string())
     }
 
-    #[allow(unused)]
     pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
     where
         T: FromStr,
         T::Err: fmt::Display,
         D: serde::Deserializer<'de>,
     {
-        let s: &str = serde::Deserialize::deserialize(deserializer)?;
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
         s.parse().map_err(serde::de::Error::custom)
     }
 }
+
+pub mod base64 {
+    pub fn serialize<T, S>(v: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&::base64::encode(v))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: &str = serde::Deserialize::deserialize(deserializer)?;
+        ::base64::decode(s).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,851 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Drop operations shared between the network frontends
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        mpsc,
+        Arc,
+        Mutex,
+        TryLockError,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+    ensure,
+};
+use log::{
+    debug,
+    error,
+    warn,
+};
+use url::Url;
+
+use crate::{
+    bundle,
+    cfg,
+    git::{
+        self,
+        if_not_found_none,
+        Refname,
+    },
+    keys,
+    metadata::{
+        self,
+        git::FromGit,
+        identity::ExpiryPolicy,
+    },
+    patches::{
+        self,
+        composite,
+        issues,
+        iter,
+        notes::TopicState,
+        record::Diffstat,
+        AcceptArgs,
+        AcceptOptions,
+        AutoReply,
+        Topic,
+        REF_HEADS_PATCHES,
+        REF_IT_BUNDLES,
+        REF_IT_PATCHES,
+        REF_IT_SEEN,
+    },
+    paths,
+    ssh::agent,
+};
+
+/// How often to check for pending webhook deliveries due for retry
+const WEBHOOK_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How often to retry pending IPFS pins, unless a patch was accepted since
+const PIN_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub struct Options {
+    /// Directory of the drop repo
+    pub git_dir: PathBuf,
+    /// Directory from where to serve bundles
+    ///
+    /// Unless absolute, treated as relative to GIT_DIR.
+    pub bundle_dir: PathBuf,
+    /// Ref prefix under which to store the refs contained in patch bundles
+    pub unbundle_prefix: String,
+    /// The refname of the drop history
+    pub drop_ref: String,
+    /// The refname anchoring the seen objects tree
+    pub seen_ref: String,
+    /// IPFS API to publish received bundles to
+    pub ipfs_api: Option<Url>,
+    /// Externally visible base URL, if different from the listen address
+    pub public_url: Option<Url>,
+    /// Start even if the refnames conflict with existing state
+    pub force: bool,
+    /// Render browsable HTML pages of the drop
+    pub serve_html: bool,
+    /// How to treat expired identities of the members of the drop's roles
+    pub expiry: ExpiryPolicy,
+}
+
+/// Summary of the drop state, for monitoring
+#[derive(serde::Serialize)]
+pub struct Status {
+    #[serde(with = "crate::git::serde::oid")]
+    pub drop_tip: git2::Oid,
+    /// Number of records in the drop history
+    pub records: usize,
+    /// Externally visible base URL of the drop, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+}
+
+/// Outcome of the readiness checks performed by [`Service::readiness`]
+#[derive(serde::Serialize)]
+pub struct Readiness {
+    pub repo: Check,
+    pub signer: Check,
+    pub bundle_dir: Check,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.repo.ok && self.signer.ok && self.bundle_dir.ok
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<crate::Result<()>> for Check {
+    fn from(res: crate::Result<()>) -> Self {
+        match res {
+            Ok(()) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Self {
+                ok: false,
+                error: Some(format!("{e:#}")),
+            },
+        }
+    }
+}
+
+/// The drop description and branches, for display
+pub struct Overview {
+    pub description: metadata::drop::Description,
+    pub branches: Vec<Branch>,
+}
+
+pub struct Branch {
+    pub name: Refname,
+    pub description: metadata::drop::Description,
+    /// Tip of the tracking branch, if it exists
+    pub tip: Option<git2::Oid>,
+}
+
+/// Notification about a patch having been accepted
+pub type Accepted = Arc<patches::Record>;
+
+pub struct Service {
+    git_dir: PathBuf,
+    repo: Mutex<git2::Repository>,
+    drop_cache: patches::DropHeadCache,
+    reachable_cache: patches::reachable::Cache,
+    /// Number of records as of the drop tip, cf. [`Self::status`]
+    status_cache: Mutex<Option<(git2::Oid, usize)>>,
+    signer: Mutex<keys::Agent<agent::UnixStream>>,
+    bundle_dir: PathBuf,
+    unbundle_prefix: String,
+    drop_ref: String,
+    seen_ref: String,
+    ipfs_api: Option<Url>,
+    public_url: Option<Url>,
+    serve_html: bool,
+    expiry: ExpiryPolicy,
+    hooks: patches::Hooks,
+    lint: patches::lint::Rules,
+    quarantine_new: bool,
+    allow_encrypted: bool,
+    dedup_snapshots: bool,
+    spam_policy: Option<Arc<dyn patches::spam::Policy>>,
+    audit: bool,
+    unbundle: bool,
+    maintenance_interval: u64,
+    maintenance_due: AtomicBool,
+    pins_due: AtomicBool,
+    auto_reply_interval: Duration,
+    last_auto_reply: Mutex<BTreeMap<Topic, Instant>>,
+    last_pin_retry: Mutex<Instant>,
+    last_webhook_retry: Mutex<Instant>,
+    subscribers: Mutex<Vec<mpsc::Sender<Accepted>>>,
+    /// Drops accepting composite patches together with this one, by part name
+    siblings: BTreeMap<String, PathBuf>,
+}
+
+impl Service {
+    pub fn open(opts: Options) -> crate::Result<Self> {
+        let repo = git::repo::open(&opts.git_dir)?;
+        check_refs(&repo, &opts)?;
+        let config = cfg::git::open(&repo)?;
+
+        let bundle_dir = if opts.bundle_dir.is_relative() {
+            repo.path().join(opts.bundle_dir)
+        } else {
+            opts.bundle_dir
+        };
+
+        let signer = keys::Agent::from_gitconfig(&config)?;
+        let maintenance_interval = cfg::git::maintenance_interval(&config)?;
+        let hooks = cfg::git::accept_hooks(&config)?;
+        let lint = cfg::git::lint_rules(&config)?;
+        let quarantine_new = cfg::git::quarantine_new(&config)?;
+        let allow_encrypted = cfg::git::allow_encrypted(&config)?;
+        let dedup_snapshots = cfg::git::dedup_snapshots(&config)?;
+        let spam_policy = cfg::git::spam_policy(&config)?
+            .map(|rules| Arc::new(rules) as Arc<dyn patches::spam::Policy>);
+        let audit = cfg::git::audit_log(&config)?;
+        let unbundle = cfg::git::unbundle(&config)?;
+        let auto_reply_interval = Duration::from_secs(cfg::git::auto_reply_interval(&config)?);
+        let siblings = cfg::git::sibling_drops(&config)?
+            .into_iter()
+            .map(|(name, path)| (name, repo.path().join(path)))
+            .collect();
+
+        Ok(Self {
+            git_dir: repo.path().to_owned(),
+            repo: Mutex::new(repo),
+            drop_cache: patches::DropHeadCache::default(),
+            reachable_cache: patches::reachable::Cache::default(),
+            status_cache: Mutex::new(None),
+            signer: Mutex::new(signer),
+            bundle_dir,
+            unbundle_prefix: opts.unbundle_prefix,
+            drop_ref: opts.drop_ref,
+            seen_ref: opts.seen_ref,
+            ipfs_api: opts.ipfs_api,
+            public_url: opts.public_url,
+            serve_html: opts.serve_html,
+            expiry: opts.expiry,
+            hooks,
+            lint,
+            quarantine_new,
+            allow_encrypted,
+            dedup_snapshots,
+            spam_policy,
+            audit,
+            unbundle,
+            maintenance_interval,
+            maintenance_due: AtomicBool::new(false),
+            pins_due: AtomicBool::new(false),
+            auto_reply_interval,
+            last_auto_reply: Mutex::new(BTreeMap::new()),
+            last_pin_retry: Mutex::new(Instant::now()),
+            last_webhook_retry: Mutex::new(Instant::now()),
+            subscribers: Mutex::new(Vec::new()),
+            siblings,
+        })
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub fn bundle_dir(&self) -> &Path {
+        &self.bundle_dir
+    }
+
+    pub fn public_url(&self) -> Option<&Url> {
+        self.public_url.as_ref()
+    }
+
+    pub fn serve_html(&self) -> bool {
+        self.serve_html
+    }
+
+    /// Path of the bundle file with the given hash, if we have it
+    ///
+    /// The bundle may be stored in chunks, in which case the path does not
+    /// exist, cf. [`bundle::chunks`].
+    pub fn stored_bundle(&self, hash: &bundle::Hash) -> Option<PathBuf> {
+        let path = self
+            .bundle_dir
+            .join(hash.to_string())
+            .with_extension(bundle::FILE_EXTENSION);
+        bundle::chunks::exists(&path).then_some(path)
+    }
+
+    /// Try to accept a patch submission, and notify subscribers if successful
+    ///
+    /// Fails with [`patches::Quarantined`] if the submission was held for
+    /// review, or [`patches::spam::Rejected`] if it violates the spam policy.
+    pub fn accept(&self, mut sub: patches::Submission) -> crate::Result<Accepted> {
+        let repo = self.repo.lock().unwrap();
+        let mut signer = self.signer.lock().unwrap();
+        let ids_before = patches::DropHead::from_refname_cached(
+            &repo,
+            &self.drop_ref,
+            Some(&self.drop_cache),
+            self.expiry,
+        )?
+        .ids
+        .id();
+        let record = sub.try_accept(self.accept_args(&repo, &mut *signer))?;
+        let reply = match self.auto_reply(&repo, &mut *signer, ids_before, &record) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("failed to post automated reply to {}: {e:#}", record.topic);
+                None
+            },
+        };
+        match git::maintenance::record_accept(repo.path(), self.maintenance_interval) {
+            Ok(due) => {
+                if due {
+                    self.maintenance_due.store(true, Ordering::Release)
+                }
+            },
+            Err(e) => error!("failed to record accept for maintenance: {e:#}"),
+        }
+        // Retry pending IPFS pins now that the IPFS API is likely reachable
+        // again
+        self.pins_due.store(true, Ordering::Release);
+
+        let record = Arc::new(record);
+        let reply = reply.map(Arc::new);
+        self.subscribers.lock().unwrap().retain(|tx| {
+            tx.send(Arc::clone(&record)).is_ok()
+                && reply.iter().all(|reply| tx.send(Arc::clone(reply)).is_ok())
+        });
+
+        Ok(record)
+    }
+
+    fn accept_args<'a, S>(
+        &'a self,
+        repo: &'a git2::Repository,
+        signer: &'a mut S,
+    ) -> AcceptArgs<'a, S> {
+        AcceptArgs {
+            unbundle_prefix: &self.unbundle_prefix,
+            drop_ref: &self.drop_ref,
+            seen_ref: &self.seen_ref,
+            repo,
+            signer,
+            ipfs_api: self.ipfs_api.as_ref(),
+            drop_cache: Some(&self.drop_cache),
+            reachable_cache: Some(&self.reachable_cache),
+            options: AcceptOptions {
+                hooks: self.hooks.clone(),
+                lint: self.lint.clone(),
+                quarantine_new: self.quarantine_new,
+                allow_encrypted: self.allow_encrypted,
+                dedup_snapshots: self.dedup_snapshots,
+                spam_policy: self.spam_policy.clone(),
+                audit: self.audit,
+                unbundle: self.unbundle,
+                expiry: self.expiry,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Directory where to store the bundle of the composite patch part `name`
+    pub fn sibling_bundle_dir(&self, name: &str) -> crate::Result<PathBuf> {
+        let repo = git::repo::open(self.sibling(name)?)?;
+        if repo.path() == self.git_dir {
+            Ok(self.bundle_dir.clone())
+        } else {
+            Ok(repo.path().join(paths::bundles()))
+        }
+    }
+
+    /// Accept the parts of a composite patch, all or none
+    ///
+    /// Every part is accepted by the sibling drop configured for its name, cf.
+    /// [`composite::accept`]. Subscribers are notified about the part accepted
+    /// by this drop, if any.
+    pub fn accept_composite(
+        &self,
+        mut parts: Vec<(String, patches::Submission)>,
+    ) -> crate::Result<Vec<Accepted>> {
+        let _repo = self.repo.lock().unwrap();
+        let mut repos = Vec::with_capacity(parts.len());
+        let mut signers = Vec::with_capacity(parts.len());
+        let mut options = Vec::with_capacity(parts.len());
+        for (name, _) in &parts {
+            let repo = git::repo::open(self.sibling(name)?)?;
+            let config = cfg::git::open(&repo)?;
+            signers.push(keys::Agent::from_gitconfig(&config)?);
+            options.push(AcceptOptions {
+                hooks: cfg::git::accept_hooks(&config)?,
+                lint: cfg::git::lint_rules(&config)?,
+                quarantine_new: cfg::git::quarantine_new(&config)?,
+                allow_encrypted: cfg::git::allow_encrypted(&config)?,
+                spam_policy: cfg::git::spam_policy(&config)?
+                    .map(|rules| Arc::new(rules) as Arc<dyn patches::spam::Policy>),
+                unbundle: cfg::git::unbundle(&config)?,
+                expiry: self.expiry,
+                ..Default::default()
+            });
+            repos.push(repo);
+        }
+
+        let accept = parts
+            .iter_mut()
+            .zip(repos.iter().zip(signers.iter_mut()))
+            .zip(options)
+            .map(|(((name, submission), (repo, signer)), options)| {
+                let own = repo.path() == self.git_dir;
+                composite::Accept {
+                    name,
+                    args: AcceptArgs {
+                        unbundle_prefix: &self.unbundle_prefix,
+                        drop_ref: &self.drop_ref,
+                        seen_ref: &self.seen_ref,
+                        repo,
+                        signer,
+                        ipfs_api: self.ipfs_api.as_ref().filter(|_| own),
+                        drop_cache: None,
+                        reachable_cache: None,
+                        options,
+                    },
+                    submission,
+                }
+            })
+            .collect();
+        let records = composite::accept(accept)?
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+
+        if let Some(own) = repos.iter().position(|repo| repo.path() == self.git_dir) {
+            self.pins_due.store(true, Ordering::Release);
+            let record = &records[own];
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|tx| tx.send(Arc::clone(record)).is_ok());
+        }
+
+        Ok(records)
+    }
+
+    fn sibling(&self, name: &str) -> crate::Result<&Path> {
+        self.siblings
+            .get(name)
+            .map(PathBuf::as_path)
+            .ok_or_else(|| anyhow!("no sibling drop configured for part '{name}'"))
+    }
+
+    /// Submit the drop's [`AutoReply`], if any, if `record` is a patch by a
+    /// submitter not present in the `ids_before` tree
+    ///
+    /// The reply is accepted like any other submission, except that the spam
+    /// policy does not apply. Replies are rate-limited to one per topic per
+    /// `it.autoReplyInterval`.
+    ///
+    /// Returns the record of the reply, if one was posted.
+    fn auto_reply<S: keys::Signer>(
+        &self,
+        repo: &git2::Repository,
+        signer: &mut S,
+        ids_before: git2::Oid,
+        record: &patches::Record,
+    ) -> crate::Result<Option<patches::Record>> {
+        let is_patch = record
+            .meta
+            .bundle
+            .references
+            .keys()
+            // Identity updates are conveyed as branches, too
+            .any(|r| r.starts_with("refs/heads/") && !r.starts_with("refs/heads/it/"));
+        if !is_patch {
+            return Ok(None);
+        }
+        let reply = match AutoReply::from_drop(repo, &self.drop_ref)? {
+            Some(reply) => reply,
+            None => return Ok(None),
+        };
+        let submitter = metadata::Identity::from_content_hash(repo, &record.meta.signature.signer)?
+            .signed
+            .verify(metadata::git::find_parent(repo))?;
+        if repo
+            .find_tree(ids_before)?
+            .get_name(&submitter.to_string())
+            .is_some()
+        {
+            return Ok(None);
+        }
+        {
+            let mut last = self.last_auto_reply.lock().unwrap();
+            last.retain(|_, prev| prev.elapsed() < self.auto_reply_interval);
+            if last.contains_key(&record.topic) {
+                debug!("rate limit exceeded, not replying to {}", record.topic);
+                return Ok(None);
+            }
+            last.insert(record.topic.clone(), Instant::now());
+        }
+
+        let mut sub = {
+            let drop = patches::DropHead::from_refname_cached(
+                repo,
+                &self.drop_ref,
+                Some(&self.drop_cache),
+                self.expiry,
+            )?;
+            reply.submission(repo, signer, &drop, &self.bundle_dir, record, &submitter)?
+        };
+        let mut args = self.accept_args(repo, signer);
+        args.options.spam_policy = None;
+        let reply = sub.try_accept(args)?;
+        debug!("posted automated reply {} to {}", reply.heads, reply.topic);
+
+        Ok(Some(reply))
+    }
+
+    /// The current drop tip and number of records
+    ///
+    /// Reads from a separate handle to the repository, so as to not contend
+    /// with accepts. The number of records is only recounted if the drop tip
+    /// changed since the last call.
+    pub fn status(&self) -> crate::Result<Status> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let drop_tip = repo.refname_to_id(&self.drop_ref)?;
+        let cached = *self.status_cache.lock().unwrap();
+        let records = match cached {
+            Some((tip, records)) if tip == drop_tip => records,
+            _ => {
+                // Count from `drop_tip`, as the ref may have moved since
+                let mut walk = repo.revwalk()?;
+                walk.push(drop_tip)?;
+                let mut records = 0;
+                for oid in walk {
+                    if Topic::from_commit(&repo.find_commit(oid?)?)?.is_some() {
+                        records += 1;
+                    }
+                }
+                *self.status_cache.lock().unwrap() = Some((drop_tip, records));
+                records
+            },
+        };
+
+        Ok(Status {
+            drop_tip,
+            records,
+            url: self.public_url.clone(),
+        })
+    }
+
+    /// Path of the stored profile of `id`, if `id` is an identity of the drop
+    ///
+    /// Like [`Self::status`], this reads from a separate handle to the
+    /// repository.
+    pub fn profile(&self, id: &metadata::IdentityId) -> crate::Result<Option<PathBuf>> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let tree = repo.find_reference(&self.drop_ref)?.peel_to_tree()?;
+        if if_not_found_none(tree.get_path(&Path::new("ids").join(id.to_string())))?.is_none() {
+            return Ok(None);
+        }
+        let path = metadata::profile::path(&self.git_dir, id);
+
+        Ok(path.exists().then_some(path))
+    }
+
+    /// The drop description and its branches, along with their current tips
+    ///
+    /// Like [`Self::status`], this reads from a separate handle to the
+    /// repository.
+    pub fn overview(&self) -> crate::Result<Overview> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let drop =
+            patches::DropHead::from_refname_cached(&repo, &self.drop_ref, None, self.expiry)?;
+        let branches = drop
+            .meta
+            .roles
+            .branches
+            .iter()
+            .map(|(name, ann)| {
+                let tip = match patches::TrackingBranch::for_branch(name) {
+                    Ok(tracking) => if_not_found_none(repo.refname_to_id(&tracking))?,
+                    Err(_) => None,
+                };
+                Ok(Branch {
+                    name: name.clone(),
+                    description: ann.description.clone(),
+                    tip,
+                })
+            })
+            .collect::<crate::Result<_>>()?;
+
+        Ok(Overview {
+            description: drop.meta.description.clone(),
+            branches,
+        })
+    }
+
+    /// Check whether the service is able to accept patches
+    ///
+    /// That is, the repository can be opened, the signer is reachable and holds
+    /// the signing key, and the bundle directory is writable.
+    pub fn readiness(&self) -> Readiness {
+        let repo = git::repo::open(&self.git_dir)
+            .map(|_| ())
+            .map_err(Into::into);
+        let signer = match self.signer.try_lock() {
+            Ok(mut signer) => signer.ensure_key(),
+            // In use, so evidently reachable
+            Err(TryLockError::WouldBlock) => Ok(()),
+            Err(TryLockError::Poisoned(_)) => Err(anyhow!("signer lock poisoned")),
+        };
+        let bundle_dir = std::fs::create_dir_all(&self.bundle_dir)
+            .and_then(|()| tempfile::tempfile_in(&self.bundle_dir))
+            .map(|_| ())
+            .map_err(Into::into);
+
+        Readiness {
+            repo: repo.into(),
+            signer: signer.into(),
+            bundle_dir: bundle_dir.into(),
+        }
+    }
+
+    /// Receive a notification for every patch accepted from now on
+    ///
+    /// Dropping the [`mpsc::Receiver`] unsubscribes.
+    #[cfg_attr(not(feature = "rpc"), allow(unused))]
+    pub fn subscribe(&self) -> mpsc::Receiver<Accepted> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Run repository maintenance if it became due after an accept, and
+    /// retry pending IPFS pins and webhook deliveries, cf.
+    /// [`Self::retry_if_due`]
+    ///
+    /// Any accepts are blocked while maintenance is running.
+    pub fn maintain_if_due(&self) {
+        if self.maintenance_due.swap(false, Ordering::AcqRel) {
+            let repo = self.repo.lock().unwrap();
+            if let Err(e) = git::maintenance::run(repo.path()) {
+                error!("maintenance failed: {e:#}");
+            }
+        }
+        self.retry_if_due();
+    }
+
+    /// Retry pending IPFS pins if a patch was accepted since they were last
+    /// retried, or [`PIN_RETRY_INTERVAL`] elapsed, and pending webhook
+    /// deliveries every [`WEBHOOK_RETRY_INTERVAL`]
+    ///
+    /// Does nothing if another thread is already retrying.
+    pub fn retry_if_due(&self) {
+        let accepted = self.pins_due.swap(false, Ordering::AcqRel);
+        run_if_due(&self.last_pin_retry, PIN_RETRY_INTERVAL, accepted, || {
+            if let Err(e) = patches::pins::retry(&self.git_dir) {
+                error!("retrying pending IPFS pins failed: {e:#}");
+            }
+        });
+        if !self.hooks.webhooks.is_empty() {
+            run_if_due(
+                &self.last_webhook_retry,
+                WEBHOOK_RETRY_INTERVAL,
+                false,
+                || {
+                    if let Err(e) =
+                        patches::hooks::webhook::retry(&self.git_dir, &self.hooks.webhooks)
+                    {
+                        error!("retrying pending webhook deliveries failed: {e:#}");
+                    }
+                },
+            );
+        }
+    }
+
+    /// Call [`Self::retry_if_due`] periodically on a background thread
+    ///
+    /// Ensures retries happen even if no requests are coming in. The thread
+    /// exits once the service is dropped.
+    pub fn spawn_retries(self: &Arc<Self>) -> io::Result<()> {
+        let service = Arc::downgrade(self);
+        let interval = WEBHOOK_RETRY_INTERVAL.min(PIN_RETRY_INTERVAL);
+        thread::Builder::new()
+            .name("retry".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match service.upgrade() {
+                    Some(service) => service.retry_if_due(),
+                    None => break,
+                }
+            })?;
+
+        Ok(())
+    }
+
+    /// List the topics known to the drop, along with their subject line,
+    /// state and total diffstat
+    ///
+    /// Like [`Self::status`], this reads from a separate handle to the
+    /// repository.
+    #[allow(clippy::type_complexity)]
+    pub fn topics(&self) -> crate::Result<Vec<(Topic, String, TopicState, Option<Diffstat>)>> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let mut diffstats = iter::dropped::diffstats(&repo, &self.drop_ref)?;
+        iter::unbundled::topics_with_subject(&repo)
+            .map(|i| {
+                i.map(|(topic, subject, state)| {
+                    let diffstat = diffstats.remove(&topic);
+                    (topic, subject, state, diffstat)
+                })
+            })
+            .collect()
+    }
+
+    /// The notes of `topic`, in topological order
+    ///
+    /// Like [`Self::status`], this reads from a separate handle to the
+    /// repository.
+    pub fn topic(&self, topic: &Topic) -> crate::Result<Vec<iter::Note>> {
+        let repo = git::repo::open(&self.git_dir)?;
+        iter::topic(&repo, topic).collect()
+    }
+
+    /// The issues of external issue trackers linked to topics, cf.
+    /// [`issues::collect`]
+    ///
+    /// Like [`Self::status`], this reads from a separate handle to the
+    /// repository.
+    pub fn issues(&self) -> crate::Result<issues::Links> {
+        let repo = git::repo::open(&self.git_dir)?;
+        let drop = patches::DropHead::from_refname_cached(
+            &repo,
+            &self.drop_ref,
+            Some(&self.drop_cache),
+            self.expiry,
+        )?;
+        issues::collect(
+            &drop.meta.custom,
+            iter::dropped::records(&repo, &self.drop_ref),
+        )
+    }
+}
+
+/// Check that the refnames in `opts` are in the expected namespaces, and don't
+/// conflict with state found in `repo`
+///
+/// A conflict is eg. a drop ref which doesn't exist while the alternative does,
+/// which is likely a typo. Conflicts are only logged if [`Options::force`] is
+/// set.
+fn check_refs(repo: &git2::Repository, opts: &Options) -> crate::Result<()> {
+    for (what, name, prefixes) in [
+        ("drop ref", &opts.drop_ref, &["refs/it/", "refs/heads/"][..]),
+        ("seen ref", &opts.seen_ref, &["refs/it/"][..]),
+        ("unbundle prefix", &opts.unbundle_prefix, &["refs/it/"][..]),
+    ] {
+        name.parse::<Refname>()?;
+        ensure!(
+            prefixes.iter().any(|prefix| name.starts_with(prefix)),
+            "{what} {name} must be in one of {}",
+            prefixes.join(", ")
+        );
+    }
+    let bundles = format!("{}/", opts.unbundle_prefix.trim_end_matches('/'));
+    ensure!(
+        !opts.drop_ref.starts_with(&bundles) && !opts.seen_ref.starts_with(&bundles),
+        "drop ref and seen ref must not be below the unbundle prefix {}",
+        opts.unbundle_prefix
+    );
+    ensure!(
+        opts.drop_ref != opts.seen_ref,
+        "drop ref and seen ref must differ"
+    );
+
+    let resolve = |name: &str| -> crate::Result<Option<git2::Oid>> {
+        Ok(if_not_found_none(repo.refname_to_id(name))?)
+    };
+    let has_refs_below = |prefix: &str| -> crate::Result<bool> {
+        let glob = format!("{}/*", prefix.trim_end_matches('/'));
+        Ok(repo.references_glob(&glob)?.next().is_some())
+    };
+
+    let mut conflicts = Vec::new();
+    if let (Some(heads), Some(it)) = (resolve(REF_HEADS_PATCHES)?, resolve(REF_IT_PATCHES)?) {
+        if heads != it {
+            conflicts.push(format!(
+                "both {REF_HEADS_PATCHES} and {REF_IT_PATCHES} exist, but point to different \
+                 commits"
+            ));
+        }
+    }
+    if resolve(&opts.drop_ref)?.is_none() {
+        conflicts.push(format!("drop ref {} does not exist", opts.drop_ref));
+    }
+    if opts.seen_ref != REF_IT_SEEN
+        && resolve(&opts.seen_ref)?.is_none()
+        && resolve(REF_IT_SEEN)?.is_some()
+    {
+        conflicts.push(format!(
+            "seen ref {} does not exist, but {REF_IT_SEEN} does",
+            opts.seen_ref
+        ));
+    }
+    if opts.unbundle_prefix.trim_end_matches('/') != REF_IT_BUNDLES
+        && !has_refs_below(&opts.unbundle_prefix)?
+        && has_refs_below(REF_IT_BUNDLES)?
+    {
+        conflicts.push(format!(
+            "no refs below unbundle prefix {}, but below {REF_IT_BUNDLES}",
+            opts.unbundle_prefix
+        ));
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else if opts.force {
+        for conflict in conflicts {
+            warn!("Ignoring conflicting ref state: {conflict}");
+        }
+        Ok(())
+    } else {
+        bail!(
+            "refusing to start with conflicting ref state:\n  {}\nuse --force to start anyway",
+            conflicts.join("\n  ")
+        )
+    }
+}
+
+/// Run `f` if `interval` elapsed since the time in `last`, or `force` is
+/// true, and update `last`
+///
+/// `f` is not run if `last` is locked, ie. `f` is already running on another
+/// thread.
+fn run_if_due<F: FnOnce()>(last: &Mutex<Instant>, interval: Duration, force: bool, f: F) {
+    let mut last = match last.try_lock() {
+        Ok(last) => last,
+        Err(_) => return,
+    };
+    if force || last.elapsed() >= interval {
+        f();
+        *last = Instant::now();
+    }
+}
@@ -2,4 +2,5 @@
 // SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
 
 pub mod agent;
+pub mod transport;
 pub use ssh_key::*;
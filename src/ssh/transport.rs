@@ -0,0 +1,156 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Channels to a command run on a remote host via `ssh`
+//!
+//! Like git, we don't speak the SSH protocol ourselves, but spawn the `ssh`
+//! client (or the command given in `$IT_SSH_COMMAND`). It takes care of host
+//! key verification and authentication, normally via the same agent
+//! [`super::agent`] talks to.
+
+use std::{
+    env,
+    io::{
+        self,
+        Read,
+        Write,
+    },
+    process::{
+        Child,
+        ChildStdin,
+        ChildStdout,
+        Command,
+        Stdio,
+    },
+};
+
+use url::Url;
+
+const IT_SSH_COMMAND: &str = "IT_SSH_COMMAND";
+const DEFAULT_SSH_COMMAND: &str = "ssh";
+
+/// A remote command's standard input and output
+pub struct Channel {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl Channel {
+    /// Run `argv` on the host of the `ssh://` URL `url`
+    ///
+    /// The arguments are quoted for the remote shell.
+    pub fn open(url: &Url, argv: &[&str]) -> io::Result<Self> {
+        if url.scheme() != "ssh" {
+            return Err(invalid_input(format!("not an ssh url: {url}")));
+        }
+        let host = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| invalid_input(format!("missing host in {url}")))?;
+        // Prevent the host being interpreted as an option
+        if host.starts_with('-') {
+            return Err(invalid_input(format!("invalid host {host}")));
+        }
+
+        let ssh = env::var(IT_SSH_COMMAND).unwrap_or_else(|_| DEFAULT_SSH_COMMAND.to_owned());
+        let mut ssh = shlex::split(&ssh)
+            .filter(|argv| !argv.is_empty())
+            .ok_or_else(|| invalid_input(format!("invalid {IT_SSH_COMMAND}")))?
+            .into_iter();
+        let mut cmd = Command::new(ssh.next().unwrap());
+        cmd.args(ssh);
+        if let Some(port) = url.port() {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if !url.username().is_empty() {
+            cmd.arg("-l").arg(url.username());
+        }
+        cmd.arg(host);
+
+        cmd.arg(
+            argv.iter()
+                .map(|arg| quote(arg))
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Signal end of input to the remote command
+    pub fn close_input(&mut self) {
+        self.stdin.take();
+    }
+
+    /// Wait for the remote command to exit, failing if it was unsuccessful
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close_input();
+        let status = self.child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("ssh exited with {status}"),
+            ))
+        }
+    }
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stdin.as_mut() {
+            Some(stdin) => stdin.write(buf),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stdin.as_mut() {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The remote path denoted by `url`, unless empty
+///
+/// As with git, `ssh://host/path` is absolute, while `ssh://host/~/path` is
+/// relative to the login directory.
+pub fn path(url: &Url) -> Option<&str> {
+    let path = url.path();
+    let path = path.strip_prefix("/~/").unwrap_or(path);
+    if path.is_empty() || path == "/" {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Quote `arg` for a POSIX shell
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
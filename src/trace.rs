@@ -0,0 +1,94 @@
+// Copyright © 2022 Kim Altintop <kim@eagain.io>
+// SPDX-License-Identifier: GPL-2.0-only WITH openvpn-openssl-exception
+
+//! Contextual fields for log records
+//!
+//! A [`Span`] names a unit of work, such as an HTTP request or the acceptance
+//! of a submission, and carries fields describing it. While a span is entered,
+//! log records emitted on the same thread can be attributed to it via
+//! [`visit`], which is how the logger of the CLI renders them.
+//!
+//! Spans nest: entering a span while another is entered makes it a child of
+//! the latter until the returned guard is dropped.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    marker::PhantomData,
+};
+
+pub struct SpanData {
+    pub name: &'static str,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+thread_local! {
+    static STACK: RefCell<Vec<SpanData>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Guard of an entered span, exited when dropped
+///
+/// Spans are bound to the thread they were entered on.
+#[must_use = "the span is exited when the guard is dropped"]
+pub struct Span {
+    depth: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Span {
+    /// Add a field to this span
+    pub fn record(&self, key: &'static str, value: impl fmt::Display) {
+        let value = value.to_string();
+        STACK.with(|stack| {
+            if let Some(span) = stack.borrow_mut().get_mut(self.depth) {
+                span.fields.push((key, value));
+            }
+        })
+    }
+
+    /// Add a field to this span, returning it
+    pub fn with(self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.record(key, value);
+        self
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        STACK.with(|stack| stack.borrow_mut().truncate(self.depth))
+    }
+}
+
+/// Enter a new span called `name`
+pub fn span(name: &'static str) -> Span {
+    STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let depth = stack.len();
+        stack.push(SpanData {
+            name,
+            fields: Vec::new(),
+        });
+        Span {
+            depth,
+            _not_send: PhantomData,
+        }
+    })
+}
+
+/// Add a field to the innermost span entered on the current thread, if any
+pub fn record(key: &'static str, value: impl fmt::Display) {
+    let value = value.to_string();
+    STACK.with(|stack| {
+        if let Some(span) = stack.borrow_mut().last_mut() {
+            span.fields.push((key, value));
+        }
+    })
+}
+
+/// Call `f` with the spans entered on the current thread, outermost first
+pub fn visit<F, T>(f: F) -> T
+where
+    F: FnOnce(&[SpanData]) -> T,
+{
+    STACK.with(|stack| f(&stack.borrow()))
+}